@@ -42,6 +42,41 @@ pub struct CounterCanister;
 #[cfg(test)]
 mod tests {
     use super::*;
+    use ic_kit::scheduler::{self, JobFuture, Schedule};
+    use ic_kit::stable::{MemoryId, StableBTreeMap, Storable, StorageKey, VectorMemory};
+    use ic_kit::timers;
+    use std::time::Duration;
+
+    const MINUTE: u64 = 60_000_000_000;
+
+    fn increment_job() -> JobFuture {
+        Box::pin(async {
+            with_mut(Counter::increment);
+            Ok(())
+        })
+    }
+
+    #[init]
+    fn init_timers() {
+        scheduler::init(MemoryId::new(0));
+        scheduler::register("increment", increment_job);
+        scheduler::schedule("increment", Schedule::Cron("*/5 * * * *".into())).unwrap();
+    }
+
+    #[update]
+    fn increment_later(seconds: u64) {
+        timers::set_timer(Duration::from_secs(seconds), || {
+            with_mut(|counter: &mut Counter| counter.increment_by(100));
+        });
+    }
+
+    #[query]
+    fn get_timer_counter(counter: &Counter) -> u64 {
+        counter.number
+    }
+
+    #[derive(KitCanister)]
+    pub struct TimerCanister;
 
     #[kit_test]
     async fn test_increment(replica: Replica) {
@@ -97,4 +132,789 @@ mod tests {
             2
         );
     }
+
+    /// The instances of the same canister, and the thread of the test, each have their own state.
+    #[kit_test]
+    async fn test_isolated_instances(replica: Replica) {
+        let a = replica.add_canister(CounterCanister::build(ic_kit::rt::replica::canister_id(1)));
+        let b = replica.add_canister(CounterCanister::build(ic_kit::rt::replica::canister_id(2)));
+
+        for _ in 0..3 {
+            a.new_call("increment").perform().await.assert_ok();
+        }
+
+        b.new_call("increment").perform().await.assert_ok();
+        ic::with_mut(|counter: &mut Counter| counter.increment_by(10));
+        a.stable_write(0, vec![1; 8]).await;
+
+        assert_eq!(
+            a.new_call("get_counter")
+                .perform()
+                .await
+                .decode_one::<u64>()
+                .unwrap(),
+            3
+        );
+
+        assert_eq!(
+            b.new_call("get_counter")
+                .perform()
+                .await
+                .decode_one::<u64>()
+                .unwrap(),
+            1
+        );
+
+        assert_eq!(ic::with(|counter: &Counter| counter.number), 10);
+        assert_eq!(b.stable_size().await, 0);
+    }
+
+    #[kit_test]
+    async fn test_reset(replica: Replica) {
+        let a = replica.add_canister(CounterCanister::build(ic_kit::rt::replica::canister_id(1)));
+        let b = replica.add_canister(CounterCanister::build(ic_kit::rt::replica::canister_id(2)));
+
+        a.new_call("increment").perform().await.assert_ok();
+        b.new_call("increment").perform().await.assert_ok();
+        a.stable_write(0, vec![1; 8]).await;
+
+        a.reset().await.assert_ok();
+
+        assert_eq!(
+            a.new_call("get_counter")
+                .perform()
+                .await
+                .decode_one::<u64>()
+                .unwrap(),
+            0
+        );
+
+        assert_eq!(
+            b.new_call("get_counter")
+                .perform()
+                .await
+                .decode_one::<u64>()
+                .unwrap(),
+            1
+        );
+
+        assert_eq!(a.stable_size().await, 0);
+    }
+
+    /// The timers and the scheduled jobs run with the global timer once the time of the replica
+    /// has passed their time.
+    #[kit_test]
+    async fn test_timers(replica: Replica) {
+        replica.set_time(1_000 * MINUTE);
+        let c = replica.add_canister(TimerCanister::anonymous());
+        c.init().await.assert_ok();
+
+        let counter = || async {
+            c.new_call("get_timer_counter")
+                .perform()
+                .await
+                .decode_one::<u64>()
+                .unwrap()
+        };
+
+        c.new_call("increment_later")
+            .with_arg(90u64)
+            .perform()
+            .await
+            .assert_ok();
+
+        c.global_timer().await.assert_ok();
+        assert_eq!(counter().await, 0);
+
+        replica.advance_time(Duration::from_secs(60));
+        c.global_timer().await.assert_ok();
+        assert_eq!(counter().await, 0);
+
+        // The job runs at 1005 minutes and the timer at 1001.5 minutes.
+        replica.advance_time(Duration::from_secs(5 * 60));
+        c.global_timer().await.assert_ok();
+        assert_eq!(counter().await, 101);
+
+        // The job is set again after each run.
+        replica.advance_time(Duration::from_secs(3 * 60));
+        c.global_timer().await.assert_ok();
+        assert_eq!(counter().await, 101);
+
+        replica.advance_time(Duration::from_secs(60));
+        c.global_timer().await.assert_ok();
+        assert_eq!(counter().await, 102);
+    }
+
+    /// The canisters are added with the ids the dfx project resolves for them.
+    #[kit_test]
+    async fn test_dfx_project(replica: Replica) {
+        // The project of the workspace is found from the directory of the crate.
+        let workspace = DfxProject::find().unwrap();
+        assert_eq!(
+            workspace.canister_names(),
+            vec!["counter", "fib", "naming_system"]
+        );
+        assert!(workspace
+            .wasm_path("counter")
+            .unwrap()
+            .ends_with("wasm32-unknown-unknown/release/ic_kit_example_counter.wasm"));
+        assert_eq!(
+            workspace.network_url("local").as_deref(),
+            Some("http://127.0.0.1:8000")
+        );
+
+        let dir = std::env::temp_dir().join(format!("ic-kit-dfx-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join(".dfx/local")).unwrap();
+        std::fs::write(
+            dir.join("dfx.json"),
+            r#"{ "canisters": { "counter": { "type": "rust" } } }"#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("canister_ids.json"),
+            r#"{ "counter": { "ic": "whq4n-xiaaa-aaaam-qaazq-cai" } }"#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join(".dfx/local/canister_ids.json"),
+            r#"{ "counter": { "local": "lj532-6iaaa-aaaah-qcc7a-cai" } }"#,
+        )
+        .unwrap();
+
+        let project = DfxProject::load(&dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let local_id = project.canister_id("counter", "local").unwrap();
+        assert_eq!(local_id.to_text(), "lj532-6iaaa-aaaah-qcc7a-cai");
+        assert_eq!(
+            project.canister_id("counter", "ic"),
+            Some(Principal::from_text("whq4n-xiaaa-aaaam-qaazq-cai").unwrap())
+        );
+        assert_eq!(project.canister_id("counter", "staging"), None);
+        assert_eq!(
+            project.network_url("local").as_deref(),
+            Some("http://127.0.0.1:4943")
+        );
+
+        let counter = replica.add_canister(CounterCanister::build(local_id));
+        counter.new_call("increment").perform().await.assert_ok();
+
+        let r = replica
+            .new_call(local_id, "get_counter")
+            .perform()
+            .await
+            .decode_one::<u64>()
+            .unwrap();
+        assert_eq!(r, 1);
+    }
+
+    mod renamed {
+        use super::*;
+
+        #[update(name = "icrc1_transfer")]
+        fn transfer(amount: u64) -> u64 {
+            amount
+        }
+
+        #[update(hidden)]
+        fn migrate(counter: &mut Counter) -> u64 {
+            counter.increment()
+        }
+
+        #[derive(KitCanister)]
+        pub struct RenamedCanister;
+
+        /// The methods are exported with their name, and the hidden ones are left out of the
+        /// candid interface but can still be called.
+        #[kit_test]
+        async fn test_renamed_and_hidden(replica: Replica) {
+            let c = replica.add_canister(RenamedCanister::anonymous());
+
+            assert_eq!(
+                c.new_call("icrc1_transfer")
+                    .with_arg(5u64)
+                    .perform()
+                    .await
+                    .decode_one::<u64>()
+                    .unwrap(),
+                5
+            );
+
+            c.new_call("transfer")
+                .with_arg(5u64)
+                .perform()
+                .await
+                .assert_error();
+
+            assert_eq!(
+                c.new_call("migrate")
+                    .perform()
+                    .await
+                    .decode_one::<u64>()
+                    .unwrap(),
+                1
+            );
+
+            assert_eq!(
+                RenamedCanister::candid().trim(),
+                "service : { icrc1_transfer : (nat64) -> (nat64) }"
+            );
+        }
+    }
+
+    mod access {
+        use super::*;
+
+        #[only_controller]
+        #[update]
+        fn grant_minter(user: Principal) {
+            ic::grant_role("minter", user);
+        }
+
+        #[only(minter)]
+        #[update]
+        fn mint(counter: &mut Counter, n: u8) -> u64 {
+            counter.increment_by(n)
+        }
+
+        #[derive(KitCanister)]
+        pub struct AccessCanister;
+
+        /// The callers which are not a controller or don't have the role are rejected before the
+        /// arguments are decoded.
+        #[kit_test]
+        async fn test_access_control(replica: Replica) {
+            let c =
+                replica.add_canister(AccessCanister::anonymous().with_controller(*users::ALICE));
+
+            c.new_call("grant_minter")
+                .with_caller(*users::BOB)
+                .with_arg(*users::BOB)
+                .perform()
+                .await
+                .assert_rejected_containing("Only the controllers of the canister");
+
+            c.new_call("mint")
+                .with_caller(*users::BOB)
+                .with_arg_raw(b"not candid".to_vec())
+                .perform()
+                .await
+                .assert_rejected_containing("The caller does not have the required role");
+
+            c.new_call("grant_minter")
+                .with_caller(*users::ALICE)
+                .with_arg(*users::BOB)
+                .perform()
+                .await
+                .assert_ok();
+
+            assert_eq!(
+                c.new_call("mint")
+                    .with_caller(*users::BOB)
+                    .with_arg(3u8)
+                    .perform()
+                    .await
+                    .decode_one::<u64>()
+                    .unwrap(),
+                3
+            );
+
+            // The controller has no role of its own.
+            c.new_call("mint")
+                .with_caller(*users::ALICE)
+                .with_arg(3u8)
+                .perform()
+                .await
+                .assert_rejected();
+        }
+    }
+
+    mod payable {
+        use super::*;
+
+        #[update(payable, max_cycles = 1_000_000)]
+        fn deposit(payment: Payment) -> Cycles {
+            payment.amount()
+        }
+
+        #[update(payable)]
+        fn donate(payment: Payment) -> Cycles {
+            payment.amount()
+        }
+
+        #[update]
+        fn tick(counter: &mut Counter) -> u64 {
+            counter.increment()
+        }
+
+        #[derive(KitCanister)]
+        pub struct PayableCanister;
+
+        #[kit_test]
+        async fn test_payable(replica: Replica) {
+            let c = replica.add_canister(PayableCanister::anonymous());
+            let balance = c.balance().await;
+
+            // The payment is accepted up to the maximum, and the rest is refunded.
+            let reply = c
+                .new_call("deposit")
+                .with_payment(5_000_000)
+                .perform()
+                .await;
+            assert_eq!(reply.decode_one::<Cycles>().unwrap(), 1_000_000);
+            reply.assert_cycles_accepted(5_000_000, 1_000_000);
+            reply.assert_cycles_refunded(4_000_000);
+
+            let reply = c.new_call("donate").with_payment(5_000).perform().await;
+            assert_eq!(reply.decode_one::<Cycles>().unwrap(), 5_000);
+            reply.assert_cycles_accepted(5_000, 5_000);
+
+            assert_eq!(c.balance().await, balance + 1_005_000);
+
+            // The other methods refund the cycles, and with the `strict-payable` feature they
+            // refuse the call.
+            let reply = c.new_call("tick").with_payment(5_000).perform().await;
+            reply.assert_fully_refunded(5_000);
+            if cfg!(feature = "strict-payable") {
+                reply.assert_rejected_containing("This method does not accept cycles.");
+            } else {
+                assert_eq!(reply.decode_one::<u64>().unwrap(), 1);
+            }
+
+            assert_eq!(c.balance().await, balance + 1_005_000);
+        }
+
+        #[kit_test]
+        async fn test_cycles_report(replica: Replica) {
+            let c =
+                replica.add_canister(PayableCanister::build(ic_kit::rt::replica::canister_id(1)));
+
+            c.new_call("deposit")
+                .with_payment(5_000_000)
+                .perform()
+                .await
+                .assert_ok();
+            c.new_call("donate")
+                .with_payment(5_000)
+                .perform()
+                .await
+                .assert_ok();
+            c.new_call("tick").with_payment(5_000).perform().await;
+
+            let report = replica.cycles_report().await;
+            report.assert_no_cycles_lost();
+
+            let flow = report.flow(&c.canister_id()).unwrap();
+            assert_eq!(flow.received, 5_010_000);
+            assert_eq!(flow.accepted, 1_005_000);
+            assert_eq!(flow.refunded, 4_005_000);
+            assert_eq!(flow.received_in_flight, 0);
+            assert_eq!(flow.sent, 0);
+            assert_eq!(
+                flow.balance.unwrap(),
+                flow.initial_balance.unwrap() + 1_005_000
+            );
+
+            // The user paid for the calls, and got back the cycles which weren't accepted.
+            let user = report.flow(&Principal::anonymous()).unwrap();
+            assert_eq!(user.sent, 5_010_000);
+            assert_eq!(user.refunds, 4_005_000);
+            assert_eq!(user.transferred[&c.canister_id()], 1_005_000);
+        }
+    }
+
+    mod metrics {
+        use super::*;
+
+        #[update(instrument)]
+        fn transfer(amount: u64) -> Result<u64, String> {
+            if amount == 0 {
+                return Err("The amount can't be zero.".into());
+            }
+
+            Ok(amount)
+        }
+
+        #[update(instrument)]
+        #[only_controller]
+        fn pause() {}
+
+        export_metrics!(text);
+
+        #[derive(KitCanister)]
+        pub struct MetricsCanister;
+
+        #[kit_test]
+        async fn test_instrument(replica: Replica) {
+            let c = replica.add_canister(MetricsCanister::anonymous());
+
+            for amount in [5u64, 0, 7] {
+                c.new_call("transfer")
+                    .with_arg(amount)
+                    .perform()
+                    .await
+                    .assert_ok();
+            }
+
+            c.new_call("pause").perform().await.assert_rejected();
+
+            let metrics = c
+                .new_call("__metrics")
+                .perform()
+                .await
+                .decode_one::<String>()
+                .unwrap();
+            // The `Err` replies and the refused calls are counted as errors.
+            let lines: Vec<&str> = metrics.lines().collect();
+            assert_eq!(lines.len(), 2);
+            assert!(lines[0].starts_with("pause: calls=1 errors=1 "));
+            assert!(lines[1].starts_with("transfer: calls=3 errors=1 "));
+
+            assert!(!MetricsCanister::candid().contains("__metrics"));
+        }
+    }
+
+    mod decoding {
+        use super::*;
+
+        #[init]
+        fn init() {
+            ic::on_decode_error(|_| ic::DecodeErrorPolicy::Trap);
+        }
+
+        #[update]
+        fn add(counter: &mut Counter, n: u8) -> u64 {
+            counter.increment_by(n)
+        }
+
+        #[update(decode_error = "reject")]
+        fn add_or_reject(counter: &mut Counter, n: u8) -> u64 {
+            counter.increment_by(n)
+        }
+
+        #[update(decode_error = "legacy_add_memo")]
+        fn add_memo(counter: &mut Counter, n: u8, _memo: u64) -> u64 {
+            counter.increment_by(n)
+        }
+
+        /// Accept the calls made before the memo was added.
+        fn legacy_add_memo(error: ic::DecodeError) {
+            match ic_kit::candid::decode_args::<(u8,)>(&error.arg_data) {
+                Ok((n,)) => ic::reply((with_mut(|counter: &mut Counter| counter.increment_by(n)),)),
+                Err(_) => ic::reject(error.to_string()),
+            }
+        }
+
+        #[derive(KitCanister)]
+        pub struct DecodingCanister;
+
+        #[kit_test]
+        async fn test_decode_error(replica: Replica) {
+            let c = replica.add_canister(DecodingCanister::anonymous());
+
+            // Without the hook, the call is rejected with the error.
+            c.new_call("add")
+                .with_arg("one")
+                .perform()
+                .await
+                .assert_rejected_containing("Could not decode the arguments of method 'add'");
+
+            // The hook set by the init traps, unless the method has its own policy.
+            c.init().await.assert_ok();
+            c.new_call("add")
+                .with_arg("one")
+                .perform()
+                .await
+                .assert_trapped_containing("Could not decode the arguments of method 'add'");
+            c.new_call("add_or_reject")
+                .with_arg("one")
+                .perform()
+                .await
+                .assert_rejected_containing(
+                    "Could not decode the arguments of method 'add_or_reject'",
+                );
+
+            // The fallback replies to the calls in the older format, and rejects the others.
+            let add_memo = |args: Vec<u8>| {
+                let call = c.new_call("add_memo").with_arg_raw(args);
+                async move { call.perform().await }
+            };
+            assert_eq!(
+                add_memo(ic_kit::candid::encode_args((2u8, 0u64)).unwrap())
+                    .await
+                    .decode_one::<u64>()
+                    .unwrap(),
+                2
+            );
+            assert_eq!(
+                add_memo(ic_kit::candid::encode_one(3u8).unwrap())
+                    .await
+                    .decode_one::<u64>()
+                    .unwrap(),
+                5
+            );
+            add_memo(ic_kit::candid::encode_one("one").unwrap())
+                .await
+                .assert_rejected_containing("Could not decode the arguments of method 'add_memo'");
+        }
+    }
+
+    mod errors {
+        use super::*;
+
+        fn closed() -> Result<(), String> {
+            Err("Withdrawals are closed.".into())
+        }
+
+        #[update(reply_errors)]
+        #[only_controller]
+        fn set_fee(fee: u64) -> Result<u64, ic_kit::Error> {
+            if fee == 0 {
+                return Err(ic_kit::Error::invalid_argument("The fee can't be zero.")
+                    .with_detail("fee", fee));
+            }
+
+            Ok(fee)
+        }
+
+        #[update(reply_errors, guard = "closed")]
+        fn withdraw() -> Result<(), ic_kit::Error> {
+            Ok(())
+        }
+
+        #[derive(KitCanister)]
+        pub struct ErrorsCanister;
+
+        /// The refused calls are replied with an `Err`, like the errors of the method.
+        #[kit_test]
+        async fn test_reply_errors(replica: Replica) {
+            let c =
+                replica.add_canister(ErrorsCanister::anonymous().with_controller(*users::ALICE));
+
+            let set_fee = |caller: Principal, arg: Vec<u8>| {
+                let call = c.new_call("set_fee").with_caller(caller).with_arg_raw(arg);
+                async move {
+                    call.perform()
+                        .await
+                        .decode_one::<Result<u64, ic_kit::Error>>()
+                        .unwrap()
+                }
+            };
+            let fee = |fee: u64| ic_kit::candid::encode_one(fee).unwrap();
+
+            assert_eq!(set_fee(*users::ALICE, fee(10)).await, Ok(10));
+
+            let error = set_fee(*users::BOB, fee(10)).await.unwrap_err();
+            assert!(error.is(ic_kit::Error::UNAUTHORIZED));
+
+            let error = set_fee(*users::ALICE, fee(0)).await.unwrap_err();
+            assert!(error.is(ic_kit::Error::INVALID_ARGUMENT));
+            assert_eq!(error.detail("fee"), Some("0"));
+
+            let error = set_fee(*users::ALICE, b"not candid".to_vec())
+                .await
+                .unwrap_err();
+            assert!(error.is(ic_kit::Error::INVALID_ARGUMENT));
+            assert_eq!(error.detail("method"), Some("set_fee"));
+
+            let error = c
+                .new_call("withdraw")
+                .perform()
+                .await
+                .decode_one::<Result<(), ic_kit::Error>>()
+                .unwrap()
+                .unwrap_err();
+            assert_eq!(
+                error,
+                ic_kit::Error::new(
+                    ic_kit::Error::PRECONDITION_FAILED,
+                    "Withdrawals are closed."
+                )
+            );
+        }
+    }
+
+    /// A dapp controlled by an SNS, which pays its contributors from the treasury.
+    mod sns {
+        use super::*;
+        use ic_kit::interfaces::icrc1::{Account, Icrc1Client};
+        use ic_kit::interfaces::sns::governance::{
+            treasury, Action, NeuronId, Proposal, SnsGovernanceClient, TransferSnsTreasuryFunds,
+        };
+        use ic_kit::interfaces::sns::root::SnsRootClient;
+
+        /// Propose to pay the contributor from the treasury, and return the id of the proposal.
+        #[update]
+        async fn propose_payment(
+            governance: Principal,
+            contributor: Principal,
+            amount: u64,
+        ) -> Result<u64, String> {
+            let action = Action::TransferSnsTreasuryFunds(TransferSnsTreasuryFunds {
+                from_treasury: treasury::SNS_TOKEN,
+                to_principal: Some(contributor),
+                to_subaccount: None,
+                memo: None,
+                amount_e8s: amount,
+            });
+
+            SnsGovernanceClient::new(governance)
+                .make_proposal(
+                    NeuronId { id: vec![1; 32] },
+                    Proposal::new("Pay a contributor", action),
+                )
+                .await
+                .map_err(|e| format!("{:?}", e))?
+                .map(|id| id.id)
+                .map_err(|e| e.error_message)
+        }
+
+        /// Returns true if this canister is one of the canisters of the dapp.
+        #[update]
+        async fn is_dapp(root: Principal) -> bool {
+            SnsRootClient::new(root)
+                .list_sns_canisters()
+                .await
+                .expect("Expected the call to succeed.")
+                .dapps
+                .contains(&id())
+        }
+
+        #[update]
+        async fn balance_of(ledger: Principal, owner: Principal) -> Nat {
+            Icrc1Client::new(ledger)
+                .balance_of(Account::from(owner))
+                .await
+                .expect("Expected the call to succeed.")
+        }
+
+        #[derive(KitCanister)]
+        pub struct DappCanister;
+
+        #[kit_test]
+        async fn test_sns_treasury_proposal(replica: Replica) {
+            let dapp = replica.add_canister(DappCanister::anonymous());
+            let sns = replica.sns();
+            let ids = sns.ids();
+            sns.mint_to_treasury(1_000_000u64);
+
+            let is_dapp = || {
+                let call = dapp.new_call("is_dapp").with_arg(ids.root);
+                async move { call.perform().await.decode_one::<bool>().unwrap() }
+            };
+            let propose = |amount: u64| {
+                let call = dapp.new_call("propose_payment").with_args((
+                    ids.governance,
+                    *users::ALICE,
+                    amount,
+                ));
+                async move {
+                    call.perform()
+                        .await
+                        .decode_one::<Result<u64, String>>()
+                        .unwrap()
+                }
+            };
+
+            assert!(!is_dapp().await);
+            sns.register_dapp(dapp.canister_id());
+            assert!(is_dapp().await);
+
+            // The transfer is executed as soon as the proposal is submitted, and the fee burned.
+            assert_eq!(propose(100_000).await, Ok(1));
+            let proposal = sns.proposal(1).unwrap();
+            assert!(proposal.executed);
+            assert_eq!(proposal.proposer, vec![1; 32]);
+            assert_eq!(sns.treasury_balance(), Nat::from(890_000u64));
+
+            let balance = dapp
+                .new_call("balance_of")
+                .with_args((ids.ledger, *users::ALICE))
+                .perform()
+                .await
+                .decode_one::<Nat>()
+                .unwrap();
+            assert_eq!(balance, Nat::from(100_000u64));
+
+            // A transfer larger than the treasury is recorded as failed.
+            assert_eq!(propose(1_000_000).await, Ok(2));
+            let proposal = sns.proposal(2).unwrap();
+            assert!(!proposal.executed);
+            assert_eq!(
+                proposal.failure.as_deref(),
+                Some("The treasury only has 890_000 tokens.")
+            );
+            assert_eq!(sns.balance_of(*users::ALICE), Nat::from(100_000u64));
+        }
+    }
+
+    #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, StorageKey)]
+    #[key_version(1)]
+    #[key_layout(0x4f18_49f1_7f4f_8774)]
+    struct AllowanceKey {
+        owner: Principal,
+        spender: Principal,
+    }
+
+    #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, StorageKey)]
+    enum EventKey {
+        Minted(u64),
+        Transferred { block: u64, index: i16 },
+        Paused,
+    }
+
+    #[test]
+    fn derived_storage_keys() {
+        let alice = Principal::from_slice(&[1; 29]);
+        let bob = Principal::from_slice(&[2; 10]);
+
+        // The version, followed by the fields in order.
+        let key = AllowanceKey {
+            owner: alice,
+            spender: bob,
+        };
+        let bytes = key.to_bytes().into_owned();
+        assert_eq!(AllowanceKey::SIZE, 60);
+        assert_eq!(bytes.len(), 61);
+        assert_eq!(bytes[0], 1);
+        assert_eq!(bytes[1], 29);
+        assert_eq!(bytes[31..33], [10, 2]);
+        assert_eq!(AllowanceKey::from_bytes(bytes), key);
+
+        // The variants are padded to the size of the largest one.
+        assert_eq!(EventKey::SIZE, 1 + 8 + 2);
+        let mut paused = vec![0; 11];
+        paused[0] = 2;
+        assert_eq!(EventKey::Paused.to_bytes(), paused);
+        assert_eq!(EventKey::from_bytes(paused), EventKey::Paused);
+
+        let events = vec![
+            EventKey::Minted(0),
+            EventKey::Minted(u64::MAX),
+            EventKey::Transferred {
+                block: 1,
+                index: -1,
+            },
+            EventKey::Transferred { block: 1, index: 0 },
+            EventKey::Transferred {
+                block: 2,
+                index: -5,
+            },
+            EventKey::Paused,
+        ];
+
+        let mut map = StableBTreeMap::<EventKey, u64, _>::init(VectorMemory::default());
+        for (i, event) in events.iter().rev().enumerate() {
+            map.insert(event.clone(), i as u64);
+        }
+
+        // The keys are iterated in the order of the type.
+        let keys: Vec<EventKey> = map.iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, events);
+        assert_eq!(
+            map.range(EventKey::Transferred { block: 1, index: 0 }..EventKey::Paused)
+                .count(),
+            2
+        );
+    }
 }
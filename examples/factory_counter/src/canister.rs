@@ -12,3 +12,131 @@ async fn deploy_counter() -> Principal {
 #[derive(KitCanister)]
 #[candid_path("candid.did")]
 pub struct FactoryCounterCanister;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An orchestrator which upgrades the counters it controls, in its own module since a module
+    /// can only derive one canister.
+    mod orchestrator {
+        use super::*;
+
+        #[derive(CandidType)]
+        struct CanisterIdRecord {
+            canister_id: Principal,
+        }
+
+        #[derive(CandidType)]
+        #[allow(non_camel_case_types)]
+        enum CanisterInstallMode {
+            upgrade,
+        }
+
+        #[derive(CandidType)]
+        struct InstallCodeArgument {
+            mode: CanisterInstallMode,
+            canister_id: Principal,
+            wasm_module: Vec<u8>,
+            arg: Vec<u8>,
+        }
+
+        async fn manage<T: CandidType>(method: &str, arg: T) -> Result<(), String> {
+            CallBuilder::new(Principal::management_canister(), method)
+                .with_arg(arg)
+                .perform::<()>()
+                .await
+                .map_err(|e| e.to_string())
+        }
+
+        /// Stop the counter, upgrade it to the module and start it again.
+        #[update]
+        async fn upgrade(counter: Principal, wasm_module: Vec<u8>) -> Result<(), String> {
+            let record = || CanisterIdRecord {
+                canister_id: counter,
+            };
+
+            manage("stop_canister", record()).await?;
+            manage(
+                "install_code",
+                InstallCodeArgument {
+                    mode: CanisterInstallMode::upgrade,
+                    canister_id: counter,
+                    wasm_module,
+                    arg: candid::encode_args(()).unwrap(),
+                },
+            )
+            .await?;
+            manage("start_canister", record()).await
+        }
+
+        #[derive(KitCanister)]
+        pub struct OrchestratorCanister;
+
+        #[kit_test]
+        async fn test_upgrade_counter(replica: Replica) {
+            let management = replica.management();
+            let orchestrator = replica.add_canister(OrchestratorCanister::build(
+                ic_kit::rt::replica::canister_id(0),
+            ));
+            let counter_id = ic_kit::rt::replica::canister_id(1);
+            let counter = replica.add_canister(
+                CounterCanister::build(counter_id).with_controller(orchestrator.canister_id()),
+            );
+            counter.new_call("increment").perform().await.assert_ok();
+
+            let v2 = CounterCanister::build(counter_id).with_build_id("v2");
+            let hash = v2.module_hash();
+            let module = management.add_module(&v2);
+
+            let reply = orchestrator
+                .new_call("upgrade")
+                .with_args((counter_id, module))
+                .perform()
+                .await;
+            assert_eq!(reply.decode_one::<Result<(), String>>().unwrap(), Ok(()));
+            counter.assert_module_hash(hash);
+
+            let calls = management.calls();
+            assert!(calls
+                .iter()
+                .all(|call| call.caller == orchestrator.canister_id()));
+            assert_eq!(
+                calls
+                    .iter()
+                    .map(|call| call.method.as_str())
+                    .collect::<Vec<_>>(),
+                vec!["stop_canister", "install_code", "start_canister"]
+            );
+
+            // The counter was started again.
+            counter.new_call("increment").perform().await.assert_ok();
+        }
+
+        #[kit_test]
+        async fn test_upgrade_uncontrolled_counter(replica: Replica) {
+            let management = replica.management();
+            let orchestrator = replica.add_canister(OrchestratorCanister::build(
+                ic_kit::rt::replica::canister_id(0),
+            ));
+            let counter_id = ic_kit::rt::replica::canister_id(1);
+            let counter = replica.add_canister(CounterCanister::build(counter_id));
+            let hash = counter.module_hash();
+            let module =
+                management.add_module(&CounterCanister::build(counter_id).with_build_id("v2"));
+
+            let reply = orchestrator
+                .new_call("upgrade")
+                .with_args((counter_id, module))
+                .perform()
+                .await;
+            let error = reply
+                .decode_one::<Result<(), String>>()
+                .unwrap()
+                .unwrap_err();
+            assert!(error.contains("Only the controllers of canister"));
+            counter.assert_module_hash(hash);
+            counter.new_call("increment").perform().await.assert_ok();
+        }
+    }
+}
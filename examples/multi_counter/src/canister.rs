@@ -34,6 +34,96 @@ mod tests {
     use super::*;
     use ic_kit_example_counter::CounterCanister;
 
+    /// Hold a budget of one call per message across an await, and return the number of calls
+    /// which could be sent after it.
+    #[update]
+    async fn budgeted_increment(counter: Principal) -> u32 {
+        let _budget = CallBudget::with_policy(1, CallBudgetPolicy::Fail);
+
+        CallBuilder::new(counter, "increment")
+            .perform_one::<u64>()
+            .await
+            .expect("Expected the call to succeed.");
+
+        (0..2)
+            .filter(|_| {
+                CallBuilder::new(counter, "increment")
+                    .perform_one_way()
+                    .is_ok()
+            })
+            .count() as u32
+    }
+
+    /// Return the number of calls which could be sent, without a budget.
+    #[update]
+    fn unbudgeted_increment(counter: Principal) -> u32 {
+        (0..3)
+            .filter(|_| {
+                CallBuilder::new(counter, "increment")
+                    .perform_one_way()
+                    .is_ok()
+            })
+            .count() as u32
+    }
+
+    /// Make `calls` calls to the counter in one message with a budget of `max_calls` and the
+    /// given policy, after awaiting a call to the given method of the counter, and trap at the
+    /// end if asked to. Returns the number of calls which could be sent.
+    #[update]
+    async fn budgeted_calls(
+        counter: Principal,
+        method: String,
+        max_calls: u32,
+        trap_policy: bool,
+        calls: u32,
+        trap: bool,
+    ) -> Result<u32, String> {
+        let policy = if trap_policy {
+            CallBudgetPolicy::Trap
+        } else {
+            CallBudgetPolicy::Fail
+        };
+        let budget = CallBudget::with_policy(max_calls, policy);
+        let _unlimited = CallBudget::new(u32::MAX);
+
+        CallBuilder::new(counter, method)
+            .perform_one::<u64>()
+            .await
+            .map_err(|e| format!("{:?}", e))?;
+
+        // The smallest limit applies.
+        assert_eq!(budget.remaining(), max_calls);
+
+        let sent = (0..calls)
+            .filter(|_| {
+                CallBuilder::new(counter, "increment")
+                    .perform_one_way()
+                    .is_ok()
+            })
+            .count() as u32;
+
+        if trap {
+            ic::trap("Trapped after the calls.");
+        }
+
+        Ok(sent)
+    }
+
+    /// Trap in the first message, while a budget is alive.
+    #[update]
+    fn budgeted_trap() {
+        let _budget = CallBudget::new(1);
+        ic::trap("Trapped with a budget.");
+    }
+
+    #[query]
+    fn active_budgets() -> u64 {
+        CallBudget::active() as u64
+    }
+
+    #[derive(KitCanister)]
+    pub struct BudgetCanister;
+
     #[kit_test]
     async fn test_multi_canister(replica: Replica) {
         let counter1_id = Principal::from_text("whq4n-xiaaa-aaaam-qaazq-cai").unwrap();
@@ -84,4 +174,533 @@ mod tests {
 
         println!("{:#?}", x);
     }
+
+    /// Run many replicas with the same canister ids in parallel threads, each replica must only
+    /// see its own calls.
+    #[test]
+    fn test_parallel_replicas() {
+        const THREADS: u64 = 16;
+
+        let threads = (1..=THREADS)
+            .map(|n| {
+                std::thread::spawn(move || {
+                    let rt = ic_kit::rt::TokioRuntimeBuilder::new_current_thread()
+                        .build()
+                        .unwrap();
+
+                    rt.block_on(async move {
+                        let replica = Replica::default();
+                        let counter_id = ic_kit::rt::replica::canister_id(1);
+
+                        let canister = replica.add_canister(MultiCounterCanister::anonymous());
+                        let counter = replica.add_canister(CounterCanister::build(counter_id));
+
+                        canister
+                            .new_call("add_counter")
+                            .with_arg(&counter_id)
+                            .perform()
+                            .await
+                            .assert_ok();
+
+                        for _ in 0..n {
+                            canister.new_call("increment").perform().await.assert_ok();
+                        }
+
+                        // Wait for the one-way calls to the counter.
+                        replica.shutdown().await;
+
+                        let r = counter
+                            .new_call("get_counter")
+                            .perform()
+                            .await
+                            .decode_one::<u64>()
+                            .unwrap();
+
+                        assert_eq!(r, n);
+                    });
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for thread in threads {
+            thread
+                .join()
+                .expect("A replica saw the calls of another replica.");
+        }
+    }
+
+    #[kit_test]
+    async fn test_call_budget_per_incoming_call(replica: Replica) {
+        let canister = replica.add_canister(BudgetCanister::anonymous());
+        let counter =
+            replica.add_canister(CounterCanister::build(ic_kit::rt::replica::canister_id(1)));
+        let counter_id = counter.canister_id();
+
+        // Both calls are queued at once, so the second message is executed while the first one
+        // awaits its call with the budget.
+        let budgeted = canister
+            .new_call("budgeted_increment")
+            .with_arg(counter_id)
+            .send();
+        let unbudgeted = canister
+            .new_call("unbudgeted_increment")
+            .with_arg(counter_id)
+            .send();
+        let (budgeted, unbudgeted) = (budgeted.await, unbudgeted.await);
+
+        assert_eq!(unbudgeted.decode_one::<u32>().unwrap(), 3);
+        assert_eq!(budgeted.decode_one::<u32>().unwrap(), 1);
+    }
+
+    /// A canister which locks its caller with a `ReentrancyGuard`, in its own module since a
+    /// module can only derive one canister.
+    mod reentrancy {
+        use super::*;
+
+        /// Lock the caller across a call to the method of the counter, and trap once it returns if
+        /// asked to.
+        #[update]
+        async fn guarded_call(
+            counter: Principal,
+            method: String,
+            trap: bool,
+        ) -> Result<u64, String> {
+            let _guard = ReentrancyGuard::caller()?;
+
+            let n = CallBuilder::new(counter, method)
+                .perform_one::<u64>()
+                .await
+                .map_err(|e| format!("{:?}", e))?;
+
+            if trap {
+                ic::trap("Trapped after the call.");
+            }
+
+            Ok(n)
+        }
+
+        #[derive(KitCanister)]
+        pub struct GuardCanister;
+
+        fn guarded<'a>(
+            canister: &'a CanisterHandle,
+            counter: Principal,
+            method: &str,
+            trap: bool,
+        ) -> ic_kit::rt::call::CallBuilder<'a> {
+            canister
+                .new_call("guarded_call")
+                .with_args((counter, method.to_string(), trap))
+        }
+
+        #[kit_test]
+        async fn test_reentrancy_guard(replica: Replica) {
+            let canister = replica.add_canister(GuardCanister::anonymous());
+            let counter =
+                replica.add_canister(CounterCanister::build(ic_kit::rt::replica::canister_id(1)));
+            let counter_id = counter.canister_id();
+
+            // The second call is delivered while the first one awaits the counter.
+            replica.pause();
+            let first = guarded(&canister, counter_id, "increment", false).send();
+            replica.step().await;
+            let second = guarded(&canister, counter_id, "increment", false).send();
+            replica
+                .step_where(|m| m.method.as_deref() == Some("guarded_call"))
+                .await;
+            replica.resume();
+
+            let second = second.await.decode_one::<Result<u64, String>>().unwrap();
+            assert_eq!(
+                second,
+                Err("Another call is already in progress for this key.".into())
+            );
+            let first = first.await.decode_one::<Result<u64, String>>().unwrap();
+            assert_eq!(first, Ok(1));
+
+            // The guard is released once the call returns.
+            let reply = guarded(&canister, counter_id, "increment", false)
+                .perform()
+                .await;
+            assert_eq!(reply.decode_one::<Result<u64, String>>().unwrap(), Ok(2));
+
+            // And when the call to the counter is rejected.
+            let reply = guarded(&canister, counter_id, "missing", false)
+                .perform()
+                .await;
+            assert!(reply.decode_one::<Result<u64, String>>().unwrap().is_err());
+            let reply = guarded(&canister, counter_id, "increment", false)
+                .perform()
+                .await;
+            assert_eq!(reply.decode_one::<Result<u64, String>>().unwrap(), Ok(3));
+        }
+
+        #[kit_test]
+        async fn test_reentrancy_guard_cleanup(replica: Replica) {
+            let canister = replica.add_canister(GuardCanister::anonymous());
+            let counter =
+                replica.add_canister(CounterCanister::build(ic_kit::rt::replica::canister_id(1)));
+            let counter_id = counter.canister_id();
+
+            // The callback traps, so the guard is dropped by the cleanup callback.
+            let reply = guarded(&canister, counter_id, "increment", true)
+                .perform()
+                .await;
+            assert!(reply
+                .rejection_message()
+                .unwrap()
+                .contains("Trapped after the call."));
+
+            let reply = guarded(&canister, counter_id, "increment", false)
+                .perform()
+                .await;
+            assert_eq!(reply.decode_one::<Result<u64, String>>().unwrap(), Ok(2));
+        }
+    }
+
+    /// A canister with a critical section limited by `max_concurrent`.
+    mod concurrency {
+        use super::*;
+
+        /// Increment the counter with at most one call at a time, and two more waiting.
+        #[update(max_concurrent = 1, max_queued = 2)]
+        async fn rebalance(counter: Principal) -> u64 {
+            CallBuilder::new(counter, "increment")
+                .perform_one::<u64>()
+                .await
+                .expect("Expected the call to succeed.")
+        }
+
+        #[derive(KitCanister)]
+        pub struct ConcurrencyCanister;
+
+        #[kit_test]
+        async fn test_concurrency_queue(replica: Replica) {
+            let canister = replica.add_canister(ConcurrencyCanister::anonymous());
+            let counter =
+                replica.add_canister(CounterCanister::build(ic_kit::rt::replica::canister_id(1)));
+            let rebalance = canister
+                .new_call("rebalance")
+                .with_arg(counter.canister_id());
+
+            // The first call awaits the counter while the others arrive.
+            replica.pause();
+            let first = rebalance.send();
+            replica.step().await;
+            let queued = vec![rebalance.send(), rebalance.send()];
+            let refused = rebalance.send();
+            replica.resume();
+
+            assert_eq!(first.await.decode_one::<u64>().unwrap(), 1);
+            let mut replies = Vec::new();
+            for reply in queued {
+                replies.push(reply.await.decode_one::<u64>().unwrap());
+            }
+            assert_eq!(replies, vec![2, 3]);
+            assert!(refused
+                .await
+                .rejection_message()
+                .unwrap()
+                .contains("at its limit of 1 concurrent calls and 2 waiting calls"));
+
+            // The permits are released once the calls are done.
+            assert_eq!(rebalance.perform().await.decode_one::<u64>().unwrap(), 4);
+        }
+    }
+
+    /// A vault which records each withdrawal with the counter, in its own module since a module
+    /// can only derive one canister.
+    mod explore {
+        use super::*;
+        use ic_kit::rt::explore::{ExploreReport, Explorer};
+
+        #[derive(Default)]
+        struct Vault {
+            balance: i64,
+        }
+
+        #[update]
+        fn deposit(vault: &mut Vault, amount: i64) {
+            vault.balance += amount;
+        }
+
+        /// Check the balance before the call to the counter, but only update it once the call
+        /// returns, so another withdrawal can pass the check in the meantime.
+        #[update]
+        async fn withdraw(counter: Principal, amount: i64) -> bool {
+            if with(|vault: &Vault| vault.balance) < amount {
+                return false;
+            }
+
+            CallBuilder::new(counter, "increment")
+                .perform_one::<u64>()
+                .await
+                .expect("Expected the call to succeed.");
+
+            with_mut(|vault: &mut Vault| vault.balance -= amount);
+            true
+        }
+
+        /// Update the balance before the call to the counter.
+        #[update]
+        async fn safe_withdraw(counter: Principal, amount: i64) -> bool {
+            let allowed = with_mut(|vault: &mut Vault| {
+                if vault.balance < amount {
+                    return false;
+                }
+
+                vault.balance -= amount;
+                true
+            });
+
+            if allowed {
+                CallBuilder::new(counter, "increment")
+                    .perform_one::<u64>()
+                    .await
+                    .expect("Expected the call to succeed.");
+            }
+
+            allowed
+        }
+
+        #[query]
+        fn balance(vault: &Vault) -> i64 {
+            vault.balance
+        }
+
+        #[derive(KitCanister)]
+        pub struct VaultCanister;
+
+        /// Withdraw the whole balance twice at once with the method, in every order of the
+        /// messages.
+        fn withdraw_twice(method: &'static str) -> ExploreReport {
+            Explorer::new().run(move |replica| async move {
+                let vault = replica.add_canister(VaultCanister::anonymous());
+                let counter = replica
+                    .add_canister(CounterCanister::build(ic_kit::rt::replica::canister_id(1)));
+                let counter_id = counter.canister_id();
+
+                vault
+                    .new_call("deposit")
+                    .with_arg(100i64)
+                    .perform()
+                    .await
+                    .assert_ok();
+
+                replica
+                    .interleave(vec![
+                        vault.new_call(method).with_args((counter_id, 100i64)),
+                        vault.new_call(method).with_args((counter_id, 100i64)),
+                    ])
+                    .await;
+
+                let balance = vault
+                    .new_call("balance")
+                    .perform()
+                    .await
+                    .decode_one::<i64>()
+                    .unwrap();
+                assert!(balance >= 0, "The balance is {}.", balance);
+            })
+        }
+
+        #[test]
+        fn test_explore_finds_double_withdrawal() {
+            let report = withdraw_twice("withdraw");
+            assert!(!report.is_ok());
+            assert!(!report.exhausted);
+            let failure = report.failure.expect("Expected an interleaving to fail.");
+            assert_eq!(failure.message, "The balance is -100.");
+            assert!(failure.schedule.len() >= 4);
+        }
+
+        #[test]
+        fn test_explore_all_interleavings() {
+            let report = withdraw_twice("safe_withdraw");
+            report.assert_ok();
+            assert!(report.exhausted);
+            assert!(report.schedules > 1);
+        }
+    }
+
+    /// A relay which increments the counter, in its own module since a module can only derive one
+    /// canister.
+    mod chaos {
+        use super::*;
+        use ic::{CallError, RejectionCode};
+        use ic_kit::rt::chaos::Chaos;
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+        use std::time::Duration;
+
+        #[update]
+        async fn relay(counter: Principal) -> u64 {
+            CallBuilder::new(counter, "increment")
+                .perform_one::<u64>()
+                .await
+                .expect("Expected the call to succeed.")
+        }
+
+        /// Retry the call while it's rejected with `SysTransient`, which means it never reached
+        /// the counter.
+        #[update]
+        async fn retrying_relay(counter: Principal) -> u64 {
+            loop {
+                match CallBuilder::new(counter, "increment")
+                    .perform_one::<u64>()
+                    .await
+                {
+                    Ok(count) => return count,
+                    Err(CallError::Rejected(RejectionCode::SysTransient, _)) => continue,
+                    Err(e) => ic::trap(&format!("The call failed: {}", e)),
+                }
+            }
+        }
+
+        #[derive(KitCanister)]
+        pub struct RelayCanister;
+
+        /// Only reject the calls of the canisters, half of the time.
+        fn transient_rejects() -> Chaos {
+            Chaos::new(0)
+                .with_transient_rejects(0.5)
+                .with_latency(0.0, 1)
+                .with_restarts(0.0)
+                .with_clock_jumps(0.0, Duration::from_secs(1))
+        }
+
+        /// Increment the counter three times through the relay with the method.
+        async fn relay_three_times(replica: Replica, method: &'static str) {
+            let relay =
+                replica.add_canister(RelayCanister::build(ic_kit::rt::replica::canister_id(0)));
+            let counter =
+                replica.add_canister(CounterCanister::build(ic_kit::rt::replica::canister_id(1)));
+
+            for expected in 1..=3u64 {
+                let reply = relay
+                    .new_call(method)
+                    .with_arg(counter.canister_id())
+                    .perform()
+                    .await;
+                reply.assert_ok();
+                assert_eq!(reply.decode_one::<u64>().unwrap(), expected);
+            }
+        }
+
+        #[test]
+        fn test_chaos_finds_transient_rejects() {
+            let chaos = transient_rejects();
+            let report = chaos.soak(20, |replica| relay_three_times(replica, "relay"));
+            assert!(!report.is_ok());
+            let failure = report.failure.expect("Expected a seed to fail.");
+            assert!(failure
+                .message
+                .contains("The call was rejected by the chaos mode of the replica."));
+            assert_eq!(report.runs, failure.seed + 1);
+
+            // The seed makes the test fail the same way again.
+            let rerun = catch_unwind(AssertUnwindSafe(|| {
+                chaos.rerun(failure.seed, |replica| relay_three_times(replica, "relay"))
+            }));
+            let payload = rerun.expect_err("Expected the rerun to fail.");
+            assert_eq!(payload.downcast_ref::<String>(), Some(&failure.message));
+        }
+
+        #[test]
+        fn test_chaos_retries() {
+            let report = transient_rejects()
+                .soak(20, |replica| relay_three_times(replica, "retrying_relay"));
+            report.assert_ok();
+            assert_eq!(report.runs, 20);
+
+            // Without the faults the relay which doesn't retry passes too.
+            transient_rejects()
+                .with_transient_rejects(0.0)
+                .soak(20, |replica| relay_three_times(replica, "relay"))
+                .assert_ok();
+        }
+    }
+
+    async fn call_budgeted(
+        canister: &CanisterHandle<'_>,
+        counter: Principal,
+        method: &str,
+        max_calls: u32,
+        trap_policy: bool,
+        trap: bool,
+    ) -> ic_kit::rt::call::CallReply {
+        canister
+            .new_call("budgeted_calls")
+            .with_args((
+                counter,
+                method.to_string(),
+                max_calls,
+                trap_policy,
+                3u32,
+                trap,
+            ))
+            .perform()
+            .await
+    }
+
+    async fn count_budgets(canister: &CanisterHandle<'_>) -> u64 {
+        canister
+            .new_call("active_budgets")
+            .perform()
+            .await
+            .decode_one::<u64>()
+            .unwrap()
+    }
+
+    #[kit_test]
+    async fn test_call_budget_policies(replica: Replica) {
+        let canister = replica.add_canister(BudgetCanister::anonymous());
+        let counter =
+            replica.add_canister(CounterCanister::build(ic_kit::rt::replica::canister_id(1)));
+        let counter_id = counter.canister_id();
+
+        // The calls over the limit fail with the fail policy.
+        let reply = call_budgeted(&canister, counter_id, "increment", 2, false, false).await;
+        assert_eq!(reply.decode_one::<Result<u32, String>>().unwrap(), Ok(2));
+
+        // And trap with the trap policy.
+        let reply = call_budgeted(&canister, counter_id, "increment", 2, true, false).await;
+        assert!(reply
+            .rejection_message()
+            .unwrap()
+            .contains("The message made more than 2 calls, the limit of its CallBudget."));
+
+        let reply = call_budgeted(&canister, counter_id, "increment", 3, true, false).await;
+        assert_eq!(reply.decode_one::<Result<u32, String>>().unwrap(), Ok(3));
+
+        assert_eq!(count_budgets(&canister).await, 0);
+    }
+
+    #[kit_test]
+    async fn test_call_budget_cleanup(replica: Replica) {
+        let canister = replica.add_canister(BudgetCanister::anonymous());
+        let counter =
+            replica.add_canister(CounterCanister::build(ic_kit::rt::replica::canister_id(1)));
+        let counter_id = counter.canister_id();
+
+        // The call to the counter is rejected.
+        let reply = call_budgeted(&canister, counter_id, "missing", 1, true, false).await;
+        assert!(reply.decode_one::<Result<u32, String>>().unwrap().is_err());
+        assert_eq!(count_budgets(&canister).await, 0);
+
+        // The callback traps, so the future is dropped by the cleanup callback.
+        let reply = call_budgeted(&canister, counter_id, "increment", 3, true, true).await;
+        assert!(reply
+            .rejection_message()
+            .unwrap()
+            .contains("Trapped after the calls."));
+        assert_eq!(count_budgets(&canister).await, 0);
+
+        // The first message traps.
+        let reply = canister.new_call("budgeted_trap").perform().await;
+        assert!(reply
+            .rejection_message()
+            .unwrap()
+            .contains("Trapped with a budget."));
+        assert_eq!(count_budgets(&canister).await, 0);
+    }
 }
@@ -1,7 +1,7 @@
 use ic_kit::prelude::*;
 use std::collections::HashMap;
 
-#[derive(Default)]
+#[derive(Default, CandidType, Deserialize, KitStable)]
 struct Registry {
     names: HashMap<Principal, String>,
 }
@@ -68,4 +68,137 @@ mod tests {
 
         assert_eq!(bob_name, Some("Bob".to_string()));
     }
+
+    #[kit_test]
+    async fn test_upgrade(replica: Replica) {
+        let ns = replica.add_canister(NamingSystemCanister::anonymous());
+
+        ns.new_call("register")
+            .with_caller(*users::ALICE)
+            .with_arg("Alice")
+            .perform()
+            .await
+            .assert_ok();
+
+        ns.upgrade(NamingSystemCanister::build(ns.canister_id()), ())
+            .await
+            .assert_ok();
+
+        let alice_name = ns
+            .new_call("get_name")
+            .with_arg(*users::ALICE)
+            .perform()
+            .await
+            .decode_one::<Option<String>>()
+            .unwrap();
+
+        assert_eq!(alice_name, Some("Alice".to_string()));
+    }
+
+    /// The state dumped by a replica is restored by another one, as on another machine.
+    #[kit_test]
+    async fn test_dump_and_load(replica: Replica) {
+        let ns = replica.add_canister(NamingSystemCanister::anonymous());
+        let id = ns.canister_id();
+        replica.name(id, "naming_system");
+
+        ns.new_call("register")
+            .with_caller(*users::ALICE)
+            .with_arg("Alice")
+            .perform()
+            .await
+            .assert_ok();
+
+        let path = std::env::temp_dir().join(format!("ic-kit-dump-{}.json", std::process::id()));
+        replica.dump(&path).await.unwrap();
+
+        let state = ic_kit::rt::dump::ReplicaState::read(&path).unwrap();
+        assert_eq!(state.canisters.len(), 1);
+        assert_eq!(state.canisters[0].id, id);
+        assert_eq!(state.canisters[0].name.as_deref(), Some("naming_system"));
+        assert!(!state.canisters[0].stable_memory().unwrap().is_empty());
+
+        let restored = Replica::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let ns = restored.add_canister(NamingSystemCanister::build(id));
+        ns.init_or_restore().await.assert_ok();
+        assert_eq!(ns.balance().await, state.canisters[0].balance);
+
+        let alice_name = ns
+            .new_call("get_name")
+            .with_arg(*users::ALICE)
+            .perform()
+            .await
+            .decode_one::<Option<String>>()
+            .unwrap();
+
+        assert_eq!(alice_name, Some("Alice".to_string()));
+    }
+
+    mod migration {
+        use super::*;
+
+        #[derive(Default, CandidType, Deserialize, KitStable)]
+        #[stable_version(1)]
+        struct Settings {
+            fee: u64,
+            upgrades: u64,
+        }
+
+        #[update]
+        fn set_fee(settings: &mut Settings, fee: u64) {
+            settings.fee = fee;
+        }
+
+        #[query]
+        fn config(settings: &Settings) -> (u64, u64) {
+            (settings.fee, settings.upgrades)
+        }
+
+        #[post_upgrade]
+        fn post_upgrade(settings: &mut Settings, fee: Option<u64>) {
+            settings.upgrades += 1;
+            if let Some(fee) = fee {
+                settings.fee = fee;
+            }
+        }
+
+        #[derive(KitCanister)]
+        pub struct SettingsCanister;
+
+        async fn get_settings(canister: &CanisterHandle<'_>) -> (u64, u64) {
+            canister
+                .new_call("config")
+                .perform()
+                .await
+                .decode::<(u64, u64)>()
+                .unwrap()
+        }
+
+        #[kit_test]
+        async fn test_upgrade_with_hook(replica: Replica) {
+            let canister = replica.add_canister(SettingsCanister::anonymous());
+            let id = canister.canister_id();
+
+            canister
+                .new_call("set_fee")
+                .with_arg(10u64)
+                .perform()
+                .await
+                .assert_ok();
+
+            canister
+                .upgrade_with_arg(SettingsCanister::build(id), None::<u64>)
+                .await
+                .assert_ok();
+            assert_eq!(get_settings(&canister).await, (10, 1));
+
+            canister
+                .upgrade_with_arg(SettingsCanister::build(id), Some(20u64))
+                .await
+                .assert_ok();
+            assert_eq!(get_settings(&canister).await, (20, 2));
+        }
+    }
 }
@@ -0,0 +1,297 @@
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use candid::utils::ArgumentEncoder;
+use candid::Principal;
+use candid::{encode_args, encode_one, CandidType};
+
+use crate::message::{EntryMode, IncomingRequestId, RequestId};
+use crate::types::{RejectionCode, CANDID_EMPTY_ARG};
+
+/// The canister's environment that should be used during a message.
+pub struct Env {
+    /// If set, the canister's cycle balance is set to this amount before the message is executed,
+    /// otherwise the canister keeps its balance from the previous messages.
+    pub balance: Option<u128>,
+    /// The type of the entry point that should be simulated, this enables trapping when a the
+    /// method is calling a system api call that it should not be able to call during the
+    /// execution of that entry point.
+    pub entry_mode: EntryMode,
+    /// The principal id of the sender.
+    pub sender: Principal,
+    /// The method to call. Only applies to update/query calls.
+    pub method_name: Option<String>,
+    /// If set, the call was made by another canister, so its reply is an inter-canister response
+    /// and is limited to the size of those.
+    pub inter_canister: bool,
+    /// The cycles provided to the canister during this call.
+    pub cycles_available: u128,
+    /// The amount of refunded cycles.
+    pub cycles_refunded: u128,
+    /// The arguments provided to the canister during this call.
+    pub args: Vec<u8>,
+    /// The reply rejection code. Default to `0`
+    pub rejection_code: RejectionCode,
+    /// The rejection message. Only applicable when `rejection_code != 0`
+    pub rejection_message: String,
+    /// The current time in nanoseconds, if not set the time of the replica is used, which is the
+    /// system time unless it was fixed with `Replica::set_time`.
+    pub time: Option<u64>,
+}
+
+/// A call that has made to another canister.
+#[derive(Debug)]
+pub struct CanisterCall {
+    pub sender: Principal,
+    pub request_id: RequestId,
+    /// The incoming message of the sender which made this call, if it's made by a canister.
+    pub parent: Option<IncomingRequestId>,
+    pub callee: Principal,
+    pub method: String,
+    pub payment: u128,
+    pub arg: Vec<u8>,
+    /// If set, the call was made by a composite query, and the callee runs it as a query.
+    pub query: bool,
+}
+
+impl Default for Env {
+    fn default() -> Self {
+        Env {
+            balance: None,
+            entry_mode: EntryMode::CustomTask,
+            sender: Principal::anonymous(),
+            method_name: None,
+            inter_canister: false,
+            cycles_available: 0,
+            cycles_refunded: 0,
+            args: CANDID_EMPTY_ARG.to_vec(),
+            rejection_code: RejectionCode::NoError,
+            rejection_message: String::new(),
+            time: None,
+        }
+    }
+}
+
+impl Env {
+    /// Create a new env for an update call.
+    pub fn update<S: Into<String>>(method_name: S) -> Self {
+        Self::default()
+            .with_entry_mode(EntryMode::Update)
+            .with_method_name(method_name)
+    }
+
+    /// Create a new env for a query call.
+    pub fn query<S: Into<String>>(method_name: S) -> Self {
+        Self::default()
+            .with_entry_mode(EntryMode::Query)
+            .with_method_name(method_name)
+    }
+
+    /// Create a new env for a query call made by a composite query, which can only run the query
+    /// and the composite query methods of the canister.
+    pub fn composite_query<S: Into<String>>(method_name: S) -> Self {
+        Self::default()
+            .with_entry_mode(EntryMode::CompositeQuery)
+            .with_method_name(method_name)
+    }
+
+    /// Create a new env for a call to the init function.
+    pub fn init() -> Self {
+        Self::default().with_entry_mode(EntryMode::Init)
+    }
+
+    /// Create a new env for a call to the pre_upgrade function.
+    pub fn pre_upgrade() -> Self {
+        Self::default().with_entry_mode(EntryMode::PreUpgrade)
+    }
+
+    /// Create a new env for a call to the post_upgrade function.
+    pub fn post_upgrade() -> Self {
+        Self::default().with_entry_mode(EntryMode::PostUpgrade)
+    }
+
+    /// Create a new env for a call to the heartbeat function.
+    pub fn heartbeat() -> Self {
+        Self::default().with_entry_mode(EntryMode::Heartbeat)
+    }
+
+    /// Create a new env for a call to the global timer function.
+    pub fn global_timer() -> Self {
+        Self::default().with_entry_mode(EntryMode::GlobalTimer)
+    }
+
+    /// Create a new env for a call to the inspect_message function, for a message to the given
+    /// method. The message is rejected unless the function accepts it.
+    pub fn inspect_message<S: Into<String>>(method_name: S) -> Self {
+        Self::default()
+            .with_entry_mode(EntryMode::InspectMessage)
+            .with_method_name(method_name)
+    }
+
+    /// Create a new env for a reply callback, the arguments are the data of the reply. The
+    /// callbacks can only be run as a custom task, see `CanisterHandle::custom` of the runtime.
+    pub fn reply_callback() -> Self {
+        Self::default().with_entry_mode(EntryMode::ReplyCallback)
+    }
+
+    /// Create a new env for a reject callback with the given rejection, see
+    /// [`Env::reply_callback`].
+    pub fn reject_callback<S: Into<String>>(
+        rejection_code: RejectionCode,
+        rejection_message: S,
+    ) -> Self {
+        Self::default()
+            .with_entry_mode(EntryMode::RejectCallback)
+            .with_rejection(rejection_code, rejection_message)
+    }
+
+    /// Create a new env for a cleanup callback, see [`Env::reply_callback`].
+    pub fn cleanup_callback() -> Self {
+        Self::default().with_entry_mode(EntryMode::CleanupCallback)
+    }
+
+    /// Set the canister's cycle balance before this call.
+    pub fn with_balance(mut self, balance: u128) -> Self {
+        self.balance = Some(balance);
+        self
+    }
+
+    /// Use the provided time for this env.
+    pub fn with_time(mut self, time: u64) -> Self {
+        self.time = Some(time);
+        self
+    }
+
+    /// Use the given entry mode in this env.
+    pub fn with_entry_mode(mut self, mode: EntryMode) -> Self {
+        self.entry_mode = mode;
+        self
+    }
+
+    /// Provide this environment with the given principal id as the caller.
+    pub fn with_sender(mut self, sender: Principal) -> Self {
+        self.sender = sender;
+        self
+    }
+
+    /// Provide the given env with the given method name to execute.
+    pub fn with_method_name<S: Into<String>>(mut self, method_name: S) -> Self {
+        self.method_name = Some(method_name.into());
+        self
+    }
+
+    /// Mark the call as made by another canister, see [`Env::inter_canister`].
+    pub fn with_inter_canister(mut self) -> Self {
+        self.inter_canister = true;
+        self
+    }
+
+    /// Provide the current env with the given amount of cycles to execute.
+    pub fn with_cycles_available(mut self, cycles: u128) -> Self {
+        self.cycles_available = cycles;
+        self
+    }
+
+    /// Provide the current env with the given amount of refunded cycles, only applicable
+    /// if this is reply/reject callback.
+    pub fn with_cycles_refunded(mut self, cycles: u128) -> Self {
+        self.cycles_refunded = cycles;
+        self
+    }
+
+    /// The arguments in this environment, in a reply mode this is the data returned to the
+    /// canister.
+    pub fn with_raw_args<A: Into<Vec<u8>>>(mut self, argument: A) -> Self {
+        self.args = argument.into();
+        self
+    }
+
+    /// Encode the provided tuple using candid and use it as arguments during this execution.
+    pub fn with_args<T: ArgumentEncoder>(mut self, arguments: T) -> Self {
+        self.args = encode_args(arguments).unwrap();
+        self
+    }
+
+    /// Shorthand for `with_args((argument, ))` to pass tuples with only one element to the call.
+    pub fn with_arg<T: CandidType>(mut self, argument: T) -> Self {
+        self.args = encode_one(argument).unwrap();
+        self
+    }
+
+    /// Set this environment's rejection code the provided value, you must also set a rejection
+    /// message if this is not equal to NoError.
+    pub fn with_rejection_code(mut self, rejection_code: RejectionCode) -> Self {
+        self.rejection_code = rejection_code;
+        self
+    }
+
+    /// Set the rejection message on this env, only applicable if rejection_code is not zero.
+    pub fn with_rejection_message<S: Into<String>>(mut self, rejection_message: S) -> Self {
+        self.rejection_message = rejection_message.into();
+        self
+    }
+
+    /// Shorthand for setting both the rejection code and the rejection message.
+    pub fn with_rejection<S: Into<String>>(
+        self,
+        rejection_code: RejectionCode,
+        rejection_message: S,
+    ) -> Self {
+        self.with_rejection_code(rejection_code)
+            .with_rejection_message(rejection_message)
+    }
+}
+
+impl Env {
+    /// Return a name we can use to get the method from the symbol table.
+    pub fn get_entry_point_name(&self) -> String {
+        match &self.entry_mode {
+            EntryMode::Init => "canister_init".to_string(),
+            EntryMode::PreUpgrade => "canister_pre_upgrade".to_string(),
+            EntryMode::PostUpgrade => "canister_post_upgrade".to_string(),
+            EntryMode::Heartbeat => "canister_heartbeat".to_string(),
+            EntryMode::GlobalTimer => "canister_global_timer".to_string(),
+            EntryMode::InspectMessage => "canister_inspect_message".to_string(),
+            EntryMode::Update => {
+                format!(
+                    "canister_update {}",
+                    self.method_name.as_ref().unwrap_or(&String::new())
+                )
+            }
+            EntryMode::Query => format!(
+                "canister_query {}",
+                self.method_name.as_ref().unwrap_or(&String::new())
+            ),
+            EntryMode::CompositeQuery => format!(
+                "canister_composite_query {}",
+                self.method_name.as_ref().unwrap_or(&String::new())
+            ),
+            EntryMode::ReplyCallback => "reply callback".to_string(),
+            EntryMode::RejectCallback => "reject callback".to_string(),
+            EntryMode::CleanupCallback => "cleanup callback".to_string(),
+            EntryMode::CustomTask => "ic-kit: custom".to_string(),
+        }
+    }
+
+    /// Returns the second possible name of this entry point.
+    pub fn get_possible_entry_point_name(&self) -> String {
+        match &self.entry_mode {
+            EntryMode::Update => {
+                format!(
+                    "canister_query {}",
+                    self.method_name.as_ref().unwrap_or(&String::new())
+                )
+            }
+            EntryMode::Query => format!(
+                "canister_update {}",
+                self.method_name.as_ref().unwrap_or(&String::new())
+            ),
+            EntryMode::CompositeQuery => format!(
+                "canister_query {}",
+                self.method_name.as_ref().unwrap_or(&String::new())
+            ),
+            _ => self.get_entry_point_name(),
+        }
+    }
+}
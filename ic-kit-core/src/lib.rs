@@ -0,0 +1,24 @@
+//! This crate provides the types shared by the bindings and the runtime of IC-Kit, it's `no_std`
+//! so embedded tooling and alternative runtimes can use them without the rest of the kit, this is
+//! part of the Psychedelic's Canister Development kit, [IC-Kit](https://github.com/psychedelic/ic-kit).
+//!
+//! The types are re-exported unchanged by `ic-kit-sys` and `ic-kit-runtime`. The `std` feature,
+//! enabled by default, implements `std::error::Error` for the errors, and the `candid` feature
+//! adds the [`env`] module, whose types hold candid principals.
+
+#![no_std]
+
+extern crate alloc;
+
+#[cfg(feature = "std")]
+extern crate std;
+
+/// The environment of a message executed by a canister, and the calls between canisters.
+#[cfg(feature = "candid")]
+pub mod env;
+
+/// The ids and the entry points of the messages.
+pub mod message;
+
+/// The common types related to the system API.
+pub mod types;
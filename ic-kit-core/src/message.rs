@@ -0,0 +1,37 @@
+///  A request ID for a request that is coming to this canister from the outside.
+pub type IncomingRequestId = RequestId;
+/// A request ID for a request that this canister has submitted.
+pub type OutgoingRequestId = RequestId;
+
+/// An opaque request id, which is unique in the replica it was created by.
+#[derive(Hash, Clone, Copy, Ord, PartialOrd, Eq, PartialEq, Debug)]
+pub struct RequestId(u64);
+
+impl RequestId {
+    /// Create the request id with the given number, the ids are assigned by the runtime so the
+    /// replicas of the tests running in parallel do not share a counter.
+    pub fn new(id: u64) -> Self {
+        Self(id)
+    }
+}
+
+/// The entry method for a request.
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone)]
+pub enum EntryMode {
+    Init,
+    PreUpgrade,
+    PostUpgrade,
+    Heartbeat,
+    GlobalTimer,
+    InspectMessage,
+    Update,
+    Query,
+    /// A composite query, which can call the query methods of other canisters. The query calls
+    /// made by canisters are also sent in this mode, and run as a plain query unless the callee
+    /// exports the method as a composite query.
+    CompositeQuery,
+    ReplyCallback,
+    RejectCallback,
+    CleanupCallback,
+    CustomTask,
+}
@@ -0,0 +1,214 @@
+//! Generate typed clients for a canister from a trait describing its interface.
+
+use proc_macro2::{Ident, Span, TokenStream};
+use quote::quote;
+use serde::Deserialize;
+use serde_tokenstream::from_tokenstream;
+use syn::{spanned::Spanned, Error};
+
+#[derive(Deserialize)]
+struct Config {
+    name: Option<String>,
+}
+
+/// A method of the canister as described in the trait.
+struct ClientMethod {
+    docs: Vec<syn::Attribute>,
+    ident: Ident,
+    args: Vec<Ident>,
+    types: Vec<syn::Type>,
+    output: ClientOutput,
+}
+
+/// How the response of a method should be decoded.
+enum ClientOutput {
+    /// The method returns a tuple, including the empty tuple.
+    Tuple(syn::Type),
+    /// The method returns a single value.
+    One(syn::Type),
+}
+
+/// Process the trait and generate the client structs.
+pub fn gen_client_code(attr: TokenStream, item: TokenStream) -> Result<TokenStream, Error> {
+    let attrs = from_tokenstream::<Config>(&attr)?;
+    let item_trait = syn::parse2::<syn::ItemTrait>(item.clone()).map_err(|e| {
+        Error::new(
+            item.span(),
+            format!("#[kit_client] must be above a trait. \n{}", e),
+        )
+    })?;
+
+    if !item_trait.generics.params.is_empty() {
+        return Err(Error::new(
+            item_trait.generics.span(),
+            "#[kit_client] can not be used on a trait with generic parameters.",
+        ));
+    }
+
+    let visibility = &item_trait.vis;
+    let docs = item_trait
+        .attrs
+        .iter()
+        .filter(|attr| attr.path.is_ident("doc"))
+        .collect::<Vec<_>>();
+    let client_name = attrs
+        .name
+        .unwrap_or_else(|| format!("{}Client", item_trait.ident));
+    let client = Ident::new(&client_name, Span::call_site());
+    let replica_client = Ident::new(&format!("Replica{}", client_name), Span::call_site());
+
+    let methods = item_trait
+        .items
+        .iter()
+        .map(collect_method)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut canister_methods = Vec::with_capacity(methods.len());
+    let mut replica_methods = Vec::with_capacity(methods.len());
+
+    for method in methods {
+        let docs = &method.docs;
+        let ident = &method.ident;
+        let method_name = ident.to_string();
+        let args = &method.args;
+        let types = &method.types;
+
+        let (ty, perform, decode) = match &method.output {
+            ClientOutput::Tuple(ty) => (ty, quote! { perform }, quote! { decode }),
+            ClientOutput::One(ty) => (ty, quote! { perform_one }, quote! { decode_one }),
+        };
+
+        canister_methods.push(quote! {
+            #(#docs)*
+            pub async fn #ident(&self, #(#args: #types),*) -> Result<#ty, ic_kit::ic::CallError> {
+                ic_kit::ic::CallBuilder::new(self.canister_id, #method_name)
+                    .with_args((#(#args,)*))
+                    .#perform::<#ty>()
+                    .await
+            }
+        });
+
+        replica_methods.push(quote! {
+            #(#docs)*
+            pub async fn #ident(&self, #(#args: #types),*) -> Result<#ty, ic_kit::ic::CallError> {
+                self.handle
+                    .new_call(#method_name)
+                    .with_args((#(#args,)*))
+                    .with_caller(self.caller)
+                    .perform()
+                    .await
+                    .#decode::<#ty>()
+            }
+        });
+    }
+
+    Ok(quote! {
+        #(#docs)*
+        #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+        #visibility struct #client {
+            canister_id: ic_kit::Principal,
+        }
+
+        impl #client {
+            /// Create a new client for the canister with the given id.
+            pub fn new(canister_id: ic_kit::Principal) -> Self {
+                Self { canister_id }
+            }
+
+            /// Return the id of the canister this client calls.
+            pub fn canister_id(&self) -> ic_kit::Principal {
+                self.canister_id
+            }
+
+            #(#canister_methods)*
+        }
+
+        #(#docs)*
+        #[cfg(not(target_family = "wasm"))]
+        #visibility struct #replica_client<'a> {
+            handle: &'a ic_kit::rt::handle::CanisterHandle<'a>,
+            caller: ic_kit::Principal,
+        }
+
+        #[cfg(not(target_family = "wasm"))]
+        impl<'a> #replica_client<'a> {
+            /// Create a new client which calls the canister through the replica, the calls are
+            /// made by the anonymous principal by default.
+            pub fn new(handle: &'a ic_kit::rt::handle::CanisterHandle<'a>) -> Self {
+                Self {
+                    handle,
+                    caller: ic_kit::Principal::anonymous(),
+                }
+            }
+
+            /// Make the calls from the given principal.
+            pub fn with_caller<I: Into<ic_kit::Principal>>(mut self, caller: I) -> Self {
+                self.caller = caller.into();
+                self
+            }
+
+            #(#replica_methods)*
+        }
+    })
+}
+
+fn collect_method(item: &syn::TraitItem) -> Result<ClientMethod, Error> {
+    let method = match item {
+        syn::TraitItem::Method(method) => method,
+        item => {
+            return Err(Error::new(
+                item.span(),
+                "#[kit_client] trait can only contain methods.",
+            ))
+        }
+    };
+
+    let signature = &method.sig;
+
+    if !signature.generics.params.is_empty() {
+        return Err(Error::new(
+            signature.generics.span(),
+            "#[kit_client] methods can not have generic parameters.",
+        ));
+    }
+
+    let mut args = Vec::new();
+    let mut types = Vec::new();
+
+    for (id, arg) in signature.inputs.iter().enumerate() {
+        match arg {
+            // Allow the methods to be written with or without the `&self`.
+            syn::FnArg::Receiver(_) => continue,
+            syn::FnArg::Typed(syn::PatType { pat, ty, .. }) => {
+                if let syn::Pat::Ident(syn::PatIdent { ident, .. }) = pat.as_ref() {
+                    args.push(ident.clone());
+                } else {
+                    args.push(Ident::new(&format!("arg_{}", id), pat.span()));
+                }
+
+                types.push(*ty.clone());
+            }
+        }
+    }
+
+    let output = match &signature.output {
+        syn::ReturnType::Default => ClientOutput::Tuple(syn::parse_quote! { () }),
+        syn::ReturnType::Type(_, ty) => match ty.as_ref() {
+            syn::Type::Tuple(_) => ClientOutput::Tuple(*ty.clone()),
+            _ => ClientOutput::One(*ty.clone()),
+        },
+    };
+
+    Ok(ClientMethod {
+        docs: method
+            .attrs
+            .iter()
+            .filter(|attr| attr.path.is_ident("doc"))
+            .cloned()
+            .collect(),
+        ident: signature.ident.clone(),
+        args,
+        types,
+        output,
+    })
+}
@@ -0,0 +1,94 @@
+//! Generate the debug mixin of the `export_debug!` macro.
+
+use crate::entry::{gen_entry_point_code, EntryPoint};
+use crate::export_service::export_mixin;
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::parse::{Parse, ParseStream};
+use syn::punctuated::Punctuated;
+use syn::{Error, Ident, Token, Type};
+
+/// The name of the mixin and the states it can dump, by their key.
+pub struct DebugInput {
+    name: Ident,
+    states: Vec<(Ident, Type)>,
+}
+
+impl Parse for DebugInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let name = input.parse()?;
+        let mut states = Vec::new();
+
+        if input.parse::<Option<Token![,]>>()?.is_some() {
+            let entries =
+                Punctuated::<(Ident, Type), Token![,]>::parse_terminated_with(input, |input| {
+                    let key = input.parse()?;
+                    input.parse::<Token![:]>()?;
+                    Ok((key, input.parse()?))
+                })?;
+            states.extend(entries);
+        }
+
+        Ok(Self { name, states })
+    }
+}
+
+/// Generate the hidden `__debug_keys` and `__debug_dump` queries, which can only be called by the
+/// controllers, grouped in a mixin. They are only compiled when the `debug-endpoints` feature of
+/// ic-kit is enabled, so they can not be exposed by accident.
+pub fn gen_debug_code(input: DebugInput) -> Result<TokenStream, Error> {
+    let DebugInput { name, states } = input;
+    let (keys, types): (Vec<_>, Vec<_>) = states
+        .into_iter()
+        .map(|(key, ty)| (key.to_string(), ty))
+        .unzip();
+
+    for (i, key) in keys.iter().enumerate() {
+        if keys[..i].contains(key) {
+            return Err(Error::new(
+                name.span(),
+                format!(
+                    "export_debug! has more than one state with the key '{}'.",
+                    key
+                ),
+            ));
+        }
+    }
+
+    let list = gen_entry_point_code(
+        EntryPoint::Query,
+        quote! { name = "__debug_keys", hidden, only_controller = true },
+        quote! {
+            fn __ic_kit_debug_keys() -> Vec<String> {
+                vec![#(#keys.to_string()),*]
+            }
+        },
+    )?;
+
+    let dump = gen_entry_point_code(
+        EntryPoint::Query,
+        quote! { name = "__debug_dump", hidden, only_controller = true },
+        quote! {
+            fn __ic_kit_debug_dump(
+                key: String,
+                format: ic_kit::debug::DebugFormat,
+                path: Option<String>,
+            ) -> Result<String, String> {
+                match key.as_str() {
+                    #(#keys => ic_kit::debug::dump::<#types>(format, path.as_deref()),)*
+                    _ => Err(format!("No state is registered with the key '{}'.", key)),
+                }
+            }
+        },
+    )?;
+
+    let mixin = export_mixin(name);
+
+    Ok(quote! {
+        ic_kit::__debug_endpoints! {
+            #list
+            #dump
+            #mixin
+        }
+    })
+}
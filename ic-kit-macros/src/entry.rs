@@ -3,8 +3,9 @@
 //! [1]: <https://internetcomputer.org/docs/current/references/ic-interface-spec/#entry-points>
 
 use crate::export_service::declare;
-use proc_macro2::{Ident, Span, TokenStream};
-use quote::quote;
+use crate::stable::stable_calls;
+use proc_macro2::{Ident, Span, TokenStream, TokenTree};
+use quote::{quote, ToTokens};
 use serde::Deserialize;
 use serde_tokenstream::from_tokenstream;
 use std::fmt::Formatter;
@@ -43,6 +44,11 @@ impl EntryPoint {
         }
     }
 
+    /// Return true if the entry point can receive candid arguments.
+    pub fn accepts_args(&self) -> bool {
+        !matches!(self, EntryPoint::PreUpgrade | EntryPoint::Heartbeat)
+    }
+
     pub fn is_inspect_message(&self) -> bool {
         match &self {
             EntryPoint::InspectMessage => true,
@@ -56,6 +62,19 @@ struct Config {
     name: Option<String>,
     guard: Option<String>,
     hidden: Option<bool>,
+    manual_reply: Option<bool>,
+    only_controller: Option<bool>,
+    only: Option<Vec<String>>,
+    payable: Option<bool>,
+    max_cycles: Option<u64>,
+    max_reply_size: Option<u64>,
+    instrument: Option<bool>,
+    dedup: Option<bool>,
+    max_concurrent: Option<u32>,
+    max_queued: Option<u32>,
+    decode_error: Option<String>,
+    reply_errors: Option<bool>,
+    composite: Option<bool>,
 }
 
 /// Process a rust syntax and generate the code for processing it.
@@ -64,13 +83,22 @@ pub fn gen_entry_point_code(
     attr: TokenStream,
     item: TokenStream,
 ) -> Result<TokenStream, Error> {
-    let attrs = from_tokenstream::<Config>(&attr)?;
-    let fun: syn::ItemFn = syn::parse2::<syn::ItemFn>(item.clone()).map_err(|e| {
+    let mut attrs = from_tokenstream::<Config>(&normalize_flags(attr))?;
+    let mut fun: syn::ItemFn = syn::parse2::<syn::ItemFn>(item.clone()).map_err(|e| {
         Error::new(
             item.span(),
             format!("#[{0}] must be above a function. \n{1}", entry_point, e),
         )
     })?;
+
+    // The access control attributes that come after the entry point attribute.
+    for access in take_access_attrs(&mut fun)? {
+        let access = from_tokenstream::<Config>(&access)?;
+        attrs.only_controller = attrs.only_controller.or(access.only_controller);
+        if let Some(roles) = access.only {
+            attrs.only.get_or_insert_with(Vec::new).extend(roles);
+        }
+    }
     let signature = &fun.sig;
     let visibility = &fun.vis;
     let generics = &signature.generics;
@@ -114,14 +142,18 @@ pub fn gen_entry_point_code(
             ));
         }
 
-        if attrs.name.is_some() {
+        // The flags are only rejected when they are enabled, `manual_reply = false` is allowed,
+        // and so is the name the hook is exported with.
+        let enabled = |flag: Option<bool>| flag.unwrap_or(false);
+
+        if matches!(&attrs.name, Some(name) if *name != entry_point.to_string()) {
             return Err(Error::new(
                 Span::call_site(),
                 format!("#[{}] function cannot be renamed.", entry_point),
             ));
         }
 
-        if attrs.hidden.is_some() {
+        if enabled(attrs.hidden) {
             return Err(Error::new(
                 Span::call_site(),
                 format!("#[{}] function cannot be hidden.", entry_point),
@@ -135,7 +167,65 @@ pub fn gen_entry_point_code(
             ));
         }
 
-        if is_async {
+        if enabled(attrs.manual_reply) {
+            return Err(Error::new(
+                Span::call_site(),
+                format!("#[{}] function cannot use manual_reply.", entry_point),
+            ));
+        }
+
+        if enabled(attrs.payable) {
+            return Err(Error::new(
+                Span::call_site(),
+                format!("#[{}] function cannot be payable.", entry_point),
+            ));
+        }
+
+        if enabled(attrs.instrument) {
+            return Err(Error::new(
+                Span::call_site(),
+                format!("#[{}] function cannot be instrumented.", entry_point),
+            ));
+        }
+
+        if enabled(attrs.only_controller) || matches!(&attrs.only, Some(roles) if !roles.is_empty())
+        {
+            return Err(Error::new(
+                Span::call_site(),
+                format!("#[{}] function cannot have access control.", entry_point),
+            ));
+        }
+
+        if enabled(attrs.dedup) {
+            return Err(Error::new(
+                Span::call_site(),
+                format!("#[{}] function cannot be deduplicated.", entry_point),
+            ));
+        }
+
+        if attrs.max_reply_size.is_some() {
+            return Err(Error::new(
+                Span::call_site(),
+                format!("#[{}] function cannot use max_reply_size.", entry_point),
+            ));
+        }
+
+        if attrs.decode_error.is_some() {
+            return Err(Error::new(
+                Span::call_site(),
+                format!("#[{}] function cannot use decode_error.", entry_point),
+            ));
+        }
+
+        if enabled(attrs.reply_errors) {
+            return Err(Error::new(
+                Span::call_site(),
+                format!("#[{}] function cannot use reply_errors.", entry_point),
+            ));
+        }
+
+        // The init hook can await the calls it makes in the runtime.
+        if is_async && entry_point != EntryPoint::Init {
             return Err(Error::new(
                 Span::call_site(),
                 format!("#[{}] function cannot be async.", entry_point),
@@ -143,50 +233,398 @@ pub fn gen_entry_point_code(
         }
     }
 
+    let payable = attrs.payable.unwrap_or(false);
+
+    if entry_point == EntryPoint::Query && payable {
+        return Err(Error::new(
+            Span::call_site(),
+            "#[query] function cannot be payable.",
+        ));
+    }
+
+    if attrs.max_cycles.is_some() && !payable {
+        return Err(Error::new(
+            Span::call_site(),
+            format!(
+                "#[{}] function must be payable to use max_cycles.",
+                entry_point
+            ),
+        ));
+    }
+
+    let composite = attrs.composite.unwrap_or(false);
+
+    if composite && entry_point != EntryPoint::Query {
+        return Err(Error::new(
+            Span::call_site(),
+            format!("#[{}] function cannot be composite.", entry_point),
+        ));
+    }
+
+    let manual_reply = attrs.manual_reply.unwrap_or(false);
+    let dedup = attrs.dedup.unwrap_or(false);
+
+    if attrs.max_concurrent.is_some() || attrs.max_queued.is_some() {
+        if entry_point != EntryPoint::Update {
+            return Err(Error::new(
+                Span::call_site(),
+                format!("#[{}] function cannot limit its concurrency.", entry_point),
+            ));
+        }
+
+        if !is_async {
+            return Err(Error::new(
+                Span::call_site(),
+                "#[update] function must be async to limit its concurrency.",
+            ));
+        }
+
+        match attrs.max_concurrent {
+            None => {
+                return Err(Error::new(
+                    Span::call_site(),
+                    "#[update] function must use max_concurrent to use max_queued.",
+                ))
+            }
+            Some(0) => {
+                return Err(Error::new(
+                    Span::call_site(),
+                    "#[update] max_concurrent must not be zero.",
+                ))
+            }
+            Some(_) => {}
+        }
+    }
+
+    if entry_point == EntryPoint::Query && dedup {
+        return Err(Error::new(
+            Span::call_site(),
+            "#[query] function cannot be deduplicated.",
+        ));
+    }
+
+    if manual_reply && attrs.max_reply_size.is_some() {
+        return Err(Error::new(
+            Span::call_site(),
+            format!(
+                "#[{}(manual_reply)] function cannot use max_reply_size.",
+                entry_point
+            ),
+        ));
+    }
+
+    if manual_reply && dedup {
+        return Err(Error::new(
+            Span::call_site(),
+            format!(
+                "#[{}(manual_reply)] function cannot be deduplicated.",
+                entry_point
+            ),
+        ));
+    }
+    let reply_errors = attrs.reply_errors.unwrap_or(false);
+
+    if reply_errors && (manual_reply || !returns_result(&signature.output)) {
+        return Err(Error::new(
+            signature.output.span(),
+            format!(
+                "#[{}(reply_errors)] function must return a Result<T, ic_kit::Error>.",
+                entry_point
+            ),
+        ));
+    }
+
+    let candid_output = if manual_reply {
+        manual_reply_output(entry_point, &signature.output)?
+    } else {
+        signature.output.clone()
+    };
+
     let outer_function_ident = Ident::new(
         &format!("_ic_kit_canister_{}_{}", entry_point, name),
         Span::call_site(),
     );
 
+    let candid_name = attrs.name.unwrap_or_else(|| name.to_string());
+    if !entry_point.is_lifecycle() {
+        validate_method_name(entry_point, &candid_name)?;
+    }
+    let instrument = attrs.instrument.unwrap_or(false);
+
+    // Count the calls and the failures of instrumented methods, the reject paths below include
+    // `record_error` so the rejected calls are counted as errors.
+    let (record_call, record_error, record_completion) = if instrument {
+        (
+            quote! {
+                let _ic_kit_started_at = ic_kit::ic::record_call(#candid_name);
+            },
+            quote! {
+                ic_kit::ic::record_error(#candid_name);
+            },
+            quote! {
+                ic_kit::ic::record_completion(#candid_name, _ic_kit_started_at);
+            },
+        )
+    } else {
+        (quote! {}, quote! {}, quote! {})
+    };
+
+    // Refuse the call before the method runs, by rejecting it, or by replying with the error when
+    // the method returns `Result<T, ic_kit::Error>` and has the `reply_errors` flag.
+    let output = &signature.output;
+    let refuse = |code: TokenStream, message: TokenStream| {
+        if reply_errors {
+            let ty = match output {
+                syn::ReturnType::Type(_, ty) => ty,
+                syn::ReturnType::Default => unreachable!(),
+            };
+
+            quote! {
+                #record_error
+                let result: #ty = Err(ic_kit::Error::new(#code, #message));
+                let bytes = ic_kit::candid::encode_one(result)
+                    .expect("Could not encode canister's response.");
+                ic_kit::utils::reply(&bytes);
+                return;
+            }
+        } else {
+            quote! {
+                #record_error
+                ic_kit::utils::reject(&#message);
+                return;
+            }
+        }
+    };
+
     let guard = if let Some(guard_name) = attrs.guard {
         let guard_ident = Ident::new(&guard_name, Span::call_site());
+        let refuse = refuse(quote! { ic_kit::Error::PRECONDITION_FAILED }, quote! { e });
 
         quote! {
             let r: Result<(), String> = #guard_ident ();
             if let Err(e) = r {
-                ic_kit::utils::reject(&e);
-                return;
+                #refuse
             }
         }
     } else {
         quote! {}
     };
 
-    let candid_name = attrs.name.unwrap_or_else(|| name.to_string());
+    let only_controller = if attrs.only_controller.unwrap_or(false) {
+        let refuse = refuse(
+            quote! { ic_kit::Error::UNAUTHORIZED },
+            quote! { "Only the controllers of the canister can call this method." },
+        );
+
+        quote! {
+            if !ic_kit::ic::is_controller(&ic_kit::ic::caller()) {
+                #refuse
+            }
+        }
+    } else {
+        quote! {}
+    };
+
+    let only_roles = match &attrs.only {
+        Some(roles) if !roles.is_empty() => {
+            let refuse = refuse(
+                quote! { ic_kit::Error::UNAUTHORIZED },
+                quote! { "The caller does not have the required role to call this method." },
+            );
+
+            quote! {
+                let caller = ic_kit::ic::caller();
+                if ![#(#roles),*].iter().any(|role| ic_kit::ic::has_role(&caller, role)) {
+                    #refuse
+                }
+            }
+        }
+        _ => quote! {},
+    };
+
+    // Reply to the duplicates with the stored reply, the guard is completed with the reply once the
+    // method returns.
+    let dedup_guard = if dedup {
+        let refuse = refuse(quote! { ic_kit::Error::CONFLICT }, quote! { e.to_string() });
+
+        quote! {
+            let _ic_kit_dedup = match ic_kit::dedup::DedupGuard::begin_call(#candid_name) {
+                Ok(guard) => guard,
+                Err(ic_kit::dedup::DedupError::Duplicate { reply, .. }) => {
+                    ic_kit::utils::reply(&reply);
+                    return;
+                }
+                Err(e) => {
+                    #refuse
+                }
+            };
+        }
+    } else {
+        quote! {}
+    };
+
+    // The calls over the limit, or over the queue, are refused before the method is spawned. The
+    // permit is moved into the future so the slot is held until it's done, and the queued calls
+    // wait for their slot once the arguments are read and the cycles are accepted.
+    let (concurrency_guard, concurrency_permit) = match attrs.max_concurrent {
+        Some(max_concurrent) => {
+            let refuse = refuse(quote! { ic_kit::Error::CONFLICT }, quote! { e });
+            let max_queued = attrs.max_queued.unwrap_or(0);
+
+            (
+                quote! {
+                    let _ic_kit_queued = match ic_kit::guards::ConcurrencyLimit::new(#candid_name, #max_concurrent)
+                        .with_queue(#max_queued)
+                        .queue()
+                    {
+                        Ok(queued) => queued,
+                        Err(e) => {
+                            #refuse
+                        }
+                    };
+                },
+                quote! {
+                    let _ic_kit_permit = _ic_kit_queued.wait().await;
+                },
+            )
+        }
+        None => (quote! {}, quote! {}),
+    };
+
+    // Reject the replies larger than the limit, rather than trapping in `msg_reply_data_append`.
+    let reply_limit = match (attrs.max_reply_size, entry_point) {
+        (Some(limit), _) => quote! { (#limit as usize) },
+        (None, EntryPoint::Query) => quote! { ic_kit::ic::MAX_QUERY_REPLY_SIZE },
+        (None, _) => quote! { ic_kit::ic::MAX_REPLY_SIZE },
+    };
+
+    let check_reply = quote! {
+        if bytes.len() > #reply_limit {
+            #record_error
+            ic_kit::utils::reject(&format!(
+                "The reply of {} bytes exceeds the limit of {} bytes.",
+                bytes.len(),
+                #reply_limit
+            ));
+            return;
+        }
+    };
+
+    let send_reply = if dedup {
+        quote! {
+            ic_kit::utils::reply(&bytes);
+            _ic_kit_dedup.complete(bytes);
+        }
+    } else {
+        quote! {
+            ic_kit::utils::reply(&bytes);
+        }
+    };
+
     let export_name = if entry_point.is_lifecycle() {
         format!("canister_{}", entry_point)
+    } else if composite {
+        format!("canister_composite_query {}", candid_name)
     } else {
         format!("canister_{0} {1}", entry_point, candid_name)
     };
 
     // Build the outer function's body.
-    let tmp = di(collect_args(entry_point, signature)?, is_async)?;
+    let tmp = di(collect_args(entry_point, signature)?, is_async, payable)?;
     let args = tmp.args;
+    let payment = tmp.payment;
+    let context = tmp.context;
     let (can_args, can_types): (Vec<_>, Vec<_>) = tmp.can_args.into_iter().unzip();
     let (imu_args, imu_types): (Vec<_>, Vec<_>) = tmp.imu_args.into_iter().unzip();
     let (mut_args, mut_types): (Vec<_>, Vec<_>) = tmp.mut_args.into_iter().unzip();
 
+    if !can_args.is_empty() && !entry_point.accepts_args() {
+        return Err(Error::new(
+            Span::call_site(),
+            format!("#[{}] function cannot have any arguments.", entry_point),
+        ));
+    }
+
+    let entry_name = entry_point.to_string();
+
+    // A method either picks the policy, or a fallback function which replies to the call itself,
+    // otherwise the error is replied with `reply_errors`, or the policy is the one returned by the
+    // `on_decode_error` hook.
+    let on_decode_error = match attrs.decode_error.as_deref() {
+        None if reply_errors => {
+            let ty = match &signature.output {
+                syn::ReturnType::Type(_, ty) => ty,
+                syn::ReturnType::Default => unreachable!(),
+            };
+
+            quote! {
+                let result: #ty = Err(ic_kit::Error::from(error));
+                let bytes = ic_kit::candid::encode_one(result)
+                    .expect("Could not encode canister's response.");
+                ic_kit::utils::reply(&bytes);
+            }
+        }
+        None => quote! {
+            ic_kit::ic::handle_decode_error(error, None);
+        },
+        Some("trap") => quote! {
+            ic_kit::ic::handle_decode_error(error, Some(ic_kit::ic::DecodeErrorPolicy::Trap));
+        },
+        Some("reject") => quote! {
+            ic_kit::ic::handle_decode_error(error, Some(ic_kit::ic::DecodeErrorPolicy::Reject));
+        },
+        Some(fallback) => {
+            let fallback: syn::Path = syn::parse_str(fallback).map_err(|_| {
+                Error::new(
+                    Span::call_site(),
+                    format!(
+                        "#[{}] decode_error must be \"trap\", \"reject\" or the path of a function.",
+                        entry_point
+                    ),
+                )
+            })?;
+
+            quote! {
+                #fallback(error);
+            }
+        }
+    };
+
     // If the method does not accept any arguments, don't even read the msg_data, and if the
     // deserialization fails, just reject the message, which is cheaper than trap.
     let arg_decode = if can_args.len() == 0 {
         quote! {}
+    } else if entry_point.is_lifecycle() {
+        // Lifecycle hooks can not reject, so trap instead. An empty argument is treated as `()`
+        // so that optional arguments can be omitted when installing or upgrading the canister.
+        quote! {
+            let bytes = ic_kit::utils::arg_data_raw();
+            let bytes: &[u8] = if bytes.is_empty() {
+                ic_kit::ic::CANDID_EMPTY_ARG
+            } else {
+                &bytes
+            };
+            let args = match ic_kit::candid::decode_args(bytes) {
+                Ok(v) => v,
+                Err(e) => {
+                    ic_kit::ic::trap(&format!("Could not decode the {} arguments: {}", #entry_name, e));
+                },
+            };
+            let ( #( #can_args, )* ) = args;
+        }
     } else {
         quote! {
             let bytes = ic_kit::utils::arg_data_raw();
             let args = match ic_kit::candid::decode_args(&bytes) {
                 Ok(v) => v,
-                Err(_) => {
-                    ic_kit::utils::reject("Could not decode arguments.");
+                Err(e) => {
+                    #record_error
+                    let error = ic_kit::ic::DecodeError {
+                        method: #candid_name.to_string(),
+                        arg_data: bytes,
+                        message: e.to_string(),
+                    };
+                    #on_decode_error
                     return;
                 },
             };
@@ -194,6 +632,58 @@ pub fn gen_entry_point_code(
         }
     };
 
+    // The context is read before the cycles are accepted, so it has all of the attached cycles.
+    let request_context = match context {
+        Some(context) => {
+            let method_name = if entry_point.is_lifecycle() {
+                entry_name.clone()
+            } else {
+                candid_name.clone()
+            };
+            let mode = Ident::new(
+                match entry_point {
+                    EntryPoint::Init => "Init",
+                    EntryPoint::PreUpgrade => "PreUpgrade",
+                    EntryPoint::PostUpgrade => "PostUpgrade",
+                    EntryPoint::InspectMessage => "InspectMessage",
+                    EntryPoint::Heartbeat => "Heartbeat",
+                    EntryPoint::Update => "Update",
+                    EntryPoint::Query if composite => "CompositeQuery",
+                    EntryPoint::Query => "Query",
+                },
+                Span::call_site(),
+            );
+
+            quote! {
+                let #context = ic_kit::ic::RequestContext::current(#method_name, ic_kit::ic::EntryMode::#mode);
+            }
+        }
+        None => quote! {},
+    };
+
+    // Accept the cycles only once the arguments are decoded, so they are refunded otherwise.
+    let payment_ident = payment.unwrap_or_else(|| Ident::new("_payment", Span::call_site()));
+    let accept_payment = match (payable, attrs.max_cycles) {
+        (false, _) => quote! {},
+        (true, None) => quote! {
+            let #payment_ident = ic_kit::ic::Payment::accept_all();
+        },
+        (true, Some(max_cycles)) => quote! {
+            let #payment_ident = ic_kit::ic::Payment::accept_up_to(#max_cycles as ic_kit::ic::Cycles);
+        },
+    };
+
+    let no_payment = if entry_point == EntryPoint::Update && !payable {
+        quote! {
+            if !ic_kit::ic::check_no_payment() {
+                #record_error
+                return;
+            }
+        }
+    } else {
+        quote! {}
+    };
+
     let return_encode = if entry_point.is_inspect_message() {
         quote! {
             let result: bool = result;
@@ -203,30 +693,51 @@ pub fn gen_entry_point_code(
         }
     } else if entry_point.is_lifecycle() {
         quote! {}
+    } else if manual_reply {
+        quote! {
+            // The method is responsible for sending the reply itself.
+            let _ = result;
+        }
     } else {
         match return_length {
             0 => quote! {
                 // Send the precomputed `encode_args(())` available in ic-kit.
                 let _ = result; // to ignore result not being used.
-                ic_kit::utils::reply(ic_kit::ic::CANDID_EMPTY_ARG)
+                let bytes = ic_kit::ic::CANDID_EMPTY_ARG;
+                #send_reply
             },
             1 => quote! {
                 let bytes = ic_kit::candid::encode_one(result)
                     .expect("Could not encode canister's response.");
-                ic_kit::utils::reply(&bytes);
+                #check_reply
+                #send_reply
             },
             _ => quote! {
                 let bytes = ic_kit::candid::encode_args(result)
                     .expect("Could not encode canister's response.");
-                ic_kit::utils::reply(&bytes);
+                #check_reply
+                #send_reply
             },
         }
     };
 
+    // Methods returning a `Result` have their `Err` responses counted as errors.
+    let record_result = if instrument && !manual_reply && returns_result(&signature.output) {
+        quote! {
+            if result.is_err() {
+                #record_error
+            }
+        }
+    } else {
+        quote! {}
+    };
+
     // Because DI doesn't work on an async method.
     let mut sync_result = quote! {
         let result = #name ( #(#args),* );
+        #record_result
         #return_encode
+        #record_completion
     };
 
     sync_result = match imu_args.len() {
@@ -257,22 +768,54 @@ pub fn gen_entry_point_code(
         },
     };
 
+    // The callbacks registered by the components run before the state is saved by the hook, and
+    // after it's restored.
+    let post_upgrade_callbacks = if entry_point == EntryPoint::PostUpgrade {
+        quote! { ic_kit::ic::run_post_upgrade_callbacks(); }
+    } else {
+        quote! {}
+    };
+
     // only spawn for async methods.
     let body = if is_async {
         quote! {
-            ic_kit::ic::spawn(async {
+            ic_kit::ic::spawn(async move {
+                #request_context
                 #arg_decode
+                #accept_payment
+                #concurrency_permit
                 let result = #name ( #(#args),* ).await;
+                #record_result
                 #return_encode
+                #record_completion
             });
         }
     } else {
         quote! {
+            #request_context
             #arg_decode
+            #accept_payment
             #sync_result;
+            #post_upgrade_callbacks
         }
     };
 
+    // The states which derive `KitStable` are saved once the hook is done, and restored before
+    // its body.
+    let (save_states, restore_states) = stable_calls();
+    let body = match entry_point {
+        EntryPoint::PreUpgrade => quote! {
+            ic_kit::ic::run_pre_upgrade_callbacks();
+            #body
+            #save_states
+        },
+        EntryPoint::PostUpgrade => quote! {
+            #restore_states
+            #body
+        },
+        _ => body,
+    };
+
     // only declare candid if hide is false
     declare(
         entry_point,
@@ -281,7 +824,7 @@ pub fn gen_entry_point_code(
         attrs.hidden.unwrap_or(false),
         can_args,
         can_types,
-        &signature.output,
+        &candid_output,
     )?;
 
     Ok(quote! {
@@ -306,7 +849,14 @@ pub fn gen_entry_point_code(
             #[cfg(target_family = "wasm")]
             ic_kit::setup_hooks();
 
+            ic_kit::ic::begin_message();
+            #record_call
+            #only_controller
+            #only_roles
+            #no_payment
             #guard
+            #dedup_guard
+            #concurrency_guard
             #body
         }
 
@@ -316,15 +866,265 @@ pub fn gen_entry_point_code(
             #[cfg(target_family = "wasm")]
             ic_kit::setup_hooks();
 
+            ic_kit::ic::begin_message();
+            #record_call
+            #only_controller
+            #only_roles
+            #no_payment
             #guard
+            #dedup_guard
+            #concurrency_guard
             #body
         }
 
         #[inline(always)]
-        #item
+        #fun
     })
 }
 
+/// Remove the `#[only_controller]` and `#[only(...)]` attributes from the function and return
+/// them as the config tokens they represent.
+fn take_access_attrs(fun: &mut syn::ItemFn) -> Result<Vec<TokenStream>, Error> {
+    let mut result = Vec::new();
+    let mut attrs = Vec::with_capacity(fun.attrs.len());
+
+    for attr in fun.attrs.drain(..) {
+        match attr.path.segments.last().map(|s| s.ident.to_string()) {
+            Some(ident) if ident == "only_controller" => {
+                result.push(access_config(AccessAttr::Controller, TokenStream::new())?);
+            }
+            Some(ident) if ident == "only" => {
+                let args = match attr.parse_meta()? {
+                    syn::Meta::List(list) => list.nested.into_token_stream(),
+                    meta => {
+                        return Err(Error::new(
+                            meta.span(),
+                            "Expected a list of roles such as #[only(owner, admin)].",
+                        ))
+                    }
+                };
+
+                result.push(access_config(AccessAttr::Roles, args)?);
+            }
+            _ => attrs.push(attr),
+        }
+    }
+
+    fun.attrs = attrs;
+    Ok(result)
+}
+
+/// The access control attributes.
+#[derive(Copy, Clone)]
+pub enum AccessAttr {
+    Controller,
+    Roles,
+}
+
+/// Convert the arguments of an access control attribute to the entry point config tokens.
+fn access_config(access: AccessAttr, attr: TokenStream) -> Result<TokenStream, Error> {
+    match access {
+        AccessAttr::Controller => {
+            if !attr.is_empty() {
+                return Err(Error::new(
+                    attr.span(),
+                    "#[only_controller] does not take any arguments.",
+                ));
+            }
+
+            Ok(quote! { only_controller = true })
+        }
+        AccessAttr::Roles => {
+            let roles = syn::parse::Parser::parse2(
+                syn::punctuated::Punctuated::<syn::Ident, syn::Token![,]>::parse_terminated,
+                attr,
+            )?
+            .into_iter()
+            .map(|role| role.to_string())
+            .collect::<Vec<_>>();
+
+            if roles.is_empty() {
+                return Err(Error::new(
+                    Span::call_site(),
+                    "#[only(...)] requires at least one role.",
+                ));
+            }
+
+            Ok(quote! { only = [#(#roles),*] })
+        }
+    }
+}
+
+/// Generate the code for the `#[only_controller]` and `#[only(...)]` attributes when they are
+/// placed above the entry point attribute, by moving them into the entry point's config.
+pub fn gen_access_code(
+    access: AccessAttr,
+    attr: TokenStream,
+    item: TokenStream,
+) -> Result<TokenStream, Error> {
+    let config = access_config(access, attr)?;
+    let mut fun: syn::ItemFn = syn::parse2::<syn::ItemFn>(item.clone()).map_err(|e| {
+        Error::new(
+            item.span(),
+            format!(
+                "Access control attributes must be above a function. \n{}",
+                e
+            ),
+        )
+    })?;
+
+    let entry = fun
+        .attrs
+        .iter_mut()
+        .find(|attr| {
+            attr.path
+                .segments
+                .last()
+                .map(|s| s.ident == "update" || s.ident == "query")
+                .unwrap_or(false)
+        })
+        .ok_or_else(|| {
+            Error::new(
+                fun.sig.ident.span(),
+                "Access control attributes can only be used on #[update] or #[query] methods.",
+            )
+        })?;
+
+    let mut inner = match entry.tokens.clone().into_iter().next() {
+        Some(TokenTree::Group(group)) => group.stream(),
+        _ => TokenStream::new(),
+    };
+
+    let ends_with_comma = matches!(
+        inner.clone().into_iter().last(),
+        Some(TokenTree::Punct(p)) if p.as_char() == ','
+    );
+
+    if !inner.is_empty() && !ends_with_comma {
+        inner.extend(quote! { , });
+    }
+
+    inner.extend(config);
+    entry.tokens = quote! { (#inner) };
+
+    Ok(quote! { #fun })
+}
+
+/// Rewrite the bare flags in the attribute such as `hidden` to `hidden = true`, so they can be
+/// deserialized as a boolean in the [`Config`].
+fn normalize_flags(attr: TokenStream) -> TokenStream {
+    let mut result = Vec::<TokenTree>::new();
+    let mut segment = Vec::<TokenTree>::new();
+
+    let flush = |segment: &mut Vec<TokenTree>, result: &mut Vec<TokenTree>| {
+        if let [TokenTree::Ident(ident)] = segment.as_slice() {
+            result.extend(quote! { #ident = true });
+        } else {
+            result.extend(segment.iter().cloned());
+        }
+        segment.clear();
+    };
+
+    for tt in attr {
+        match &tt {
+            TokenTree::Punct(p) if p.as_char() == ',' => {
+                flush(&mut segment, &mut result);
+                result.push(tt);
+            }
+            _ => segment.push(tt),
+        }
+    }
+
+    flush(&mut segment, &mut result);
+    result.into_iter().collect()
+}
+
+/// Return true if the function returns a `Result`.
+fn returns_result(output: &syn::ReturnType) -> bool {
+    match output {
+        syn::ReturnType::Type(_, ty) => match ty.as_ref() {
+            syn::Type::Path(path) => path
+                .path
+                .segments
+                .last()
+                .map(|s| s.ident == "Result")
+                .unwrap_or(false),
+            _ => false,
+        },
+        syn::ReturnType::Default => false,
+    }
+}
+
+/// The names of the methods exported by ic-kit itself, or called by it on the canister.
+const RESERVED_METHOD_NAMES: &[&str] = &["__get_candid_interface_tmp_hack", "__ic_kit_yield"];
+
+/// Check the name of a method can be exported by the canister, so an invalid name fails to compile
+/// rather than to install.
+fn validate_method_name(entry_point: EntryPoint, name: &str) -> Result<(), Error> {
+    let error = |reason: String| {
+        Err(Error::new(
+            Span::call_site(),
+            format!(
+                "#[{}] invalid method name '{}': {}",
+                entry_point, name, reason
+            ),
+        ))
+    };
+
+    if name.is_empty() {
+        return error("the name cannot be empty.".into());
+    }
+
+    if let Some(c) = name.chars().find(|c| c.is_whitespace() || c.is_control()) {
+        return error(format!(
+            "the name cannot contain whitespace or control characters, found {:?}.",
+            c
+        ));
+    }
+
+    if name.starts_with("canister_") {
+        return error("the 'canister_' prefix is reserved for the system entry points.".into());
+    }
+
+    if RESERVED_METHOD_NAMES.contains(&name) {
+        return error("the name is reserved by ic-kit.".into());
+    }
+
+    Ok(())
+}
+
+/// Return the candid return type of a `manual_reply` method, which is the `T` in the
+/// `ManualReply<T>` returned by the function.
+fn manual_reply_output(
+    entry_point: EntryPoint,
+    output: &syn::ReturnType,
+) -> Result<syn::ReturnType, Error> {
+    let (arrow, ty) = match output {
+        syn::ReturnType::Default => return Ok(syn::ReturnType::Default),
+        syn::ReturnType::Type(arrow, ty) => (arrow, ty),
+    };
+
+    if let syn::Type::Path(syn::TypePath { qself: None, path }) = ty.as_ref() {
+        if let Some(segment) = path.segments.last() {
+            if segment.ident == "ManualReply" {
+                if let syn::PathArguments::AngleBracketed(generics) = &segment.arguments {
+                    if let Some(syn::GenericArgument::Type(inner)) = generics.args.first() {
+                        return Ok(syn::ReturnType::Type(*arrow, Box::new(inner.clone())));
+                    }
+                }
+            }
+        }
+    }
+
+    Err(Error::new(
+        ty.span(),
+        format!(
+            "#[{}(manual_reply)] function must return ManualReply<T>.",
+            entry_point
+        ),
+    ))
+}
+
 #[derive(Default)]
 struct ProcessedArgs {
     args: Vec<Ident>,
@@ -332,15 +1132,41 @@ struct ProcessedArgs {
     imu_args: Vec<(Ident, syn::Type)>,
     can_args: Vec<(Ident, syn::Type)>,
     injected: Vec<syn::Type>,
+    payment: Option<Ident>,
+    context: Option<Ident>,
 }
 
-fn di(args: Vec<(Ident, syn::Type)>, is_async: bool) -> Result<ProcessedArgs, Error> {
+fn di(
+    args: Vec<(Ident, syn::Type)>,
+    is_async: bool,
+    payable: bool,
+) -> Result<ProcessedArgs, Error> {
     let mut result = ProcessedArgs::default();
 
     for (ident, ty) in args {
         result.args.push(ident.clone());
 
         match ty {
+            syn::Type::Path(ty_path) if is_named_type(&ty_path, "RequestContext") => {
+                if result.context.is_some() {
+                    return Err(Error::new(
+                        ty_path.span(),
+                        "A method can only have one RequestContext parameter.",
+                    ));
+                }
+
+                result.context = Some(ident);
+            }
+            syn::Type::Path(ty_path) if payable && is_named_type(&ty_path, "Payment") => {
+                if result.payment.is_some() {
+                    return Err(Error::new(
+                        ty_path.span(),
+                        "A payable method can only have one Payment parameter.",
+                    ));
+                }
+
+                result.payment = Some(ident);
+            }
             syn::Type::Reference(ty_ref) if is_async => {
                 return Err(Error::new(
                     ty_ref.span(),
@@ -378,6 +1204,18 @@ fn di(args: Vec<(Ident, syn::Type)>, is_async: bool) -> Result<ProcessedArgs, Er
     Ok(result)
 }
 
+/// Returns true if the type is the given type injected by the macros, such as the `Payment` of the
+/// payable methods or the `RequestContext`.
+fn is_named_type(ty: &syn::TypePath, name: &str) -> bool {
+    ty.qself.is_none()
+        && ty
+            .path
+            .segments
+            .last()
+            .map(|s| s.ident == name && s.arguments.is_empty())
+            .unwrap_or(false)
+}
+
 fn collect_args(
     entry_point: EntryPoint,
     signature: &syn::Signature,
@@ -409,3 +1247,60 @@ fn collect_args(
 
     Ok(args)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn init(attr: TokenStream) -> Result<TokenStream, Error> {
+        gen_entry_point_code(EntryPoint::Init, attr, quote! { fn init() {} })
+    }
+
+    #[test]
+    fn lifecycle_flags() {
+        for (attr, message) in [
+            (
+                quote! { manual_reply },
+                "#[init] function cannot use manual_reply.",
+            ),
+            (
+                quote! { hidden = true },
+                "#[init] function cannot be hidden.",
+            ),
+            (quote! { payable }, "#[init] function cannot be payable."),
+            (
+                quote! { instrument },
+                "#[init] function cannot be instrumented.",
+            ),
+            (quote! { dedup }, "#[init] function cannot be deduplicated."),
+            (
+                quote! { only_controller },
+                "#[init] function cannot have access control.",
+            ),
+            (
+                quote! { only = ["admin"] },
+                "#[init] function cannot have access control.",
+            ),
+            (
+                quote! { name = "setup" },
+                "#[init] function cannot be renamed.",
+            ),
+        ] {
+            assert_eq!(init(attr).unwrap_err().to_string(), message);
+        }
+
+        // The flags which are disabled, and the name of the hook, change nothing.
+        init(quote! {
+            manual_reply = false,
+            hidden = false,
+            payable = false,
+            instrument = false,
+            dedup = false,
+            only_controller = false,
+            only = [],
+            reply_errors = false,
+            name = "init"
+        })
+        .unwrap();
+    }
+}
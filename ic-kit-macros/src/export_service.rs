@@ -6,14 +6,17 @@ use proc_macro2::{Ident, Span, TokenStream};
 use quote::{quote, ToTokens};
 use syn::{DeriveInput, Error};
 
-use crate::metadata::generate_metadata;
+use crate::metadata::{generate_metadata, take_declared_metadata};
+use crate::stable::{stable_calls, take_stable_calls};
 use crate::EntryPoint;
 
 struct Method {
     hidden: bool,
+    /// The name of the mixin which exports this method, if any.
+    mixin: Option<String>,
     mode: EntryPoint,
     rust_name: String,
-    _arg_names: Vec<String>,
+    arg_names: Vec<String>,
     arg_types: Vec<String>,
     rets: Vec<String>,
 }
@@ -47,9 +50,10 @@ pub(crate) fn declare(
 
     let method = Method {
         hidden,
+        mixin: None,
         mode: entry_point,
         rust_name: rust_name.to_string(),
-        _arg_names: can_args.iter().map(|i| i.to_string()).collect(),
+        arg_names: can_args.iter().map(|i| i.to_string()).collect(),
         arg_types: can_types
             .iter()
             .map(|t| format!("{}", t.to_token_stream()))
@@ -72,95 +76,442 @@ pub(crate) fn declare(
                 format!("Canister's '{}' method already defined.", entry_point),
             ));
         }
-    } else if METHODS
-        .lock()
-        .unwrap()
-        .insert(name.clone(), method)
-        .is_some()
-    {
+    } else if let Some(existing) = METHODS.lock().unwrap().insert(name.clone(), method) {
         return Err(Error::new(
             rust_name.span(),
-            format!("Method '{}' is already defined.", name),
+            format!(
+                "Method '{}' is already exported by the function '{}'.",
+                name, existing.rust_name
+            ),
         ));
     };
 
     Ok(())
 }
 
-pub fn export_service(input: DeriveInput, save_candid_path: Option<syn::LitStr>) -> TokenStream {
-    let methods = {
-        let mut map = METHODS.lock().unwrap();
-        std::mem::replace(&mut *map, BTreeMap::new())
-    };
+/// Return true if the `pre_upgrade` or the `post_upgrade` hook of the next canister is declared.
+pub(crate) fn has_upgrade_hooks() -> bool {
+    let life_cycles = LIFE_CYCLES.lock().unwrap();
+    life_cycles.contains_key(&EntryPoint::PreUpgrade)
+        || life_cycles.contains_key(&EntryPoint::PostUpgrade)
+}
 
-    let mut life_cycles = {
-        let mut map = LIFE_CYCLES.lock().unwrap();
-        std::mem::replace(&mut *map, BTreeMap::new())
-    };
+pub fn export_service(
+    input: DeriveInput,
+    save_candid_path: Option<syn::LitStr>,
+    mixins: Vec<syn::Path>,
+) -> TokenStream {
+    let (mut methods, mut life_cycles) = take_declared_methods();
+
+    // The methods exported by a mixin are registered by the mixin itself.
+    let mixin_names = mixins
+        .iter()
+        .filter_map(|p| p.segments.last())
+        .map(|s| s.ident.to_string())
+        .collect::<Vec<_>>();
+
+    for method in methods.values().chain(life_cycles.values()) {
+        if let Some(mixin) = &method.mixin {
+            if !mixin_names.contains(mixin) {
+                let message = format!(
+                    "The method '{}' is exported by the mixin '{}', which is not included in the canister's #[mixins(...)] attribute.",
+                    method.rust_name, mixin
+                );
+                return quote! { compile_error!(#message); };
+            }
+        }
+    }
 
     let mut rust_methods = Vec::new();
     rust_methods.extend(
         life_cycles
             .values()
+            .filter(|m| m.mixin.is_none())
             .map(|m| Ident::new(m.rust_name.as_str(), Span::call_site())),
     );
     rust_methods.extend(
         methods
             .values()
+            .filter(|m| m.mixin.is_none())
             .map(|m| Ident::new(m.rust_name.as_str(), Span::call_site())),
     );
 
-    let gen_tys = methods.iter().map(
-        |(
-            name,
-            Method {
-                arg_types,
-                rets,
-                mode,
-                hidden,
-                ..
+    methods.retain(|_, m| m.mixin.is_none());
+
+    let name = input.ident;
+    let (default_hooks, native_hooks, wasm_hooks) = generate_default_hooks(&name, &life_cycles);
+    take_stable_calls();
+    rust_methods.extend(default_hooks);
+
+    let candid = generate_candid(&methods, &mut life_cycles, &mixins);
+
+    let save_candid = if let Some(path) = save_candid_path {
+        generate_save_candid(quote! { <#name as ic_kit::KitCanister>::candid() }, path)
+    } else {
+        quote! {}
+    };
+
+    let metadata = generate_metadata();
+    let declared_metadata = take_declared_metadata();
+    let handle = generate_handle(&name, &input.vis, &methods);
+
+    quote! {
+        #metadata
+        #handle
+        #native_hooks
+        #wasm_hooks
+
+        impl ic_kit::KitCanister for #name {
+            #[cfg(not(target_family = "wasm"))]
+            fn build(canister_id: ic_kit::Principal) -> ic_kit::rt::Canister {
+                let canister = ic_kit::rt::Canister::new(canister_id)
+                #(
+                    .with_method::<#rust_methods>()
+                )*;
+
+                #(
+                    let canister = <#mixins as ic_kit::KitMixin>::register(canister);
+                )*
+
+                canister
+                    .with_candid(<#name as ic_kit::KitCanister>::candid())
+                    #declared_metadata
+            }
+
+            fn candid() -> String {
+                #candid
+            }
+        }
+
+        #[cfg(target_family = "wasm")]
+        #[doc(hidden)]
+        #[export_name = "canister_query __get_candid_interface_tmp_hack"]
+        fn _ic_kit_canister_query___get_candid_interface_tmp_hack() {
+            let candid = #name::candid();
+            let bytes = ic_kit::candid::encode_one(candid)
+                .expect("Could not encode canister's response.");
+            ic_kit::utils::reply(&bytes);
+        }
+
+        #save_candid
+    }
+}
+
+/// Generate the `pre_upgrade` and `post_upgrade` hooks the canister does not define, which save
+/// and restore the states which derive `KitStable` and run the callbacks registered with
+/// `ic::on_pre_upgrade` and `ic::on_post_upgrade`, and the `global_timer` hook which runs the
+/// timers of `ic_kit::timers`. Returns the methods to register on the canister in the runtime,
+/// their definitions, and the WASM exports.
+fn generate_default_hooks(
+    canister: &Ident,
+    life_cycles: &BTreeMap<EntryPoint, Method>,
+) -> (Vec<Ident>, TokenStream, TokenStream) {
+    let mut methods = Vec::new();
+    let mut native = Vec::new();
+    let mut wasm = Vec::new();
+    let (save, restore) = stable_calls();
+
+    for (entry_point, defined, body) in [
+        (
+            "pre_upgrade",
+            life_cycles.contains_key(&EntryPoint::PreUpgrade),
+            quote! {
+                ic_kit::ic::run_pre_upgrade_callbacks();
+                #save
             },
-        )| {
-            let args = arg_types
+        ),
+        (
+            "post_upgrade",
+            life_cycles.contains_key(&EntryPoint::PostUpgrade),
+            quote! {
+                #restore
+                ic_kit::ic::run_post_upgrade_callbacks();
+            },
+        ),
+        // The global timer can not be defined by the canister.
+        (
+            "global_timer",
+            false,
+            quote! { ic_kit::timers::run_expired(); },
+        ),
+    ] {
+        if defined {
+            continue;
+        }
+
+        let name = Ident::new(
+            &format!("_ic_kit_{}_default_{}", canister, entry_point),
+            Span::call_site(),
+        );
+        let export_name = format!("canister_{}", entry_point);
+
+        native.push(quote! {
+            #[doc(hidden)]
+            #[allow(non_camel_case_types)]
+            #[cfg(not(target_family = "wasm"))]
+            pub struct #name {}
+
+            #[cfg(not(target_family = "wasm"))]
+            impl ic_kit::rt::CanisterMethod for #name {
+                const EXPORT_NAME: &'static str = #export_name;
+
+                fn exported_method() {
+                    ic_kit::ic::begin_message();
+                    #body
+                }
+            }
+        });
+
+        wasm.push(quote! {
+            #[cfg(target_family = "wasm")]
+            #[doc(hidden)]
+            #[export_name = #export_name]
+            fn #name() {
+                ic_kit::setup_hooks();
+                ic_kit::ic::begin_message();
+                #body
+            }
+        });
+
+        methods.push(name);
+    }
+
+    (methods, quote! { #(#native)* }, quote! { #(#wasm)* })
+}
+
+/// Generate a `{Canister}Handle` which wraps a `CanisterHandle` with a typed async method for each
+/// of the methods of the canister, so the calls made in the tests are checked at compile time.
+fn generate_handle(
+    name: &Ident,
+    visibility: &syn::Visibility,
+    methods: &BTreeMap<String, Method>,
+) -> TokenStream {
+    let handle = Ident::new(&format!("{}Handle", name), Span::call_site());
+    let doc = format!(
+        "A handle to a `{}` installed on a replica, with a typed method for each of its methods.",
+        name
+    );
+
+    let methods = methods
+        .iter()
+        .filter(|(_, m)| !m.hidden)
+        .map(|(candid_name, method)| {
+            let ident = Ident::new(&method.rust_name, Span::call_site());
+            let args = method
+                .arg_names
                 .iter()
-                .map(|t| generate_arg(quote! { args }, t))
+                .map(|a| Ident::new(a, Span::call_site()))
                 .collect::<Vec<_>>();
-
-            let rets = rets
+            let types = method
+                .arg_types
                 .iter()
-                .map(|t| generate_arg(quote! { rets }, t))
+                .map(|t| syn::parse_str::<syn::Type>(t).unwrap())
+                .collect::<Vec<_>>();
+            let rets = method
+                .rets
+                .iter()
+                .map(|t| owned_type(syn::parse_str::<syn::Type>(t).unwrap()))
                 .collect::<Vec<_>>();
 
-            let modes = match mode {
-                EntryPoint::Update => quote! { vec![] },
-                EntryPoint::Query => {
-                    quote! { vec![ic_kit::candid::parser::types::FuncMode::Query] }
-                }
-                _ => unreachable!(),
+            let (ty, decode) = if rets.len() == 1 {
+                (quote! { #(#rets)* }, quote! { decode_one })
+            } else {
+                (quote! { (#(#rets,)*) }, quote! { decode })
             };
 
-            if !hidden {
-                quote! {
-                    {
-                        let mut args = Vec::new();
-                        #(#args)*
-                        let mut rets = Vec::new();
-                        #(#rets)*
-                        let func = Function { args, rets, modes: #modes };
-                        service.push((#name.to_string(), Type::Func(func)));
-                    }
+            let doc = format!("Call the `{}` method of the canister.", candid_name);
+
+            quote! {
+                #[doc = #doc]
+                pub async fn #ident(&self, #(#args: #types),*) -> Result<#ty, ic_kit::ic::CallError> {
+                    self.handle
+                        .new_call(#candid_name)
+                        .with_args((#(#args,)*))
+                        .with_caller(self.caller)
+                        .perform()
+                        .await
+                        .#decode::<#ty>()
                 }
-            } else {
-                quote! {}
             }
-        },
+        });
+
+    quote! {
+        #[doc = #doc]
+        #[cfg(not(target_family = "wasm"))]
+        #[allow(dead_code)]
+        #visibility struct #handle<'a> {
+            handle: ic_kit::rt::handle::CanisterHandle<'a>,
+            caller: ic_kit::Principal,
+        }
+
+        #[cfg(not(target_family = "wasm"))]
+        #[allow(dead_code)]
+        impl<'a> #handle<'a> {
+            /// Wrap the handle, the calls are made by the anonymous principal by default.
+            pub fn new(handle: ic_kit::rt::handle::CanisterHandle<'a>) -> Self {
+                Self {
+                    handle,
+                    caller: ic_kit::Principal::anonymous(),
+                }
+            }
+
+            /// Make the calls from the given principal.
+            pub fn with_caller<I: Into<ic_kit::Principal>>(mut self, caller: I) -> Self {
+                self.caller = caller.into();
+                self
+            }
+
+            #(#methods)*
+        }
+
+        #[cfg(not(target_family = "wasm"))]
+        impl<'a> From<ic_kit::rt::handle::CanisterHandle<'a>> for #handle<'a> {
+            fn from(handle: ic_kit::rt::handle::CanisterHandle<'a>) -> Self {
+                Self::new(handle)
+            }
+        }
+
+        #[cfg(not(target_family = "wasm"))]
+        impl<'a> std::ops::Deref for #handle<'a> {
+            type Target = ic_kit::rt::handle::CanisterHandle<'a>;
+
+            fn deref(&self) -> &Self::Target {
+                &self.handle
+            }
+        }
+    }
+}
+
+/// Generate the code for the `export_mixin!()` macro, which groups all of the methods declared
+/// so far that are not already part of another mixin under a type implementing `KitMixin`, so
+/// they can be included in a canister defined in another module.
+pub fn export_mixin(name: Ident) -> TokenStream {
+    let mixin = name.to_string();
+    let mut methods = METHODS.lock().unwrap();
+    let mut life_cycles = LIFE_CYCLES.lock().unwrap();
+
+    let mut rust_methods = Vec::new();
+    for method in life_cycles.values_mut().chain(methods.values_mut()) {
+        if method.mixin.is_none() {
+            method.mixin = Some(mixin.clone());
+            rust_methods.push(Ident::new(method.rust_name.as_str(), Span::call_site()));
+        }
+    }
+
+    let gen_tys = generate_service_entries(
+        methods
+            .iter()
+            .filter(|(_, m)| m.mixin.as_deref() == Some(mixin.as_str())),
     );
 
+    quote! {
+        /// The methods exported by this module, which can be included in a canister using
+        /// the `#[mixins(...)]` attribute of `#[derive(KitCanister)]`.
+        pub struct #name;
+
+        impl ic_kit::KitMixin for #name {
+            #[cfg(not(target_family = "wasm"))]
+            fn register(canister: ic_kit::rt::Canister) -> ic_kit::rt::Canister {
+                canister
+                #(
+                    .with_method::<#rust_methods>()
+                )*
+            }
+
+            fn candid_service(
+                env: &mut ic_kit::candid::types::internal::TypeContainer,
+                service: &mut Vec<(String, ic_kit::candid::types::Type)>,
+            ) {
+                use ic_kit::candid::types::{CandidType, Function, Type};
+                #(#gen_tys)*
+            }
+        }
+    }
+}
+
+/// Generate the code for the `export_candid!()` macro, this is an alternative to the
+/// `KitCanister` derive macro for canisters that only want their candid interface to be exported
+/// without having an importable canister type.
+pub fn export_candid(save_candid_path: Option<syn::LitStr>) -> TokenStream {
+    let (methods, mut life_cycles) = take_declared_methods();
+
+    if methods
+        .values()
+        .chain(life_cycles.values())
+        .any(|m| m.mixin.is_some())
+    {
+        return quote! {
+            compile_error!("export_candid! does not support mixins, use #[derive(KitCanister)] with the #[mixins(...)] attribute instead.");
+        };
+    }
+
+    let name = Ident::new("export_candid", Span::call_site());
+    let (_, _, wasm_hooks) = generate_default_hooks(&name, &life_cycles);
+    take_stable_calls();
+    let candid = generate_candid(&methods, &mut life_cycles, &[]);
+
+    let save_candid = if let Some(path) = save_candid_path {
+        generate_save_candid(quote! { __ic_kit_export_candid() }, path)
+    } else {
+        quote! {}
+    };
+
+    quote! {
+        /// Return the candid interface of this canister.
+        #[doc(hidden)]
+        pub fn __ic_kit_export_candid() -> String {
+            #candid
+        }
+
+        #wasm_hooks
+
+        #[cfg(target_family = "wasm")]
+        #[doc(hidden)]
+        #[export_name = "canister_query __get_candid_interface_tmp_hack"]
+        fn _ic_kit_canister_query___get_candid_interface_tmp_hack() {
+            let candid = __ic_kit_export_candid();
+            let bytes = ic_kit::candid::encode_one(candid)
+                .expect("Could not encode canister's response.");
+            ic_kit::utils::reply(&bytes);
+        }
+
+        #save_candid
+    }
+}
+
+/// Take all of the methods that have been declared so far, leaving the registry empty for the
+/// next canister in the crate.
+fn take_declared_methods() -> (BTreeMap<String, Method>, BTreeMap<EntryPoint, Method>) {
+    let methods = {
+        let mut map = METHODS.lock().unwrap();
+        std::mem::replace(&mut *map, BTreeMap::new())
+    };
+
+    let life_cycles = {
+        let mut map = LIFE_CYCLES.lock().unwrap();
+        std::mem::replace(&mut *map, BTreeMap::new())
+    };
+
+    (methods, life_cycles)
+}
+
+/// Generate the body of a function which returns the candid interface of the given methods as
+/// a string.
+fn generate_candid(
+    methods: &BTreeMap<String, Method>,
+    life_cycles: &mut BTreeMap<EntryPoint, Method>,
+    mixins: &[syn::Path],
+) -> TokenStream {
+    let gen_tys = generate_service_entries(methods.iter());
+
     let service = quote! {
         use ic_kit::candid::types::{CandidType, Function, Type};
         let mut service = Vec::<(String, Type)>::new();
         let mut env = ic_kit::candid::types::internal::TypeContainer::new();
         #(#gen_tys)*
+        #(
+            <#mixins as ic_kit::KitMixin>::candid_service(&mut env, &mut service);
+        )*
         service.sort_unstable_by_key(|(name, _)| name.clone());
         let ty = Type::Service(service);
     };
@@ -181,79 +532,130 @@ pub fn export_service(input: DeriveInput, save_candid_path: Option<syn::LitStr>)
         quote! { let actor = Some(ty); }
     };
 
-    let name = input.ident;
+    // Candid has no syntax for the arguments of an upgrade, so they are described by a comment
+    // above the service, whose types are defined with the others.
+    let upgrade = match life_cycles.get(&EntryPoint::PostUpgrade) {
+        Some(post_upgrade) if !post_upgrade.arg_types.is_empty() => {
+            let args = post_upgrade
+                .arg_types
+                .iter()
+                .map(|t| generate_arg(quote! { upgrade_args }, t))
+                .collect::<Vec<_>>();
 
-    let save_candid = if let Some(path) = save_candid_path {
-        quote! {
-            #[cfg(test)]
-            #[test]
-            fn ic_kit_save_candid() {
-                use ic_kit::KitCanister;
-                use std::env;
-                use std::fs;
-                use std::path::PathBuf;
-
-                let candid = #name::candid();
-                let mut path = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
-                path.push(#path);
-                let dir = path.parent().unwrap();
-
-                fs::create_dir_all(dir).unwrap_or_else(|e| {
-                    panic!(
-                        "Failed to create the directory '{}': {}",
-                        dir.as_os_str().to_string_lossy(),
-                        e
-                    )
-                });
-
-                fs::write(&path, candid).unwrap_or_else(|e| {
-                    panic!(
-                        "Failed to write to the file '{}': {}",
-                        path.as_os_str().to_string_lossy(),
-                        e
-                    )
-                });
-
-                println!("Saved candid to: {}", path.as_os_str().to_string_lossy());
+            quote! {
+                let mut upgrade_args = Vec::new();
+                #(#args)*
+                let upgrade = upgrade_args
+                    .iter()
+                    .map(|t| ic_kit::candid::bindings::candid::pp_ty(t).pretty(80).to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                let upgrade = format!("// post_upgrade : ({})\n", upgrade);
             }
         }
-    } else {
-        quote! {}
+        _ => quote! { let upgrade = String::new(); },
     };
 
-    let metadata = generate_metadata();
-
     quote! {
-        #metadata
-
-        impl ic_kit::KitCanister for #name {
-            #[cfg(not(target_family = "wasm"))]
-            fn build(canister_id: ic_kit::Principal) -> ic_kit::rt::Canister {
-                ic_kit::rt::Canister::new(canister_id)
-                #(
-                    .with_method::<#rust_methods>()
-                )*
-            }
-
-            fn candid() -> String {
-                #service
-                #actor
-                let result = ic_kit::candid::bindings::candid::compile(&env.env, &actor);
-                format!("{}", result)
-            }
+        #service
+        #actor
+        #upgrade
+        let result = ic_kit::candid::bindings::candid::compile(&env.env, &actor);
+        match result.rfind("service :") {
+            Some(index) => format!("{}{}{}", &result[..index], upgrade, &result[index..]),
+            None => result,
         }
+    }
+}
 
-        #[cfg(target_family = "wasm")]
-        #[doc(hidden)]
-        #[export_name = "canister_query __get_candid_interface_tmp_hack"]
-        fn _ic_kit_canister_query___get_candid_interface_tmp_hack() {
-            let candid = #name::candid();
-            let bytes = ic_kit::candid::encode_one(candid)
-                .expect("Could not encode canister's response.");
-            ic_kit::utils::reply(&bytes);
-        }
+/// Generate the code which pushes the candid type of each of the given methods to the `service`,
+/// using the type container `env`.
+fn generate_service_entries<'a>(
+    methods: impl Iterator<Item = (&'a String, &'a Method)>,
+) -> Vec<TokenStream> {
+    methods
+        .map(
+            |(
+                name,
+                Method {
+                    arg_types,
+                    rets,
+                    mode,
+                    hidden,
+                    ..
+                },
+            )| {
+                let args = arg_types
+                    .iter()
+                    .map(|t| generate_arg(quote! { args }, t))
+                    .collect::<Vec<_>>();
+
+                let rets = rets
+                    .iter()
+                    .map(|t| generate_arg(quote! { rets }, t))
+                    .collect::<Vec<_>>();
+
+                let modes = match mode {
+                    EntryPoint::Update => quote! { vec![] },
+                    EntryPoint::Query => {
+                        quote! { vec![ic_kit::candid::parser::types::FuncMode::Query] }
+                    }
+                    _ => unreachable!(),
+                };
+
+                if !hidden {
+                    quote! {
+                        {
+                            let mut args = Vec::new();
+                            #(#args)*
+                            let mut rets = Vec::new();
+                            #(#rets)*
+                            let func = Function { args, rets, modes: #modes };
+                            service.push((#name.to_string(), Type::Func(func)));
+                        }
+                    }
+                } else {
+                    quote! {}
+                }
+            },
+        )
+        .collect()
+}
 
-        #save_candid
+/// Generate a test which writes the candid returned by the given expression to the provided path,
+/// relative to the crate's manifest directory.
+fn generate_save_candid(candid: TokenStream, path: syn::LitStr) -> TokenStream {
+    quote! {
+        #[cfg(test)]
+        #[test]
+        fn ic_kit_save_candid() {
+            use std::env;
+            use std::fs;
+            use std::path::PathBuf;
+
+            let candid = #candid;
+            let mut path = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+            path.push(#path);
+            let dir = path.parent().unwrap();
+
+            fs::create_dir_all(dir).unwrap_or_else(|e| {
+                panic!(
+                    "Failed to create the directory '{}': {}",
+                    dir.as_os_str().to_string_lossy(),
+                    e
+                )
+            });
+
+            fs::write(&path, candid).unwrap_or_else(|e| {
+                panic!(
+                    "Failed to write to the file '{}': {}",
+                    path.as_os_str().to_string_lossy(),
+                    e
+                )
+            });
+
+            println!("Saved candid to: {}", path.as_os_str().to_string_lossy());
+        }
     }
 }
 
@@ -264,6 +666,41 @@ fn generate_arg(name: TokenStream, ty: &str) -> TokenStream {
     }
 }
 
+/// Return the owned type a reply can be decoded to, such as `Option<String>` for the reply of a
+/// method returning an `Option<&String>`.
+fn owned_type(ty: syn::Type) -> syn::Type {
+    match ty {
+        syn::Type::Reference(r) => owned_type(*r.elem),
+        syn::Type::Path(p) if p.qself.is_none() && p.path.is_ident("str") => {
+            syn::parse_quote! { String }
+        }
+        syn::Type::Slice(slice) => {
+            let elem = owned_type(*slice.elem);
+            syn::parse_quote! { Vec<#elem> }
+        }
+        syn::Type::Path(mut p) => {
+            for segment in p.path.segments.iter_mut() {
+                if let syn::PathArguments::AngleBracketed(args) = &mut segment.arguments {
+                    for arg in args.args.iter_mut() {
+                        if let syn::GenericArgument::Type(ty) = arg {
+                            *ty = owned_type(ty.clone());
+                        }
+                    }
+                }
+            }
+
+            syn::Type::Path(p)
+        }
+        syn::Type::Tuple(mut tuple) => {
+            tuple.elems = tuple.elems.into_iter().map(owned_type).collect();
+            syn::Type::Tuple(tuple)
+        }
+        syn::Type::Paren(paren) => owned_type(*paren.elem),
+        syn::Type::Group(group) => owned_type(*group.elem),
+        t => t,
+    }
+}
+
 /// Remove the references in a type and makes it an owned type, this is used to parse the return
 /// type when it's using Kit's DI.
 fn remove_reference_recursive(ty: syn::Type) -> syn::Type {
@@ -0,0 +1,30 @@
+//! Generate the query endpoint of the `export_journal!` macro.
+
+use crate::entry::{gen_entry_point_code, EntryPoint};
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::Error;
+
+/// Generate the hidden `__journal_entries` query, which returns a page of the entries of the
+/// journal, and can only be called by the controllers.
+pub fn gen_journal_code() -> Result<TokenStream, Error> {
+    gen_entry_point_code(
+        EntryPoint::Query,
+        quote! { name = "__journal_entries", hidden, only_controller = true },
+        quote! {
+            fn __ic_kit_journal_entries(
+                request: ic_kit::pagination::PageRequest,
+                kind: Option<String>,
+            ) -> Result<
+                ic_kit::pagination::Page<ic_kit::journal::JournalEntry>,
+                String,
+            > {
+                ic_kit::journal::page(
+                    &request,
+                    kind.as_deref(),
+                    ic_kit::pagination::PageLimits::default(),
+                )
+            }
+        },
+    )
+}
@@ -0,0 +1,341 @@
+//! Generate the implementation of `StorageKey` for the `StorageKey` derive macro.
+
+use proc_macro2::{Span, TokenStream};
+use quote::{format_ident, quote};
+use syn::{spanned::Spanned, Data, DeriveInput, Error, Fields, Ident, Type};
+
+/// Implement `StorageKey`, `Storable` and `BoundedStorable` for a struct or an enum whose fields
+/// are storage keys.
+pub fn gen_key_code(input: DeriveInput) -> Result<TokenStream, Error> {
+    if !input.generics.params.is_empty() {
+        return Err(Error::new(
+            input.generics.span(),
+            "StorageKey can not be derived for a type with generic parameters.",
+        ));
+    }
+
+    let name = &input.ident;
+    let version = get_key_version(&input)?;
+    let layout = get_key_layout(&input)?;
+
+    let (size, layout_hash, encode, decode) = match &input.data {
+        Data::Struct(data) => {
+            let (types, pattern, construct) = destructure(&data.fields);
+            let bindings = bindings(types.len());
+            let decode = construct(&decode_fields(&types));
+
+            (
+                quote! { 0 #(+ <#types as ic_kit::stable::StorageKey>::SIZE)* },
+                quote! {
+                    let hash = ic_kit::stable::layout_hash(ic_kit::stable::LAYOUT_SEED, "struct");
+                    #(let hash = ic_kit::stable::layout_combine(
+                        hash,
+                        <#types as ic_kit::stable::StorageKey>::LAYOUT,
+                    );)*
+                    hash
+                },
+                quote! {
+                    let Self #pattern = self;
+                    #(ic_kit::stable::StorageKey::encode_key(#bindings, buf);)*
+                },
+                quote! {
+                    let mut offset = 0;
+                    Self #decode
+                },
+            )
+        }
+        Data::Enum(data) => {
+            if data.variants.len() > 256 {
+                return Err(Error::new(
+                    data.variants.span(),
+                    "StorageKey can not be derived for an enum with more than 256 variants.",
+                ));
+            }
+
+            let mut sizes = Vec::new();
+            let mut layouts = Vec::new();
+            let mut encodes = Vec::new();
+            let mut decodes = Vec::new();
+
+            for (index, variant) in data.variants.iter().enumerate() {
+                let index = index as u8;
+                let ident = &variant.ident;
+                let (types, pattern, construct) = destructure(&variant.fields);
+                let bindings = bindings(types.len());
+                let decode = construct(&decode_fields(&types));
+
+                sizes.push(quote! { 0 #(+ <#types as ic_kit::stable::StorageKey>::SIZE)* });
+                layouts.push(quote! {
+                    let hash = ic_kit::stable::layout_hash(hash, "variant");
+                    #(let hash = ic_kit::stable::layout_combine(
+                        hash,
+                        <#types as ic_kit::stable::StorageKey>::LAYOUT,
+                    );)*
+                });
+                encodes.push(quote! {
+                    Self::#ident #pattern => {
+                        buf.push(#index);
+                        #(ic_kit::stable::StorageKey::encode_key(#bindings, buf);)*
+                    }
+                });
+                decodes.push(quote! {
+                    #index => {
+                        let mut offset = 1;
+                        Self::#ident #decode
+                    }
+                });
+            }
+
+            let message = format!("The key of type {} has no variant {{}}.", name);
+
+            (
+                quote! {{
+                    let mut size = 0;
+                    #(if #sizes > size { size = #sizes; })*
+                    1 + size
+                }},
+                quote! {
+                    let hash = ic_kit::stable::layout_hash(ic_kit::stable::LAYOUT_SEED, "enum");
+                    #(#layouts)*
+                    hash
+                },
+                quote! {
+                    // The variants are padded to the size of the largest one.
+                    let start = buf.len();
+                    match self {
+                        #(#encodes)*
+                    }
+                    buf.resize(start + <Self as ic_kit::stable::StorageKey>::SIZE, 0);
+                },
+                quote! {
+                    match bytes[0] {
+                        #(#decodes)*
+                        variant => panic!(#message, variant),
+                    }
+                },
+            )
+        }
+        Data::Union(data) => {
+            return Err(Error::new(
+                data.union_token.span(),
+                "StorageKey can not be derived for a union.",
+            ))
+        }
+    };
+
+    let (version_push, version_arg, version_size) = match version {
+        Some(version) => (
+            quote! { buf.push(#version); },
+            quote! { Some(#version) },
+            1usize,
+        ),
+        None => (TokenStream::new(), quote! { None }, 0usize),
+    };
+
+    let layout_assertion = layout.map(|layout| {
+        let message = format!(
+            "The layout of the storage key {} changed, the keys written with the pinned layout \
+             can not be read anymore. Bump its key_version and pin the new layout.",
+            name
+        );
+
+        quote! {
+            const _: () = assert!(
+                <#name as ic_kit::stable::StorageKey>::LAYOUT == #layout,
+                #message
+            );
+        }
+    });
+
+    Ok(quote! {
+        impl ic_kit::stable::StorageKey for #name {
+            const SIZE: usize = #size;
+            const LAYOUT: u64 = { #layout_hash };
+
+            fn encode_key(&self, buf: &mut Vec<u8>) {
+                #encode
+            }
+
+            #[allow(unused_assignments, unused_mut)]
+            fn decode_key(bytes: &[u8]) -> Self {
+                #decode
+            }
+        }
+
+        impl ic_kit::stable::Storable for #name {
+            fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+                let mut buf = Vec::with_capacity(
+                    <Self as ic_kit::stable::StorageKey>::SIZE + #version_size,
+                );
+                #version_push
+                ic_kit::stable::StorageKey::encode_key(self, &mut buf);
+                std::borrow::Cow::Owned(buf)
+            }
+
+            fn from_bytes(bytes: Vec<u8>) -> Self {
+                ic_kit::stable::decode_versioned_key(&bytes, #version_arg)
+            }
+        }
+
+        impl ic_kit::stable::BoundedStorable for #name {
+            const MAX_SIZE: u32 =
+                (<Self as ic_kit::stable::StorageKey>::SIZE + #version_size) as u32;
+        }
+
+        #layout_assertion
+    })
+}
+
+/// The constructor of a struct or a variant from the expressions of its fields.
+type Construct = Box<dyn Fn(&[TokenStream]) -> TokenStream>;
+
+/// Return the types of the fields, the pattern which binds them to `__key_N`, and the constructor
+/// of the struct or the variant.
+fn destructure(fields: &Fields) -> (Vec<Type>, TokenStream, Construct) {
+    let types = fields.iter().map(|f| f.ty.clone()).collect::<Vec<_>>();
+    let bindings = bindings(types.len());
+
+    match fields {
+        Fields::Named(named) => {
+            let names = named
+                .named
+                .iter()
+                .map(|f| f.ident.clone().unwrap())
+                .collect::<Vec<_>>();
+            let pattern = quote! { { #(#names: #bindings),* } };
+            let construct: Construct = Box::new(move |values| quote! { { #(#names: #values),* } });
+            (types, pattern, construct)
+        }
+        Fields::Unnamed(_) => {
+            let pattern = quote! { ( #(#bindings),* ) };
+            let construct: Construct = Box::new(|values| quote! { ( #(#values),* ) });
+            (types, pattern, construct)
+        }
+        Fields::Unit => (types, TokenStream::new(), Box::new(|_| TokenStream::new())),
+    }
+}
+
+/// The names the fields are bound to.
+fn bindings(count: usize) -> Vec<Ident> {
+    (0..count)
+        .map(|i| format_ident!("__key_{}", i, span = Span::call_site()))
+        .collect()
+}
+
+/// The expressions which decode each field at the current `offset` of the `bytes`.
+fn decode_fields(types: &[Type]) -> Vec<TokenStream> {
+    types
+        .iter()
+        .map(|ty| {
+            quote! {{
+                let value = <#ty as ic_kit::stable::StorageKey>::decode_key(&bytes[offset..]);
+                offset += <#ty as ic_kit::stable::StorageKey>::SIZE;
+                value
+            }}
+        })
+        .collect()
+}
+
+fn get_key_version(input: &DeriveInput) -> Result<Option<u8>, Error> {
+    match input
+        .attrs
+        .iter()
+        .find(|attr| attr.path.is_ident("key_version"))
+    {
+        Some(attr) => Ok(Some(
+            attr.parse_args::<syn::LitInt>()?.base10_parse::<u8>()?,
+        )),
+        None => Ok(None),
+    }
+}
+
+fn get_key_layout(input: &DeriveInput) -> Result<Option<u64>, Error> {
+    match input
+        .attrs
+        .iter()
+        .find(|attr| attr.path.is_ident("key_layout"))
+    {
+        Some(attr) => Ok(Some(
+            attr.parse_args::<syn::LitInt>()?.base10_parse::<u64>()?,
+        )),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syn::parse_quote;
+
+    fn error(input: DeriveInput) -> String {
+        gen_key_code(input).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn expand_struct() {
+        let code = gen_key_code(parse_quote! {
+            #[key_version(3)]
+            #[key_layout(0x1234)]
+            struct Key {
+                owner: Principal,
+                id: u64,
+            }
+        })
+        .unwrap()
+        .to_string();
+
+        assert!(code.contains("impl ic_kit :: stable :: StorageKey for Key"));
+        assert!(code.contains("impl ic_kit :: stable :: Storable for Key"));
+        assert!(code.contains("impl ic_kit :: stable :: BoundedStorable for Key"));
+        assert!(code.contains("< Principal as ic_kit :: stable :: StorageKey > :: SIZE"));
+        assert!(code.contains("buf . push (3u8)"));
+        assert!(code.contains("Some (3u8)"));
+        assert!(code.contains(":: LAYOUT == 4660u64"));
+    }
+
+    #[test]
+    fn expand_enum() {
+        let code = gen_key_code(parse_quote! {
+            enum Key {
+                A(u8),
+                B { id: u64 },
+                C,
+            }
+        })
+        .unwrap()
+        .to_string();
+
+        // The variants are tagged with their index, without a version or a pinned layout.
+        for (index, variant) in ["A", "B", "C"].iter().enumerate() {
+            assert!(code.contains(&format!("Self :: {}", variant)));
+            assert!(code.contains(&format!("buf . push ({}u8)", index)));
+        }
+        assert!(code.contains("The key of type Key has no variant {}."));
+        assert!(code.contains("decode_versioned_key (& bytes , None)"));
+        assert!(!code.contains("assert !"));
+    }
+
+    #[test]
+    fn reject_invalid() {
+        assert_eq!(
+            error(parse_quote! { struct Key<T> { id: T } }),
+            "StorageKey can not be derived for a type with generic parameters."
+        );
+        assert_eq!(
+            error(parse_quote! { union Key { a: u8, b: u16 } }),
+            "StorageKey can not be derived for a union."
+        );
+
+        let variants = (0..257u32).map(|i| format_ident!("V{}", i));
+        assert_eq!(
+            error(parse_quote! { enum Key { #(#variants),* } }),
+            "StorageKey can not be derived for an enum with more than 256 variants."
+        );
+
+        assert!(gen_key_code(parse_quote! {
+            #[key_version(256)]
+            struct Key(u8);
+        })
+        .is_err());
+    }
+}
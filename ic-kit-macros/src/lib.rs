@@ -2,12 +2,21 @@ use proc_macro::TokenStream;
 
 use syn::parse_macro_input;
 
-use entry::{gen_entry_point_code, EntryPoint};
+use entry::{gen_access_code, gen_entry_point_code, AccessAttr, EntryPoint};
 use test::gen_test_code;
 
+mod client;
+mod debug;
 mod entry;
 mod export_service;
+mod journal;
+mod key;
 mod metadata;
+mod metrics;
+mod outbox;
+mod pubsub;
+mod reply_stream;
+mod stable;
 mod test;
 
 fn process_entry_point(
@@ -21,6 +30,20 @@ fn process_entry_point(
 }
 
 /// Export the function as the init hook of the canister.
+///
+/// In the runtime, the hook can be async and make calls to other canisters, such as to fetch its
+/// configuration, and the init is done once the future completes. The Internet Computer does not
+/// let the init hook make calls, it traps there, so the runtime prints a warning when it makes a
+/// call, and this is only meant for the canisters which never leave the tests, such as the mocks
+/// of a service.
+///
+/// ```ignore
+/// #[init]
+/// async fn init(registry: Principal) {
+///     let config: Config = CallBuilder::new(registry, "config").perform_one().await.unwrap();
+///     ic::with_mut(|c: &mut Config| *c = config);
+/// }
+/// ```
 #[proc_macro_attribute]
 pub fn init(attr: TokenStream, item: TokenStream) -> TokenStream {
     process_entry_point(EntryPoint::Init, attr, item)
@@ -33,6 +56,10 @@ pub fn pre_upgrade(attr: TokenStream, item: TokenStream) -> TokenStream {
 }
 
 /// Export the function as the post_upgrade hook of the canister.
+///
+/// The hook can take the upgrade arguments, which are distinct from the arguments of `#[init]`,
+/// such as the parameters of a migration. They are described in the candid of the canister by a
+/// `// post_upgrade : (...)` comment above the service, since candid has no syntax for them.
 #[proc_macro_attribute]
 pub fn post_upgrade(attr: TokenStream, item: TokenStream) -> TokenStream {
     process_entry_point(EntryPoint::PostUpgrade, attr, item)
@@ -51,18 +78,93 @@ pub fn heartbeat(attr: TokenStream, item: TokenStream) -> TokenStream {
 }
 
 /// Export an update method for the canister.
+///
+/// An async update can limit the number of its calls which run at once with `max_concurrent`,
+/// the calls over the limit are rejected, or wait for a slot if `max_queued` lets them, see
+/// `ic_kit::guards::ConcurrencyLimit`.
+///
+/// ```ignore
+/// #[update(max_concurrent = 1, max_queued = 10)]
+/// async fn rebalance() {
+///     // ...
+/// }
+/// ```
 #[proc_macro_attribute]
 pub fn update(attr: TokenStream, item: TokenStream) -> TokenStream {
     process_entry_point(EntryPoint::Update, attr, item)
 }
 
-/// Export a query method for the canister.
+/// Export a query method for the canister, `#[query(composite)]` exports a composite query, which
+/// can call the query methods of other canisters with `ic::query_call`.
 #[proc_macro_attribute]
 pub fn query(attr: TokenStream, item: TokenStream) -> TokenStream {
     process_entry_point(EntryPoint::Query, attr, item)
 }
 
+/// Export a query whose reply can be larger than the message limit, as a query returning the first
+/// page of its candid encoded reply and a `{name}_next` query returning the page following a
+/// token, see `ic_kit::reply_stream`. The function is called again with the same arguments for
+/// each page, so it can not take the state as an argument. The size of the pages can be set with
+/// `page_size`.
+///
+/// ```ignore
+/// #[stream_query(page_size = 500_000)]
+/// fn blocks(from: u64) -> Vec<Block> {
+///     with(|chain: &Chain| chain.blocks[from as usize..].to_vec())
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn stream_query(attr: TokenStream, item: TokenStream) -> TokenStream {
+    reply_stream::gen_stream_query_code(attr.into(), item.into())
+        .unwrap_or_else(|error| error.to_compile_error())
+        .into()
+}
+
+/// Only allow the controllers of the canister to call this method, other callers are rejected
+/// before the arguments are decoded.
+///
+/// ```ignore
+/// #[only_controller]
+/// #[update]
+/// fn set_fee(fee: u64) {}
+/// ```
+#[proc_macro_attribute]
+pub fn only_controller(attr: TokenStream, item: TokenStream) -> TokenStream {
+    gen_access_code(AccessAttr::Controller, attr.into(), item.into())
+        .unwrap_or_else(|error| error.to_compile_error())
+        .into()
+}
+
+/// Only allow the callers which have at least one of the given roles to call this method, the
+/// roles are checked using `ic::has_role`.
+///
+/// ```ignore
+/// #[only(owner, admin)]
+/// #[update]
+/// fn mint(amount: u64) {}
+/// ```
+#[proc_macro_attribute]
+pub fn only(attr: TokenStream, item: TokenStream) -> TokenStream {
+    gen_access_code(AccessAttr::Roles, attr.into(), item.into())
+        .unwrap_or_else(|error| error.to_compile_error())
+        .into()
+}
+
 /// A macro to generate IC-Kit tests.
+///
+/// The canisters listed in the attribute are installed on the replica and their init hooks are
+/// executed before the test, the test function receives a `CanisterHandle` for each of them, in
+/// the same order, which can be preceded by a `&Replica`. A handle can also be taken as the typed
+/// `{Canister}Handle` generated by `#[derive(KitCanister)]`.
+///
+/// ```ignore
+/// use ic_kit::prelude::*;
+///
+/// #[kit_test(CounterCanister, MultiCounterCanister)]
+/// async fn test(replica: &Replica, counter: CounterCanisterHandle<'_>, multi: CanisterHandle<'_>) {
+///     assert_eq!(counter.increment().await.unwrap(), 1);
+/// }
+/// ```
 #[proc_macro_attribute]
 pub fn kit_test(attr: TokenStream, item: TokenStream) -> TokenStream {
     gen_test_code(attr.into(), item.into())
@@ -70,15 +172,336 @@ pub fn kit_test(attr: TokenStream, item: TokenStream) -> TokenStream {
         .into()
 }
 
-#[proc_macro_derive(KitCanister, attributes(candid_path))]
+/// Generate a typed client for a canister from a trait describing its methods.
+///
+/// This generates a `{Trait}Client` struct which performs inter-canister calls to the canister,
+/// and a `Replica{Trait}Client` which calls the canister through a `CanisterHandle` in the runtime
+/// tests. The name of the client can be changed using `#[kit_client(name = "...")]`.
+///
+/// ```ignore
+/// use ic_kit::prelude::*;
+///
+/// #[kit_client]
+/// pub trait Counter {
+///     fn increment_by(n: u8) -> u64;
+///     fn get_counter() -> u64;
+/// }
+///
+/// async fn increment_remote(counter: Principal) -> u64 {
+///     CounterClient::new(counter).increment_by(5).await.unwrap()
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn kit_client(attr: TokenStream, item: TokenStream) -> TokenStream {
+    client::gen_client_code(attr.into(), item.into())
+        .unwrap_or_else(|error| error.to_compile_error())
+        .into()
+}
+
+#[proc_macro_derive(KitCanister, attributes(candid_path, mixins))]
 pub fn kit_export(input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input as syn::DeriveInput);
     let save_candid_path_result = get_save_candid_path(&input);
+    let mixins_result = get_mixins(&input);
 
-    match save_candid_path_result {
-        Ok(save_candid_path) => export_service::export_service(input, save_candid_path).into(),
-        Err(e) => e.to_compile_error().into(),
+    match (save_candid_path_result, mixins_result) {
+        (Ok(save_candid_path), Ok(mixins)) => {
+            export_service::export_service(input, save_candid_path, mixins).into()
+        }
+        (Err(e), _) | (_, Err(e)) => e.to_compile_error().into(),
+    }
+}
+
+/// Group all of the methods declared so far in the module under a type with the given name, so
+/// that they can be included in a canister using `#[mixins(...)]`.
+///
+/// Since the methods are collected in the order they are expanded, the modules that export a
+/// mixin must be declared before the rest of the canister's methods. Name collisions between the
+/// methods of different mixins are reported at compile time.
+///
+/// ```ignore
+/// mod icrc1 {
+///     use ic_kit::prelude::*;
+///
+///     #[query]
+///     fn icrc1_name() -> String {
+///         "Token".into()
+///     }
+///
+///     export_mixin!(Icrc1);
+/// }
+///
+/// #[derive(KitCanister)]
+/// #[mixins(icrc1::Icrc1)]
+/// pub struct TokenCanister;
+/// ```
+#[proc_macro]
+pub fn export_mixin(input: TokenStream) -> TokenStream {
+    let name = parse_macro_input!(input as syn::Ident);
+    export_service::export_mixin(name).into()
+}
+
+/// Export the candid interface of all of the methods defined so far in the crate, and a hidden
+/// `__get_candid_interface_tmp_hack` query which returns it.
+///
+/// This is an alternative to `#[derive(KitCanister)]` and they should not be used together. The
+/// macro can optionally be given a path to which a test will write the generated candid file.
+///
+/// ```ignore
+/// use ic_kit::prelude::*;
+///
+/// export_candid!("candid.did");
+/// ```
+#[proc_macro]
+pub fn export_candid(input: TokenStream) -> TokenStream {
+    let save_candid_path = if input.is_empty() {
+        None
+    } else {
+        Some(parse_macro_input!(input as syn::LitStr))
+    };
+
+    export_service::export_candid(save_candid_path).into()
+}
+
+/// Export a hidden `__metrics` query which returns the metrics collected for the methods marked
+/// with the `instrument` flag, either in the Prometheus exposition format (default) or as `text`.
+///
+/// Like the methods, this must come before `#[derive(KitCanister)]`.
+///
+/// ```ignore
+/// use ic_kit::prelude::*;
+///
+/// #[update(instrument)]
+/// fn transfer(to: Principal, amount: u64) -> Result<(), String> {
+///     // ...
+/// }
+///
+/// export_metrics!();
+/// ```
+#[proc_macro]
+pub fn export_metrics(input: TokenStream) -> TokenStream {
+    let format = if input.is_empty() {
+        None
+    } else {
+        Some(parse_macro_input!(input as syn::Ident))
+    };
+
+    metrics::gen_metrics_code(format)
+        .unwrap_or_else(|error| error.to_compile_error())
+        .into()
+}
+
+/// Export the hidden `__journal_entries` query, which lets the controllers read the entries of the
+/// `ic_kit::journal` page by page, only with the events of the given kind if it's set.
+///
+/// Like the methods, this must come before `#[derive(KitCanister)]`.
+///
+/// ```ignore
+/// use ic_kit::prelude::*;
+///
+/// export_journal!();
+/// ```
+#[proc_macro]
+pub fn export_journal(input: TokenStream) -> TokenStream {
+    if !input.is_empty() {
+        return syn::Error::new(
+            proc_macro2::Span::call_site(),
+            "export_journal! does not take any arguments.",
+        )
+        .to_compile_error()
+        .into();
     }
+
+    journal::gen_journal_code()
+        .unwrap_or_else(|error| error.to_compile_error())
+        .into()
+}
+
+/// Export the hidden `__outbox_entries` query and the `__outbox_retry` and `__outbox_remove`
+/// updates, which let the controllers inspect the calls of the `ic_kit::outbox` and retry or remove
+/// the failed ones.
+///
+/// Like the methods, this must come before `#[derive(KitCanister)]`.
+///
+/// ```ignore
+/// use ic_kit::prelude::*;
+///
+/// export_outbox!();
+/// ```
+#[proc_macro]
+pub fn export_outbox(input: TokenStream) -> TokenStream {
+    if !input.is_empty() {
+        return syn::Error::new(
+            proc_macro2::Span::call_site(),
+            "export_outbox! does not take any arguments.",
+        )
+        .to_compile_error()
+        .into();
+    }
+
+    outbox::gen_outbox_code()
+        .unwrap_or_else(|error| error.to_compile_error())
+        .into()
+}
+
+/// Export the hidden `__pubsub_subscribe` and `__pubsub_unsubscribe` updates of a publisher, which
+/// are called by `ic_kit::pubsub::register` and `ic_kit::pubsub::unregister` from the subscribers.
+///
+/// Like the methods, this must come before `#[derive(KitCanister)]`.
+///
+/// ```ignore
+/// use ic_kit::prelude::*;
+///
+/// #[update]
+/// fn transfer(to: Principal, amount: u64) {
+///     // ...
+///     ic_kit::pubsub::publish(&Transferred { to, amount });
+/// }
+///
+/// export_pubsub!();
+/// ```
+#[proc_macro]
+pub fn export_pubsub(input: TokenStream) -> TokenStream {
+    if !input.is_empty() {
+        return syn::Error::new(
+            proc_macro2::Span::call_site(),
+            "export_pubsub! does not take any arguments.",
+        )
+        .to_compile_error()
+        .into();
+    }
+
+    pubsub::gen_pubsub_code()
+        .unwrap_or_else(|error| error.to_compile_error())
+        .into()
+}
+
+/// Export a mixin with the hidden `__debug_keys` and `__debug_dump` queries, which let the
+/// controllers dump the given states of the canister, or a part of them, as candid or JSON, see
+/// `ic_kit::debug::dump`. Each state is given with the key it's dumped by.
+///
+/// The endpoints are only compiled when the `debug-endpoints` feature of ic-kit is enabled, so they
+/// are not exposed by accident. Like any mixin, it must be exported from its own module, which is
+/// declared before the rest of the canister's methods.
+///
+/// ```ignore
+/// mod debug {
+///     use super::*;
+///
+///     export_debug!(Debug, balances: Balances, registry: Registry);
+/// }
+///
+/// #[derive(KitCanister)]
+/// #[mixins(debug::Debug)]
+/// pub struct TokenCanister;
+/// ```
+#[proc_macro]
+pub fn export_debug(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as debug::DebugInput);
+    debug::gen_debug_code(input)
+        .unwrap_or_else(|error| error.to_compile_error())
+        .into()
+}
+
+/// Embed sections in the metadata of the canister, as `icp:public <name>` or
+/// `icp:private <name>` custom sections of its wasm module. The public sections can be read by
+/// anyone with a `read_state` request, and the private ones only by the controllers of the
+/// canister.
+///
+/// The sections are also added to the canister built by `#[derive(KitCanister)]` for the tests,
+/// so like the methods this must come before it.
+///
+/// ```ignore
+/// use ic_kit::prelude::*;
+///
+/// metadata! {
+///     public "supported_standards" = "ICRC-1,ICRC-2",
+///     private "build_notes" = b"...",
+/// }
+/// ```
+#[proc_macro]
+pub fn metadata(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as metadata::Sections);
+    metadata::gen_metadata_code(input)
+        .unwrap_or_else(|error| error.to_compile_error())
+        .into()
+}
+
+/// Persist a state struct across upgrades, it's written to the stable storage at the end of the
+/// `pre_upgrade` hook, after the callbacks registered with `ic::on_pre_upgrade`, and read back at
+/// the start of the `post_upgrade` hook, before the body and the `ic::on_post_upgrade` callbacks.
+///
+/// The data is prefixed with the version provided by `#[stable_version(n)]` (default `0`), and the
+/// upgrade traps if the stored version does not match. The hooks are generated by
+/// `#[derive(KitCanister)]` unless the canister defines them, so the state can be migrated with
+/// the arguments of a `#[post_upgrade]` function. The state must be derived in the module of the
+/// canister, before its hooks and `#[derive(KitCanister)]`.
+///
+/// By default the state is stored in the whole stable memory, `#[stable_memory(id)]` stores it in
+/// a virtual memory of the canister's `MemoryManager` instead, so it can be used along the stable
+/// structures.
+///
+/// ```ignore
+/// use ic_kit::prelude::*;
+///
+/// #[derive(Default, CandidType, Deserialize, KitStable)]
+/// #[stable_version(1)]
+/// pub struct State {
+///     users: Vec<Principal>,
+/// }
+/// ```
+#[proc_macro_derive(KitStable, attributes(stable_version, stable_memory))]
+pub fn kit_stable(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as syn::DeriveInput);
+    stable::gen_stable_code(input)
+        .unwrap_or_else(|error| error.to_compile_error())
+        .into()
+}
+
+/// Implement `ic_kit::pubsub::Event` for a type, so it can be published to the subscribed
+/// canisters. The topic of the events is the name of the type unless it's given with
+/// `#[topic("...")]`.
+///
+/// ```ignore
+/// use ic_kit::prelude::*;
+///
+/// #[derive(CandidType, Deserialize, Event)]
+/// #[topic("ledger.transfer")]
+/// pub struct Transferred {
+///     to: Principal,
+///     amount: u64,
+/// }
+/// ```
+#[proc_macro_derive(Event, attributes(topic))]
+pub fn event(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as syn::DeriveInput);
+    pubsub::gen_event_code(input)
+        .unwrap_or_else(|error| error.to_compile_error())
+        .into()
+}
+
+/// Implement `ic_kit::stable::StorageKey` for a struct or an enum, along with `Storable` and
+/// `BoundedStorable`, so it can be used as the key of a `StableBTreeMap` whose keys are iterated in
+/// the order of the type. The keys start with the version given by `#[key_version(n)]` if there is
+/// one, and `#[key_layout(...)]` pins the fingerprint of the encoding, so the build fails if a
+/// change of the type would orphan the keys already stored.
+///
+/// ```ignore
+/// use ic_kit::prelude::*;
+///
+/// #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, StorageKey)]
+/// #[key_version(1)]
+/// pub struct AllowanceKey {
+///     owner: Principal,
+///     spender: Principal,
+/// }
+/// ```
+#[proc_macro_derive(StorageKey, attributes(key_version, key_layout))]
+pub fn storage_key(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as syn::DeriveInput);
+    key::gen_key_code(input)
+        .unwrap_or_else(|error| error.to_compile_error())
+        .into()
 }
 
 fn get_save_candid_path(input: &syn::DeriveInput) -> syn::Result<Option<syn::LitStr>> {
@@ -95,3 +518,20 @@ fn get_save_candid_path(input: &syn::DeriveInput) -> syn::Result<Option<syn::Lit
         None => Ok(None),
     }
 }
+
+fn get_mixins(input: &syn::DeriveInput) -> syn::Result<Vec<syn::Path>> {
+    let mut mixins = Vec::new();
+
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path.is_ident("mixins"))
+    {
+        let paths = attr.parse_args_with(
+            syn::punctuated::Punctuated::<syn::Path, syn::Token![,]>::parse_terminated,
+        )?;
+        mixins.extend(paths);
+    }
+
+    Ok(mixins)
+}
@@ -1,6 +1,129 @@
+use std::sync::Mutex;
+
 use compile_time_run::run_command_str;
+use lazy_static::lazy_static;
 use proc_macro2::{Ident, Literal, Span, TokenStream};
 use quote::quote;
+use syn::parse::{Parse, ParseStream};
+use syn::punctuated::Punctuated;
+use syn::{Error, LitStr, Token};
+
+/// A section of the metadata of the canister declared with `metadata!`.
+pub struct Section {
+    public: bool,
+    name: LitStr,
+    data: Vec<u8>,
+}
+
+lazy_static! {
+    static ref SECTIONS: Mutex<Vec<(bool, String, Vec<u8>)>> = Mutex::new(Vec::new());
+}
+
+/// The sections of a `metadata!` macro, `public|private "name" = "value"` separated by commas.
+pub struct Sections(Punctuated<Section, Token![,]>);
+
+impl Parse for Sections {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        Punctuated::parse_terminated(input).map(Sections)
+    }
+}
+
+impl Parse for Section {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let visibility = input.parse::<Ident>()?;
+        let public =
+            match visibility.to_string().as_str() {
+                "public" => true,
+                "private" => false,
+                _ => return Err(Error::new(
+                    visibility.span(),
+                    "The visibility of a section of the metadata must be `public` or `private`.",
+                )),
+            };
+
+        let name = input.parse::<LitStr>()?;
+        if name.value().is_empty() || name.value().contains(char::is_whitespace) {
+            return Err(Error::new(
+                name.span(),
+                "The name of a section of the metadata can not be empty or contain whitespace.",
+            ));
+        }
+
+        input.parse::<Token![=]>()?;
+        let data = match input.parse::<syn::Lit>()? {
+            syn::Lit::Str(value) => value.value().into_bytes(),
+            syn::Lit::ByteStr(value) => value.value(),
+            lit => {
+                return Err(Error::new(
+                    lit.span(),
+                    "The value of a section of the metadata must be a string or a byte string.",
+                ))
+            }
+        };
+
+        Ok(Section { public, name, data })
+    }
+}
+
+/// Embed the sections in the custom sections of the wasm module, and declare them so that
+/// `#[derive(KitCanister)]` adds them to the canister in the tests.
+pub fn gen_metadata_code(sections: Sections) -> Result<TokenStream, Error> {
+    let mut declared = SECTIONS.lock().unwrap();
+    let mut statics = Vec::new();
+
+    for section in sections.0 {
+        let name = section.name.value();
+
+        if declared.iter().any(|(_, n, _)| *n == name) {
+            return Err(Error::new(
+                section.name.span(),
+                format!(
+                    "The section '{}' of the metadata is already declared.",
+                    name
+                ),
+            ));
+        }
+
+        let link_section = format!(
+            "icp:{} {}",
+            if section.public { "public" } else { "private" },
+            name
+        );
+        let data = Literal::byte_string(&section.data);
+        let len = section.data.len();
+
+        statics.push(quote! {
+            #[cfg(target_family = "wasm")]
+            const _: () = {
+                #[link_section = #link_section]
+                #[used]
+                static SECTION: [u8; #len] = *#data;
+            };
+        });
+
+        declared.push((section.public, name, section.data));
+    }
+
+    Ok(quote! { #(#statics)* })
+}
+
+/// Take the sections declared so far with `metadata!` and generate the calls which add them to
+/// the canister in `build`, leaving the registry empty for the next canister in the crate.
+pub fn take_declared_metadata() -> TokenStream {
+    let sections = std::mem::take(&mut *SECTIONS.lock().unwrap());
+
+    let calls = sections.into_iter().map(|(public, name, data)| {
+        let data = Literal::byte_string(&data);
+
+        if public {
+            quote! { .with_public_metadata(#name, &#data[..]) }
+        } else {
+            quote! { .with_private_metadata(#name, &#data[..]) }
+        }
+    });
+
+    quote! { #(#calls)* }
+}
 
 pub fn generate_static_string<T: ToString>(key: T, val: T) -> TokenStream {
     let val = val.to_string();
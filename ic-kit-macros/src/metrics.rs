@@ -0,0 +1,32 @@
+//! Generate the `__metrics` query for the `export_metrics!` macro.
+
+use crate::entry::{gen_entry_point_code, EntryPoint};
+use proc_macro2::{Ident, TokenStream};
+use quote::quote;
+use syn::Error;
+
+/// Generate a hidden `__metrics` query that returns the metrics of the instrumented methods, in
+/// either the `prometheus` (default) or the `text` format.
+pub fn gen_metrics_code(format: Option<Ident>) -> Result<TokenStream, Error> {
+    let render = match &format {
+        None => quote! { to_prometheus },
+        Some(format) if format == "prometheus" => quote! { to_prometheus },
+        Some(format) if format == "text" => quote! { to_text },
+        Some(format) => {
+            return Err(Error::new(
+                format.span(),
+                "Expected the metrics format to be either `prometheus` or `text`.",
+            ))
+        }
+    };
+
+    gen_entry_point_code(
+        EntryPoint::Query,
+        quote! { name = "__metrics", hidden },
+        quote! {
+            fn __ic_kit_metrics() -> String {
+                ic_kit::ic::metrics().#render()
+            }
+        },
+    )
+}
@@ -0,0 +1,46 @@
+//! Generate the inspection endpoints of the `export_outbox!` macro.
+
+use crate::entry::{gen_entry_point_code, EntryPoint};
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::Error;
+
+/// Generate the hidden `__outbox_entries` query and the `__outbox_retry` and `__outbox_remove`
+/// updates, which can only be called by the controllers.
+pub fn gen_outbox_code() -> Result<TokenStream, Error> {
+    let entries = gen_entry_point_code(
+        EntryPoint::Query,
+        quote! { name = "__outbox_entries", hidden, only_controller = true },
+        quote! {
+            fn __ic_kit_outbox_entries() -> Vec<ic_kit::outbox::OutboxEntry> {
+                ic_kit::outbox::entries()
+            }
+        },
+    )?;
+
+    let retry = gen_entry_point_code(
+        EntryPoint::Update,
+        quote! { name = "__outbox_retry", hidden, only_controller = true },
+        quote! {
+            fn __ic_kit_outbox_retry(id: u64) -> bool {
+                ic_kit::outbox::retry(id)
+            }
+        },
+    )?;
+
+    let remove = gen_entry_point_code(
+        EntryPoint::Update,
+        quote! { name = "__outbox_remove", hidden, only_controller = true },
+        quote! {
+            fn __ic_kit_outbox_remove(id: u64) -> Option<ic_kit::outbox::OutboxEntry> {
+                ic_kit::outbox::remove(id)
+            }
+        },
+    )?;
+
+    Ok(quote! {
+        #entries
+        #retry
+        #remove
+    })
+}
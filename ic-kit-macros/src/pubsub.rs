@@ -0,0 +1,53 @@
+//! Generate the code of the `Event` derive macro and of the `export_pubsub!` macro.
+
+use crate::entry::{gen_entry_point_code, EntryPoint};
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::{DeriveInput, Error};
+
+/// Implement the `Event` trait for the type, whose topic is the name of the type unless it's
+/// given by `#[topic("...")]`.
+pub fn gen_event_code(input: DeriveInput) -> Result<TokenStream, Error> {
+    let name = &input.ident;
+    let topic = match input.attrs.iter().find(|attr| attr.path.is_ident("topic")) {
+        Some(attr) => attr.parse_args::<syn::LitStr>()?.value(),
+        None => name.to_string(),
+    };
+
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics ic_kit::pubsub::Event for #name #ty_generics #where_clause {
+            const TOPIC: &'static str = #topic;
+        }
+    })
+}
+
+/// Generate the hidden `__pubsub_subscribe` and `__pubsub_unsubscribe` updates, which are called
+/// by the subscribers to register with the publisher.
+pub fn gen_pubsub_code() -> Result<TokenStream, Error> {
+    let subscribe = gen_entry_point_code(
+        EntryPoint::Update,
+        quote! { name = "__pubsub_subscribe", hidden },
+        quote! {
+            fn __ic_kit_pubsub_subscribe(topic: String, method: String) {
+                ic_kit::pubsub::subscribe(ic_kit::ic::caller(), topic, method);
+            }
+        },
+    )?;
+
+    let unsubscribe = gen_entry_point_code(
+        EntryPoint::Update,
+        quote! { name = "__pubsub_unsubscribe", hidden },
+        quote! {
+            fn __ic_kit_pubsub_unsubscribe(topic: String) {
+                ic_kit::pubsub::unsubscribe(ic_kit::ic::caller(), &topic);
+            }
+        },
+    )?;
+
+    Ok(quote! {
+        #subscribe
+        #unsubscribe
+    })
+}
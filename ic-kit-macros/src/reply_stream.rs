@@ -0,0 +1,129 @@
+//! Generate the queries of the `#[stream_query]` macro.
+
+use crate::entry::{gen_entry_point_code, EntryPoint};
+use proc_macro2::{Ident, Span, TokenStream};
+use quote::quote;
+use serde::Deserialize;
+use serde_tokenstream::from_tokenstream;
+use syn::{spanned::Spanned, Error};
+
+#[derive(Deserialize)]
+struct Config {
+    name: Option<String>,
+    page_size: Option<usize>,
+}
+
+/// Keep the function as it is, and generate the query returning the first page of its reply and
+/// the `{name}_next` query returning the following pages.
+pub fn gen_stream_query_code(attr: TokenStream, item: TokenStream) -> Result<TokenStream, Error> {
+    let attrs = from_tokenstream::<Config>(&attr)?;
+    let fun = syn::parse2::<syn::ItemFn>(item.clone()).map_err(|e| {
+        Error::new(
+            item.span(),
+            format!("#[stream_query] must be above a function. \n{}", e),
+        )
+    })?;
+
+    let signature = &fun.sig;
+    let ident = &signature.ident;
+
+    if signature.asyncness.is_some() || !signature.generics.params.is_empty() {
+        return Err(Error::new(
+            signature.span(),
+            "#[stream_query] must be above a synchronous function with no generic parameters.",
+        ));
+    }
+
+    if let syn::ReturnType::Default = signature.output {
+        return Err(Error::new(
+            signature.span(),
+            "#[stream_query] function must have a return value.",
+        ));
+    }
+
+    let mut args = Vec::with_capacity(signature.inputs.len());
+    let mut types = Vec::with_capacity(signature.inputs.len());
+
+    for input in &signature.inputs {
+        let typed = match input {
+            syn::FnArg::Typed(typed) => typed,
+            syn::FnArg::Receiver(receiver) => {
+                return Err(Error::new(
+                    receiver.span(),
+                    "#[stream_query] can not be used on a method.",
+                ))
+            }
+        };
+
+        let arg = match typed.pat.as_ref() {
+            syn::Pat::Ident(pat) => &pat.ident,
+            pat => {
+                return Err(Error::new(
+                    pat.span(),
+                    "The arguments of a #[stream_query] function must be identifiers.",
+                ))
+            }
+        };
+
+        // The state can not be injected, since the method is called again for each page.
+        if let syn::Type::Reference(ty) = typed.ty.as_ref() {
+            return Err(Error::new(
+                ty.span(),
+                "The arguments of a #[stream_query] function can not be references, use \
+                 `ic::with` to read the state instead.",
+            ));
+        }
+
+        args.push(arg);
+        types.push(typed.ty.as_ref());
+    }
+
+    let name = attrs.name.unwrap_or_else(|| ident.to_string());
+    let next_name = format!("{}_next", name);
+    let page_size = match attrs.page_size {
+        Some(page_size) => quote! { #page_size },
+        None => quote! { ic_kit::reply_stream::DEFAULT_PAGE_SIZE },
+    };
+
+    let first_ident = Ident::new(&format!("__ic_kit_stream_{}", ident), Span::call_site());
+    let next_ident = Ident::new(
+        &format!("__ic_kit_stream_{}_next", ident),
+        Span::call_site(),
+    );
+
+    let first = gen_entry_point_code(
+        EntryPoint::Query,
+        quote! { name = #name },
+        quote! {
+            fn #first_ident(#(#args: #types),*) -> ic_kit::reply_stream::StreamPage {
+                let __ic_kit_args = ic_kit::candid::encode_args((#(&#args,)*))
+                    .expect("Could not encode the arguments.");
+                let __ic_kit_reply = ic_kit::candid::encode_one(#ident(#(#args),*))
+                    .expect("Could not encode the reply.");
+                ic_kit::reply_stream::first_page(__ic_kit_args, __ic_kit_reply, #page_size)
+            }
+        },
+    )?;
+
+    let next = gen_entry_point_code(
+        EntryPoint::Query,
+        quote! { name = #next_name },
+        quote! {
+            fn #next_ident(
+                __ic_kit_token: ic_kit::reply_stream::StreamToken,
+            ) -> Result<ic_kit::reply_stream::StreamPage, String> {
+                let (#(#args,)*): (#(#types,)*) = ic_kit::candid::decode_args(&__ic_kit_token.args)
+                    .map_err(|e| e.to_string())?;
+                let __ic_kit_reply = ic_kit::candid::encode_one(#ident(#(#args),*))
+                    .expect("Could not encode the reply.");
+                ic_kit::reply_stream::next_page(__ic_kit_token, __ic_kit_reply, #page_size)
+            }
+        },
+    )?;
+
+    Ok(quote! {
+        #fun
+        #first
+        #next
+    })
+}
@@ -0,0 +1,154 @@
+//! Generate the functions which save and restore the state for the `KitStable` derive macro, and
+//! call them from the upgrade hooks of the canister.
+
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use proc_macro2::{Ident, TokenStream};
+use quote::{format_ident, quote};
+use syn::{spanned::Spanned, DeriveInput, Error};
+
+use crate::export_service::has_upgrade_hooks;
+
+lazy_static! {
+    /// The types which derive `KitStable` since the last canister, whose state is saved and
+    /// restored by the upgrade hooks of the next canister.
+    static ref STATES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+}
+
+/// Generate a function that saves the state to the stable storage and one that restores it, which
+/// are called by the `pre_upgrade` and `post_upgrade` hooks of the canister, whether they are
+/// defined by the canister or generated by `#[derive(KitCanister)]`.
+pub fn gen_stable_code(input: DeriveInput) -> Result<TokenStream, Error> {
+    if !input.generics.params.is_empty() {
+        return Err(Error::new(
+            input.generics.span(),
+            "KitStable can not be derived for a type with generic parameters.",
+        ));
+    }
+
+    let name = &input.ident;
+    let version = get_stable_version(&input)?;
+
+    // The hooks are generated with the calls to the states declared before them.
+    if has_upgrade_hooks() {
+        return Err(Error::new(
+            name.span(),
+            "KitStable must be derived before the #[pre_upgrade] and #[post_upgrade] functions of the canister, so they save and restore the state.",
+        ));
+    }
+
+    // The snapshot is stored in the whole stable memory, unless a virtual memory of the memory
+    // manager is provided so it can be used along the stable structures. The memory is retrieved
+    // before borrowing the state, to not access the storage while it is borrowed.
+    let (save, restore) = match get_stable_memory(&input)? {
+        Some(id) => (
+            quote! {
+                let memory = ic_kit::ic::with(|manager: &ic_kit::stable::MemoryManager| {
+                    manager.get(ic_kit::stable::MemoryId::new(#id))
+                });
+                ic_kit::ic::with(|state: &#name| {
+                    ic_kit::stable::stable_save_versioned_to(&memory, #version, state)
+                })
+            },
+            quote! {
+                let memory = ic_kit::ic::with(|manager: &ic_kit::stable::MemoryManager| {
+                    manager.get(ic_kit::stable::MemoryId::new(#id))
+                });
+                ic_kit::stable::stable_restore_versioned_from::<_, #name>(&memory, #version)
+            },
+        ),
+        None => (
+            quote! {
+                ic_kit::ic::with(|state: &#name| {
+                    ic_kit::stable::stable_save_versioned(#version, state)
+                })
+            },
+            quote! { ic_kit::stable::stable_restore_versioned::<#name>(#version) },
+        ),
+    };
+
+    STATES.lock().unwrap().push(name.to_string());
+    let save_fn = save_ident(&name.to_string());
+    let restore_fn = restore_ident(&name.to_string());
+
+    Ok(quote! {
+        #[doc(hidden)]
+        #[allow(non_snake_case)]
+        fn #save_fn() {
+            let result = { #save };
+            if let Err(e) = result {
+                ic_kit::ic::trap(&format!("Could not save the stable state: {}", e));
+            }
+        }
+
+        #[doc(hidden)]
+        #[allow(non_snake_case)]
+        fn #restore_fn() {
+            let result = { #restore };
+            match result {
+                Ok(Some(state)) => {
+                    ic_kit::ic::swap(state);
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    ic_kit::ic::trap(&format!("Could not restore the stable state: {}", e));
+                }
+            }
+        }
+    })
+}
+
+/// Return the calls which save the states declared so far, for the `pre_upgrade` hook, and the
+/// calls which restore them, for the `post_upgrade` hook.
+pub fn stable_calls() -> (TokenStream, TokenStream) {
+    calls(&STATES.lock().unwrap())
+}
+
+/// Like [`stable_calls`], but also forget the states, since they belong to the canister whose
+/// hooks are being generated.
+pub fn take_stable_calls() -> (TokenStream, TokenStream) {
+    calls(&std::mem::take(&mut *STATES.lock().unwrap()))
+}
+
+fn calls(states: &[String]) -> (TokenStream, TokenStream) {
+    let save = states.iter().map(|name| save_ident(name));
+    let restore = states.iter().map(|name| restore_ident(name));
+    (quote! { #(#save();)* }, quote! { #(#restore();)* })
+}
+
+fn save_ident(name: &str) -> Ident {
+    format_ident!("__ic_kit_stable_save_{}", name)
+}
+
+fn restore_ident(name: &str) -> Ident {
+    format_ident!("__ic_kit_stable_restore_{}", name)
+}
+
+fn get_stable_version(input: &DeriveInput) -> Result<u32, Error> {
+    match input
+        .attrs
+        .iter()
+        .find(|attr| attr.path.is_ident("stable_version"))
+    {
+        Some(attr) => attr.parse_args::<syn::LitInt>()?.base10_parse::<u32>(),
+        None => Ok(0),
+    }
+}
+
+fn get_stable_memory(input: &DeriveInput) -> Result<Option<u8>, Error> {
+    match input
+        .attrs
+        .iter()
+        .find(|attr| attr.path.is_ident("stable_memory"))
+    {
+        Some(attr) => {
+            let id = attr.parse_args::<syn::LitInt>()?.base10_parse::<u8>()?;
+            if id == u8::MAX {
+                return Err(Error::new(attr.span(), "The memory id 255 is reserved."));
+            }
+            Ok(Some(id))
+        }
+        None => Ok(None),
+    }
+}
@@ -1,9 +1,10 @@
-use proc_macro2::{Span, TokenStream};
+use proc_macro2::{Ident, Span, TokenStream};
 use quote::quote;
+use syn::punctuated::Punctuated;
 use syn::spanned::Spanned;
 use syn::{parse2, Error, ItemFn};
 
-pub fn gen_test_code(_: TokenStream, item: TokenStream) -> Result<TokenStream, Error> {
+pub fn gen_test_code(attr: TokenStream, item: TokenStream) -> Result<TokenStream, Error> {
     let fun: ItemFn = parse2::<ItemFn>(item.clone()).map_err(|e| {
         Error::new(
             item.span(),
@@ -26,18 +27,79 @@ pub fn gen_test_code(_: TokenStream, item: TokenStream) -> Result<TokenStream, E
         ));
     }
 
+    // The canisters to install on the replica, in the order their handles are passed.
+    let canisters = syn::parse::Parser::parse2(
+        Punctuated::<syn::Path, syn::Token![,]>::parse_terminated,
+        attr,
+    )?
+    .into_iter()
+    .collect::<Vec<_>>();
+
+    // Without any canisters the replica is passed by value, otherwise the handles borrow the
+    // replica so the function can optionally take a reference to it as its first argument. The
+    // handles are converted, so the function can take either a `CanisterHandle` or the typed
+    // handle generated for the canister.
+    let call = if canisters.is_empty() {
+        quote! {
+            #name(replica).await;
+        }
+    } else {
+        let handles = (0..canisters.len())
+            .map(|i| Ident::new(&format!("_ic_kit_canister_{}", i), Span::call_site()))
+            .collect::<Vec<_>>();
+
+        let args = match signature.inputs.len() {
+            n if n == canisters.len() => quote! { #(#handles.into()),* },
+            n if n == canisters.len() + 1 => quote! { &replica, #(#handles.into()),* },
+            _ => {
+                return Err(Error::new(
+                    signature.inputs.span(),
+                    format!(
+                        "The #[kit_test] function must take a handle for each of the {} canisters, optionally preceded by a `&Replica`.",
+                        canisters.len()
+                    ),
+                ))
+            }
+        };
+
+        let install = canisters.iter().zip(handles.iter()).enumerate().map(
+            |(index, (canister, handle))| {
+                let index = index as u64;
+                let canister_name = quote!(#canister).to_string();
+
+                quote! {
+                    let #handle = replica.add_canister(
+                        <#canister as ic_kit::KitCanister>::build(ic_kit::rt::replica::canister_id(#index))
+                    );
+
+                    if let Some(e) = #handle.init().await.rejection_message() {
+                        panic!("ic-kit: The init hook of {} failed: {}", #canister_name, e);
+                    }
+                }
+            },
+        );
+
+        quote! {
+            #(#install)*
+            #name(#args).await;
+        }
+    };
+
     Ok(quote! {
         #[test]
         #visibility fn #name() {
             #item
 
             let rt = ic_kit::rt::TokioRuntimeBuilder::new_current_thread()
+                .enable_all()
                 .build()
                 .expect("ic-kit: Could not build tokio runtime.");
 
             rt.block_on(async {
-                let replica = ic_kit::rt::replica::Replica::default();
-                #name(replica).await;
+                let replica = ic_kit::rt::replica::Replica::from_env().await;
+                let _ic_kit_shutdown = replica.shutdown_handle();
+                #call
+                _ic_kit_shutdown.shutdown().await;
             });
         }
     })
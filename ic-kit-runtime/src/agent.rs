@@ -0,0 +1,290 @@
+//! A backend for the replica which sends the calls to the canisters deployed on a live network,
+//! such as a local dfx replica or the mainnet, using `ic-agent`. This lets the smoke tests written
+//! with ic-kit run against the deployed canisters without rewriting them.
+//!
+//! The canisters are not installed by the tests, the canisters added to the replica must have the
+//! ids of the deployed canisters. Each call is sent as an update call and the agent polls for its
+//! reply, and it's signed by the identity given for its sender, the anonymous principal is signed
+//! for by default.
+//!
+//! ```ignore
+//! let replica = Replica::with_agent(
+//!     AgentBackend::local()
+//!         .with_dfx_identity("default")
+//!         .with_identity_for(*users::ALICE, alice_identity),
+//! );
+//!
+//! let counter = replica.add_canister(CounterCanister::build(counter_id));
+//! counter.new_call("increment").with_caller(*users::ALICE).perform().await.assert_ok();
+//! ```
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use candid::Principal;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use ic_agent::agent::{RejectCode, RejectResponse};
+use ic_agent::export::Principal as AgentPrincipal;
+use ic_agent::identity::{AnonymousIdentity, BasicIdentity, Secp256k1Identity};
+use ic_agent::{Agent, AgentError, Identity};
+
+use ic_kit_sys::types::RejectionCode;
+
+use crate::backend::{unsupported, Backend};
+use crate::call::CallReply;
+use crate::canister::Canister;
+use crate::types::{CanisterCall, EntryMode, Env};
+
+/// The url of the local replica started by `dfx start`.
+pub const LOCAL_URL: &str = "http://127.0.0.1:4943";
+
+/// The url of the boundary nodes of the mainnet.
+pub const MAINNET_URL: &str = "https://icp-api.io";
+
+/// The backend which sends the calls to a live network, see [`crate::agent`].
+pub struct AgentBackend {
+    url: String,
+    /// If set, the root key is fetched from the replica, which is needed for a local replica.
+    fetch_root_key: bool,
+    /// How long the calls are valid for, if not the default of the agent.
+    ingress_expiry: Option<Duration>,
+    /// The identity which signs the calls of each sender.
+    identities: HashMap<Principal, Arc<dyn Identity>>,
+    /// The agent of each sender, created once it sends its first call.
+    agents: Mutex<HashMap<Principal, Agent>>,
+}
+
+impl AgentBackend {
+    /// Create a backend which sends the calls to the given url. The root key is fetched from the
+    /// network unless the url is the one of the mainnet.
+    pub fn new<S: Into<String>>(url: S) -> Self {
+        let url = url.into();
+        let mut identities = HashMap::<_, Arc<dyn Identity>>::new();
+        identities.insert(Principal::anonymous(), Arc::new(AnonymousIdentity));
+
+        Self {
+            fetch_root_key: url != MAINNET_URL,
+            url,
+            ingress_expiry: None,
+            identities,
+            agents: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Create a backend which sends the calls to the local replica started by `dfx start`.
+    pub fn local() -> Self {
+        Self::new(LOCAL_URL)
+    }
+
+    /// Create a backend which sends the calls to the mainnet.
+    pub fn mainnet() -> Self {
+        Self::new(MAINNET_URL)
+    }
+
+    /// Sign the calls sent by the principal of the identity with it.
+    pub fn with_identity<I: Identity + 'static>(self, identity: I) -> Self {
+        let sender = identity
+            .sender()
+            .expect("ic-kit-runtime: Could not get the principal of the identity.");
+
+        self.with_identity_for(Principal::from_slice(sender.as_slice()), identity)
+    }
+
+    /// Sign the calls sent by the given principal with the identity, which lets the tests that use
+    /// the mock users, such as [`crate::users::ALICE`], run without changing their callers.
+    pub fn with_identity_for<I: Identity + 'static>(
+        mut self,
+        sender: Principal,
+        identity: I,
+    ) -> Self {
+        self.identities.insert(sender, Arc::new(identity));
+        self
+    }
+
+    /// Sign the calls sent by the principal of the identity in the PEM file with it, the file can
+    /// contain either a secp256k1 or an ed25519 key.
+    ///
+    /// # Panics
+    ///
+    /// If the file can not be read or does not contain a key.
+    pub fn with_pem_file<P: AsRef<Path>>(self, path: P) -> Self {
+        let path = path.as_ref();
+
+        if let Ok(identity) = Secp256k1Identity::from_pem_file(path) {
+            return self.with_identity(identity);
+        }
+
+        match BasicIdentity::from_pem_file(path) {
+            Ok(identity) => self.with_identity(identity),
+            Err(e) => panic!(
+                "ic-kit-runtime: Could not load the identity from '{}': {}",
+                path.display(),
+                e
+            ),
+        }
+    }
+
+    /// Sign the calls sent by the principal of the dfx identity with the given name with it.
+    ///
+    /// # Panics
+    ///
+    /// If the identity does not exist or is encrypted.
+    pub fn with_dfx_identity(self, name: &str) -> Self {
+        let home = std::env::var_os("HOME").expect("ic-kit-runtime: HOME is not set.");
+        let path = PathBuf::from(home)
+            .join(".config/dfx/identity")
+            .join(name)
+            .join("identity.pem");
+
+        self.with_pem_file(path)
+    }
+
+    /// Set how long the calls are valid for, which bounds how long the agent polls for a reply.
+    pub fn with_ingress_expiry(mut self, duration: Duration) -> Self {
+        self.ingress_expiry = Some(duration);
+        self
+    }
+
+    /// Return the agent which signs the calls of the sender, creating it if needed.
+    async fn agent(&self, sender: &Principal) -> Result<Agent, String> {
+        if let Some(agent) = self.agents.lock().unwrap().get(sender) {
+            return Ok(agent.clone());
+        }
+
+        let identity = self.identities.get(sender).cloned().unwrap_or_else(|| {
+            panic!(
+                "ic-kit-runtime: There is no identity to sign the calls of '{}', see AgentBackend::with_identity_for.",
+                sender
+            )
+        });
+
+        let mut builder = Agent::builder()
+            .with_url(self.url.as_str())
+            .with_arc_identity(identity);
+
+        if let Some(expiry) = self.ingress_expiry {
+            builder = builder.with_ingress_expiry(expiry);
+        }
+
+        let agent = builder.build().map_err(|e| e.to_string())?;
+
+        if self.fetch_root_key {
+            agent.fetch_root_key().await.map_err(|e| e.to_string())?;
+        }
+
+        self.agents.lock().unwrap().insert(*sender, agent.clone());
+
+        Ok(agent)
+    }
+
+    /// Send the update call and wait for its reply.
+    async fn update(
+        &self,
+        sender: Principal,
+        canister_id: Principal,
+        method: &str,
+        arg: Vec<u8>,
+    ) -> CallReply {
+        let agent = match self.agent(&sender).await {
+            Ok(agent) => agent,
+            Err(e) => {
+                return CallReply::Reject {
+                    rejection_code: RejectionCode::SysTransient,
+                    rejection_message: e,
+                    cycles_refunded: 0,
+                }
+            }
+        };
+
+        let result = agent
+            .update(&AgentPrincipal::from_slice(canister_id.as_slice()), method)
+            .with_arg(arg)
+            .call_and_wait()
+            .await;
+
+        match result {
+            Ok(data) => CallReply::Reply {
+                data,
+                cycles_refunded: 0,
+            },
+            Err(AgentError::CertifiedReject { reject, .. })
+            | Err(AgentError::UncertifiedReject { reject, .. }) => to_reject(reject),
+            Err(e) => CallReply::Reject {
+                rejection_code: RejectionCode::SysTransient,
+                rejection_message: e.to_string(),
+                cycles_refunded: 0,
+            },
+        }
+    }
+}
+
+impl Backend for AgentBackend {
+    fn name(&self) -> &'static str {
+        "a live network"
+    }
+
+    /// The canister is expected to be deployed already.
+    fn add(&self, _canister: &Canister) {}
+
+    fn call(&self, call: CanisterCall) -> BoxFuture<'_, CallReply> {
+        if call.payment > 0 {
+            unsupported(self.name(), "Sending cycles with an ingress message");
+        }
+
+        async move {
+            self.update(call.sender, call.callee, &call.method, call.arg)
+                .await
+        }
+        .boxed()
+    }
+
+    fn run_env(&self, canister_id: Principal, env: Env) -> BoxFuture<'_, CallReply> {
+        match env.entry_mode {
+            EntryMode::Update | EntryMode::Query => {
+                let method = env
+                    .method_name
+                    .expect("ic-kit-runtime: The message does not have a method name.");
+
+                async move {
+                    self.update(env.sender, canister_id, &method, env.args)
+                        .await
+                }
+                .boxed()
+            }
+            mode => unsupported(self.name(), &format!("Running a {:?} message", mode)),
+        }
+    }
+
+    fn upgrade(&self, _canister: Canister, _arg: Vec<u8>) -> BoxFuture<'_, CallReply> {
+        unsupported(self.name(), "Upgrading a canister")
+    }
+
+    fn balance(&self, _canister_id: Principal) -> BoxFuture<'_, u128> {
+        unsupported(self.name(), "Reading the balance of a canister")
+    }
+
+    fn set_time(&self, _time: u64) {
+        unsupported(self.name(), "Changing the time")
+    }
+
+    fn advance_time(&self, _duration: Duration) {
+        unsupported(self.name(), "Changing the time")
+    }
+}
+
+fn to_reject(reject: RejectResponse) -> CallReply {
+    CallReply::Reject {
+        rejection_code: match reject.reject_code {
+            RejectCode::SysFatal => RejectionCode::SysFatal,
+            RejectCode::SysTransient => RejectionCode::SysTransient,
+            RejectCode::DestinationInvalid => RejectionCode::DestinationInvalid,
+            RejectCode::CanisterReject => RejectionCode::CanisterReject,
+            RejectCode::CanisterError => RejectionCode::CanisterError,
+        },
+        rejection_message: reject.reject_message,
+        cycles_refunded: 0,
+    }
+}
@@ -0,0 +1,51 @@
+//! The backends which run the canisters of a replica outside of the process, such as PocketIC or a
+//! live network, behind the same [`Replica`], [`CanisterHandle`] and [`CallBuilder`] API as the
+//! in-process replica.
+//!
+//! [`Replica`]: crate::Replica
+//! [`CanisterHandle`]: crate::handle::CanisterHandle
+//! [`CallBuilder`]: crate::call::CallBuilder
+
+use std::time::Duration;
+
+use candid::Principal;
+use futures::future::BoxFuture;
+
+use crate::call::CallReply;
+use crate::canister::Canister;
+use crate::types::{CanisterCall, Env};
+
+/// The operations of a replica which are forwarded to a backend.
+pub(crate) trait Backend: Send + Sync {
+    /// The name of the backend, used in the errors.
+    fn name(&self) -> &'static str;
+
+    /// Add the canister to the backend.
+    fn add(&self, canister: &Canister);
+
+    /// Perform the call and return its reply.
+    fn call(&self, call: CanisterCall) -> BoxFuture<'_, CallReply>;
+
+    /// Run the message on the canister.
+    fn run_env(&self, canister_id: Principal, env: Env) -> BoxFuture<'_, CallReply>;
+
+    /// Upgrade the canister to the given build, passing the arguments to its post_upgrade hook.
+    fn upgrade(&self, canister: Canister, arg: Vec<u8>) -> BoxFuture<'_, CallReply>;
+
+    /// Return the cycle balance of the canister.
+    fn balance(&self, canister_id: Principal) -> BoxFuture<'_, u128>;
+
+    /// Fix the time used for the next messages.
+    fn set_time(&self, time: u64);
+
+    /// Move the time used for the next messages forward.
+    fn advance_time(&self, duration: Duration);
+}
+
+/// Panic because the feature is not supported by the backend.
+pub(crate) fn unsupported(backend: &str, what: &str) -> ! {
+    panic!(
+        "ic-kit-runtime: {} is not supported when the replica runs on {}.",
+        what, backend
+    )
+}
@@ -0,0 +1,337 @@
+//! Measure the cost of the calls to a canister, to catch the performance regressions of its
+//! methods locally.
+//!
+//! ```ignore
+//! let report = replica
+//!     .bench(counter.new_call("increment"))
+//!     .iterations(100)
+//!     .run()
+//!     .await;
+//!
+//! println!("{}", report);
+//! std::fs::write("target/increment.json", report.to_json()).unwrap();
+//! ```
+//!
+//! The canisters are executed natively, so the number of instructions of a call is not known, the
+//! number of system API calls it makes is reported instead. The sizes of the replies and of the
+//! payloads of the calls made by the canisters are reported as percentiles, to keep an eye on the
+//! message limits.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use candid::Principal;
+use serde::Serialize;
+
+use crate::call::CallBuilder;
+use crate::ingress::IngressHistory;
+use crate::invariant::Invariants;
+use crate::management::RunStatus;
+use crate::span::CallSpans;
+use crate::types::RequestId;
+
+/// The usage of the replica resources, counted over all of the canisters.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Usage {
+    /// The number of system API calls made by the canisters.
+    pub system_calls: u64,
+    /// The number of messages executed, including the inter-canister calls and their callbacks.
+    pub messages: u64,
+    /// The number of bytes read from the stable memories.
+    pub stable_bytes_read: u64,
+    /// The number of bytes written to the stable memories.
+    pub stable_bytes_written: u64,
+}
+
+/// The counters of a replica, shared with each of its canisters.
+#[derive(Default)]
+pub(crate) struct Counters {
+    pub system_calls: AtomicU64,
+    pub messages: AtomicU64,
+    pub stable_bytes_read: AtomicU64,
+    pub stable_bytes_written: AtomicU64,
+    /// The number of calls to each of the update and query methods of each canister.
+    pub methods: Mutex<BTreeMap<Principal, BTreeMap<String, u64>>>,
+    /// The number of request ids assigned so far.
+    pub request_ids: AtomicU64,
+    /// The spans the calls which are not received yet were made from.
+    pub spans: CallSpans,
+    /// The data certified by each canister, which is kept across upgrades.
+    pub certified_data: Mutex<HashMap<Principal, Vec<u8>>>,
+    /// The largest number of calls made by a single message of each canister.
+    pub max_calls_per_message: Mutex<HashMap<Principal, usize>>,
+    /// The cycle balance and the size of the stable memory in bytes of each canister after its
+    /// last message, which the management canister reports in `canister_status`.
+    pub statuses: Mutex<HashMap<Principal, (u128, u64)>>,
+    /// The observations and the invariants checked after each message.
+    pub invariants: Invariants,
+    /// The statuses of the calls made by the users.
+    pub ingress: IngressHistory,
+    /// The canisters which are stopping or stopped by the management canister, the others are
+    /// running.
+    pub run_statuses: Mutex<HashMap<Principal, RunStatus>>,
+    /// The seed the random bytes returned by `raw_rand` are derived from.
+    pub beacon_seed: AtomicU64,
+    /// The panic message of each canister whose execution task crashed, see
+    /// [`CanisterHealth`].
+    ///
+    /// [`CanisterHealth`]: crate::handle::CanisterHealth
+    pub crashes: Mutex<HashMap<Principal, String>>,
+    /// The compute allocation of each canister which has one, in percent.
+    pub compute_allocations: Mutex<HashMap<Principal, u8>>,
+}
+
+/// A benchmark of a call, created by [`Replica::bench`].
+///
+/// [`Replica::bench`]: crate::Replica::bench
+pub struct Bench<'a> {
+    call: CallBuilder<'a>,
+    iterations: usize,
+}
+
+/// The cost of a call, measured over several iterations.
+#[derive(Clone, Debug, Serialize)]
+pub struct BenchReport {
+    /// The name of the method which was called.
+    pub method: String,
+    /// The number of times the call was performed.
+    pub iterations: usize,
+    /// The number of calls which were rejected.
+    pub rejected: usize,
+    /// The wall time of each call, in nanoseconds.
+    pub wall_time_ns: Vec<u64>,
+    /// The size of the argument of the call in bytes.
+    pub arg_size: usize,
+    /// The size of the reply of each call in bytes, see [`CallReply::reply_size`].
+    ///
+    /// [`CallReply::reply_size`]: crate::call::CallReply::reply_size
+    pub reply_sizes: Vec<usize>,
+    /// The sizes in bytes of the arguments and of the replies of the calls made by the canisters
+    /// during the iterations.
+    pub payload_sizes: Vec<usize>,
+    /// The average usage of a call.
+    pub usage: Usage,
+}
+
+impl Counters {
+    /// Return the message the calls to the canister are rejected with if its execution task
+    /// crashed.
+    pub fn crash_message(&self, canister_id: Principal) -> Option<String> {
+        self.crashes.lock().unwrap().get(&canister_id).map(|panic| {
+            format!(
+                "Canister '{}' crashed, its execution task panicked: {}",
+                canister_id, panic
+            )
+        })
+    }
+
+    /// Assign a new request id.
+    pub fn next_request_id(&self) -> RequestId {
+        RequestId::new(self.request_ids.fetch_add(1, Ordering::SeqCst))
+    }
+
+    /// Start the request ids from the given seed, the ids are `seed << 32` onwards.
+    ///
+    /// # Panics
+    ///
+    /// If request ids were already assigned.
+    pub fn seed_request_ids(&self, seed: u64) {
+        if self.request_ids.load(Ordering::SeqCst) != 0 {
+            panic!("ic-kit-runtime: The request id seed must be set before the first call.");
+        }
+
+        self.request_ids.store(seed << 32, Ordering::SeqCst);
+    }
+
+    /// Return the usage counted so far.
+    pub fn usage(&self) -> Usage {
+        Usage {
+            system_calls: self.system_calls.load(Ordering::SeqCst),
+            messages: self.messages.load(Ordering::SeqCst),
+            stable_bytes_read: self.stable_bytes_read.load(Ordering::SeqCst),
+            stable_bytes_written: self.stable_bytes_written.load(Ordering::SeqCst),
+        }
+    }
+}
+
+impl Usage {
+    fn since(&self, start: &Usage) -> Usage {
+        Usage {
+            system_calls: self.system_calls - start.system_calls,
+            messages: self.messages - start.messages,
+            stable_bytes_read: self.stable_bytes_read - start.stable_bytes_read,
+            stable_bytes_written: self.stable_bytes_written - start.stable_bytes_written,
+        }
+    }
+}
+
+impl<'a> Bench<'a> {
+    pub(crate) fn new(call: CallBuilder<'a>) -> Self {
+        Self {
+            call,
+            iterations: 10,
+        }
+    }
+
+    /// Set the number of times the call is performed, the default is 10.
+    pub fn iterations(mut self, iterations: usize) -> Self {
+        assert!(iterations > 0, "A benchmark needs at least one iteration.");
+        self.iterations = iterations;
+        self
+    }
+
+    /// Perform the call the given number of times, one after the other, and report its cost.
+    pub async fn run(self) -> BenchReport {
+        let counters = self.call.replica().counters();
+        let start = counters.usage();
+        let payloads_start = self.call.replica().payload_count();
+        let mut wall_time_ns = Vec::with_capacity(self.iterations);
+        let mut reply_sizes = Vec::with_capacity(self.iterations);
+        let mut rejected = 0;
+
+        for _ in 0..self.iterations {
+            let now = Instant::now();
+            let reply = self.call.perform().await;
+            wall_time_ns.push(now.elapsed().as_nanos() as u64);
+            reply_sizes.push(reply.reply_size());
+
+            if reply.is_error() {
+                rejected += 1;
+            }
+        }
+
+        let total = counters.usage().since(&start);
+        let n = self.iterations as u64;
+        let payload_sizes = self
+            .call
+            .replica()
+            .payload_sizes_since(payloads_start)
+            .into_iter()
+            .filter(|payload| payload.origin.is_some())
+            .flat_map(|payload| [payload.arg_size, payload.reply_size])
+            .collect();
+
+        BenchReport {
+            method: self.call.method_name().to_string(),
+            iterations: self.iterations,
+            rejected,
+            wall_time_ns,
+            arg_size: self.call.arg_size(),
+            reply_sizes,
+            payload_sizes,
+            usage: Usage {
+                system_calls: total.system_calls / n,
+                messages: total.messages / n,
+                stable_bytes_read: total.stable_bytes_read / n,
+                stable_bytes_written: total.stable_bytes_written / n,
+            },
+        }
+    }
+}
+
+impl BenchReport {
+    /// The mean wall time of a call.
+    pub fn mean(&self) -> Duration {
+        let total = self.wall_time_ns.iter().sum::<u64>();
+        Duration::from_nanos(total / self.wall_time_ns.len() as u64)
+    }
+
+    /// The median wall time of a call.
+    pub fn median(&self) -> Duration {
+        let mut samples = self.wall_time_ns.clone();
+        samples.sort_unstable();
+        Duration::from_nanos(samples[samples.len() / 2])
+    }
+
+    /// The shortest wall time of a call.
+    pub fn min(&self) -> Duration {
+        Duration::from_nanos(*self.wall_time_ns.iter().min().unwrap())
+    }
+
+    /// The longest wall time of a call.
+    pub fn max(&self) -> Duration {
+        Duration::from_nanos(*self.wall_time_ns.iter().max().unwrap())
+    }
+
+    /// The size of the reply below which the given percentage of the replies are, in bytes.
+    pub fn reply_size_percentile(&self, percentile: f64) -> usize {
+        nearest_rank(&self.reply_sizes, percentile)
+    }
+
+    /// The size of the payload below which the given percentage of the arguments and the replies of
+    /// the calls made by the canisters are, in bytes, or `None` if they made no calls.
+    pub fn payload_size_percentile(&self, percentile: f64) -> Option<usize> {
+        if self.payload_sizes.is_empty() {
+            return None;
+        }
+
+        Some(nearest_rank(&self.payload_sizes, percentile))
+    }
+
+    /// Render the report as JSON, to be stored and compared between the runs.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("ic-kit-runtime: Could not serialize the report.")
+    }
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{}: {} iterations, {} rejected",
+            self.method, self.iterations, self.rejected
+        )?;
+        writeln!(
+            f,
+            "  wall time:    mean {:?}, median {:?}, min {:?}, max {:?}",
+            self.mean(),
+            self.median(),
+            self.min(),
+            self.max()
+        )?;
+        writeln!(f, "  arg size:     {} bytes", self.arg_size)?;
+        writeln!(
+            f,
+            "  reply size:   p50 {}, p90 {}, p99 {}, max {} bytes",
+            self.reply_size_percentile(50.0),
+            self.reply_size_percentile(90.0),
+            self.reply_size_percentile(99.0),
+            self.reply_size_percentile(100.0)
+        )?;
+        if !self.payload_sizes.is_empty() {
+            writeln!(
+                f,
+                "  payloads:     p50 {}, p90 {}, p99 {}, max {} bytes over {} payloads",
+                self.payload_size_percentile(50.0).unwrap(),
+                self.payload_size_percentile(90.0).unwrap(),
+                self.payload_size_percentile(99.0).unwrap(),
+                self.payload_size_percentile(100.0).unwrap(),
+                self.payload_sizes.len()
+            )?;
+        }
+        writeln!(f, "  system calls: {}", self.usage.system_calls)?;
+        writeln!(f, "  messages:     {}", self.usage.messages)?;
+        writeln!(
+            f,
+            "  stable I/O:   {} bytes read, {} bytes written",
+            self.usage.stable_bytes_read, self.usage.stable_bytes_written
+        )
+    }
+}
+
+/// Return the sample at the given percentile of the samples, with the nearest rank method.
+pub(crate) fn nearest_rank<T: Copy + Ord>(samples: &[T], percentile: f64) -> T {
+    assert!(
+        (0.0..=100.0).contains(&percentile),
+        "ic-kit-runtime: The percentile must be between 0 and 100, got {}.",
+        percentile
+    );
+
+    let mut samples = samples.to_vec();
+    samples.sort_unstable();
+    let rank = (percentile / 100.0 * samples.len() as f64).ceil() as usize;
+    samples[rank.max(1) - 1]
+}
@@ -0,0 +1,70 @@
+//! A mock of the blackhole canister, a canister without controllers which is added as a controller
+//! of other canisters so anyone can read their status through it, such as the cycle monitoring
+//! dashboards, see [`Replica::blackhole`].
+//!
+//! The mock replies to `canister_status` with the status returned by the management canister for
+//! the calls made by the blackhole, see [`crate::management`], so the status is only returned for
+//! the canisters which have the blackhole as a controller, and the call is rejected otherwise.
+//!
+//! ```ignore
+//! replica.blackhole();
+//!
+//! let ledger = replica.add_canister(LedgerCanister::anonymous().with_controller(BLACKHOLE_CANISTER_ID));
+//! let status = replica
+//!     .get_canister(BLACKHOLE_CANISTER_ID)
+//!     .new_call("canister_status")
+//!     .with_arg(CanisterIdRecord { canister_id: ledger.canister_id() })
+//!     .perform()
+//!     .await;
+//! ```
+//!
+//! [`Replica::blackhole`]: crate::Replica::blackhole
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use candid::Principal;
+
+use crate::bench::Counters;
+use crate::canister::{Canister, CanisterCode};
+use crate::management::{canister_status, MANAGEMENT_CANDID};
+use crate::mock::{MockCall, MockCanister, MockRecorder};
+
+/// The id of the blackhole canister on the mainnet, `e3mmv-5qaaa-aaaah-aadma-cai`.
+pub const BLACKHOLE_CANISTER_ID: Principal =
+    Principal::from_slice(&[0, 0, 0, 0, 0, 224, 0, 216, 1, 1]);
+
+/// A handle to the mock of the blackhole canister of a replica.
+#[derive(Clone)]
+pub struct BlackholeMock {
+    recorder: MockRecorder,
+}
+
+impl BlackholeMock {
+    /// Create the mock and the canister which runs it, with the builds and the counters of the
+    /// canisters of the replica.
+    pub(crate) fn new(
+        codes: Arc<Mutex<HashMap<Principal, CanisterCode>>>,
+        counters: Arc<Counters>,
+    ) -> (Self, Canister) {
+        let mock = MockCanister::new(BLACKHOLE_CANISTER_ID, MANAGEMENT_CANDID).with_handler(
+            "canister_status",
+            move |call| {
+                canister_status(
+                    &codes.lock().unwrap(),
+                    &counters,
+                    BLACKHOLE_CANISTER_ID,
+                    call,
+                )
+            },
+        );
+        let recorder = mock.recorder();
+
+        (Self { recorder }, mock.into())
+    }
+
+    /// Return the calls made to the blackhole canister, in order.
+    pub fn calls(&self) -> Vec<MockCall> {
+        self.recorder.calls()
+    }
+}
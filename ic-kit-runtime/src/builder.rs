@@ -0,0 +1,177 @@
+use std::path::PathBuf;
+
+use candid::utils::ArgumentEncoder;
+use candid::{encode_args, Principal};
+
+use crate::canister::Canister;
+use crate::replica::TimePolicy;
+use crate::subnet::SubnetConfig;
+use crate::types::Env;
+use crate::Replica;
+
+/// A builder to configure a [`Replica`] and the canisters it starts with.
+///
+/// ```ignore
+/// let replica = ReplicaBuilder::new()
+///     .with_time(1_650_000_000_000_000_000)
+///     .with_canister(CounterCanister::anonymous())
+///     .with_init(LedgerCanister::anonymous().with_balance(1_000_000), (owner, 1_000u64))
+///     .build()
+///     .await;
+/// ```
+#[derive(Default)]
+pub struct ReplicaBuilder {
+    state_dir: Option<PathBuf>,
+    time: Option<u64>,
+    time_policy: Option<TimePolicy>,
+    subnet: SubnetConfig,
+    names: Vec<(Principal, String)>,
+    log_calls: bool,
+    call_timings: Option<bool>,
+    request_id_seed: Option<u64>,
+    canisters: Vec<(Canister, Option<Vec<u8>>)>,
+}
+
+impl ReplicaBuilder {
+    /// Create a builder for an empty replica.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Persist the state of the canisters in the given directory, see [`Replica::with_state_dir`].
+    pub fn with_state_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.state_dir = Some(dir.into());
+        self
+    }
+
+    /// Fix the time of the replica, see [`Replica::set_time`].
+    pub fn with_time(mut self, time: u64) -> Self {
+        self.time = Some(time);
+        self
+    }
+
+    /// Choose how the time of the replica moves, see [`Replica::set_time_policy`].
+    pub fn with_time_policy(mut self, policy: TimePolicy) -> Self {
+        self.time_policy = Some(policy);
+        self
+    }
+
+    /// Run the canisters with the costs and the limits of the given subnet, see
+    /// [`crate::subnet`].
+    pub fn with_subnet(mut self, subnet: SubnetConfig) -> Self {
+        self.subnet = subnet;
+        self
+    }
+
+    /// Use the given name for the principal, see [`Replica::name`].
+    pub fn with_name<S: Into<String>>(mut self, id: Principal, name: S) -> Self {
+        self.names.push((id, name.into()));
+        self
+    }
+
+    /// Print the calls made on the replica, see [`Replica::log_calls`].
+    pub fn with_call_log(mut self) -> Self {
+        self.log_calls = true;
+        self
+    }
+
+    /// Do not show how long each call took in the log of the calls, see
+    /// [`Replica::log_call_timings`].
+    pub fn without_call_timings(mut self) -> Self {
+        self.call_timings = Some(false);
+        self
+    }
+
+    /// Assign the request ids from the given seed, see [`Replica::set_request_id_seed`].
+    pub fn with_request_id_seed(mut self, seed: u64) -> Self {
+        self.request_id_seed = Some(seed);
+        self
+    }
+
+    /// Add the canister to the replica, without running its init hook.
+    pub fn with_canister(mut self, canister: Canister) -> Self {
+        self.canisters.push((canister, None));
+        self
+    }
+
+    /// Add the canister to the replica and run its init hook with the given arguments once all of
+    /// the canisters are added. If the state of the canister is restored from the state
+    /// directory, its post_upgrade hook runs instead.
+    pub fn with_init<T: ArgumentEncoder>(self, canister: Canister, arguments: T) -> Self {
+        let arg = encode_args(arguments).expect("Failed to encode arguments.");
+        self.with_init_raw(canister, arg)
+    }
+
+    /// Add the canister to the replica and run its init hook with the given raw arguments.
+    pub fn with_init_raw<A: Into<Vec<u8>>>(mut self, canister: Canister, argument: A) -> Self {
+        self.canisters.push((canister, Some(argument.into())));
+        self
+    }
+
+    /// Create the replica and run the init hooks of the canisters, in the order they were added.
+    ///
+    /// # Panics
+    ///
+    /// If the init hook of a canister fails.
+    pub async fn build(self) -> Replica {
+        let replica = match self.state_dir {
+            Some(dir) => Replica::with_state_dir(dir),
+            None => Replica::default(),
+        }
+        .with_subnet(self.subnet);
+
+        if let Some(time) = self.time {
+            replica.set_time(time);
+        }
+
+        if let Some(policy) = self.time_policy {
+            replica.set_time_policy(policy);
+        }
+
+        for (id, name) in self.names {
+            replica.name(id, name);
+        }
+
+        replica.log_calls(self.log_calls);
+
+        if let Some(timings) = self.call_timings {
+            replica.log_call_timings(timings);
+        }
+
+        if let Some(seed) = self.request_id_seed {
+            replica.set_request_id_seed(seed);
+        }
+
+        let mut init = Vec::new();
+        for (canister, arg) in self.canisters {
+            let canister_id = replica.add_canister(canister).canister_id();
+
+            if let Some(arg) = arg {
+                init.push((canister_id, arg));
+            }
+        }
+
+        for (canister_id, arg) in init {
+            let handle = replica.get_canister(canister_id);
+
+            let env = if replica.is_restored(canister_id) {
+                Env::post_upgrade()
+            } else {
+                Env::init()
+            };
+
+            if let Some(e) = handle
+                .run_env(env.with_raw_args(arg))
+                .await
+                .rejection_message()
+            {
+                panic!(
+                    "ic-kit-runtime: The init hook of canister '{}' failed: {}",
+                    canister_id, e
+                );
+            }
+        }
+
+        replica
+    }
+}
@@ -1,9 +1,16 @@
+use std::fmt;
+
 use candid::utils::{ArgumentDecoder, ArgumentEncoder};
+use candid::{check_prog, IDLArgs, IDLProg, TypeEnv};
 use candid::{decode_args, decode_one, encode_args, encode_one, CandidType, Principal};
+use futures::future::BoxFuture;
 use serde::de::DeserializeOwned;
 
 use ic_kit_sys::types::{CallError, RejectionCode, CANDID_EMPTY_ARG};
 
+use crate::certification;
+use crate::pretty::pretty;
+use crate::snapshot::Snapshot;
 use crate::types::*;
 use crate::Replica;
 
@@ -18,8 +25,7 @@ pub struct CallBuilder<'a> {
     arg: Option<Vec<u8>>,
 }
 
-/// A reply by the canister.
-#[derive(Debug)]
+/// A reply by the canister, its data is shown as candid text when it's debug printed.
 pub enum CallReply {
     Reply {
         data: Vec<u8>,
@@ -50,7 +56,7 @@ impl<'a> CallBuilder<'a> {
     /// # Panics
     ///
     /// This method panics if the argument for this call is already set via a prior
-    /// call to any of the `with_args`, `with_arg` or `with_arg_raw`.
+    /// call to any of the `with_args`, `with_arg`, `with_arg_raw` or `with_arg_text`.
     pub fn with_args<T: ArgumentEncoder>(mut self, arguments: T) -> Self {
         assert!(self.arg.is_none(), "Arguments may only be set once.");
         self.arg = Some(encode_args(arguments).unwrap());
@@ -62,7 +68,7 @@ impl<'a> CallBuilder<'a> {
     /// # Panics
     ///
     /// This method panics if the argument for this call is already set via a prior
-    /// call to any of the `with_args`, `with_arg` or `with_arg_raw`.
+    /// call to any of the `with_args`, `with_arg`, `with_arg_raw` or `with_arg_text`.
     pub fn with_arg<T: CandidType>(mut self, argument: T) -> Self {
         assert!(self.arg.is_none(), "Arguments may only be set once.");
         self.arg = Some(encode_one(argument).unwrap());
@@ -75,15 +81,52 @@ impl<'a> CallBuilder<'a> {
     /// # Panics
     ///
     /// This method panics if the argument for this call is already set via a prior
-    /// call to any of the `with_args`, `with_arg` or `with_arg_raw`.
+    /// call to any of the `with_args`, `with_arg`, `with_arg_raw` or `with_arg_text`.
     pub fn with_arg_raw<A: Into<Vec<u8>>>(mut self, argument: A) -> Self {
         assert!(self.arg.is_none(), "Arguments may only be set once.");
         self.arg = Some(argument.into());
         self
     }
 
+    /// Parse the given candid textual value, such as `(record { amount = 1 })`, as the argument
+    /// for this mock call. The values are encoded with the types of the method if the candid
+    /// interface of the canister is known, otherwise they are encoded without any annotation,
+    /// in which case the numbers are encoded as `int`.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if the argument for this call is already set, or if the text can not
+    /// be parsed or does not match the types of the method.
+    pub fn with_arg_text<S: AsRef<str>>(mut self, text: S) -> Self {
+        assert!(self.arg.is_none(), "Arguments may only be set once.");
+
+        let args = text
+            .as_ref()
+            .parse::<IDLArgs>()
+            .unwrap_or_else(|e| panic!("Could not parse the candid arguments: {}", e));
+
+        let bytes = match self.replica.candid(self.canister_id) {
+            Some(candid) => encode_args_with_types(&candid, &self.method_name, args),
+            None => args.to_bytes(),
+        };
+
+        self.arg = Some(bytes.unwrap_or_else(|e| {
+            panic!(
+                "Could not encode the candid arguments of '{}': {}",
+                self.method_name, e
+            )
+        }));
+        self
+    }
+
     /// Use the given amount of cycles for this mock call.
-    pub fn with_payment(mut self, cycles: u128) -> Self {
+    pub fn with_payment(mut self, cycles: u64) -> Self {
+        self.payment = cycles as u128;
+        self
+    }
+
+    /// Use the given amount of cycles for this mock call, the 128-bit version of `with_payment`.
+    pub fn with_payment128(mut self, cycles: u128) -> Self {
         self.payment = cycles;
         self
     }
@@ -94,10 +137,91 @@ impl<'a> CallBuilder<'a> {
         self
     }
 
+    /// Make the call from the anonymous principal, which is the default sender.
+    pub fn anonymous(self) -> Self {
+        self.with_caller(Principal::anonymous())
+    }
+
+    /// Return the replica which the call is sent to.
+    pub(crate) fn replica(&self) -> &'a Replica {
+        self.replica
+    }
+
+    /// Return the canister the call is sent to.
+    pub(crate) fn canister_id(&self) -> Principal {
+        self.canister_id
+    }
+
+    /// Return the name of the method to call.
+    pub fn method_name(&self) -> &str {
+        &self.method_name
+    }
+
+    /// Return the size of the encoded argument of the call in bytes.
+    pub fn arg_size(&self) -> usize {
+        self.arg.as_deref().unwrap_or(CANDID_EMPTY_ARG).len()
+    }
+
+    /// Return the id of the request of this call, under which the replica certifies its status,
+    /// see [`Replica::read_state`]. The same call always has the same id.
+    pub fn request_id(&self) -> [u8; 32] {
+        let arg = self.arg.as_deref().unwrap_or(CANDID_EMPTY_ARG);
+        certification::request_id(&self.sender, &self.canister_id, &self.method_name, arg)
+    }
+
+    /// Describe the call with the names of the principals, see [`Replica::name`].
+    pub(crate) fn describe(&self) -> String {
+        format!(
+            "{} -> {}.{}",
+            self.replica.name_of(&self.sender),
+            self.replica.name_of(&self.canister_id),
+            self.method_name
+        )
+    }
+
     /// Perform the call and returns the reply from the canister.
     pub async fn perform(&self) -> CallReply {
+        self.replica.chaos_restart(self.canister_id).await;
         self.replica.perform_call(self.into()).await
     }
+
+    /// Perform the call as a query, which is executed without being replicated, so the method
+    /// must be a query or a composite query. Unlike the other calls, a query can read the certificate of the data
+    /// certified by the canister, see [`crate::certification`]. The payment is ignored.
+    pub async fn perform_query(&self) -> CallReply {
+        self.replica
+            .get_canister(self.canister_id)
+            .run_env(self.query_env())
+            .await
+    }
+
+    /// Return the env of the call performed as a query, see [`CallBuilder::perform_query`].
+    pub(crate) fn query_env(&self) -> Env {
+        Env::query(self.method_name.clone())
+            .with_sender(self.sender)
+            .with_raw_args(
+                self.arg
+                    .clone()
+                    .unwrap_or_else(|| CANDID_EMPTY_ARG.to_vec()),
+            )
+    }
+
+    /// Send the call now and return a future resolved with the reply from the canister, so the
+    /// call is queued before its reply is awaited, such as on a paused replica.
+    pub fn send(&self) -> BoxFuture<'static, CallReply> {
+        self.replica.perform_call(self.into())
+    }
+
+    /// Send the same call `count` times at once and return the replies in the same order. The
+    /// calls are all queued before any of them is processed, so their execution may interleave
+    /// if the method awaits on inter-canister calls.
+    pub async fn send_n(&self, count: usize) -> Vec<CallReply> {
+        let replies = (0..count)
+            .map(|_| self.replica.perform_call(self.into()))
+            .collect::<Vec<_>>();
+
+        futures::future::join_all(replies).await
+    }
 }
 
 impl CallReply {
@@ -174,7 +298,7 @@ impl CallReply {
         }
     }
 
-    //// Returns the number of cycles refunded from this canister.
+    /// Returns the number of cycles refunded from this canister.
     pub fn cycles_refunded(&self) -> u128 {
         match &self {
             CallReply::Reply {
@@ -186,6 +310,42 @@ impl CallReply {
         }
     }
 
+    /// Returns the number of cycles refunded from this canister as a u64.
+    ///
+    /// # Panics
+    ///
+    /// If the number of cycles does not fit in a u64.
+    pub fn cycles_refunded64(&self) -> u64 {
+        u64::try_from(self.cycles_refunded()).expect("The refunded cycles do not fit in a u64.")
+    }
+
+    /// Returns the number of cycles the canister accepted out of the given payment sent with the
+    /// call, which is the part of the payment that was not refunded, for a reply as for a reject.
+    ///
+    /// # Panics
+    ///
+    /// If more cycles were refunded than the payment.
+    pub fn cycles_accepted(&self, payment: u128) -> u128 {
+        payment.checked_sub(self.cycles_refunded()).unwrap_or_else(|| {
+            panic!(
+                "ic-kit-runtime: {} cycles were refunded, which is more than the payment of {} cycles.",
+                self.cycles_refunded(),
+                payment
+            )
+        })
+    }
+
+    /// Return the size of the reply in bytes, or of the rejection message if the call was
+    /// rejected.
+    pub fn reply_size(&self) -> usize {
+        match self {
+            CallReply::Reply { data, .. } => data.len(),
+            CallReply::Reject {
+                rejection_message, ..
+            } => rejection_message.len(),
+        }
+    }
+
     /// Returns true if the call was okay.
     pub fn is_ok(&self) -> bool {
         match &self {
@@ -204,12 +364,197 @@ impl CallReply {
 
     /// Assert the response is okay.
     pub fn assert_ok(&self) {
-        assert!(self.is_ok(), "The call was rejected.");
+        if let CallReply::Reject {
+            rejection_code,
+            rejection_message,
+            ..
+        } = &self
+        {
+            panic!(
+                "The call was rejected with {:?}: {}",
+                rejection_code, rejection_message
+            );
+        }
     }
 
     /// Assert the response is a rejection.
     pub fn assert_error(&self) {
-        assert!(self.is_error(), "Expected a rejection, but got a reply.");
+        if let CallReply::Reply { data, .. } = &self {
+            panic!("Expected a rejection, but got the reply {}", pretty(data));
+        }
+    }
+
+    /// Assert the response is a rejection with the given code.
+    pub fn assert_rejected_with(&self, code: RejectionCode) {
+        match &self {
+            CallReply::Reply { data, .. } => panic!(
+                "Expected a rejection with {:?}, but got the reply {}",
+                code,
+                pretty(data)
+            ),
+            CallReply::Reject {
+                rejection_code,
+                rejection_message,
+                ..
+            } => assert_eq!(
+                *rejection_code, code,
+                "Expected a rejection with {:?}, but got {:?}: {}",
+                code, rejection_code, rejection_message
+            ),
+        }
+    }
+
+    /// Assert the canister trapped while processing the call, for example by panicking. The
+    /// canister keeps processing the next messages after a trap, so the test can go on.
+    pub fn assert_trapped(&self) {
+        self.assert_rejected_with(RejectionCode::CanisterError);
+    }
+
+    /// Assert the canister trapped with a message which contains the given text.
+    ///
+    /// ```ignore
+    /// ledger
+    ///     .new_call("transfer")
+    ///     .with_arg(too_much)
+    ///     .perform()
+    ///     .await
+    ///     .assert_trapped_containing("insufficient funds");
+    /// ```
+    pub fn assert_trapped_containing(&self, text: &str) {
+        self.assert_trapped();
+
+        let message = self.rejection_message().unwrap_or_default();
+        assert!(
+            message.contains(text),
+            "Expected the canister to trap with a message containing {:?}, but got: {}",
+            text,
+            message
+        );
+    }
+
+    /// Assert the canister explicitly rejected the call, such as with `ic::reject`, rather than
+    /// trapping.
+    pub fn assert_rejected(&self) {
+        self.assert_rejected_with(RejectionCode::CanisterReject);
+    }
+
+    /// Assert the canister explicitly rejected the call with a message which contains the given
+    /// text.
+    pub fn assert_rejected_containing(&self, text: &str) {
+        self.assert_rejected();
+
+        let message = self.rejection_message().unwrap_or_default();
+        assert!(
+            message.contains(text),
+            "Expected the canister to reject the call with a message containing {:?}, but got: {}",
+            text,
+            message
+        );
+    }
+
+    /// Render the reply as candid text, or the rejection code and message if the call was
+    /// rejected. The fields of the records are shown by their hash, since the reply does not
+    /// carry the names of its types.
+    pub fn snapshot(&self) -> Snapshot {
+        let mut text = match &self {
+            CallReply::Reply { data, .. } => IDLArgs::from_bytes(data)
+                .map(|args| args.to_string())
+                .unwrap_or_else(|_| pretty(data)),
+            CallReply::Reject {
+                rejection_code,
+                rejection_message,
+                ..
+            } => format!("reject {:?}: {}", rejection_code, rejection_message),
+        };
+
+        if self.cycles_refunded() > 0 {
+            text.push_str(&format!("\ncycles_refunded = {}", self.cycles_refunded()));
+        }
+
+        Snapshot::new(text)
+    }
+
+    /// Assert the reply matches the snapshot with the given name, see [`Snapshot::assert_matches`].
+    pub fn assert_snapshot(&self, name: &str) {
+        self.snapshot().assert_matches(name);
+    }
+
+    /// Assert the given number of cycles was refunded from this canister.
+    pub fn assert_cycles_refunded(&self, cycles: u128) {
+        assert_eq!(
+            self.cycles_refunded(),
+            cycles,
+            "Expected {} cycles to be refunded, but got {}.",
+            cycles,
+            self.cycles_refunded()
+        );
+    }
+
+    /// Assert the canister accepted the given number of cycles out of the payment sent with the
+    /// call, and refunded the rest.
+    pub fn assert_cycles_accepted(&self, payment: u128, cycles: u128) {
+        assert_eq!(
+            self.cycles_accepted(payment),
+            cycles,
+            "Expected {} of the {} cycles sent to be accepted, but got {}.",
+            cycles,
+            payment,
+            self.cycles_accepted(payment)
+        );
+    }
+
+    /// Assert the whole payment sent with the call was refunded, such as when the call is rejected
+    /// before the canister accepts any cycles.
+    pub fn assert_fully_refunded(&self, payment: u128) {
+        self.assert_cycles_accepted(payment, 0);
+    }
+
+    /// Assert the reply is smaller than the given number of bytes, such as half of
+    /// `MAX_REPLY_SIZE`, so the method keeps some headroom below the message limit as its data
+    /// grows.
+    pub fn assert_reply_size_below(&self, limit: usize) {
+        assert!(
+            self.reply_size() < limit,
+            "Expected the reply to be smaller than {} bytes, but it is {} bytes.",
+            limit,
+            self.reply_size()
+        );
+    }
+}
+
+/// Encode the parsed arguments with the argument types of the method in the candid interface.
+fn encode_args_with_types(candid: &str, method: &str, args: IDLArgs) -> candid::Result<Vec<u8>> {
+    let prog = candid.parse::<IDLProg>()?;
+    let mut env = TypeEnv::new();
+    let actor = check_prog(&mut env, &prog)?
+        .ok_or_else(|| candid::Error::msg("The candid interface does not define a service."))?;
+    let types = &env.get_method(&actor, method)?.args;
+    args.annotate_types(true, &env, types)?
+        .to_bytes_with_types(&env, types)
+}
+
+impl fmt::Debug for CallReply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CallReply::Reply {
+                data,
+                cycles_refunded,
+            } => f
+                .debug_struct("Reply")
+                .field("data", &format_args!("{}", pretty(data)))
+                .field("cycles_refunded", cycles_refunded)
+                .finish(),
+            CallReply::Reject {
+                rejection_code,
+                rejection_message,
+                cycles_refunded,
+            } => f
+                .debug_struct("Reject")
+                .field("rejection_code", rejection_code)
+                .field("rejection_message", rejection_message)
+                .field("cycles_refunded", cycles_refunded)
+                .finish(),
+        }
     }
 }
 
@@ -233,7 +578,8 @@ impl<'a> From<&'a CallBuilder<'a>> for CanisterCall {
     fn from(builder: &'a CallBuilder) -> Self {
         CanisterCall {
             sender: builder.sender,
-            request_id: RequestId::new(),
+            request_id: builder.replica.counters().next_request_id(),
+            parent: None,
             callee: builder.canister_id,
             method: builder.method_name.clone(),
             payment: builder.payment,
@@ -241,6 +587,7 @@ impl<'a> From<&'a CallBuilder<'a>> for CanisterCall {
                 .arg
                 .clone()
                 .unwrap_or_else(|| CANDID_EMPTY_ARG.to_vec()),
+            query: false,
         }
     }
 }
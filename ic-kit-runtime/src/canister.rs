@@ -1,10 +1,14 @@
 use std::any::Any;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::panic::catch_unwind;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
 
-use candid::Principal;
+use candid::utils::ArgumentEncoder;
+use candid::{encode_args, encode_one, CandidType, Principal};
 use futures::executor::block_on;
+use sha2::{Digest, Sha256};
 use thread_local_panic_hook::set_hook;
 use tokio::select;
 use tokio::sync::mpsc::{self, Receiver, Sender};
@@ -15,13 +19,94 @@ use ic_kit_sys::ic0::runtime;
 use ic_kit_sys::ic0::runtime::Ic0CallHandlerProxy;
 use ic_kit_sys::types::RejectionCode;
 
+use crate::bench::Counters;
 use crate::call::CallReply;
+use crate::certification;
+use crate::metadata::{self, MetadataVisibility};
+use crate::replica::Rounds;
+use crate::span::MessageSpan;
 use crate::stable::{HeapStableMemory, StableMemoryBackend};
+use crate::subnet::SubnetConfig;
 use crate::types::*;
 
-const MAX_CYCLES_PER_RESPONSE: u128 = 12;
+/// The cycle balance of a new canister.
+pub(crate) const DEFAULT_BALANCE: u128 = 100_000_000_000_000;
+
+/// Return the task which runs the update or query method of the message, if the canister has one.
+type DynamicMethods = dyn Fn(&Env) -> Option<TaskFn> + Send + Sync;
+
+/// The build of a canister, which is kept by the replica to create the forks of the canister.
+#[derive(Clone)]
+pub(crate) struct CanisterCode {
+    symbol_table: HashMap<String, fn()>,
+    controllers: Vec<Principal>,
+    candid: Option<String>,
+    wasm: Option<Vec<u8>>,
+    build_id: Option<String>,
+    init_arg: Option<Vec<u8>>,
+    dynamic_methods: Option<Arc<DynamicMethods>>,
+    metadata: BTreeMap<String, (MetadataVisibility, Vec<u8>)>,
+}
+
+impl CanisterCode {
+    /// Return the arguments of the init hook of the canister, if they were provided.
+    pub(crate) fn init_arg(&self) -> Option<&[u8]> {
+        self.init_arg.as_deref()
+    }
+
+    /// Return the controllers of the canister.
+    pub(crate) fn controllers(&self) -> &[Principal] {
+        &self.controllers
+    }
+
+    /// Return the sections of the metadata of the canister, with its candid interface as the
+    /// `candid:service` section if it does not have one.
+    pub(crate) fn metadata(&self) -> BTreeMap<String, (MetadataVisibility, Vec<u8>)> {
+        let mut metadata = self.metadata.clone();
+
+        if let Some(candid) = &self.candid {
+            metadata
+                .entry("candid:service".to_string())
+                .or_insert_with(|| (MetadataVisibility::Public, candid.clone().into_bytes()));
+        }
+
+        metadata
+    }
+
+    /// Replace the controllers of the canister, such as when a build is installed on a canister
+    /// which keeps its controllers.
+    pub(crate) fn set_controllers(&mut self, controllers: Vec<Principal>) {
+        self.controllers = controllers;
+    }
+
+    /// Return the sha256 hash of the wasm module of the canister, or if it runs natively without
+    /// one, of its build id or of the sorted names of its methods, so the hash changes with the
+    /// upgrades which change the methods of the canister.
+    pub(crate) fn module_hash(&self) -> [u8; 32] {
+        if let Some(wasm) = &self.wasm {
+            return Sha256::digest(wasm).into();
+        }
+
+        if let Some(build_id) = &self.build_id {
+            return Sha256::digest(build_id).into();
+        }
+
+        let mut methods: Vec<&str> = self.symbol_table.keys().map(String::as_str).collect();
+        methods.sort_unstable();
+        Sha256::digest(methods.join("\n")).into()
+    }
+}
 
 /// A canister that is being executed.
+///
+/// Each instance runs the code of the canister on a thread of its own, so the state it keeps with
+/// `ic::with` and the other thread locals of ic-kit, its stable memory and the context of its
+/// system API calls are never shared with another instance, even of the same canister in the same
+/// replica or in another replica of the process. A `MockContext` injected on the thread of a test
+/// does not reach the canisters either. The state of an instance is wiped with
+/// [`CanisterHandle::reset`].
+///
+/// [`CanisterHandle::reset`]: crate::handle::CanisterHandle::reset
 pub struct Canister {
     /// The id of the canister.
     canister_id: Principal,
@@ -42,6 +127,17 @@ pub struct Canister {
     cycles_available_store: HashMap<IncomingRequestId, u128>,
     /// Amount of cycles accept during this message process.
     cycles_accepted: u128,
+    /// Whether the current `inspect_message` accepted the message.
+    message_accepted: bool,
+    /// The data certified by the current message, which is kept once it completes.
+    certified_data: Option<Vec<u8>>,
+    /// The time the global timer of the canister is set to, or zero if it's not set.
+    global_timer: u64,
+    /// The global timer set by the current message, which is kept once it completes.
+    pending_global_timer: Option<u64>,
+    /// The callee, the method and the hook of the calls made by the init hook in the current
+    /// message, which trap on the Internet Computer and are reported by the replica.
+    hook_calls: Vec<(Principal, String, String)>,
     /// Pending outgoing requests that have not been resolved yet. This is used so we know when
     /// an incoming request is finally finished so we can send the last trapping message as the
     /// response.
@@ -51,10 +147,58 @@ pub struct Canister {
     outgoing_calls: HashMap<OutgoingRequestId, RequestCallbacks>,
     /// The canister execution environment.
     env: Env,
+    /// The cycle balance of the canister, not including the cycles accepted during the current
+    /// message.
+    balance: u128,
     /// The stable storage backend for this canister.
     stable: Box<dyn StableMemoryBackend + Send>,
+    /// The controllers of this canister.
+    controllers: Vec<Principal>,
+    /// The percentage of a core reserved for this canister, which weighs its turns in the queues
+    /// of the canisters it calls.
+    compute_allocation: Option<u8>,
+    /// The candid interface of this canister, used to type the textual call arguments.
+    candid: Option<String>,
+    /// The wasm module of this canister, which is installed when the replica runs on PocketIC.
+    wasm: Option<Vec<u8>>,
+    /// The sections of the metadata of this canister, with their visibility.
+    metadata: BTreeMap<String, (MetadataVisibility, Vec<u8>)>,
+    /// The synthetic build id of this canister, which its module hash is computed from when it
+    /// runs natively without a wasm module.
+    build_id: Option<String>,
+    /// The arguments of the init hook of this canister, the hook runs with no argument if they
+    /// are not provided.
+    init_arg: Option<Vec<u8>>,
+    /// Create the task of the update and query methods which are not in the symbol table, this is
+    /// how the mock canisters implement the methods of their candid interface.
+    dynamic_methods: Option<Arc<DynamicMethods>>,
+    /// The counters of the replica this canister is running on.
+    counters: Arc<Counters>,
+    /// The costs and the limits of the subnet this canister is running on.
+    subnet: SubnetConfig,
+    /// Used to pause a long message until the next round, if the canister runs on a replica.
+    rounds: Option<Rounds>,
+    /// The instructions counted for the system API calls of the current message.
+    instructions: u64,
+    /// The number of slices the current message was executed in.
+    slices: u64,
     /// The request id of the current incoming message.
     request_id: Option<IncomingRequestId>,
+    /// The span of each incoming request which is not finished yet, the callbacks of its calls
+    /// are executed in spans nested under it.
+    spans: HashMap<IncomingRequestId, MessageSpan>,
+    /// The incoming requests executed by a composite query, whose callbacks are still run as a
+    /// query.
+    composite_queries: HashSet<IncomingRequestId>,
+    /// The incoming requests running an init or a post_upgrade hook, which succeed without a reply
+    /// once the calls they made are done.
+    hooks: HashSet<IncomingRequestId>,
+    /// The limit of the reply of each incoming call which is not finished yet, which is kept
+    /// since the reply can be sent by a callback.
+    reply_limits: HashMap<IncomingRequestId, ReplyLimit>,
+    /// The previous content of the stable memory written by the current query, which is restored
+    /// once the query ends, since the changes of a query are discarded.
+    stable_undo: Vec<(u64, Vec<u8>)>,
     /// The calls that are finalized and should be sent after this entry point's successful
     /// execution.
     call_queue: Vec<(Principal, String, RequestCallbacks, u128, Vec<u8>)>,
@@ -64,7 +208,7 @@ pub struct Canister {
     /// The thread in which the canister is being executed at.
     _execution_thread_handle: JoinHandle<()>,
     /// The communication channel to send tasks to the execution thread.
-    task_tx: Sender<TaskFn>,
+    task_tx: Sender<Task>,
     /// Emits when the task we just sent has returned.
     task_completion_rx: Receiver<Completion>,
     /// To send the response to the calls.
@@ -79,6 +223,14 @@ enum Completion {
     Panicked(String),
 }
 
+/// The maximum size of the reply to an incoming call, and what the call was for the diagnostic
+/// of an oversized reply.
+struct ReplyLimit {
+    method: String,
+    kind: &'static str,
+    bytes: usize,
+}
+
 /// Any of the reply, reject or clean up callbacks.
 /// (callback_fun, callback_env)
 ///
@@ -122,7 +274,7 @@ impl Canister {
     pub fn new<T: Into<Principal>>(canister_id: T) -> Self {
         let (request_tx, request_rx) = mpsc::channel(8);
         let (reply_tx, reply_rx) = mpsc::channel(8);
-        let (task_tx, mut task_rx) = mpsc::channel::<TaskFn>(8);
+        let (task_tx, mut task_rx) = mpsc::channel::<Task>(8);
         let (task_completion_tx, task_completion_rx) = mpsc::channel(8);
 
         let execution_thread_handle = std::thread::spawn(move || {
@@ -140,7 +292,7 @@ impl Canister {
 
             while let Some(task) = block_on(task_rx.recv()) {
                 let c = if let Err(payload) = catch_unwind(|| {
-                    task();
+                    task.run();
                 }) {
                     Completion::Panicked(downcast_panic_payload(&payload))
                 } else {
@@ -164,11 +316,35 @@ impl Canister {
             msg_reply: None,
             cycles_available_store: HashMap::new(),
             cycles_accepted: 0,
+            message_accepted: false,
+            certified_data: None,
+            global_timer: 0,
+            pending_global_timer: None,
+            hook_calls: Vec::new(),
             pending_outgoing_requests: HashMap::new(),
             outgoing_calls: HashMap::new(),
             env: Env::default(),
+            balance: DEFAULT_BALANCE,
             stable: Box::new(HeapStableMemory::default()),
+            controllers: Vec::new(),
+            compute_allocation: None,
+            candid: None,
+            wasm: None,
+            metadata: BTreeMap::new(),
+            build_id: None,
+            init_arg: None,
+            dynamic_methods: None,
+            counters: Arc::new(Counters::default()),
+            subnet: SubnetConfig::default(),
+            rounds: None,
+            instructions: 0,
+            slices: 1,
             request_id: None,
+            spans: HashMap::new(),
+            composite_queries: HashSet::new(),
+            hooks: HashSet::new(),
+            reply_limits: HashMap::new(),
+            stable_undo: Vec::new(),
             call_queue: Vec::with_capacity(8),
             pending_call: None,
             _execution_thread_handle: execution_thread_handle,
@@ -197,22 +373,320 @@ impl Canister {
         self
     }
 
+    /// Add the given principal to the controllers of this canister.
+    pub fn with_controller<T: Into<Principal>>(mut self, controller: T) -> Self {
+        self.controllers.push(controller.into());
+        self
+    }
+
+    /// Provide the candid interface of this canister, so the textual arguments of the calls to
+    /// the canister are encoded with the types of its methods.
+    pub fn with_candid<S: Into<String>>(mut self, candid: S) -> Self {
+        self.candid = Some(candid.into());
+        self
+    }
+
+    /// Return the candid interface of this canister, if it was provided.
+    pub fn candid(&self) -> Option<&str> {
+        self.candid.as_deref()
+    }
+
+    /// Set the initial cycle balance of this canister.
+    pub fn with_balance(mut self, balance: u128) -> Self {
+        self.balance = balance;
+        self
+    }
+
+    /// Set the compute allocation of this canister, the percentage of a core reserved for it. The
+    /// messages waiting for a canister are delivered in turns across their senders, and a canister
+    /// with a compute allocation of `n` delivers up to `n` messages in each of its turns instead of
+    /// one, so it gets that much more of the canisters it calls under load.
+    ///
+    /// # Panics
+    ///
+    /// If the allocation is larger than 100.
+    pub fn with_compute_allocation(mut self, percent: u8) -> Self {
+        assert!(
+            percent <= 100,
+            "The compute allocation must be between 0 and 100, got {}.",
+            percent
+        );
+        self.compute_allocation = Some(percent);
+        self
+    }
+
+    /// Provide the wasm module of this canister, which is what runs when the replica is backed by
+    /// PocketIC instead of the methods of this canister, see [`Replica::pocket_ic`].
+    ///
+    /// ```ignore
+    /// CounterCanister::anonymous().with_wasm(include_bytes!("../counter.wasm").to_vec())
+    /// ```
+    ///
+    /// The metadata of the canister is read from the `icp:public` and `icp:private` custom
+    /// sections of the module, see [`crate::metadata`].
+    ///
+    /// [`Replica::pocket_ic`]: crate::Replica::pocket_ic
+    pub fn with_wasm(mut self, wasm: Vec<u8>) -> Self {
+        for (name, visibility, data) in metadata::custom_sections(&wasm) {
+            self.metadata.insert(name, (visibility, data));
+        }
+
+        self.wasm = Some(wasm);
+        self
+    }
+
+    /// Add a section to the metadata of this canister which anyone can read, like a
+    /// `icp:public <name>` custom section of its wasm module, see [`crate::metadata`].
+    ///
+    /// ```ignore
+    /// LedgerCanister::anonymous().with_public_metadata("supported_standards", "ICRC-1")
+    /// ```
+    pub fn with_public_metadata<S: Into<String>, D: Into<Vec<u8>>>(
+        mut self,
+        name: S,
+        data: D,
+    ) -> Self {
+        self.metadata
+            .insert(name.into(), (MetadataVisibility::Public, data.into()));
+        self
+    }
+
+    /// Add a section to the metadata of this canister which only its controllers can read, like
+    /// a `icp:private <name>` custom section of its wasm module, see [`crate::metadata`].
+    pub fn with_private_metadata<S: Into<String>, D: Into<Vec<u8>>>(
+        mut self,
+        name: S,
+        data: D,
+    ) -> Self {
+        self.metadata
+            .insert(name.into(), (MetadataVisibility::Private, data.into()));
+        self
+    }
+
+    /// Return the wasm module of this canister, if it was provided.
+    pub fn wasm(&self) -> Option<&[u8]> {
+        self.wasm.as_deref()
+    }
+
+    /// Identify the build of this canister, so its module hash is the hash of the given id when it
+    /// runs natively without a wasm module, instead of the hash of the names of its methods. This
+    /// tells apart two builds with the same methods, such as in the tests of an upgrade process
+    /// which compares the module hashes.
+    ///
+    /// ```ignore
+    /// let v2 = LedgerCanister::anonymous().with_build_id("ledger-v2");
+    /// ledger.upgrade(v2, ()).await.assert_ok();
+    /// ```
+    pub fn with_build_id<S: Into<String>>(mut self, build_id: S) -> Self {
+        self.build_id = Some(build_id.into());
+        self
+    }
+
+    /// Return the module hash of this canister, the sha256 hash of its wasm module, of its build
+    /// id, or of the sorted names of its methods, in this order of preference. This is the hash
+    /// returned by `canister_status` and `read_state` once the canister is installed.
+    pub fn module_hash(&self) -> [u8; 32] {
+        self.code().module_hash()
+    }
+
+    /// Encode the provided tuple using candid and use it as the arguments of the init hook of
+    /// this canister, which [`CanisterHandle::init`] runs with.
+    ///
+    /// ```ignore
+    /// let ledger = replica.add_canister(
+    ///     LedgerCanister::anonymous()
+    ///         .with_init_args((owner, 1_000u64))
+    ///         .with_balance(10_000_000_000),
+    /// );
+    ///
+    /// ledger.init().await.assert_ok();
+    /// ```
+    ///
+    /// [`CanisterHandle::init`]: crate::handle::CanisterHandle::init
+    pub fn with_init_args<T: ArgumentEncoder>(self, arguments: T) -> Self {
+        let arg = encode_args(arguments).expect("Failed to encode arguments.");
+        self.with_init_arg_raw(arg)
+    }
+
+    /// Shorthand for `with_init_args((argument, ))` to pass tuples with only one element to the
+    /// init hook.
+    pub fn with_init_arg<T: CandidType>(self, argument: T) -> Self {
+        let arg = encode_one(argument).expect("Failed to encode argument.");
+        self.with_init_arg_raw(arg)
+    }
+
+    /// Use the given raw buffer as the arguments of the init hook of this canister.
+    pub fn with_init_arg_raw<A: Into<Vec<u8>>>(mut self, argument: A) -> Self {
+        self.init_arg = Some(argument.into());
+        self
+    }
+
+    /// Run the update and query methods which are not defined with [`Canister::with_method`] with
+    /// the task returned by the given function for their message.
+    pub(crate) fn with_dynamic_methods<F>(mut self, methods: F) -> Self
+    where
+        F: Fn(&Env) -> Option<TaskFn> + Send + Sync + 'static,
+    {
+        self.dynamic_methods = Some(Arc::new(methods));
+        self
+    }
+
+    /// Return the initial cycle balance of this canister.
+    pub(crate) fn initial_balance(&self) -> u128 {
+        self.balance
+    }
+
+    /// Return the build of this canister.
+    pub(crate) fn code(&self) -> CanisterCode {
+        CanisterCode {
+            symbol_table: self.symbol_table.clone(),
+            controllers: self.controllers.clone(),
+            candid: self.candid.clone(),
+            wasm: self.wasm.clone(),
+            build_id: self.build_id.clone(),
+            metadata: self.metadata.clone(),
+            init_arg: self.init_arg.clone(),
+            dynamic_methods: self.dynamic_methods.clone(),
+        }
+    }
+
+    /// Create a canister with the given id running the given build.
+    pub(crate) fn from_code(canister_id: Principal, code: CanisterCode) -> Self {
+        let mut canister = Canister::new(canister_id);
+        canister.symbol_table = code.symbol_table;
+        canister.controllers = code.controllers;
+        canister.candid = code.candid;
+        canister.wasm = code.wasm;
+        canister.build_id = code.build_id;
+        canister.metadata = code.metadata;
+        canister.init_arg = code.init_arg;
+        canister.dynamic_methods = code.dynamic_methods;
+        canister
+    }
+
+    /// Run this canister with the costs and the limits of the given subnet.
+    pub(crate) fn set_subnet(&mut self, subnet: SubnetConfig) {
+        self.subnet = subnet;
+    }
+
+    /// Return the costs and the limits of the subnet this canister is running on.
+    pub(crate) fn subnet(&self) -> &SubnetConfig {
+        &self.subnet
+    }
+
+    /// Pause the long messages of this canister with the given rounds.
+    pub(crate) fn set_rounds(&mut self, rounds: Rounds) {
+        self.rounds = Some(rounds);
+    }
+
+    /// Return true if the last message was executed in more than one slice.
+    pub(crate) fn take_sliced(&mut self) -> bool {
+        std::mem::replace(&mut self.slices, 1) > 1
+    }
+
+    /// Reject the calls this canister has not replied to yet, and refund the cycles sent with them.
+    pub(crate) fn reject_open_calls(
+        &mut self,
+        rejection_code: RejectionCode,
+        rejection_message: &str,
+    ) {
+        for (request_id, sender) in self.msg_reply_senders.drain() {
+            let cycles_refunded = self
+                .cycles_available_store
+                .remove(&request_id)
+                .unwrap_or_default();
+
+            let _ = sender.send(CallReply::Reject {
+                rejection_code,
+                rejection_message: rejection_message.to_string(),
+                cycles_refunded,
+            });
+        }
+    }
+
+    /// Return the calls made by the init or the post_upgrade hook in the last message, as the
+    /// callee, the method and the name of the hook.
+    pub(crate) fn take_hook_calls(&mut self) -> Vec<(Principal, String, String)> {
+        std::mem::take(&mut self.hook_calls)
+    }
+
+    /// Return the counters this canister counts its resources in.
+    pub(crate) fn counters(&self) -> &Arc<Counters> {
+        &self.counters
+    }
+
+    /// Count the resources used by this canister in the given counters.
+    pub(crate) fn set_counters(&mut self, counters: Arc<Counters>) {
+        let methods = self
+            .symbol_table
+            .keys()
+            .filter_map(|name| {
+                name.strip_prefix("canister_update ")
+                    .or_else(|| name.strip_prefix("canister_query "))
+                    .or_else(|| name.strip_prefix("canister_composite_query "))
+            })
+            .map(|name| (name.to_string(), 0))
+            .collect();
+
+        counters
+            .methods
+            .lock()
+            .unwrap()
+            .insert(self.canister_id, methods);
+
+        if let Some(percent) = self.compute_allocation {
+            counters
+                .compute_allocations
+                .lock()
+                .unwrap()
+                .insert(self.canister_id, percent);
+        }
+
+        self.counters = counters;
+        self.publish_status();
+    }
+
     /// Provide the canister with this stable storage backend.
     pub fn with_stable(mut self, stable: Box<dyn StableMemoryBackend + Send>) -> Self {
         self.stable = stable;
         self
     }
 
+    /// Replace the content of the stable storage with the given data, which is padded to a
+    /// multiple of the page size.
+    pub(crate) fn load_stable(&mut self, data: &[u8]) {
+        let size = self.stable.stable_size();
+        let pages = (data.len() as u64 + (1 << 16) - 1) >> 16;
+
+        if pages > size && self.stable.stable_grow(pages - size) == -1 {
+            panic!(
+                "ic-kit-runtime: The stable storage of canister '{}' is too small for {} pages.",
+                self.canister_id, pages
+            );
+        }
+
+        self.stable.stable_write(0, data);
+    }
+
     pub async fn process_message(
         &mut self,
         message: Message,
         reply_sender: Option<oneshot::Sender<CallReply>>,
     ) -> Vec<CanisterCall> {
+        self.counters.messages.fetch_add(1, Ordering::SeqCst);
+
         // Force reset the state.
         self.discard_pending_call();
         self.discard_call_queue();
         self.request_id = None;
         self.cycles_accepted = 0;
+        self.message_accepted = false;
+        self.certified_data = None;
+        self.pending_global_timer = None;
+        self.hook_calls.clear();
+        // A message which trapped after replying must not leak its reply to the next one.
+        self.msg_reply = None;
+        self.msg_reply_data.clear();
 
         // Assign the request_id for this message.
         let (request_id, env, task) = match message {
@@ -226,19 +700,19 @@ impl Canister {
                     "A request must provide a response channel."
                 );
 
-                assert!(
-                    env.entry_mode != EntryMode::ReplyCallback
-                        && env.entry_mode != EntryMode::RejectCallback
-                );
-
-                (request_id, env, Some(task))
+                (request_id, env, Some(Task::Boxed(task)))
             }
-            Message::Request { request_id, env } => {
+            Message::Request {
+                request_id,
+                mut env,
+            } => {
                 assert!(
                     reply_sender.is_some(),
                     "A request must provide a response channel."
                 );
 
+                self.counters.ingress.processing(request_id);
+
                 assert!(
                     env.entry_mode != EntryMode::ReplyCallback
                         && env.entry_mode != EntryMode::RejectCallback
@@ -246,24 +720,56 @@ impl Canister {
                         && env.entry_mode != EntryMode::CustomTask
                 );
 
-                let entry_point_name = env.get_entry_point_name();
-                let task = self
-                    .symbol_table
-                    .get(&entry_point_name)
-                    .or_else(|| self.symbol_table.get(&env.get_possible_entry_point_name()))
-                    .map(|f| {
-                        let f = f.clone();
-                        Box::new(move || {
-                            f();
-                        }) as TaskFn
-                    });
+                // The global timer is deactivated when it runs and when the code of the canister
+                // is installed or upgraded, like on the Internet Computer.
+                if matches!(
+                    env.entry_mode,
+                    EntryMode::GlobalTimer | EntryMode::Init | EntryMode::PostUpgrade
+                ) {
+                    self.global_timer = 0;
+                }
+
+                let task = match self.find_method(&mut env) {
+                    Some(f) => Some(Task::Method(f)),
+                    None => match &self.dynamic_methods {
+                        Some(methods)
+                            if matches!(
+                                env.entry_mode,
+                                EntryMode::Update | EntryMode::Query | EntryMode::CompositeQuery
+                            ) =>
+                        {
+                            // The methods of a mock are plain queries.
+                            if env.entry_mode == EntryMode::CompositeQuery {
+                                env.entry_mode = EntryMode::Query;
+                            }
+
+                            methods(&env).map(Task::Boxed)
+                        }
+                        _ => None,
+                    },
+                };
+
+                if let (Some(_), Some(method_name)) = (&task, &env.method_name) {
+                    *self
+                        .counters
+                        .methods
+                        .lock()
+                        .unwrap()
+                        .entry(self.canister_id)
+                        .or_default()
+                        .entry(method_name.clone())
+                        .or_default() += 1;
+                }
 
                 (request_id, env, task)
             }
             Message::Reply { reply_to, env } => {
-                let callbacks = self.outgoing_calls.remove(&reply_to).expect(
-                    "ic-kit-runtime: No outgoing message with the given id on this canister.",
-                );
+                let callbacks = match self.outgoing_calls.remove(&reply_to) {
+                    Some(callbacks) => callbacks,
+                    // The call was made by the build of the canister before an upgrade, its
+                    // callbacks are gone.
+                    None => return Vec::new(),
+                };
 
                 let id = callbacks.message_id;
                 let _clean_callbacks = callbacks.cleanup;
@@ -286,28 +792,53 @@ impl Canister {
                     _ => unreachable!(),
                 };
 
-                let task = Box::new(move || unsafe {
-                    // -1 is used by a one-way call.
-                    if fun != -1 {
-                        let fun = std::mem::transmute::<isize, fn(isize)>(fun);
-                        fun(fun_env);
-                    }
-                }) as TaskFn;
+                (id, env, Some(Task::Callback(fun, fun_env)))
+            }
+        };
 
-                (id, env, Some(task))
+        if let Some(balance) = env.balance {
+            self.balance = balance;
+        }
+
+        let span = match env.entry_mode {
+            EntryMode::ReplyCallback | EntryMode::RejectCallback => self
+                .spans
+                .get(&request_id)
+                .cloned()
+                .unwrap_or_default()
+                .callback(&self.canister_id, &env),
+            _ => {
+                let parent = self.counters.spans.remove(request_id);
+                MessageSpan::message(parent.as_ref(), &self.canister_id, &env)
             }
         };
 
         if task.is_none() {
             let chan = reply_sender.unwrap();
 
-            let reply = CallReply::Reject {
-                rejection_code: RejectionCode::DestinationInvalid,
-                rejection_message: format!(
-                    "Canister does not have a '{}' method.",
-                    env.method_name.unwrap_or_default()
-                ),
-                cycles_refunded: env.cycles_available,
+            // A canister is not required to export the system hooks, so they are a no-op, and
+            // the messages are accepted without an `inspect_message`.
+            let is_system_task = matches!(
+                env.entry_mode,
+                EntryMode::Init
+                    | EntryMode::PreUpgrade
+                    | EntryMode::PostUpgrade
+                    | EntryMode::Heartbeat
+                    | EntryMode::GlobalTimer
+                    | EntryMode::InspectMessage
+            );
+
+            let reply = if is_system_task {
+                CallReply::Reply {
+                    data: Vec::new(),
+                    cycles_refunded: env.cycles_available,
+                }
+            } else {
+                CallReply::Reject {
+                    rejection_code: RejectionCode::DestinationInvalid,
+                    rejection_message: self.missing_method_message(&env),
+                    cycles_refunded: env.cycles_available,
+                }
             };
 
             chan.send(reply)
@@ -316,25 +847,58 @@ impl Canister {
             return Vec::new();
         }
 
+        if env.entry_mode == EntryMode::CompositeQuery {
+            self.composite_queries.insert(request_id);
+        }
+
+        if matches!(env.entry_mode, EntryMode::Init | EntryMode::PostUpgrade) {
+            self.hooks.insert(request_id);
+        }
+
+        if matches!(
+            env.entry_mode,
+            EntryMode::CustomTask
+                | EntryMode::Update
+                | EntryMode::Query
+                | EntryMode::CompositeQuery
+        ) {
+            let limit = self.reply_limit(&env);
+            self.reply_limits.entry(request_id).or_insert(limit);
+        }
+
         self.request_id = Some(request_id);
         self.env = env;
+        // The time does not change during the execution of a message.
+        self.env.time.get_or_insert_with(now);
         self.env.cycles_available = *self
             .cycles_available_store
             .entry(request_id)
             .or_insert(self.env.cycles_available);
-        self.env.balance += self.env.cycles_refunded;
+        self.balance += self.env.cycles_refunded;
 
         if let Some(sender) = reply_sender {
             self.msg_reply_senders
                 .insert(self.request_id.unwrap(), sender);
         }
 
-        let completion = self.perform(task.unwrap()).await;
+        self.spans.entry(request_id).or_insert_with(|| span.clone());
+
+        let completion = self.perform(span.wrap(task.unwrap())).await;
+
+        while let Some((offset, data)) = self.stable_undo.pop() {
+            self.stable.stable_write(offset, &data);
+        }
 
         match completion {
             Completion::Panicked(m) => {
+                span.trapped(&m);
                 // We panicked, so we don't want to send any of the outgoing messages.
                 self.discard_call_queue();
+                // The reply or the reject is rolled back with the rest of the message, so the cycles
+                // it refunded are available again.
+                if let Some(reply) = self.msg_reply.take() {
+                    self.env.cycles_available += reply.cycles_refunded();
+                }
                 // return the cycles available in this call.
                 self.env.cycles_available += self.cycles_accepted;
                 self.cycles_accepted = 0;
@@ -343,6 +907,22 @@ impl Canister {
                 self.maybe_final_reply(Some(m), self.env.cycles_available);
             }
             Completion::Ok => {
+                // The accepted cycles are kept once the message completes.
+                self.balance += self.cycles_accepted;
+                self.cycles_accepted = 0;
+
+                if let Some(data) = self.certified_data.take() {
+                    self.counters
+                        .certified_data
+                        .lock()
+                        .unwrap()
+                        .insert(self.canister_id, data);
+                }
+
+                if let Some(timer) = self.pending_global_timer.take() {
+                    self.global_timer = timer;
+                }
+
                 if let Some(reply) = self.msg_reply.take() {
                     let chan = self
                         .msg_reply_senders
@@ -357,10 +937,21 @@ impl Canister {
             }
         };
 
-        let queue = std::mem::replace(&mut self.call_queue, Vec::new());
+        // The queue is drained and put back, so its buffer is reused by the next messages.
+        let mut queue = std::mem::take(&mut self.call_queue);
+        self.counters
+            .max_calls_per_message
+            .lock()
+            .unwrap()
+            .entry(self.canister_id)
+            .and_modify(|max| *max = (*max).max(queue.len()))
+            .or_insert(queue.len());
+
+        // The calls of a composite query and of its callbacks are query calls.
+        let query = self.is_query();
         let mut tmp = Vec::<CanisterCall>::with_capacity(queue.len());
-        for (callee, method, cb, payment, arg) in queue {
-            let request_id = RequestId::new();
+        for (callee, method, cb, payment, arg) in queue.drain(..) {
+            let request_id = self.counters.next_request_id();
 
             // Insert the pending request id for the current call.
             self.pending_outgoing_requests
@@ -371,26 +962,200 @@ impl Canister {
             // Store the callbacks to wake up the caller.
             self.outgoing_calls.insert(request_id, cb);
 
+            // The message of the callee is nested under the span of this message.
+            self.counters.spans.insert(request_id, span.clone());
+
             tmp.push(CanisterCall {
                 sender: self.id(),
                 request_id,
+                parent: self.request_id,
                 callee,
                 method,
                 payment,
                 arg,
+                query,
             });
         }
+        self.call_queue = queue;
+
+        if !self.pending_outgoing_requests.contains_key(&request_id) {
+            self.spans.remove(&request_id);
+            self.composite_queries.remove(&request_id);
+            self.hooks.remove(&request_id);
+            self.reply_limits.remove(&request_id);
+        }
+
+        self.publish_status();
+        self.check_invariants().await;
 
         tmp
     }
 
+    /// Find the exported method of the request, and set the entry mode of the env to the one the
+    /// method runs in. A query can run a composite query, but an update can not, and a query call
+    /// made by a composite query can only run a query or a composite query.
+    fn find_method(&self, env: &mut Env) -> Option<fn()> {
+        let candidates: &[(&str, EntryMode)] = match env.entry_mode {
+            EntryMode::Update => &[
+                ("canister_update", EntryMode::Update),
+                ("canister_query", EntryMode::Update),
+            ],
+            EntryMode::Query => &[
+                ("canister_query", EntryMode::Query),
+                ("canister_composite_query", EntryMode::CompositeQuery),
+                ("canister_update", EntryMode::Query),
+            ],
+            EntryMode::CompositeQuery => &[
+                ("canister_composite_query", EntryMode::CompositeQuery),
+                ("canister_query", EntryMode::Query),
+            ],
+            _ => return self.symbol_table.get(&env.get_entry_point_name()).copied(),
+        };
+
+        let method = env.method_name.clone().unwrap_or_default();
+        let (f, mode) = candidates.iter().find_map(|(prefix, mode)| {
+            self.symbol_table
+                .get(&format!("{} {}", prefix, method))
+                .map(|f| (*f, *mode))
+        })?;
+
+        env.entry_mode = mode;
+        Some(f)
+    }
+
+    /// Return the rejection message of a request to a method the canister does not export, or
+    /// which can not be called in the mode of the request.
+    fn missing_method_message(&self, env: &Env) -> String {
+        let method = env.method_name.clone().unwrap_or_default();
+        let exports = |prefix: &str| {
+            self.symbol_table
+                .contains_key(&format!("{} {}", prefix, method))
+        };
+
+        match env.entry_mode {
+            EntryMode::Update if exports("canister_composite_query") => format!(
+                "Composite query method '{}' can not be called as an update.",
+                method
+            ),
+            EntryMode::CompositeQuery if exports("canister_update") => format!(
+                "Update method '{}' can not be called by a composite query.",
+                method
+            ),
+            _ => format!("Canister does not have a '{}' method.", method),
+        }
+    }
+
+    /// Return true if the current message is a query, or a callback of a composite query, whose
+    /// changes to the state of the canister are discarded.
+    fn is_query(&self) -> bool {
+        matches!(
+            self.env.entry_mode,
+            EntryMode::Query | EntryMode::CompositeQuery
+        ) || matches!(self.request_id, Some(id) if self.composite_queries.contains(&id))
+    }
+
+    /// Return the limit of the reply to the incoming call of the env, which is the limit of the
+    /// inter-canister responses if the call was made by another canister.
+    fn reply_limit(&self, env: &Env) -> ReplyLimit {
+        let (kind, bytes) = match env.entry_mode {
+            _ if env.inter_canister => (
+                "an inter-canister response",
+                self.subnet.max_inter_canister_reply_size,
+            ),
+            EntryMode::Query | EntryMode::CompositeQuery => {
+                ("a query reply", self.subnet.max_query_reply_size)
+            }
+            _ => ("an update reply", self.subnet.max_reply_size),
+        };
+
+        ReplyLimit {
+            method: env
+                .method_name
+                .clone()
+                .unwrap_or_else(|| env.get_entry_point_name()),
+            kind,
+            bytes,
+        }
+    }
+
+    /// Return the fee of a call made by the current message, the query calls are free.
+    fn call_fee(&self) -> u128 {
+        if self.is_query() {
+            0
+        } else {
+            self.subnet.call_fee
+        }
+    }
+
+    /// Keep the content of the stable memory which is about to be overwritten by a query, so it
+    /// can be restored once the query ends.
+    fn save_stable(&mut self, offset: u64, size: usize) {
+        if !self.is_query() {
+            return;
+        }
+
+        let mut data = vec![0u8; size];
+        self.stable.stable_read(offset, &mut data);
+        self.stable_undo.push((offset, data));
+    }
+
+    /// Publish the balance and the memory size of the canister, see [`Counters::statuses`].
+    fn publish_status(&mut self) {
+        let memory_size = self.stable.stable_size() << 16;
+        self.counters
+            .statuses
+            .lock()
+            .unwrap()
+            .insert(self.canister_id, (self.balance, memory_size));
+    }
+
+    /// Take the observations of the canister registered with [`Replica::observe`] after a message,
+    /// and check the invariants of the replica against them.
+    ///
+    /// [`Replica::observe`]: crate::Replica::observe
+    async fn check_invariants(&mut self) {
+        let probes = self.counters.invariants.probes(&self.canister_id);
+
+        if probes.is_empty() {
+            return;
+        }
+
+        let values = Arc::new(Mutex::new(Vec::new()));
+        let output = values.clone();
+
+        // The observations are taken like a custom task, the environment of the message is kept
+        // for the report.
+        let env = std::mem::take(&mut self.env);
+        let completion = self
+            .perform(Task::Boxed(Box::new(move || {
+                *output.lock().unwrap() = probes
+                    .iter()
+                    .map(|(key, probe)| (key.clone(), probe()))
+                    .collect();
+            })))
+            .await;
+        self.env = env;
+
+        let values = match completion {
+            Completion::Ok => Ok(std::mem::take(&mut *values.lock().unwrap())),
+            Completion::Panicked(m) => Err(m),
+        };
+
+        self.counters
+            .invariants
+            .check(self.canister_id, &self.env, values);
+    }
+
     /// Execute the given task in the execution thread and return the completion status.
-    async fn perform(&mut self, task: TaskFn) -> Completion {
+    async fn perform(&mut self, task: Task) -> Completion {
         // make sure we clean the task_returned receiver. since we may have sent more than one
         // completion signal from previous task.
         while self.task_completion_rx.try_recv().is_ok() {}
         while self.request_rx.try_recv().is_ok() {}
 
+        self.instructions = 0;
+        self.slices = 1;
+
         self.task_tx.send(task).await.unwrap_or_else(|_| {
             panic!("ic-kit-runtime: Could not send the task to the execution thread.")
         });
@@ -402,7 +1167,19 @@ impl Canister {
                     break c;
                 },
                 Some(req) = self.request_rx.recv() => {
-                    let res = req.proxy(self);
+                    self.counters.system_calls.fetch_add(1, Ordering::SeqCst);
+                    self.instructions += self.subnet.system_call_instructions + copied_bytes(&req);
+
+                    let res = match self.instruction_limit() {
+                        Some(limit) if self.instructions > limit => runtime::Response::Trap(format!(
+                            "Canister exceeded the limit of {} instructions for single message execution.",
+                            limit
+                        )),
+                        _ => {
+                            self.maybe_next_slice().await;
+                            req.proxy(self)
+                        }
+                    };
                     self.reply_tx
                         .send(res)
                         .await
@@ -417,6 +1194,42 @@ impl Canister {
         completion
     }
 
+    /// Return the maximum number of instructions of the current message, the custom tasks of the
+    /// tests are not limited.
+    fn instruction_limit(&self) -> Option<u64> {
+        match self.env.entry_mode {
+            EntryMode::CustomTask => None,
+            EntryMode::Query | EntryMode::CompositeQuery => {
+                Some(self.subnet.max_instructions_per_query)
+            }
+            _ => Some(self.subnet.max_instructions_per_message),
+        }
+    }
+
+    /// Wait for the next round if the current message used the instructions of its slice.
+    async fn maybe_next_slice(&mut self) {
+        let slice = match self.subnet.max_instructions_per_slice {
+            Some(slice) => slice,
+            None => return,
+        };
+
+        if matches!(
+            self.env.entry_mode,
+            EntryMode::Query | EntryMode::CompositeQuery | EntryMode::CustomTask
+        ) || self.instructions < self.slices * slice
+        {
+            return;
+        }
+
+        // A system API call which copies a lot of bytes can use the instructions of several
+        // slices, the message is still paused once.
+        self.slices = self.instructions / slice + 1;
+
+        if let Some(rounds) = &self.rounds {
+            rounds.next(&self.env, self.slices).await;
+        }
+    }
+
     /// Send the final reply for the current call if none has already been sent.
     fn maybe_final_reply(&mut self, trap_message: Option<String>, cycles: u128) {
         let id = match self.request_id {
@@ -436,6 +1249,46 @@ impl Canister {
 
         self.cycles_available_store.remove(&id);
 
+        // System tasks such as init never reply, so completing without a trap is a success, even
+        // in the callback of a call made by an init or a post_upgrade hook.
+        let is_system_task = matches!(
+            self.env.entry_mode,
+            EntryMode::Init
+                | EntryMode::PreUpgrade
+                | EntryMode::PostUpgrade
+                | EntryMode::Heartbeat
+                | EntryMode::GlobalTimer
+        ) || self.hooks.contains(&id);
+
+        // The message is rejected unless the inspect_message accepted it.
+        if trap_message.is_none() && self.env.entry_mode == EntryMode::InspectMessage {
+            let reply = if self.message_accepted {
+                CallReply::Reply {
+                    data: Vec::new(),
+                    cycles_refunded: cycles,
+                }
+            } else {
+                CallReply::Reject {
+                    rejection_code: RejectionCode::CanisterReject,
+                    rejection_message: "Canister rejected the message".to_string(),
+                    cycles_refunded: cycles,
+                }
+            };
+
+            chan.send(reply)
+                .expect("ic-kit-runtime: Could not send the message reply.");
+            return;
+        }
+
+        if trap_message.is_none() && is_system_task {
+            chan.send(CallReply::Reply {
+                data: Vec::new(),
+                cycles_refunded: cycles,
+            })
+            .expect("ic-kit-runtime: Could not send the message reply.");
+            return;
+        }
+
         chan.send(CallReply::Reject {
             rejection_code: RejectionCode::CanisterError,
             rejection_message: trap_message
@@ -447,14 +1300,37 @@ impl Canister {
 
     fn discard_pending_call(&mut self) {
         if let Some(pending_call) = self.pending_call.take() {
-            self.env.balance += MAX_CYCLES_PER_RESPONSE + pending_call.3;
+            self.balance += self.call_fee() + pending_call.3;
         }
     }
 
     fn discard_call_queue(&mut self) {
         while let Some(pending_call) = self.call_queue.pop() {
-            self.env.balance += MAX_CYCLES_PER_RESPONSE + pending_call.3;
+            self.balance += self.call_fee() + pending_call.3;
+        }
+    }
+
+    /// Return the certificate of the data certified by the canister, which is only available to
+    /// the queries.
+    fn data_certificate(&self) -> Result<Vec<u8>, String> {
+        if self.env.entry_mode != EntryMode::Query {
+            return Err("There is no data certificate.".into());
         }
+
+        let certified_data = self
+            .counters
+            .certified_data
+            .lock()
+            .unwrap()
+            .get(&self.canister_id)
+            .cloned()
+            .unwrap_or_default();
+
+        Ok(certification::certificate(
+            &self.canister_id,
+            &certified_data,
+            self.env.time.unwrap_or_else(now),
+        ))
     }
 }
 
@@ -463,8 +1339,10 @@ impl Ic0CallHandlerProxy for Canister {
         match self.env.entry_mode {
             EntryMode::CustomTask
             | EntryMode::Init
+            | EntryMode::PostUpgrade
             | EntryMode::Update
             | EntryMode::Query
+            | EntryMode::CompositeQuery
             | EntryMode::ReplyCallback
             | EntryMode::InspectMessage => Ok(self.env.args.len() as isize),
             _ => Err(format!(
@@ -481,6 +1359,7 @@ impl Ic0CallHandlerProxy for Canister {
             | EntryMode::PostUpgrade
             | EntryMode::Update
             | EntryMode::Query
+            | EntryMode::CompositeQuery
             | EntryMode::ReplyCallback
             | EntryMode::InspectMessage => {
                 let data = self.env.args.as_slice();
@@ -502,6 +1381,7 @@ impl Ic0CallHandlerProxy for Canister {
             | EntryMode::PreUpgrade
             | EntryMode::Update
             | EntryMode::Query
+            | EntryMode::CompositeQuery
             | EntryMode::InspectMessage => Ok(self.env.sender.as_slice().len() as isize),
             _ => Err(format!(
                 "msg_caller_size can not be called from '{}'",
@@ -518,6 +1398,7 @@ impl Ic0CallHandlerProxy for Canister {
             | EntryMode::PreUpgrade
             | EntryMode::Update
             | EntryMode::Query
+            | EntryMode::CompositeQuery
             | EntryMode::InspectMessage => {
                 let data = self.env.sender.as_slice();
                 copy_to_canister(dst, offset, size, data)?;
@@ -578,6 +1459,7 @@ impl Ic0CallHandlerProxy for Canister {
             EntryMode::CustomTask
             | EntryMode::Update
             | EntryMode::Query
+            | EntryMode::CompositeQuery
             | EntryMode::ReplyCallback
             | EntryMode::RejectCallback => {
                 // this should always be present when processing a call.
@@ -598,6 +1480,21 @@ impl Ic0CallHandlerProxy for Canister {
             );
         }
 
+        // Like the replica, trap instead of sending a reply larger than the limit, the caller
+        // gets the trap as the message of the rejection.
+        let len = self.msg_reply_data.len() + size as usize;
+        let limit = self
+            .reply_limits
+            .get(&message_id)
+            .expect("ic-kit: Unexpected canister state, reply limit not set.");
+
+        if len > limit.bytes {
+            return Err(format!(
+                "msg_reply_data_append: application payload size ({}) cannot be larger than {}, the limit of {}, in the reply of method '{}'.",
+                len, limit.bytes, limit.kind, limit.method
+            ));
+        }
+
         self.msg_reply_data
             .extend_from_slice(copy_from_canister(src, size));
 
@@ -609,6 +1506,7 @@ impl Ic0CallHandlerProxy for Canister {
             EntryMode::CustomTask
             | EntryMode::Update
             | EntryMode::Query
+            | EntryMode::CompositeQuery
             | EntryMode::ReplyCallback
             | EntryMode::RejectCallback => {
                 // this should always be present when processing a call.
@@ -647,6 +1545,7 @@ impl Ic0CallHandlerProxy for Canister {
             EntryMode::CustomTask
             | EntryMode::Update
             | EntryMode::Query
+            | EntryMode::CompositeQuery
             | EntryMode::ReplyCallback
             | EntryMode::RejectCallback => {
                 // this should always be present when processing a call.
@@ -816,7 +1715,7 @@ impl Ic0CallHandlerProxy for Canister {
     }
 
     fn canister_cycle_balance(&mut self) -> Result<i64, String> {
-        let balance = self.env.balance + self.cycles_accepted;
+        let balance = self.balance + self.cycles_accepted;
 
         if balance > (u64::MAX as u128) {
             return Err("refunded cycles does not fit in u64".to_string());
@@ -826,7 +1725,7 @@ impl Ic0CallHandlerProxy for Canister {
     }
 
     fn canister_cycle_balance128(&mut self, dst: isize) -> Result<(), String> {
-        let balance = self.env.balance + self.cycles_accepted;
+        let balance = self.balance + self.cycles_accepted;
         let data = balance.to_le_bytes();
         copy_to_canister(dst, 0, 16, &data)?;
         Ok(())
@@ -837,6 +1736,12 @@ impl Ic0CallHandlerProxy for Canister {
         Ok(1)
     }
 
+    fn is_controller(&mut self, src: isize, size: isize) -> Result<i32, String> {
+        let principal = Principal::try_from_slice(copy_from_canister(src, size))
+            .map_err(|e| format!("Invalid principal: {}", e))?;
+        Ok(self.controllers.contains(&principal) as i32)
+    }
+
     fn msg_method_name_size(&mut self) -> Result<isize, String> {
         let method_name = match self.env.entry_mode {
             EntryMode::CustomTask | EntryMode::InspectMessage => self
@@ -882,8 +1787,19 @@ impl Ic0CallHandlerProxy for Canister {
     }
 
     fn accept_message(&mut self) -> Result<(), String> {
-        // TODO(qti3e) Hmm.. this has room for some thoughts.
-        todo!()
+        if self.env.entry_mode != EntryMode::InspectMessage {
+            return Err(format!(
+                "accept_message can not be called from '{}'",
+                self.env.get_entry_point_name()
+            ));
+        }
+
+        if self.message_accepted {
+            return Err("accept_message can only be called once.".to_string());
+        }
+
+        self.message_accepted = true;
+        Ok(())
     }
 
     fn call_new(
@@ -900,9 +1816,12 @@ impl Ic0CallHandlerProxy for Canister {
         match self.env.entry_mode {
             EntryMode::CustomTask
             | EntryMode::Update
+            | EntryMode::CompositeQuery
             | EntryMode::ReplyCallback
             | EntryMode::RejectCallback
-            | EntryMode::Heartbeat => {}
+            | EntryMode::Heartbeat
+            | EntryMode::GlobalTimer
+            | EntryMode::Init => {}
             _ => {
                 return Err(format!(
                     "call_new can not be called from '{}'",
@@ -913,16 +1832,24 @@ impl Ic0CallHandlerProxy for Canister {
 
         self.discard_pending_call();
 
-        if self.env.balance < MAX_CYCLES_PER_RESPONSE {
+        let fee = self.call_fee();
+
+        if self.balance < fee {
             return Err("Insufficient cycles balance to process canister response.".into());
         }
 
-        self.env.balance -= MAX_CYCLES_PER_RESPONSE;
+        self.balance -= fee;
 
         let callee_bytes = copy_from_canister(callee_src, callee_size);
         let name_bytes = copy_from_canister(name_src, name_size);
         let callee = Principal::from_slice(callee_bytes);
         let name = String::from_utf8_lossy(name_bytes).to_string();
+
+        if self.env.entry_mode == EntryMode::Init {
+            self.hook_calls
+                .push((callee, name.clone(), self.env.get_entry_point_name()));
+        }
+
         let callbacks = RequestCallbacks {
             message_id: self
                 .request_id
@@ -976,13 +1903,17 @@ impl Ic0CallHandlerProxy for Canister {
             ));
         }
 
+        if self.is_query() {
+            return Err("call_cycles_add can not be called from a query.".into());
+        }
+
         let amount = amount as u128;
 
-        if self.env.balance < amount {
+        if self.balance < amount {
             return Err(format!("Insufficient cycles balance."));
         }
 
-        self.env.balance -= amount;
+        self.balance -= amount;
         self.pending_call.as_mut().unwrap().3 += amount;
 
         Ok(())
@@ -995,15 +1926,19 @@ impl Ic0CallHandlerProxy for Canister {
             ));
         }
 
+        if self.is_query() {
+            return Err("call_cycles_add128 can not be called from a query.".into());
+        }
+
         let high = amount_high as u128;
         let low = amount_low as u128;
         let amount = high << 64 + low;
 
-        if self.env.balance < amount {
+        if self.balance < amount {
             return Err(format!("Insufficient cycles balance."));
         }
 
-        self.env.balance -= amount;
+        self.balance -= amount;
         self.pending_call.as_mut().unwrap().3 += amount;
 
         Ok(())
@@ -1042,6 +1977,10 @@ impl Ic0CallHandlerProxy for Canister {
     }
 
     fn stable_write(&mut self, _offset: i32, _src: isize, _size: isize) -> Result<(), String> {
+        self.save_stable(_offset as u64, _size as usize);
+        self.counters
+            .stable_bytes_written
+            .fetch_add(_size as u64, Ordering::SeqCst);
         self.stable
             .stable_write(_offset as u64, copy_from_canister(_src, _size));
 
@@ -1049,9 +1988,12 @@ impl Ic0CallHandlerProxy for Canister {
     }
 
     fn stable_read(&mut self, dst: isize, offset: i32, size: isize) -> Result<(), String> {
+        self.counters
+            .stable_bytes_read
+            .fetch_add(size as u64, Ordering::SeqCst);
         let mut buf = vec![0u8; size as usize];
         self.stable.stable_read(offset as u64, &mut buf);
-        copy_to_canister(dst, offset as isize, size, &buf)?;
+        copy_to_canister(dst, 0, size, &buf)?;
         Ok(())
     }
 
@@ -1064,6 +2006,10 @@ impl Ic0CallHandlerProxy for Canister {
     }
 
     fn stable64_write(&mut self, offset: i64, src: i64, size: i64) -> Result<(), String> {
+        self.save_stable(offset as u64, size as usize);
+        self.counters
+            .stable_bytes_written
+            .fetch_add(size as u64, Ordering::SeqCst);
         Ok(self.stable.stable_write(
             offset as u64,
             copy_from_canister(src as isize, size as isize),
@@ -1071,39 +2017,71 @@ impl Ic0CallHandlerProxy for Canister {
     }
 
     fn stable64_read(&mut self, dst: i64, offset: i64, size: i64) -> Result<(), String> {
+        self.counters
+            .stable_bytes_read
+            .fetch_add(size as u64, Ordering::SeqCst);
         let mut buf = vec![0u8; size as usize];
         self.stable.stable_read(offset as u64, &mut buf);
-        copy_to_canister(dst as isize, offset as isize, size as isize, &buf)?;
+        copy_to_canister(dst as isize, 0, size as isize, &buf)?;
         Ok(())
     }
 
-    fn certified_data_set(&mut self, _src: isize, _size: isize) -> Result<(), String> {
-        todo!()
+    fn certified_data_set(&mut self, src: isize, size: isize) -> Result<(), String> {
+        if self.is_query() || self.env.entry_mode == EntryMode::InspectMessage {
+            return Err(format!(
+                "certified_data_set can not be called from '{}'",
+                self.env.get_entry_point_name()
+            ));
+        }
+
+        if size > 32 {
+            return Err("certified_data_set: the data can not be larger than 32 bytes.".into());
+        }
+
+        self.certified_data = Some(copy_from_canister(src, size).to_vec());
+        Ok(())
     }
 
     fn data_certificate_present(&mut self) -> Result<i32, String> {
-        todo!()
+        Ok((self.env.entry_mode == EntryMode::Query) as i32)
     }
 
     fn data_certificate_size(&mut self) -> Result<isize, String> {
-        todo!()
+        Ok(self.data_certificate()?.len() as isize)
     }
 
     fn data_certificate_copy(
         &mut self,
-        _dst: isize,
-        _offset: isize,
-        _size: isize,
+        dst: isize,
+        offset: isize,
+        size: isize,
     ) -> Result<(), String> {
-        todo!()
+        let certificate = self.data_certificate()?;
+        copy_to_canister(dst, offset, size, &certificate)?;
+        Ok(())
     }
 
     fn time(&mut self) -> Result<i64, String> {
-        Ok(self.env.time as i64)
+        Ok(self.env.time.unwrap_or_else(now) as i64)
+    }
+
+    fn global_timer_set(&mut self, timestamp: i64) -> Result<i64, String> {
+        if self.is_query() || self.env.entry_mode == EntryMode::InspectMessage {
+            return Err(format!(
+                "global_timer_set can not be called from '{}'",
+                self.env.get_entry_point_name()
+            ));
+        }
+
+        let previous = self.pending_global_timer.unwrap_or(self.global_timer);
+        self.pending_global_timer = Some(timestamp as u64);
+        Ok(previous as i64)
     }
 
     fn performance_counter(&mut self, _counter_type: i32) -> Result<i64, String> {
-        todo!()
+        // The canister is executed natively, so only the instructions of the system API calls are
+        // counted.
+        Ok(self.instructions as i64)
     }
 
     fn debug_print(&mut self, src: isize, size: isize) -> Result<(), String> {
@@ -1116,10 +2094,38 @@ impl Ic0CallHandlerProxy for Canister {
     fn trap(&mut self, src: isize, size: isize) -> Result<(), String> {
         let bytes = copy_from_canister(src, size);
         let message = String::from_utf8_lossy(bytes).to_string();
-        Err(format!("Canister trapped: '{}'", message))
+        Err(message)
     }
 }
 
+/// Return the number of bytes copied by the system API call, each of them is counted as an
+/// instruction.
+fn copied_bytes(request: &runtime::Request) -> u64 {
+    use runtime::Request::*;
+
+    let size = match request {
+        msg_arg_data_copy { size, .. }
+        | msg_caller_copy { size, .. }
+        | msg_reject_msg_copy { size, .. }
+        | msg_reply_data_append { size, .. }
+        | msg_reject { size, .. }
+        | canister_self_copy { size, .. }
+        | is_controller { size, .. }
+        | msg_method_name_copy { size, .. }
+        | call_data_append { size, .. }
+        | stable_write { size, .. }
+        | stable_read { size, .. }
+        | certified_data_set { size, .. }
+        | data_certificate_copy { size, .. }
+        | debug_print { size, .. }
+        | trap { size, .. } => *size as i64,
+        stable64_write { size, .. } | stable64_read { size, .. } => *size,
+        _ => 0,
+    };
+
+    size.max(0) as u64
+}
+
 fn copy_to_canister(dst: isize, offset: isize, size: isize, data: &[u8]) -> Result<(), String> {
     let dst = dst as usize;
     let offset = offset as usize;
@@ -1141,7 +2147,7 @@ fn copy_from_canister<'a>(src: isize, size: isize) -> &'a [u8] {
     unsafe { std::slice::from_raw_parts(src as *const u8, size) }
 }
 
-fn downcast_panic_payload(payload: &Box<dyn Any + Send>) -> String {
+pub(crate) fn downcast_panic_payload(payload: &Box<dyn Any + Send>) -> String {
     payload
         .downcast_ref::<&'static str>()
         .cloned()
@@ -1149,3 +2155,138 @@ fn downcast_panic_payload(payload: &Box<dyn Any + Send>) -> String {
         .or_else(|| payload.downcast_ref::<String>().cloned())
         .unwrap_or_else(|| String::from("Box<Any>"))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replica::Replica;
+
+    fn arg_data() -> Vec<u8> {
+        let size = unsafe { ic0::msg_arg_data_size() };
+        let mut buf = vec![0u8; size as usize];
+        unsafe { ic0::msg_arg_data_copy(buf.as_mut_ptr() as isize, 0, size) };
+        buf
+    }
+
+    fn callback(_env: isize) {}
+
+    /// A hook which traps if its argument is `trap`, and makes a call if it's `call`.
+    fn hook() {
+        match arg_data().as_slice() {
+            b"trap" => unsafe {
+                let message = "The hook trapped.";
+                ic0::trap(message.as_ptr() as isize, message.len() as isize);
+            },
+            b"call" => unsafe {
+                let callee = Principal::anonymous();
+                let name = "ping";
+                let callback = callback as fn(isize) as usize as isize;
+                ic0::call_new(
+                    callee.as_slice().as_ptr() as isize,
+                    callee.as_slice().len() as isize,
+                    name.as_ptr() as isize,
+                    name.len() as isize,
+                    callback,
+                    0,
+                    callback,
+                    0,
+                );
+                ic0::call_perform();
+            },
+            _ => {}
+        }
+    }
+
+    struct Init;
+    struct PreUpgrade;
+    struct PostUpgrade;
+    struct Heartbeat;
+
+    impl CanisterMethod for Init {
+        const EXPORT_NAME: &'static str = "canister_init";
+
+        fn exported_method() {
+            hook()
+        }
+    }
+
+    impl CanisterMethod for PreUpgrade {
+        const EXPORT_NAME: &'static str = "canister_pre_upgrade";
+
+        fn exported_method() {}
+    }
+
+    impl CanisterMethod for PostUpgrade {
+        const EXPORT_NAME: &'static str = "canister_post_upgrade";
+
+        fn exported_method() {
+            hook()
+        }
+    }
+
+    impl CanisterMethod for Heartbeat {
+        const EXPORT_NAME: &'static str = "canister_heartbeat";
+
+        fn exported_method() {}
+    }
+
+    fn build() -> Canister {
+        Canister::new(crate::replica::canister_id(1))
+            .with_method::<Init>()
+            .with_method::<PreUpgrade>()
+            .with_method::<PostUpgrade>()
+            .with_method::<Heartbeat>()
+    }
+
+    #[tokio::test]
+    async fn system_tasks_reply() {
+        let replica = Replica::default();
+        let canister = replica.add_canister(build());
+
+        assert!(matches!(
+            canister.init().await,
+            CallReply::Reply { ref data, .. } if data.is_empty()
+        ));
+        assert!(matches!(
+            canister.heartbeat().await,
+            CallReply::Reply { ref data, .. } if data.is_empty()
+        ));
+        assert!(matches!(
+            canister.upgrade_raw(build(), Vec::new()).await,
+            CallReply::Reply { ref data, .. } if data.is_empty()
+        ));
+
+        // The hooks which are not exported are a no-op.
+        let bare = replica.add_canister(Canister::new(crate::replica::canister_id(2)));
+        bare.init().await.assert_ok();
+        bare.heartbeat().await.assert_ok();
+        bare.upgrade_raw(Canister::new(crate::replica::canister_id(2)), Vec::new())
+            .await
+            .assert_ok();
+    }
+
+    #[tokio::test]
+    async fn system_tasks_reject() {
+        let replica = Replica::default();
+        let canister = replica.add_canister(build().with_init_arg_raw(b"trap".to_vec()));
+
+        let reply = canister.init().await;
+        assert!(reply
+            .rejection_message()
+            .unwrap()
+            .contains("The hook trapped."));
+
+        let reply = canister.upgrade_raw(build(), b"trap".to_vec()).await;
+        assert!(reply
+            .rejection_message()
+            .unwrap()
+            .contains("The hook trapped."));
+
+        // The post_upgrade hook can't make calls, like on the Internet Computer.
+        let reply = canister.upgrade_raw(build(), b"call".to_vec()).await;
+        assert!(reply
+            .rejection_message()
+            .unwrap()
+            .contains("call_new can not be called from 'canister_post_upgrade'"));
+    }
+}
@@ -0,0 +1,854 @@
+//! The certificates of the data certified by the canisters, and a verifier for the certified
+//! query responses.
+//!
+//! A canister sets its certified data with `ic::set_certified_data` in an update, usually the root
+//! hash of a hash tree of its state, and the queries return a value along with the certificate of
+//! the replica and a witness, the part of the hash tree which proves the value:
+//!
+//! ```ignore
+//! let reply = ledger.new_call("balance").with_arg(alice).perform_query().await;
+//! let balance: Certified<u64> = reply.decode_one().unwrap();
+//!
+//! let tree = balance.verify(&ledger.canister_id()).unwrap();
+//! assert_eq!(tree.lookup(&[alice.to_text().as_bytes()]), Some(&100u64.to_be_bytes()[..]));
+//! ```
+//!
+//! The certificates follow the format of the Internet Computer, with the `time` of the replica and
+//! the certified data of the canister at `canister/<id>/certified_data`, but they are signed with
+//! the ed25519 key of `root_identity` instead of the BLS key of a subnet, or without the
+//! `identity` feature with a SHA-256 hash keyed by the seed of that key. They are only available to
+//! the queries, like on the Internet Computer.
+//!
+//! The state of the replica can also be read like with the `read_state` requests of the Internet
+//! Computer, which return a certificate of the requested paths, such as the status of a call:
+//!
+//! ```ignore
+//! let call = ledger.new_call("transfer").with_args((bob, 10u64));
+//! call.perform().await.assert_ok();
+//!
+//! let certificate = replica.read_state(&[request_status_path(&call.request_id())]).await;
+//! let certificate = Certificate::decode(&certificate).unwrap();
+//! assert!(certificate.is_signed());
+//!
+//! let path = [&b"request_status"[..], &call.request_id(), b"status"];
+//! assert_eq!(certificate.tree.lookup(&path), Some(&b"replied"[..]));
+//! ```
+
+use std::collections::BTreeMap;
+
+use candid::Principal;
+use ic_kit_sys::types::RejectionCode;
+use serde_cbor::Value;
+use sha2::{Digest, Sha256};
+
+use crate::call::CallReply;
+#[cfg(feature = "identity")]
+use crate::identity::Identity;
+
+/// The tag which starts a self-describing CBOR value.
+const CBOR_SELF_DESCRIBE: [u8; 3] = [0xd9, 0xd9, 0xf7];
+
+/// The domain separator of the signature of the root hash of a certificate.
+const STATE_ROOT_DOMAIN: &[u8] = b"\x0dic-state-root";
+
+/// The seed of the key which signs the certificates of the replicas.
+const ROOT_SEED: &str = "ic-kit-runtime:root";
+
+/// A hash tree as defined by the interface specification of the Internet Computer, such as the
+/// tree of a certificate or a witness.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HashTree {
+    Empty,
+    Fork(Box<HashTree>, Box<HashTree>),
+    Labeled(Vec<u8>, Box<HashTree>),
+    Leaf(Vec<u8>),
+    Pruned([u8; 32]),
+}
+
+/// The state tree of a replica, in which each node is a leaf or has labeled children, see
+/// [`HashTree`] for the tree of its hashes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum LabeledTree {
+    Leaf(Vec<u8>),
+    SubTree(BTreeMap<Vec<u8>, LabeledTree>),
+}
+
+/// The status of a call made by a user, at `request_status/<request id>` in the state tree, see
+/// [`crate::ingress`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RequestStatus {
+    /// The call is queued for its canister.
+    Received,
+    /// The canister is executing the call.
+    Processing,
+    /// The call was replied to with the given data.
+    Replied(Vec<u8>),
+    /// The call was rejected with the given code and message.
+    Rejected(RejectionCode, String),
+    /// The call was replied to and its ingress expiry has passed, so the reply was dropped.
+    Done,
+}
+
+/// A certificate of the replica, which authenticates its tree with a signature of its root hash.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Certificate {
+    /// The tree of the certificate.
+    pub tree: HashTree,
+    /// The signature of the root hash of the tree.
+    pub signature: Vec<u8>,
+}
+
+impl HashTree {
+    /// Decode the CBOR encoding of a hash tree.
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        let bytes = bytes.strip_prefix(&CBOR_SELF_DESCRIBE).unwrap_or(bytes);
+        let value: Value =
+            serde_cbor::from_slice(bytes).map_err(|e| format!("Invalid CBOR: {}", e))?;
+        Self::from_value(&value)
+    }
+
+    /// Return the CBOR encoding of the hash tree.
+    pub fn encode(&self) -> Vec<u8> {
+        serde_cbor::to_vec(&self.to_value()).expect("ic-kit-runtime: Could not encode the tree.")
+    }
+
+    /// Return the root hash of the tree.
+    pub fn digest(&self) -> [u8; 32] {
+        match self {
+            HashTree::Empty => domain_hash("ic-hashtree-empty", &[]),
+            HashTree::Fork(left, right) => {
+                domain_hash("ic-hashtree-fork", &[&left.digest(), &right.digest()])
+            }
+            HashTree::Labeled(label, tree) => {
+                domain_hash("ic-hashtree-labeled", &[label, &tree.digest()])
+            }
+            HashTree::Leaf(data) => domain_hash("ic-hashtree-leaf", &[data]),
+            HashTree::Pruned(hash) => *hash,
+        }
+    }
+
+    /// Return the data of the leaf at the given path of labels, if it's in the tree.
+    pub fn lookup(&self, path: &[&[u8]]) -> Option<&[u8]> {
+        match (path.split_first(), self) {
+            (None, HashTree::Leaf(data)) => Some(data),
+            (None, _) => None,
+            (Some((label, rest)), _) => self.find_label(label)?.lookup(rest),
+        }
+    }
+
+    /// Find the subtree with the label among the labeled subtrees of the forks of this tree.
+    fn find_label(&self, label: &[u8]) -> Option<&HashTree> {
+        match self {
+            HashTree::Labeled(l, tree) if l.as_slice() == label => Some(tree),
+            HashTree::Fork(left, right) => {
+                left.find_label(label).or_else(|| right.find_label(label))
+            }
+            _ => None,
+        }
+    }
+
+    fn from_value(value: &Value) -> Result<Self, String> {
+        let invalid = || "Invalid hash tree.".to_string();
+        let items = match value {
+            Value::Array(items) => items,
+            _ => return Err(invalid()),
+        };
+
+        match items.as_slice() {
+            [Value::Integer(0)] => Ok(HashTree::Empty),
+            [Value::Integer(1), left, right] => Ok(HashTree::Fork(
+                Box::new(Self::from_value(left)?),
+                Box::new(Self::from_value(right)?),
+            )),
+            [Value::Integer(2), Value::Bytes(label), tree] => Ok(HashTree::Labeled(
+                label.clone(),
+                Box::new(Self::from_value(tree)?),
+            )),
+            [Value::Integer(3), Value::Bytes(data)] => Ok(HashTree::Leaf(data.clone())),
+            [Value::Integer(4), Value::Bytes(hash)] => hash
+                .as_slice()
+                .try_into()
+                .map(HashTree::Pruned)
+                .map_err(|_| invalid()),
+            _ => Err(invalid()),
+        }
+    }
+
+    fn to_value(&self) -> Value {
+        let items = match self {
+            HashTree::Empty => vec![Value::Integer(0)],
+            HashTree::Fork(left, right) => {
+                vec![Value::Integer(1), left.to_value(), right.to_value()]
+            }
+            HashTree::Labeled(label, tree) => vec![
+                Value::Integer(2),
+                Value::Bytes(label.clone()),
+                tree.to_value(),
+            ],
+            HashTree::Leaf(data) => vec![Value::Integer(3), Value::Bytes(data.clone())],
+            HashTree::Pruned(hash) => vec![Value::Integer(4), Value::Bytes(hash.to_vec())],
+        };
+
+        Value::Array(items)
+    }
+}
+
+impl Certificate {
+    /// Decode the CBOR encoding of a certificate.
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        let bytes = bytes.strip_prefix(&CBOR_SELF_DESCRIBE).unwrap_or(bytes);
+        let value: Value =
+            serde_cbor::from_slice(bytes).map_err(|e| format!("Invalid CBOR: {}", e))?;
+
+        let map = match value {
+            Value::Map(map) => map,
+            _ => return Err("Invalid certificate.".to_string()),
+        };
+
+        let tree = map
+            .get(&Value::Text("tree".into()))
+            .ok_or("The certificate does not have a tree.")?;
+        let signature = match map.get(&Value::Text("signature".into())) {
+            Some(Value::Bytes(signature)) => signature.clone(),
+            _ => return Err("The certificate does not have a signature.".to_string()),
+        };
+
+        Ok(Self {
+            tree: HashTree::from_value(tree)?,
+            signature,
+        })
+    }
+
+    /// Return the CBOR encoding of the certificate.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut map = BTreeMap::new();
+        map.insert(Value::Text("tree".into()), self.tree.to_value());
+        map.insert(
+            Value::Text("signature".into()),
+            Value::Bytes(self.signature.clone()),
+        );
+
+        let mut bytes = CBOR_SELF_DESCRIBE.to_vec();
+        bytes.extend(
+            serde_cbor::to_vec(&Value::Map(map))
+                .expect("ic-kit-runtime: Could not encode the certificate."),
+        );
+        bytes
+    }
+
+    /// Return true if the certificate is signed by the replicas, see `root_identity`.
+    pub fn is_signed(&self) -> bool {
+        // Both of the signatures are deterministic, so the certificate is signed again.
+        self.signature == root_sign(&signed_message(&self.tree))
+    }
+
+    /// Return the time of the replica when the certificate was made, in nanoseconds.
+    pub fn time(&self) -> Option<u64> {
+        let mut time = 0u64;
+
+        for (i, byte) in self.tree.lookup(&[b"time"])?.iter().enumerate() {
+            time |= ((byte & 0x7f) as u64).checked_shl(7 * i as u32)?;
+        }
+
+        Some(time)
+    }
+
+    /// Return the certified data of the canister in the certificate.
+    pub fn certified_data(&self, canister_id: &Principal) -> Option<&[u8]> {
+        self.tree
+            .lookup(&[b"canister", canister_id.as_slice(), b"certified_data"])
+    }
+}
+
+impl LabeledTree {
+    /// Create an empty subtree.
+    pub(crate) fn new() -> Self {
+        LabeledTree::SubTree(BTreeMap::new())
+    }
+
+    /// Insert the leaf at the given path, creating the subtrees on the way.
+    ///
+    /// # Panics
+    ///
+    /// If the path goes through a leaf.
+    pub(crate) fn insert(&mut self, path: &[&[u8]], data: Vec<u8>) {
+        let children = match self {
+            LabeledTree::SubTree(children) => children,
+            LabeledTree::Leaf(_) => panic!("ic-kit-runtime: The path goes through a leaf."),
+        };
+
+        match path {
+            [] => panic!("ic-kit-runtime: The path of a leaf can not be empty."),
+            [label] => {
+                children.insert(label.to_vec(), LabeledTree::Leaf(data));
+            }
+            [label, rest @ ..] => children
+                .entry(label.to_vec())
+                .or_insert_with(LabeledTree::new)
+                .insert(rest, data),
+        }
+    }
+
+    /// Return the hash tree of the whole tree.
+    pub(crate) fn hash_tree(&self) -> HashTree {
+        match self {
+            LabeledTree::Leaf(data) => HashTree::Leaf(data.clone()),
+            LabeledTree::SubTree(children) => fork(
+                children
+                    .iter()
+                    .map(|(label, child)| {
+                        HashTree::Labeled(label.clone(), Box::new(child.hash_tree()))
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Return the hash tree which reveals the subtrees at the given paths, and prunes the others,
+    /// so it has the same root hash as the whole tree.
+    pub(crate) fn witness(&self, paths: &[&[Vec<u8>]]) -> HashTree {
+        let children = match self {
+            LabeledTree::Leaf(data) => return HashTree::Leaf(data.clone()),
+            LabeledTree::SubTree(_) if paths.iter().any(|p| p.is_empty()) => {
+                return self.hash_tree();
+            }
+            LabeledTree::SubTree(children) => children,
+        };
+
+        let nodes = children
+            .iter()
+            .map(|(label, child)| {
+                let rest: Vec<&[Vec<u8>]> = paths
+                    .iter()
+                    .filter(|p| &p[0] == label)
+                    .map(|p| &p[1..])
+                    .collect();
+
+                let node = HashTree::Labeled(label.clone(), Box::new(child.witness(&rest)));
+                if rest.is_empty() {
+                    HashTree::Pruned(node.digest())
+                } else {
+                    node
+                }
+            })
+            .collect();
+
+        fork(nodes)
+    }
+}
+
+impl RequestStatus {
+    /// Return the status of a call with the given reply.
+    pub(crate) fn of(reply: &CallReply) -> Self {
+        match reply {
+            CallReply::Reply { data, .. } => RequestStatus::Replied(data.clone()),
+            CallReply::Reject {
+                rejection_code,
+                rejection_message,
+                ..
+            } => RequestStatus::Rejected(*rejection_code, rejection_message.clone()),
+        }
+    }
+
+    /// Insert the status at `request_status/<request id>` in the state tree.
+    pub(crate) fn insert_into(&self, tree: &mut LabeledTree, request_id: &[u8]) {
+        let path = |field: &'static [u8]| [&b"request_status"[..], request_id, field];
+
+        match self {
+            RequestStatus::Received => tree.insert(&path(b"status"), b"received".to_vec()),
+            RequestStatus::Processing => tree.insert(&path(b"status"), b"processing".to_vec()),
+            RequestStatus::Replied(data) => {
+                tree.insert(&path(b"status"), b"replied".to_vec());
+                tree.insert(&path(b"reply"), data.clone());
+            }
+            RequestStatus::Rejected(code, message) => {
+                tree.insert(&path(b"status"), b"rejected".to_vec());
+                tree.insert(&path(b"reject_code"), leb128(*code as i32 as u64));
+                tree.insert(&path(b"reject_message"), message.clone().into_bytes());
+            }
+            RequestStatus::Done => tree.insert(&path(b"status"), b"done".to_vec()),
+        }
+    }
+}
+
+/// Return the id of the request of a call made by a user, which is the representation-independent
+/// hash of the content of the request like on the Internet Computer, without the `ingress_expiry`
+/// and the `nonce` fields, so the same call always has the same id.
+pub fn request_id(
+    sender: &Principal,
+    canister_id: &Principal,
+    method: &str,
+    arg: &[u8],
+) -> [u8; 32] {
+    let mut fields: Vec<[u8; 32]> = [
+        (&b"request_type"[..], &b"call"[..]),
+        (b"sender", sender.as_slice()),
+        (b"canister_id", canister_id.as_slice()),
+        (b"method_name", method.as_bytes()),
+        (b"arg", arg),
+    ]
+    .iter()
+    .map(|(key, value)| {
+        let mut hasher = Sha256::new();
+        hasher.update(Sha256::digest(key));
+        hasher.update(Sha256::digest(value));
+        hasher.finalize().into()
+    })
+    .collect();
+
+    fields.sort_unstable();
+    Sha256::digest(fields.concat()).into()
+}
+
+/// Return the path of the time of the replica in the state tree.
+pub fn time_path() -> Vec<Vec<u8>> {
+    vec![b"time".to_vec()]
+}
+
+/// Return the path of the sha256 hash of the module of the canister in the state tree, which is
+/// the hash of its wasm module if it was provided, otherwise the hash of the names of its methods.
+pub fn module_hash_path(canister_id: &Principal) -> Vec<Vec<u8>> {
+    canister_path(canister_id, b"module_hash")
+}
+
+/// Return the path of the CBOR encoded list of the controllers of the canister in the state tree.
+pub fn controllers_path(canister_id: &Principal) -> Vec<Vec<u8>> {
+    canister_path(canister_id, b"controllers")
+}
+
+/// Return the path of the certified data of the canister in the state tree.
+pub fn certified_data_path(canister_id: &Principal) -> Vec<Vec<u8>> {
+    canister_path(canister_id, b"certified_data")
+}
+
+/// Return the path of the section of the metadata of the canister with the given name in the
+/// state tree, see [`crate::metadata`].
+pub fn metadata_path(canister_id: &Principal, name: &str) -> Vec<Vec<u8>> {
+    vec![
+        b"canister".to_vec(),
+        canister_id.as_slice().to_vec(),
+        b"metadata".to_vec(),
+        name.as_bytes().to_vec(),
+    ]
+}
+
+/// Return the path of the status of the call with the given request id in the state tree, see
+/// [`request_id`].
+pub fn request_status_path(request_id: &[u8]) -> Vec<Vec<u8>> {
+    vec![b"request_status".to_vec(), request_id.to_vec()]
+}
+
+fn canister_path(canister_id: &Principal, field: &[u8]) -> Vec<Vec<u8>> {
+    vec![
+        b"canister".to_vec(),
+        canister_id.as_slice().to_vec(),
+        field.to_vec(),
+    ]
+}
+
+/// Return the identity which signs the certificates of the replicas.
+#[cfg(feature = "identity")]
+pub fn root_identity() -> Identity {
+    Identity::ed25519(ROOT_SEED)
+}
+
+/// Sign the message with the ed25519 key of the [`root_identity`].
+#[cfg(feature = "identity")]
+fn root_sign(message: &[u8]) -> Vec<u8> {
+    root_identity().sign(message)
+}
+
+/// Sign the message with a SHA-256 hash keyed by the seed of the root key, which can only be
+/// checked by the runtime.
+#[cfg(not(feature = "identity"))]
+fn root_sign(message: &[u8]) -> Vec<u8> {
+    domain_hash(ROOT_SEED, &[message]).to_vec()
+}
+
+/// Verify the response of a certified query: the certificate must be signed by the replicas, and
+/// the root hash of the witness must be the certified data of the canister. Returns the witness,
+/// in which the value of the response can be looked up.
+pub fn verify(
+    canister_id: &Principal,
+    certificate: &[u8],
+    witness: &[u8],
+) -> Result<HashTree, String> {
+    let certificate = Certificate::decode(certificate)?;
+
+    if !certificate.is_signed() {
+        return Err("The certificate is not signed by the replica.".to_string());
+    }
+
+    let certified_data = certificate.certified_data(canister_id).ok_or_else(|| {
+        format!(
+            "The certificate does not have the certified data of canister '{}'.",
+            canister_id
+        )
+    })?;
+
+    let witness = HashTree::decode(witness)?;
+    if witness.digest().as_slice() != certified_data {
+        return Err(format!(
+            "The witness does not match the certified data of canister '{}'.",
+            canister_id
+        ));
+    }
+
+    Ok(witness)
+}
+
+/// Create the certificate of the certified data of a canister at the given time.
+pub(crate) fn certificate(canister_id: &Principal, certified_data: &[u8], time: u64) -> Vec<u8> {
+    let mut tree = LabeledTree::new();
+    tree.insert(
+        &[b"canister", canister_id.as_slice(), b"certified_data"],
+        certified_data.to_vec(),
+    );
+    tree.insert(&[b"time"], leb128(time));
+
+    sign(tree.hash_tree())
+}
+
+/// Create the certificate of the given paths of the state tree, and of the time of the replica
+/// which is always included, like the response to a `read_state` request.
+pub(crate) fn read_state(tree: &LabeledTree, paths: &[Vec<Vec<u8>>]) -> Vec<u8> {
+    let time = time_path();
+    let mut paths: Vec<&[Vec<u8>]> = paths.iter().map(Vec::as_slice).collect();
+    paths.push(&time);
+
+    sign(tree.witness(&paths))
+}
+
+/// Encode the time of the replica as a leaf of the state tree.
+pub(crate) fn time_leaf(time: u64) -> Vec<u8> {
+    leb128(time)
+}
+
+/// Encode a list of principals as a leaf of the state tree, such as the controllers of a canister.
+pub(crate) fn principals_leaf(principals: &[Principal]) -> Vec<u8> {
+    let list = principals
+        .iter()
+        .map(|p| Value::Bytes(p.as_slice().to_vec()))
+        .collect();
+
+    let mut bytes = CBOR_SELF_DESCRIBE.to_vec();
+    bytes.extend(
+        serde_cbor::to_vec(&Value::Array(list))
+            .expect("ic-kit-runtime: Could not encode the principals."),
+    );
+    bytes
+}
+
+fn sign(tree: HashTree) -> Vec<u8> {
+    Certificate {
+        signature: root_sign(&signed_message(&tree)),
+        tree,
+    }
+    .encode()
+}
+
+/// Join the nodes in a balanced tree of forks, in order.
+fn fork(mut nodes: Vec<HashTree>) -> HashTree {
+    match nodes.len() {
+        0 => HashTree::Empty,
+        1 => nodes.remove(0),
+        n => {
+            let right = nodes.split_off(n / 2);
+            HashTree::Fork(Box::new(fork(nodes)), Box::new(fork(right)))
+        }
+    }
+}
+
+fn signed_message(tree: &HashTree) -> Vec<u8> {
+    let mut message = STATE_ROOT_DOMAIN.to_vec();
+    message.extend_from_slice(&tree.digest());
+    message
+}
+
+fn domain_hash(domain: &str, parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([domain.len() as u8]);
+    hasher.update(domain.as_bytes());
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn leb128(mut value: u64) -> Vec<u8> {
+    let mut bytes = Vec::new();
+
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+
+        if value == 0 {
+            bytes.push(byte);
+            return bytes;
+        }
+
+        bytes.push(byte | 0x80);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labeled(label: &str, tree: HashTree) -> HashTree {
+        HashTree::Labeled(label.as_bytes().to_vec(), Box::new(tree))
+    }
+
+    fn leaf(data: &str) -> HashTree {
+        HashTree::Leaf(data.as_bytes().to_vec())
+    }
+
+    fn fork(left: HashTree, right: HashTree) -> HashTree {
+        HashTree::Fork(Box::new(left), Box::new(right))
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// The example tree of the interface specification of the Internet Computer.
+    fn spec_tree() -> HashTree {
+        fork(
+            fork(
+                labeled(
+                    "a",
+                    fork(
+                        fork(labeled("x", leaf("hello")), HashTree::Empty),
+                        labeled("y", leaf("world")),
+                    ),
+                ),
+                labeled("b", leaf("good")),
+            ),
+            fork(labeled("c", HashTree::Empty), labeled("d", leaf("morning"))),
+        )
+    }
+
+    const SPEC_ROOT_HASH: &str = "eb5c5b2195e62d996b84c9bcc8259d19a83786a2f59e0878cec84c811f669aa0";
+
+    const SPEC_CBOR: &str = "8301830183024161830183018302417882034568656c6c6f810083024179820345776f726c6483024162820344676f6f648301830241638100830241648203476d6f726e696e67";
+
+    #[test]
+    fn spec_root_hash() {
+        assert_eq!(hex(&spec_tree().digest()), SPEC_ROOT_HASH);
+        assert_eq!(hex(&spec_tree().encode()), SPEC_CBOR);
+        assert_eq!(HashTree::decode(&unhex(SPEC_CBOR)).unwrap(), spec_tree());
+    }
+
+    #[test]
+    fn spec_pruned_root_hash() {
+        // Prune everything but the path to "d", and then the "y" branch of "a".
+        let tree = spec_tree();
+        let (left, right) = match &tree {
+            HashTree::Fork(left, right) => (left, right),
+            _ => unreachable!(),
+        };
+        let pruned = fork(HashTree::Pruned(left.digest()), *right.clone());
+        assert_eq!(hex(&pruned.digest()), SPEC_ROOT_HASH);
+        assert_eq!(pruned.lookup(&[b"d"]), Some(&b"morning"[..]));
+        assert_eq!(pruned.lookup(&[b"b"]), None);
+
+        let pruned = fork(
+            fork(
+                labeled(
+                    "a",
+                    fork(
+                        fork(labeled("x", leaf("hello")), HashTree::Empty),
+                        HashTree::Pruned(labeled("y", leaf("world")).digest()),
+                    ),
+                ),
+                labeled("b", leaf("good")),
+            ),
+            HashTree::Pruned(right.digest()),
+        );
+        assert_eq!(hex(&pruned.digest()), SPEC_ROOT_HASH);
+        assert_eq!(pruned.lookup(&[b"a", b"x"]), Some(&b"hello"[..]));
+        assert_eq!(pruned.lookup(&[b"a", b"y"]), None);
+    }
+
+    #[test]
+    fn decode_invalid() {
+        assert!(HashTree::decode(&[0xff]).is_err());
+        // A pruned node with a hash shorter than 32 bytes.
+        let mut pruned = HashTree::Leaf(vec![]).encode();
+        pruned[1] = 4;
+        assert_eq!(
+            HashTree::decode(&pruned),
+            Err("Invalid hash tree.".to_string())
+        );
+    }
+
+    fn state_tree() -> LabeledTree {
+        let canister = Principal::from_slice(&[1; 10]);
+        let mut tree = LabeledTree::new();
+        tree.insert(&[b"time"], leb128(1_000));
+        tree.insert(
+            &[b"canister", canister.as_slice(), b"certified_data"],
+            vec![7; 32],
+        );
+        tree.insert(
+            &[b"canister", canister.as_slice(), b"controllers"],
+            principals_leaf(&[Principal::anonymous()]),
+        );
+        tree.insert(
+            &[b"canister", canister.as_slice(), b"module_hash"],
+            vec![8; 32],
+        );
+
+        for i in 0..10u8 {
+            RequestStatus::Replied(vec![i]).insert_into(&mut tree, &[i; 32]);
+        }
+        RequestStatus::Rejected(RejectionCode::CanisterReject, "no".to_string())
+            .insert_into(&mut tree, &[20; 32]);
+
+        tree
+    }
+
+    #[test]
+    fn witness_keeps_root_hash() {
+        let tree = state_tree();
+        let root = tree.hash_tree().digest();
+        let canister = Principal::from_slice(&[1; 10]);
+
+        let path_sets = vec![
+            vec![],
+            vec![time_path()],
+            vec![certified_data_path(&canister)],
+            vec![request_status_path(&[3; 32]), time_path()],
+            vec![request_status_path(&[20; 32]), controllers_path(&canister)],
+            vec![vec![b"canister".to_vec()]],
+            // The paths which are not in the tree are pruned.
+            vec![
+                request_status_path(&[99; 32]),
+                metadata_path(&canister, "a"),
+            ],
+        ];
+
+        for paths in path_sets {
+            let paths: Vec<&[Vec<u8>]> = paths.iter().map(Vec::as_slice).collect();
+            let witness = tree.witness(&paths);
+            assert_eq!(witness.digest(), root);
+
+            // The witness is the same once encoded and decoded.
+            let decoded = HashTree::decode(&witness.encode()).unwrap();
+            assert_eq!(decoded, witness);
+            assert_eq!(decoded.digest(), root);
+        }
+    }
+
+    #[test]
+    fn witness_reveals_paths() {
+        let tree = state_tree();
+        let canister = Principal::from_slice(&[1; 10]);
+        let witness = tree.witness(&[
+            &request_status_path(&[3; 32]),
+            &request_status_path(&[20; 32]),
+            &module_hash_path(&canister),
+        ]);
+
+        let status = |id: &[u8], field: &[u8]| {
+            witness
+                .lookup(&[b"request_status", id, field])
+                .map(|data| data.to_vec())
+        };
+        assert_eq!(status(&[3; 32], b"status"), Some(b"replied".to_vec()));
+        assert_eq!(status(&[3; 32], b"reply"), Some(vec![3]));
+        assert_eq!(status(&[20; 32], b"status"), Some(b"rejected".to_vec()));
+        assert_eq!(status(&[20; 32], b"reject_code"), Some(vec![4]));
+        assert_eq!(status(&[4; 32], b"status"), None);
+
+        assert_eq!(
+            witness.lookup(&[b"canister", canister.as_slice(), b"module_hash"]),
+            Some(&[8; 32][..])
+        );
+        assert_eq!(
+            witness.lookup(&[b"canister", canister.as_slice(), b"certified_data"]),
+            None
+        );
+        assert_eq!(witness.lookup(&[b"time"]), None);
+    }
+
+    #[test]
+    fn read_state_certificate() {
+        let tree = state_tree();
+        let certificate =
+            Certificate::decode(&read_state(&tree, &[request_status_path(&[5; 32])])).unwrap();
+
+        assert!(certificate.is_signed());
+        assert_eq!(certificate.tree.digest(), tree.hash_tree().digest());
+        assert_eq!(certificate.time(), Some(1_000));
+        assert_eq!(
+            certificate
+                .tree
+                .lookup(&[b"request_status", &[5; 32], b"reply"]),
+            Some(&[5][..])
+        );
+
+        // The certificate is the same once encoded and decoded.
+        assert_eq!(Certificate::decode(&certificate.encode()), Ok(certificate));
+    }
+
+    #[test]
+    fn verify_certified_query() {
+        let canister = Principal::from_slice(&[1; 10]);
+        let other = Principal::from_slice(&[2; 10]);
+        let witness = fork(labeled("x", leaf("hello")), labeled("y", leaf("world")));
+        let certificate = certificate(&canister, &witness.digest(), 1_234_567);
+
+        let verified = verify(&canister, &certificate, &witness.encode()).unwrap();
+        assert_eq!(verified, witness);
+        assert_eq!(verified.lookup(&[b"x"]), Some(&b"hello"[..]));
+        assert_eq!(
+            Certificate::decode(&certificate).unwrap().time(),
+            Some(1_234_567)
+        );
+
+        // A pruned witness has the same root hash.
+        let pruned = fork(
+            labeled("x", leaf("hello")),
+            HashTree::Pruned(labeled("y", leaf("world")).digest()),
+        );
+        assert!(verify(&canister, &certificate, &pruned.encode()).is_ok());
+
+        let other_witness = fork(labeled("x", leaf("bye")), labeled("y", leaf("world")));
+        assert_eq!(
+            verify(&canister, &certificate, &other_witness.encode()),
+            Err(format!(
+                "The witness does not match the certified data of canister '{}'.",
+                canister
+            ))
+        );
+        assert_eq!(
+            verify(&other, &certificate, &witness.encode()),
+            Err(format!(
+                "The certificate does not have the certified data of canister '{}'.",
+                other
+            ))
+        );
+
+        let mut forged = Certificate::decode(&certificate).unwrap();
+        forged.signature[0] ^= 1;
+        assert_eq!(
+            verify(&canister, &forged.encode(), &witness.encode()),
+            Err("The certificate is not signed by the replica.".to_string())
+        );
+    }
+
+    #[test]
+    fn leb128_encoding() {
+        assert_eq!(leb128(0), vec![0]);
+        assert_eq!(leb128(127), vec![0x7f]);
+        assert_eq!(leb128(624_485), vec![0xe5, 0x8e, 0x26]);
+    }
+}
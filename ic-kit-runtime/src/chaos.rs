@@ -0,0 +1,308 @@
+//! A chaos mode for the replica, which injects random faults in the execution of a test so the
+//! canisters are soak-tested against the conditions of a busy subnet:
+//!
+//! - Latency: a message is held back while a few of the messages received after it are delivered,
+//!   or until the replica has nothing else to do.
+//! - Transient rejects: a call made by a canister is rejected with `SysTransient`.
+//! - Restarts: a canister is upgraded to its own build before a call of the test, so its heap is
+//!   rebuilt by its `pre_upgrade` and `post_upgrade` hooks.
+//! - Clock jumps: the time of the replica moves forward before a message.
+//!
+//! All of the faults are drawn from a single seed, so a test whose messages are sent in the same
+//! order sees the same faults on every run. [`Chaos::soak`] runs a test with a new replica for
+//! each seed, and [`Chaos::rerun`] runs it again with the seed that made it fail, with the log of
+//! the calls and of the faults enabled, see [`crate::trace`].
+//!
+//! ```ignore
+//! let chaos = Chaos::new(0);
+//!
+//! chaos
+//!     .soak(100, |replica| async move {
+//!         let ledger = replica.add_canister(LedgerCanister::anonymous());
+//!         ledger.new_call("mint").with_args((alice, 100u64)).perform().await.assert_ok();
+//!         let balance = ledger.new_call("balance_of").with_arg(alice).perform().await;
+//!         assert_eq!(balance.decode_one::<u64>().unwrap(), 100);
+//!     })
+//!     .assert_ok();
+//! ```
+
+use std::fmt;
+use std::future::Future;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::time::Duration;
+
+use crate::replica::block_on_replica;
+use crate::Replica;
+
+/// Mixed in the seed of the faults drawn by the replica before the calls of the test, so they do
+/// not depend on the faults drawn for the messages.
+const RESTART_STREAM: u64 = 0x5245_5354_4152_5453;
+
+/// The faults injected by the chaos mode of a replica, see [`Replica::set_chaos`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct Chaos {
+    seed: u64,
+    latency: f64,
+    max_delay: u32,
+    transient_rejects: f64,
+    restarts: f64,
+    clock_jumps: f64,
+    max_clock_jump: Duration,
+}
+
+impl Chaos {
+    /// Create the chaos mode with the given seed, which delays 20% of the messages by up to 4
+    /// messages, rejects 5% of the calls made by the canisters, restarts the called canister
+    /// before 5% of the calls of the test, and moves the time forward by up to a minute before
+    /// 10% of the messages.
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            latency: 0.2,
+            max_delay: 4,
+            transient_rejects: 0.05,
+            restarts: 0.05,
+            clock_jumps: 0.1,
+            max_clock_jump: Duration::from_secs(60),
+        }
+    }
+
+    /// Use the given seed.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Set the probability that a message is held back, while up to `max_delay` of the messages
+    /// received after it are delivered.
+    pub fn with_latency(mut self, probability: f64, max_delay: u32) -> Self {
+        assert!(
+            max_delay > 0,
+            "ic-kit-runtime: The maximum delay of the messages must not be zero."
+        );
+        self.latency = check_probability(probability);
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Set the probability that a call made by a canister is rejected with `SysTransient`.
+    pub fn with_transient_rejects(mut self, probability: f64) -> Self {
+        self.transient_rejects = check_probability(probability);
+        self
+    }
+
+    /// Set the probability that the called canister is restarted before a call of the test.
+    pub fn with_restarts(mut self, probability: f64) -> Self {
+        self.restarts = check_probability(probability);
+        self
+    }
+
+    /// Set the probability that the time moves forward before a message, by up to `max_jump`.
+    pub fn with_clock_jumps(mut self, probability: f64, max_jump: Duration) -> Self {
+        assert!(
+            !max_jump.is_zero(),
+            "ic-kit-runtime: The maximum clock jump must not be zero."
+        );
+        self.clock_jumps = check_probability(probability);
+        self.max_clock_jump = max_jump;
+        self
+    }
+
+    /// Return the seed of the faults.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Run the test with a new replica in the chaos mode for each of the `runs` seeds starting
+    /// from the seed of this config, until the test panics.
+    pub fn soak<F, Fut>(&self, runs: u64, test: F) -> ChaosReport
+    where
+        F: Fn(Replica) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let mut report = ChaosReport::default();
+
+        for seed in (0..runs).map(|i| self.seed.wrapping_add(i)) {
+            let chaos = self.clone().with_seed(seed);
+
+            let result = catch_unwind(AssertUnwindSafe(|| {
+                block_on_replica(|replica| {
+                    replica.set_chaos(chaos);
+                    test(replica)
+                })
+            }));
+
+            report.runs += 1;
+
+            if let Err(payload) = result {
+                let message = payload
+                    .downcast_ref::<String>()
+                    .cloned()
+                    .or_else(|| payload.downcast_ref::<&str>().map(|s| s.to_string()))
+                    .unwrap_or_default();
+
+                report.failure = Some(ChaosFailure { seed, message });
+                break;
+            }
+        }
+
+        report
+    }
+
+    /// Run the test once with the given seed, such as the one reported by [`Chaos::soak`], with
+    /// the log of the calls and of the injected faults enabled. The timings are not logged, so the
+    /// log is the same on every run.
+    ///
+    /// # Panics
+    ///
+    /// If the test panics.
+    pub fn rerun<F, Fut>(&self, seed: u64, test: F)
+    where
+        F: FnOnce(Replica) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let chaos = self.clone().with_seed(seed);
+
+        block_on_replica(|replica| {
+            replica.log_calls(true);
+            replica.log_call_timings(false);
+            replica.set_chaos(chaos);
+            test(replica)
+        })
+    }
+}
+
+fn check_probability(probability: f64) -> f64 {
+    assert!(
+        (0.0..=1.0).contains(&probability),
+        "ic-kit-runtime: The probability {} is not between 0 and 1.",
+        probability
+    );
+    probability
+}
+
+/// The seed which made a test fail in the chaos mode.
+#[derive(Clone, Debug)]
+pub struct ChaosFailure {
+    /// The seed of the faults.
+    pub seed: u64,
+    /// The panic message of the test.
+    pub message: String,
+}
+
+/// The result of [`Chaos::soak`].
+#[derive(Clone, Debug, Default)]
+pub struct ChaosReport {
+    /// The number of seeds the test was run with.
+    pub runs: u64,
+    /// The first seed that made the test fail.
+    pub failure: Option<ChaosFailure>,
+}
+
+impl ChaosReport {
+    /// Returns true if the test passed with all of the seeds.
+    pub fn is_ok(&self) -> bool {
+        self.failure.is_none()
+    }
+
+    /// Assert the test passed with all of the seeds, the panic message shows the seed which made
+    /// it fail.
+    pub fn assert_ok(&self) {
+        assert!(self.is_ok(), "{}", self);
+    }
+}
+
+impl fmt::Display for ChaosReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Ran the test with {} seeds.", self.runs)?;
+
+        if let Some(failure) = &self.failure {
+            writeln!(
+                f,
+                "\nThe test failed with the seed {}: {}\n",
+                failure.seed, failure.message
+            )?;
+            writeln!(
+                f,
+                "Use `Chaos::rerun({}, ...)` to run it again with the log of the calls and of the faults.",
+                failure.seed
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The faults drawn for a replica in the chaos mode.
+pub(crate) struct ChaosState {
+    chaos: Chaos,
+    /// The state of the splitmix64 generator.
+    state: u64,
+}
+
+impl ChaosState {
+    /// Draw the faults of the messages delivered by the replica.
+    pub(crate) fn messages(chaos: Chaos) -> Self {
+        Self {
+            state: chaos.seed,
+            chaos,
+        }
+    }
+
+    /// Draw the restarts before the calls of the test, see [`Replica::set_chaos`].
+    pub(crate) fn restarts(chaos: Chaos) -> Self {
+        Self {
+            state: chaos.seed ^ RESTART_STREAM,
+            chaos,
+        }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        // Nothing is drawn for the faults which are disabled, so they do not change the others.
+        if probability <= 0.0 {
+            return false;
+        }
+
+        ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < probability
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    /// Return the number of messages the next message is held back for, if it's delayed.
+    pub(crate) fn delay(&mut self) -> Option<u32> {
+        if self.chance(self.chaos.latency) {
+            Some(1 + self.below(self.chaos.max_delay as u64) as u32)
+        } else {
+            None
+        }
+    }
+
+    /// Return true if the next call made by a canister is rejected.
+    pub(crate) fn transient_reject(&mut self) -> bool {
+        self.chance(self.chaos.transient_rejects)
+    }
+
+    /// Return true if the canister called by the next call of the test is restarted.
+    pub(crate) fn restart(&mut self) -> bool {
+        self.chance(self.chaos.restarts)
+    }
+
+    /// Return how far the time moves forward before the next message, in nanoseconds.
+    pub(crate) fn clock_jump(&mut self) -> Option<u64> {
+        if self.chance(self.chaos.clock_jumps) {
+            Some(1 + self.below(self.chaos.max_clock_jump.as_nanos() as u64))
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,384 @@
+//! Conformance tests, which check that the in-process replica executes a canister like the Internet
+//! Computer does. A [`Script`] is a sequence of calls to the canisters of a replica, which is run
+//! on PocketIC to record the replies of the wasm build of the canisters in a golden file, and then
+//! on the in-process replica, whose replies must match the recorded ones byte for byte.
+//!
+//! The golden files are stored in the `conformance` directory of the crate being tested, and are
+//! committed with the tests. They are only written by the runs on a backend, since they must come
+//! from the real execution: a missing golden file is recorded by the first run on PocketIC, and
+//! the existing ones are updated after an intended change by running the tests on PocketIC with
+//! the `IC_KIT_BLESS=1` environment variable. With `#[kit_test]`, the same test records the golden
+//! file when it runs with `IC_KIT_POCKET_IC=1` and checks the runtime otherwise:
+//!
+//! ```ignore
+//! #[kit_test]
+//! async fn counter_conformance(replica: Replica) {
+//!     let counter = replica.add_canister(CounterCanister::anonymous().with_wasm(COUNTER_WASM.to_vec()));
+//!     counter.init().await.assert_ok();
+//!
+//!     Script::new("counter")
+//!         .call(ScriptCall::new(counter.canister_id(), "increment"))
+//!         .call(ScriptCall::new(counter.canister_id(), "increment_by").with_arg(3u8))
+//!         .query(ScriptCall::new(counter.canister_id(), "get_counter").with_caller(*users::ALICE))
+//!         .assert_golden(&replica)
+//!         .await;
+//! }
+//! ```
+//!
+//! The replies and the refunded cycles are compared byte for byte, and the rejections by their
+//! code, since the messages of the Internet Computer have another wording, unless
+//! [`Script::with_reject_messages`] is used. The steps which are not a single call, such as a
+//! call made through a typed handle or several calls sent at once with [`CallBuilder::send`], are
+//! added with [`Script::step`].
+//!
+//! [`CallBuilder::send`]: crate::call::CallBuilder::send
+
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::{env, fs};
+
+use candid::utils::ArgumentEncoder;
+use candid::{encode_args, encode_one, CandidType, Principal};
+
+use ic_kit_sys::types::CANDID_EMPTY_ARG;
+
+use crate::call::CallReply;
+use crate::snapshot::BLESS_ENV_VAR;
+use crate::Replica;
+
+/// A step of a script which is not a single call, see [`Script::step`].
+pub type CustomStep<'a> =
+    Box<dyn Fn(&'a Replica) -> Pin<Box<dyn Future<Output = CallReply> + 'a>> + 'a>;
+
+/// A call made by a script, which does not depend on the replica it runs on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScriptCall {
+    canister_id: Principal,
+    method_name: String,
+    sender: Principal,
+    payment: u128,
+    arg: Vec<u8>,
+}
+
+impl ScriptCall {
+    /// Create a call to the given method of the canister, made by the anonymous principal with
+    /// no arguments.
+    pub fn new<S: Into<String>>(canister_id: Principal, method_name: S) -> Self {
+        Self {
+            canister_id,
+            method_name: method_name.into(),
+            sender: Principal::anonymous(),
+            payment: 0,
+            arg: CANDID_EMPTY_ARG.to_vec(),
+        }
+    }
+
+    /// Use the given candid tuple as the arguments of the call.
+    pub fn with_args<T: ArgumentEncoder>(mut self, arguments: T) -> Self {
+        self.arg = encode_args(arguments).expect("Failed to encode arguments.");
+        self
+    }
+
+    /// Use the given value as the only argument of the call.
+    pub fn with_arg<T: CandidType>(mut self, argument: T) -> Self {
+        self.arg = encode_one(argument).expect("Failed to encode argument.");
+        self
+    }
+
+    /// Use the given bytes as the arguments of the call, without encoding them.
+    pub fn with_arg_raw<A: Into<Vec<u8>>>(mut self, argument: A) -> Self {
+        self.arg = argument.into();
+        self
+    }
+
+    /// Send the given amount of cycles with the call.
+    pub fn with_payment(mut self, cycles: u128) -> Self {
+        self.payment = cycles;
+        self
+    }
+
+    /// Make the call from the given principal.
+    pub fn with_caller<I: Into<Principal>>(mut self, caller: I) -> Self {
+        self.sender = caller.into();
+        self
+    }
+
+    /// Describe the call with the text of the principals, which is the same on all of the
+    /// replicas, unlike their names.
+    fn describe(&self) -> String {
+        format!(
+            "{} -> {}.{}",
+            self.sender, self.canister_id, self.method_name
+        )
+    }
+
+    async fn perform(&self, replica: &Replica, query: bool) -> CallReply {
+        let call = replica
+            .new_call(self.canister_id, self.method_name.clone())
+            .with_caller(self.sender)
+            .with_payment128(self.payment)
+            .with_arg_raw(self.arg.clone());
+
+        if query {
+            call.perform_query().await
+        } else {
+            call.perform().await
+        }
+    }
+}
+
+enum Step<'a> {
+    Call(ScriptCall),
+    Query(ScriptCall),
+    SetTime(u64),
+    Custom(CustomStep<'a>),
+}
+
+/// A sequence of calls whose replies are compared between the replicas, see
+/// [`crate::conformance`].
+pub struct Script<'a> {
+    name: String,
+    steps: Vec<(String, Step<'a>)>,
+    reject_messages: bool,
+}
+
+impl<'a> Script<'a> {
+    /// Create an empty script, whose golden file is stored under the given name.
+    pub fn new<S: Into<String>>(name: S) -> Self {
+        Self {
+            name: name.into(),
+            steps: Vec::new(),
+            reject_messages: false,
+        }
+    }
+
+    /// Perform the call as an update call.
+    pub fn call(mut self, call: ScriptCall) -> Self {
+        self.steps.push((call.describe(), Step::Call(call)));
+        self
+    }
+
+    /// Perform the call as a query, see [`CallBuilder::perform_query`].
+    ///
+    /// [`CallBuilder::perform_query`]: crate::call::CallBuilder::perform_query
+    pub fn query(mut self, call: ScriptCall) -> Self {
+        let description = format!("query {}", call.describe());
+        self.steps.push((description, Step::Query(call)));
+        self
+    }
+
+    /// Set the time of the replica, in nanoseconds since the epoch, so the replies which depend on
+    /// the time are the same on all of the replicas.
+    pub fn set_time(mut self, time: u64) -> Self {
+        self.steps
+            .push((format!("set the time to {}", time), Step::SetTime(time)));
+        self
+    }
+
+    /// Run the given function, and compare the reply it returns like the reply of a call.
+    ///
+    /// ```ignore
+    /// script.step("transfer with the typed handle", |replica| {
+    ///     Box::pin(async move {
+    ///         LedgerCanisterHandle::new(replica, ledger_id).transfer_raw(bob, 10).await
+    ///     })
+    /// })
+    /// ```
+    pub fn step<S, F>(mut self, description: S, step: F) -> Self
+    where
+        S: Into<String>,
+        F: Fn(&'a Replica) -> Pin<Box<dyn Future<Output = CallReply> + 'a>> + 'a,
+    {
+        self.steps
+            .push((description.into(), Step::Custom(Box::new(step))));
+        self
+    }
+
+    /// Also compare the messages of the rejections, which are otherwise compared by their code.
+    pub fn with_reject_messages(mut self) -> Self {
+        self.reject_messages = true;
+        self
+    }
+
+    /// Run the steps on the replica, one after the other, and return the replies.
+    pub async fn run(&self, replica: &'a Replica) -> Transcript {
+        let mut steps = Vec::with_capacity(self.steps.len());
+
+        for (description, step) in &self.steps {
+            let reply = match step {
+                Step::Call(call) => call.perform(replica, false).await,
+                Step::Query(call) => call.perform(replica, true).await,
+                Step::SetTime(time) => {
+                    replica.set_time(*time);
+                    continue;
+                }
+                Step::Custom(step) => step(replica).await,
+            };
+
+            steps.push((description.clone(), reply));
+        }
+
+        Transcript { steps }
+    }
+
+    /// Run the script on both of the replicas, usually one running on PocketIC and an in-process
+    /// one, and return the first step whose reply is not the same.
+    pub async fn compare(
+        &self,
+        reference: &'a Replica,
+        replica: &'a Replica,
+    ) -> Result<(), String> {
+        let expected = self.run(reference).await.render();
+        let actual = self.run(replica).await.render();
+        compare(&expected, &actual, self.reject_messages)
+    }
+
+    /// Run the script and compare its replies to the golden file of the script, which is recorded
+    /// if the replica runs on a backend such as PocketIC and the file is missing, or if the
+    /// `IC_KIT_BLESS` environment variable is set.
+    ///
+    /// # Panics
+    ///
+    /// If a reply does not match the golden file, or if the golden file is missing on the
+    /// in-process replica, or if it can not be read or written.
+    pub async fn assert_golden(&self, replica: &'a Replica) {
+        let path = golden_path(&self.name);
+        let actual = self.run(replica).await.render();
+        let record = replica.backend().is_some();
+
+        let expected = match fs::read_to_string(&path) {
+            Ok(_) if record && env::var_os(BLESS_ENV_VAR).is_some() => {
+                return write(&path, &actual)
+            }
+            Ok(expected) => expected,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && record => {
+                return write(&path, &actual)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => panic!(
+                "The golden file of script '{}' is missing, record it by running the test on PocketIC with IC_KIT_POCKET_IC=1.",
+                self.name
+            ),
+            Err(e) => panic!(
+                "Could not read the golden file '{}': {}",
+                path.display(),
+                e
+            ),
+        };
+
+        if let Err(e) = compare(&expected, &actual, self.reject_messages) {
+            panic!(
+                "The replies of script '{}' do not match its golden file.\n\n{}",
+                self.name, e
+            );
+        }
+    }
+}
+
+/// The replies to the steps of a script.
+pub struct Transcript {
+    steps: Vec<(String, CallReply)>,
+}
+
+impl Transcript {
+    /// Return the description and the reply of each step.
+    pub fn steps(&self) -> &[(String, CallReply)] {
+        &self.steps
+    }
+
+    /// Render the replies as the text of a golden file, a reply is rendered as the hex of its
+    /// bytes, and a rejection as its code and its message on the next line.
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+
+        for (index, (description, reply)) in self.steps.iter().enumerate() {
+            text.push_str(&format!("step {}: {}\n", index + 1, description));
+
+            match reply {
+                CallReply::Reply { data, .. } => {
+                    text.push_str(&format!("  reply {}\n", hex(data)));
+                }
+                CallReply::Reject {
+                    rejection_code,
+                    rejection_message,
+                    ..
+                } => {
+                    text.push_str(&format!("  reject {:?}\n", rejection_code));
+                    text.push_str(&format!("  message {:?}\n", rejection_message));
+                }
+            }
+
+            if reply.cycles_refunded() > 0 {
+                text.push_str(&format!("  refunded {}\n", reply.cycles_refunded()));
+            }
+        }
+
+        text
+    }
+}
+
+/// Compare the rendered transcripts, and describe the first step which is not the same.
+fn compare(expected: &str, actual: &str, reject_messages: bool) -> Result<(), String> {
+    let lines = |text: &'_ str| {
+        text.lines()
+            .filter(|line| reject_messages || !line.starts_with("  message "))
+            .map(str::to_string)
+            .collect::<Vec<_>>()
+    };
+
+    let (expected, actual) = (lines(expected), lines(actual));
+    let len = expected.len().max(actual.len());
+
+    for i in 0..len {
+        let (e, a) = (expected.get(i), actual.get(i));
+        if e == a {
+            continue;
+        }
+
+        let step = expected[..i.min(expected.len())]
+            .iter()
+            .rev()
+            .find(|line| line.starts_with("step "))
+            .map(String::as_str)
+            .unwrap_or("the start of the script");
+
+        return Err(format!(
+            "At {}\n\nExpected:\n{}\n\nActual:\n{}",
+            step,
+            e.map(String::as_str).unwrap_or("the end of the script"),
+            a.map(String::as_str).unwrap_or("the end of the script")
+        ));
+    }
+
+    Ok(())
+}
+
+/// Return the path of the golden file of the script with the given name, in the directory of the
+/// crate which is being tested.
+fn golden_path(name: &str) -> PathBuf {
+    let dir = env::var_os("CARGO_MANIFEST_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("."));
+
+    dir.join("conformance").join(format!("{}.golden", name))
+}
+
+fn write(path: &Path, text: &str) {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).unwrap_or_else(|e| {
+            panic!("Could not create the directory '{}': {}", dir.display(), e)
+        });
+    }
+
+    fs::write(path, text).unwrap_or_else(|e| {
+        panic!(
+            "Could not write the golden file '{}': {}",
+            path.display(),
+            e
+        )
+    });
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,1303 @@
+//! A synchronous mock of the system API for the unit tests which do not need a replica, such as
+//! the tests of a function which reads the caller or makes a call to another canister.
+//!
+//! Once injected, the system API calls made on the current thread, such as `ic::caller()` or
+//! `ic::balance()`, are handled by the context. The calls to other canisters are answered with the
+//! configured replies and recorded by the [`CallWatcher`], and the futures are executed with
+//! [`MockContext::block_on`]:
+//!
+//! ```ignore
+//! let ctx = MockContext::new()
+//!     .with_caller(users::ALICE.clone())
+//!     .with_reply(ledger_id, "transfer", (Ok::<u64, String>(12),))
+//!     .inject();
+//! let watcher = ctx.watcher();
+//!
+//! assert_eq!(ctx.block_on(withdraw(100)), Ok(12));
+//! assert!(watcher.is_method_called("transfer"));
+//! ```
+//!
+//! The replies can also be expected with the name of the canister, and checked at the end of the
+//! test:
+//!
+//! ```ignore
+//! let ctx = MockContext::new()
+//!     .with_name(ledger_id, "ledger")
+//!     .expect_call("ledger", "transfer")
+//!     .return_ok(Ok::<u64, String>(12))
+//!     .inject();
+//!
+//! ctx.block_on(withdraw(100)).unwrap();
+//! ctx.assert_expected_calls();
+//! ```
+//!
+//! The context is shared with the system API, so it can be changed between the calls of a test:
+//!
+//! ```ignore
+//! let ctx = ctx.with_caller(users::BOB.clone());
+//! ```
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use candid::utils::{ArgumentDecoder, ArgumentEncoder};
+use candid::{decode_args, decode_one, encode_args, encode_one, CandidType, Principal};
+use futures::task::noop_waker;
+use serde::de::DeserializeOwned;
+
+use ic_kit_sys::ic0;
+use ic_kit_sys::ic0::Ic0CallHandler;
+use ic_kit_sys::types::RejectionCode;
+
+use crate::call::CallReply;
+use crate::canister::DEFAULT_BALANCE;
+use crate::certification;
+use crate::replica::canister_id;
+use crate::stable::{HeapStableMemory, StableMemoryBackend};
+use crate::types::now;
+
+/// A mock of the system API, see the [module documentation](self).
+#[derive(Clone)]
+pub struct MockContext {
+    state: Rc<RefCell<ContextState>>,
+}
+
+/// A handle to the calls made to the other canisters through a [`MockContext`].
+#[derive(Clone)]
+pub struct CallWatcher {
+    state: Rc<RefCell<ContextState>>,
+}
+
+/// The reply to the calls to a method of another canister, created by
+/// [`MockContext::expect_call`].
+#[must_use = "The reply is only configured once one of the methods is called."]
+pub struct CallExpectation {
+    context: MockContext,
+    callee: Principal,
+    method: String,
+}
+
+/// A call made to another canister.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WatchedCall {
+    /// The canister which is called.
+    pub callee: Principal,
+    /// The name of the method.
+    pub method: String,
+    /// The candid encoded arguments of the call.
+    pub arg: Vec<u8>,
+    /// The cycles sent with the call.
+    pub cycles: u128,
+}
+
+/// Return the candid encoded reply to the call, or the message to reject it with.
+type ContextHandler = dyn Fn(&WatchedCall) -> Result<Vec<u8>, String>;
+
+#[derive(Clone)]
+enum ContextReply {
+    Reply(Vec<u8>),
+    Reject(String),
+    Handler(Rc<ContextHandler>),
+}
+
+struct PendingCall {
+    call: WatchedCall,
+    reply: (isize, isize),
+    reject: (isize, isize),
+}
+
+struct PendingCallback {
+    fun: isize,
+    env: isize,
+    data: Vec<u8>,
+    rejection: Option<(RejectionCode, String)>,
+    cycles_refunded: u128,
+}
+
+struct ContextState {
+    id: Principal,
+    caller: Principal,
+    time: u64,
+    global_timer: u64,
+    balance: u128,
+    controllers: Vec<Principal>,
+    method_name: Option<String>,
+    args: Vec<u8>,
+    cycles_available: u128,
+    cycles_refunded: u128,
+    rejection: Option<(RejectionCode, String)>,
+    reply_data: Vec<u8>,
+    reply: Option<CallReply>,
+    stable: HeapStableMemory,
+    certified_data: Vec<u8>,
+    certificate: Option<Vec<u8>>,
+    names: HashMap<String, Principal>,
+    replies: HashMap<(Principal, String), ContextReply>,
+    expected: Vec<(Principal, String)>,
+    pending_call: Option<PendingCall>,
+    callbacks: VecDeque<PendingCallback>,
+    calls: Vec<WatchedCall>,
+}
+
+/// The handler registered for the thread, which shares the state of the context.
+struct ContextHandle(Rc<RefCell<ContextState>>);
+
+impl Default for MockContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockContext {
+    /// Create a context for the canister with the first id of a replica, called by the anonymous
+    /// principal at the current time.
+    pub fn new() -> Self {
+        let state = ContextState {
+            id: canister_id(0),
+            caller: Principal::anonymous(),
+            time: now(),
+            global_timer: 0,
+            balance: DEFAULT_BALANCE,
+            controllers: Vec::new(),
+            method_name: None,
+            args: encode_args(()).unwrap(),
+            cycles_available: 0,
+            cycles_refunded: 0,
+            rejection: None,
+            reply_data: Vec::new(),
+            reply: None,
+            stable: HeapStableMemory::default(),
+            certified_data: Vec::new(),
+            certificate: None,
+            names: HashMap::new(),
+            replies: HashMap::new(),
+            expected: Vec::new(),
+            pending_call: None,
+            callbacks: VecDeque::new(),
+            calls: Vec::new(),
+        };
+
+        Self {
+            state: Rc::new(RefCell::new(state)),
+        }
+    }
+
+    /// Handle the system API calls made on the current thread with this context, which replaces
+    /// any context injected before.
+    pub fn inject(self) -> Self {
+        ic0::register_handler(ContextHandle(self.state.clone()));
+        self
+    }
+
+    /// Use the given id for the canister.
+    pub fn with_id<T: Into<Principal>>(self, id: T) -> Self {
+        self.state.borrow_mut().id = id.into();
+        self
+    }
+
+    /// Make the calls from the given principal.
+    pub fn with_caller<T: Into<Principal>>(self, caller: T) -> Self {
+        self.state.borrow_mut().caller = caller.into();
+        self
+    }
+
+    /// Set the time of the IC, in nanoseconds since the epoch.
+    pub fn with_time(self, time: u64) -> Self {
+        self.state.borrow_mut().time = time;
+        self
+    }
+
+    /// Set the cycle balance of the canister.
+    pub fn with_balance(self, balance: u128) -> Self {
+        self.state.borrow_mut().balance = balance;
+        self
+    }
+
+    /// Add the given principal to the controllers of the canister.
+    pub fn with_controller<T: Into<Principal>>(self, controller: T) -> Self {
+        self.state.borrow_mut().controllers.push(controller.into());
+        self
+    }
+
+    /// Set the name of the method being called, as it's returned to the `inspect_message` hook.
+    pub fn with_method_name<S: Into<String>>(self, method_name: S) -> Self {
+        self.state.borrow_mut().method_name = Some(method_name.into());
+        self
+    }
+
+    /// Use the given candid tuple value as the arguments of the message.
+    pub fn with_args<T: ArgumentEncoder>(self, arguments: T) -> Self {
+        self.state.borrow_mut().args =
+            encode_args(arguments).expect("ic-kit-runtime: Could not encode the arguments.");
+        self
+    }
+
+    /// Use the given value as the only argument of the message.
+    pub fn with_arg<T: CandidType>(self, argument: T) -> Self {
+        self.state.borrow_mut().args =
+            encode_one(argument).expect("ic-kit-runtime: Could not encode the argument.");
+        self
+    }
+
+    /// Set the cycles sent with the message, which can be accepted by the canister.
+    pub fn with_payment(self, cycles: u128) -> Self {
+        self.state.borrow_mut().cycles_available = cycles;
+        self
+    }
+
+    /// Set the certificate of the certified data, which is returned to the queries.
+    pub fn with_certificate<T: Into<Vec<u8>>>(self, certificate: T) -> Self {
+        self.state.borrow_mut().certificate = Some(certificate.into());
+        self
+    }
+
+    /// Reply to the calls to the method of the given canister with the given values. The cycles
+    /// sent with these calls are accepted.
+    pub fn with_reply<T: ArgumentEncoder>(
+        self,
+        canister_id: Principal,
+        method: &str,
+        reply: T,
+    ) -> Self {
+        let bytes = encode_args(reply).expect("ic-kit-runtime: Could not encode the reply.");
+        self.set_reply(canister_id, method, ContextReply::Reply(bytes))
+    }
+
+    /// Reject the calls to the method of the given canister with the given message. The cycles
+    /// sent with these calls are refunded.
+    pub fn with_reject<S: Into<String>>(
+        self,
+        canister_id: Principal,
+        method: &str,
+        message: S,
+    ) -> Self {
+        self.set_reply(canister_id, method, ContextReply::Reject(message.into()))
+    }
+
+    /// Reply to the calls to the method of the given canister with the result of the function,
+    /// which returns the candid encoded reply or the message to reject the call with.
+    pub fn with_handler<F>(self, canister_id: Principal, method: &str, handler: F) -> Self
+    where
+        F: Fn(&WatchedCall) -> Result<Vec<u8>, String> + 'static,
+    {
+        self.set_reply(canister_id, method, ContextReply::Handler(Rc::new(handler)))
+    }
+
+    fn set_reply(self, canister_id: Principal, method: &str, reply: ContextReply) -> Self {
+        self.state
+            .borrow_mut()
+            .replies
+            .insert((canister_id, method.to_string()), reply);
+        self
+    }
+
+    /// Give a name to the canister with the given id, so its calls can be expected with the name.
+    pub fn with_name<S: Into<String>>(self, id: Principal, name: S) -> Self {
+        self.state.borrow_mut().names.insert(name.into(), id);
+        self
+    }
+
+    /// Configure the reply to the calls to the method of the given canister, which is either a
+    /// name given with [`MockContext::with_name`] or the textual form of its id. The call is
+    /// expected, see [`MockContext::assert_expected_calls`].
+    ///
+    /// ```ignore
+    /// let ctx = MockContext::new()
+    ///     .with_name(ledger_id, "ledger")
+    ///     .expect_call("ledger", "transfer")
+    ///     .return_ok(Ok::<u64, String>(12))
+    ///     .inject();
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// If the canister is neither a name nor a principal.
+    pub fn expect_call(&self, canister: &str, method: &str) -> CallExpectation {
+        let name = self.state.borrow().names.get(canister).copied();
+        let callee = name
+            .or_else(|| Principal::from_text(canister).ok())
+            .unwrap_or_else(|| {
+                panic!(
+                    "ic-kit-runtime: '{}' is neither the name of a canister nor a principal.",
+                    canister
+                )
+            });
+
+        CallExpectation {
+            context: self.clone(),
+            callee,
+            method: method.to_string(),
+        }
+    }
+
+    /// Check that each of the calls configured with [`MockContext::expect_call`] was made at
+    /// least once.
+    ///
+    /// # Panics
+    ///
+    /// If one of the expected calls was not made.
+    pub fn assert_expected_calls(&self) {
+        let state = self.state.borrow();
+        let missing = state
+            .expected
+            .iter()
+            .filter(|(callee, method)| {
+                !state
+                    .calls
+                    .iter()
+                    .any(|call| &call.callee == callee && &call.method == method)
+            })
+            .map(|(callee, method)| format!("'{}' of {}", method, state.name_of(callee)))
+            .collect::<Vec<_>>();
+
+        if !missing.is_empty() {
+            panic!(
+                "ic-kit-runtime: The expected calls to {} were not made.",
+                missing.join(", ")
+            );
+        }
+    }
+
+    /// Return a handle to the calls made by the canister.
+    pub fn watcher(&self) -> CallWatcher {
+        CallWatcher {
+            state: self.state.clone(),
+        }
+    }
+
+    /// Return the cycle balance of the canister.
+    pub fn balance(&self) -> u128 {
+        self.state.borrow().balance
+    }
+
+    /// Return the cycles sent with the message which are not accepted yet.
+    pub fn cycles_available(&self) -> u128 {
+        self.state.borrow().cycles_available
+    }
+
+    /// Return the time the global timer of the canister is set to, or zero if it's not set.
+    pub fn global_timer(&self) -> u64 {
+        self.state.borrow().global_timer
+    }
+
+    /// Return the data certified by the canister.
+    pub fn certified_data(&self) -> Vec<u8> {
+        self.state.borrow().certified_data.clone()
+    }
+
+    /// Set the certificate to one of the data certified by the canister so far, signed like the
+    /// certificates of the replica, so the certified queries can be verified with
+    /// [`certification::verify`].
+    pub fn certify(&self) {
+        let mut state = self.state.borrow_mut();
+        state.certificate = Some(certification::certificate(
+            &state.id,
+            &state.certified_data,
+            state.time,
+        ));
+    }
+
+    /// Take the reply or the rejection sent by the canister to the message, so the next message
+    /// can be replied to.
+    pub fn take_reply(&self) -> Option<CallReply> {
+        let mut state = self.state.borrow_mut();
+        state.reply_data.clear();
+        state.reply.take()
+    }
+
+    /// Execute the future on the current thread, the calls it makes to the other canisters are
+    /// answered in the order they were made.
+    ///
+    /// # Panics
+    ///
+    /// If the future waits for something other than a call to another canister.
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        let waker = noop_waker();
+        let mut context = Context::from_waker(&waker);
+        let mut future = Box::pin(future);
+
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+                return output;
+            }
+
+            if !self.resolve_next_call() {
+                panic!("ic-kit-runtime: The future is waiting for something other than a call.");
+            }
+        }
+    }
+
+    /// Answer the calls which are made by the canister and not answered yet, such as the calls of
+    /// the futures passed to `ic::spawn`.
+    pub fn resolve_calls(&self) {
+        while self.resolve_next_call() {}
+    }
+
+    /// Execute the callback of the oldest call which is not answered yet, with the reply as the
+    /// current message. Returns false if there are no such calls.
+    fn resolve_next_call(&self) -> bool {
+        let callback = {
+            let mut state = self.state.borrow_mut();
+            let callback = match state.callbacks.pop_front() {
+                Some(callback) => callback,
+                None => return false,
+            };
+
+            state.args = callback.data.clone();
+            state.rejection = callback.rejection.clone();
+            state.cycles_refunded = callback.cycles_refunded;
+            state.balance += callback.cycles_refunded;
+            callback
+        };
+
+        // The state is not borrowed here, since the callback runs the code of the canister.
+        if callback.fun != -1 {
+            unsafe {
+                let fun = std::mem::transmute::<isize, fn(isize)>(callback.fun);
+                fun(callback.env);
+            }
+        }
+
+        true
+    }
+}
+
+impl CallExpectation {
+    /// Reply to the calls with the given value.
+    pub fn return_ok<T: CandidType>(self, value: T) -> MockContext {
+        let bytes = encode_one(value).expect("ic-kit-runtime: Could not encode the reply.");
+        self.respond(ContextReply::Reply(bytes))
+    }
+
+    /// Reply to the calls with the given candid tuple value.
+    pub fn return_args<T: ArgumentEncoder>(self, values: T) -> MockContext {
+        let bytes = encode_args(values).expect("ic-kit-runtime: Could not encode the reply.");
+        self.respond(ContextReply::Reply(bytes))
+    }
+
+    /// Reply to the calls with the given candid encoded bytes.
+    pub fn return_raw<T: Into<Vec<u8>>>(self, bytes: T) -> MockContext {
+        self.respond(ContextReply::Reply(bytes.into()))
+    }
+
+    /// Reject the calls with the given message.
+    pub fn reject<S: Into<String>>(self, message: S) -> MockContext {
+        self.respond(ContextReply::Reject(message.into()))
+    }
+
+    /// Reply to the calls with the result of the function, which returns the candid encoded reply
+    /// or the message to reject the call with.
+    pub fn respond_with<F>(self, handler: F) -> MockContext
+    where
+        F: Fn(&WatchedCall) -> Result<Vec<u8>, String> + 'static,
+    {
+        self.respond(ContextReply::Handler(Rc::new(handler)))
+    }
+
+    fn respond(self, reply: ContextReply) -> MockContext {
+        let expected = (self.callee, self.method.clone());
+        {
+            let mut state = self.context.state.borrow_mut();
+            if !state.expected.contains(&expected) {
+                state.expected.push(expected);
+            }
+        }
+
+        self.context.set_reply(self.callee, &self.method, reply)
+    }
+}
+
+impl CallWatcher {
+    /// Return all of the calls made by the canister, in order.
+    pub fn calls(&self) -> Vec<WatchedCall> {
+        self.state.borrow().calls.clone()
+    }
+
+    /// Return the calls made to the given method of any canister, in order.
+    pub fn calls_to(&self, method: &str) -> Vec<WatchedCall> {
+        self.state
+            .borrow()
+            .calls
+            .iter()
+            .filter(|call| call.method == method)
+            .cloned()
+            .collect()
+    }
+
+    /// Return the number of calls made by the canister.
+    pub fn call_count(&self) -> usize {
+        self.state.borrow().calls.len()
+    }
+
+    /// Return true if the given method of any canister was called.
+    pub fn is_method_called(&self, method: &str) -> bool {
+        self.state
+            .borrow()
+            .calls
+            .iter()
+            .any(|call| call.method == method)
+    }
+
+    /// Return true if any method of the given canister was called.
+    pub fn is_canister_called(&self, canister_id: &Principal) -> bool {
+        self.state
+            .borrow()
+            .calls
+            .iter()
+            .any(|call| &call.callee == canister_id)
+    }
+
+    /// Return the total amount of cycles sent with the calls.
+    pub fn cycles_sent(&self) -> u128 {
+        self.state
+            .borrow()
+            .calls
+            .iter()
+            .map(|call| call.cycles)
+            .sum()
+    }
+
+    /// Forget the calls recorded so far.
+    pub fn clear(&self) {
+        self.state.borrow_mut().calls.clear();
+    }
+}
+
+impl WatchedCall {
+    /// Decode the arguments of the call.
+    pub fn decode<T: for<'a> ArgumentDecoder<'a>>(&self) -> candid::Result<T> {
+        decode_args(&self.arg)
+    }
+
+    /// Decode the single argument of the call.
+    pub fn decode_one<T>(&self) -> candid::Result<T>
+    where
+        T: DeserializeOwned + CandidType,
+    {
+        decode_one(&self.arg)
+    }
+}
+
+impl ContextState {
+    /// Return the name given to the canister, or the textual form of its id.
+    fn name_of(&self, id: &Principal) -> String {
+        self.names
+            .iter()
+            .find(|(_, other)| *other == id)
+            .map(|(name, _)| name.clone())
+            .unwrap_or_else(|| id.to_text())
+    }
+
+    fn pending_call(&mut self, name: &str) -> &mut PendingCall {
+        match self.pending_call.as_mut() {
+            Some(call) => call,
+            None => trap(format!(
+                "{} cannot be called when there is no pending call.",
+                name
+            )),
+        }
+    }
+
+    fn set_reply(&mut self, reply: CallReply) {
+        if self.reply.is_some() {
+            trap("Current call is already replied to.".to_string());
+        }
+
+        self.reply = Some(reply);
+    }
+
+    fn discard_pending_call(&mut self) {
+        if let Some(call) = self.pending_call.take() {
+            self.balance += call.call.cycles;
+        }
+    }
+}
+
+/// The system API can not return an error, so a trap panics with its message, which can be
+/// expected with `#[should_panic]`.
+fn trap(message: String) -> ! {
+    panic!("Canister trapped: {}", message)
+}
+
+impl Ic0CallHandler for ContextHandle {
+    fn msg_arg_data_size(&mut self) -> isize {
+        self.0.borrow().args.len() as isize
+    }
+
+    fn msg_arg_data_copy(&mut self, dst: isize, offset: isize, size: isize) {
+        copy_to_canister(dst, offset, size, &self.0.borrow().args);
+    }
+
+    fn msg_caller_size(&mut self) -> isize {
+        self.0.borrow().caller.as_slice().len() as isize
+    }
+
+    fn msg_caller_copy(&mut self, dst: isize, offset: isize, size: isize) {
+        copy_to_canister(dst, offset, size, self.0.borrow().caller.as_slice());
+    }
+
+    fn msg_reject_code(&mut self) -> i32 {
+        match &self.0.borrow().rejection {
+            Some((code, _)) => *code as i32,
+            None => 0,
+        }
+    }
+
+    fn msg_reject_msg_size(&mut self) -> isize {
+        match &self.0.borrow().rejection {
+            Some((_, message)) => message.len() as isize,
+            None => trap("msg_reject_msg_size can only be called in a reject callback.".into()),
+        }
+    }
+
+    fn msg_reject_msg_copy(&mut self, dst: isize, offset: isize, size: isize) {
+        match &self.0.borrow().rejection {
+            Some((_, message)) => copy_to_canister(dst, offset, size, message.as_bytes()),
+            None => trap("msg_reject_msg_copy can only be called in a reject callback.".into()),
+        }
+    }
+
+    fn msg_reply_data_append(&mut self, src: isize, size: isize) {
+        let mut state = self.0.borrow_mut();
+
+        if state.reply.is_some() {
+            trap("msg_reply_data_append may only be invoked before canister responses.".into());
+        }
+
+        state
+            .reply_data
+            .extend_from_slice(copy_from_canister(src, size));
+    }
+
+    fn msg_reply(&mut self) {
+        let mut state = self.0.borrow_mut();
+        let data = std::mem::take(&mut state.reply_data);
+        let cycles_refunded = std::mem::take(&mut state.cycles_available);
+
+        state.set_reply(CallReply::Reply {
+            data,
+            cycles_refunded,
+        });
+    }
+
+    fn msg_reject(&mut self, src: isize, size: isize) {
+        let mut state = self.0.borrow_mut();
+        let rejection_message = String::from_utf8_lossy(copy_from_canister(src, size)).into();
+        let cycles_refunded = std::mem::take(&mut state.cycles_available);
+
+        state.reply_data.clear();
+        state.set_reply(CallReply::Reject {
+            rejection_code: RejectionCode::CanisterReject,
+            rejection_message,
+            cycles_refunded,
+        });
+    }
+
+    fn msg_cycles_available(&mut self) -> i64 {
+        self.0.borrow().cycles_available as u64 as i64
+    }
+
+    fn msg_cycles_available128(&mut self, dst: isize) {
+        let data = self.0.borrow().cycles_available.to_le_bytes();
+        copy_to_canister(dst, 0, 16, &data);
+    }
+
+    fn msg_cycles_refunded(&mut self) -> i64 {
+        self.0.borrow().cycles_refunded as u64 as i64
+    }
+
+    fn msg_cycles_refunded128(&mut self, dst: isize) {
+        let data = self.0.borrow().cycles_refunded.to_le_bytes();
+        copy_to_canister(dst, 0, 16, &data);
+    }
+
+    fn msg_cycles_accept(&mut self, max_amount: i64) -> i64 {
+        let mut state = self.0.borrow_mut();
+        let amount = state.cycles_available.min(max_amount as u64 as u128);
+        state.cycles_available -= amount;
+        state.balance += amount;
+        amount as i64
+    }
+
+    fn msg_cycles_accept128(&mut self, max_amount_high: i64, max_amount_low: i64, dst: isize) {
+        let mut state = self.0.borrow_mut();
+        let max_amount = ((max_amount_high as u64 as u128) << 64) + max_amount_low as u64 as u128;
+        let amount = state.cycles_available.min(max_amount);
+        state.cycles_available -= amount;
+        state.balance += amount;
+        copy_to_canister(dst, 0, 16, &amount.to_le_bytes());
+    }
+
+    fn canister_self_size(&mut self) -> isize {
+        self.0.borrow().id.as_slice().len() as isize
+    }
+
+    fn canister_self_copy(&mut self, dst: isize, offset: isize, size: isize) {
+        copy_to_canister(dst, offset, size, self.0.borrow().id.as_slice());
+    }
+
+    fn canister_cycle_balance(&mut self) -> i64 {
+        self.0.borrow().balance as u64 as i64
+    }
+
+    fn canister_cycle_balance128(&mut self, dst: isize) {
+        let data = self.0.borrow().balance.to_le_bytes();
+        copy_to_canister(dst, 0, 16, &data);
+    }
+
+    fn canister_status(&mut self) -> i32 {
+        1
+    }
+
+    fn is_controller(&mut self, src: isize, size: isize) -> i32 {
+        let principal = Principal::from_slice(copy_from_canister(src, size));
+        self.0.borrow().controllers.contains(&principal) as i32
+    }
+
+    fn msg_method_name_size(&mut self) -> isize {
+        match &self.0.borrow().method_name {
+            Some(name) => name.len() as isize,
+            None => trap("The method name is not set on the context.".into()),
+        }
+    }
+
+    fn msg_method_name_copy(&mut self, dst: isize, offset: isize, size: isize) {
+        match &self.0.borrow().method_name {
+            Some(name) => copy_to_canister(dst, offset, size, name.as_bytes()),
+            None => trap("The method name is not set on the context.".into()),
+        }
+    }
+
+    fn accept_message(&mut self) {}
+
+    fn call_new(
+        &mut self,
+        callee_src: isize,
+        callee_size: isize,
+        name_src: isize,
+        name_size: isize,
+        reply_fun: isize,
+        reply_env: isize,
+        reject_fun: isize,
+        reject_env: isize,
+    ) {
+        let mut state = self.0.borrow_mut();
+        state.discard_pending_call();
+
+        let callee = Principal::from_slice(copy_from_canister(callee_src, callee_size));
+        let method = String::from_utf8_lossy(copy_from_canister(name_src, name_size)).into();
+
+        state.pending_call = Some(PendingCall {
+            call: WatchedCall {
+                callee,
+                method,
+                arg: Vec::new(),
+                cycles: 0,
+            },
+            reply: (reply_fun, reply_env),
+            reject: (reject_fun, reject_env),
+        });
+    }
+
+    fn call_on_cleanup(&mut self, _fun: isize, _env: isize) {
+        // The callbacks of the context never trap on their own, so there is nothing to clean.
+        self.0.borrow_mut().pending_call("call_on_cleanup");
+    }
+
+    fn call_data_append(&mut self, src: isize, size: isize) {
+        let mut state = self.0.borrow_mut();
+        let call = state.pending_call("call_data_append");
+        call.call
+            .arg
+            .extend_from_slice(copy_from_canister(src, size));
+    }
+
+    fn call_cycles_add(&mut self, amount: i64) {
+        self.call_cycles_add128(0, amount);
+    }
+
+    fn call_cycles_add128(&mut self, amount_high: i64, amount_low: i64) {
+        let mut state = self.0.borrow_mut();
+        let amount = ((amount_high as u64 as u128) << 64) + amount_low as u64 as u128;
+
+        state.pending_call("call_cycles_add");
+
+        if state.balance < amount {
+            trap("Insufficient cycles balance.".into());
+        }
+
+        state.balance -= amount;
+        state.pending_call("call_cycles_add").call.cycles += amount;
+    }
+
+    fn call_perform(&mut self) -> i32 {
+        let mut state = self.0.borrow_mut();
+        state.pending_call("call_perform");
+
+        let PendingCall {
+            call,
+            reply,
+            reject,
+        } = state.pending_call.take().unwrap();
+
+        let result = match state.replies.get(&(call.callee, call.method.clone())) {
+            Some(ContextReply::Reply(data)) => Ok(data.clone()),
+            Some(ContextReply::Reject(message)) => {
+                Err((RejectionCode::CanisterReject, message.clone()))
+            }
+            Some(ContextReply::Handler(handler)) => {
+                handler(&call).map_err(|message| (RejectionCode::CanisterReject, message))
+            }
+            None => Err((
+                RejectionCode::DestinationInvalid,
+                format!(
+                    "No reply is configured for the method '{}' of {}.",
+                    call.method,
+                    state.name_of(&call.callee)
+                ),
+            )),
+        };
+
+        let callback = match result {
+            Ok(data) => PendingCallback {
+                fun: reply.0,
+                env: reply.1,
+                data,
+                rejection: None,
+                cycles_refunded: 0,
+            },
+            Err(rejection) => PendingCallback {
+                fun: reject.0,
+                env: reject.1,
+                data: Vec::new(),
+                rejection: Some(rejection),
+                cycles_refunded: call.cycles,
+            },
+        };
+
+        state.callbacks.push_back(callback);
+        state.calls.push(call);
+
+        0
+    }
+
+    fn stable_size(&mut self) -> i32 {
+        self.0.borrow_mut().stable.stable_size() as i32
+    }
+
+    fn stable_grow(&mut self, new_pages: i32) -> i32 {
+        self.0.borrow_mut().stable.stable_grow(new_pages as u64) as i32
+    }
+
+    fn stable_write(&mut self, offset: i32, src: isize, size: isize) {
+        self.stable64_write(offset as i64, src as i64, size as i64);
+    }
+
+    fn stable_read(&mut self, dst: isize, offset: i32, size: isize) {
+        self.stable64_read(dst as i64, offset as i64, size as i64);
+    }
+
+    fn stable64_size(&mut self) -> i64 {
+        self.0.borrow_mut().stable.stable_size() as i64
+    }
+
+    fn stable64_grow(&mut self, new_pages: i64) -> i64 {
+        self.0.borrow_mut().stable.stable_grow(new_pages as u64)
+    }
+
+    fn stable64_write(&mut self, offset: i64, src: i64, size: i64) {
+        let mut state = self.0.borrow_mut();
+
+        if (offset + size) as u64 > state.stable.stable_size() << 16 {
+            trap("stable memory out of bounds".into());
+        }
+
+        let bytes = copy_from_canister(src as isize, size as isize);
+        state.stable.stable_write(offset as u64, bytes);
+    }
+
+    fn stable64_read(&mut self, dst: i64, offset: i64, size: i64) {
+        let mut state = self.0.borrow_mut();
+
+        if (offset + size) as u64 > state.stable.stable_size() << 16 {
+            trap("stable memory out of bounds".into());
+        }
+
+        let mut buf = vec![0u8; size as usize];
+        state.stable.stable_read(offset as u64, &mut buf);
+        copy_to_canister(dst as isize, 0, size as isize, &buf);
+    }
+
+    fn certified_data_set(&mut self, src: isize, size: isize) {
+        if size > 32 {
+            trap("certified_data_set: the data can not be larger than 32 bytes.".into());
+        }
+
+        self.0.borrow_mut().certified_data = copy_from_canister(src, size).to_vec();
+    }
+
+    fn data_certificate_present(&mut self) -> i32 {
+        self.0.borrow().certificate.is_some() as i32
+    }
+
+    fn data_certificate_size(&mut self) -> isize {
+        match &self.0.borrow().certificate {
+            Some(certificate) => certificate.len() as isize,
+            None => trap("There is no data certificate.".into()),
+        }
+    }
+
+    fn data_certificate_copy(&mut self, dst: isize, offset: isize, size: isize) {
+        match &self.0.borrow().certificate {
+            Some(certificate) => copy_to_canister(dst, offset, size, certificate),
+            None => trap("There is no data certificate.".into()),
+        }
+    }
+
+    fn time(&mut self) -> i64 {
+        self.0.borrow().time as i64
+    }
+
+    fn global_timer_set(&mut self, timestamp: i64) -> i64 {
+        std::mem::replace(&mut self.0.borrow_mut().global_timer, timestamp as u64) as i64
+    }
+
+    fn performance_counter(&mut self, _counter_type: i32) -> i64 {
+        0
+    }
+
+    fn debug_print(&mut self, src: isize, size: isize) {
+        let message = String::from_utf8_lossy(copy_from_canister(src, size));
+        println!("canister: {}", message);
+    }
+
+    fn trap(&mut self, src: isize, size: isize) {
+        trap(String::from_utf8_lossy(copy_from_canister(src, size)).into())
+    }
+}
+
+fn copy_to_canister(dst: isize, offset: isize, size: isize, data: &[u8]) {
+    let offset = offset as usize;
+    let size = size as usize;
+
+    if offset + size > data.len() {
+        trap("Out of bound read.".into());
+    }
+
+    let slice = unsafe { std::slice::from_raw_parts_mut(dst as usize as *mut u8, size) };
+    slice.copy_from_slice(&data[offset..offset + size]);
+}
+
+fn copy_from_canister<'a>(src: isize, size: isize) -> &'a [u8] {
+    unsafe { std::slice::from_raw_parts(src as usize as *const u8, size as usize) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::certification::HashTree;
+
+    /// The outcome of a call made with the system API, set by its callbacks.
+    type Outcome = RefCell<Option<Result<Vec<u8>, (i32, String)>>>;
+
+    fn read(size: isize, copy: unsafe fn(isize, isize, isize)) -> Vec<u8> {
+        let mut buf = vec![0u8; size as usize];
+        unsafe { copy(buf.as_mut_ptr() as isize, 0, size) };
+        buf
+    }
+
+    fn caller() -> Principal {
+        Principal::from_slice(&read(
+            unsafe { ic0::msg_caller_size() },
+            ic0::msg_caller_copy,
+        ))
+    }
+
+    fn id() -> Principal {
+        Principal::from_slice(&read(
+            unsafe { ic0::canister_self_size() },
+            ic0::canister_self_copy,
+        ))
+    }
+
+    fn arg_data() -> Vec<u8> {
+        read(unsafe { ic0::msg_arg_data_size() }, ic0::msg_arg_data_copy)
+    }
+
+    fn balance() -> u128 {
+        let mut buf = [0u8; 16];
+        unsafe { ic0::canister_cycle_balance128(buf.as_mut_ptr() as isize) };
+        u128::from_le_bytes(buf)
+    }
+
+    fn accept(amount: u128) -> u128 {
+        let mut buf = [0u8; 16];
+        unsafe {
+            ic0::msg_cycles_accept128(
+                (amount >> 64) as i64,
+                amount as u64 as i64,
+                buf.as_mut_ptr() as isize,
+            )
+        };
+        u128::from_le_bytes(buf)
+    }
+
+    fn reply(data: &[u8]) {
+        unsafe {
+            ic0::msg_reply_data_append(data.as_ptr() as isize, data.len() as isize);
+            ic0::msg_reply();
+        }
+    }
+
+    fn on_reply(env: isize) {
+        let outcome = unsafe { &*(env as *const Outcome) };
+        *outcome.borrow_mut() = Some(Ok(arg_data()));
+    }
+
+    fn on_reject(env: isize) {
+        let outcome = unsafe { &*(env as *const Outcome) };
+        let code = unsafe { ic0::msg_reject_code() };
+        let message = read(
+            unsafe { ic0::msg_reject_msg_size() },
+            ic0::msg_reject_msg_copy,
+        );
+        *outcome.borrow_mut() = Some(Err((code, String::from_utf8(message).unwrap())));
+    }
+
+    /// Make a call, whose outcome is set once the context answers it.
+    fn call(callee: Principal, method: &str, arg: &[u8], cycles: u128, outcome: &Outcome) {
+        let env = outcome as *const Outcome as isize;
+
+        unsafe {
+            ic0::call_new(
+                callee.as_slice().as_ptr() as isize,
+                callee.as_slice().len() as isize,
+                method.as_ptr() as isize,
+                method.len() as isize,
+                on_reply as fn(isize) as usize as isize,
+                env,
+                on_reject as fn(isize) as usize as isize,
+                env,
+            );
+            ic0::call_data_append(arg.as_ptr() as isize, arg.len() as isize);
+            if cycles > 0 {
+                ic0::call_cycles_add128((cycles >> 64) as i64, cycles as u64 as i64);
+            }
+            assert_eq!(ic0::call_perform(), 0);
+        }
+    }
+
+    #[test]
+    fn environment() {
+        let alice = Principal::from_slice(&[1; 29]);
+        let bob = Principal::from_slice(&[2; 29]);
+        let ctx = MockContext::new()
+            .with_id(canister_id(5))
+            .with_caller(alice)
+            .with_time(1_000)
+            .with_balance(2_000)
+            .with_controller(bob)
+            .with_arg(7u64)
+            .inject();
+
+        assert_eq!(id(), canister_id(5));
+        assert_eq!(caller(), alice);
+        assert_eq!(unsafe { ic0::time() }, 1_000);
+        assert_eq!(balance(), 2_000);
+        assert_eq!(decode_one::<u64>(&arg_data()).unwrap(), 7);
+
+        let is_controller = |p: Principal| unsafe {
+            ic0::is_controller(p.as_slice().as_ptr() as isize, p.as_slice().len() as isize)
+        };
+        assert_eq!(is_controller(bob), 1);
+        assert_eq!(is_controller(alice), 0);
+
+        // The context is shared with the system API, so it can be changed between the calls.
+        let ctx = ctx.with_caller(bob).with_time(2_000);
+        assert_eq!(caller(), bob);
+        assert_eq!(unsafe { ic0::time() }, 2_000);
+
+        assert_eq!(unsafe { ic0::global_timer_set(5_000) }, 0);
+        assert_eq!(unsafe { ic0::global_timer_set(6_000) }, 5_000);
+        assert_eq!(ctx.global_timer(), 6_000);
+    }
+
+    #[test]
+    fn replies() {
+        let ctx = MockContext::new().with_payment(100).inject();
+
+        assert_eq!(accept(30), 30);
+        assert_eq!(ctx.cycles_available(), 70);
+        assert_eq!(ctx.balance(), DEFAULT_BALANCE + 30);
+
+        reply(b"hello");
+        match ctx.take_reply() {
+            Some(CallReply::Reply {
+                data,
+                cycles_refunded,
+            }) => {
+                assert_eq!(data, b"hello");
+                assert_eq!(cycles_refunded, 70);
+            }
+            _ => panic!("The message is not replied to."),
+        }
+        assert!(ctx.take_reply().is_none());
+
+        let message = "no";
+        unsafe { ic0::msg_reject(message.as_ptr() as isize, message.len() as isize) };
+        assert!(matches!(
+            ctx.take_reply(),
+            Some(CallReply::Reject {
+                rejection_code: RejectionCode::CanisterReject,
+                rejection_message,
+                cycles_refunded: 0,
+            }) if rejection_message == "no"
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "Canister trapped: Current call is already replied to.")]
+    fn reply_twice() {
+        MockContext::new().inject();
+        reply(b"a");
+        unsafe { ic0::msg_reply() };
+    }
+
+    #[test]
+    fn calls() {
+        let ledger = canister_id(1);
+        let ctx = MockContext::new()
+            .with_balance(1_000)
+            .with_reply(ledger, "balance", (42u64,))
+            .with_reject(ledger, "transfer", "Insufficient funds.")
+            .with_handler(ledger, "echo", |call| Ok(call.arg.clone()))
+            .inject();
+        let watcher = ctx.watcher();
+
+        let balance_outcome = Outcome::default();
+        let transfer_outcome = Outcome::default();
+        let echo_outcome = Outcome::default();
+        let missing_outcome = Outcome::default();
+
+        call(ledger, "balance", &[], 0, &balance_outcome);
+        call(ledger, "transfer", &[], 300, &transfer_outcome);
+        call(ledger, "echo", b"ping", 0, &echo_outcome);
+        call(canister_id(2), "balance", &[], 0, &missing_outcome);
+
+        // The calls are answered once they are resolved, and the rejected one is refunded.
+        assert!(balance_outcome.borrow().is_none());
+        assert_eq!(ctx.balance(), 700);
+        ctx.resolve_calls();
+        assert_eq!(ctx.balance(), 1_000);
+
+        let balance = balance_outcome.take().unwrap().unwrap();
+        assert_eq!(decode_one::<u64>(&balance).unwrap(), 42);
+        assert_eq!(
+            transfer_outcome.take(),
+            Some(Err((
+                RejectionCode::CanisterReject as i32,
+                "Insufficient funds.".to_string()
+            )))
+        );
+        assert_eq!(echo_outcome.take(), Some(Ok(b"ping".to_vec())));
+        assert_eq!(
+            missing_outcome.take(),
+            Some(Err((
+                RejectionCode::DestinationInvalid as i32,
+                format!(
+                    "No reply is configured for the method 'balance' of {}.",
+                    canister_id(2)
+                )
+            )))
+        );
+
+        assert_eq!(watcher.call_count(), 4);
+        assert_eq!(watcher.calls_to("balance").len(), 2);
+        assert_eq!(watcher.calls_to("echo")[0].arg, b"ping".to_vec());
+        assert_eq!(watcher.cycles_sent(), 300);
+        assert!(watcher.is_method_called("transfer"));
+        assert!(watcher.is_canister_called(&canister_id(2)));
+        assert!(!watcher.is_canister_called(&canister_id(3)));
+
+        watcher.clear();
+        assert_eq!(watcher.call_count(), 0);
+    }
+
+    #[test]
+    fn expected_calls() {
+        let ledger = canister_id(1);
+        let ctx = MockContext::new().with_name(ledger, "ledger");
+        let ctx = ctx
+            .expect_call("ledger", "balance")
+            .return_ok(42u64)
+            .inject();
+
+        let outcome = Outcome::default();
+        call(ledger, "balance", &[], 0, &outcome);
+        ctx.resolve_calls();
+
+        assert_eq!(outcome.take(), Some(Ok(encode_one(42u64).unwrap())));
+        ctx.assert_expected_calls();
+    }
+
+    #[test]
+    #[should_panic(expected = "The expected calls to 'transfer' of ledger were not made.")]
+    fn expected_calls_missing() {
+        let ctx = MockContext::new().with_name(canister_id(1), "ledger");
+        let ctx = ctx.expect_call("ledger", "transfer").reject("no").inject();
+        ctx.assert_expected_calls();
+    }
+
+    #[test]
+    fn certified_data() {
+        let id = canister_id(3);
+        let ctx = MockContext::new().with_id(id).inject();
+        let witness = HashTree::Leaf(b"state".to_vec());
+        let data = witness.digest();
+
+        assert_eq!(unsafe { ic0::data_certificate_present() }, 0);
+        unsafe { ic0::certified_data_set(data.as_ptr() as isize, data.len() as isize) };
+        assert_eq!(ctx.certified_data(), data.to_vec());
+
+        ctx.certify();
+        assert_eq!(unsafe { ic0::data_certificate_present() }, 1);
+        let certificate = read(
+            unsafe { ic0::data_certificate_size() },
+            ic0::data_certificate_copy,
+        );
+        assert_eq!(
+            certification::verify(&id, &certificate, &witness.encode()),
+            Ok(witness)
+        );
+    }
+
+    #[test]
+    fn stable_memory() {
+        MockContext::new().inject();
+
+        unsafe {
+            assert_eq!(ic0::stable64_size(), 0);
+            assert_eq!(ic0::stable64_grow(2), 0);
+            assert_eq!(ic0::stable64_size(), 2);
+
+            let data = b"hello";
+            ic0::stable64_write(70_000, data.as_ptr() as i64, data.len() as i64);
+
+            let mut buf = [0u8; 5];
+            ic0::stable64_read(buf.as_mut_ptr() as i64, 70_000, 5);
+            assert_eq!(&buf, data);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Canister trapped: stable memory out of bounds")]
+    fn stable_memory_out_of_bounds() {
+        MockContext::new().inject();
+
+        let mut buf = [0u8; 5];
+        unsafe { ic0::stable64_read(buf.as_mut_ptr() as i64, 0, 5) };
+    }
+}
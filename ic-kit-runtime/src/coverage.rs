@@ -0,0 +1,100 @@
+//! Report which methods of the canisters were called during a test, to find the methods which are
+//! not tested.
+//!
+//! ```ignore
+//! #[kit_test]
+//! async fn test_ledger(replica: Replica) {
+//!     let ledger = replica.add_canister(LedgerCanister::anonymous());
+//!     // ...
+//!     replica.coverage().assert_all_called();
+//! }
+//! ```
+
+use std::fmt;
+
+use candid::Principal;
+
+/// The number of calls made to each of the update and query methods of the canisters of a
+/// replica, which is returned by [`Replica::coverage`].
+///
+/// [`Replica::coverage`]: crate::Replica::coverage
+#[derive(Clone, Debug, Default)]
+pub struct Coverage {
+    /// The coverage of each canister, in the order of their ids.
+    pub canisters: Vec<CanisterCoverage>,
+}
+
+/// The number of calls made to each of the methods of a canister.
+#[derive(Clone, Debug)]
+pub struct CanisterCoverage {
+    /// The id of the canister.
+    pub canister_id: Principal,
+    /// The name of each method, in alphabetical order, with the number of times it was called.
+    pub methods: Vec<(String, u64)>,
+}
+
+impl CanisterCoverage {
+    /// Return the names of the methods which were not called.
+    pub fn uncalled(&self) -> Vec<&str> {
+        self.methods
+            .iter()
+            .filter(|(_, calls)| *calls == 0)
+            .map(|(name, _)| name.as_str())
+            .collect()
+    }
+}
+
+impl Coverage {
+    /// Return the coverage of the given canister.
+    pub fn canister(&self, canister_id: Principal) -> Option<&CanisterCoverage> {
+        self.canisters.iter().find(|c| c.canister_id == canister_id)
+    }
+
+    /// Return the canister id and name of each method which was not called.
+    pub fn uncalled(&self) -> Vec<(Principal, &str)> {
+        self.canisters
+            .iter()
+            .flat_map(|c| {
+                c.uncalled()
+                    .into_iter()
+                    .map(move |name| (c.canister_id, name))
+            })
+            .collect()
+    }
+
+    /// Returns true if each of the methods was called at least once.
+    pub fn is_complete(&self) -> bool {
+        self.uncalled().is_empty()
+    }
+
+    /// Assert each of the methods of the canisters was called at least once, the panic message
+    /// lists the methods which were not.
+    pub fn assert_all_called(&self) {
+        assert!(
+            self.is_complete(),
+            "Some methods were not called.\n{}",
+            self
+        );
+    }
+}
+
+impl fmt::Display for Coverage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for canister in &self.canisters {
+            let called = canister.methods.iter().filter(|(_, n)| *n > 0).count();
+            writeln!(
+                f,
+                "{}: {}/{} methods called",
+                canister.canister_id,
+                called,
+                canister.methods.len()
+            )?;
+
+            for (name, calls) in &canister.methods {
+                writeln!(f, "  {:>6}  {}", calls, name)?;
+            }
+        }
+
+        Ok(())
+    }
+}
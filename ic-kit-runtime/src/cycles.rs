@@ -0,0 +1,278 @@
+//! A report of the cycles which moved between the principals of a replica, to audit the fees and
+//! the payments of the canisters at the end of a test.
+//!
+//! The replica follows the cycles sent with each call, the cycles the callee accepted or refunded
+//! and the fees charged to the canisters for their calls, which is what [`Replica::cycles_report`]
+//! returns along with the balance of each canister. The report is printed as a summary, and
+//! [`CyclesReport::assert_no_cycles_lost`] checks that the cycles of each call were either accepted
+//! or refunded, and that the balance of each canister matches its flows:
+//!
+//! ```ignore
+//! ledger.new_call("transfer").with_payment(1_000).perform().await.assert_ok();
+//!
+//! let report = replica.cycles_report().await;
+//! println!("{}", report);
+//! report.assert_no_cycles_lost();
+//! ```
+//!
+//! ```text
+//! ledger: 100000000000000 -> 100000000000988 cycles
+//!   received 1000, accepted 1000, refunded 0
+//!   sent 0, refunds 0, burned 12
+//! ```
+//!
+//! The changes made to the balances with [`CanisterHandle::set_balance`] are part of the flows, but
+//! not the ones made with an [`Env`] which sets the balance.
+//!
+//! [`Replica::cycles_report`]: crate::Replica::cycles_report
+//! [`CanisterHandle::set_balance`]: crate::handle::CanisterHandle::set_balance
+//! [`Env`]: crate::types::Env
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use candid::Principal;
+
+/// The cycles which moved to and from a principal, which is a canister or a user.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CyclesFlow {
+    /// The balance of the canister when it was added to the replica, `None` for a user.
+    pub initial_balance: Option<u128>,
+    /// The balance of the canister at the time of the report, `None` for a user or a canister
+    /// which was removed.
+    pub balance: Option<u128>,
+    /// The changes made to the balance with `CanisterHandle::set_balance`.
+    pub adjusted: i128,
+    /// The cycles sent with the calls made to the principal.
+    pub received: u128,
+    /// The cycles kept out of the ones received, once the calls were resolved.
+    pub accepted: u128,
+    /// The cycles given back to the callers out of the ones received.
+    pub refunded: u128,
+    /// The cycles sent with the calls made to the principal which are not resolved yet.
+    pub received_in_flight: u128,
+    /// The cycles sent with the calls the principal made.
+    pub sent: u128,
+    /// The cycles given back by the callees out of the ones sent.
+    pub refunds: u128,
+    /// The cycles sent with the calls the principal made which are not resolved yet.
+    pub sent_in_flight: u128,
+    /// The fees charged for the calls the principal made.
+    pub burned: u128,
+    /// The cycles accepted by each of the callees out of the ones sent.
+    pub transferred: BTreeMap<Principal, u128>,
+}
+
+impl CyclesFlow {
+    /// Return the balance the canister should have according to its flows, or `None` for a user.
+    ///
+    /// The cycles a canister accepts are added to its balance when the message accepting them is
+    /// finished, but they are only counted as accepted once the call is resolved, so this only
+    /// matches the balance when no call to the canister is in flight.
+    pub fn expected_balance(&self) -> Option<i128> {
+        let initial = self.initial_balance? as i128;
+
+        Some(
+            initial + self.adjusted + self.accepted as i128 - self.sent as i128
+                + self.refunds as i128
+                - self.burned as i128,
+        )
+    }
+}
+
+/// The flows of the cycles of each principal of a replica, see [`crate::cycles`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CyclesReport {
+    flows: BTreeMap<Principal, CyclesFlow>,
+    names: HashMap<Principal, String>,
+}
+
+impl CyclesReport {
+    /// Return the flows of the principal, if any cycles moved to or from it.
+    pub fn flow(&self, id: &Principal) -> Option<&CyclesFlow> {
+        self.flows.get(id)
+    }
+
+    /// Return the flows of each principal.
+    pub fn flows(&self) -> &BTreeMap<Principal, CyclesFlow> {
+        &self.flows
+    }
+
+    /// Return the fees charged to all of the canisters.
+    pub fn total_burned(&self) -> u128 {
+        self.flows.values().map(|flow| flow.burned).sum()
+    }
+
+    /// Return the problems found in the flows, see [`CyclesReport::assert_no_cycles_lost`].
+    pub fn lost_cycles(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        for (id, flow) in &self.flows {
+            let name = self.name(id);
+
+            let transferred = flow.transferred.values().sum::<u128>();
+            let resolved_sent = transferred + flow.refunds + flow.sent_in_flight;
+            if resolved_sent != flow.sent {
+                problems.push(format!(
+                    "{} sent {} cycles, but {} were accepted, refunded or are in flight.",
+                    name, flow.sent, resolved_sent
+                ));
+            }
+
+            let resolved_received = flow.accepted + flow.refunded + flow.received_in_flight;
+            if resolved_received != flow.received {
+                problems.push(format!(
+                    "{} received {} cycles, but {} were accepted, refunded or are in flight.",
+                    name, flow.received, resolved_received
+                ));
+            }
+
+            if flow.received_in_flight > 0 {
+                continue;
+            }
+
+            if let (Some(balance), Some(expected)) = (flow.balance, flow.expected_balance()) {
+                if balance as i128 != expected {
+                    problems.push(format!(
+                        "{} has a balance of {} cycles, but its flows add up to {}.",
+                        name, balance, expected
+                    ));
+                }
+            }
+        }
+
+        problems
+    }
+
+    /// Assert the cycles sent with each call were either accepted by the callee, refunded to the
+    /// caller or are still in flight, and that the balance of each canister is its initial
+    /// balance plus the cycles it accepted and the refunds it got, minus the cycles it sent and
+    /// the fees it paid. The balance of a canister with calls to it in flight is not checked.
+    pub fn assert_no_cycles_lost(&self) {
+        let problems = self.lost_cycles();
+
+        if !problems.is_empty() {
+            panic!(
+                "ic-kit-runtime: The cycles don't add up.\n{}",
+                problems
+                    .iter()
+                    .map(|problem| format!("  {}", problem))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            );
+        }
+    }
+
+    fn name(&self, id: &Principal) -> String {
+        self.names.get(id).cloned().unwrap_or_else(|| id.to_text())
+    }
+}
+
+impl fmt::Display for CyclesReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (id, flow) in &self.flows {
+            match (flow.initial_balance, flow.balance) {
+                (Some(initial), Some(balance)) => {
+                    writeln!(f, "{}: {} -> {} cycles", self.name(id), initial, balance)?
+                }
+                (Some(initial), None) => writeln!(f, "{}: {} -> removed", self.name(id), initial)?,
+                _ => writeln!(f, "{}:", self.name(id))?,
+            }
+
+            writeln!(
+                f,
+                "  received {}, accepted {}, refunded {}",
+                flow.received, flow.accepted, flow.refunded
+            )?;
+            writeln!(
+                f,
+                "  sent {}, refunds {}, burned {}",
+                flow.sent, flow.refunds, flow.burned
+            )?;
+
+            for (callee, cycles) in &flow.transferred {
+                writeln!(f, "  -> {}: {}", self.name(callee), cycles)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Records the flows of the cycles as the calls are made and resolved, this is owned by the
+/// tracer of the replica.
+#[derive(Default)]
+pub(crate) struct CyclesLedger {
+    flows: BTreeMap<Principal, CyclesFlow>,
+}
+
+impl CyclesLedger {
+    /// Record the balance of a canister added to the replica.
+    pub fn add_canister(&mut self, id: Principal, balance: u128) {
+        self.flows
+            .entry(id)
+            .or_default()
+            .initial_balance
+            .get_or_insert(balance);
+    }
+
+    /// Record a change of the balance of a canister made by the test.
+    pub fn adjust(&mut self, id: Principal, delta: i128) {
+        self.flows.entry(id).or_default().adjusted += delta;
+    }
+
+    /// Record the fee charged to a canister for a call.
+    pub fn burn(&mut self, id: Principal, fee: u128) {
+        self.flows.entry(id).or_default().burned += fee;
+    }
+
+    /// Record the cycles sent with a call.
+    pub fn call(&mut self, sender: Principal, callee: Principal, payment: u128) {
+        if payment == 0 {
+            return;
+        }
+
+        let flow = self.flows.entry(sender).or_default();
+        flow.sent += payment;
+        flow.sent_in_flight += payment;
+
+        let flow = self.flows.entry(callee).or_default();
+        flow.received += payment;
+        flow.received_in_flight += payment;
+    }
+
+    /// Record the cycles refunded for a call once it's resolved, the rest was accepted.
+    pub fn resolve(&mut self, sender: Principal, callee: Principal, payment: u128, refunded: u128) {
+        if payment == 0 {
+            return;
+        }
+
+        let accepted = payment.saturating_sub(refunded);
+
+        let flow = self.flows.entry(callee).or_default();
+        flow.received_in_flight -= payment;
+        flow.accepted += accepted;
+        flow.refunded += refunded;
+
+        let flow = self.flows.entry(sender).or_default();
+        flow.sent_in_flight -= payment;
+        flow.refunds += refunded;
+        *flow.transferred.entry(callee).or_default() += accepted;
+    }
+
+    /// Create the report, with the current balance of the canisters and the names of the
+    /// principals.
+    pub fn report(
+        &self,
+        balances: HashMap<Principal, u128>,
+        names: HashMap<Principal, String>,
+    ) -> CyclesReport {
+        let mut flows = self.flows.clone();
+
+        for (id, balance) in balances {
+            flows.entry(id).or_default().balance = Some(balance);
+        }
+
+        CyclesReport { flows, names }
+    }
+}
@@ -0,0 +1,255 @@
+//! A descriptor of the deployment of a set of canisters, such as the canisters of a dapp which know
+//! each other's ids, which a [`Replica`] deploys in one call.
+//!
+//! ```ignore
+//! let deployment = Deployment::new()
+//!     .canister("ledger", LedgerCanister::build)
+//!     .canister("dex", |id| DexCanister::build(id).with_balance(10_000_000_000))
+//!     .with_init_args("ledger", |ids| (ids["dex"], 1_000_000u64))
+//!     .with_init_arg("dex", |ids| ids["ledger"])
+//!     .with_controller("ledger", |ids| ids["dex"]);
+//!
+//! let dapp = replica.deploy(deployment).await;
+//! dapp["dex"].new_call("swap").with_caller(*users::ALICE).perform().await.assert_ok();
+//! ```
+//!
+//! The ids of the canisters are assigned in the order they are described, so they are known before
+//! any of the canisters is built, and are passed to the functions which create the init arguments
+//! and the controllers of each canister. Once all of the canisters are added to the replica, their
+//! init hooks run in the same order.
+
+use std::collections::BTreeMap;
+use std::ops::Index;
+
+use candid::utils::ArgumentEncoder;
+use candid::{encode_args, encode_one, CandidType, Principal};
+
+use crate::canister::Canister;
+use crate::handle::CanisterHandle;
+use crate::replica::canister_id;
+use crate::Replica;
+
+/// The index of the id of the first canister of a deployment, see [`canister_id`].
+const DEPLOYMENT_CANISTER_INDEX: u64 = 0x10_0000;
+
+type Build = Box<dyn FnOnce(Principal) -> Canister + Send>;
+type Wire<T> = Box<dyn FnOnce(&DeploymentIds) -> T + Send>;
+
+/// The ids of the canisters of a deployment, by their name.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DeploymentIds(BTreeMap<String, Principal>);
+
+/// The description of a set of canisters, their init arguments and their controllers, see
+/// [`crate::deploy`].
+#[derive(Default)]
+pub struct Deployment {
+    ids: DeploymentIds,
+    canisters: Vec<DeploymentCanister>,
+}
+
+struct DeploymentCanister {
+    name: String,
+    build: Build,
+    init_arg: Option<Wire<Vec<u8>>>,
+    controllers: Vec<Wire<Principal>>,
+}
+
+/// The canisters of a deployment which was deployed on a replica, by their name.
+pub struct Deployed<'a> {
+    ids: DeploymentIds,
+    handles: BTreeMap<String, CanisterHandle<'a>>,
+}
+
+impl DeploymentIds {
+    /// Return the id of the canister with the given name, if it's in the deployment.
+    pub fn get(&self, name: &str) -> Option<Principal> {
+        self.0.get(name).copied()
+    }
+
+    /// Return the names and the ids of the canisters, ordered by name.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, Principal)> {
+        self.0.iter().map(|(name, id)| (name.as_str(), *id))
+    }
+}
+
+impl Index<&str> for DeploymentIds {
+    type Output = Principal;
+
+    fn index(&self, name: &str) -> &Principal {
+        self.0.get(name).unwrap_or_else(|| {
+            panic!(
+                "ic-kit-runtime: The deployment has no canister named '{}'.",
+                name
+            )
+        })
+    }
+}
+
+impl Deployment {
+    /// Create an empty deployment.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a canister with the given name to the deployment, which is created by the given
+    /// function from its id, such as `CounterCanister::build`.
+    ///
+    /// # Panics
+    ///
+    /// If the deployment already has a canister with the same name.
+    pub fn canister<S, F>(mut self, name: S, build: F) -> Self
+    where
+        S: Into<String>,
+        F: FnOnce(Principal) -> Canister + Send + 'static,
+    {
+        let name = name.into();
+
+        if self.ids.0.contains_key(&name) {
+            panic!(
+                "ic-kit-runtime: The deployment already has a canister named '{}'.",
+                name
+            );
+        }
+
+        let id = canister_id(DEPLOYMENT_CANISTER_INDEX + self.canisters.len() as u64);
+        self.ids.0.insert(name.clone(), id);
+        self.canisters.push(DeploymentCanister {
+            name,
+            build: Box::new(build),
+            init_arg: None,
+            controllers: Vec::new(),
+        });
+
+        self
+    }
+
+    /// Run the init hook of the canister with the arguments returned by the given function, which
+    /// is given the ids of the canisters of the deployment.
+    pub fn with_init_args<T, F>(self, name: &str, arguments: F) -> Self
+    where
+        T: ArgumentEncoder,
+        F: FnOnce(&DeploymentIds) -> T + Send + 'static,
+    {
+        self.with_init_arg_raw(name, |ids| {
+            encode_args(arguments(ids)).expect("Failed to encode arguments.")
+        })
+    }
+
+    /// Shorthand for `with_init_args` returning a tuple with only one element.
+    pub fn with_init_arg<T, F>(self, name: &str, argument: F) -> Self
+    where
+        T: CandidType,
+        F: FnOnce(&DeploymentIds) -> T + Send + 'static,
+    {
+        self.with_init_arg_raw(name, |ids| {
+            encode_one(argument(ids)).expect("Failed to encode argument.")
+        })
+    }
+
+    /// Run the init hook of the canister with the raw arguments returned by the given function.
+    pub fn with_init_arg_raw<F>(mut self, name: &str, argument: F) -> Self
+    where
+        F: FnOnce(&DeploymentIds) -> Vec<u8> + Send + 'static,
+    {
+        self.get_mut(name).init_arg = Some(Box::new(argument));
+        self
+    }
+
+    /// Add the principal returned by the given function to the controllers of the canister, such
+    /// as the id of another canister of the deployment.
+    pub fn with_controller<F>(mut self, name: &str, controller: F) -> Self
+    where
+        F: FnOnce(&DeploymentIds) -> Principal + Send + 'static,
+    {
+        self.get_mut(name).controllers.push(Box::new(controller));
+        self
+    }
+
+    /// Return the ids the canisters of the deployment are deployed with.
+    pub fn ids(&self) -> &DeploymentIds {
+        &self.ids
+    }
+
+    fn get_mut(&mut self, name: &str) -> &mut DeploymentCanister {
+        self.canisters
+            .iter_mut()
+            .find(|c| c.name == name)
+            .unwrap_or_else(|| {
+                panic!(
+                    "ic-kit-runtime: The deployment has no canister named '{}'.",
+                    name
+                )
+            })
+    }
+
+    /// Add the canisters to the replica with their names, and run their init hooks in the order
+    /// they were described. If the state of a canister is restored from the state directory of
+    /// the replica, its post_upgrade hook runs instead.
+    pub(crate) async fn deploy(self, replica: &Replica) -> Deployed<'_> {
+        let Deployment { ids, canisters } = self;
+        let mut handles = BTreeMap::new();
+        let mut order = Vec::with_capacity(canisters.len());
+
+        for c in canisters {
+            let id = ids[c.name.as_str()];
+            let mut canister = (c.build)(id);
+
+            if canister.id() != id {
+                panic!(
+                    "ic-kit-runtime: Canister '{}' of the deployment was built with the id '{}' instead of '{}'.",
+                    c.name,
+                    canister.id(),
+                    id
+                );
+            }
+
+            if let Some(init_arg) = c.init_arg {
+                canister = canister.with_init_arg_raw(init_arg(&ids));
+            }
+
+            for controller in c.controllers {
+                canister = canister.with_controller(controller(&ids));
+            }
+
+            replica.name(id, c.name.clone());
+            handles.insert(c.name.clone(), replica.add_canister(canister));
+            order.push((c.name, id));
+        }
+
+        for (name, id) in order {
+            if let Some(e) = handles[&name].init_or_restore().await.rejection_message() {
+                panic!(
+                    "ic-kit-runtime: The init hook of canister '{}' ({}) failed: {}",
+                    name, id, e
+                );
+            }
+        }
+
+        Deployed { ids, handles }
+    }
+}
+
+impl<'a> Deployed<'a> {
+    /// Return the handle of the canister with the given name, if it's in the deployment.
+    pub fn get(&self, name: &str) -> Option<&CanisterHandle<'a>> {
+        self.handles.get(name)
+    }
+
+    /// Return the ids of the canisters of the deployment.
+    pub fn ids(&self) -> &DeploymentIds {
+        &self.ids
+    }
+}
+
+impl<'a> Index<&str> for Deployed<'a> {
+    type Output = CanisterHandle<'a>;
+
+    fn index(&self, name: &str) -> &CanisterHandle<'a> {
+        self.handles.get(name).unwrap_or_else(|| {
+            panic!(
+                "ic-kit-runtime: The deployment has no canister named '{}'.",
+                name
+            )
+        })
+    }
+}
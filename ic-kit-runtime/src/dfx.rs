@@ -0,0 +1,262 @@
+//! A loader for the `dfx.json` and `canister_ids.json` files of a project, which resolves the
+//! names of the canisters to their ids on each network and to the paths of their wasm modules, so
+//! the tests don't need to hardcode them.
+//!
+//! ```ignore
+//! let project = DfxProject::find().unwrap();
+//! let counter_id = project.canister_id("counter", "local").unwrap();
+//!
+//! // On the in-process replica, or on PocketIC with the wasm module built by dfx.
+//! let replica = Replica::pocket_ic().await;
+//! replica.add_canister(CounterCanister::build(counter_id).with_wasm(project.wasm("counter")?));
+//!
+//! // Or against the canister deployed on the local replica.
+//! let replica = Replica::with_agent(project.agent("local"));
+//! ```
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::{env, fs, io};
+
+use candid::Principal;
+use serde::Deserialize;
+
+/// The canisters of a dfx project, and their ids on each of the networks they are deployed to.
+#[derive(Debug)]
+pub struct DfxProject {
+    /// The directory which contains the `dfx.json` file.
+    root: PathBuf,
+    config: DfxConfig,
+    /// Map the name of each canister to its id on each network.
+    ids: HashMap<String, HashMap<String, Principal>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct DfxConfig {
+    #[serde(default)]
+    canisters: HashMap<String, DfxCanister>,
+    #[serde(default)]
+    networks: HashMap<String, DfxNetwork>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DfxCanister {
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    package: Option<String>,
+    wasm: Option<String>,
+    candid: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DfxNetwork {
+    bind: Option<String>,
+    #[serde(default)]
+    providers: Vec<String>,
+}
+
+impl DfxProject {
+    /// Load the project in the given directory, which must contain a `dfx.json` file. The ids of
+    /// the canisters are read from the `canister_ids.json` file of the project, and from the ones
+    /// dfx writes under `.dfx` for the networks which are not persistent, such as `local`.
+    pub fn load<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let root = dir.as_ref().to_path_buf();
+        let config: DfxConfig = read_json(&root.join("dfx.json"))?;
+
+        let mut ids = HashMap::new();
+        let mut files = vec![root.join("canister_ids.json")];
+
+        match fs::read_dir(root.join(".dfx")) {
+            Ok(entries) => {
+                for entry in entries {
+                    files.push(entry?.path().join("canister_ids.json"));
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        for file in files {
+            if !file.exists() {
+                continue;
+            }
+
+            let networks: HashMap<String, HashMap<String, String>> = read_json(&file)?;
+
+            for (name, networks) in networks {
+                for (network, id) in networks {
+                    let id = Principal::from_text(&id).map_err(|e| {
+                        invalid_data(format!(
+                            "The id of canister '{}' on '{}' in '{}' is invalid: {}",
+                            name,
+                            network,
+                            file.display(),
+                            e
+                        ))
+                    })?;
+
+                    ids.entry(name.clone())
+                        .or_insert_with(HashMap::new)
+                        .insert(network, id);
+                }
+            }
+        }
+
+        Ok(Self { root, config, ids })
+    }
+
+    /// Load the project whose `dfx.json` is in the current directory or in the closest of its
+    /// parents, which finds the project of a workspace from the directory of any of its crates.
+    pub fn find() -> io::Result<Self> {
+        let current = env::current_dir()?;
+
+        for dir in current.ancestors() {
+            if dir.join("dfx.json").exists() {
+                return Self::load(dir);
+            }
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "Could not find a dfx.json in '{}' or its parents.",
+                current.display()
+            ),
+        ))
+    }
+
+    /// Return the directory of the project.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Return the names of the canisters of the project.
+    pub fn canister_names(&self) -> Vec<&str> {
+        let mut names = self
+            .config
+            .canisters
+            .keys()
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        names.sort_unstable();
+        names
+    }
+
+    /// Return the id of the canister on the given network, if it was deployed to it.
+    pub fn canister_id(&self, name: &str, network: &str) -> Option<Principal> {
+        self.ids.get(name)?.get(network).copied()
+    }
+
+    /// Return the path of the wasm module of the canister: the `wasm` field of the canister if it
+    /// has one, the output of cargo for a rust canister, for the wasm32 target or for the wasm64
+    /// one if it's the only one built, or the module built by dfx for the local network otherwise.
+    pub fn wasm_path(&self, name: &str) -> Option<PathBuf> {
+        let canister = self.config.canisters.get(name)?;
+
+        if let Some(wasm) = &canister.wasm {
+            return Some(self.root.join(wasm));
+        }
+
+        if canister.kind.as_deref() == Some("rust") {
+            let package = canister.package.as_deref().unwrap_or(name);
+            let target = env::var_os("CARGO_TARGET_DIR")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| self.root.join("target"));
+
+            let file = format!("{}.wasm", package);
+            let wasm32 = target.join("wasm32-unknown-unknown/release").join(&file);
+            let wasm64 = target.join("wasm64-unknown-unknown/release").join(&file);
+
+            if !wasm32.exists() && wasm64.exists() {
+                return Some(wasm64);
+            }
+
+            return Some(wasm32);
+        }
+
+        Some(
+            self.root
+                .join(".dfx/local/canisters")
+                .join(name)
+                .join(format!("{}.wasm", name)),
+        )
+    }
+
+    /// Read the wasm module of the canister, see [`DfxProject::wasm_path`].
+    pub fn wasm(&self, name: &str) -> io::Result<Vec<u8>> {
+        let path = self.wasm_path(name).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("The project does not have a canister named '{}'.", name),
+            )
+        })?;
+
+        fs::read(&path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!(
+                    "Could not read the wasm module of canister '{}' from '{}': {}",
+                    name,
+                    path.display(),
+                    e
+                ),
+            )
+        })
+    }
+
+    /// Read the candid interface of the canister, if it has one.
+    pub fn candid(&self, name: &str) -> Option<io::Result<String>> {
+        let candid = self.config.canisters.get(name)?.candid.as_ref()?;
+        Some(fs::read_to_string(self.root.join(candid)))
+    }
+
+    /// Return the url of the replica of the network, from its `bind` address or its first
+    /// provider, or the defaults of dfx for the `local` and `ic` networks.
+    pub fn network_url(&self, network: &str) -> Option<String> {
+        if let Some(config) = self.config.networks.get(network) {
+            if let Some(bind) = &config.bind {
+                return Some(format!("http://{}", bind));
+            }
+
+            if let Some(provider) = config.providers.first() {
+                return Some(provider.clone());
+            }
+        }
+
+        match network {
+            "local" => Some("http://127.0.0.1:4943".to_string()),
+            "ic" => Some("https://icp-api.io".to_string()),
+            _ => None,
+        }
+    }
+
+    /// Create an agent backend which sends the calls to the given network of the project, see
+    /// [`Replica::with_agent`].
+    ///
+    /// # Panics
+    ///
+    /// If the url of the network is not known.
+    ///
+    /// [`Replica::with_agent`]: crate::Replica::with_agent
+    #[cfg(feature = "ic-agent")]
+    pub fn agent(&self, network: &str) -> crate::agent::AgentBackend {
+        let url = self.network_url(network).unwrap_or_else(|| {
+            panic!(
+                "ic-kit-runtime: The url of network '{}' is not in dfx.json.",
+                network
+            )
+        });
+
+        crate::agent::AgentBackend::new(url)
+    }
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> io::Result<T> {
+    let data = fs::read(path)?;
+    serde_json::from_slice(&data)
+        .map_err(|e| invalid_data(format!("Could not parse '{}': {}", path.display(), e)))
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
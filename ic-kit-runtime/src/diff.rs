@@ -0,0 +1,398 @@
+//! Structural diffs between two captures of the state, to quickly see what a call changed.
+//!
+//! The values in the storage of a canister are compared through their candid representation, the
+//! fields of the records are matched by their name and the elements of the vectors by their index:
+//!
+//! ```ignore
+//! let before = ledger.state_value::<Ledger>().await;
+//! ledger.new_call("transfer").with_args((bob, 10u64)).perform().await.assert_ok();
+//! let after = ledger.state_value::<Ledger>().await;
+//! println!("{}", ValueDiff::between(&before, &after));
+//! ```
+//!
+//! ```text
+//! ~ .balances[0].amount: 100 : nat64 -> 90 : nat64
+//! + .balances[1]: record { owner = principal "..."; amount = 10 : nat64 }
+//! ```
+//!
+//! The captures of a whole replica, returned by [`Replica::capture`], are compared by the balance
+//! and the ranges of the stable memory of each canister:
+//!
+//! ```text
+//! ~ canister ledger (rrkah-fqaaa-aaaaa-aaaaq-cai)
+//!     balance: 1000000 -> 999000
+//!     stable memory [0x10..0x18]: 6400000000000000 -> 5a00000000000000
+//! ```
+//!
+//! [`Replica::capture`]: crate::Replica::capture
+
+use std::fmt;
+use std::io;
+
+use candid::parser::value::{IDLField, IDLValue};
+use candid::types::internal::TypeContainer;
+use candid::{encode_one, CandidType, IDLArgs, Principal};
+
+use crate::dump::{CanisterState, ReplicaState};
+
+/// The ranges of the stable memory separated by fewer equal bytes than this are merged.
+const MERGE_GAP: usize = 8;
+
+/// The number of bytes of a range of the stable memory which are printed.
+const MAX_PRINTED_BYTES: usize = 32;
+
+/// Return the candid representation of the value, with the field names from its type.
+pub fn to_value<T: CandidType>(value: &T) -> IDLValue {
+    let mut types = TypeContainer::new();
+    let ty = types.add::<T>();
+    let bytes = encode_one(value).expect("Could not encode the value.");
+    let mut args = IDLArgs::from_bytes_with_types(&bytes, &types.env, &[ty])
+        .expect("Could not decode the value.");
+    args.args.remove(0)
+}
+
+/// A change of a part of a value, found by [`ValueDiff::between`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum ValueChange {
+    /// A field or an element which is only in the new value.
+    Added { path: String, value: IDLValue },
+    /// A field or an element which is only in the old value.
+    Removed { path: String, value: IDLValue },
+    /// A part of the value which is different.
+    Changed {
+        path: String,
+        before: IDLValue,
+        after: IDLValue,
+    },
+}
+
+/// The changes between two values, with the path of each change such as `.balances[0].amount`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ValueDiff {
+    /// The changes, in the order of the fields and the elements of the values.
+    pub changes: Vec<ValueChange>,
+}
+
+impl ValueDiff {
+    /// Compare the two values.
+    pub fn between(before: &IDLValue, after: &IDLValue) -> Self {
+        let mut diff = Self::default();
+        diff.compare(String::new(), before, after);
+        diff
+    }
+
+    /// Compare the candid representation of the two values.
+    pub fn from_candid<T: CandidType>(before: &T, after: &T) -> Self {
+        Self::between(&to_value(before), &to_value(after))
+    }
+
+    /// Return true if the values are equal.
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    fn compare(&mut self, path: String, before: &IDLValue, after: &IDLValue) {
+        if before == after {
+            return;
+        }
+
+        match (before, after) {
+            (IDLValue::Record(before), IDLValue::Record(after)) => {
+                for field in before {
+                    let path = format!("{}.{}", path, field.id);
+
+                    match find_field(after, field) {
+                        Some(other) => self.compare(path, &field.val, &other.val),
+                        None => self.changes.push(ValueChange::Removed {
+                            path,
+                            value: field.val.clone(),
+                        }),
+                    }
+                }
+
+                for field in after {
+                    if find_field(before, field).is_none() {
+                        self.changes.push(ValueChange::Added {
+                            path: format!("{}.{}", path, field.id),
+                            value: field.val.clone(),
+                        });
+                    }
+                }
+            }
+            (IDLValue::Vec(before), IDLValue::Vec(after)) => {
+                for (i, (before, after)) in before.iter().zip(after).enumerate() {
+                    self.compare(format!("{}[{}]", path, i), before, after);
+                }
+
+                for (i, value) in before.iter().enumerate().skip(after.len()) {
+                    self.changes.push(ValueChange::Removed {
+                        path: format!("{}[{}]", path, i),
+                        value: value.clone(),
+                    });
+                }
+
+                for (i, value) in after.iter().enumerate().skip(before.len()) {
+                    self.changes.push(ValueChange::Added {
+                        path: format!("{}[{}]", path, i),
+                        value: value.clone(),
+                    });
+                }
+            }
+            (IDLValue::Variant(before), IDLValue::Variant(after)) if before.0.id == after.0.id => {
+                let path = format!("{}.{}", path, before.0.id);
+                self.compare(path, &before.0.val, &after.0.val);
+            }
+            (IDLValue::Opt(before), IDLValue::Opt(after)) => {
+                self.compare(format!("{}?", path), before, after);
+            }
+            _ => self.changes.push(ValueChange::Changed {
+                path,
+                before: before.clone(),
+                after: after.clone(),
+            }),
+        }
+    }
+}
+
+fn find_field<'a>(fields: &'a [IDLField], field: &IDLField) -> Option<&'a IDLField> {
+    fields.iter().find(|other| other.id == field.id)
+}
+
+impl fmt::Display for ValueDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for change in &self.changes {
+            match change {
+                ValueChange::Added { path, value } => {
+                    writeln!(f, "+ {}: {}", or_root(path), value)?
+                }
+                ValueChange::Removed { path, value } => {
+                    writeln!(f, "- {}: {}", or_root(path), value)?
+                }
+                ValueChange::Changed {
+                    path,
+                    before,
+                    after,
+                } => writeln!(f, "~ {}: {} -> {}", or_root(path), before, after)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn or_root(path: &str) -> &str {
+    if path.is_empty() {
+        "."
+    } else {
+        path
+    }
+}
+
+/// A range of the stable memory which is different, the bytes past the end of a stable memory
+/// are read as zeros.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RangeDiff {
+    /// The offset of the range in the stable memory.
+    pub offset: u64,
+    /// The bytes of the range in the old stable memory.
+    pub before: Vec<u8>,
+    /// The bytes of the range in the new stable memory.
+    pub after: Vec<u8>,
+}
+
+/// The changes of a canister between two captures of a replica.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CanisterDiff {
+    /// The canister is only in the new capture.
+    Added { id: Principal, name: Option<String> },
+    /// The canister is only in the old capture.
+    Removed { id: Principal, name: Option<String> },
+    /// The canister is different.
+    Changed {
+        id: Principal,
+        name: Option<String>,
+        /// The old and the new balance, if it changed.
+        balance: Option<(u128, u128)>,
+        /// The old and the new size of the stable memory in bytes, if it changed.
+        stable_memory_size: Option<(u64, u64)>,
+        /// The ranges of the stable memory which changed.
+        stable_memory: Vec<RangeDiff>,
+    },
+}
+
+/// The changes between two captures of a replica, see [`Replica::capture`].
+///
+/// [`Replica::capture`]: crate::Replica::capture
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReplicaDiff {
+    /// The old and the new time of the replica, if it changed.
+    pub time: Option<(Option<u64>, Option<u64>)>,
+    /// The canisters which changed, in the order they were added.
+    pub canisters: Vec<CanisterDiff>,
+}
+
+impl ReplicaDiff {
+    /// Compare the two captures.
+    pub fn between(before: &ReplicaState, after: &ReplicaState) -> io::Result<Self> {
+        let mut canisters = Vec::new();
+
+        for canister in &before.canisters {
+            match after.canisters.iter().find(|other| other.id == canister.id) {
+                Some(other) => {
+                    if let Some(diff) = diff_canister(canister, other)? {
+                        canisters.push(diff);
+                    }
+                }
+                None => canisters.push(CanisterDiff::Removed {
+                    id: canister.id,
+                    name: canister.name.clone(),
+                }),
+            }
+        }
+
+        for canister in &after.canisters {
+            if !before.canisters.iter().any(|other| other.id == canister.id) {
+                canisters.push(CanisterDiff::Added {
+                    id: canister.id,
+                    name: canister.name.clone(),
+                });
+            }
+        }
+
+        Ok(Self {
+            time: changed(before.time, after.time),
+            canisters,
+        })
+    }
+
+    /// Return true if the captures are equal.
+    pub fn is_empty(&self) -> bool {
+        self.time.is_none() && self.canisters.is_empty()
+    }
+}
+
+fn diff_canister(
+    before: &CanisterState,
+    after: &CanisterState,
+) -> io::Result<Option<CanisterDiff>> {
+    let before_memory = before.stable_memory()?;
+    let after_memory = after.stable_memory()?;
+
+    let balance = changed(before.balance, after.balance);
+    let stable_memory_size = changed(before_memory.len() as u64, after_memory.len() as u64);
+    let stable_memory = diff_memory(&before_memory, &after_memory);
+
+    if balance.is_none() && stable_memory_size.is_none() && stable_memory.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(CanisterDiff::Changed {
+        id: after.id,
+        name: after.name.clone().or_else(|| before.name.clone()),
+        balance,
+        stable_memory_size,
+        stable_memory,
+    }))
+}
+
+fn changed<T: PartialEq>(before: T, after: T) -> Option<(T, T)> {
+    if before == after {
+        None
+    } else {
+        Some((before, after))
+    }
+}
+
+/// Return the ranges of the memories which are different.
+fn diff_memory(before: &[u8], after: &[u8]) -> Vec<RangeDiff> {
+    let len = before.len().max(after.len());
+    let byte = |memory: &[u8], i: usize| memory.get(i).copied().unwrap_or(0);
+
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+
+    for i in (0..len).filter(|&i| byte(before, i) != byte(after, i)) {
+        match ranges.last_mut() {
+            Some((_, end)) if i - *end < MERGE_GAP => *end = i + 1,
+            _ => ranges.push((i, i + 1)),
+        }
+    }
+
+    ranges
+        .into_iter()
+        .map(|(start, end)| RangeDiff {
+            offset: start as u64,
+            before: (start..end).map(|i| byte(before, i)).collect(),
+            after: (start..end).map(|i| byte(after, i)).collect(),
+        })
+        .collect()
+}
+
+impl fmt::Display for ReplicaDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some((before, after)) = self.time {
+            writeln!(f, "~ time: {:?} -> {:?}", before, after)?;
+        }
+
+        for canister in &self.canisters {
+            match canister {
+                CanisterDiff::Added { id, name } => {
+                    writeln!(f, "+ canister {}", describe(id, name))?
+                }
+                CanisterDiff::Removed { id, name } => {
+                    writeln!(f, "- canister {}", describe(id, name))?
+                }
+                CanisterDiff::Changed {
+                    id,
+                    name,
+                    balance,
+                    stable_memory_size,
+                    stable_memory,
+                } => {
+                    writeln!(f, "~ canister {}", describe(id, name))?;
+
+                    if let Some((before, after)) = balance {
+                        writeln!(f, "    balance: {} -> {}", before, after)?;
+                    }
+
+                    if let Some((before, after)) = stable_memory_size {
+                        writeln!(f, "    stable memory size: {} -> {}", before, after)?;
+                    }
+
+                    for range in stable_memory {
+                        writeln!(
+                            f,
+                            "    stable memory [{:#x}..{:#x}]: {} -> {}",
+                            range.offset,
+                            range.offset + range.before.len() as u64,
+                            hex(&range.before),
+                            hex(&range.after)
+                        )?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn describe(id: &Principal, name: &Option<String>) -> String {
+    match name {
+        Some(name) => format!("{} ({})", name, id),
+        None => id.to_string(),
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut result = bytes
+        .iter()
+        .take(MAX_PRINTED_BYTES)
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+
+    if bytes.len() > MAX_PRINTED_BYTES {
+        result.push_str("...");
+    }
+
+    result
+}
@@ -0,0 +1,142 @@
+//! A portable format for the state of a replica, which is written by [`Replica::dump`] and read by
+//! [`Replica::load`], so the state in which a test failed can be attached to a bug report and
+//! replayed on another machine.
+//!
+//! The state is stored as JSON, with the stable memory of each canister encoded in hex. The heap of
+//! the canisters is not part of the state, the `pre_upgrade` hook of each canister runs before the
+//! dump so it can write its heap to the stable memory, and [`CanisterHandle::init_or_restore`]
+//! runs the `post_upgrade` hook of the canisters that are restored from the dump.
+//!
+//! ```ignore
+//! // In the failing test.
+//! replica.dump("./failure.json").await.unwrap();
+//!
+//! // On another machine.
+//! let replica = Replica::load("./failure.json").unwrap();
+//! let ledger = replica.add_canister(LedgerCanister::anonymous());
+//! ledger.init_or_restore().await.assert_ok();
+//! ```
+//!
+//! [`Replica::dump`]: crate::Replica::dump
+//! [`Replica::load`]: crate::Replica::load
+//! [`CanisterHandle::init_or_restore`]: crate::handle::CanisterHandle::init_or_restore
+
+use std::path::Path;
+use std::{fs, io};
+
+use candid::Principal;
+use serde::{Deserialize, Serialize};
+
+/// The version of the format written by this version of the runtime.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// The state of a replica.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplicaState {
+    /// The version of the format, see [`FORMAT_VERSION`].
+    pub version: u32,
+    /// The time of the replica in nanoseconds since the unix epoch, if it was fixed.
+    pub time: Option<u64>,
+    /// The settings of the replica.
+    pub settings: ReplicaSettings,
+    /// The canisters of the replica, in the order they were added.
+    pub canisters: Vec<CanisterState>,
+}
+
+/// The settings of a replica, which are restored with its state.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplicaSettings {
+    /// If set, the calls are printed, see [`Replica::log_calls`].
+    ///
+    /// [`Replica::log_calls`]: crate::Replica::log_calls
+    pub log_calls: bool,
+}
+
+/// The state of a canister.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CanisterState {
+    /// The id of the canister.
+    pub id: Principal,
+    /// The name given to the canister with [`Replica::name`], if any.
+    ///
+    /// [`Replica::name`]: crate::Replica::name
+    pub name: Option<String>,
+    /// The cycle balance of the canister.
+    pub balance: u128,
+    /// The content of the stable memory of the canister, encoded in hex.
+    pub stable_memory: String,
+}
+
+impl ReplicaState {
+    /// Read the state from the file.
+    pub fn read<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let data = fs::read(path)?;
+        let state: ReplicaState = serde_json::from_slice(&data).map_err(|e| {
+            invalid_data(format!(
+                "Could not parse the replica state in '{}': {}",
+                path.display(),
+                e
+            ))
+        })?;
+
+        if state.version != FORMAT_VERSION {
+            return Err(invalid_data(format!(
+                "The replica state in '{}' has version {}, expected version {}.",
+                path.display(),
+                state.version,
+                FORMAT_VERSION
+            )));
+        }
+
+        Ok(state)
+    }
+
+    /// Write the state to the file.
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let data = serde_json::to_vec_pretty(self).map_err(|e| invalid_data(e.to_string()))?;
+        fs::write(path, data)
+    }
+}
+
+impl CanisterState {
+    /// Create the state of a canister with the given stable memory.
+    pub fn new(id: Principal, name: Option<String>, balance: u128, stable_memory: &[u8]) -> Self {
+        Self {
+            id,
+            name,
+            balance,
+            stable_memory: stable_memory.iter().map(|b| format!("{:02x}", b)).collect(),
+        }
+    }
+
+    /// Decode the stable memory of the canister.
+    pub fn stable_memory(&self) -> io::Result<Vec<u8>> {
+        let pairs = self.stable_memory.as_bytes().chunks_exact(2);
+
+        if !pairs.remainder().is_empty() {
+            return Err(invalid_data(format!(
+                "The stable memory of canister '{}' has an odd number of hex digits.",
+                self.id
+            )));
+        }
+
+        pairs
+            .map(|pair| {
+                std::str::from_utf8(pair)
+                    .ok()
+                    .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                    .ok_or_else(|| {
+                        invalid_data(format!(
+                            "The stable memory of canister '{}' is not valid hex.",
+                            self.id
+                        ))
+                    })
+            })
+            .collect()
+    }
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,231 @@
+//! Systematic exploration of the interleavings of concurrent calls, to find the re-entrancy bugs
+//! which only happen when the messages of a call are processed between the await points of
+//! another one.
+//!
+//! The test is run once for each interleaving, with a new replica, and the messages resulting
+//! from the calls passed to [`Replica::interleave`] are delivered one at a time in a different
+//! order each time. The invariants are checked by the test itself, by panicking when they do not
+//! hold.
+//!
+//! ```ignore
+//! Explorer::new()
+//!     .run(|replica| async move {
+//!         let vault = replica.add_canister(VaultCanister::anonymous());
+//!         vault.new_call("deposit").with_arg(100u64).perform().await;
+//!
+//!         replica
+//!             .interleave(vec![
+//!                 vault.new_call("withdraw").with_arg(100u64),
+//!                 vault.new_call("withdraw").with_arg(100u64),
+//!             ])
+//!             .await;
+//!
+//!         let balance = vault.new_call("balance").perform().await.decode_one::<i64>().unwrap();
+//!         assert!(balance >= 0);
+//!     })
+//!     .assert_ok();
+//! ```
+
+use std::fmt;
+use std::future::Future;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+
+use crate::replica::block_on_replica;
+use crate::Replica;
+
+/// The choices made by [`Replica::interleave`] on the order of the messages.
+#[derive(Default)]
+pub(crate) struct Schedule {
+    /// The index of the message to deliver at each step, the first message is delivered once
+    /// the prefix is used.
+    prefix: Vec<usize>,
+    /// The choices made so far.
+    trace: Vec<Choice>,
+}
+
+struct Choice {
+    /// The index of the delivered message.
+    index: usize,
+    /// The number of messages that could have been delivered.
+    options: usize,
+    /// The description of the delivered message.
+    message: String,
+}
+
+impl Schedule {
+    /// Choose which one of the held messages is delivered next.
+    pub(crate) fn choose(&mut self, pending: Vec<String>) -> usize {
+        let options = pending.len();
+        let index = self
+            .prefix
+            .get(self.trace.len())
+            .copied()
+            .unwrap_or(0)
+            .min(options - 1);
+
+        self.trace.push(Choice {
+            index,
+            options,
+            message: pending.into_iter().nth(index).unwrap(),
+        });
+
+        index
+    }
+
+    /// Return the prefix of the next schedule to explore in depth-first order, or `None` if all
+    /// of them are explored.
+    fn next(&self) -> Option<Vec<usize>> {
+        let step = self
+            .trace
+            .iter()
+            .rposition(|choice| choice.index + 1 < choice.options)?;
+
+        let mut prefix = self.trace[..step]
+            .iter()
+            .map(|choice| choice.index)
+            .collect::<Vec<_>>();
+        prefix.push(self.trace[step].index + 1);
+
+        Some(prefix)
+    }
+}
+
+/// Runs a test once for each interleaving of the calls it passes to [`Replica::interleave`],
+/// until the test panics or all of the interleavings are explored.
+pub struct Explorer {
+    max_schedules: usize,
+}
+
+/// An interleaving of the calls that made the test fail.
+#[derive(Clone, Debug)]
+pub struct InterleavingFailure {
+    /// The messages in the order they were delivered.
+    pub schedule: Vec<String>,
+    /// The panic message of the test.
+    pub message: String,
+}
+
+/// The result of an exploration.
+#[derive(Clone, Debug, Default)]
+pub struct ExploreReport {
+    /// The number of interleavings which were run.
+    pub schedules: usize,
+    /// True if all of the interleavings were run.
+    pub exhausted: bool,
+    /// The first interleaving that made the test fail.
+    pub failure: Option<InterleavingFailure>,
+}
+
+impl Default for Explorer {
+    fn default() -> Self {
+        Self {
+            max_schedules: 1000,
+        }
+    }
+}
+
+impl Explorer {
+    /// Create an explorer which runs up to 1000 interleavings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the maximum number of interleavings to run.
+    pub fn max_schedules(mut self, max_schedules: usize) -> Self {
+        self.max_schedules = max_schedules;
+        self
+    }
+
+    /// Run the test with a new replica for each interleaving, and return the first interleaving
+    /// which made it panic.
+    pub fn run<F, Fut>(&self, test: F) -> ExploreReport
+    where
+        F: Fn(Replica) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let mut report = ExploreReport::default();
+        let mut prefix = Vec::new();
+
+        while report.schedules < self.max_schedules {
+            let schedule = Arc::new(Mutex::new(Schedule {
+                prefix,
+                trace: Vec::new(),
+            }));
+
+            let result = catch_unwind(AssertUnwindSafe(|| {
+                block_on_replica(|mut replica| {
+                    replica.schedule = schedule.clone();
+                    test(replica)
+                })
+            }));
+
+            report.schedules += 1;
+            let schedule = schedule.lock().unwrap();
+
+            if let Err(payload) = result {
+                let message = payload
+                    .downcast_ref::<String>()
+                    .cloned()
+                    .or_else(|| payload.downcast_ref::<&str>().map(|s| s.to_string()))
+                    .unwrap_or_default();
+
+                report.failure = Some(InterleavingFailure {
+                    schedule: schedule.trace.iter().map(|c| c.message.clone()).collect(),
+                    message,
+                });
+
+                break;
+            }
+
+            match schedule.next() {
+                Some(next) => prefix = next,
+                None => {
+                    report.exhausted = true;
+                    break;
+                }
+            }
+        }
+
+        report
+    }
+}
+
+impl ExploreReport {
+    /// Returns true if the test passed for all of the interleavings which were run.
+    pub fn is_ok(&self) -> bool {
+        self.failure.is_none()
+    }
+
+    /// Assert the test passed for all of the interleavings which were run, the panic message
+    /// shows the order of the messages which made it fail.
+    pub fn assert_ok(&self) {
+        assert!(self.is_ok(), "{}", self);
+    }
+}
+
+impl fmt::Display for ExploreReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Explored {} interleavings{}.",
+            self.schedules,
+            if self.exhausted {
+                ", which is all of them"
+            } else {
+                ""
+            }
+        )?;
+
+        if let Some(failure) = &self.failure {
+            writeln!(f, "\nThe test failed with: {}\n", failure.message)?;
+            writeln!(f, "The messages were delivered in this order:")?;
+
+            for (i, message) in failure.schedule.iter().enumerate() {
+                writeln!(f, "  {}. {}", i + 1, message)?;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,181 @@
+//! Fixtures, the async functions which set up the state a test starts from, such as a funded user
+//! or a pool with some liquidity, and which compose by calling each other. Since the same state is
+//! often set up by many tests, [`cached`] builds the state of a fixture once per process, and
+//! restores it in the replica of the following tests instead of running the fixture again.
+//!
+//! ```ignore
+//! async fn funded_user(ledger: &CanisterHandle<'_>, name: &str, amount: u64) -> Principal {
+//!     let user = Identity::ed25519(name).principal();
+//!     ledger.new_call("mint").with_args((user, amount)).perform().await.assert_ok();
+//!     user
+//! }
+//!
+//! async fn pool_with_liquidity(replica: &Replica, amount: u64) -> Pool {
+//!     fixture::cached(replica, &format!("pool_with_liquidity/{}", amount), || async {
+//!         let ledger = replica.add_canister(LedgerCanister::anonymous());
+//!         let dex = replica.add_canister(DexCanister::build(canister_id(2)));
+//!         let alice = funded_user(&ledger, "alice", amount).await;
+//!         dex.new_call("add_liquidity").with_caller(alice).with_arg(amount).perform().await;
+//!         Pool { ledger: ledger.canister_id(), dex: dex.canister_id(), alice }
+//!     })
+//!     .await
+//! }
+//! ```
+//!
+//! The state of a fixture is made of the canisters it added to the replica, with their build,
+//! their balance, their name and their stable memory, and of the time of the replica if it was
+//! fixed. Like with [`Replica::dump`], the `pre_upgrade` hook of the canisters runs once the
+//! fixture is built, so their heap is written to their stable memory, and their `post_upgrade`
+//! hook runs when they are restored. The changes made by the fixture to the canisters which were
+//! already in the replica, such as the mocks of the system canisters, are not part of its state,
+//! so they should be added before the fixture, or by the test itself.
+//!
+//! [`Replica::dump`]: crate::Replica::dump
+
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::sync::Mutex;
+
+use candid::Principal;
+use lazy_static::lazy_static;
+
+use crate::canister::{Canister, CanisterCode};
+use crate::Replica;
+
+/// The state of a fixture, and the value it returned.
+struct CachedFixture {
+    time: Option<u64>,
+    canisters: Vec<CachedCanister>,
+    value: Box<dyn Any + Send>,
+}
+
+/// A canister added by a fixture.
+#[derive(Clone)]
+struct CachedCanister {
+    id: Principal,
+    name: Option<String>,
+    balance: u128,
+    stable_memory: Vec<u8>,
+    code: CanisterCode,
+}
+
+lazy_static! {
+    static ref FIXTURES: Mutex<HashMap<String, CachedFixture>> = Mutex::new(HashMap::new());
+}
+
+/// Run the fixture and cache its state under the given key, or restore the state cached by a
+/// previous test in the replica and return the value the fixture returned then. The key must
+/// identify the fixture and its parameters.
+///
+/// The fixtures which start at once in several tests are each built, and the first state to be
+/// cached is the one restored afterwards. On the PocketIC and the agent backends, the fixture is
+/// built every time.
+///
+/// # Panics
+///
+/// If the fixture was cached under the same key with another type of value, or if the
+/// `pre_upgrade` or the `post_upgrade` hook of a canister fails.
+pub async fn cached<T, F, Fut>(replica: &Replica, key: &str, build: F) -> T
+where
+    T: Clone + Send + 'static,
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = T>,
+{
+    if replica.backend().is_some() {
+        return build().await;
+    }
+
+    let hit = FIXTURES.lock().unwrap().get(key).map(|fixture| {
+        (
+            fixture.time,
+            fixture.canisters.clone(),
+            fixture.value.downcast_ref::<T>().cloned(),
+        )
+    });
+
+    if let Some((time, canisters, value)) = hit {
+        let value = value.unwrap_or_else(|| {
+            panic!(
+                "ic-kit-runtime: The fixture '{}' was cached with another type of value.",
+                key
+            )
+        });
+
+        restore(replica, key, time, canisters).await;
+        return value;
+    }
+
+    let before = replica.canister_ids().into_iter().collect::<HashSet<_>>();
+    let value = build().await;
+
+    let mut canisters = Vec::new();
+    for id in replica.canister_ids() {
+        if before.contains(&id) {
+            continue;
+        }
+
+        let code = match replica.canister_code(id) {
+            Some(code) => code,
+            None => continue,
+        };
+
+        canisters.push(CachedCanister {
+            id,
+            name: replica.given_name(&id),
+            balance: replica.get_canister(id).balance().await,
+            stable_memory: replica.flush_stable_memory(id).await,
+            code,
+        });
+    }
+
+    let fixture = CachedFixture {
+        time: replica.time().await,
+        canisters,
+        value: Box::new(value.clone()),
+    };
+
+    FIXTURES
+        .lock()
+        .unwrap()
+        .entry(key.to_string())
+        .or_insert(fixture);
+
+    value
+}
+
+/// Return true if the state of a fixture is cached under the given key.
+pub fn is_cached(key: &str) -> bool {
+    FIXTURES.lock().unwrap().contains_key(key)
+}
+
+/// Remove the states of all of the fixtures, so they are built again by the next tests.
+pub fn clear() {
+    FIXTURES.lock().unwrap().clear();
+}
+
+/// Add the canisters of the fixture to the replica, with their stable memory, and run their
+/// `post_upgrade` hook.
+async fn restore(replica: &Replica, key: &str, time: Option<u64>, canisters: Vec<CachedCanister>) {
+    if let Some(time) = time {
+        replica.set_time(time);
+    }
+
+    for cached in canisters {
+        let mut canister = Canister::from_code(cached.id, cached.code).with_balance(cached.balance);
+        canister.load_stable(&cached.stable_memory);
+
+        let handle = replica.add_canister(canister);
+
+        if let Some(name) = cached.name {
+            replica.name(cached.id, name);
+        }
+
+        if let Some(e) = handle.post_upgrade().await.rejection_message() {
+            panic!(
+                "ic-kit-runtime: The post_upgrade hook of canister '{}' failed while restoring the fixture '{}': {}",
+                cached.id, key, e
+            );
+        }
+    }
+}
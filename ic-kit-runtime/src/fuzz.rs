@@ -0,0 +1,293 @@
+//! A fuzzer which calls every method of a canister with random arguments generated from its
+//! candid interface, and reports the calls which trap. This module requires the `proptest`
+//! feature.
+//!
+//! ```ignore
+//! #[test]
+//! fn fuzz_counter() {
+//!     Fuzzer::new(CounterCanister::anonymous).run().assert_ok();
+//! }
+//! ```
+
+use std::collections::HashSet;
+use std::fmt;
+
+use candid::{check_prog, IDLProg, TypeEnv};
+use ic_kit_sys::types::RejectionCode;
+use proptest::test_runner::{Config, TestCaseError, TestError, TestRunner};
+
+use crate::prop::candid_args_of;
+use crate::replica::block_on_replica;
+use crate::Canister;
+
+/// Calls the methods of a canister with random arguments, each call is made to a new instance
+/// of the canister. A call fails if the canister traps, the rejections made by the canister
+/// itself are expected responses to the invalid arguments.
+pub struct Fuzzer<F> {
+    build: F,
+    config: Config,
+    skip: HashSet<String>,
+}
+
+/// A call that made the canister trap, with the simplest arguments found which still do.
+#[derive(Clone, Debug)]
+pub struct FuzzFailure {
+    /// The name of the method.
+    pub method: String,
+    /// The arguments of the call as candid text, which can be passed to `with_arg_text`.
+    pub args: String,
+    /// The trap message of the canister.
+    pub message: String,
+}
+
+/// The result of a fuzzer run.
+#[derive(Clone, Debug, Default)]
+pub struct FuzzReport {
+    /// The methods which were called.
+    pub methods: Vec<String>,
+    /// The failures found, at most one for each method.
+    pub failures: Vec<FuzzFailure>,
+}
+
+impl<F: Fn() -> Canister> Fuzzer<F> {
+    /// Create a fuzzer for the canisters created by the function, for example the `anonymous`
+    /// function of a `KitCanister`, which provides the candid interface of the canister.
+    pub fn new(build: F) -> Self {
+        Self {
+            build,
+            config: Config::with_cases(64),
+            skip: HashSet::new(),
+        }
+    }
+
+    /// Use the given proptest config, the number of cases is the number of calls made to each
+    /// of the methods.
+    pub fn with_config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Do not call the method with the given name.
+    pub fn skip<S: Into<String>>(mut self, method: S) -> Self {
+        self.skip.insert(method.into());
+        self
+    }
+
+    /// Call each of the methods of the canister and return the failures found.
+    ///
+    /// # Panics
+    ///
+    /// If the canister does not provide a valid candid interface, or if its init hook fails.
+    pub fn run(&self) -> FuzzReport {
+        let candid = (self.build)()
+            .candid()
+            .map(String::from)
+            .expect("The canister does not provide its candid interface.");
+
+        let prog = candid
+            .parse::<IDLProg>()
+            .unwrap_or_else(|e| panic!("Could not parse the candid interface: {}", e));
+        let mut env = TypeEnv::new();
+        let actor = check_prog(&mut env, &prog)
+            .unwrap_or_else(|e| panic!("Could not check the candid interface: {}", e))
+            .expect("The candid interface does not define a service.");
+        let service = env
+            .as_service(&actor)
+            .expect("The candid interface does not define a service.")
+            .to_vec();
+
+        // Each of the calls would fail if the canister can not be initialized, which is not
+        // caused by the arguments of the calls.
+        let canister = (self.build)();
+        let init =
+            block_on_replica(|replica| async move { replica.add_canister(canister).init().await });
+
+        if init.is_error() {
+            panic!(
+                "The init hook of the canister failed: {}",
+                init.rejection_message().unwrap_or_default()
+            );
+        }
+
+        let mut report = FuzzReport::default();
+
+        for (method, ty) in service {
+            if self.skip.contains(&method) {
+                continue;
+            }
+
+            let types = env.as_func(&ty).unwrap().args.clone();
+            let mut runner = TestRunner::new(Config {
+                failure_persistence: None,
+                ..self.config.clone()
+            });
+
+            let result = runner.run(&candid_args_of(&env, &types), |args| {
+                let arg = args
+                    .to_bytes_with_types(&env, &types)
+                    .expect("Could not encode the generated arguments.");
+
+                self.call(&method, arg).map_err(TestCaseError::fail)
+            });
+
+            match result {
+                Ok(()) => {}
+                Err(TestError::Fail(message, args)) => report.failures.push(FuzzFailure {
+                    method: method.clone(),
+                    args: args.to_string(),
+                    message: message.to_string(),
+                }),
+                Err(e) => panic!("The fuzzer failed on '{}': {}", method, e),
+            }
+
+            report.methods.push(method);
+        }
+
+        report
+    }
+
+    /// Call the method of a new instance of the canister, returns the trap message if it traps.
+    fn call(&self, method: &str, arg: Vec<u8>) -> Result<(), String> {
+        let canister = (self.build)();
+
+        block_on_replica(|replica| async move {
+            let handle = replica.add_canister(canister);
+            let init = handle.init().await;
+            if init.is_error() {
+                return Err(format!(
+                    "The init hook failed: {}",
+                    init.rejection_message().unwrap_or_default()
+                ));
+            }
+
+            let reply = handle.new_call(method).with_arg_raw(arg).perform().await;
+
+            match reply.rejection_code() {
+                RejectionCode::CanisterError => {
+                    Err(reply.rejection_message().unwrap_or_default().to_string())
+                }
+                _ => Ok(()),
+            }
+        })
+    }
+}
+
+impl FuzzReport {
+    /// Returns true if none of the calls trapped.
+    pub fn is_ok(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    /// Assert none of the calls trapped, the panic message lists the failing calls.
+    pub fn assert_ok(&self) {
+        assert!(self.is_ok(), "{}", self);
+    }
+}
+
+impl fmt::Display for FuzzReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Fuzzed {} methods, {} of them trapped.",
+            self.methods.len(),
+            self.failures.len()
+        )?;
+
+        for failure in &self.failures {
+            writeln!(
+                f,
+                "\n{}: {}\n  new_call({:?}).with_arg_text({:?})",
+                failure.method, failure.message, failure.method, failure.args
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CanisterMethod;
+    use ic_kit_sys::ic0;
+
+    fn arg_data() -> Vec<u8> {
+        let size = unsafe { ic0::msg_arg_data_size() };
+        let mut buf = vec![0u8; size as usize];
+        unsafe { ic0::msg_arg_data_copy(buf.as_mut_ptr() as isize, 0, size) };
+        buf
+    }
+
+    fn trap(message: &str) {
+        unsafe { ic0::trap(message.as_ptr() as isize, message.len() as isize) }
+    }
+
+    struct Init;
+    struct Ping;
+    struct Boom;
+
+    /// The init hook traps if its argument is `trap`.
+    impl CanisterMethod for Init {
+        const EXPORT_NAME: &'static str = "canister_init";
+
+        fn exported_method() {
+            if arg_data() == b"trap" {
+                trap("Could not initialize.");
+            }
+        }
+    }
+
+    impl CanisterMethod for Ping {
+        const EXPORT_NAME: &'static str = "canister_query ping";
+
+        fn exported_method() {
+            let reply = candid::encode_args(()).unwrap();
+            unsafe {
+                ic0::msg_reply_data_append(reply.as_ptr() as isize, reply.len() as isize);
+                ic0::msg_reply();
+            }
+        }
+    }
+
+    impl CanisterMethod for Boom {
+        const EXPORT_NAME: &'static str = "canister_update boom";
+
+        fn exported_method() {
+            trap("Boom.");
+        }
+    }
+
+    fn build() -> Canister {
+        Canister::new(crate::replica::canister_id(1))
+            .with_candid("service : { ping : (nat8) -> () query; boom : (text) -> () }")
+            .with_method::<Init>()
+            .with_method::<Ping>()
+            .with_method::<Boom>()
+    }
+
+    #[test]
+    fn report_traps() {
+        let report = Fuzzer::new(build).with_config(Config::with_cases(4)).run();
+
+        assert!(!report.is_ok());
+        assert_eq!(report.methods.len(), 2);
+        assert_eq!(report.failures.len(), 1);
+        assert_eq!(report.failures[0].method, "boom");
+        assert!(report.failures[0].message.contains("Boom."));
+    }
+
+    #[test]
+    fn skip() {
+        Fuzzer::new(build)
+            .with_config(Config::with_cases(4))
+            .skip("boom")
+            .run()
+            .assert_ok();
+    }
+
+    #[test]
+    #[should_panic(expected = "The init hook of the canister failed")]
+    fn init_fails() {
+        Fuzzer::new(|| build().with_init_arg_raw(b"trap".to_vec())).run();
+    }
+}
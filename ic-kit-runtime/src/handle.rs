@@ -1,35 +1,74 @@
 use std::panic::{RefUnwindSafe, UnwindSafe};
+use std::sync::{Arc, Mutex};
 
-use candid::Principal;
+use candid::utils::ArgumentEncoder;
+use candid::{encode_args, encode_one, CandidType, Principal};
+use ic_kit_sys::ic0;
 use tokio::sync::oneshot;
 
 use crate::call::{CallBuilder, CallReply};
-use crate::types::{Env, Message, RequestId};
+use crate::canister::Canister;
+use crate::http::HttpCall;
+use crate::types::{EntryMode, Env, Message};
 use crate::Replica;
 
+/// Whether the execution task of a canister is alive, see [`CanisterHandle::health`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CanisterHealth {
+    /// The canister executes the messages sent to it.
+    Running,
+    /// The execution task of the canister panicked with the given message, which is not a trap of
+    /// the canister but a failure of the runtime, such as a stable memory backend which panicked.
+    /// The messages sent to the canister are rejected until it's removed or reinstalled.
+    Crashed(String),
+}
+
 pub struct CanisterHandle<'a> {
     pub(crate) replica: &'a Replica,
     pub(crate) canister_id: Principal,
+    /// The cycle balance of the canister when it was last read or set through this handle, which
+    /// the balance assertions compare against.
+    pub(crate) last_balance: Mutex<Option<u128>>,
 }
 
 impl<'a> CanisterHandle<'a> {
+    /// Return the id of the canister.
+    pub fn canister_id(&self) -> Principal {
+        self.canister_id
+    }
+
     /// Create a new call builder to call this canister.
     pub fn new_call<S: Into<String>>(&self, method_name: S) -> CallBuilder {
         CallBuilder::new(self.replica, self.canister_id, method_name.into())
     }
 
+    /// Create an HTTP request to this canister, which is served through its `http_request`
+    /// interface like the HTTP gateways do.
+    pub fn http_request(&self, method: &str, url: &str) -> HttpCall<'a> {
+        HttpCall::new(self.replica, self.canister_id, method, url)
+    }
+
+    /// Create a `GET` request to this canister, see [`CanisterHandle::http_request`].
+    pub fn http_get(&self, url: &str) -> HttpCall<'a> {
+        self.http_request("GET", url)
+    }
+
     /// Run the given custom function in the execution thread of the canister.
     pub async fn custom<F: FnOnce() + Send + RefUnwindSafe + UnwindSafe + 'static>(
         &self,
         f: F,
         env: Env,
     ) -> CallReply {
+        if let Some(backend) = self.replica.backend() {
+            crate::backend::unsupported(backend.name(), "Running a custom task in a canister");
+        }
+
         let (tx, rx) = oneshot::channel();
 
         self.replica.enqueue_request(
             self.canister_id,
             Message::CustomTask {
-                request_id: RequestId::new(),
+                request_id: self.replica.counters().next_request_id(),
                 task: Box::new(f),
                 env,
             },
@@ -39,14 +78,311 @@ impl<'a> CanisterHandle<'a> {
         rx.await.unwrap()
     }
 
+    /// Run the function in the execution thread of the canister and return its result.
+    pub async fn run<T, F>(&self, f: F) -> T
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + RefUnwindSafe + UnwindSafe + 'static,
+    {
+        let result = Arc::new(Mutex::new(None));
+        let output = result.clone();
+
+        let reply = self
+            .custom(
+                move || {
+                    *output.lock().unwrap() = Some(f());
+                },
+                Env::default(),
+            )
+            .await;
+
+        let value = result.lock().unwrap().take();
+        value.unwrap_or_else(|| {
+            panic!(
+                "ic-kit-runtime: The task did not complete: {}",
+                reply.rejection_message().unwrap_or_default()
+            )
+        })
+    }
+
+    /// Return whether the execution task of the canister is alive, or the panic it crashed with.
+    ///
+    /// ```ignore
+    /// assert_eq!(ledger.health(), CanisterHealth::Running);
+    /// ```
+    pub fn health(&self) -> CanisterHealth {
+        match self
+            .replica
+            .counters()
+            .crashes
+            .lock()
+            .unwrap()
+            .get(&self.canister_id)
+        {
+            Some(panic) => CanisterHealth::Crashed(panic.clone()),
+            None => CanisterHealth::Running,
+        }
+    }
+
+    /// Panic if the execution task of the canister crashed.
+    pub fn assert_healthy(&self) {
+        if let CanisterHealth::Crashed(panic) = self.health() {
+            panic!(
+                "ic-kit-runtime: The execution task of the canister {} crashed: {}",
+                self.canister_id, panic
+            );
+        }
+    }
+
+    /// Return the largest number of calls made by a single message of the canister so far, the
+    /// calls of a message which trapped are not counted since they were never sent.
+    pub fn max_calls_per_message(&self) -> usize {
+        self.replica
+            .counters()
+            .max_calls_per_message
+            .lock()
+            .unwrap()
+            .get(&self.canister_id)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Panic if a single message of the canister has made more than the given number of calls,
+    /// to make sure a method stays within the limit of the output queue.
+    pub fn assert_max_calls_per_message(&self, max: usize) {
+        let calls = self.max_calls_per_message();
+
+        if calls > max {
+            panic!(
+                "ic-kit-runtime: A message of the canister {} made {} calls, more than the limit of {}.",
+                self.canister_id, calls, max
+            );
+        }
+    }
+
+    /// Return the module hash of the build the canister is running, which changes with its
+    /// upgrades, see [`Canister::module_hash`].
+    pub fn module_hash(&self) -> [u8; 32] {
+        self.replica
+            .module_hash(self.canister_id)
+            .expect("ic-kit-runtime: The canister is not in the replica.")
+    }
+
+    /// Panic if the canister is not running the build with the given module hash.
+    ///
+    /// ```ignore
+    /// let v2 = LedgerCanister::anonymous().with_build_id("ledger-v2");
+    /// let hash = v2.module_hash();
+    /// orchestrator.new_call("upgrade_ledger").perform().await.assert_ok();
+    /// ledger.assert_module_hash(hash);
+    /// ```
+    pub fn assert_module_hash(&self, expected: [u8; 32]) {
+        let hash = self.module_hash();
+
+        if hash != expected {
+            let hex = |hash: [u8; 32]| {
+                hash.iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect::<String>()
+            };
+            panic!(
+                "ic-kit-runtime: The module hash of the canister {} is {}, not {}.",
+                self.canister_id,
+                hex(hash),
+                hex(expected)
+            );
+        }
+    }
+
+    /// Return the data certified by the canister, which is empty until it's set by the canister.
+    pub fn certified_data(&self) -> Vec<u8> {
+        self.replica
+            .counters()
+            .certified_data
+            .lock()
+            .unwrap()
+            .get(&self.canister_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Return the size of the stable memory of the canister in pages.
+    pub async fn stable_size(&self) -> u64 {
+        self.run(|| unsafe { ic0::stable64_size() as u64 }).await
+    }
+
+    /// Read `len` bytes of the stable memory of the canister starting at the given offset, which
+    /// can be used to assert on the data written by the canister, such as in its `pre_upgrade`.
+    ///
+    /// # Panics
+    ///
+    /// If the range is out of the bounds of the stable memory.
+    pub async fn stable_read(&self, offset: u64, len: usize) -> Vec<u8> {
+        let size = self.stable_size().await;
+        assert!(
+            offset + len as u64 <= size << 16,
+            "ic-kit-runtime: Out of bounds read from the stable memory."
+        );
+
+        self.run(move || {
+            let mut bytes = vec![0u8; len];
+            unsafe { ic0::stable64_read(bytes.as_mut_ptr() as i64, offset as i64, len as i64) };
+            bytes
+        })
+        .await
+    }
+
+    /// Write the data to the stable memory of the canister at the given offset, the memory is
+    /// grown if needed. This can be used to seed the stable memory with fixture data before
+    /// running the `post_upgrade` hook, such as the snapshot of a previous version.
+    pub async fn stable_write(&self, offset: u64, data: Vec<u8>) {
+        self.run(move || unsafe {
+            let pages = (offset + data.len() as u64 + (1 << 16) - 1) >> 16;
+            let size = ic0::stable64_size() as u64;
+
+            if pages > size && ic0::stable64_grow((pages - size) as i64) == -1 {
+                panic!("ic-kit-runtime: Could not grow the stable memory.");
+            }
+
+            ic0::stable64_write(offset as i64, data.as_ptr() as i64, data.len() as i64);
+        })
+        .await
+    }
+
+    /// Return the cycle balance of the canister.
+    pub async fn balance(&self) -> u128 {
+        if let Some(backend) = self.replica.backend() {
+            let balance = backend.balance(self.canister_id).await;
+            *self.last_balance.lock().unwrap() = Some(balance);
+            return balance;
+        }
+
+        let balance = self
+            .run(|| unsafe {
+                let mut bytes = [0u8; 16];
+                ic0::canister_cycle_balance128(bytes.as_mut_ptr() as isize);
+                u128::from_le_bytes(bytes)
+            })
+            .await;
+
+        *self.last_balance.lock().unwrap() = Some(balance);
+        balance
+    }
+
+    /// Set the cycle balance of the canister, for example to test how the canister handles
+    /// running low on cycles.
+    pub async fn set_balance(&self, balance: u128) {
+        let before = self.balance().await;
+
+        self.custom(|| {}, Env::default().with_balance(balance))
+            .await;
+
+        self.replica
+            .adjust_balance(self.canister_id, balance as i128 - before as i128);
+
+        *self.last_balance.lock().unwrap() = Some(balance);
+    }
+
+    /// Assert the balance of the canister is the given amount.
+    pub async fn assert_balance(&self, expected: u128) {
+        let balance = self.balance().await;
+        assert_eq!(
+            balance, expected,
+            "The canister '{}' has a balance of {} cycles, expected {}.",
+            self.canister_id, balance, expected
+        );
+    }
+
+    /// Assert the canister spent at most the given amount of cycles since its balance was last
+    /// read or set through this handle, which is useful to test the fees paid by the canister.
+    ///
+    /// ```ignore
+    /// ledger.set_balance(1_000_000).await;
+    /// ledger.new_call("transfer").with_arg(args).perform().await.assert_ok();
+    /// ledger.assert_balance_decreased_by_at_most(10_000).await;
+    /// ```
+    pub async fn assert_balance_decreased_by_at_most(&self, max: u128) {
+        let before = self.last_balance();
+        let balance = self.balance().await;
+        let spent = before.saturating_sub(balance);
+        assert!(
+            spent <= max,
+            "The balance of the canister '{}' decreased by {} cycles, expected at most {}.",
+            self.canister_id,
+            spent,
+            max
+        );
+    }
+
+    /// Assert the balance of the canister decreased by exactly the given amount of cycles since
+    /// it was last read or set through this handle, such as the cycles it forwarded to another
+    /// canister.
+    pub async fn assert_balance_decreased_by(&self, amount: u128) {
+        let before = self.last_balance();
+        let balance = self.balance().await;
+        assert_eq!(
+            before.checked_sub(balance),
+            Some(amount),
+            "The balance of the canister '{}' went from {} to {} cycles, expected a decrease of {}.",
+            self.canister_id,
+            before,
+            balance,
+            amount
+        );
+    }
+
+    /// Assert the balance of the canister increased by exactly the given amount of cycles since
+    /// it was last read or set through this handle, such as the cycles it accepted from a call.
+    pub async fn assert_balance_increased_by(&self, amount: u128) {
+        let before = self.last_balance();
+        let balance = self.balance().await;
+        assert_eq!(
+            balance.checked_sub(before),
+            Some(amount),
+            "The balance of the canister '{}' went from {} to {} cycles, expected an increase of {}.",
+            self.canister_id,
+            before,
+            balance,
+            amount
+        );
+    }
+
+    /// Return the balance last read or set through this handle.
+    fn last_balance(&self) -> u128 {
+        self.last_balance.lock().unwrap().expect(
+            "ic-kit-runtime: The balance must be read or set through the handle before asserting on how it changed.",
+        )
+    }
+
     /// Run the given raw message in the canister's execution thread.
+    ///
+    /// # Panics
+    ///
+    /// If the env is for a callback or a custom task, which do not have an entry point, such
+    /// messages can be run with [`CanisterHandle::custom`].
     pub async fn run_env(&self, env: Env) -> CallReply {
+        assert!(
+            !matches!(
+                env.entry_mode,
+                EntryMode::ReplyCallback
+                    | EntryMode::RejectCallback
+                    | EntryMode::CleanupCallback
+                    | EntryMode::CustomTask
+            ),
+            "ic-kit-runtime: A {:?} message can only be run with CanisterHandle::custom.",
+            env.entry_mode
+        );
+
+        if let Some(backend) = self.replica.backend() {
+            return backend.run_env(self.canister_id, env).await;
+        }
+
         let (tx, rx) = oneshot::channel();
 
         self.replica.enqueue_request(
             self.canister_id,
             Message::Request {
-                request_id: RequestId::new(),
+                request_id: self.replica.counters().next_request_id(),
                 env,
             },
             Some(tx),
@@ -55,10 +391,26 @@ impl<'a> CanisterHandle<'a> {
         rx.await.unwrap()
     }
 
-    /// Runs the init hook of the canister. For more customization use [`CanisterHandle::run_env`]
-    /// with [`Env::init()`].
+    /// Runs the init hook of the canister, with the arguments given to [`Canister::with_init_args`]
+    /// if any. For more customization use [`CanisterHandle::run_env`] with [`Env::init()`].
     pub async fn init(&self) -> CallReply {
-        self.run_env(Env::init()).await
+        let env = match self.replica.init_arg(self.canister_id) {
+            Some(arg) => Env::init().with_raw_args(arg),
+            None => Env::init(),
+        };
+
+        self.run_env(env).await
+    }
+
+    /// Runs the post_upgrade hook of the canister if its state was loaded from the state directory
+    /// of the replica or from a dump, otherwise runs the init hook. See
+    /// [`Replica::with_state_dir`] and [`Replica::load`].
+    pub async fn init_or_restore(&self) -> CallReply {
+        if self.replica.is_restored(self.canister_id) {
+            self.post_upgrade().await
+        } else {
+            self.init().await
+        }
     }
 
     /// Runs the pre_upgrade hook of the canister. For more customization use
@@ -73,9 +425,153 @@ impl<'a> CanisterHandle<'a> {
         self.run_env(Env::post_upgrade()).await
     }
 
-    /// Runs the post_upgrade hook of the canister. For more customization use
+    /// Runs the heartbeat of the canister. For more customization use
     /// [`CanisterHandle::run_env`] with [`Env::heartbeat()`].
     pub async fn heartbeat(&self) -> CallReply {
         self.run_env(Env::heartbeat()).await
     }
+
+    /// Runs the global timer of the canister. For more customization use
+    /// [`CanisterHandle::run_env`] with [`Env::global_timer()`].
+    pub async fn global_timer(&self) -> CallReply {
+        self.run_env(Env::global_timer()).await
+    }
+
+    /// Create a copy of the canister with the given id, running the same build with a copy of its
+    /// stable memory and its cycle balance, so the tests can branch several scenarios from a
+    /// canister whose setup is expensive. The pre_upgrade hook of the canister runs first so its
+    /// heap is written to its stable memory, and the post_upgrade hook of the copy restores it,
+    /// like an upgrade does. The canister keeps running afterwards.
+    ///
+    /// ```ignore
+    /// let ledger = replica.add_canister(LedgerCanister::anonymous());
+    /// mint_many_accounts(&ledger).await;
+    ///
+    /// let a = ledger.fork(canister_id(10)).await;
+    /// let b = ledger.fork(canister_id(11)).await;
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// If there is already a canister with the given id, or if the pre_upgrade hook of the
+    /// canister or the post_upgrade hook of the copy fails.
+    pub async fn fork<T: Into<Principal>>(&self, canister_id: T) -> CanisterHandle<'a> {
+        if let Some(backend) = self.replica.backend() {
+            crate::backend::unsupported(backend.name(), "Forking a canister");
+        }
+
+        let mut canister = self
+            .replica
+            .fork_canister(self.canister_id, canister_id.into());
+
+        if let Some(e) = self.pre_upgrade().await.rejection_message() {
+            panic!(
+                "ic-kit-runtime: The pre_upgrade hook of canister '{}' failed: {}",
+                self.canister_id, e
+            );
+        }
+
+        let size = self.stable_size().await;
+        let data = self.stable_read(0, (size << 16) as usize).await;
+        let balance = self.balance().await;
+
+        canister.load_stable(&data);
+        let handle = self.replica.add_canister(canister.with_balance(balance));
+
+        if let Some(e) = handle.post_upgrade().await.rejection_message() {
+            panic!(
+                "ic-kit-runtime: The post_upgrade hook of the fork '{}' of canister '{}' failed: {}",
+                handle.canister_id, self.canister_id, e
+            );
+        }
+
+        handle
+    }
+
+    /// Wipe the state of the canister, by replacing it with a new instance of the build it's
+    /// running, whose heap and stable memory are empty, and run its init hook with the arguments
+    /// given to [`Canister::with_init_args`], like a reinstall does on the IC. The cycle balance and
+    /// the name of the canister are kept, and the other canisters of the replica are not affected,
+    /// so a long running session, such as a REPL, can start a canister over without starting a new
+    /// replica.
+    ///
+    /// Returns the reply of the init hook.
+    ///
+    /// ```ignore
+    /// ledger.new_call("mint").with_args((alice, 100u64)).perform().await;
+    /// ledger.reset().await.assert_ok();
+    /// let balance = ledger.new_call("balance").with_arg(alice).perform().await;
+    /// assert_eq!(balance.decode_one::<u64>().unwrap(), 0);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// If the canister is not in the replica.
+    pub async fn reset(&self) -> CallReply {
+        if let Some(backend) = self.replica.backend() {
+            crate::backend::unsupported(backend.name(), "Resetting a canister");
+        }
+
+        let balance = self.balance().await;
+        self.replica.reset_canister(self.canister_id, balance);
+        self.init().await
+    }
+
+    /// Upgrade the canister to the given build, which must have the same id. The pre_upgrade
+    /// hook of the current build runs first, then the stable memory and the cycle balance are
+    /// moved to the new build, and its post_upgrade hook runs with the given arguments. The heap
+    /// of the canister is lost, like it is on the IC.
+    ///
+    /// Returns the reply of the post_upgrade hook, or of the pre_upgrade hook if it failed, in
+    /// which case the canister keeps running the current build.
+    ///
+    /// ```ignore
+    /// let ledger = replica.add_canister(LedgerV1::anonymous());
+    /// // ...
+    /// ledger.upgrade(LedgerV2::anonymous(), ()).await.assert_ok();
+    /// ```
+    pub async fn upgrade<T: ArgumentEncoder>(&self, canister: Canister, args: T) -> CallReply {
+        let arg = encode_args(args).expect("Failed to encode arguments.");
+        self.upgrade_raw(canister, arg).await
+    }
+
+    /// Upgrade the canister to the given build, passing the upgrade argument to its post_upgrade
+    /// hook, such as the parameters of a migration, see [`CanisterHandle::upgrade`].
+    ///
+    /// ```ignore
+    /// let arg = MigrationArg { fee: 10 };
+    /// ledger.upgrade_with_arg(LedgerV2::anonymous(), arg).await.assert_ok();
+    /// ```
+    pub async fn upgrade_with_arg<T: CandidType>(&self, canister: Canister, arg: T) -> CallReply {
+        let arg = encode_one(arg).expect("Failed to encode argument.");
+        self.upgrade_raw(canister, arg).await
+    }
+
+    /// Upgrade the canister to the given build, passing the raw arguments to its post_upgrade
+    /// hook, see [`CanisterHandle::upgrade`].
+    pub async fn upgrade_raw(&self, mut canister: Canister, arg: Vec<u8>) -> CallReply {
+        assert_eq!(
+            canister.id(),
+            self.canister_id,
+            "ic-kit-runtime: The new build of the canister must have the same id."
+        );
+
+        if let Some(backend) = self.replica.backend() {
+            return backend.upgrade(canister, arg).await;
+        }
+
+        let reply = self.pre_upgrade().await;
+        if reply.is_error() {
+            return reply;
+        }
+
+        let size = self.stable_size().await;
+        let data = self.stable_read(0, (size << 16) as usize).await;
+        let balance = self.balance().await;
+
+        canister.load_stable(&data);
+        let handle = self.replica.install(canister.with_balance(balance), true);
+
+        handle.run_env(Env::post_upgrade().with_raw_args(arg)).await
+    }
 }
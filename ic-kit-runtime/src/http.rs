@@ -0,0 +1,303 @@
+//! A simulation of the HTTP gateway, which serves the requests with the `http_request` interface of
+//! a canister, see [`CanisterHandle::http_request`].
+//!
+//! Like the gateways, the request is first sent to the `http_request` query, and again to the
+//! `http_request_update` update method if the response asks for an upgrade, and the rest of a
+//! streamed body is fetched from the streaming callback.
+//!
+//! ```ignore
+//! let reply = counter.http_get("/counter?format=json").perform().await;
+//! reply.assert_status(200);
+//! assert_eq!(reply.text(), "{\"count\":1}");
+//! ```
+//!
+//! The response certification is not verified.
+//!
+//! [`CanisterHandle::http_request`]: crate::handle::CanisterHandle::http_request
+
+use candid::parser::value::{IDLField, IDLValue};
+use candid::{encode_one, idl_hash, CandidType, IDLArgs, Principal};
+
+use crate::call::{CallBuilder, CallReply};
+use crate::Replica;
+
+/// The maximum number of chunks fetched from a streaming callback, so a callback which never
+/// returns the last chunk fails the request instead of looping forever.
+const MAX_STREAMING_CHUNKS: usize = 4096;
+
+/// The request as encoded by the gateway.
+#[derive(CandidType)]
+struct HttpRequest {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    certificate_version: Option<u16>,
+}
+
+/// An HTTP request to a canister, sent through the simulated gateway.
+pub struct HttpCall<'a> {
+    replica: &'a Replica,
+    canister_id: Principal,
+    sender: Principal,
+    request: HttpRequest,
+}
+
+/// The response of the canister to an [`HttpCall`], after the gateway has upgraded the call and
+/// fetched the streamed body if needed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HttpReply {
+    /// The status code of the response.
+    pub status_code: u16,
+    /// The headers of the response.
+    pub headers: Vec<(String, String)>,
+    /// The whole body of the response.
+    pub body: Vec<u8>,
+    /// If the request was upgraded to an update call.
+    pub upgraded: bool,
+}
+
+impl<'a> HttpCall<'a> {
+    pub(crate) fn new(
+        replica: &'a Replica,
+        canister_id: Principal,
+        method: &str,
+        url: &str,
+    ) -> Self {
+        Self {
+            replica,
+            canister_id,
+            sender: Principal::anonymous(),
+            request: HttpRequest {
+                method: method.to_string(),
+                url: url.to_string(),
+                headers: vec![],
+                body: vec![],
+                certificate_version: None,
+            },
+        }
+    }
+
+    /// Add a header to the request.
+    pub fn with_header<N: Into<String>, V: Into<String>>(mut self, name: N, value: V) -> Self {
+        self.request.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Set the body of the request.
+    pub fn with_body<B: Into<Vec<u8>>>(mut self, body: B) -> Self {
+        self.request.body = body.into();
+        self
+    }
+
+    /// Make the calls to the canister as the given principal instead of the anonymous principal,
+    /// which the gateways use.
+    pub fn with_caller<I: Into<Principal>>(mut self, caller: I) -> Self {
+        self.sender = caller.into();
+        self
+    }
+
+    /// Send the request and return the response of the canister. A rejected call or a response
+    /// which can't be decoded is returned as a `500 Internal Server Error` with the error in the
+    /// body, as the gateways do.
+    pub async fn perform(&self) -> HttpReply {
+        match self.serve().await {
+            Ok(reply) => reply,
+            Err(message) => HttpReply {
+                status_code: 500,
+                headers: vec![],
+                body: message.into_bytes(),
+                upgraded: false,
+            },
+        }
+    }
+
+    async fn serve(&self) -> Result<HttpReply, String> {
+        let arg = encode_one(&self.request).unwrap();
+
+        let mut response = self
+            .call(self.canister_id, "http_request", arg.clone())
+            .await?;
+        let mut upgraded = false;
+
+        if get(&response, "upgrade").and_then(opt) == Some(&IDLValue::Bool(true)) {
+            response = self
+                .call(self.canister_id, "http_request_update", arg)
+                .await?;
+            upgraded = true;
+        }
+
+        let status_code = match get(&response, "status_code") {
+            Some(IDLValue::Nat16(code)) => *code,
+            _ => return Err(invalid("status_code")),
+        };
+
+        let headers = match get(&response, "headers") {
+            Some(IDLValue::Vec(headers)) => headers
+                .iter()
+                .map(header)
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| invalid("headers"))?,
+            _ => return Err(invalid("headers")),
+        };
+
+        let mut body = blob(get(&response, "body")).ok_or_else(|| invalid("body"))?;
+
+        if let Some(strategy) = get(&response, "streaming_strategy").and_then(opt) {
+            self.stream(strategy, &mut body).await?;
+        }
+
+        Ok(HttpReply {
+            status_code,
+            headers,
+            body,
+            upgraded,
+        })
+    }
+
+    /// Fetch the rest of the body from the streaming callback, until it does not return a token.
+    async fn stream(&self, strategy: &IDLValue, body: &mut Vec<u8>) -> Result<(), String> {
+        let callback = match strategy {
+            IDLValue::Variant(variant) if variant.0.id.get_id() == idl_hash("Callback") => {
+                &variant.0.val
+            }
+            _ => return Err(invalid("streaming_strategy")),
+        };
+
+        let (principal, method) = match get(callback, "callback") {
+            Some(IDLValue::Func(principal, method)) => (*principal, method.clone()),
+            _ => return Err(invalid("streaming_strategy")),
+        };
+
+        let mut token = get(callback, "token")
+            .cloned()
+            .ok_or_else(|| invalid("streaming_strategy"))?;
+
+        for _ in 0..MAX_STREAMING_CHUNKS {
+            let arg = IDLArgs::new(&[token])
+                .to_bytes()
+                .map_err(|e| format!("The streaming token could not be encoded: {}", e))?;
+
+            let response = self.call(principal, &method, arg).await?;
+            body.extend(blob(get(&response, "body")).ok_or_else(|| invalid("body"))?);
+
+            match get(&response, "token").and_then(opt) {
+                Some(next) => token = next.clone(),
+                None => return Ok(()),
+            }
+        }
+
+        Err(format!(
+            "The streaming callback did not return the last chunk after {} chunks.",
+            MAX_STREAMING_CHUNKS
+        ))
+    }
+
+    async fn call(
+        &self,
+        canister_id: Principal,
+        method: &str,
+        arg: Vec<u8>,
+    ) -> Result<IDLValue, String> {
+        let reply = CallBuilder::new(self.replica, canister_id, method.to_string())
+            .with_caller(self.sender)
+            .with_arg_raw(arg)
+            .perform()
+            .await;
+
+        match reply {
+            CallReply::Reply { data, .. } => IDLArgs::from_bytes(&data)
+                .ok()
+                .and_then(|args| args.args.into_iter().next())
+                .ok_or_else(|| format!("The response of '{}' could not be decoded.", method)),
+            CallReply::Reject {
+                rejection_code,
+                rejection_message,
+                ..
+            } => Err(format!(
+                "The call to '{}' was rejected ({:?}): {}",
+                method, rejection_code, rejection_message
+            )),
+        }
+    }
+}
+
+impl HttpReply {
+    /// Return the value of the first header with the given name, which is case insensitive.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Return the body as text, replacing the invalid UTF-8 sequences.
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+
+    /// Assert that the response has the given status code.
+    #[track_caller]
+    pub fn assert_status(&self, status_code: u16) {
+        assert_eq!(
+            self.status_code,
+            status_code,
+            "Expected the status {} but the response was {}: {}",
+            status_code,
+            self.status_code,
+            self.text()
+        );
+    }
+}
+
+fn invalid(field: &str) -> String {
+    format!("The response has an invalid '{}' field.", field)
+}
+
+/// Return the field of a record with the given name.
+fn get<'v>(value: &'v IDLValue, name: &str) -> Option<&'v IDLValue> {
+    match value {
+        IDLValue::Record(fields) => fields
+            .iter()
+            .find(|field| field.id.get_id() == idl_hash(name))
+            .map(|field| &field.val),
+        _ => None,
+    }
+}
+
+/// Return the value of an option, or `None` if it's not set.
+fn opt(value: &IDLValue) -> Option<&IDLValue> {
+    match value {
+        IDLValue::Opt(value) => Some(value),
+        _ => None,
+    }
+}
+
+fn blob(value: Option<&IDLValue>) -> Option<Vec<u8>> {
+    match value? {
+        IDLValue::Vec(bytes) => bytes
+            .iter()
+            .map(|byte| match byte {
+                IDLValue::Nat8(byte) => Some(*byte),
+                _ => None,
+            })
+            .collect(),
+        _ => None,
+    }
+}
+
+fn header(value: &IDLValue) -> Option<(String, String)> {
+    match value {
+        IDLValue::Record(fields) => match fields.as_slice() {
+            [IDLField {
+                val: IDLValue::Text(name),
+                ..
+            }, IDLField {
+                val: IDLValue::Text(value),
+                ..
+            }] => Some((name.clone(), value.clone())),
+            _ => None,
+        },
+        _ => None,
+    }
+}
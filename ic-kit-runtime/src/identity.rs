@@ -0,0 +1,171 @@
+//! Test identities with ed25519 or secp256k1 keys, whose principals are derived from their public
+//! keys like the ones of the users of the Internet Computer.
+//!
+//! The keys are derived from a seed, so an identity is the same in every run of the tests:
+//!
+//! ```ignore
+//! let alice = Identity::ed25519("alice");
+//! let bob = Identity::secp256k1("bob");
+//!
+//! counter.new_call("increment").with_caller(&alice).perform().await.assert_ok();
+//! assert!(alice.verify(b"message", &alice.sign(b"message")));
+//! ```
+
+use candid::Principal;
+use ed25519_dalek::{Signer as _, Verifier as _};
+use sha2::{Digest, Sha256};
+
+/// The DER prefix of an ed25519 public key, the key follows it.
+const ED25519_DER_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+
+/// The DER prefix of an uncompressed secp256k1 public key, the key follows it.
+const SECP256K1_DER_PREFIX: [u8; 23] = [
+    0x30, 0x56, 0x30, 0x10, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x05, 0x2b,
+    0x81, 0x04, 0x00, 0x0a, 0x03, 0x42, 0x00,
+];
+
+/// The algorithm of the key of an identity.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum KeyType {
+    Ed25519,
+    Secp256k1,
+}
+
+/// An identity holding a private key, whose principal is self-authenticating.
+#[derive(Clone)]
+pub struct Identity {
+    key: Key,
+    principal: Principal,
+}
+
+#[derive(Clone)]
+enum Key {
+    Ed25519(ed25519_dalek::SigningKey),
+    Secp256k1(k256::ecdsa::SigningKey),
+}
+
+impl Identity {
+    /// Create an identity with an ed25519 key derived from the seed.
+    pub fn ed25519(seed: &str) -> Self {
+        Self::ed25519_from_secret(derive_secret(KeyType::Ed25519, seed))
+    }
+
+    /// Create an identity with a secp256k1 key derived from the seed.
+    pub fn secp256k1(seed: &str) -> Self {
+        Self::secp256k1_from_secret(derive_secret(KeyType::Secp256k1, seed))
+    }
+
+    /// Create an identity with the given ed25519 private key.
+    pub fn ed25519_from_secret(secret: [u8; 32]) -> Self {
+        Self::new(Key::Ed25519(ed25519_dalek::SigningKey::from_bytes(&secret)))
+    }
+
+    /// Create an identity with the given secp256k1 private key.
+    ///
+    /// # Panics
+    ///
+    /// If the bytes are not a valid secp256k1 private key.
+    pub fn secp256k1_from_secret(secret: [u8; 32]) -> Self {
+        let key = k256::ecdsa::SigningKey::from_slice(&secret)
+            .expect("ic-kit-runtime: Invalid secp256k1 private key.");
+        Self::new(Key::Secp256k1(key))
+    }
+
+    fn new(key: Key) -> Self {
+        let principal = Principal::self_authenticating(public_key_der(&key));
+        Self { key, principal }
+    }
+
+    /// Return the algorithm of the key of the identity.
+    pub fn key_type(&self) -> KeyType {
+        match &self.key {
+            Key::Ed25519(_) => KeyType::Ed25519,
+            Key::Secp256k1(_) => KeyType::Secp256k1,
+        }
+    }
+
+    /// Return the self-authenticating principal of the identity.
+    pub fn principal(&self) -> Principal {
+        self.principal
+    }
+
+    /// Return the DER encoded public key of the identity, which is the public key sent with the
+    /// messages signed by the identity.
+    pub fn public_key_der(&self) -> Vec<u8> {
+        public_key_der(&self.key)
+    }
+
+    /// Sign the message, an ed25519 signature or a secp256k1 signature of the SHA-256 of the
+    /// message, in the 64 bytes format used by the Internet Computer.
+    pub fn sign(&self, message: &[u8]) -> Vec<u8> {
+        match &self.key {
+            Key::Ed25519(key) => key.sign(message).to_bytes().to_vec(),
+            Key::Secp256k1(key) => {
+                let signature: k256::ecdsa::Signature = key.sign(message);
+                signature.to_bytes().to_vec()
+            }
+        }
+    }
+
+    /// Return true if the signature of the message was made by the identity.
+    pub fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        match &self.key {
+            Key::Ed25519(key) => ed25519_dalek::Signature::from_slice(signature)
+                .map(|signature| key.verifying_key().verify(message, &signature).is_ok())
+                .unwrap_or(false),
+            Key::Secp256k1(key) => k256::ecdsa::Signature::from_slice(signature)
+                .map(|signature| key.verifying_key().verify(message, &signature).is_ok())
+                .unwrap_or(false),
+        }
+    }
+}
+
+impl std::fmt::Debug for Identity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Identity")
+            .field("key_type", &self.key_type())
+            .field("principal", &self.principal.to_text())
+            .finish()
+    }
+}
+
+impl From<&Identity> for Principal {
+    fn from(identity: &Identity) -> Self {
+        identity.principal
+    }
+}
+
+impl From<Identity> for Principal {
+    fn from(identity: Identity) -> Self {
+        identity.principal
+    }
+}
+
+/// Derive a private key from the seed, the key type is part of the hash so the identities with
+/// the same seed and different key types are unrelated.
+fn derive_secret(key_type: KeyType, seed: &str) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(match key_type {
+        KeyType::Ed25519 => b"ic-kit-runtime:ed25519:".as_slice(),
+        KeyType::Secp256k1 => b"ic-kit-runtime:secp256k1:".as_slice(),
+    });
+    hasher.update(seed.as_bytes());
+    hasher.finalize().into()
+}
+
+fn public_key_der(key: &Key) -> Vec<u8> {
+    match key {
+        Key::Ed25519(key) => {
+            let mut der = ED25519_DER_PREFIX.to_vec();
+            der.extend_from_slice(key.verifying_key().as_bytes());
+            der
+        }
+        Key::Secp256k1(key) => {
+            let mut der = SECP256K1_DER_PREFIX.to_vec();
+            der.extend_from_slice(key.verifying_key().to_encoded_point(false).as_bytes());
+            der
+        }
+    }
+}
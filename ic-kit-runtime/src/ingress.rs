@@ -0,0 +1,123 @@
+//! The ingress history of the replica, which keeps the status of each call made by a user under
+//! its request id, through the same lifecycle as on the Internet Computer:
+//!
+//! ```text
+//! received -> processing -> replied | rejected -> done
+//! ```
+//!
+//! A call is `received` once it's queued for its canister, and `processing` once the canister
+//! starts to execute it, so the calls held by a paused replica stay `received`. A call rejected
+//! before it's executed, such as a call to a removed canister, goes to `rejected` directly. The
+//! replies are kept until the ingress expiry of the calls passes, simulated by
+//! [`Replica::expire_ingress_history`], after which the calls are `done` and their replies are
+//! dropped, so the clients which poll for the reply of a call can be tested against each status:
+//!
+//! ```ignore
+//! let call = ledger.new_call("transfer").with_args((bob, 10u64));
+//! let request_id = call.request_id();
+//!
+//! replica.pause();
+//! let reply = call.send();
+//! assert_eq!(replica.request_status(&request_id), Some(RequestStatus::Received));
+//!
+//! replica.resume();
+//! reply.await.assert_ok();
+//! assert!(matches!(replica.request_status(&request_id), Some(RequestStatus::Replied(_))));
+//!
+//! replica.expire_ingress_history();
+//! assert_eq!(replica.request_status(&request_id), Some(RequestStatus::Done));
+//! ```
+//!
+//! The request id of a call does not depend on a nonce, see [`CallBuilder::request_id`], so the
+//! same call made again starts a new history under the same request id.
+//!
+//! [`Replica::expire_ingress_history`]: crate::Replica::expire_ingress_history
+//! [`CallBuilder::request_id`]: crate::call::CallBuilder::request_id
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::call::CallReply;
+pub use crate::certification::RequestStatus;
+use crate::types::RequestId;
+
+/// The statuses of the calls made by the users, shared with the canisters of a replica.
+#[derive(Default)]
+pub(crate) struct IngressHistory {
+    /// The request id of each call which is not replied to yet, by the id of its message.
+    ids: Mutex<HashMap<RequestId, [u8; 32]>>,
+    /// The statuses each call went through, by its request id.
+    statuses: Mutex<HashMap<[u8; 32], Vec<RequestStatus>>>,
+}
+
+impl IngressHistory {
+    /// Record a call which was queued for its canister.
+    pub fn received(&self, id: RequestId, request_id: [u8; 32]) {
+        self.ids.lock().unwrap().insert(id, request_id);
+        self.statuses
+            .lock()
+            .unwrap()
+            .insert(request_id, vec![RequestStatus::Received]);
+    }
+
+    /// Record that the canister started to execute the message, if it's a call made by a user.
+    pub fn processing(&self, id: RequestId) {
+        if let Some(request_id) = self.ids.lock().unwrap().get(&id) {
+            self.push(*request_id, RequestStatus::Processing);
+        }
+    }
+
+    /// Record the reply to a call made by a user.
+    pub fn completed(&self, id: RequestId, reply: &CallReply) {
+        if let Some(request_id) = self.ids.lock().unwrap().remove(&id) {
+            self.push(request_id, RequestStatus::of(reply));
+        }
+    }
+
+    /// Mark the calls which were replied to as done, and drop their replies.
+    pub fn expire(&self) {
+        for history in self.statuses.lock().unwrap().values_mut() {
+            if let Some(RequestStatus::Replied(_) | RequestStatus::Rejected(..)) = history.last() {
+                history.push(RequestStatus::Done);
+            }
+        }
+    }
+
+    /// Return the current status of the call with the given request id.
+    pub fn status(&self, request_id: &[u8; 32]) -> Option<RequestStatus> {
+        self.statuses
+            .lock()
+            .unwrap()
+            .get(request_id)
+            .and_then(|history| history.last().cloned())
+    }
+
+    /// Return the statuses the call with the given request id went through, in order.
+    pub fn history(&self, request_id: &[u8; 32]) -> Vec<RequestStatus> {
+        self.statuses
+            .lock()
+            .unwrap()
+            .get(request_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Return the current status of each call.
+    pub fn statuses(&self) -> Vec<([u8; 32], RequestStatus)> {
+        self.statuses
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(request_id, history)| Some((*request_id, history.last()?.clone())))
+            .collect()
+    }
+
+    fn push(&self, request_id: [u8; 32], status: RequestStatus) {
+        self.statuses
+            .lock()
+            .unwrap()
+            .entry(request_id)
+            .or_default()
+            .push(status);
+    }
+}
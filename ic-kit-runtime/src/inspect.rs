@@ -0,0 +1,267 @@
+//! A local HTTP endpoint to observe a replica from outside of the test, enabled with the
+//! `inspector` feature, so external tools can follow a long-running development session.
+//!
+//! The endpoint only answers `GET` requests, with JSON:
+//!
+//! - `/` the time of the replica, whether it's paused and the number of messages in flight.
+//! - `/canisters` the id, name, balance and number of stable memory pages of each canister.
+//! - `/queues` the messages held by a paused replica, see [`Replica::pause`].
+//! - `/calls` the calls which are not resolved yet, and the number of calls of each method.
+//!
+//! The inspector borrows the replica, so it's served next to the session:
+//!
+//! ```ignore
+//! let inspector = replica.inspector("127.0.0.1:8090").await.unwrap();
+//!
+//! tokio::select! {
+//!     _ = inspector.serve() => {}
+//!     _ = run_session(&replica) => {}
+//! }
+//! ```
+//!
+//! ```text
+//! $ curl localhost:8090/canisters
+//! [{"id":"rrkah-fqaaa-aaaaa-aaaaq-cai","name":"ledger","balance":100000000000000,"stable_memory_pages":1}]
+//! ```
+//!
+//! [`Replica::pause`]: crate::Replica::pause
+
+use std::io;
+use std::net::SocketAddr;
+
+use candid::Principal;
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+use crate::Replica;
+
+/// The maximum size of the head of a request.
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+/// A local HTTP endpoint exposing the state of a replica, created by [`Replica::inspector`].
+pub struct Inspector<'a> {
+    replica: &'a Replica,
+    listener: TcpListener,
+}
+
+#[derive(Serialize)]
+struct Overview {
+    time: Option<u64>,
+    paused: bool,
+    in_flight: usize,
+    canisters: usize,
+}
+
+#[derive(Serialize)]
+struct CanisterView {
+    id: Principal,
+    name: Option<String>,
+    balance: u128,
+    stable_memory_pages: u64,
+}
+
+#[derive(Serialize)]
+struct QueuesView {
+    paused: bool,
+    in_flight: usize,
+    pending: Vec<PendingView>,
+}
+
+#[derive(Serialize)]
+struct PendingView {
+    canister_id: Principal,
+    entry_mode: String,
+    sender: Option<Principal>,
+    method: Option<String>,
+    arg: String,
+    cycles: u128,
+    description: String,
+}
+
+#[derive(Serialize)]
+struct CallsView {
+    unresolved: Vec<UnresolvedView>,
+    methods: Vec<MethodView>,
+}
+
+#[derive(Serialize)]
+struct UnresolvedView {
+    sender: Principal,
+    callee: Principal,
+    method: String,
+    origin: Option<String>,
+    dropped: bool,
+}
+
+#[derive(Serialize)]
+struct MethodView {
+    canister_id: Principal,
+    method: String,
+    calls: u64,
+}
+
+impl<'a> Inspector<'a> {
+    pub(crate) async fn bind<A: ToSocketAddrs>(replica: &'a Replica, addr: A) -> io::Result<Self> {
+        Ok(Self {
+            replica,
+            listener: TcpListener::bind(addr).await?,
+        })
+    }
+
+    /// Return the address the endpoint listens on, such as the port chosen for `127.0.0.1:0`.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Answer the requests one at a time, until the future is dropped or the listener fails.
+    pub async fn serve(&self) -> io::Result<()> {
+        loop {
+            let (stream, _) = self.listener.accept().await?;
+
+            // A client which goes away does not stop the endpoint.
+            let _ = self.answer(stream).await;
+        }
+    }
+
+    async fn answer(&self, mut stream: TcpStream) -> io::Result<()> {
+        let mut head = Vec::new();
+        let mut buffer = [0u8; 1024];
+
+        while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+            let n = stream.read(&mut buffer).await?;
+
+            if n == 0 || head.len() + n > MAX_REQUEST_SIZE {
+                return Ok(());
+            }
+
+            head.extend_from_slice(&buffer[..n]);
+        }
+
+        let head = String::from_utf8_lossy(&head);
+        let mut line = head.lines().next().unwrap_or_default().split(' ');
+        let method = line.next().unwrap_or_default();
+        let path = line.next().unwrap_or_default();
+        let path = path.split('?').next().unwrap_or_default();
+
+        let (status, body) = if method != "GET" {
+            (
+                "405 Method Not Allowed",
+                error("Only GET requests are supported."),
+            )
+        } else {
+            match path {
+                "/" => ("200 OK", to_json(&self.overview().await)),
+                "/canisters" => ("200 OK", to_json(&self.canisters().await)),
+                "/queues" => ("200 OK", to_json(&self.queues().await)),
+                "/calls" => ("200 OK", to_json(&self.calls())),
+                _ => ("404 Not Found", error("Unknown path.")),
+            }
+        };
+
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nAccess-Control-Allow-Origin: *\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+
+        stream.write_all(response.as_bytes()).await?;
+        stream.shutdown().await
+    }
+
+    async fn overview(&self) -> Overview {
+        Overview {
+            time: self.replica.time().await,
+            paused: self.replica.is_paused(),
+            in_flight: self.replica.in_flight(),
+            canisters: self.replica.canister_ids().len(),
+        }
+    }
+
+    async fn canisters(&self) -> Vec<CanisterView> {
+        let mut canisters = Vec::new();
+
+        for id in self.replica.canister_ids() {
+            let canister = self.replica.get_canister(id);
+
+            canisters.push(CanisterView {
+                id,
+                name: self.replica.given_name(&id),
+                balance: canister.balance().await,
+                stable_memory_pages: canister.stable_size().await,
+            });
+        }
+
+        canisters
+    }
+
+    async fn queues(&self) -> QueuesView {
+        let pending = self
+            .replica
+            .pending_messages()
+            .await
+            .into_iter()
+            .map(|message| PendingView {
+                canister_id: message.canister_id,
+                entry_mode: format!("{:?}", message.entry_mode),
+                sender: message.sender,
+                method: message.method,
+                arg: message.arg.iter().map(|b| format!("{:02x}", b)).collect(),
+                cycles: message.cycles,
+                description: message.description,
+            })
+            .collect();
+
+        QueuesView {
+            paused: self.replica.is_paused(),
+            in_flight: self.replica.in_flight(),
+            pending,
+        }
+    }
+
+    fn calls(&self) -> CallsView {
+        let unresolved = self
+            .replica
+            .unresolved_calls()
+            .into_iter()
+            .map(|call| UnresolvedView {
+                sender: call.sender,
+                callee: call.callee,
+                method: call.method,
+                origin: call.origin,
+                dropped: call.dropped,
+            })
+            .collect();
+
+        let methods = self
+            .replica
+            .coverage()
+            .canisters
+            .into_iter()
+            .flat_map(|canister| {
+                canister
+                    .methods
+                    .into_iter()
+                    .map(move |(method, calls)| MethodView {
+                        canister_id: canister.canister_id,
+                        method,
+                        calls,
+                    })
+            })
+            .collect();
+
+        CallsView {
+            unresolved,
+            methods,
+        }
+    }
+}
+
+fn to_json<T: Serialize>(value: &T) -> String {
+    serde_json::to_string(value).expect("ic-kit-runtime: Could not serialize the response.")
+}
+
+fn error(message: &str) -> String {
+    to_json(&serde_json::json!({ "error": message }))
+}
@@ -0,0 +1,228 @@
+//! Check the invariants which span several canisters after each message executed by the replica,
+//! such as the supply of a ledger being the sum of the balances held by the other canisters, and
+//! report the first message which breaks one of them.
+//!
+//! The values an invariant is about are observed in the canisters with [`Replica::observe`], the
+//! observations of a canister are taken in its execution thread after each of its messages, and
+//! the invariants are checked against the latest observations of every canister.
+//!
+//! ```ignore
+//! let ledger_id = ledger.canister_id();
+//! let escrow_id = escrow.canister_id();
+//!
+//! replica.observe(ledger_id, "supply", || with(|l: &Ledger| l.supply)).await;
+//! replica.observe(ledger_id, "balances", || with(|l: &Ledger| l.balances.values().sum::<u64>())).await;
+//! replica.observe(escrow_id, "held", || with(|e: &Escrow| e.held)).await;
+//!
+//! replica.invariant("conservation", move |view| {
+//!     view.get::<u64>(ledger_id, "supply")
+//!         == view.get::<u64>(ledger_id, "balances") + view.get::<u64>(escrow_id, "held")
+//! });
+//!
+//! // ... make the calls, the replica panics at the end of the test if a message broke the
+//! // invariant, or sooner with:
+//! replica.assert_invariants();
+//! ```
+//!
+//! [`Replica::observe`]: crate::Replica::observe
+
+use std::any::{type_name, Any};
+use std::collections::HashMap;
+use std::fmt;
+use std::panic::{catch_unwind, AssertUnwindSafe, RefUnwindSafe, UnwindSafe};
+use std::sync::{Arc, Mutex};
+
+use candid::Principal;
+
+use crate::canister::downcast_panic_payload;
+use crate::types::{EntryMode, Env};
+
+/// Return the value of an observation in the execution thread of a canister.
+pub(crate) type Probe =
+    Arc<dyn Fn() -> Box<dyn Any + Send> + Send + Sync + RefUnwindSafe + UnwindSafe>;
+
+/// The values observed in a canister, by their key.
+pub(crate) type Observations = Vec<(String, Box<dyn Any + Send>)>;
+
+/// An invariant registered with [`Replica::invariant`], which holds if it returns true.
+///
+/// [`Replica::invariant`]: crate::Replica::invariant
+type Check = Box<dyn Fn(&InvariantView) -> bool + Send + Sync>;
+
+/// The observations and the invariants registered with a replica, shared with its canisters.
+#[derive(Default)]
+pub(crate) struct Invariants {
+    probes: Mutex<HashMap<Principal, Vec<(String, Probe)>>>,
+    view: Mutex<InvariantView>,
+    checks: Mutex<Vec<(String, Check)>>,
+    violation: Mutex<Option<Violation>>,
+}
+
+/// The latest observations of the canisters, which the invariants are checked against.
+#[derive(Default)]
+pub struct InvariantView {
+    values: HashMap<(Principal, String), Box<dyn Any + Send>>,
+}
+
+/// The first message after which an invariant did not hold.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Violation {
+    /// The name of the invariant which was broken.
+    pub invariant: String,
+    /// The canister which executed the message.
+    pub canister_id: Principal,
+    /// The entry point which executed the message.
+    pub entry_mode: EntryMode,
+    /// The method called by the message, if it's a call to an update or a query.
+    pub method_name: Option<String>,
+    /// The caller of the message.
+    pub caller: Principal,
+    /// The panic message, if the invariant or one of the observations panicked instead of
+    /// returning.
+    pub panic_message: Option<String>,
+}
+
+impl Invariants {
+    /// Register the observation of a canister under the given key, replacing the one with the
+    /// same key.
+    pub fn observe(&self, canister_id: Principal, key: &str, probe: Probe) {
+        let mut probes = self.probes.lock().unwrap();
+        let probes = probes.entry(canister_id).or_default();
+        probes.retain(|(k, _)| k != key);
+        probes.push((key.to_string(), probe));
+    }
+
+    /// Register an invariant.
+    pub fn invariant(&self, name: &str, check: Check) {
+        self.checks.lock().unwrap().push((name.to_string(), check));
+    }
+
+    /// Return the observations registered for the canister.
+    pub fn probes(&self, canister_id: &Principal) -> Vec<(String, Probe)> {
+        self.probes
+            .lock()
+            .unwrap()
+            .get(canister_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Store the observations of a canister, without checking the invariants.
+    pub fn record(&self, canister_id: Principal, values: Observations) {
+        let mut view = self.view.lock().unwrap();
+
+        for (key, value) in values {
+            view.values.insert((canister_id, key), value);
+        }
+    }
+
+    /// Store the observations taken after a message of the canister, and check the invariants
+    /// unless one of them was already broken. The observations are `Err` if they panicked.
+    pub fn check(&self, canister_id: Principal, env: &Env, values: Result<Observations, String>) {
+        if self.violation.lock().unwrap().is_some() {
+            return;
+        }
+
+        let violation = |invariant: &str, panic_message: Option<String>| Violation {
+            invariant: invariant.to_string(),
+            canister_id,
+            entry_mode: env.entry_mode,
+            method_name: env.method_name.clone(),
+            caller: env.sender,
+            panic_message,
+        };
+
+        let values = match values {
+            Ok(values) => values,
+            Err(m) => {
+                *self.violation.lock().unwrap() = Some(violation("observations", Some(m)));
+                return;
+            }
+        };
+
+        self.record(canister_id, values);
+
+        let view = self.view.lock().unwrap();
+        let checks = self.checks.lock().unwrap();
+
+        for (name, check) in checks.iter() {
+            let broken = match catch_unwind(AssertUnwindSafe(|| check(&view))) {
+                Ok(true) => None,
+                Ok(false) => Some(violation(name, None)),
+                Err(e) => Some(violation(name, Some(downcast_panic_payload(&e)))),
+            };
+
+            if broken.is_some() {
+                *self.violation.lock().unwrap() = broken;
+                return;
+            }
+        }
+    }
+
+    /// Return the first violation of an invariant, if there was one.
+    pub fn violation(&self) -> Option<Violation> {
+        self.violation.lock().unwrap().clone()
+    }
+}
+
+impl InvariantView {
+    /// Return the latest value observed in the canister under the given key.
+    ///
+    /// # Panics
+    ///
+    /// If the canister has no observation with this key, or if the value is not a `T`.
+    pub fn get<T: Clone + 'static>(&self, canister_id: Principal, key: &str) -> T {
+        let value = self
+            .values
+            .get(&(canister_id, key.to_string()))
+            .unwrap_or_else(|| {
+                panic!(
+                    "ic-kit-runtime: The canister {} has no observation '{}'.",
+                    canister_id, key
+                )
+            });
+
+        value.downcast_ref::<T>().cloned().unwrap_or_else(|| {
+            panic!(
+                "ic-kit-runtime: The observation '{}' of the canister {} is not a {}.",
+                key,
+                canister_id,
+                type_name::<T>()
+            )
+        })
+    }
+
+    /// Return the latest value observed in the canister under the given key, if there is one of
+    /// the type `T`.
+    pub fn try_get<T: Clone + 'static>(&self, canister_id: Principal, key: &str) -> Option<T> {
+        self.values
+            .get(&(canister_id, key.to_string()))
+            .and_then(|value| value.downcast_ref::<T>())
+            .cloned()
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The invariant '{}' was broken by the {:?} message",
+            self.invariant, self.entry_mode
+        )?;
+
+        if let Some(method_name) = &self.method_name {
+            write!(f, " '{}'", method_name)?;
+        }
+
+        write!(
+            f,
+            " of the canister {} called by {}",
+            self.canister_id, self.caller
+        )?;
+
+        match &self.panic_message {
+            Some(m) => write!(f, ", it panicked: {}", m),
+            None => write!(f, "."),
+        }
+    }
+}
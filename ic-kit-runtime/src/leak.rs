@@ -0,0 +1,222 @@
+//! Find the state of a canister which grows with every call to a method, such as a map which is
+//! only ever inserted into, before it reaches the limits of the stable memory in production.
+//!
+//! ```ignore
+//! let report = replica
+//!     .leak_check(ledger.new_call("transfer").with_args((bob, 1u64)))
+//!     .iterations(50)
+//!     .run()
+//!     .await;
+//!
+//! println!("{}", report);
+//! report.assert_bounded();
+//! ```
+//!
+//! The canisters are executed natively, so the size of their heap is not known. Instead the
+//! `pre_upgrade` hook of the canister runs after each call, and the number of bytes it writes to
+//! the stable memory is the size of its heap state, which is measured along with the size of the
+//! stable memory itself. The canister keeps running afterwards, as with [`CanisterHandle::fork`].
+//!
+//! A size is growing if it never shrank during the check and it was still growing in the second
+//! half of the calls, once the state which is only created by the first calls, such as an entry
+//! per caller, is in place.
+//!
+//! [`CanisterHandle::fork`]: crate::handle::CanisterHandle::fork
+
+use std::fmt;
+use std::sync::atomic::Ordering;
+
+use candid::Principal;
+use serde::Serialize;
+
+use crate::call::CallBuilder;
+
+/// The sizes of the state of a canister after a call.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct MemorySample {
+    /// The number of bytes written to the stable memory by the `pre_upgrade` hook.
+    pub heap_bytes: u64,
+    /// The size of the stable memory, in pages of 64KiB.
+    pub stable_pages: u64,
+}
+
+/// A check of the growth of the state of a canister, created by [`Replica::leak_check`].
+///
+/// [`Replica::leak_check`]: crate::Replica::leak_check
+pub struct LeakCheck<'a> {
+    call: CallBuilder<'a>,
+    iterations: usize,
+}
+
+/// The sizes of the state of a canister after each of the calls of a [`LeakCheck`].
+#[derive(Clone, Debug, Serialize)]
+pub struct LeakReport {
+    /// The canister which was called.
+    pub canister_id: Principal,
+    /// The name of the method which was called.
+    pub method: String,
+    /// The number of calls which were rejected.
+    pub rejected: usize,
+    /// The sizes measured after each call.
+    pub samples: Vec<MemorySample>,
+}
+
+impl<'a> LeakCheck<'a> {
+    pub(crate) fn new(call: CallBuilder<'a>) -> Self {
+        Self {
+            call,
+            iterations: 20,
+        }
+    }
+
+    /// Set the number of times the call is performed, the default is 20.
+    ///
+    /// # Panics
+    ///
+    /// If there are less than 4 iterations, since the growth is measured over the second half of
+    /// the calls.
+    pub fn iterations(mut self, iterations: usize) -> Self {
+        assert!(iterations >= 4, "A leak check needs at least 4 iterations.");
+        self.iterations = iterations;
+        self
+    }
+
+    /// Perform the call the given number of times, one after the other, and measure the state of
+    /// the canister after each of them.
+    ///
+    /// # Panics
+    ///
+    /// If the `pre_upgrade` hook of the canister fails.
+    pub async fn run(self) -> LeakReport {
+        let replica = self.call.replica();
+        let canister_id = self.call.canister_id();
+        let canister = replica.get_canister(canister_id);
+        let counters = replica.counters();
+
+        let mut samples = Vec::with_capacity(self.iterations);
+        let mut rejected = 0;
+
+        for _ in 0..self.iterations {
+            if self.call.perform().await.is_error() {
+                rejected += 1;
+            }
+
+            let written = counters.stable_bytes_written.load(Ordering::SeqCst);
+
+            if let Some(e) = canister.pre_upgrade().await.rejection_message() {
+                panic!(
+                    "ic-kit-runtime: The pre_upgrade hook of canister '{}' failed: {}",
+                    canister_id, e
+                );
+            }
+
+            samples.push(MemorySample {
+                heap_bytes: counters.stable_bytes_written.load(Ordering::SeqCst) - written,
+                stable_pages: canister.stable_size().await,
+            });
+        }
+
+        LeakReport {
+            canister_id,
+            method: self.call.method_name().to_string(),
+            rejected,
+            samples,
+        }
+    }
+}
+
+impl LeakReport {
+    /// Return true if the heap state of the canister is growing with the calls.
+    pub fn is_heap_growing(&self) -> bool {
+        is_growing(&self.heap_bytes())
+    }
+
+    /// Return true if the stable memory of the canister is growing with the calls.
+    pub fn is_stable_memory_growing(&self) -> bool {
+        is_growing(&self.stable_pages())
+    }
+
+    /// Return the average number of bytes the heap state grew by with each call.
+    pub fn heap_growth_per_call(&self) -> f64 {
+        growth_per_call(&self.heap_bytes())
+    }
+
+    /// Assert neither the heap state nor the stable memory of the canister is growing with the
+    /// calls.
+    pub fn assert_bounded(&self) {
+        if self.is_heap_growing() {
+            panic!(
+                "ic-kit-runtime: The heap of canister '{}' grows by {:.1} bytes with each call to '{}', from {} to {} bytes.",
+                self.canister_id,
+                self.heap_growth_per_call(),
+                self.method,
+                self.samples[0].heap_bytes,
+                self.samples[self.samples.len() - 1].heap_bytes
+            );
+        }
+
+        if self.is_stable_memory_growing() {
+            panic!(
+                "ic-kit-runtime: The stable memory of canister '{}' grows with the calls to '{}', from {} to {} pages.",
+                self.canister_id,
+                self.method,
+                self.samples[0].stable_pages,
+                self.samples[self.samples.len() - 1].stable_pages
+            );
+        }
+    }
+
+    fn heap_bytes(&self) -> Vec<u64> {
+        self.samples.iter().map(|s| s.heap_bytes).collect()
+    }
+
+    fn stable_pages(&self) -> Vec<u64> {
+        self.samples.iter().map(|s| s.stable_pages).collect()
+    }
+}
+
+/// A size is growing if it never shrank and it still grew in the second half of the samples.
+fn is_growing(sizes: &[u64]) -> bool {
+    let never_shrank = sizes.windows(2).all(|w| w[0] <= w[1]);
+    let middle = sizes.len() / 2;
+
+    never_shrank && sizes[sizes.len() - 1] > sizes[middle]
+}
+
+fn growth_per_call(sizes: &[u64]) -> f64 {
+    let first = sizes[0] as f64;
+    let last = sizes[sizes.len() - 1] as f64;
+
+    (last - first) / (sizes.len() - 1) as f64
+}
+
+impl fmt::Display for LeakReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let first = self.samples[0];
+        let last = self.samples[self.samples.len() - 1];
+        let state = |growing: bool| if growing { "growing" } else { "bounded" };
+
+        writeln!(
+            f,
+            "{}: {} calls, {} rejected",
+            self.method,
+            self.samples.len(),
+            self.rejected
+        )?;
+        writeln!(
+            f,
+            "  heap:          {} -> {} bytes, {:.1} bytes per call, {}",
+            first.heap_bytes,
+            last.heap_bytes,
+            self.heap_growth_per_call(),
+            state(self.is_heap_growing())
+        )?;
+        writeln!(
+            f,
+            "  stable memory: {} -> {} pages, {}",
+            first.stable_pages,
+            last.stable_pages,
+            state(self.is_stable_memory_growing())
+        )
+    }
+}
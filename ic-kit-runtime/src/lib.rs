@@ -5,20 +5,84 @@ cfg_if::cfg_if! {
     if #[cfg(target_family = "wasm")] {
         compile_error!("IC-Kit runtime does not support builds for WASM.");
     } else {
+        #[cfg(feature = "ic-agent")]
+        pub mod agent;
+        mod backend;
+        pub mod bench;
+        pub mod blackhole;
+        pub mod builder;
         pub mod call;
         pub mod canister;
+        pub mod certification;
+        pub mod chaos;
+        pub mod conformance;
+        pub mod context;
+        pub mod coverage;
+        pub mod cycles;
+        pub mod deploy;
+        pub mod dfx;
+        pub mod diff;
+        pub mod dump;
+        pub mod explore;
+        pub mod fixture;
+        #[cfg(feature = "proptest")]
+        pub mod fuzz;
+        pub mod http;
+        #[cfg(feature = "identity")]
+        pub mod identity;
+        pub mod ingress;
+        #[cfg(feature = "inspector")]
+        pub mod inspect;
+        pub mod invariant;
+        pub mod leak;
+        pub mod load;
+        pub mod management;
+        pub mod metadata;
+        pub mod mock;
+        pub mod otel;
+        pub mod pretty;
+        #[cfg(feature = "proptest")]
+        pub mod prop;
+        #[cfg(feature = "pocket-ic")]
+        mod pocket;
         pub mod replica;
+        pub mod scenario;
+        pub mod shard;
+        pub mod snapshot;
+        pub mod sns;
+        mod span;
         pub mod stable;
+        pub mod subnet;
+        pub mod trace;
         pub mod types;
+        pub mod upgrade;
         pub mod users;
+        pub mod xrc;
         pub mod handle;
 
+        pub use builder::ReplicaBuilder;
         pub use canister::{Canister, CanisterMethod};
         pub use replica::Replica;
         pub use tokio::runtime::Builder as TokioRuntimeBuilder;
 
         pub mod prelude {
-            pub use crate::replica::Replica;
+            #[cfg(feature = "ic-agent")]
+            pub use crate::agent::AgentBackend;
+            pub use crate::builder::ReplicaBuilder;
+            pub use crate::chaos::Chaos;
+            pub use crate::conformance::{Script, ScriptCall};
+            pub use crate::context::MockContext;
+            pub use crate::deploy::Deployment;
+            pub use crate::dfx::DfxProject;
+            pub use crate::handle::{CanisterHandle, CanisterHealth};
+            #[cfg(feature = "identity")]
+            pub use crate::identity::Identity;
+            pub use crate::mock::MockCanister;
+            pub use crate::replica::{Replica, TimePolicy};
+            pub use crate::scenario::Scenario;
+            pub use crate::snapshot::Snapshot;
+            pub use crate::subnet::{LanePolicy, SubnetConfig};
+            pub use crate::upgrade::UpgradeTest;
             pub use crate::users;
         }
     }
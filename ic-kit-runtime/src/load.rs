@@ -0,0 +1,182 @@
+//! Load test the query methods of a canister, by sending a lot of queries at once and reporting
+//! the percentiles of their latency.
+//!
+//! ```ignore
+//! let report = replica
+//!     .load_test(ledger.new_call("balance_of").with_arg(alice))
+//!     .queries(20_000)
+//!     .concurrency(1_000)
+//!     .run()
+//!     .await;
+//!
+//! println!("{}", report);
+//! assert!(report.percentile(99.0) < Duration::from_millis(5));
+//! ```
+//!
+//! The queries are sent to the replica in waves of `concurrency` queries, each wave with a single
+//! message, and the latency of a query is measured from the start of its wave until its reply. The
+//! queries take the fast path of the replica: they are not logged by [`crate::trace`] and they are
+//! not recorded in the ingress history.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use futures::future::join_all;
+use serde::Serialize;
+use tokio::sync::oneshot;
+
+use crate::backend;
+use crate::bench::nearest_rank;
+use crate::call::CallBuilder;
+use crate::types::Message;
+
+/// A load test of a query, created by [`Replica::load_test`].
+///
+/// [`Replica::load_test`]: crate::Replica::load_test
+pub struct LoadTest<'a> {
+    call: CallBuilder<'a>,
+    queries: usize,
+    concurrency: Option<usize>,
+}
+
+/// The latency of the queries of a load test.
+#[derive(Clone, Debug, Serialize)]
+pub struct LoadReport {
+    /// The name of the method which was called.
+    pub method: String,
+    /// The number of queries which were sent.
+    pub queries: usize,
+    /// The number of queries which were rejected.
+    pub rejected: usize,
+    /// The wall time of the whole load test, in nanoseconds.
+    pub elapsed_ns: u64,
+    /// The latency of each query, in nanoseconds.
+    pub latencies_ns: Vec<u64>,
+}
+
+impl<'a> LoadTest<'a> {
+    pub(crate) fn new(call: CallBuilder<'a>) -> Self {
+        Self {
+            call,
+            queries: 10_000,
+            concurrency: None,
+        }
+    }
+
+    /// Set the number of queries which are sent, the default is 10000.
+    pub fn queries(mut self, queries: usize) -> Self {
+        assert!(queries > 0, "A load test needs at least one query.");
+        self.queries = queries;
+        self
+    }
+
+    /// Set the number of queries which are in flight at once, by default all of the queries are
+    /// sent at once.
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        assert!(
+            concurrency > 0,
+            "The concurrency of a load test must not be zero."
+        );
+        self.concurrency = Some(concurrency);
+        self
+    }
+
+    /// Send the queries and report their latency.
+    pub async fn run(self) -> LoadReport {
+        let replica = self.call.replica();
+
+        if let Some(backend) = replica.backend() {
+            backend::unsupported(backend.name(), "Load tests");
+        }
+
+        let canister_id = self.call.canister_id();
+        let concurrency = self.concurrency.unwrap_or(self.queries);
+        let mut latencies_ns = Vec::with_capacity(self.queries);
+        let mut rejected = 0;
+        let start = Instant::now();
+
+        while latencies_ns.len() < self.queries {
+            let n = concurrency.min(self.queries - latencies_ns.len());
+            let mut requests = Vec::with_capacity(n);
+            let mut replies = Vec::with_capacity(n);
+
+            for _ in 0..n {
+                let (tx, rx) = oneshot::channel();
+                let message = Message::Request {
+                    request_id: replica.counters().next_request_id(),
+                    env: self.call.query_env(),
+                };
+                requests.push((message, tx));
+                replies.push(rx);
+            }
+
+            let wave = Instant::now();
+            replica.enqueue_requests(canister_id, requests);
+
+            let results = join_all(replies.into_iter().map(|rx| async move {
+                let reply = rx
+                    .await
+                    .expect("ic-kit-runtime: Could not retrieve the response from the call.");
+                (reply, wave.elapsed())
+            }))
+            .await;
+
+            for (reply, latency) in results {
+                if reply.is_error() {
+                    rejected += 1;
+                }
+
+                latencies_ns.push(latency.as_nanos() as u64);
+            }
+        }
+
+        LoadReport {
+            method: self.call.method_name().to_string(),
+            queries: self.queries,
+            rejected,
+            elapsed_ns: start.elapsed().as_nanos() as u64,
+            latencies_ns,
+        }
+    }
+}
+
+impl LoadReport {
+    /// The latency below which the given percentage of the queries are.
+    pub fn percentile(&self, percentile: f64) -> Duration {
+        Duration::from_nanos(nearest_rank(&self.latencies_ns, percentile))
+    }
+
+    /// The number of queries answered per second.
+    pub fn throughput(&self) -> f64 {
+        self.queries as f64 / Duration::from_nanos(self.elapsed_ns).as_secs_f64()
+    }
+
+    /// Render the report as JSON, to be stored and compared between the runs.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("ic-kit-runtime: Could not serialize the report.")
+    }
+}
+
+impl fmt::Display for LoadReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{}: {} queries, {} rejected",
+            self.method, self.queries, self.rejected
+        )?;
+        writeln!(
+            f,
+            "  latency:    p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+            self.percentile(50.0),
+            self.percentile(90.0),
+            self.percentile(99.0),
+            self.percentile(100.0)
+        )?;
+        writeln!(
+            f,
+            "  throughput: {:.0} queries/s over {:?}",
+            self.throughput(),
+            Duration::from_nanos(self.elapsed_ns)
+        )
+    }
+}
@@ -0,0 +1,585 @@
+//! A mock of the management canister which implements `canister_status` for the canisters of the
+//! replica, so the canisters which compare the module hashes of other canisters, such as an
+//! upgrade orchestrator, can be tested, see [`Replica::management`].
+//!
+//! The status of a canister has its module hash, its controllers, its cycle balance and the size of
+//! its stable memory after its last message. The canisters run natively, so the heap is not
+//! counted in the memory size, and the allocations and the idle burn are the defaults of a new
+//! canister.
+//!
+//! ```ignore
+//! replica.management();
+//!
+//! let v2 = LedgerCanister::anonymous().with_build_id("ledger-v2");
+//! ledger.upgrade(v2, ()).await.assert_ok();
+//! ledger.assert_module_hash(LedgerCanister::anonymous().with_build_id("ledger-v2").module_hash());
+//! ```
+//!
+//! The canisters can also manage the code of the canisters they control with `install_code`,
+//! `stop_canister` and `start_canister`, so an upgrade orchestrator can be tested in-process. The
+//! builds which can be installed are added with [`ManagementMock::add_module`], which returns the
+//! bytes to pass as the `wasm_module`:
+//!
+//! ```ignore
+//! let management = replica.management();
+//! let v2 = LedgerCanister::build(ledger.canister_id()).with_build_id("ledger-v2");
+//! let hash = v2.module_hash();
+//! let module = management.add_module(&v2);
+//!
+//! orchestrator.new_call("upgrade").with_args((ledger.canister_id(), module)).perform().await.assert_ok();
+//! ledger.assert_module_hash(hash);
+//! ```
+//!
+//! A canister is stopped once the messages delivered to it are executed and it has no open calls,
+//! the calls made to it in the meantime are rejected. An upgrade runs the `pre_upgrade` hook of the
+//! running build, and the `post_upgrade` hook of the new build, which only replaces the running
+//! build if the hook succeeds, so a failed upgrade leaves the canister running its previous build
+//! with its heap. The changes made by the `pre_upgrade` hook are kept in this case, unlike on the
+//! Internet Computer.
+//!
+//! The replies to the calls made by the replaced build are dropped, since its callbacks are gone,
+//! and the calls it did not reply to are rejected, which is why the canisters are usually stopped
+//! before they are upgraded. In particular a canister which upgrades itself does not receive the
+//! reply to `install_code`, so it should make the call without awaiting it. A canister can not stop
+//! itself, since it would never finish stopping.
+//!
+//! The random bytes returned by `raw_rand` are the beacon of the round the call is executed in,
+//! see [`beacon`], so the canisters drawing randomness are deterministic: every call made in the
+//! same round gets the same bytes, and the bytes change with the round. A round is identified by
+//! the time of its messages, so with a fixed time the round changes when the time is moved, and
+//! with [`TimePolicy::AutoAdvance`] each message is in its own round. The beacon is derived from
+//! the seed set with [`Replica::set_beacon_seed`]:
+//!
+//! ```ignore
+//! replica.management();
+//! replica.set_time(1_000);
+//! lottery.new_call("draw").perform().await.assert_ok();
+//! assert_eq!(lottery.new_call("seed").perform().await.decode_one::<Vec<u8>>().unwrap(), replica.beacon(1_000));
+//! ```
+//!
+//! [`Replica::management`]: crate::Replica::management
+//! [`Replica::set_beacon_seed`]: crate::Replica::set_beacon_seed
+//! [`TimePolicy::AutoAdvance`]: crate::replica::TimePolicy::AutoAdvance
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+
+use candid::{encode_args, encode_one, CandidType, Deserialize, Nat, Principal};
+use ic_kit_sys::types::RejectionCode;
+use sha2::{Digest, Sha256};
+
+use crate::bench::Counters;
+use crate::call::CallReply;
+use crate::canister::{Canister, CanisterCode};
+use crate::mock::{MockCall, MockCanister, MockRecorder};
+use crate::replica::Installer;
+use crate::types::{EntryMode, Env, Message};
+
+/// The default freezing threshold of a canister, 30 days in seconds.
+const DEFAULT_FREEZING_THRESHOLD: u64 = 2_592_000;
+
+/// The candid interface of the methods of the management canister implemented by the mock.
+pub(crate) const MANAGEMENT_CANDID: &str = r#"
+type canister_id = principal;
+type definite_canister_settings = record {
+    controllers : vec principal;
+    compute_allocation : nat;
+    memory_allocation : nat;
+    freezing_threshold : nat;
+};
+type canister_status_result = record {
+    status : variant { running; stopping; stopped };
+    settings : definite_canister_settings;
+    module_hash : opt blob;
+    memory_size : nat;
+    cycles : nat;
+    idle_cycles_burned_per_day : nat;
+};
+type canister_install_mode = variant { install; reinstall; upgrade };
+service : {
+    canister_status : (record { canister_id : canister_id }) -> (canister_status_result);
+    install_code : (record {
+        mode : canister_install_mode;
+        canister_id : canister_id;
+        wasm_module : blob;
+        arg : blob;
+    }) -> ();
+    stop_canister : (record { canister_id : canister_id }) -> ();
+    start_canister : (record { canister_id : canister_id }) -> ();
+    raw_rand : () -> (blob);
+}
+"#;
+
+/// The methods of the management canister which are executed by the replica rather than by the
+/// mock, see [`Lifecycle`].
+const LIFECYCLE_METHODS: [&str; 3] = ["install_code", "stop_canister", "start_canister"];
+
+/// A handle to the mock of the management canister of a replica.
+#[derive(Clone)]
+pub struct ManagementMock {
+    recorder: MockRecorder,
+    modules: Arc<Mutex<HashMap<Vec<u8>, CanisterCode>>>,
+}
+
+/// Whether a canister which is not running is stopping or stopped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum RunStatus {
+    Stopping,
+    Stopped,
+}
+
+impl fmt::Display for RunStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunStatus::Stopping => f.write_str("stopping"),
+            RunStatus::Stopped => f.write_str("stopped"),
+        }
+    }
+}
+
+/// Executes the methods of the management canister which change the code and the status of the
+/// canisters, which need the replica rather than the thread of the mock.
+#[derive(Clone)]
+pub(crate) struct Lifecycle {
+    installer: Installer,
+    recorder: MockRecorder,
+    modules: Arc<Mutex<HashMap<Vec<u8>, CanisterCode>>>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct CanisterIdRecord {
+    canister_id: Principal,
+}
+
+#[derive(CandidType, Deserialize)]
+#[allow(non_camel_case_types)]
+enum CanisterInstallMode {
+    install,
+    reinstall,
+    upgrade,
+}
+
+#[derive(CandidType, Deserialize)]
+struct InstallCodeArgument {
+    mode: CanisterInstallMode,
+    canister_id: Principal,
+    wasm_module: Vec<u8>,
+    arg: Vec<u8>,
+}
+
+#[derive(CandidType)]
+#[allow(non_camel_case_types)]
+enum CanisterStatusType {
+    running,
+    stopping,
+    stopped,
+}
+
+#[derive(CandidType)]
+struct DefiniteCanisterSettings {
+    controllers: Vec<Principal>,
+    compute_allocation: Nat,
+    memory_allocation: Nat,
+    freezing_threshold: Nat,
+}
+
+#[derive(CandidType)]
+struct CanisterStatusResult {
+    status: CanisterStatusType,
+    settings: DefiniteCanisterSettings,
+    module_hash: Option<Vec<u8>>,
+    memory_size: Nat,
+    cycles: Nat,
+    idle_cycles_burned_per_day: Nat,
+}
+
+impl ManagementMock {
+    /// Create the mock and the canister which runs it, with the builds and the counters of the
+    /// canisters of the replica.
+    pub(crate) fn new(
+        codes: Arc<Mutex<HashMap<Principal, CanisterCode>>>,
+        counters: Arc<Counters>,
+    ) -> (Self, Canister) {
+        let mock = MockCanister::new(Principal::management_canister(), MANAGEMENT_CANDID)
+            .with_handler("canister_status", {
+                let counters = counters.clone();
+                move |call| canister_status(&codes.lock().unwrap(), &counters, call.caller, call)
+            })
+            .with_handler("raw_rand", move |_| {
+                // The handler runs in the message, so it has the time of the round.
+                let time = unsafe { ic_kit_sys::ic0::time() } as u64;
+                let seed = counters.beacon_seed.load(Ordering::SeqCst);
+                encode_one(beacon(seed, time).to_vec()).map_err(|e| e.to_string())
+            });
+        let recorder = mock.recorder();
+        let modules = Arc::new(Mutex::new(HashMap::new()));
+
+        (Self { recorder, modules }, mock.into())
+    }
+
+    /// Return the executor of the lifecycle methods, which share the modules of this mock.
+    pub(crate) fn lifecycle(&self, installer: Installer) -> Lifecycle {
+        Lifecycle {
+            installer,
+            recorder: self.recorder.clone(),
+            modules: self.modules.clone(),
+        }
+    }
+
+    /// Return the calls made to the management canister, in order.
+    pub fn calls(&self) -> Vec<MockCall> {
+        self.recorder.calls()
+    }
+
+    /// Allow the canisters to install the build with `install_code`, and return the bytes to pass
+    /// as its `wasm_module`, which are its wasm module if it has one.
+    pub fn add_module(&self, canister: &Canister) -> Vec<u8> {
+        let code = canister.code();
+        let module = match canister.wasm() {
+            Some(wasm) => wasm.to_vec(),
+            None => {
+                // The magic number and the version of a wasm module, followed by the hash of the
+                // build, so the bytes are unique to the build.
+                let mut module = b"\0asm\x01\0\0\0".to_vec();
+                module.extend_from_slice(&code.module_hash());
+                module
+            }
+        };
+
+        self.modules.lock().unwrap().insert(module.clone(), code);
+        module
+    }
+}
+
+impl Lifecycle {
+    /// Return true if the message is a call to one of the lifecycle methods.
+    pub(crate) fn handles(&self, env: &Env) -> bool {
+        match &env.method_name {
+            Some(method) => LIFECYCLE_METHODS.contains(&method.as_str()),
+            None => false,
+        }
+    }
+
+    /// Return the status of the canister if it's not running and the message is a call, which
+    /// is then rejected.
+    pub(crate) fn rejects(&self, canister_id: Principal, env: &Env) -> Option<RunStatus> {
+        if !matches!(
+            env.entry_mode,
+            EntryMode::Update | EntryMode::Query | EntryMode::CompositeQuery
+        ) {
+            return None;
+        }
+
+        self.status(canister_id)
+    }
+
+    fn status(&self, canister_id: Principal) -> Option<RunStatus> {
+        self.installer
+            .counters()
+            .run_statuses
+            .lock()
+            .unwrap()
+            .get(&canister_id)
+            .copied()
+    }
+
+    fn set_status(&self, canister_id: Principal, status: Option<RunStatus>) {
+        let mut statuses = self.installer.counters().run_statuses.lock().unwrap();
+
+        match status {
+            Some(status) => statuses.insert(canister_id, status),
+            None => statuses.remove(&canister_id),
+        };
+    }
+
+    /// Execute the call to the lifecycle method made by the given caller.
+    pub(crate) async fn call(&self, caller: Principal, method: &str, arg: Vec<u8>) -> CallReply {
+        self.recorder.record(MockCall {
+            caller,
+            method: method.to_string(),
+            arg: arg.clone(),
+            cycles: 0,
+        });
+
+        let result = match method {
+            "install_code" => self.install_code(caller, &arg).await,
+            "stop_canister" => self.stop_canister(caller, &arg).await,
+            _ => self.start_canister(caller, &arg),
+        };
+
+        match result {
+            Ok(()) => CallReply::Reply {
+                data: encode_args(()).unwrap(),
+                cycles_refunded: 0,
+            },
+            Err(message) => CallReply::Reject {
+                rejection_code: RejectionCode::CanisterError,
+                rejection_message: message,
+                cycles_refunded: 0,
+            },
+        }
+    }
+
+    /// Return the build the canister is running, if the caller controls it.
+    fn controlled(
+        &self,
+        caller: Principal,
+        canister_id: Principal,
+    ) -> Result<CanisterCode, String> {
+        let code = self
+            .installer
+            .code(canister_id)
+            .ok_or_else(|| format!("Canister {} not found.", canister_id))?;
+
+        if !code.controllers().contains(&caller) {
+            return Err(format!(
+                "Only the controllers of canister {} can manage its code and its status.",
+                canister_id
+            ));
+        }
+
+        Ok(code)
+    }
+
+    async fn install_code(&self, caller: Principal, arg: &[u8]) -> Result<(), String> {
+        let arg = candid::decode_one::<InstallCodeArgument>(arg).map_err(|e| e.to_string())?;
+        let canister_id = arg.canister_id;
+        let controllers = self.controlled(caller, canister_id)?.controllers().to_vec();
+
+        let mut code = self
+            .modules
+            .lock()
+            .unwrap()
+            .get(&arg.wasm_module)
+            .cloned()
+            .ok_or_else(|| {
+                "The wasm module was not added with ManagementMock::add_module.".to_string()
+            })?;
+        code.set_controllers(controllers);
+
+        let mut canister = Canister::from_code(canister_id, code);
+        let balance = self.balance(canister_id).await;
+
+        let env = match arg.mode {
+            // The canisters of the replica always have a build installed.
+            CanisterInstallMode::install => {
+                return Err(format!("Canister {} is not empty.", canister_id));
+            }
+            CanisterInstallMode::reinstall => Env::init(),
+            CanisterInstallMode::upgrade => {
+                let request_id = self.installer.counters().next_request_id();
+                let reply = self
+                    .installer
+                    .request(
+                        canister_id,
+                        Message::Request {
+                            request_id,
+                            env: Env::pre_upgrade(),
+                        },
+                    )
+                    .await;
+
+                if let CallReply::Reject {
+                    rejection_message, ..
+                } = reply
+                {
+                    return Err(format!(
+                        "The pre_upgrade hook of canister {} failed: {}",
+                        canister_id, rejection_message
+                    ));
+                }
+
+                let data = self
+                    .installer
+                    .run(canister_id, || unsafe {
+                        let size = ic_kit_sys::ic0::stable64_size() as u64;
+                        let mut data = vec![0u8; (size << 16) as usize];
+                        ic_kit_sys::ic0::stable64_read(
+                            data.as_mut_ptr() as i64,
+                            0,
+                            data.len() as i64,
+                        );
+                        data
+                    })
+                    .await;
+
+                canister.load_stable(&data);
+                Env::post_upgrade()
+            }
+        };
+
+        let mut canister = canister.with_balance(balance);
+        self.installer.prepare(&mut canister);
+
+        // The hook runs before the new build replaces the running one, so the running build is
+        // kept if it fails.
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let request_id = self.installer.counters().next_request_id();
+        let calls = canister
+            .process_message(
+                Message::Request {
+                    request_id,
+                    env: env.with_raw_args(arg.arg),
+                },
+                Some(tx),
+            )
+            .await;
+
+        // The new build is not running yet, so the replies to the calls could not reach it.
+        if !calls.is_empty() {
+            return Err(format!(
+                "The new build of canister {} made calls from its hook, which is only supported when the hook is run by the handle of the canister.",
+                canister_id
+            ));
+        }
+
+        if let Ok(CallReply::Reject {
+            rejection_message, ..
+        }) = rx.await
+        {
+            return Err(format!(
+                "The new build of canister {} failed to start: {}",
+                canister_id, rejection_message
+            ));
+        }
+
+        self.installer.drop_pending_replies(canister_id);
+        self.installer.register(&canister);
+        self.installer.start(canister, true);
+
+        Ok(())
+    }
+
+    async fn stop_canister(&self, caller: Principal, arg: &[u8]) -> Result<(), String> {
+        let canister_id = candid::decode_one::<CanisterIdRecord>(arg)
+            .map_err(|e| e.to_string())?
+            .canister_id;
+        self.controlled(caller, canister_id)?;
+
+        if caller == canister_id {
+            return Err(format!(
+                "Canister {} can not stop itself, it would never finish stopping.",
+                canister_id
+            ));
+        }
+
+        if self.status(canister_id) == Some(RunStatus::Stopped) {
+            return Ok(());
+        }
+
+        self.set_status(canister_id, Some(RunStatus::Stopping));
+
+        // Wait for the messages already delivered to the canister to be executed, and for the
+        // calls it made or received to be replied to.
+        loop {
+            self.installer.run(canister_id, || ()).await;
+
+            if !self.installer.has_open_calls(canister_id) {
+                break;
+            }
+
+            tokio::task::yield_now().await;
+        }
+
+        // The canister may have been started again in the meantime.
+        if self.status(canister_id) == Some(RunStatus::Stopping) {
+            self.set_status(canister_id, Some(RunStatus::Stopped));
+        }
+
+        Ok(())
+    }
+
+    fn start_canister(&self, caller: Principal, arg: &[u8]) -> Result<(), String> {
+        let canister_id = candid::decode_one::<CanisterIdRecord>(arg)
+            .map_err(|e| e.to_string())?
+            .canister_id;
+        self.controlled(caller, canister_id)?;
+        self.set_status(canister_id, None);
+
+        Ok(())
+    }
+
+    async fn balance(&self, canister_id: Principal) -> u128 {
+        self.installer
+            .run(canister_id, || unsafe {
+                let mut bytes = [0u8; 16];
+                ic_kit_sys::ic0::canister_cycle_balance128(bytes.as_mut_ptr() as isize);
+                u128::from_le_bytes(bytes)
+            })
+            .await
+    }
+}
+
+/// Return the random bytes of the round at the given time, which are derived from the seed of the
+/// beacon of the replica.
+pub fn beacon(seed: u64, time: u64) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"ic-kit-runtime beacon");
+    hasher.update(seed.to_le_bytes());
+    hasher.update(time.to_le_bytes());
+    hasher.finalize().into()
+}
+
+/// Reply to a call to `canister_status` made by the given caller, this runs on the thread of the
+/// canister.
+pub(crate) fn canister_status(
+    codes: &HashMap<Principal, CanisterCode>,
+    counters: &Counters,
+    caller: Principal,
+    call: &MockCall,
+) -> Result<Vec<u8>, String> {
+    let canister_id = call
+        .decode_one::<CanisterIdRecord>()
+        .map_err(|e| e.to_string())?
+        .canister_id;
+
+    let code = codes
+        .get(&canister_id)
+        .ok_or_else(|| format!("Canister {} not found.", canister_id))?;
+
+    if !code.controllers().contains(&caller) {
+        return Err(format!(
+            "Only controllers of canister {} can call ic00 method canister_status",
+            canister_id
+        ));
+    }
+
+    let (cycles, memory_size) = counters
+        .statuses
+        .lock()
+        .unwrap()
+        .get(&canister_id)
+        .copied()
+        .unwrap_or_default();
+
+    let status = match counters.run_statuses.lock().unwrap().get(&canister_id) {
+        Some(RunStatus::Stopping) => CanisterStatusType::stopping,
+        Some(RunStatus::Stopped) => CanisterStatusType::stopped,
+        None => CanisterStatusType::running,
+    };
+
+    encode_one(CanisterStatusResult {
+        status,
+        settings: DefiniteCanisterSettings {
+            controllers: code.controllers().to_vec(),
+            compute_allocation: Nat::from(
+                counters
+                    .compute_allocations
+                    .lock()
+                    .unwrap()
+                    .get(&canister_id)
+                    .copied()
+                    .unwrap_or_default(),
+            ),
+            memory_allocation: Nat::from(0u64),
+            freezing_threshold: Nat::from(DEFAULT_FREEZING_THRESHOLD),
+        },
+        module_hash: Some(code.module_hash().to_vec()),
+        memory_size: Nat::from(memory_size),
+        cycles: Nat::from(cycles),
+        idle_cycles_burned_per_day: Nat::from(0u64),
+    })
+    .map_err(|e| e.to_string())
+}
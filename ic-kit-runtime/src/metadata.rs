@@ -0,0 +1,136 @@
+//! The metadata of the canisters, the custom sections of their wasm module named
+//! `icp:public <name>` or `icp:private <name>`, such as the `candid:service` section read by the
+//! tools which show the interface of a canister.
+//!
+//! The metadata of a canister is served at `canister/<id>/metadata/<name>` by
+//! [`Replica::read_state`], like on the Internet Computer. The public sections can be read by
+//! anyone, and the private ones only by the controllers of the canister, see
+//! [`Replica::read_state_as`]. The sections come from the wasm module given to
+//! [`Canister::with_wasm`], from [`Canister::with_public_metadata`] and
+//! [`Canister::with_private_metadata`], and from the `metadata!` macro of ic-kit, which embeds
+//! them in the wasm module at build time. The candid interface of a canister is its
+//! `candid:service` section if it does not have one.
+//!
+//! ```ignore
+//! let certificate = replica.read_state(&[metadata_path(&ledger.canister_id(), "candid:service")]).await;
+//! let certificate = Certificate::decode(&certificate).unwrap();
+//! let candid = certificate.tree.lookup(&metadata_path(&ledger.canister_id(), "candid:service"));
+//! ```
+//!
+//! [`Replica::read_state`]: crate::Replica::read_state
+//! [`Replica::read_state_as`]: crate::Replica::read_state_as
+//! [`Canister::with_wasm`]: crate::Canister::with_wasm
+//! [`Canister::with_public_metadata`]: crate::Canister::with_public_metadata
+//! [`Canister::with_private_metadata`]: crate::Canister::with_private_metadata
+
+/// The magic number and the version which start a wasm module.
+const WASM_HEADER: [u8; 8] = *b"\0asm\x01\0\0\0";
+
+/// The id of the custom sections of a wasm module.
+const CUSTOM_SECTION_ID: u8 = 0;
+
+/// Who can read a section of the metadata of a canister.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MetadataVisibility {
+    /// Anyone can read the section.
+    Public,
+    /// Only the controllers of the canister can read the section.
+    Private,
+}
+
+impl MetadataVisibility {
+    /// Return the prefix of the name of the custom sections with this visibility.
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            MetadataVisibility::Public => "icp:public ",
+            MetadataVisibility::Private => "icp:private ",
+        }
+    }
+}
+
+/// Return the sections of the metadata in the custom sections of the wasm module, with their
+/// name without the `icp:public` or `icp:private` prefix. The other custom sections are skipped,
+/// and the bytes which are not a wasm module have no metadata.
+pub fn custom_sections(wasm: &[u8]) -> Vec<(String, MetadataVisibility, Vec<u8>)> {
+    let mut sections = Vec::new();
+
+    if !wasm.starts_with(&WASM_HEADER) {
+        return sections;
+    }
+
+    let mut reader = Reader {
+        bytes: wasm,
+        offset: WASM_HEADER.len(),
+    };
+
+    while let Some((id, content)) = reader.section() {
+        if id != CUSTOM_SECTION_ID {
+            continue;
+        }
+
+        let mut content = Reader {
+            bytes: content,
+            offset: 0,
+        };
+
+        let name = match content
+            .bytes_with_len()
+            .and_then(|name| std::str::from_utf8(name).ok())
+        {
+            Some(name) => name,
+            None => continue,
+        };
+
+        let data = content.bytes[content.offset..].to_vec();
+
+        for visibility in [MetadataVisibility::Public, MetadataVisibility::Private] {
+            if let Some(name) = name.strip_prefix(visibility.prefix()) {
+                sections.push((name.to_string(), visibility, data.clone()));
+            }
+        }
+    }
+
+    sections
+}
+
+/// Read the sections of a wasm module, a truncated module ends the sections.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    /// Read the next section, with its id and its content.
+    fn section(&mut self) -> Option<(u8, &'a [u8])> {
+        let id = *self.bytes.get(self.offset)?;
+        self.offset += 1;
+        let content = self.bytes_with_len()?;
+        Some((id, content))
+    }
+
+    /// Read bytes prefixed with their length.
+    fn bytes_with_len(&mut self) -> Option<&'a [u8]> {
+        let len = self.leb128()? as usize;
+        let end = self.offset.checked_add(len)?;
+        let bytes = self.bytes.get(self.offset..end)?;
+        self.offset = end;
+        Some(bytes)
+    }
+
+    /// Read an unsigned LEB128 integer of at most 32 bits.
+    fn leb128(&mut self) -> Option<u32> {
+        let mut value = 0u32;
+
+        for shift in (0..35).step_by(7) {
+            let byte = *self.bytes.get(self.offset)?;
+            self.offset += 1;
+            value |= u32::from(byte & 0x7f).checked_shl(shift)?;
+
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+
+        None
+    }
+}
@@ -0,0 +1,451 @@
+//! Mock canisters generated from the candid interface of a third-party canister, such as the NNS
+//! registry or an exchange rate canister, so the canisters which depend on it can be tested
+//! without its code.
+//!
+//! Each method of the interface replies with the default value of its return types, `0` for the
+//! numbers, the empty text and vector, `null` for the options and the first case of the variants,
+//! unless another reply is configured. The replies and the arguments of the calls are checked
+//! against the interface, and every call to the mock is recorded.
+//!
+//! ```ignore
+//! let registry = MockCanister::new(registry_id, include_str!("registry.did"))
+//!     .with_reply("get_subnet_for_canister", (Ok::<_, String>(subnet),))
+//!     .with_reject("get_value", "The registry is down.");
+//! let calls = registry.recorder();
+//!
+//! let replica = Replica::default();
+//! replica.add_canister(registry.into());
+//!
+//! // ...
+//!
+//! assert_eq!(calls.call_count("get_subnet_for_canister"), 1);
+//! ```
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use candid::parser::value::{IDLField, IDLValue, VariantValue};
+use candid::types::{Function, Type};
+use candid::utils::{ArgumentDecoder, ArgumentEncoder};
+use candid::{check_prog, IDLArgs, IDLProg, Int, Nat, TypeEnv};
+use candid::{decode_args, decode_one, encode_args, CandidType, Principal};
+use serde::de::DeserializeOwned;
+
+use ic_kit_sys::ic0;
+
+use crate::canister::Canister;
+use crate::types::TaskFn;
+
+/// How deep the default value of a recursive type can be nested before giving up.
+const MAX_DEFAULT_DEPTH: usize = 32;
+
+/// A canister which implements a candid interface with configurable replies.
+pub struct MockCanister {
+    canister_id: Principal,
+    interface: Arc<Interface>,
+    state: Arc<Mutex<MockState>>,
+}
+
+/// A handle to the calls made to a mock canister, which stays usable once the mock is added to
+/// the replica.
+#[derive(Clone)]
+pub struct MockRecorder {
+    state: Arc<Mutex<MockState>>,
+}
+
+/// A call made to a mock canister.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MockCall {
+    /// The principal which made the call.
+    pub caller: Principal,
+    /// The name of the method.
+    pub method: String,
+    /// The candid encoded arguments of the call.
+    pub arg: Vec<u8>,
+    /// The cycles sent with the call.
+    pub cycles: u128,
+}
+
+struct Interface {
+    candid: String,
+    env: TypeEnv,
+    methods: HashMap<String, Function>,
+}
+
+struct MockState {
+    replies: HashMap<String, MockReply>,
+    calls: Vec<MockCall>,
+}
+
+/// Return the candid encoded reply to the call, or the message to reject it with.
+type MockHandler = dyn Fn(&MockCall) -> Result<Vec<u8>, String> + Send + Sync;
+
+#[derive(Clone)]
+enum MockReply {
+    Reply(Vec<u8>),
+    Reject(String),
+    Handler(Arc<MockHandler>),
+}
+
+impl MockCanister {
+    /// Create a mock canister with the given id which implements the service of the candid
+    /// interface.
+    ///
+    /// # Panics
+    ///
+    /// If the candid interface is not valid or does not define a service.
+    pub fn new<T: Into<Principal>>(canister_id: T, candid: &str) -> Self {
+        let interface = Interface::parse(candid).unwrap_or_else(|e| {
+            panic!(
+                "ic-kit-runtime: Could not parse the candid interface of the mock: {}",
+                e
+            )
+        });
+
+        let replies = interface
+            .methods
+            .iter()
+            .map(|(name, method)| (name.clone(), interface.default_reply(name, method)))
+            .collect();
+
+        Self {
+            canister_id: canister_id.into(),
+            interface: Arc::new(interface),
+            state: Arc::new(Mutex::new(MockState {
+                replies,
+                calls: Vec::new(),
+            })),
+        }
+    }
+
+    /// Return the id of the canister.
+    pub fn id(&self) -> Principal {
+        self.canister_id
+    }
+
+    /// Reply to the calls to the method with the given values.
+    ///
+    /// # Panics
+    ///
+    /// If the method is not in the interface, or if the values do not have its return types.
+    pub fn with_reply<T: ArgumentEncoder>(self, method: &str, reply: T) -> Self {
+        let rets = &self.interface.method(method).rets;
+        let bytes = encode_args(reply).expect("ic-kit-runtime: Could not encode the reply.");
+
+        if let Err(e) = IDLArgs::from_bytes_with_types(&bytes, &self.interface.env, rets) {
+            panic!(
+                "ic-kit-runtime: The reply of '{}' does not match its candid type: {}",
+                method, e
+            );
+        }
+
+        self.set_reply(method, MockReply::Reply(bytes))
+    }
+
+    /// Reply to the calls to the method with the values in the candid text format, such as
+    /// `(record { rate = 100 : nat64 })`, which are encoded with the return types of the method.
+    ///
+    /// # Panics
+    ///
+    /// If the method is not in the interface, or if the text is not valid for its return types.
+    pub fn with_reply_text(self, method: &str, reply: &str) -> Self {
+        let rets = &self.interface.method(method).rets;
+        let env = &self.interface.env;
+        let bytes = reply
+            .parse::<IDLArgs>()
+            .and_then(|args| args.annotate_types(true, env, rets))
+            .and_then(|args| args.to_bytes_with_types(env, rets))
+            .unwrap_or_else(|e| {
+                panic!(
+                    "ic-kit-runtime: Could not encode the reply of '{}': {}",
+                    method, e
+                )
+            });
+
+        self.set_reply(method, MockReply::Reply(bytes))
+    }
+
+    /// Reject the calls to the method with the given message.
+    ///
+    /// # Panics
+    ///
+    /// If the method is not in the interface.
+    pub fn with_reject<S: Into<String>>(self, method: &str, message: S) -> Self {
+        self.interface.method(method);
+        self.set_reply(method, MockReply::Reject(message.into()))
+    }
+
+    /// Reply to the calls to the method with the result of the function, which returns the candid
+    /// encoded reply or the message to reject the call with. The function runs on the thread of
+    /// the canister, so it can use the system API, such as to accept the cycles of the call.
+    ///
+    /// # Panics
+    ///
+    /// If the method is not in the interface. The call traps if the reply does not have the return
+    /// types of the method.
+    pub fn with_handler<F>(self, method: &str, handler: F) -> Self
+    where
+        F: Fn(&MockCall) -> Result<Vec<u8>, String> + Send + Sync + 'static,
+    {
+        self.interface.method(method);
+        self.set_reply(method, MockReply::Handler(Arc::new(handler)))
+    }
+
+    /// Return a handle to the calls made to this canister.
+    pub fn recorder(&self) -> MockRecorder {
+        MockRecorder {
+            state: self.state.clone(),
+        }
+    }
+
+    fn set_reply(self, method: &str, reply: MockReply) -> Self {
+        self.state
+            .lock()
+            .unwrap()
+            .replies
+            .insert(method.to_string(), reply);
+        self
+    }
+}
+
+impl From<MockCanister> for Canister {
+    fn from(mock: MockCanister) -> Self {
+        let MockCanister {
+            canister_id,
+            interface,
+            state,
+        } = mock;
+        let candid = interface.candid.clone();
+
+        Canister::new(canister_id)
+            .with_candid(candid)
+            .with_dynamic_methods(move |env| {
+                let method = env.method_name.clone()?;
+                interface.methods.get(&method)?;
+
+                let call = MockCall {
+                    caller: env.sender,
+                    method,
+                    arg: env.args.clone(),
+                    cycles: env.cycles_available,
+                };
+                let interface = interface.clone();
+                let state = state.clone();
+
+                Some(Box::new(move || mock_task(&interface, &state, call)) as TaskFn)
+            })
+    }
+}
+
+impl MockRecorder {
+    /// Return all of the calls made to the canister, in order.
+    pub fn calls(&self) -> Vec<MockCall> {
+        self.state.lock().unwrap().calls.clone()
+    }
+
+    /// Return the calls made to the given method, in order.
+    pub fn calls_to(&self, method: &str) -> Vec<MockCall> {
+        self.state
+            .lock()
+            .unwrap()
+            .calls
+            .iter()
+            .filter(|call| call.method == method)
+            .cloned()
+            .collect()
+    }
+
+    /// Return how many times the given method was called.
+    pub fn call_count(&self, method: &str) -> usize {
+        self.calls_to(method).len()
+    }
+
+    /// Record a call which was handled outside of the mock, such as the lifecycle methods of the
+    /// management canister.
+    pub(crate) fn record(&self, call: MockCall) {
+        self.state.lock().unwrap().calls.push(call);
+    }
+
+    /// Forget the calls recorded so far.
+    pub fn clear(&self) {
+        self.state.lock().unwrap().calls.clear();
+    }
+}
+
+impl MockCall {
+    /// Decode the arguments of the call.
+    pub fn decode<T: for<'a> ArgumentDecoder<'a>>(&self) -> candid::Result<T> {
+        decode_args(&self.arg)
+    }
+
+    /// Decode the single argument of the call.
+    pub fn decode_one<T>(&self) -> candid::Result<T>
+    where
+        T: DeserializeOwned + CandidType,
+    {
+        decode_one(&self.arg)
+    }
+}
+
+impl Interface {
+    fn parse(candid: &str) -> candid::Result<Self> {
+        let prog = candid.parse::<IDLProg>()?;
+        let mut env = TypeEnv::new();
+        let actor = check_prog(&mut env, &prog)?
+            .ok_or_else(|| candid::Error::msg("The candid interface does not define a service."))?;
+
+        let methods = env
+            .as_service(&actor)?
+            .iter()
+            .map(|(name, _)| Ok((name.clone(), env.get_method(&actor, name)?.clone())))
+            .collect::<candid::Result<_>>()?;
+
+        Ok(Self {
+            candid: candid.to_string(),
+            env,
+            methods,
+        })
+    }
+
+    fn method(&self, method: &str) -> &Function {
+        self.methods.get(method).unwrap_or_else(|| {
+            panic!(
+                "ic-kit-runtime: The candid interface of the mock does not have a '{}' method.",
+                method
+            )
+        })
+    }
+
+    /// The reply of the method made of the default values of its return types, or a reject if one
+    /// of them has no value.
+    fn default_reply(&self, name: &str, method: &Function) -> MockReply {
+        let values = method
+            .rets
+            .iter()
+            .map(|ty| self.default_value(ty, 0))
+            .collect::<Option<Vec<_>>>();
+
+        match values
+            .map(|values| IDLArgs::new(&values).to_bytes_with_types(&self.env, &method.rets))
+        {
+            Some(Ok(bytes)) => MockReply::Reply(bytes),
+            _ => MockReply::Reject(format!(
+                "The mock does not have a default reply for '{}'.",
+                name
+            )),
+        }
+    }
+
+    fn default_value(&self, ty: &Type, depth: usize) -> Option<IDLValue> {
+        if depth > MAX_DEFAULT_DEPTH {
+            return None;
+        }
+
+        let value = match ty {
+            Type::Var(_) => return self.default_value(&self.env.trace_type(ty).ok()?, depth + 1),
+            Type::Null => IDLValue::Null,
+            Type::Bool => IDLValue::Bool(false),
+            Type::Nat => IDLValue::Nat(Nat::from(0)),
+            Type::Int => IDLValue::Int(Int::from(0)),
+            Type::Nat8 => IDLValue::Nat8(0),
+            Type::Nat16 => IDLValue::Nat16(0),
+            Type::Nat32 => IDLValue::Nat32(0),
+            Type::Nat64 => IDLValue::Nat64(0),
+            Type::Int8 => IDLValue::Int8(0),
+            Type::Int16 => IDLValue::Int16(0),
+            Type::Int32 => IDLValue::Int32(0),
+            Type::Int64 => IDLValue::Int64(0),
+            Type::Float32 => IDLValue::Float32(0.0),
+            Type::Float64 => IDLValue::Float64(0.0),
+            Type::Text => IDLValue::Text(String::new()),
+            Type::Reserved => IDLValue::Reserved,
+            Type::Principal => IDLValue::Principal(Principal::anonymous()),
+            Type::Opt(_) => IDLValue::None,
+            Type::Vec(_) => IDLValue::Vec(Vec::new()),
+            Type::Record(fields) => IDLValue::Record(
+                fields
+                    .iter()
+                    .map(|field| {
+                        Some(IDLField {
+                            id: field.id.clone(),
+                            val: self.default_value(&field.ty, depth + 1)?,
+                        })
+                    })
+                    .collect::<Option<_>>()?,
+            ),
+            Type::Variant(fields) => {
+                let (index, field) = fields.iter().enumerate().find_map(|(index, field)| {
+                    let val = self.default_value(&field.ty, depth + 1)?;
+                    Some((
+                        index,
+                        IDLField {
+                            id: field.id.clone(),
+                            val,
+                        },
+                    ))
+                })?;
+
+                IDLValue::Variant(VariantValue(Box::new(field), index as u64))
+            }
+            Type::Service(_) => IDLValue::Service(Principal::management_canister()),
+            _ => return None,
+        };
+
+        Some(value)
+    }
+}
+
+/// Record the call to the method of the mock and reply to it, this runs on the thread of the
+/// canister.
+fn mock_task(interface: &Interface, state: &Mutex<MockState>, call: MockCall) {
+    let method_type = &interface.methods[&call.method];
+
+    if let Err(e) = IDLArgs::from_bytes_with_types(&call.arg, &interface.env, &method_type.args) {
+        trap(&format!(
+            "The arguments of '{}' do not match its candid type: {}",
+            call.method, e
+        ));
+        return;
+    }
+
+    let reply = {
+        let mut state = state.lock().unwrap();
+        state.calls.push(call.clone());
+        state.replies[&call.method].clone()
+    };
+
+    let reply = match reply {
+        MockReply::Handler(handler) => match handler(&call) {
+            Ok(bytes) => {
+                if let Err(e) =
+                    IDLArgs::from_bytes_with_types(&bytes, &interface.env, &method_type.rets)
+                {
+                    trap(&format!(
+                        "The reply of '{}' does not match its candid type: {}",
+                        call.method, e
+                    ));
+                    return;
+                }
+
+                MockReply::Reply(bytes)
+            }
+            Err(message) => MockReply::Reject(message),
+        },
+        reply => reply,
+    };
+
+    match reply {
+        MockReply::Reply(bytes) => unsafe {
+            ic0::msg_reply_data_append(bytes.as_ptr() as isize, bytes.len() as isize);
+            ic0::msg_reply();
+        },
+        MockReply::Reject(message) => unsafe {
+            ic0::msg_reject(message.as_ptr() as isize, message.len() as isize);
+        },
+        MockReply::Handler(_) => unreachable!(),
+    }
+}
+
+fn trap(message: &str) {
+    unsafe { ic0::trap(message.as_ptr() as isize, message.len() as isize) };
+}
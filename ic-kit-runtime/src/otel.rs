@@ -0,0 +1,226 @@
+//! Export the calls made during a test as OpenTelemetry spans, so a complex execution can be
+//! explored in a tracing UI such as Jaeger or Tempo instead of a flat log.
+//!
+//! Once [`Replica::record_spans`] is enabled, each call is recorded as a span of the service named
+//! after the canister which was called, from the time it was sent until its reply. The calls made
+//! by the test and by the users start a new trace, and the calls made by a canister are the child
+//! spans of the call it was processing, so each trace shows the inter-canister calls resulting
+//! from an ingress message.
+//!
+//! [`Replica::export_otlp`] writes the spans in the OTLP/JSON format, which can be imported by the
+//! tracing UIs or sent as is to the `/v1/traces` endpoint of an OpenTelemetry collector:
+//!
+//! ```ignore
+//! replica.record_spans(true);
+//! replica.name(ledger.canister_id(), "ledger");
+//! ledger.new_call("transfer").with_args((bob, 10u64)).perform().await;
+//! replica.export_otlp("./target/traces/transfer.json").unwrap();
+//! ```
+//!
+//! ```text
+//! curl -X POST -H 'Content-Type: application/json' \
+//!     --data-binary @target/traces/transfer.json http://localhost:4318/v1/traces
+//! ```
+//!
+//! [`Replica::record_spans`]: crate::Replica::record_spans
+//! [`Replica::export_otlp`]: crate::Replica::export_otlp
+
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use candid::Principal;
+use serde_json::{json, Value};
+
+/// The name of the instrumentation scope of the spans.
+const SCOPE_NAME: &str = "ic-kit-runtime";
+
+/// The `SPAN_KIND_SERVER` kind of OpenTelemetry, since the spans are the calls received by the
+/// canisters.
+const SPAN_KIND_SERVER: u8 = 2;
+
+/// A call recorded as an OpenTelemetry span.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OtelSpan {
+    /// The id of the trace, which is shared by all of the calls resulting from the same call of
+    /// the test or of a user.
+    pub trace_id: [u8; 16],
+    /// The id of the span.
+    pub span_id: [u8; 8],
+    /// The span of the call the caller was processing when it made the call, if the caller is a
+    /// canister.
+    pub parent_span_id: Option<[u8; 8]>,
+    /// The name of the service of the span, which is the name of the called canister.
+    pub service: String,
+    /// The principal which made the call.
+    pub sender: Principal,
+    /// The canister which was called.
+    pub callee: Principal,
+    /// The name of the method which was called.
+    pub method: String,
+    /// The cycles sent with the call.
+    pub payment: u128,
+    /// The size of the encoded argument in bytes.
+    pub arg_size: usize,
+    /// The size of the reply in bytes, or of the rejection message if the call was rejected.
+    pub reply_size: usize,
+    /// The time the call was sent, in nanoseconds since the unix epoch.
+    pub start_time: u64,
+    /// The time of the reply, in nanoseconds since the unix epoch.
+    pub end_time: u64,
+    /// The rejection code and message if the call was rejected, or dropped without a reply.
+    pub error: Option<String>,
+}
+
+/// The ids of the span of a call which is waiting for its reply.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct SpanIds {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub parent_span_id: Option<[u8; 8]>,
+    pub start_time: u64,
+}
+
+/// Assigns the ids of the spans, which are unique across the runs of the tests since they start
+/// with the time the recording started.
+pub(crate) struct SpanRecorder {
+    epoch: u64,
+    next: u64,
+    spans: Vec<OtelSpan>,
+}
+
+impl SpanRecorder {
+    pub fn new() -> Self {
+        Self {
+            epoch: now(),
+            next: 1,
+            spans: Vec::new(),
+        }
+    }
+
+    /// Return the ids of the span of a new call, in the trace of its parent if it has one.
+    pub fn start(&mut self, parent: Option<&SpanIds>) -> SpanIds {
+        let id = self.next;
+        self.next += 1;
+
+        let trace_id = match parent {
+            Some(parent) => parent.trace_id,
+            None => {
+                let mut trace_id = [0; 16];
+                trace_id[..8].copy_from_slice(&self.epoch.to_be_bytes());
+                trace_id[8..].copy_from_slice(&id.to_be_bytes());
+                trace_id
+            }
+        };
+
+        SpanIds {
+            trace_id,
+            span_id: (self.epoch ^ id.rotate_left(48)).to_be_bytes(),
+            parent_span_id: parent.map(|parent| parent.span_id),
+            start_time: now(),
+        }
+    }
+
+    /// Record the span of a call which was resolved.
+    pub fn finish(&mut self, span: OtelSpan) {
+        self.spans.push(span);
+    }
+
+    /// Return the spans of the calls resolved so far, in the order of their replies.
+    pub fn spans(&self) -> &[OtelSpan] {
+        &self.spans
+    }
+}
+
+/// Return the current time in nanoseconds since the unix epoch.
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default()
+}
+
+/// Encode the spans in the OTLP/JSON format, with a resource for each service.
+pub fn to_otlp_json(spans: &[OtelSpan]) -> String {
+    let mut services = BTreeMap::<&str, Vec<Value>>::new();
+
+    for span in spans {
+        services
+            .entry(span.service.as_str())
+            .or_default()
+            .push(encode_span(span));
+    }
+
+    let resource_spans = services
+        .into_iter()
+        .map(|(service, spans)| {
+            json!({
+                "resource": {
+                    "attributes": [attribute("service.name", json!({ "stringValue": service }))],
+                },
+                "scopeSpans": [{
+                    "scope": { "name": SCOPE_NAME },
+                    "spans": spans,
+                }],
+            })
+        })
+        .collect::<Vec<_>>();
+
+    json!({ "resourceSpans": resource_spans }).to_string()
+}
+
+fn encode_span(span: &OtelSpan) -> Value {
+    let mut attributes = vec![
+        attribute(
+            "ic.canister_id",
+            json!({ "stringValue": span.callee.to_text() }),
+        ),
+        attribute("ic.method", json!({ "stringValue": span.method })),
+        attribute("ic.caller", json!({ "stringValue": span.sender.to_text() })),
+        attribute(
+            "ic.arg_size",
+            json!({ "intValue": span.arg_size.to_string() }),
+        ),
+        attribute(
+            "ic.reply_size",
+            json!({ "intValue": span.reply_size.to_string() }),
+        ),
+    ];
+
+    if span.payment > 0 {
+        // The payments can be larger than the 64 bits integers of OTLP.
+        attributes.push(attribute(
+            "ic.cycles",
+            json!({ "stringValue": span.payment.to_string() }),
+        ));
+    }
+
+    let status = match &span.error {
+        Some(message) => json!({ "code": 2, "message": message }),
+        None => json!({ "code": 1 }),
+    };
+
+    let mut value = json!({
+        "traceId": hex(&span.trace_id),
+        "spanId": hex(&span.span_id),
+        "name": span.method,
+        "kind": SPAN_KIND_SERVER,
+        "startTimeUnixNano": span.start_time.to_string(),
+        "endTimeUnixNano": span.end_time.to_string(),
+        "attributes": attributes,
+        "status": status,
+    });
+
+    if let Some(parent) = &span.parent_span_id {
+        value["parentSpanId"] = json!(hex(parent));
+    }
+
+    value
+}
+
+fn attribute(key: &str, value: Value) -> Value {
+    json!({ "key": key, "value": value })
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,311 @@
+//! A backend for the replica which runs the wasm modules of the canisters on PocketIC, the
+//! execution environment of the Internet Computer, instead of running their methods in-process.
+//!
+//! The same tests can run on both of the backends, the in-process replica is fast and is the one
+//! used during development, and the PocketIC one validates the builds which are released. The
+//! features of the replica which need access to the internals of a canister, such as custom tasks,
+//! reading the stable memory or controlling the order of the messages, are not supported.
+//!
+//! The PocketIC server binary is found using the `POCKET_IC_BIN` environment variable.
+//!
+//! ```ignore
+//! let replica = Replica::pocket_ic().await;
+//! let counter = replica.add_canister(CounterCanister::anonymous().with_wasm(wasm));
+//! counter.init().await.assert_ok();
+//! ```
+
+use std::collections::HashMap;
+use std::panic::AssertUnwindSafe;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use candid::Principal;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use pocket_ic::nonblocking::PocketIc;
+use pocket_ic::{CanisterId, PocketIcBuilder, RejectCode, RejectResponse, Time};
+
+use ic_kit_sys::types::RejectionCode;
+
+use crate::backend::{unsupported, Backend};
+use crate::call::CallReply;
+use crate::canister::Canister;
+use crate::types::{CanisterCall, EntryMode, Env};
+
+/// The state of the canisters on a PocketIC instance.
+pub(crate) struct PocketBackend {
+    pic: PocketIc,
+    /// The wasm module and the initial balance of each canister which is not created yet, the
+    /// canisters are created by their init hook or before the first message sent to them.
+    pending: Mutex<HashMap<Principal, (Vec<u8>, u128)>>,
+    /// The change of the time which is applied before the next message.
+    time: Mutex<Option<TimeChange>>,
+}
+
+enum TimeChange {
+    Set(u64),
+    Advance(Duration),
+}
+
+impl PocketBackend {
+    /// Start a new PocketIC instance with an application subnet.
+    pub async fn new() -> Self {
+        Self {
+            pic: PocketIcBuilder::new()
+                .with_application_subnet()
+                .build_async()
+                .await,
+            pending: Mutex::new(HashMap::new()),
+            time: Mutex::new(None),
+        }
+    }
+
+    /// Perform the call as an ingress message.
+    async fn call(&self, call: CanisterCall) -> CallReply {
+        if call.payment > 0 {
+            unsupported(self.name(), "Sending cycles with an ingress message");
+        }
+
+        self.ensure_created(call.callee, None).await;
+        self.sync_time().await;
+
+        let result = self
+            .pic
+            .update_call(
+                to_pocket(&call.callee),
+                to_pocket(&call.sender),
+                &call.method,
+                call.arg,
+            )
+            .await;
+
+        to_reply(result)
+    }
+
+    /// Run the message on the canister, only the init hook, the heartbeat, the global timer and
+    /// the update and query methods can be run on PocketIC.
+    async fn run_env(&self, canister_id: Principal, env: Env) -> CallReply {
+        if let Some(time) = env.time {
+            self.set_time(time);
+        }
+
+        match env.entry_mode {
+            EntryMode::Init => {
+                if !self.pending.lock().unwrap().contains_key(&canister_id) {
+                    return CallReply::Reject {
+                        rejection_code: RejectionCode::CanisterError,
+                        rejection_message: format!(
+                            "Canister '{}' is already installed.",
+                            canister_id
+                        ),
+                        cycles_refunded: 0,
+                    };
+                }
+
+                self.sync_time().await;
+                self.ensure_created(canister_id, Some(env.args)).await
+            }
+            EntryMode::Heartbeat | EntryMode::GlobalTimer => {
+                self.ensure_created(canister_id, None).await;
+                self.sync_time().await;
+                self.pic.tick().await;
+
+                CallReply::Reply {
+                    data: Vec::new(),
+                    cycles_refunded: 0,
+                }
+            }
+            EntryMode::Update | EntryMode::Query => {
+                let method = env
+                    .method_name
+                    .expect("ic-kit-runtime: The message does not have a method name.");
+
+                self.ensure_created(canister_id, None).await;
+                self.sync_time().await;
+
+                let result = self
+                    .pic
+                    .update_call(
+                        to_pocket(&canister_id),
+                        to_pocket(&env.sender),
+                        &method,
+                        env.args,
+                    )
+                    .await;
+
+                to_reply(result)
+            }
+            mode => unsupported(self.name(), &format!("Running a {:?} message", mode)),
+        }
+    }
+
+    /// Upgrade the canister to the wasm module.
+    async fn upgrade(&self, canister_id: Principal, wasm: Vec<u8>, arg: Vec<u8>) -> CallReply {
+        self.ensure_created(canister_id, None).await;
+        self.sync_time().await;
+
+        let result = self
+            .pic
+            .upgrade_canister(to_pocket(&canister_id), wasm, arg, None)
+            .await;
+
+        to_reply(result.map(|_| Vec::new()))
+    }
+
+    /// Return the cycle balance of the canister.
+    async fn balance(&self, canister_id: Principal) -> u128 {
+        self.ensure_created(canister_id, None).await;
+        self.pic.cycle_balance(to_pocket(&canister_id)).await
+    }
+
+    /// Apply the change of the time, if there is one.
+    async fn sync_time(&self) {
+        let change = self.time.lock().unwrap().take();
+
+        match change {
+            Some(TimeChange::Set(time)) => {
+                self.pic
+                    .set_time(Time::from_nanos_since_unix_epoch(time))
+                    .await
+            }
+            Some(TimeChange::Advance(duration)) => self.pic.advance_time(duration).await,
+            None => {}
+        }
+    }
+
+    /// Create and install the canister if it's not created yet, using the given arguments for its
+    /// init hook or no arguments if there are none. Returns the reply of the init hook.
+    async fn ensure_created(&self, canister_id: Principal, arg: Option<Vec<u8>>) -> CallReply {
+        let (wasm, balance) = match self.pending.lock().unwrap().remove(&canister_id) {
+            Some(pending) => pending,
+            None => {
+                return CallReply::Reply {
+                    data: Vec::new(),
+                    cycles_refunded: 0,
+                }
+            }
+        };
+
+        let id = to_pocket(&canister_id);
+
+        if let Err(e) = self.pic.create_canister_with_id(None, None, id).await {
+            return CallReply::Reject {
+                rejection_code: RejectionCode::DestinationInvalid,
+                rejection_message: e,
+                cycles_refunded: 0,
+            };
+        }
+
+        let current = self.pic.cycle_balance(id).await;
+        if balance > current {
+            self.pic.add_cycles(id, balance - current).await;
+        }
+
+        let arg = arg.unwrap_or_else(|| ic_kit_sys::types::CANDID_EMPTY_ARG.to_vec());
+
+        // PocketIC panics if the init hook traps, which is reported as the rejection of the hook.
+        let result = AssertUnwindSafe(self.pic.install_canister(id, wasm, arg, None))
+            .catch_unwind()
+            .await;
+
+        match result {
+            Ok(()) => CallReply::Reply {
+                data: Vec::new(),
+                cycles_refunded: 0,
+            },
+            Err(payload) => CallReply::Reject {
+                rejection_code: RejectionCode::CanisterError,
+                rejection_message: payload
+                    .downcast_ref::<String>()
+                    .cloned()
+                    .or_else(|| payload.downcast_ref::<&str>().map(|s| s.to_string()))
+                    .unwrap_or_default(),
+                cycles_refunded: 0,
+            },
+        }
+    }
+}
+
+impl Backend for PocketBackend {
+    fn name(&self) -> &'static str {
+        "PocketIC"
+    }
+
+    /// The canister is created on PocketIC once it's first used.
+    fn add(&self, canister: &Canister) {
+        self.pending.lock().unwrap().insert(
+            canister.id(),
+            (wasm_of(canister).to_vec(), canister.initial_balance()),
+        );
+    }
+
+    fn call(&self, call: CanisterCall) -> BoxFuture<'_, CallReply> {
+        self.call(call).boxed()
+    }
+
+    fn run_env(&self, canister_id: Principal, env: Env) -> BoxFuture<'_, CallReply> {
+        self.run_env(canister_id, env).boxed()
+    }
+
+    fn upgrade(&self, canister: Canister, arg: Vec<u8>) -> BoxFuture<'_, CallReply> {
+        let wasm = wasm_of(&canister).to_vec();
+        self.upgrade(canister.id(), wasm, arg).boxed()
+    }
+
+    fn balance(&self, canister_id: Principal) -> BoxFuture<'_, u128> {
+        self.balance(canister_id).boxed()
+    }
+
+    fn set_time(&self, time: u64) {
+        *self.time.lock().unwrap() = Some(TimeChange::Set(time));
+    }
+
+    fn advance_time(&self, duration: Duration) {
+        let mut change = self.time.lock().unwrap();
+
+        *change = Some(match change.take() {
+            Some(TimeChange::Set(time)) => TimeChange::Set(time + duration.as_nanos() as u64),
+            Some(TimeChange::Advance(d)) => TimeChange::Advance(d + duration),
+            None => TimeChange::Advance(duration),
+        });
+    }
+}
+
+/// Return the wasm module of the canister.
+///
+/// # Panics
+///
+/// If the canister does not have a wasm module.
+fn wasm_of(canister: &Canister) -> &[u8] {
+    canister.wasm().unwrap_or_else(|| {
+        panic!(
+            "ic-kit-runtime: The canister '{}' needs a wasm module to run on PocketIC, see Canister::with_wasm.",
+            canister.id()
+        )
+    })
+}
+
+fn to_pocket(id: &Principal) -> CanisterId {
+    CanisterId::from_slice(id.as_slice())
+}
+
+fn to_reply(result: Result<Vec<u8>, RejectResponse>) -> CallReply {
+    match result {
+        Ok(data) => CallReply::Reply {
+            data,
+            cycles_refunded: 0,
+        },
+        Err(e) => CallReply::Reject {
+            rejection_code: match e.reject_code {
+                RejectCode::SysFatal => RejectionCode::SysFatal,
+                RejectCode::SysTransient => RejectionCode::SysTransient,
+                RejectCode::DestinationInvalid => RejectionCode::DestinationInvalid,
+                RejectCode::CanisterReject => RejectionCode::CanisterReject,
+                RejectCode::CanisterError => RejectionCode::CanisterError,
+                RejectCode::SysUnknown => RejectionCode::Unknown,
+            },
+            rejection_message: e.reject_message,
+            cycles_refunded: 0,
+        },
+    }
+}
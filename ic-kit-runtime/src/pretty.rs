@@ -0,0 +1,86 @@
+//! Render the candid arguments and replies of the calls as text rather than bytes, in the log of
+//! the calls and in the failures of the assertions.
+//!
+//! The bytes are decoded with the types of the method if the candid interface of the canister is
+//! known, see [`Canister::with_candid`], so the fields of the records are shown by their names.
+//! Otherwise they are decoded with the types carried by the message, and the fields are shown by
+//! their hash. The bytes which are not valid candid are shown in hex.
+//!
+//! ```text
+//! alice -> ledger.transfer (record { to = principal "rrkah-fqaaa-aaaaa-aaaaq-cai"; amount = 100 : nat64 })
+//! alice <- ledger.transfer: (variant { Ok = 1 : nat64 }) in 312µs
+//! ```
+//!
+//! [`Canister::with_candid`]: crate::Canister::with_candid
+
+use candid::parser::pretty::pp_args;
+use candid::types::Type;
+use candid::{check_prog, IDLArgs, IDLProg, TypeEnv};
+
+/// The number of bytes shown when the bytes are not valid candid.
+const MAX_PRINTED_BYTES: usize = 32;
+
+/// A parsed candid interface, which decodes the arguments and the replies of its methods.
+#[derive(Clone)]
+pub struct Interface {
+    env: TypeEnv,
+    actor: Type,
+}
+
+impl Interface {
+    /// Parse the candid interface, which must define a service.
+    pub fn parse(candid: &str) -> Result<Self, String> {
+        let prog = candid.parse::<IDLProg>().map_err(|e| e.to_string())?;
+        let mut env = TypeEnv::new();
+        let actor = check_prog(&mut env, &prog)
+            .map_err(|e| e.to_string())?
+            .ok_or_else(|| "The candid interface does not define a service.".to_string())?;
+
+        Ok(Self { env, actor })
+    }
+
+    /// Render the arguments of a call to the method.
+    pub fn args(&self, method: &str, bytes: &[u8]) -> String {
+        match self.env.get_method(&self.actor, method) {
+            Ok(function) => render(bytes, Some((&self.env, &function.args))),
+            Err(_) => render(bytes, None),
+        }
+    }
+
+    /// Render the reply of the method.
+    pub fn reply(&self, method: &str, bytes: &[u8]) -> String {
+        match self.env.get_method(&self.actor, method) {
+            Ok(function) => render(bytes, Some((&self.env, &function.rets))),
+            Err(_) => render(bytes, None),
+        }
+    }
+}
+
+/// Render the candid encoded bytes as text, with the types carried by the message.
+pub fn pretty(bytes: &[u8]) -> String {
+    render(bytes, None)
+}
+
+/// Render the bytes on a single line, decoded with the given types if they match.
+fn render(bytes: &[u8], types: Option<(&TypeEnv, &[Type])>) -> String {
+    let typed =
+        types.and_then(|(env, types)| IDLArgs::from_bytes_with_types(bytes, env, types).ok());
+
+    match typed.or_else(|| IDLArgs::from_bytes(bytes).ok()) {
+        Some(args) => pp_args(&args).pretty(usize::MAX).to_string(),
+        None => {
+            let hex = bytes
+                .iter()
+                .take(MAX_PRINTED_BYTES)
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>();
+            let ellipsis = if bytes.len() > MAX_PRINTED_BYTES {
+                "..."
+            } else {
+                ""
+            };
+
+            format!("<{} bytes: {}{}>", bytes.len(), hex, ellipsis)
+        }
+    }
+}
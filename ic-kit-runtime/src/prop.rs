@@ -0,0 +1,299 @@
+//! Strategies for property-based testing of canisters with the IC types, and a runner for the
+//! property tests against a replica. This module requires the `proptest` feature.
+//!
+//! ```ignore
+//! use ic_kit::rt::prop::{self, cycles, principal};
+//! use proptest::test_runner::Config;
+//!
+//! #[test]
+//! fn transfer_never_mints() {
+//!     prop::check(Config::default(), (principal(), cycles()), |replica, (to, amount)| async move {
+//!         let ledger = replica.add_canister(Ledger::anonymous());
+//!         // ...
+//!     });
+//! }
+//! ```
+
+use std::future::Future;
+
+use candid::parser::value::{IDLField, IDLValue, VariantValue};
+use candid::types::internal::Label;
+use candid::types::{Field, Type};
+use candid::{IDLArgs, Int, Nat, Principal, TypeEnv};
+use proptest::collection::vec;
+use proptest::prelude::*;
+use proptest::test_runner::{Config, TestRunner};
+
+use crate::replica::{block_on_replica, canister_id};
+use crate::Replica;
+
+/// How deep the values of the recursive types are generated, deeper optional values are `null`
+/// and deeper vectors are empty.
+const MAX_DEPTH: usize = 4;
+
+/// The largest number of elements generated for a vector.
+const MAX_VEC_LEN: usize = 8;
+
+/// Generate principals of the different kinds: the anonymous and management principals, canister
+/// ids, self-authenticating principals of users, and opaque principals of any length.
+pub fn principal() -> impl Strategy<Value = Principal> {
+    prop_oneof![
+        Just(Principal::anonymous()),
+        Just(Principal::management_canister()),
+        any::<u64>().prop_map(canister_id),
+        any::<[u8; 28]>().prop_map(|hash| {
+            let mut bytes = hash.to_vec();
+            bytes.push(0x02);
+            Principal::from_slice(&bytes)
+        }),
+        vec(any::<u8>(), 0..=29).prop_map(|bytes| Principal::from_slice(&bytes)),
+    ]
+}
+
+/// Generate valid ledger account identifiers, which are a hash prefixed by its CRC32 checksum.
+pub fn account_identifier() -> impl Strategy<Value = [u8; 32]> {
+    any::<[u8; 28]>().prop_map(|hash| {
+        let mut id = [0; 32];
+        id[0..4].copy_from_slice(&crc32fast::hash(&hash).to_be_bytes());
+        id[4..].copy_from_slice(&hash);
+        id
+    })
+}
+
+/// Generate natural numbers, including the ones larger than a u128.
+pub fn nat() -> impl Strategy<Value = Nat> {
+    prop_oneof![
+        any::<u64>().prop_map(Nat::from),
+        any::<u128>().prop_map(Nat::from),
+        "[1-9][0-9]{39,80}".prop_map(|n| Nat::parse(n.as_bytes()).unwrap()),
+    ]
+}
+
+/// Generate integers, including the ones out of the range of an i128.
+pub fn int() -> impl Strategy<Value = Int> {
+    prop_oneof![
+        any::<i64>().prop_map(Int::from),
+        any::<i128>().prop_map(Int::from),
+        "-?[1-9][0-9]{39,80}".prop_map(|n| Int::parse(n.as_bytes()).unwrap()),
+    ]
+}
+
+/// Generate amounts of cycles, biased towards zero, the common amounts and the edges of a u64.
+pub fn cycles() -> impl Strategy<Value = u128> {
+    prop_oneof![
+        Just(0),
+        1..1_000_000u128,
+        1_000_000..100_000_000_000_000u128,
+        Just(u64::MAX as u128),
+        Just(u64::MAX as u128 + 1),
+        any::<u128>(),
+    ]
+}
+
+/// Generate candid types, which are used to generate random candid values.
+pub fn candid_type() -> impl Strategy<Value = Type> {
+    let leaf = prop_oneof![
+        Just(Type::Null),
+        Just(Type::Bool),
+        Just(Type::Nat),
+        Just(Type::Int),
+        Just(Type::Nat8),
+        Just(Type::Nat16),
+        Just(Type::Nat32),
+        Just(Type::Nat64),
+        Just(Type::Int8),
+        Just(Type::Int16),
+        Just(Type::Int32),
+        Just(Type::Int64),
+        Just(Type::Float32),
+        Just(Type::Float64),
+        Just(Type::Text),
+        Just(Type::Reserved),
+        Just(Type::Principal),
+    ];
+
+    leaf.prop_recursive(MAX_DEPTH as u32, 32, 4, |inner| {
+        prop_oneof![
+            inner.clone().prop_map(|ty| Type::Opt(Box::new(ty))),
+            inner.clone().prop_map(|ty| Type::Vec(Box::new(ty))),
+            vec(inner.clone(), 0..4).prop_map(|types| Type::Record(fields(types))),
+            vec(inner, 1..4).prop_map(|types| Type::Variant(fields(types))),
+        ]
+    })
+}
+
+/// Generate a value of a random candid type, along with its type so the value can be encoded.
+pub fn candid_value() -> impl Strategy<Value = (Type, IDLValue)> {
+    candid_type().prop_flat_map(|ty| {
+        let value = candid_value_of(&TypeEnv::new(), &ty);
+        (Just(ty), value)
+    })
+}
+
+/// Generate the values of the given candid type, where the named types are resolved using the
+/// environment, for example one loaded from the candid interface of a canister.
+///
+/// # Panics
+///
+/// If the type has no values, such as `empty`, or if it refers to a type missing from the
+/// environment.
+pub fn candid_value_of(env: &TypeEnv, ty: &Type) -> BoxedStrategy<IDLValue> {
+    value_of(env, ty, 0)
+}
+
+/// Generate the arguments of a method with the given argument types, which can be encoded with
+/// [`IDLArgs::to_bytes_with_types`].
+pub fn candid_args_of(env: &TypeEnv, types: &[Type]) -> BoxedStrategy<IDLArgs> {
+    types
+        .iter()
+        .map(|ty| candid_value_of(env, ty))
+        .collect::<Vec<_>>()
+        .prop_map(|args| IDLArgs::new(&args))
+        .boxed()
+}
+
+/// Run the property test for the values generated by the strategy, each case runs in a new
+/// replica so the cases do not affect each other. The failing cases are shrunk to the simplest
+/// value which still fails, like the `proptest!` macro does. The failures are only persisted if
+/// the `source_file` of the config is set.
+///
+/// # Panics
+///
+/// If the test fails for any of the generated values.
+pub fn check<S, F, Fut>(mut config: Config, strategy: S, test: F)
+where
+    S: Strategy,
+    F: Fn(Replica, S::Value) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    if config.source_file.is_none() {
+        config.failure_persistence = None;
+    }
+
+    let mut runner = TestRunner::new(config);
+
+    let result = runner.run(&strategy, |value| {
+        block_on_replica(|replica| test(replica, value));
+        Ok(())
+    });
+
+    if let Err(e) = result {
+        panic!("{}\n{}", e, runner);
+    }
+}
+
+/// Name the fields of a generated record or variant type.
+fn fields(types: Vec<Type>) -> Vec<Field> {
+    let mut fields = types
+        .into_iter()
+        .enumerate()
+        .map(|(i, ty)| Field {
+            id: Label::Named(format!("field_{}", i)),
+            ty,
+        })
+        .collect::<Vec<_>>();
+
+    fields.sort_by_key(|field| field.id.get_id());
+    fields
+}
+
+fn value_of(env: &TypeEnv, ty: &Type, depth: usize) -> BoxedStrategy<IDLValue> {
+    if depth > 4 * MAX_DEPTH {
+        panic!(
+            "Can not generate the values of the recursive type '{}'.",
+            ty
+        );
+    }
+
+    match ty {
+        Type::Null => Just(IDLValue::Null).boxed(),
+        Type::Bool => any::<bool>().prop_map(IDLValue::Bool).boxed(),
+        Type::Nat => nat().prop_map(IDLValue::Nat).boxed(),
+        Type::Int => int().prop_map(IDLValue::Int).boxed(),
+        Type::Nat8 => any::<u8>().prop_map(IDLValue::Nat8).boxed(),
+        Type::Nat16 => any::<u16>().prop_map(IDLValue::Nat16).boxed(),
+        Type::Nat32 => any::<u32>().prop_map(IDLValue::Nat32).boxed(),
+        Type::Nat64 => any::<u64>().prop_map(IDLValue::Nat64).boxed(),
+        Type::Int8 => any::<i8>().prop_map(IDLValue::Int8).boxed(),
+        Type::Int16 => any::<i16>().prop_map(IDLValue::Int16).boxed(),
+        Type::Int32 => any::<i32>().prop_map(IDLValue::Int32).boxed(),
+        Type::Int64 => any::<i64>().prop_map(IDLValue::Int64).boxed(),
+        Type::Float32 => any::<f32>().prop_map(IDLValue::Float32).boxed(),
+        Type::Float64 => any::<f64>().prop_map(IDLValue::Float64).boxed(),
+        Type::Text => any::<String>().prop_map(IDLValue::Text).boxed(),
+        Type::Reserved => Just(IDLValue::Reserved).boxed(),
+        Type::Principal => principal().prop_map(IDLValue::Principal).boxed(),
+        Type::Service(_) => principal().prop_map(IDLValue::Service).boxed(),
+        Type::Func(_) => principal()
+            .prop_map(|id| IDLValue::Func(id, "method".into()))
+            .boxed(),
+        Type::Var(name) => {
+            let ty = env
+                .rec_find_type(name)
+                .unwrap_or_else(|e| panic!("Could not resolve the type '{}': {}", name, e));
+            value_of(env, ty, depth)
+        }
+        Type::Opt(_) if depth >= MAX_DEPTH => Just(IDLValue::None).boxed(),
+        Type::Opt(ty) => prop_oneof![
+            1 => Just(IDLValue::None),
+            3 => value_of(env, ty, depth + 1).prop_map(|v| IDLValue::Opt(Box::new(v))),
+        ]
+        .boxed(),
+        Type::Vec(_) if depth >= MAX_DEPTH => Just(IDLValue::Vec(Vec::new())).boxed(),
+        Type::Vec(ty) => vec(value_of(env, ty, depth + 1), 0..MAX_VEC_LEN)
+            .prop_map(IDLValue::Vec)
+            .boxed(),
+        Type::Record(fields) => fields
+            .iter()
+            .map(|field| {
+                let id = field.id.clone();
+                value_of(env, &field.ty, depth + 1).prop_map(move |val| IDLField {
+                    id: id.clone(),
+                    val,
+                })
+            })
+            .collect::<Vec<_>>()
+            .prop_map(IDLValue::Record)
+            .boxed(),
+        Type::Variant(fields) => {
+            // Past the maximum depth only the cases which do not nest more values are chosen,
+            // so the values of the recursive variants end.
+            let shallow = fields.iter().any(|field| is_shallow(env, &field.ty));
+
+            let options = fields
+                .iter()
+                .enumerate()
+                .filter(|(_, field)| depth < MAX_DEPTH || !shallow || is_shallow(env, &field.ty))
+                .map(|(index, field)| {
+                    let id = field.id.clone();
+                    value_of(env, &field.ty, depth + 1)
+                        .prop_map(move |val| {
+                            let field = IDLField {
+                                id: id.clone(),
+                                val,
+                            };
+                            IDLValue::Variant(VariantValue(Box::new(field), index as u64))
+                        })
+                        .boxed()
+                })
+                .collect::<Vec<_>>();
+
+            proptest::strategy::Union::new(options).boxed()
+        }
+        _ => panic!("Can not generate the values of the type '{}'.", ty),
+    }
+}
+
+/// Returns true if the values of the type do not contain any other value, except for the
+/// optional values and the vectors which are empty past the maximum depth.
+fn is_shallow(env: &TypeEnv, ty: &Type) -> bool {
+    match ty {
+        Type::Var(name) => env
+            .rec_find_type(name)
+            .map(|ty| !matches!(ty, Type::Var(_)) && is_shallow(env, ty))
+            .unwrap_or(false),
+        Type::Record(fields) => fields.is_empty(),
+        Type::Variant(_) => false,
+        _ => true,
+    }
+}
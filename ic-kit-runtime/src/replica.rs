@@ -13,25 +13,193 @@
 //! This also allows the canister event loops to have accesses to the replica without any borrows by
 //! just sending their request to the same channel, causing the replica to process the messages.
 
-use std::collections::HashMap;
+use std::any::Any;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::future::Future;
-use std::panic::{RefUnwindSafe, UnwindSafe};
+use std::panic::{AssertUnwindSafe, RefUnwindSafe, UnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::{fmt, fs, io};
 
 use candid::Principal;
+use futures::future::BoxFuture;
+use futures::FutureExt;
 use tokio::sync::{mpsc, oneshot};
 
 use ic_kit_sys::types::RejectionCode;
 
+#[cfg(feature = "ic-agent")]
+use crate::agent::AgentBackend;
+use crate::backend::{self, Backend};
+use crate::bench::{Bench, Counters};
+use crate::blackhole::{BlackholeMock, BLACKHOLE_CANISTER_ID};
 use crate::call::{CallBuilder, CallReply};
-use crate::canister::Canister;
-use crate::handle::CanisterHandle;
+use crate::canister::{downcast_panic_payload, Canister, CanisterCode};
+use crate::certification::{self, LabeledTree};
+use crate::chaos::{Chaos, ChaosState};
+use crate::coverage::{CanisterCoverage, Coverage};
+use crate::cycles::CyclesReport;
+use crate::deploy::{Deployed, Deployment};
+use crate::dump::{self, CanisterState, ReplicaSettings};
+use crate::explore::Schedule;
+use crate::handle::{CanisterHandle, CanisterHealth};
+use crate::ingress::RequestStatus;
+#[cfg(feature = "inspector")]
+use crate::inspect::Inspector;
+use crate::invariant::{InvariantView, Probe, Violation};
+use crate::leak::LeakCheck;
+use crate::load::LoadTest;
+use crate::management::{self, Lifecycle, ManagementMock, RunStatus};
+use crate::metadata::MetadataVisibility;
+use crate::otel::{self, OtelSpan};
+#[cfg(feature = "pocket-ic")]
+use crate::pocket::PocketBackend;
+use crate::pretty::Interface;
+use crate::shard::{self, Broadcast};
+use crate::sns::SnsMock;
+use crate::span::MessageSpan;
+use crate::subnet::{LanePolicy, SubnetConfig};
+use crate::trace::{CallTracer, PayloadSize, UnresolvedCall};
 use crate::types::*;
+use crate::xrc::{XrcMock, XRC_CANISTER_ID};
+use crate::TokioRuntimeBuilder;
 
 /// A local replica that contains one or several canisters.
 pub struct Replica {
     // The current implementation uses a `tokio::spawn` to run an event loop for the replica,
     // the state of the replica is store in that event loop.
     sender: mpsc::UnboundedSender<ReplicaMessage>,
+    /// The directory the state of the canisters is persisted to, if any.
+    state_dir: Option<PathBuf>,
+    /// The canisters added to the replica, in order.
+    canisters: Mutex<Vec<Principal>>,
+    /// The canisters whose state was loaded from the state directory or from a dump.
+    restored: Mutex<HashSet<Principal>>,
+    /// The stable memory and the balance of the canisters in the dump loaded by [`Replica::load`],
+    /// which are restored when the canisters are added.
+    loaded: Mutex<HashMap<Principal, (Vec<u8>, u128)>>,
+    /// The candid interfaces of the canisters, if they were provided.
+    interfaces: Arc<Mutex<HashMap<Principal, String>>>,
+    /// The build each canister is running, which is used to create its forks.
+    codes: Arc<Mutex<HashMap<Principal, CanisterCode>>>,
+    /// The number of messages delivered to the canisters which are not processed yet.
+    in_flight: Arc<AtomicUsize>,
+    /// If set, the messages are held until they are delivered by [`Replica::step`].
+    paused: AtomicBool,
+    /// The order in which the held messages are delivered by `interleave`.
+    pub(crate) schedule: Arc<Mutex<Schedule>>,
+    /// The usage of the resources by the canisters, which is measured by the benchmarks.
+    counters: Arc<Counters>,
+    /// The names of the principals and the log of the calls.
+    tracer: Arc<Mutex<CallTracer>>,
+    /// The costs and the limits of the subnet the canisters run on.
+    subnet: SubnetConfig,
+    /// If set, the canisters run on this backend instead of in-process, see [`crate::backend`].
+    backend: Option<Arc<dyn Backend>>,
+    /// The mock of the management canister, once it was added by [`Replica::management`].
+    management: Mutex<Option<ManagementMock>>,
+    /// The mock of the exchange rate canister, once it was added by [`Replica::xrc`].
+    xrc: Mutex<Option<XrcMock>>,
+    /// The mock of the blackhole canister, once it was added by [`Replica::blackhole`].
+    blackhole: Mutex<Option<BlackholeMock>>,
+    /// Draws the restarts of the chaos mode, once it was enabled by [`Replica::set_chaos`].
+    chaos: Mutex<Option<ChaosState>>,
+    /// The mocks of the canisters of the SNS, once they were added by [`Replica::sns`].
+    sns: Mutex<Option<SnsMock>>,
+}
+
+/// Return the id of the canister at the given index, in the same format as the ids assigned to
+/// the canisters on the Internet Computer.
+pub fn canister_id(index: u64) -> Principal {
+    let mut bytes = index.to_be_bytes().to_vec();
+    bytes.extend_from_slice(&[0x01, 0x01]);
+    Principal::from_slice(&bytes)
+}
+
+/// A message held by a paused replica, see [`Replica::pause`]. The next slice of a long message is
+/// also held, as a message without a sender and an argument, see
+/// [`SubnetConfig::max_instructions_per_slice`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct PendingMessage {
+    /// The canister the message is delivered to.
+    pub canister_id: Principal,
+    /// The type of the message, such as an update call or a reply callback.
+    pub entry_mode: EntryMode,
+    /// The principal which made the call, if the message is a call.
+    pub sender: Option<Principal>,
+    /// The method called, if the message is a call.
+    pub method: Option<String>,
+    /// The argument of the call, or the data of the reply.
+    pub arg: Vec<u8>,
+    /// The cycles sent with the call, or refunded with the reply.
+    pub cycles: u128,
+    /// The description of the message, with the names given to the principals.
+    pub description: String,
+}
+
+/// The metadata of a canister of the replica, see [`Replica::canisters`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CanisterInfo {
+    /// The id of the canister.
+    pub canister_id: Principal,
+    /// The name given to the canister with [`Replica::name`], if any.
+    pub name: Option<String>,
+    /// The module hash of the build the canister is running, see [`Canister::module_hash`].
+    pub module_hash: [u8; 32],
+    /// The controllers of the canister.
+    pub controllers: Vec<Principal>,
+    /// The cycle balance of the canister after its last message.
+    pub balance: u128,
+    /// The size of the stable memory of the canister in bytes after its last message.
+    pub memory_size: u64,
+    /// Whether the canister is running, or stopped by the management canister.
+    pub status: CanisterStatus,
+    /// Whether the execution task of the canister is alive.
+    pub health: CanisterHealth,
+}
+
+/// Whether a canister is running, see [`CanisterInfo::status`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CanisterStatus {
+    /// The canister executes the messages sent to it.
+    Running,
+    /// The canister is waiting for its open calls to finish before it stops.
+    Stopping,
+    /// The canister rejects the messages sent to it, see [`crate::management`].
+    Stopped,
+}
+
+impl fmt::Display for CanisterStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CanisterStatus::Running => f.write_str("running"),
+            CanisterStatus::Stopping => f.write_str("stopping"),
+            CanisterStatus::Stopped => f.write_str("stopped"),
+        }
+    }
+}
+
+/// How the time of the replica moves, see [`Replica::set_time_policy`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimePolicy {
+    /// Use the system time, this is the policy of a new replica until its time is fixed with
+    /// [`Replica::set_time`].
+    WallClock,
+    /// Freeze the time, which only moves with [`Replica::set_time`] and
+    /// [`Replica::advance_time`].
+    Logical,
+    /// Move the time forward by the given duration after each message, like the rounds of the
+    /// Internet Computer, so the time flows with the execution of the canisters and not with the
+    /// speed of the host.
+    AutoAdvance(Duration),
+}
+
+impl Default for TimePolicy {
+    fn default() -> Self {
+        TimePolicy::WallClock
+    }
 }
 
 /// The state of the replica, it does not live inside the replica itself, but an instance of it
@@ -41,6 +209,35 @@ pub struct Replica {
 struct ReplicaState {
     /// Map each of the current canisters to the receiver of that canister's event loop.
     canisters: HashMap<Principal, mpsc::UnboundedSender<ReplicaCanisterRequest>>,
+    /// Used to stop the event loop of each canister when it's removed, see `canister_worker`.
+    stops: HashMap<Principal, mpsc::UnboundedSender<oneshot::Sender<()>>>,
+    /// If set, the messages are held in `pending` until they are delivered one by one.
+    hold: bool,
+    /// The messages which are held, in the order they were received.
+    pending: Vec<(Principal, HeldMessage)>,
+    /// The canisters executing a long message in slices, with the messages delivered to them in
+    /// the meantime, which are queued until the long message is finished.
+    sliced: HashMap<Principal, Vec<ReplicaCanisterRequest>>,
+    /// Shared with the `Replica`, see `Replica::in_flight`.
+    in_flight: Arc<AtomicUsize>,
+    /// The time used for the messages which do not provide one, if it was fixed.
+    time: Option<u64>,
+    /// How the time moves, see `Replica::set_time_policy`.
+    time_policy: TimePolicy,
+    /// Shared with the `Replica`, see `Replica::tracer`.
+    tracer: Arc<Mutex<CallTracer>>,
+    /// Draws the faults of the messages, if the chaos mode is enabled.
+    chaos: Option<ChaosState>,
+    /// The messages held back by the chaos mode, with the number of messages to deliver before
+    /// each of them.
+    delayed: Vec<(u32, Principal, ReplicaCanisterRequest)>,
+    /// The calls whose replies are dropped, since the build of the canister which made them was
+    /// replaced.
+    dropped_replies: HashSet<RequestId>,
+    /// Executes the lifecycle methods of the management canister, once it was added.
+    lifecycle: Option<Lifecycle>,
+    /// Shared with the `Replica`, see `Replica::counters`.
+    counters: Arc<Counters>,
 }
 
 /// A message that Replica wants to send to a canister to be processed.
@@ -49,20 +246,117 @@ struct ReplicaCanisterRequest {
     reply_sender: Option<oneshot::Sender<CallReply>>,
 }
 
+/// The next slice of a long message, which is executed in the next round.
+struct ReplicaSlice {
+    entry_mode: EntryMode,
+    method: Option<String>,
+    /// The number of the slice, starting from 2 for the first slice after the one the message
+    /// started in.
+    slice: u64,
+    resume: oneshot::Sender<()>,
+}
+
+/// A message held by a paused replica.
+enum HeldMessage {
+    Request(ReplicaCanisterRequest),
+    Slice(ReplicaSlice),
+}
+
+/// Used by a canister to pause a long message until the next round, see
+/// [`SubnetConfig::max_instructions_per_slice`].
+pub(crate) struct Rounds {
+    canister_id: Principal,
+    sender: mpsc::UnboundedSender<ReplicaMessage>,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl Rounds {
+    /// Wait for the given slice of the message to be delivered, the messages of the other
+    /// canisters are executed in between.
+    pub async fn next(&self, env: &Env, slice: u64) {
+        let (tx, rx) = oneshot::channel();
+
+        self.sender
+            .send(ReplicaMessage::Slice {
+                canister_id: self.canister_id,
+                slice: ReplicaSlice {
+                    entry_mode: env.entry_mode,
+                    method: env.method_name.clone(),
+                    slice,
+                    resume: tx,
+                },
+            })
+            .unwrap_or_else(|_| panic!("ic-kit-runtime: could not send message to replica"));
+
+        // The message is not in flight until its next slice is delivered, so a paused replica can
+        // still be stepped.
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+
+        // If the replica is gone, or the canister was removed, the rest of the message is
+        // executed right away.
+        if rx.await.is_err() {
+            self.in_flight.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
 enum ReplicaMessage {
     CanisterAdded {
         canister_id: Principal,
         channel: mpsc::UnboundedSender<ReplicaCanisterRequest>,
+        stop: mpsc::UnboundedSender<oneshot::Sender<()>>,
+        /// If set, the canister replaces an existing canister with the same id.
+        replace: bool,
+    },
+    CanisterRemoved {
+        canister_id: Principal,
+        /// Resolved once the event loop of the canister is stopped.
+        reply_sender: oneshot::Sender<()>,
     },
     CanisterRequest {
         canister_id: Principal,
         message: Message,
         reply_sender: Option<oneshot::Sender<CallReply>>,
     },
+    /// Several requests to the same canister sent at once, see `Replica::enqueue_requests`.
+    CanisterRequests {
+        canister_id: Principal,
+        requests: Vec<(Message, oneshot::Sender<CallReply>)>,
+    },
     CanisterReply {
         canister_id: Principal,
         message: Message,
     },
+    SetTime(u64),
+    AdvanceTime(Duration),
+    SetTimePolicy(TimePolicy),
+    SetChaos(Chaos),
+    Time {
+        reply_sender: oneshot::Sender<Option<u64>>,
+    },
+    Hold(bool),
+    Pending {
+        reply_sender: oneshot::Sender<Vec<PendingMessage>>,
+    },
+    Deliver {
+        index: usize,
+        reply_sender: oneshot::Sender<()>,
+    },
+    /// A canister paused a long message until the next round.
+    Slice {
+        canister_id: Principal,
+        slice: ReplicaSlice,
+    },
+    /// A canister finished a long message, so the messages queued for it can be delivered.
+    SlicesDone {
+        canister_id: Principal,
+        reply_sender: oneshot::Sender<()>,
+    },
+    /// Drop the replies to these calls, which were made by a build of a canister which was
+    /// replaced since.
+    DropReplies(Vec<RequestId>),
+    /// Execute the lifecycle methods of the management canister, see `Replica::management`.
+    SetLifecycle(Lifecycle),
 }
 
 impl Replica {
@@ -77,72 +371,1556 @@ impl Replica {
         tmp
     }
 
-    /// Add the given canister to this replica.
-    pub fn add_canister(&self, canister: Canister) -> CanisterHandle {
+    /// Create an empty replica which persists the state of its canisters in the given directory.
+    ///
+    /// When a canister is added to the replica, its stable memory is loaded from the directory if
+    /// it was saved by a previous replica, in which case [`CanisterHandle::init_or_restore`] runs
+    /// the `post_upgrade` hook instead of the `init` hook. Calling [`Replica::save_state`] runs the
+    /// `pre_upgrade` hook of each canister, so the heap state is written to the stable memory, and
+    /// saves the stable memory of each canister to the directory.
+    ///
+    /// ```ignore
+    /// let replica = Replica::with_state_dir("./.ic-kit-state");
+    /// let canister = replica.add_canister(CounterCanister::anonymous());
+    /// canister.init_or_restore().await;
+    /// // ...
+    /// replica.save_state().await.unwrap();
+    /// ```
+    pub fn with_state_dir<P: Into<PathBuf>>(dir: P) -> Self {
+        Replica {
+            state_dir: Some(dir.into()),
+            ..Replica::default()
+        }
+    }
+
+    /// Create an empty replica whose canisters run on a new PocketIC instance, using the wasm
+    /// module provided with [`Canister::with_wasm`] for each canister. The calls, the hooks, the
+    /// upgrades, the time and the balances work the same as on the in-process replica, but the
+    /// features that need access to the internals of a canister panic, see [`crate::pocket`].
+    #[cfg(feature = "pocket-ic")]
+    pub async fn pocket_ic() -> Self {
+        Replica {
+            backend: Some(Arc::new(PocketBackend::new().await)),
+            ..Replica::default()
+        }
+    }
+
+    /// Create an empty replica which sends the calls to the canisters deployed on a live
+    /// network, see [`crate::agent`].
+    ///
+    /// ```ignore
+    /// let replica = Replica::with_agent(AgentBackend::local().with_dfx_identity("default"));
+    /// ```
+    #[cfg(feature = "ic-agent")]
+    pub fn with_agent(backend: AgentBackend) -> Self {
+        Replica {
+            backend: Some(Arc::new(backend)),
+            ..Replica::default()
+        }
+    }
+
+    /// Create the replica used by the tests created by the `#[kit_test]` macro, which is an
+    /// in-process replica unless one of these environment variables is set:
+    ///
+    /// - `IC_KIT_POCKET_IC`, with the `pocket-ic` feature, runs the canisters on PocketIC, which
+    ///   validates the release builds of the canisters.
+    /// - `IC_KIT_AGENT_URL`, with the `ic-agent` feature, sends the calls to the canisters
+    ///   deployed on the network at the url, signed by the dfx identity named by
+    ///   `IC_KIT_DFX_IDENTITY` if it's set.
+    pub async fn from_env() -> Self {
+        #[cfg(feature = "pocket-ic")]
+        if std::env::var_os("IC_KIT_POCKET_IC").is_some() {
+            return Replica::pocket_ic().await;
+        }
+
+        #[cfg(feature = "ic-agent")]
+        if let Ok(url) = std::env::var("IC_KIT_AGENT_URL") {
+            let mut backend = AgentBackend::new(url);
+
+            if let Ok(name) = std::env::var("IC_KIT_DFX_IDENTITY") {
+                backend = backend.with_dfx_identity(&name);
+            }
+
+            return Replica::with_agent(backend);
+        }
+
+        Replica::default()
+    }
+
+    /// Run the canisters added from now on with the costs and the limits of the given subnet, see
+    /// [`crate::subnet`].
+    pub fn with_subnet(mut self, subnet: SubnetConfig) -> Self {
+        self.subnet = subnet;
+        self
+    }
+
+    /// Return the costs and the limits of the subnet the canisters run on.
+    pub fn subnet(&self) -> &SubnetConfig {
+        &self.subnet
+    }
+
+    /// Return the backend the canisters run on, if they don't run in-process.
+    pub(crate) fn backend(&self) -> Option<&dyn Backend> {
+        self.backend.as_deref()
+    }
+
+    /// Add the given canister to this replica.
+    pub fn add_canister(&self, mut canister: Canister) -> CanisterHandle {
+        let canister_id = canister.id();
+
+        if let Some(dir) = &self.state_dir {
+            match fs::read(stable_memory_path(dir, canister_id)) {
+                Ok(data) => {
+                    canister.load_stable(&data);
+                    self.restored.lock().unwrap().insert(canister_id);
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => panic!(
+                    "ic-kit-runtime: Could not load the state of canister '{}': {}",
+                    canister_id, e
+                ),
+            }
+        }
+
+        let loaded = self.loaded.lock().unwrap().remove(&canister_id);
+        if let Some((data, balance)) = loaded {
+            canister.load_stable(&data);
+            canister = canister.with_balance(balance);
+            self.restored.lock().unwrap().insert(canister_id);
+        }
+
+        self.canisters.lock().unwrap().push(canister_id);
+        self.install(canister, false)
+    }
+
+    /// Start running the canister, if `replace` is set the canister replaces the running canister
+    /// with the same id, which is how a canister is upgraded.
+    pub(crate) fn install(&self, mut canister: Canister, replace: bool) -> CanisterHandle<'_> {
+        let canister_id = canister.id();
+        let installer = self.installer();
+
+        installer.prepare(&mut canister);
+        installer.register(&canister);
+
+        if let Some(backend) = self.backend() {
+            backend.add(&canister);
+        } else {
+            installer.start(canister, replace);
+        }
+
+        CanisterHandle {
+            replica: self,
+            canister_id,
+            last_balance: Mutex::new(None),
+        }
+    }
+
+    /// Return the parts of the replica which install the canisters, which can be moved to the
+    /// tasks run by the management canister.
+    pub(crate) fn installer(&self) -> Installer {
+        Installer {
+            sender: self.sender.clone(),
+            codes: self.codes.clone(),
+            interfaces: self.interfaces.clone(),
+            in_flight: self.in_flight.clone(),
+            counters: self.counters.clone(),
+            tracer: self.tracer.clone(),
+            subnet: self.subnet.clone(),
+        }
+    }
+
+    /// Remove the canister from the replica, once the message it's executing is finished. The
+    /// messages queued for the canister are rejected with `DestinationInvalid`, like the calls
+    /// made to it afterwards, and the calls it has not replied to yet are rejected with
+    /// `CanisterReject`.
+    ///
+    /// # Panics
+    ///
+    /// If the canister is not in the replica.
+    pub async fn remove_canister(&self, canister_id: Principal) {
+        if let Some(backend) = self.backend() {
+            backend::unsupported(backend.name(), "Removing a canister");
+        }
+
+        {
+            let mut canisters = self.canisters.lock().unwrap();
+            let index = canisters
+                .iter()
+                .position(|id| *id == canister_id)
+                .unwrap_or_else(|| panic!("Canister '{}' is not in the replica.", canister_id));
+            canisters.remove(index);
+        }
+
+        self.restored.lock().unwrap().remove(&canister_id);
+        self.interfaces.lock().unwrap().remove(&canister_id);
+        self.codes.lock().unwrap().remove(&canister_id);
+        self.counters.statuses.lock().unwrap().remove(&canister_id);
+        self.counters.crashes.lock().unwrap().remove(&canister_id);
+        self.counters
+            .compute_allocations
+            .lock()
+            .unwrap()
+            .remove(&canister_id);
+
+        let (tx, rx) = oneshot::channel();
+        self.send(ReplicaMessage::CanisterRemoved {
+            canister_id,
+            reply_sender: tx,
+        });
+        let _ = rx.await;
+    }
+
+    /// Return the ids of the canisters, in the order they were added.
+    pub(crate) fn canister_ids(&self) -> Vec<Principal> {
+        self.canisters.lock().unwrap().clone()
+    }
+
+    /// Return the metadata of each of the canisters, in the order they were added, so the tools of
+    /// the tests such as the invariant checkers can go over all of the canisters without a handle
+    /// to each of them. The mocks added to the replica are listed as well.
+    ///
+    /// ```ignore
+    /// for canister in replica.canisters() {
+    ///     println!("{}: {} cycles, {}", canister.canister_id, canister.balance, canister.status);
+    /// }
+    /// ```
+    pub fn canisters(&self) -> Vec<CanisterInfo> {
+        if let Some(backend) = self.backend() {
+            backend::unsupported(backend.name(), "Listing the canisters");
+        }
+
+        self.canister_ids()
+            .into_iter()
+            .filter_map(|canister_id| {
+                let (module_hash, controllers) = {
+                    let codes = self.codes.lock().unwrap();
+                    let code = codes.get(&canister_id)?;
+                    (code.module_hash(), code.controllers().to_vec())
+                };
+
+                let (balance, memory_size) = self
+                    .counters
+                    .statuses
+                    .lock()
+                    .unwrap()
+                    .get(&canister_id)
+                    .copied()
+                    .unwrap_or_default();
+
+                let status = match self.counters.run_statuses.lock().unwrap().get(&canister_id) {
+                    Some(RunStatus::Stopping) => CanisterStatus::Stopping,
+                    Some(RunStatus::Stopped) => CanisterStatus::Stopped,
+                    None => CanisterStatus::Running,
+                };
+
+                let health = match self.counters.crashes.lock().unwrap().get(&canister_id) {
+                    Some(panic) => CanisterHealth::Crashed(panic.clone()),
+                    None => CanisterHealth::Running,
+                };
+
+                Some(CanisterInfo {
+                    canister_id,
+                    name: self.tracer.lock().unwrap().given_name(&canister_id),
+                    module_hash,
+                    controllers,
+                    balance,
+                    memory_size,
+                    status,
+                    health,
+                })
+            })
+            .collect()
+    }
+
+    /// Return the handle to a canister.
+    pub fn get_canister(&self, canister_id: Principal) -> CanisterHandle {
+        CanisterHandle {
+            replica: &self,
+            canister_id,
+            last_balance: Mutex::new(None),
+        }
+    }
+
+    /// Create a canister with the given id running the same build as the canister, see
+    /// [`CanisterHandle::fork`].
+    ///
+    /// # Panics
+    ///
+    /// If the canister does not exist, or if there is already a canister with the given id.
+    pub(crate) fn fork_canister(&self, canister_id: Principal, fork_id: Principal) -> Canister {
+        if self.canisters.lock().unwrap().contains(&fork_id) {
+            panic!(
+                "ic-kit-runtime: Canister '{}' is already defined in the replica.",
+                fork_id
+            );
+        }
+
+        let code = self.canister_code(canister_id).unwrap_or_else(|| {
+            panic!(
+                "ic-kit-runtime: Canister '{}' is not defined in the replica.",
+                canister_id
+            )
+        });
+
+        Canister::from_code(fork_id, code)
+    }
+
+    /// Replace the canister with a new instance of the build it's running, with an empty heap and
+    /// an empty stable memory, see [`CanisterHandle::reset`].
+    pub(crate) fn reset_canister(&self, canister_id: Principal, balance: u128) {
+        let code = self.canister_code(canister_id).unwrap_or_else(|| {
+            panic!(
+                "ic-kit-runtime: Canister '{}' is not defined in the replica.",
+                canister_id
+            )
+        });
+
+        // The state loaded from the state directory or from a dump is wiped as well.
+        self.restored.lock().unwrap().remove(&canister_id);
+        self.install(
+            Canister::from_code(canister_id, code).with_balance(balance),
+            true,
+        );
+    }
+
+    /// Return the build the canister is running, if it exists.
+    pub(crate) fn canister_code(&self, canister_id: Principal) -> Option<CanisterCode> {
+        self.codes.lock().unwrap().get(&canister_id).cloned()
+    }
+
+    /// Returns true if the state of the canister was loaded from the state directory or a dump.
+    pub(crate) fn is_restored(&self, canister_id: Principal) -> bool {
+        self.restored.lock().unwrap().contains(&canister_id)
+    }
+
+    /// Return the candid interface of the canister, if it was provided.
+    pub(crate) fn candid(&self, canister_id: Principal) -> Option<String> {
+        self.interfaces.lock().unwrap().get(&canister_id).cloned()
+    }
+
+    /// Return the arguments of the init hook of the canister, if they were provided.
+    pub(crate) fn init_arg(&self, canister_id: Principal) -> Option<Vec<u8>> {
+        self.codes
+            .lock()
+            .unwrap()
+            .get(&canister_id)
+            .and_then(|code| code.init_arg().map(<[u8]>::to_vec))
+    }
+
+    /// Save the state of each canister to the state directory of the replica, by running their
+    /// `pre_upgrade` hook and writing their stable memory. The canisters keep running afterwards.
+    ///
+    /// # Panics
+    ///
+    /// If the replica was not created using [`Replica::with_state_dir`], or if the `pre_upgrade`
+    /// hook of a canister fails.
+    pub async fn save_state(&self) -> io::Result<()> {
+        let dir = self
+            .state_dir
+            .as_ref()
+            .expect("ic-kit-runtime: The replica does not have a state directory.");
+
+        fs::create_dir_all(dir)?;
+
+        let canisters = self.canister_ids();
+        for canister_id in canisters {
+            let data = self.flush_stable_memory(canister_id).await;
+            fs::write(stable_memory_path(dir, canister_id), data)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the state of the replica to the file in a portable format, see [`crate::dump`]. The
+    /// `pre_upgrade` hook of each canister runs first, so its heap is written to its stable
+    /// memory, and the canisters keep running afterwards.
+    ///
+    /// # Panics
+    ///
+    /// If the `pre_upgrade` hook of a canister fails.
+    pub async fn dump<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        self.capture().await.write(path)
+    }
+
+    /// Return the state of the replica, as written by [`Replica::dump`], so it can be compared
+    /// with another capture using [`ReplicaDiff::between`]. The `pre_upgrade` hook of each
+    /// canister runs first, so its heap is written to its stable memory, and the canisters keep
+    /// running afterwards.
+    ///
+    /// # Panics
+    ///
+    /// If the `pre_upgrade` hook of a canister fails.
+    ///
+    /// [`ReplicaDiff::between`]: crate::diff::ReplicaDiff::between
+    pub async fn capture(&self) -> dump::ReplicaState {
+        let canisters = self.canister_ids();
+        let mut states = Vec::with_capacity(canisters.len());
+
+        for canister_id in canisters {
+            let data = self.flush_stable_memory(canister_id).await;
+            let balance = self.get_canister(canister_id).balance().await;
+            let name = self.given_name(&canister_id);
+            states.push(CanisterState::new(canister_id, name, balance, &data));
+        }
+
+        dump::ReplicaState {
+            version: dump::FORMAT_VERSION,
+            time: self.time().await,
+            settings: ReplicaSettings {
+                log_calls: self.tracer.lock().unwrap().is_enabled(),
+            },
+            canisters: states,
+        }
+    }
+
+    /// Create an empty replica with the state written by [`Replica::dump`]. The time, the settings
+    /// and the names of the canisters are restored right away, and the stable memory and the
+    /// balance of each canister are restored when a canister with the same id is added, in which
+    /// case [`CanisterHandle::init_or_restore`] runs its `post_upgrade` hook.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let state = dump::ReplicaState::read(path)?;
+        let replica = Replica::default();
+
+        if let Some(time) = state.time {
+            replica.set_time(time);
+        }
+
+        replica.log_calls(state.settings.log_calls);
+
+        for canister in state.canisters {
+            if let Some(name) = &canister.name {
+                replica.name(canister.id, name.clone());
+            }
+
+            let data = canister.stable_memory()?;
+            replica
+                .loaded
+                .lock()
+                .unwrap()
+                .insert(canister.id, (data, canister.balance));
+        }
+
+        Ok(replica)
+    }
+
+    /// Run the `pre_upgrade` hook of the canister, so its heap is written to its stable memory,
+    /// and return its stable memory.
+    ///
+    /// # Panics
+    ///
+    /// If the `pre_upgrade` hook fails.
+    pub(crate) async fn flush_stable_memory(&self, canister_id: Principal) -> Vec<u8> {
+        let handle = self.get_canister(canister_id);
+
+        if let Some(e) = handle.pre_upgrade().await.rejection_message() {
+            panic!(
+                "ic-kit-runtime: The pre_upgrade hook of canister '{}' failed: {}",
+                canister_id, e
+            );
+        }
+
+        let size = handle.stable_size().await;
+        handle.stable_read(0, (size << 16) as usize).await
+    }
+
+    /// Return the mock of the management canister, which is added to the replica the first time
+    /// this method is called and replies to `canister_status` with the module hash, the
+    /// controllers and the balance of the canisters of the replica, see [`crate::management`].
+    pub fn management(&self) -> ManagementMock {
+        let mut management = self.management.lock().unwrap();
+
+        if let Some(mock) = &*management {
+            return mock.clone();
+        }
+
+        let (mock, canister) = ManagementMock::new(self.codes.clone(), self.counters.clone());
+        let installer = self.installer();
+        installer.set_lifecycle(mock.lifecycle(installer.clone()));
+        self.add_canister(canister);
+        *management = Some(mock.clone());
+        mock
+    }
+
+    /// Return the module hash of the build the canister is running, see
+    /// [`Canister::module_hash`].
+    pub(crate) fn module_hash(&self, canister_id: Principal) -> Option<[u8; 32]> {
+        self.codes
+            .lock()
+            .unwrap()
+            .get(&canister_id)
+            .map(CanisterCode::module_hash)
+    }
+
+    /// Return the mock of the exchange rate canister, which is added to the replica with the id of
+    /// the exchange rate canister on the mainnet the first time this method is called, see
+    /// [`crate::xrc`].
+    ///
+    /// ```ignore
+    /// replica.xrc().set_rate("ICP/USDT", 12.34);
+    /// ```
+    pub fn xrc(&self) -> XrcMock {
+        let mut xrc = self.xrc.lock().unwrap();
+
+        if let Some(mock) = &*xrc {
+            return mock.clone();
+        }
+
+        let (mock, canister) = XrcMock::new();
+        self.add_canister(canister);
+        self.name(XRC_CANISTER_ID, "xrc");
+        *xrc = Some(mock.clone());
+        mock
+    }
+
+    /// Return the mock of the blackhole canister, which is added to the replica with the id of the
+    /// blackhole canister on the mainnet the first time this method is called, and replies to
+    /// `canister_status` for the canisters it controls, see [`crate::blackhole`].
+    ///
+    /// ```ignore
+    /// replica.blackhole();
+    /// let ledger = replica.add_canister(LedgerCanister::anonymous().with_controller(BLACKHOLE_CANISTER_ID));
+    /// ```
+    pub fn blackhole(&self) -> BlackholeMock {
+        let mut blackhole = self.blackhole.lock().unwrap();
+
+        if let Some(mock) = &*blackhole {
+            return mock.clone();
+        }
+
+        let (mock, canister) = BlackholeMock::new(self.codes.clone(), self.counters.clone());
+        self.add_canister(canister);
+        self.name(BLACKHOLE_CANISTER_ID, "blackhole");
+        *blackhole = Some(mock.clone());
+        mock
+    }
+
+    /// Return the mocks of the root, governance, ledger and swap canisters of an SNS, which are
+    /// added to the replica the first time this method is called, see [`crate::sns`].
+    ///
+    /// ```ignore
+    /// let sns = replica.sns();
+    /// sns.register_dapp(dapp.canister_id());
+    /// ```
+    pub fn sns(&self) -> SnsMock {
+        let mut sns = self.sns.lock().unwrap();
+
+        if let Some(mock) = &*sns {
+            return mock.clone();
+        }
+
+        let (mock, canisters) = SnsMock::new();
+
+        for canister in canisters {
+            self.add_canister(canister);
+        }
+
+        let ids = mock.ids();
+        self.name(ids.root, "sns_root");
+        self.name(ids.governance, "sns_governance");
+        self.name(ids.ledger, "sns_ledger");
+        self.name(ids.swap, "sns_swap");
+        *sns = Some(mock.clone());
+        mock
+    }
+
+    /// Use the given name for the principal in the log of the calls and in the reports, instead
+    /// of its textual form. The mock users and the anonymous principal are named by default.
+    ///
+    /// ```ignore
+    /// let ledger = replica.add_canister(LedgerCanister::anonymous());
+    /// replica.name(ledger.canister_id(), "ledger");
+    /// ```
+    pub fn name<S: Into<String>>(&self, id: Principal, name: S) {
+        self.tracer.lock().unwrap().set_name(id, name.into());
+    }
+
+    /// Return the name given to the principal, if it has one.
+    pub(crate) fn given_name(&self, id: &Principal) -> Option<String> {
+        self.tracer.lock().unwrap().given_name(id)
+    }
+
+    /// Return the name given to the principal, or its textual form if it does not have one.
+    pub(crate) fn name_of(&self, id: &Principal) -> String {
+        self.tracer.lock().unwrap().name(id)
+    }
+
+    /// Print each of the calls and their replies from now on, as a tree where the calls made by a
+    /// canister are nested under the call it was processing, see [`crate::trace`].
+    pub fn log_calls(&self, enabled: bool) {
+        self.tracer.lock().unwrap().set_enabled(enabled);
+    }
+
+    /// Show how long each call took in the log of the calls, which is enabled by default. Without
+    /// the timings the log is the same on every run, see [`crate::trace`].
+    pub fn log_call_timings(&self, enabled: bool) {
+        self.tracer.lock().unwrap().set_timings(enabled);
+    }
+
+    /// Show the arguments and the replies of the calls in the log of the calls as candid text
+    /// instead of their sizes, decoded with the candid interfaces of the canisters when they are
+    /// known, see [`crate::pretty`].
+    pub fn log_call_values(&self, enabled: bool) {
+        self.tracer.lock().unwrap().set_values(enabled);
+    }
+
+    /// Record each of the calls as an OpenTelemetry span from now on, or stop recording them and
+    /// drop the recorded spans, see [`crate::otel`].
+    pub fn record_spans(&self, enabled: bool) {
+        self.tracer.lock().unwrap().set_spans(enabled);
+    }
+
+    /// Return the spans of the calls resolved since they are recorded, in the order of their
+    /// replies, see [`Replica::record_spans`].
+    pub fn spans(&self) -> Vec<OtelSpan> {
+        self.tracer.lock().unwrap().spans()
+    }
+
+    /// Write the spans of the calls resolved since they are recorded to the file in the OTLP/JSON
+    /// format, which can be imported by the tracing UIs or sent to an OpenTelemetry collector, see
+    /// [`crate::otel`].
+    pub fn export_otlp<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        fs::write(path, otel::to_otlp_json(&self.spans()))
+    }
+
+    /// Assign the request ids of this replica from the given seed, so the replicas of a test have
+    /// distinct ids which are the same on every run. The ids are assigned in order from
+    /// `seed << 32`, and from `0` by default.
+    ///
+    /// # Panics
+    ///
+    /// If a call was already made on the replica.
+    pub fn set_request_id_seed(&self, seed: u64) {
+        self.counters.seed_request_ids(seed);
+    }
+
+    /// Set the seed of the randomness beacon, which the random bytes returned by the `raw_rand`
+    /// method of the management canister are derived from, `0` by default. See
+    /// [`crate::management`].
+    pub fn set_beacon_seed(&self, seed: u64) {
+        self.counters.beacon_seed.store(seed, Ordering::SeqCst);
+    }
+
+    /// Return the random bytes returned by `raw_rand` in the round of the messages executed at the
+    /// given time, such as the time fixed with [`Replica::set_time`].
+    pub fn beacon(&self, time: u64) -> [u8; 32] {
+        management::beacon(self.counters.beacon_seed.load(Ordering::SeqCst), time)
+    }
+
+    /// Create a benchmark of the call, which performs it several times and reports its cost.
+    ///
+    /// ```ignore
+    /// let report = replica.bench(counter.new_call("increment")).iterations(100).run().await;
+    /// ```
+    pub fn bench<'a>(&self, call: CallBuilder<'a>) -> Bench<'a> {
+        Bench::new(call)
+    }
+
+    /// Create a load test of the call, which performs it as a query a lot of times at once and
+    /// reports the percentiles of its latency, see [`crate::load`].
+    ///
+    /// ```ignore
+    /// let report = replica.load_test(ledger.new_call("balance_of").with_arg(alice)).queries(20_000).run().await;
+    /// ```
+    pub fn load_test<'a>(&self, call: CallBuilder<'a>) -> LoadTest<'a> {
+        LoadTest::new(call)
+    }
+
+    /// Add the canisters of the deployment to the replica and run their init hooks, see
+    /// [`crate::deploy`].
+    ///
+    /// # Panics
+    ///
+    /// If the init hook of a canister fails.
+    pub async fn deploy(&self, deployment: Deployment) -> Deployed<'_> {
+        deployment.deploy(self).await
+    }
+
+    /// Build `count` canisters with the given function, which is given the index of the shard and
+    /// its id, add them to the replica and run their init hooks in order, see [`crate::shard`].
+    ///
+    /// # Panics
+    ///
+    /// If a canister is not built with the given id, or if its init hook fails.
+    pub async fn install_many<F>(&self, count: usize, factory: F) -> Vec<CanisterHandle<'_>>
+    where
+        F: FnMut(usize, Principal) -> Canister,
+    {
+        shard::install_many(self, count, factory).await
+    }
+
+    /// Create a call to the given method of each of the canisters, such as the shards returned by
+    /// [`Replica::install_many`], see [`crate::shard`].
+    pub fn broadcast<S: Into<String>>(
+        &self,
+        canisters: &[CanisterHandle<'_>],
+        method_name: S,
+    ) -> Broadcast<'_> {
+        Broadcast::new(self, canisters, method_name.into())
+    }
+
+    /// Create a check of the growth of the state of a canister, which performs the call several
+    /// times and measures the state of the canister after each of them, see [`crate::leak`].
+    ///
+    /// ```ignore
+    /// replica.leak_check(ledger.new_call("transfer")).run().await.assert_bounded();
+    /// ```
+    pub fn leak_check<'a>(&self, call: CallBuilder<'a>) -> LeakCheck<'a> {
+        if let Some(backend) = self.backend() {
+            backend::unsupported(backend.name(), "Checking the growth of a canister");
+        }
+
+        LeakCheck::new(call)
+    }
+
+    /// Return the number of calls made to each of the update and query methods of the canisters,
+    /// which can be used to find the methods which are not tested.
+    pub fn coverage(&self) -> Coverage {
+        let canisters = self
+            .counters
+            .methods
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(canister_id, methods)| CanisterCoverage {
+                canister_id: *canister_id,
+                methods: methods
+                    .iter()
+                    .map(|(name, calls)| (name.clone(), *calls))
+                    .collect(),
+            })
+            .collect();
+
+        Coverage { canisters }
+    }
+
+    /// Serve a local HTTP endpoint exposing the state of the replica as JSON, see
+    /// [`crate::inspect`].
+    #[cfg(feature = "inspector")]
+    pub async fn inspector<A: tokio::net::ToSocketAddrs>(
+        &self,
+        addr: A,
+    ) -> io::Result<Inspector<'_>> {
+        if let Some(backend) = self.backend() {
+            backend::unsupported(backend.name(), "Inspecting the replica");
+        }
+
+        Inspector::bind(self, addr).await
+    }
+
+    /// Return the counters of the resources used by the canisters.
+    pub(crate) fn counters(&self) -> &Counters {
+        &self.counters
+    }
+
+    /// Return the time of the replica, if it was fixed.
+    pub(crate) async fn time(&self) -> Option<u64> {
+        let (tx, rx) = oneshot::channel();
+        self.send(ReplicaMessage::Time { reply_sender: tx });
+        rx.await.unwrap()
+    }
+
+    /// Fix the time of the replica to the given nanoseconds since the unix epoch, which is used for
+    /// the messages sent after this call that do not set their own time, instead of the system
+    /// time.
+    pub fn set_time(&self, time: u64) {
+        if let Some(backend) = self.backend() {
+            return backend.set_time(time);
+        }
+
+        self.send(ReplicaMessage::SetTime(time));
+    }
+
+    /// Move the time of the replica forward by the given duration, starting from the system time
+    /// if the time was not fixed with [`Replica::set_time`].
+    pub fn advance_time(&self, duration: Duration) {
+        if let Some(backend) = self.backend() {
+            return backend.advance_time(duration);
+        }
+
+        self.send(ReplicaMessage::AdvanceTime(duration));
+    }
+
+    /// Enable the chaos mode, which injects random faults drawn from the seed of the config in the
+    /// messages sent from now on, see [`crate::chaos`].
+    ///
+    /// ```ignore
+    /// replica.set_chaos(Chaos::new(42).with_restarts(0.0));
+    /// ```
+    pub fn set_chaos(&self, chaos: Chaos) {
+        if let Some(backend) = self.backend() {
+            backend::unsupported(backend.name(), "The chaos mode");
+        }
+
+        *self.chaos.lock().unwrap() = Some(ChaosState::restarts(chaos.clone()));
+        self.send(ReplicaMessage::SetChaos(chaos));
+    }
+
+    /// Restart the canister before a call of the test if the chaos mode draws a restart, by
+    /// upgrading it to its own build. The canisters are only restarted when no call is pending, so
+    /// the replies are not delivered to the new build.
+    ///
+    /// # Panics
+    ///
+    /// If the `pre_upgrade` or the `post_upgrade` hook of the canister fails.
+    pub(crate) async fn chaos_restart(&self, canister_id: Principal) {
+        let restart = match &mut *self.chaos.lock().unwrap() {
+            Some(chaos) => chaos.restart(),
+            None => return,
+        };
+
+        if !restart || self.is_paused() || self.tracer.lock().unwrap().has_pending() {
+            return;
+        }
+
+        let code = match self.codes.lock().unwrap().get(&canister_id) {
+            Some(code) => code.clone(),
+            None => return,
+        };
+
+        {
+            let tracer = self.tracer.lock().unwrap();
+            if tracer.is_enabled() {
+                println!("chaos: restarted {}", tracer.name(&canister_id));
+            }
+        }
+
+        let reply = self
+            .get_canister(canister_id)
+            .upgrade_raw(Canister::from_code(canister_id, code), Vec::new())
+            .await;
+
+        if let Some(e) = reply.rejection_message() {
+            panic!(
+                "ic-kit-runtime: The chaos mode could not restart canister '{}': {}",
+                canister_id, e
+            );
+        }
+    }
+
+    /// Return the certificate of the given paths of the state tree of the replica, like the
+    /// response to a `read_state` request on the Internet Computer, see [`crate::certification`].
+    /// The state tree has the `time` of the replica, the `certified_data`, `controllers`,
+    /// `module_hash` and public `metadata/<name>` sections of each canister at `canister/<id>`,
+    /// and the status of the calls made by the users at `request_status/<request id>`, see
+    /// [`CallBuilder::request_id`] and [`crate::metadata`].
+    ///
+    /// The request is made by the anonymous principal, use [`Replica::read_state_as`] to read the
+    /// private sections of the metadata of a canister.
+    pub async fn read_state(&self, paths: &[Vec<Vec<u8>>]) -> Vec<u8> {
+        self.read_state_as(Principal::anonymous(), paths)
+            .await
+            .unwrap_or_else(|e| panic!("ic-kit-runtime: {}", e))
+    }
+
+    /// Like [`Replica::read_state`] but the request is made by the given principal, so the state
+    /// tree also has the private sections of the metadata of the canisters it controls. Requesting
+    /// a private section of a canister it does not control is an error, like on the Internet
+    /// Computer.
+    pub async fn read_state_as(
+        &self,
+        sender: Principal,
+        paths: &[Vec<Vec<u8>>],
+    ) -> Result<Vec<u8>, String> {
+        if let Some(backend) = self.backend() {
+            backend::unsupported(backend.name(), "Reading the state tree");
+        }
+
+        let mut tree = LabeledTree::new();
+        let time = self.time().await.unwrap_or_else(now);
+        tree.insert(&[b"time"], certification::time_leaf(time));
+
+        for canister_id in self.canister_ids() {
+            let code = match self.codes.lock().unwrap().get(&canister_id) {
+                Some(code) => code.clone(),
+                None => continue,
+            };
+
+            let id = canister_id.as_slice();
+            tree.insert(
+                &[b"canister", id, b"controllers"],
+                certification::principals_leaf(code.controllers()),
+            );
+            tree.insert(
+                &[b"canister", id, b"module_hash"],
+                code.module_hash().to_vec(),
+            );
+
+            if let Some(data) = self
+                .counters
+                .certified_data
+                .lock()
+                .unwrap()
+                .get(&canister_id)
+            {
+                tree.insert(&[b"canister", id, b"certified_data"], data.clone());
+            }
+
+            let is_controller = code.controllers().contains(&sender);
+
+            for (name, (visibility, data)) in code.metadata() {
+                if visibility == MetadataVisibility::Private && !is_controller {
+                    if paths.contains(&certification::metadata_path(&canister_id, &name)) {
+                        return Err(format!(
+                            "Custom section {} can only be requested by the controllers of the \
+                             canister.",
+                            name
+                        ));
+                    }
+
+                    continue;
+                }
+
+                tree.insert(&[b"canister", id, b"metadata", name.as_bytes()], data);
+            }
+        }
+
+        for (request_id, status) in self.counters.ingress.statuses() {
+            status.insert_into(&mut tree, &request_id);
+        }
+
+        Ok(certification::read_state(&tree, paths))
+    }
+
+    /// Return the status of the call made by a user with the given request id, or `None` if the
+    /// replica never received it, see [`crate::ingress`] and [`CallBuilder::request_id`].
+    pub fn request_status(&self, request_id: &[u8; 32]) -> Option<RequestStatus> {
+        if let Some(backend) = self.backend() {
+            backend::unsupported(backend.name(), "Reading the ingress history");
+        }
+
+        self.counters.ingress.status(request_id)
+    }
+
+    /// Return the statuses the call made by a user with the given request id went through, in
+    /// order, such as `[Received, Processing, Replied(..)]`.
+    pub fn request_status_history(&self, request_id: &[u8; 32]) -> Vec<RequestStatus> {
+        if let Some(backend) = self.backend() {
+            backend::unsupported(backend.name(), "Reading the ingress history");
+        }
+
+        self.counters.ingress.history(request_id)
+    }
+
+    /// Let the ingress expiry of the calls replied to so far pass, so their status becomes
+    /// [`RequestStatus::Done`] and their replies can not be read anymore.
+    pub fn expire_ingress_history(&self) {
+        self.counters.ingress.expire();
+    }
+
+    /// Choose how the time of the replica moves for the messages sent after this call, such as
+    /// frozen for the tests of the expiries, or flowing with each message for the soak tests.
+    /// The logical time starts from the current time of the replica.
+    ///
+    /// ```ignore
+    /// replica.set_time_policy(TimePolicy::AutoAdvance(Duration::from_secs(1)));
+    /// ```
+    pub fn set_time_policy(&self, policy: TimePolicy) {
+        if let Some(backend) = self.backend() {
+            backend::unsupported(backend.name(), "Changing the time policy");
+        }
+
+        self.send(ReplicaMessage::SetTimePolicy(policy));
+    }
+
+    /// Enqueue the given request to the destination canister.
+    pub(crate) fn enqueue_request(
+        &self,
+        canister_id: Principal,
+        message: Message,
+        reply_sender: Option<oneshot::Sender<CallReply>>,
+    ) {
+        // The message is nested under the span of the test which made the call.
+        match &message {
+            Message::CustomTask { request_id, .. } | Message::Request { request_id, .. } => self
+                .counters
+                .spans
+                .insert(*request_id, MessageSpan::current()),
+            Message::Reply { .. } => {}
+        }
+
+        self.sender
+            .send(ReplicaMessage::CanisterRequest {
+                canister_id,
+                message,
+                reply_sender,
+            })
+            .unwrap_or_else(|_| panic!("ic-kit-runtime: could not send message to replica"));
+    }
+
+    /// Enqueue the given requests to the destination canister with a single message to the event
+    /// loop of the replica, so sending a lot of requests at once is not slowed down by the channel.
+    pub(crate) fn enqueue_requests(
+        &self,
+        canister_id: Principal,
+        requests: Vec<(Message, oneshot::Sender<CallReply>)>,
+    ) {
+        let span = MessageSpan::current();
+        for (message, _) in &requests {
+            if let Message::CustomTask { request_id, .. } | Message::Request { request_id, .. } =
+                message
+            {
+                self.counters.spans.insert(*request_id, span.clone());
+            }
+        }
+
+        self.send(ReplicaMessage::CanisterRequests {
+            canister_id,
+            requests,
+        });
+    }
+
+    /// Perform the given call in this replica and return a future that will be resolved once the
+    /// call is executed.
+    pub(crate) fn perform_call(&self, call: CanisterCall) -> BoxFuture<'static, CallReply> {
+        let canister_id = call.callee;
+        let request_id = call.request_id;
+        let method = call.method.clone();
+        let tracer = self.tracer.clone();
+        tracer.lock().unwrap().request(&call);
+
+        if let Some(backend) = self.backend.clone() {
+            return async move {
+                let reply = backend.call(call).await;
+                tracer.lock().unwrap().reply(request_id, &reply);
+                reply
+            }
+            .boxed();
+        }
+
+        let status_id =
+            certification::request_id(&call.sender, &canister_id, &call.method, &call.arg);
+        self.counters.ingress.received(request_id, status_id);
+        let counters = self.counters.clone();
+
+        let message = Message::from(call);
+        let (tx, rx) = oneshot::channel();
+        self.enqueue_request(canister_id, message, Some(tx));
+
+        // Record the reply as soon as it is sent, so a call whose future is never awaited is not
+        // reported as unresolved.
+        let (reply_tx, reply_rx) = oneshot::channel();
+        tokio::spawn(async move {
+            match rx.await {
+                Ok(reply) => {
+                    tracer.lock().unwrap().reply(request_id, &reply);
+                    counters.ingress.completed(request_id, &reply);
+                    let _ = reply_tx.send(reply);
+                }
+                Err(_) => tracer.lock().unwrap().dropped(request_id),
+            }
+        });
+
+        let callee = self.tracer.lock().unwrap().name(&canister_id);
+        async move {
+            reply_rx.await.unwrap_or_else(|_| {
+                panic!(
+                    "ic-kit-runtime: The call to '{}' of {} was dropped without a reply.",
+                    method, callee
+                )
+            })
+        }
+        .boxed()
+    }
+
+    /// Create a new call builder on the replica, that can be used to send a request to the given
+    /// canister.
+    pub fn new_call<S: Into<String>>(&self, id: Principal, method: S) -> CallBuilder {
+        CallBuilder::new(&self, id, method.into())
+    }
+
+    /// Perform the calls concurrently, and deliver the messages that result from them one at a
+    /// time in the order chosen by the schedule of the replica, which is set by the [`Explorer`]
+    /// to explore the different interleavings of the calls. Returns the replies in the same
+    /// order as the calls.
+    ///
+    /// [`Explorer`]: crate::explore::Explorer
+    pub async fn interleave(&self, calls: Vec<CallBuilder<'_>>) -> Vec<CallReply> {
+        if let Some(backend) = self.backend() {
+            backend::unsupported(backend.name(), "Choosing the order of the messages");
+        }
+
+        self.send(ReplicaMessage::Hold(true));
+
+        let replies = calls
+            .iter()
+            .map(|call| self.perform_call(call.into()))
+            .collect::<Vec<_>>();
+
+        loop {
+            self.wait_in_flight().await;
+
+            let pending = self.pending_messages().await;
+
+            if pending.is_empty() {
+                break;
+            }
+
+            let pending = pending.into_iter().map(|m| m.description).collect();
+            let index = self.schedule.lock().unwrap().choose(pending);
+
+            let (tx, rx) = oneshot::channel();
+            self.send(ReplicaMessage::Deliver {
+                index,
+                reply_sender: tx,
+            });
+            rx.await.unwrap();
+        }
+
+        if !self.is_paused() {
+            self.send(ReplicaMessage::Hold(false));
+        }
+
+        futures::future::join_all(replies).await
+    }
+
+    /// Hold the messages sent to the canisters from now on, including the calls made by the
+    /// canisters and their replies, until they are delivered one at a time by [`Replica::step`]
+    /// or the replica is resumed. This is used to single-step an interaction between several
+    /// canisters and to inspect their state between the messages.
+    ///
+    /// The calls sent by the test are held too, so they should be sent with
+    /// [`CallBuilder::send`] and their replies awaited once they are delivered. The state of the
+    /// canisters can be inspected between the steps with the `run` method of their handles, whose
+    /// tasks are not held.
+    pub fn pause(&self) {
+        if let Some(backend) = self.backend() {
+            backend::unsupported(backend.name(), "Pausing the replica");
+        }
+
+        self.paused.store(true, Ordering::SeqCst);
+        self.send(ReplicaMessage::Hold(true));
+    }
+
+    /// Deliver the held messages and stop holding the new ones.
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+        self.send(ReplicaMessage::Hold(false));
+    }
+
+    /// Return true if the replica is paused.
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Return the messages held by the paused replica, in the order they are delivered by
+    /// [`Replica::step`].
+    pub async fn pending_messages(&self) -> Vec<PendingMessage> {
+        let (tx, rx) = oneshot::channel();
+        self.send(ReplicaMessage::Pending { reply_sender: tx });
+        rx.await.unwrap()
+    }
+
+    /// Deliver the oldest message held by the paused replica and wait for it to be processed, the
+    /// calls and the replies resulting from it are held. Returns the delivered message, or None
+    /// if no message is held.
+    pub async fn step(&self) -> Option<PendingMessage> {
+        self.step_where(|_| true).await
+    }
+
+    /// Like [`Replica::step`], but deliver the oldest held message matching the predicate, the
+    /// others stay held in their order. This is used to deliver the messages in another order than
+    /// they were sent, such as to hold the reply of a call while the canister which made it runs
+    /// other messages, for example to test both the response and the timeout of a call winning.
+    pub async fn step_where<F>(&self, predicate: F) -> Option<PendingMessage>
+    where
+        F: Fn(&PendingMessage) -> bool,
+    {
+        self.wait_in_flight().await;
+
+        let (index, message) = self
+            .pending_messages()
+            .await
+            .into_iter()
+            .enumerate()
+            .find(|(_, message)| predicate(message))?;
+
+        let (tx, rx) = oneshot::channel();
+        self.send(ReplicaMessage::Deliver {
+            index,
+            reply_sender: tx,
+        });
+        rx.await.unwrap();
+
+        self.wait_in_flight().await;
+
+        // The round trip makes sure the replies sent to the replica are held.
+        self.pending_messages().await;
+
+        Some(message)
+    }
+
+    /// Return the number of messages delivered to the canisters which are not processed yet.
+    pub(crate) fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Wait for the messages delivered to the canisters to be processed.
+    async fn wait_in_flight(&self) {
+        while self.in_flight() > 0 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    /// Return the cycles which moved between the principals so far, with the current balance of
+    /// each canister, see [`crate::cycles`].
+    ///
+    /// # Panics
+    ///
+    /// If the canisters don't run in-process, since the fees are charged by the backend.
+    pub async fn cycles_report(&self) -> CyclesReport {
+        if let Some(backend) = self.backend() {
+            backend::unsupported(backend.name(), "The cycles report");
+        }
+
+        let mut balances = HashMap::new();
+        for id in self.canister_ids() {
+            balances.insert(id, self.get_canister(id).balance().await);
+        }
+
+        self.tracer.lock().unwrap().cycles_report(balances)
+    }
+
+    /// Record a change of the balance of a canister made by the test, see
+    /// [`CanisterHandle::set_balance`].
+    pub(crate) fn adjust_balance(&self, canister_id: Principal, delta: i128) {
+        self.tracer
+            .lock()
+            .unwrap()
+            .adjust_balance(canister_id, delta);
+    }
+
+    /// Return the calls which were dropped without a reply, and the calls which are still waiting
+    /// for their reply.
+    pub fn unresolved_calls(&self) -> Vec<UnresolvedCall> {
+        self.tracer.lock().unwrap().unresolved()
+    }
+
+    /// Return the sizes of the arguments and of the replies of the calls replied to so far, in the
+    /// order of the replies, including the calls made by the canisters. The payloads can be
+    /// checked against the message limits of the Internet Computer as the data of a test grows.
+    ///
+    /// ```ignore
+    /// for payload in replica.payload_sizes() {
+    ///     assert!(payload.reply_size < MAX_REPLY_SIZE / 2, "{:?}", payload);
+    /// }
+    /// ```
+    pub fn payload_sizes(&self) -> Vec<PayloadSize> {
+        self.tracer.lock().unwrap().payload_sizes(0)
+    }
+
+    /// Return the sizes of the payloads of the calls replied to since the given number of calls,
+    /// see [`Replica::payload_count`].
+    pub(crate) fn payload_sizes_since(&self, from: usize) -> Vec<PayloadSize> {
+        self.tracer.lock().unwrap().payload_sizes(from)
+    }
+
+    /// Return the number of calls replied to so far.
+    pub(crate) fn payload_count(&self) -> usize {
+        self.tracer.lock().unwrap().payload_count()
+    }
+
+    /// Observe a value in the canister under the given key, the function is executed in the
+    /// execution thread of the canister now and after each of its messages, and the invariants
+    /// registered with [`Replica::invariant`] can read its latest result. See [`crate::invariant`].
+    pub async fn observe<T, F>(&self, canister_id: Principal, key: &str, f: F)
+    where
+        T: Send + 'static,
+        F: Fn() -> T + Send + Sync + RefUnwindSafe + UnwindSafe + 'static,
+    {
+        let probe: Probe = Arc::new(move || Box::new(f()) as Box<dyn Any + Send>);
+        let invariants = &self.counters.invariants;
+        invariants.observe(canister_id, key, probe.clone());
+
+        let value = self.get_canister(canister_id).run(move || probe()).await;
+        invariants.record(canister_id, vec![(key.to_string(), value)]);
+    }
+
+    /// Register an invariant, which is checked against the values observed with
+    /// [`Replica::observe`] after each message of a canister with observations. The first message
+    /// after which an invariant returns false or panics is kept, and reported by
+    /// [`Replica::assert_invariants`] and when the replica is shut down.
+    pub fn invariant<F>(&self, name: &str, check: F)
+    where
+        F: Fn(&InvariantView) -> bool + Send + Sync + 'static,
+    {
+        self.counters.invariants.invariant(name, Box::new(check));
+    }
+
+    /// Return the first message which broke one of the invariants, if there was one.
+    pub fn invariant_violation(&self) -> Option<Violation> {
+        self.counters.invariants.violation()
+    }
+
+    /// Panic if a message broke one of the invariants registered with [`Replica::invariant`].
+    pub fn assert_invariants(&self) {
+        if let Some(violation) = self.invariant_violation() {
+            panic!("ic-kit-runtime: {}", violation);
+        }
+    }
+
+    /// Wait for the messages in flight to be processed, then panic if any of the calls made on the
+    /// replica was dropped without a reply or is still waiting for its reply, listing each of the
+    /// calls with the canister and method which made it, or if a message broke one of the
+    /// invariants. This is done at the end of the tests created by the `#[kit_test]` macro.
+    pub async fn shutdown(&self) {
+        self.shutdown_handle().shutdown().await
+    }
+
+    /// Return a handle to shut down the replica once it's moved, see [`Replica::shutdown`].
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            sender: self.sender.clone(),
+            in_flight: self.in_flight.clone(),
+            tracer: self.tracer.clone(),
+            counters: self.counters.clone(),
+        }
+    }
+
+    fn send(&self, message: ReplicaMessage) {
+        self.sender
+            .send(message)
+            .unwrap_or_else(|_| panic!("ic-kit-runtime: could not send message to replica"));
+    }
+}
+
+/// A handle to shut down a replica, created by [`Replica::shutdown_handle`].
+pub struct ShutdownHandle {
+    sender: mpsc::UnboundedSender<ReplicaMessage>,
+    in_flight: Arc<AtomicUsize>,
+    tracer: Arc<Mutex<CallTracer>>,
+    counters: Arc<Counters>,
+}
+
+impl ShutdownHandle {
+    /// See [`Replica::shutdown`].
+    pub async fn shutdown(self) {
+        loop {
+            while self.in_flight.load(Ordering::SeqCst) > 0 {
+                tokio::task::yield_now().await;
+            }
+
+            // The round trip makes sure the replies sent to the replica are delivered.
+            let (tx, rx) = oneshot::channel();
+            self.send(ReplicaMessage::Pending { reply_sender: tx });
+            let held = rx.await.unwrap();
+
+            if !held.is_empty() {
+                self.send(ReplicaMessage::Hold(false));
+                continue;
+            }
+
+            tokio::task::yield_now().await;
+
+            if self.in_flight.load(Ordering::SeqCst) == 0 {
+                break;
+            }
+        }
+
+        if let Some(violation) = self.counters.invariants.violation() {
+            panic!("ic-kit-runtime: {}", violation);
+        }
+
+        let tracer = self.tracer.lock().unwrap();
+        let unresolved = tracer.unresolved();
+
+        if unresolved.is_empty() {
+            return;
+        }
+
+        let calls = unresolved
+            .iter()
+            .map(|call| format!("  {}", tracer.describe(call)))
+            .collect::<Vec<_>>()
+            .join("\n");
+        drop(tracer);
+
+        panic!(
+            "ic-kit-runtime: {} calls were not resolved when the replica was shut down.\n{}",
+            unresolved.len(),
+            calls
+        );
+    }
+
+    fn send(&self, message: ReplicaMessage) {
+        self.sender
+            .send(message)
+            .unwrap_or_else(|_| panic!("ic-kit-runtime: could not send message to replica"));
+    }
+}
+
+/// The parts of a replica which install the canisters, see [`Replica::installer`].
+#[derive(Clone)]
+pub(crate) struct Installer {
+    sender: mpsc::UnboundedSender<ReplicaMessage>,
+    codes: Arc<Mutex<HashMap<Principal, CanisterCode>>>,
+    interfaces: Arc<Mutex<HashMap<Principal, String>>>,
+    in_flight: Arc<AtomicUsize>,
+    counters: Arc<Counters>,
+    tracer: Arc<Mutex<CallTracer>>,
+    subnet: SubnetConfig,
+}
+
+impl Installer {
+    /// Count the resources used by the canister and run it on the subnet of the replica, before
+    /// it executes any message.
+    pub(crate) fn prepare(&self, canister: &mut Canister) {
+        canister.set_counters(self.counters.clone());
+        canister.set_subnet(self.subnet.clone());
+    }
+
+    /// Record the build and the candid interface the canister is running.
+    pub(crate) fn register(&self, canister: &Canister) {
+        let canister_id = canister.id();
+
+        self.codes
+            .lock()
+            .unwrap()
+            .insert(canister_id, canister.code());
+
+        if let Some(candid) = canister.candid() {
+            self.interfaces
+                .lock()
+                .unwrap()
+                .insert(canister_id, candid.to_string());
+
+            // An interface which does not parse is reported when it's used to encode the textual
+            // arguments of a call, the log falls back to the types carried by the messages.
+            if let Ok(interface) = Interface::parse(candid) {
+                self.tracer
+                    .lock()
+                    .unwrap()
+                    .set_interface(canister_id, interface);
+            }
+        }
+    }
+
+    /// Start the event loop of the canister, which replaces the one of the canister with the same
+    /// id if `replace` is set. The messages already queued for the replaced canister are still
+    /// executed by it.
+    pub(crate) fn start(&self, mut canister: Canister, replace: bool) {
         let canister_id = canister.id();
 
+        if !replace {
+            self.tracer
+                .lock()
+                .unwrap()
+                .add_canister(canister_id, canister.initial_balance());
+        }
+
+        // A new build replaces the crashed one.
+        self.counters.crashes.lock().unwrap().remove(&canister_id);
+
+        canister.set_rounds(Rounds {
+            canister_id,
+            sender: self.sender.clone(),
+            in_flight: self.in_flight.clone(),
+        });
+
         // Create a execution queue for the canister so we can send messages to the canister
         // asynchronously
         let replica = self.sender.clone();
 
         let (tx, rx) = mpsc::unbounded_channel();
+        let (stop_tx, stop_rx) = mpsc::unbounded_channel();
         replica
             .send(ReplicaMessage::CanisterAdded {
                 canister_id,
                 channel: tx,
+                stop: stop_tx,
+                replace,
             })
             .unwrap_or_else(|_| panic!("ic-kit-runtime: could not send message to replica"));
 
         // Start the event loop for the canister.
-        tokio::spawn(canister_worker(rx, replica, canister));
+        tokio::spawn(canister_worker(
+            rx,
+            stop_rx,
+            replica,
+            canister,
+            self.in_flight.clone(),
+            self.tracer.clone(),
+        ));
+    }
 
-        CanisterHandle {
-            replica: self,
-            canister_id,
-        }
+    /// Return the build the canister is running.
+    pub(crate) fn code(&self, canister_id: Principal) -> Option<CanisterCode> {
+        self.codes.lock().unwrap().get(&canister_id).cloned()
     }
 
-    /// Return the handle to a canister.
-    pub fn get_canister(&self, canister_id: Principal) -> CanisterHandle {
-        CanisterHandle {
-            replica: &self,
-            canister_id,
-        }
+    /// Return the counters of the replica.
+    pub(crate) fn counters(&self) -> &Counters {
+        &self.counters
     }
 
-    /// Enqueue the given request to the destination canister.
-    pub(crate) fn enqueue_request(
-        &self,
-        canister_id: Principal,
-        message: Message,
-        reply_sender: Option<oneshot::Sender<CallReply>>,
-    ) {
-        self.sender
-            .send(ReplicaMessage::CanisterRequest {
-                canister_id,
-                message,
-                reply_sender,
-            })
-            .unwrap_or_else(|_| panic!("ic-kit-runtime: could not send message to replica"));
+    /// Return true if the canister made calls which are not replied to yet, or was called and did
+    /// not reply yet.
+    pub(crate) fn has_open_calls(&self, canister_id: Principal) -> bool {
+        self.tracer.lock().unwrap().has_open_calls(canister_id)
     }
 
-    /// Perform the given call in this replica and return a future that will be resolved once the
-    /// call is executed.
-    pub(crate) fn perform_call(&self, call: CanisterCall) -> impl Future<Output = CallReply> {
-        let canister_id = call.callee;
-        let message = Message::from(call);
+    /// Execute the message in the canister and return its reply.
+    pub(crate) async fn request(&self, canister_id: Principal, message: Message) -> CallReply {
         let (tx, rx) = oneshot::channel();
-        self.enqueue_request(canister_id, message, Some(tx));
-        async {
-            rx.await
-                .expect("ic-kit-runtime: Could not retrieve the response from the call.")
-        }
+
+        self.send(ReplicaMessage::CanisterRequest {
+            canister_id,
+            message,
+            reply_sender: Some(tx),
+        });
+
+        rx.await
+            .expect("ic-kit-runtime: Could not retrieve the response from the call.")
     }
 
-    /// Create a new call builder on the replica, that can be used to send a request to the given
-    /// canister.
-    pub fn new_call<S: Into<String>>(&self, id: Principal, method: S) -> CallBuilder {
-        CallBuilder::new(&self, id, method.into())
+    /// Run the function in the execution thread of the canister and return its result.
+    pub(crate) async fn run<T, F>(&self, canister_id: Principal, f: F) -> T
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + RefUnwindSafe + UnwindSafe + 'static,
+    {
+        let result = Arc::new(Mutex::new(None));
+        let output = result.clone();
+
+        let message = Message::CustomTask {
+            request_id: self.counters.next_request_id(),
+            task: Box::new(move || {
+                *output.lock().unwrap() = Some(f());
+            }),
+            env: Env::default(),
+        };
+        let reply = self.request(canister_id, message).await;
+
+        let value = result.lock().unwrap().take();
+        value.unwrap_or_else(|| {
+            panic!(
+                "ic-kit-runtime: The task did not complete: {}",
+                reply.rejection_message().unwrap_or_default()
+            )
+        })
+    }
+
+    /// Drop the replies to the calls the canister made which are not replied to yet, since the
+    /// build which made them is replaced.
+    pub(crate) fn drop_pending_replies(&self, canister_id: Principal) {
+        let calls = self.tracer.lock().unwrap().pending_from(canister_id);
+        self.send(ReplicaMessage::DropReplies(calls));
+    }
+
+    /// Enable the lifecycle methods of the management canister.
+    pub(crate) fn set_lifecycle(&self, lifecycle: Lifecycle) {
+        self.send(ReplicaMessage::SetLifecycle(lifecycle));
+    }
+
+    fn send(&self, message: ReplicaMessage) {
+        self.sender
+            .send(message)
+            .unwrap_or_else(|_| panic!("ic-kit-runtime: could not send message to replica"));
     }
 }
 
@@ -150,30 +1928,188 @@ impl Default for Replica {
     /// Create an empty replica and run the start the event loop.
     fn default() -> Self {
         let (sender, rx) = mpsc::unbounded_channel::<ReplicaMessage>();
-        tokio::spawn(replica_worker(rx));
-        Replica { sender }
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let tracer = Arc::new(Mutex::new(CallTracer::default()));
+        let counters = Arc::new(Counters::default());
+        tokio::spawn(replica_worker(
+            rx,
+            in_flight.clone(),
+            tracer.clone(),
+            counters.clone(),
+        ));
+        Replica {
+            sender,
+            state_dir: None,
+            canisters: Mutex::new(Vec::new()),
+            restored: Mutex::new(HashSet::new()),
+            loaded: Mutex::new(HashMap::new()),
+            interfaces: Arc::new(Mutex::new(HashMap::new())),
+            codes: Arc::new(Mutex::new(HashMap::new())),
+            management: Mutex::new(None),
+            in_flight,
+            paused: AtomicBool::new(false),
+            schedule: Arc::new(Mutex::new(Schedule::default())),
+            counters,
+            tracer,
+            subnet: SubnetConfig::default(),
+            backend: None,
+            xrc: Mutex::new(None),
+            blackhole: Mutex::new(None),
+            chaos: Mutex::new(None),
+            sns: Mutex::new(None),
+        }
+    }
+}
+
+/// Run the future returned by the function with a new replica, on a new tokio runtime, and shut
+/// the replica down once the future completes.
+pub(crate) fn block_on_replica<F, Fut>(f: F) -> Fut::Output
+where
+    F: FnOnce(Replica) -> Fut,
+    Fut: Future,
+{
+    let rt = TokioRuntimeBuilder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("ic-kit-runtime: Could not build tokio runtime.");
+
+    rt.block_on(async {
+        let replica = Replica::default();
+        let shutdown = replica.shutdown_handle();
+        let output = f(replica).await;
+        shutdown.shutdown().await;
+        output
+    })
+}
+
+/// Reject the request with the given code, the replies to the calls of a canister are dropped.
+fn reject(
+    request: ReplicaCanisterRequest,
+    rejection_code: RejectionCode,
+    rejection_message: String,
+) {
+    let cycles_refunded = match &request.message {
+        Message::CustomTask { env, .. } | Message::Request { env, .. } => env.cycles_available,
+        Message::Reply { .. } => 0,
+    };
+
+    if let Some(reply_sender) = request.reply_sender {
+        let _ = reply_sender.send(CallReply::Reject {
+            rejection_code,
+            rejection_message,
+            cycles_refunded,
+        });
     }
 }
 
+/// Reject a request sent to a canister which was removed.
+fn reject_removed(canister_id: Principal, request: ReplicaCanisterRequest) {
+    reject(
+        request,
+        RejectionCode::DestinationInvalid,
+        format!("Canister '{}' was removed.", canister_id),
+    );
+}
+
+/// Return the path of the file which the stable memory of the canister is saved to.
+fn stable_memory_path(dir: &Path, canister_id: Principal) -> PathBuf {
+    dir.join(format!("{}.stable", canister_id))
+}
+
 /// Run replica's event loop, gets ReplicaMessages and performs the state transition accordingly.
-async fn replica_worker(mut rx: mpsc::UnboundedReceiver<ReplicaMessage>) {
-    let mut state = ReplicaState::default();
+async fn replica_worker(
+    mut rx: mpsc::UnboundedReceiver<ReplicaMessage>,
+    in_flight: Arc<AtomicUsize>,
+    tracer: Arc<Mutex<CallTracer>>,
+    counters: Arc<Counters>,
+) {
+    let mut state = ReplicaState {
+        in_flight,
+        tracer,
+        counters,
+        ..ReplicaState::default()
+    };
+
+    loop {
+        let message = if state.delayed.is_empty() {
+            rx.recv().await
+        } else {
+            // The messages held back by the chaos mode are delivered once the replica has nothing
+            // else to do, so they can't be held forever.
+            tokio::select! {
+                biased;
+                message = rx.recv() => message,
+                _ = tokio::task::yield_now() => {
+                    state.release_if_idle();
+                    continue;
+                }
+            }
+        };
+
+        let message = match message {
+            Some(message) => message,
+            None => break,
+        };
 
-    while let Some(message) = rx.recv().await {
         match message {
             ReplicaMessage::CanisterAdded {
                 canister_id,
                 channel,
-            } => state.canister_added(canister_id, channel),
+                stop,
+                replace,
+            } => state.canister_added(canister_id, channel, stop, replace),
+            ReplicaMessage::CanisterRemoved {
+                canister_id,
+                reply_sender,
+            } => state.canister_removed(canister_id, reply_sender),
             ReplicaMessage::CanisterRequest {
                 canister_id,
                 message,
                 reply_sender,
             } => state.canister_request(canister_id, message, reply_sender),
+            ReplicaMessage::CanisterRequests {
+                canister_id,
+                requests,
+            } => {
+                for (message, reply_sender) in requests {
+                    state.canister_request(canister_id, message, Some(reply_sender));
+                }
+            }
             ReplicaMessage::CanisterReply {
                 canister_id,
                 message,
             } => state.canister_reply(canister_id, message),
+            ReplicaMessage::SetTime(time) => state.set_time(time),
+            ReplicaMessage::AdvanceTime(duration) => {
+                state.set_time(state.time.unwrap_or_else(now) + duration.as_nanos() as u64)
+            }
+            ReplicaMessage::SetTimePolicy(policy) => state.set_time_policy(policy),
+            ReplicaMessage::SetChaos(chaos) => state.chaos = Some(ChaosState::messages(chaos)),
+            ReplicaMessage::Hold(hold) => state.hold(hold),
+            ReplicaMessage::Time { reply_sender } => {
+                let _ = reply_sender.send(state.time);
+            }
+            ReplicaMessage::Pending { reply_sender } => {
+                let _ = reply_sender.send(state.pending());
+            }
+            ReplicaMessage::Deliver {
+                index,
+                reply_sender,
+            } => {
+                let (canister_id, message) = state.pending.remove(index);
+                state.deliver_held(canister_id, message);
+                let _ = reply_sender.send(());
+            }
+            ReplicaMessage::Slice { canister_id, slice } => state.slice(canister_id, slice),
+            ReplicaMessage::SlicesDone {
+                canister_id,
+                reply_sender,
+            } => {
+                state.slices_done(canister_id);
+                let _ = reply_sender.send(());
+            }
+            ReplicaMessage::DropReplies(calls) => state.dropped_replies.extend(calls),
+            ReplicaMessage::SetLifecycle(lifecycle) => state.lifecycle = Some(lifecycle),
         }
     }
 }
@@ -182,21 +2118,113 @@ async fn replica_worker(mut rx: mpsc::UnboundedReceiver<ReplicaMessage>) {
 /// channel and perform
 async fn canister_worker(
     mut rx: mpsc::UnboundedReceiver<ReplicaCanisterRequest>,
+    mut stop: mpsc::UnboundedReceiver<oneshot::Sender<()>>,
     mut replica: mpsc::UnboundedSender<ReplicaMessage>,
     mut canister: Canister,
+    in_flight: Arc<AtomicUsize>,
+    tracer: Arc<Mutex<CallTracer>>,
 ) {
     let canister_id = canister.id();
 
     let mut rx = rx;
     let mut canister = canister;
+    let mut lanes = Lanes::new(canister.subnet().clone(), canister.counters().clone());
+    // The message the requests are rejected with once the canister crashed.
+    let mut crash: Option<String> = None;
+
+    loop {
+        // The messages received while the previous one was executed are sorted into the lanes, so
+        // the next one is chosen among all of them.
+        while let Ok(request) = rx.try_recv() {
+            lanes.push(request);
+        }
+
+        let done = if lanes.is_empty() {
+            tokio::select! {
+                biased;
+                Some(done) = stop.recv() => Some(done),
+                message = rx.recv() => match message {
+                    Some(message) => {
+                        lanes.push(message);
+                        None
+                    }
+                    None => return,
+                },
+            }
+        } else {
+            stop.try_recv().ok()
+        };
+
+        if let Some(done) = done {
+            // The canister was removed, the messages queued for it are rejected once the
+            // message it was executing is finished.
+            rx.close();
+
+            while let Ok(request) = rx.try_recv() {
+                lanes.push(request);
+            }
+
+            while let Some(request) = lanes.pop() {
+                reject_removed(canister_id, request);
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+            }
+
+            canister.reject_open_calls(
+                RejectionCode::CanisterReject,
+                &format!("Canister '{}' was removed.", canister_id),
+            );
+            let _ = done.send(());
+            return;
+        }
+
+        let message = match lanes.pop() {
+            Some(message) => message,
+            None => continue,
+        };
+
+        // The canister crashed, its state can not be trusted anymore so the messages are rejected
+        // until it's removed or replaced.
+        if let Some(crash) = &crash {
+            reject(message, RejectionCode::CanisterError, crash.clone());
+            in_flight.fetch_sub(1, Ordering::SeqCst);
+            continue;
+        }
 
-    while let Some(message) = rx.recv().await {
         // Perform the message on the canister's thread, the result containing a list of
         // inter-canister call requests is returned here, so we can send each call back to
-        // replica.
-        let canister_requested_calls = canister
-            .process_message(message.message, message.reply_sender)
-            .await;
+        // replica. The traps of the canister are caught by its thread, a panic here is a bug of
+        // the runtime or a canister which broke it, such as with an invalid system call.
+        let canister_requested_calls =
+            match AssertUnwindSafe(canister.process_message(message.message, message.reply_sender))
+                .catch_unwind()
+                .await
+            {
+                Ok(calls) => calls,
+                Err(payload) => {
+                    let counters = canister.counters();
+                    counters
+                        .crashes
+                        .lock()
+                        .unwrap()
+                        .insert(canister_id, downcast_panic_payload(&payload));
+                    let message = counters.crash_message(canister_id).unwrap();
+
+                    if tracer.lock().unwrap().is_enabled() {
+                        println!("{}", message);
+                    }
+
+                    canister.reject_open_calls(RejectionCode::CanisterError, &message);
+                    crash = Some(message);
+                    Vec::new()
+                }
+            };
+
+        {
+            let tracer = tracer.lock().unwrap();
+            for (callee, method, hook) in canister.take_hook_calls() {
+                tracer.hook_call(&canister_id, &callee, &method, &hook);
+            }
+        }
 
         for call in canister_requested_calls {
             // For each call a oneshot channel is created that is used to receive the response
@@ -208,8 +2236,15 @@ async fn canister_worker(
             // TODO(qti3e) Do the optimization - we don't need to send the result to the replica
             // just so that it queues to our own `rx`.
             let request_id = call.request_id;
+            let payment = call.payment;
             let (tx, rx) = oneshot::channel();
 
+            {
+                let mut tracer = tracer.lock().unwrap();
+                tracer.request(&call);
+                tracer.burn(call.sender, canister.subnet().call_fee);
+            }
+
             replica
                 .send(ReplicaMessage::CanisterRequest {
                     canister_id: call.callee,
@@ -219,14 +2254,29 @@ async fn canister_worker(
                 .unwrap_or_else(|_| panic!("ic-kit-runtime: could not send message to replica"));
 
             let rs = replica.clone();
+            let tracer = tracer.clone();
 
             tokio::spawn(async move {
                 let replica = rs;
 
-                // wait for the response from the destination canister.
-                let response = rx
-                    .await
-                    .expect("ic-kit-runtime: Could not get the response of inter-canister call.");
+                // wait for the response from the destination canister, if the destination dropped
+                // the call the caller gets a reject instead of waiting forever, and the call is
+                // reported when the replica is shut down.
+                let response = match rx.await {
+                    Ok(response) => {
+                        tracer.lock().unwrap().reply(request_id, &response);
+                        response
+                    }
+                    Err(_) => {
+                        tracer.lock().unwrap().dropped(request_id);
+                        CallReply::Reject {
+                            rejection_code: RejectionCode::CanisterError,
+                            rejection_message: "The callee dropped the call without a reply."
+                                .to_string(),
+                            cycles_refunded: payment,
+                        }
+                    }
+                };
 
                 let message = response.to_message(request_id);
 
@@ -241,6 +2291,161 @@ async fn canister_worker(
                     });
             });
         }
+
+        if canister.take_sliced() {
+            // Deliver the messages queued while the long message was executed, before it's no
+            // longer counted as in flight.
+            let (tx, rx) = oneshot::channel();
+            replica
+                .send(ReplicaMessage::SlicesDone {
+                    canister_id,
+                    reply_sender: tx,
+                })
+                .unwrap_or_else(|_| panic!("ic-kit-runtime: could not send message to replica"));
+            let _ = rx.await;
+        }
+
+        // The replies sent by the canister wake up the tasks above which route them, yield so
+        // they run before the message is no longer counted as in flight.
+        tokio::task::yield_now().await;
+        in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// The messages waiting to be executed by a canister, in two lanes, see [`LanePolicy`].
+struct Lanes {
+    subnet: SubnetConfig,
+    /// The compute allocations of the senders are read from the counters.
+    counters: Arc<Counters>,
+    /// The messages of the normal lane.
+    normal: Turns,
+    /// The messages of the low priority lane.
+    low: Turns,
+    /// The number of messages received so far.
+    received: u64,
+    /// The number of messages delivered since the last one of the low priority lane.
+    since_low: u32,
+}
+
+/// The messages of a lane, in a queue for each of their senders which take turns, so a sender
+/// with a lot of messages does not hold back the others. A sender delivers a single message in
+/// its turn, or as many as its compute allocation. The replies and the tasks of the tests have
+/// their own queue.
+#[derive(Default)]
+struct Turns {
+    /// The messages of each sender, with the order they were received in.
+    queues: HashMap<Option<Principal>, VecDeque<(u64, ReplicaCanisterRequest)>>,
+    /// The senders with messages waiting, the one whose turn it is first.
+    senders: VecDeque<Option<Principal>>,
+    /// The number of messages the sender whose turn it is can still deliver in its turn.
+    credit: u32,
+}
+
+impl Lanes {
+    fn new(subnet: SubnetConfig, counters: Arc<Counters>) -> Self {
+        Self {
+            subnet,
+            counters,
+            normal: Turns::default(),
+            low: Turns::default(),
+            received: 0,
+            since_low: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.normal.is_empty() && self.low.is_empty()
+    }
+
+    fn push(&mut self, request: ReplicaCanisterRequest) {
+        let order = self.received;
+        self.received += 1;
+
+        if self.subnet.is_low_priority(&request.message) {
+            self.low.push(order, request);
+        } else {
+            self.normal.push(order, request);
+        }
+    }
+
+    /// Return the next message to execute.
+    fn pop(&mut self) -> Option<ReplicaCanisterRequest> {
+        let low = match (self.normal.front(), self.low.front()) {
+            (Some(normal), Some(low)) => match self.subnet.lane_policy {
+                LanePolicy::Fifo => low < normal,
+                LanePolicy::Strict => false,
+                LanePolicy::Weighted(n) => self.since_low >= n,
+            },
+            (Some(_), None) => false,
+            (None, Some(_)) => true,
+            (None, None) => return None,
+        };
+
+        if low {
+            self.since_low = 0;
+            self.low.pop(&self.counters)
+        } else {
+            self.since_low = self.since_low.saturating_add(1);
+            self.normal.pop(&self.counters)
+        }
+    }
+}
+
+impl Turns {
+    fn is_empty(&self) -> bool {
+        self.senders.is_empty()
+    }
+
+    fn push(&mut self, order: u64, request: ReplicaCanisterRequest) {
+        let sender = match &request.message {
+            Message::Request { env, .. } => Some(env.sender),
+            Message::Reply { .. } | Message::CustomTask { .. } => None,
+        };
+
+        // The queues are kept once empty, so their buffers are reused.
+        let queue = self.queues.entry(sender).or_default();
+        if queue.is_empty() {
+            self.senders.push_back(sender);
+        }
+
+        queue.push_back((order, request));
+    }
+
+    /// Return the order the next message was received in.
+    fn front(&self) -> Option<u64> {
+        let sender = self.senders.front()?;
+        self.queues[sender].front().map(|(order, _)| *order)
+    }
+
+    fn pop(&mut self, counters: &Counters) -> Option<ReplicaCanisterRequest> {
+        let sender = *self.senders.front()?;
+
+        if self.credit == 0 {
+            self.credit = match sender {
+                // The turns only matter when several senders are waiting.
+                Some(sender) if self.senders.len() > 1 => counters
+                    .compute_allocations
+                    .lock()
+                    .unwrap()
+                    .get(&sender)
+                    .map(|percent| u32::from(*percent).max(1))
+                    .unwrap_or(1),
+                _ => 1,
+            };
+        }
+
+        let queue = self.queues.get_mut(&sender)?;
+        let (_, request) = queue.pop_front()?;
+        self.credit -= 1;
+
+        if queue.is_empty() {
+            self.senders.pop_front();
+            self.credit = 0;
+        } else if self.credit == 0 {
+            self.senders.rotate_left(1);
+        }
+
+        Some(request)
     }
 }
 
@@ -249,8 +2454,10 @@ impl ReplicaState {
         &mut self,
         canister_id: Principal,
         channel: mpsc::UnboundedSender<ReplicaCanisterRequest>,
+        stop: mpsc::UnboundedSender<oneshot::Sender<()>>,
+        replace: bool,
     ) {
-        if self.canisters.contains_key(&canister_id) {
+        if !replace && self.canisters.contains_key(&canister_id) {
             panic!(
                 "Canister '{}' is already defined in the replica.",
                 canister_id
@@ -258,6 +2465,35 @@ impl ReplicaState {
         }
 
         self.canisters.insert(canister_id, channel);
+        self.stops.insert(canister_id, stop);
+    }
+
+    /// Stop delivering messages to the canister, reject the messages held for it and stop its
+    /// event loop.
+    fn canister_removed(&mut self, canister_id: Principal, reply_sender: oneshot::Sender<()>) {
+        // The event loop rejects the messages already delivered to it.
+        if let Some(stop) = self.stops.remove(&canister_id) {
+            let _ = stop.send(reply_sender);
+        }
+
+        self.canisters.remove(&canister_id);
+
+        for request in self.sliced.remove(&canister_id).unwrap_or_default() {
+            reject_removed(canister_id, request);
+        }
+
+        let (removed, pending) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition::<Vec<_>, _>(|(id, _)| *id == canister_id);
+        self.pending = pending;
+
+        for (_, message) in removed {
+            match message {
+                HeldMessage::Request(request) => reject_removed(canister_id, request),
+                // The canister is gone, so its long message can't be finished.
+                HeldMessage::Slice(slice) => drop(slice),
+            }
+        }
     }
 
     pub fn canister_request(
@@ -266,36 +2502,362 @@ impl ReplicaState {
         message: Message,
         reply_sender: Option<oneshot::Sender<CallReply>>,
     ) {
-        if let Some(chan) = self.canisters.get(&canister_id) {
-            chan.send(ReplicaCanisterRequest {
-                message,
-                reply_sender,
-            })
-            .unwrap_or_else(|_| panic!("ic-kit-runtime: Could not enqueue the request."));
-        } else {
-            let cycles_refunded = match message {
-                Message::CustomTask { env, .. } => env.cycles_available,
-                Message::Request { env, .. } => env.cycles_refunded,
-                Message::Reply { .. } => 0,
+        let request = ReplicaCanisterRequest {
+            message,
+            reply_sender,
+        };
+
+        if !self.canisters.contains_key(&canister_id) {
+            return reject(
+                request,
+                RejectionCode::DestinationInvalid,
+                format!("Canister '{}' does not exists", canister_id),
+            );
+        }
+
+        if let (Some(lifecycle), Message::Request { env, .. }) = (&self.lifecycle, &request.message)
+        {
+            if canister_id == Principal::management_canister() && lifecycle.handles(env) {
+                let lifecycle = lifecycle.clone();
+                let sender = env.sender;
+                let method = env.method_name.clone().unwrap_or_default();
+                let arg = env.args.clone();
+
+                tokio::spawn(async move {
+                    let reply = lifecycle.call(sender, &method, arg).await;
+                    if let Some(reply_sender) = request.reply_sender {
+                        let _ = reply_sender.send(reply);
+                    }
+                });
+
+                return;
+            }
+
+            if let Some(status) = lifecycle.rejects(canister_id, env) {
+                return reject(
+                    request,
+                    RejectionCode::CanisterError,
+                    format!("Canister {} is {}.", canister_id, status),
+                );
+            }
+        }
+
+        if let Message::Request { env, .. } = &request.message {
+            let is_canister_call = self.canisters.contains_key(&env.sender);
+            let rejected = match &mut self.chaos {
+                Some(chaos) if is_canister_call => chaos.transient_reject(),
+                _ => false,
             };
 
-            reply_sender
-                .unwrap()
-                .send(CallReply::Reject {
-                    rejection_code: RejectionCode::DestinationInvalid,
-                    rejection_message: format!("Canister '{}' does not exists", canister_id),
-                    cycles_refunded,
-                })
-                .expect("ic-kit-runtime: Could not send the response.");
+            if rejected {
+                self.log_fault(format!(
+                    "rejected the call '{}' of {} from {}",
+                    env.method_name.as_deref().unwrap_or_default(),
+                    self.name(&canister_id),
+                    self.name(&env.sender)
+                ));
+
+                return reject(
+                    request,
+                    RejectionCode::SysTransient,
+                    "The call was rejected by the chaos mode of the replica.".to_string(),
+                );
+            }
         }
+
+        self.enqueue(canister_id, request);
     }
 
     fn canister_reply(&mut self, canister_id: Principal, message: Message) {
-        let chan = self.canisters.get(&canister_id).unwrap();
-        chan.send(ReplicaCanisterRequest {
-            message,
-            reply_sender: None,
-        })
-        .unwrap_or_else(|_| panic!("ic-kit-runtime: Could not enqueue the response request."));
+        if let Message::Reply { reply_to, .. } = &message {
+            if self.dropped_replies.remove(reply_to) {
+                return;
+            }
+        }
+
+        self.enqueue(
+            canister_id,
+            ReplicaCanisterRequest {
+                message,
+                reply_sender: None,
+            },
+        );
+    }
+
+    /// Fix the time, the system time is no longer used once the time is fixed.
+    fn set_time(&mut self, time: u64) {
+        self.time = Some(time);
+
+        if self.time_policy == TimePolicy::WallClock {
+            self.time_policy = TimePolicy::Logical;
+        }
+    }
+
+    fn set_time_policy(&mut self, policy: TimePolicy) {
+        self.time_policy = policy;
+        self.time = match policy {
+            TimePolicy::WallClock => None,
+            TimePolicy::Logical | TimePolicy::AutoAdvance(_) => Some(self.time.unwrap_or_else(now)),
+        };
+    }
+
+    /// Deliver the request to the canister, or hold it until it's chosen by `interleave` or
+    /// delivered by `step`.
+    fn enqueue(&mut self, canister_id: Principal, mut request: ReplicaCanisterRequest) {
+        // The custom tasks inspect the state of the canisters, so they are not held.
+        let is_custom_task = matches!(request.message, Message::CustomTask { .. });
+
+        if !is_custom_task {
+            if let Some(jump) = self.chaos.as_mut().and_then(ChaosState::clock_jump) {
+                self.log_fault(format!(
+                    "moved the time forward by {:?}",
+                    Duration::from_nanos(jump)
+                ));
+                self.set_time(self.time.unwrap_or_else(now) + jump);
+            }
+        }
+
+        if let Some(time) = self.time {
+            let env = match &mut request.message {
+                Message::CustomTask { env, .. }
+                | Message::Request { env, .. }
+                | Message::Reply { env, .. } => env,
+            };
+
+            env.time.get_or_insert(time);
+
+            if let TimePolicy::AutoAdvance(step) = self.time_policy {
+                if !is_custom_task {
+                    self.time = Some(time + step.as_nanos() as u64);
+                }
+            }
+        }
+
+        if is_custom_task {
+            return self.deliver(canister_id, request);
+        }
+
+        // Each message moves the messages held back by the chaos mode closer to their delivery.
+        for (delay, _, _) in self.delayed.iter_mut() {
+            *delay -= 1;
+        }
+
+        let (ready, delayed) = std::mem::take(&mut self.delayed)
+            .into_iter()
+            .partition::<Vec<_>, _>(|(delay, _, _)| *delay == 0);
+        self.delayed = delayed;
+
+        if let Some(delay) = self.chaos.as_mut().and_then(ChaosState::delay) {
+            self.log_fault(format!(
+                "delayed the {} by {} messages",
+                self.describe(canister_id, &request),
+                delay
+            ));
+            self.delayed.push((delay, canister_id, request));
+        } else {
+            self.hold_or_deliver(canister_id, request);
+        }
+
+        for (_, canister_id, request) in ready {
+            self.hold_or_deliver(canister_id, request);
+        }
+    }
+
+    /// Hold the request if the replica is paused, or deliver it.
+    fn hold_or_deliver(&mut self, canister_id: Principal, request: ReplicaCanisterRequest) {
+        if self.hold {
+            self.pending
+                .push((canister_id, HeldMessage::Request(request)));
+        } else {
+            self.deliver(canister_id, request);
+        }
+    }
+
+    /// Deliver the message held back by the chaos mode which is the closest to its delivery, if
+    /// no message is being processed.
+    fn release_if_idle(&mut self) {
+        if self.in_flight.load(Ordering::SeqCst) != 0 {
+            return;
+        }
+
+        let next = match self.delayed.iter().map(|(delay, _, _)| *delay).min() {
+            Some(next) => next,
+            None => return,
+        };
+
+        let index = self
+            .delayed
+            .iter()
+            .position(|(delay, _, _)| *delay == next)
+            .unwrap();
+        let (_, canister_id, request) = self.delayed.remove(index);
+        self.hold_or_deliver(canister_id, request);
+    }
+
+    /// Print the fault injected by the chaos mode, if the log of the calls is enabled.
+    fn log_fault(&self, fault: String) {
+        if self.tracer.lock().unwrap().is_enabled() {
+            println!("chaos: {}", fault);
+        }
+    }
+
+    /// Return the name of the principal in the log of the calls.
+    fn name(&self, id: &Principal) -> String {
+        self.tracer.lock().unwrap().name(id)
+    }
+
+    /// Describe the request in the log of the faults.
+    fn describe(&self, canister_id: Principal, request: &ReplicaCanisterRequest) -> String {
+        match &request.message {
+            Message::Request { env, .. } => format!(
+                "call '{}' of {} from {}",
+                env.method_name.as_deref().unwrap_or_default(),
+                self.name(&canister_id),
+                self.name(&env.sender)
+            ),
+            Message::Reply { env, .. } => {
+                format!("{:?} of {}", env.entry_mode, self.name(&canister_id))
+            }
+            Message::CustomTask { .. } => format!("custom task on {}", self.name(&canister_id)),
+        }
+    }
+
+    fn deliver(&mut self, canister_id: Principal, request: ReplicaCanisterRequest) {
+        // The canister is executing a long message, the request waits for it to finish.
+        if let Some(queue) = self.sliced.get_mut(&canister_id) {
+            queue.push(request);
+            return;
+        }
+
+        let chan = match self.canisters.get(&canister_id) {
+            Some(chan) => chan,
+            // The canister was removed after the request was held, or while it was waiting for
+            // the reply to one of its calls.
+            None => return reject_removed(canister_id, request),
+        };
+
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+
+        // The event loop of the canister is gone without being removed, it died while executing
+        // a message.
+        if let Err(mpsc::error::SendError(request)) = chan.send(request) {
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            let crash = self.counters.crash_message(canister_id).unwrap_or_else(|| {
+                format!(
+                    "Canister '{}' crashed, its execution task stopped.",
+                    canister_id
+                )
+            });
+            reject(request, RejectionCode::CanisterError, crash);
+        }
+    }
+
+    fn deliver_held(&mut self, canister_id: Principal, message: HeldMessage) {
+        match message {
+            HeldMessage::Request(request) => self.deliver(canister_id, request),
+            HeldMessage::Slice(slice) => self.resume(slice),
+        }
+    }
+
+    fn hold(&mut self, hold: bool) {
+        self.hold = hold;
+
+        if !hold {
+            for (canister_id, message) in std::mem::take(&mut self.pending) {
+                self.deliver_held(canister_id, message);
+            }
+        }
+    }
+
+    /// Execute the next slice of the long message of the canister in the next round, or hold it
+    /// until it's delivered by `step`.
+    fn slice(&mut self, canister_id: Principal, slice: ReplicaSlice) {
+        self.sliced.entry(canister_id).or_default();
+
+        if self.hold {
+            self.pending.push((canister_id, HeldMessage::Slice(slice)));
+        } else {
+            self.resume(slice);
+        }
+    }
+
+    fn resume(&mut self, slice: ReplicaSlice) {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        let _ = slice.resume.send(());
+    }
+
+    fn slices_done(&mut self, canister_id: Principal) {
+        for request in self.sliced.remove(&canister_id).unwrap_or_default() {
+            self.deliver(canister_id, request);
+        }
+    }
+
+    /// Describe each of the held messages.
+    fn pending(&self) -> Vec<PendingMessage> {
+        let tracer = self.tracer.lock().unwrap();
+
+        self.pending
+            .iter()
+            .map(|(canister_id, message)| {
+                let canister = tracer.name(canister_id);
+
+                let request = match message {
+                    HeldMessage::Request(request) => request,
+                    HeldMessage::Slice(slice) => {
+                        let message = match &slice.method {
+                            Some(method) => format!("'{}'", method),
+                            None => format!("{:?}", slice.entry_mode),
+                        };
+
+                        return PendingMessage {
+                            canister_id: *canister_id,
+                            entry_mode: slice.entry_mode,
+                            sender: None,
+                            method: slice.method.clone(),
+                            arg: Vec::new(),
+                            cycles: 0,
+                            description: format!(
+                                "slice {} of {} on {}",
+                                slice.slice, message, canister
+                            ),
+                        };
+                    }
+                };
+
+                let (env, sender, description) = match &request.message {
+                    Message::Request { env, .. } => (
+                        env,
+                        Some(env.sender),
+                        format!(
+                            "call '{}' of {} from {}",
+                            env.method_name.as_deref().unwrap_or_default(),
+                            canister,
+                            tracer.name(&env.sender)
+                        ),
+                    ),
+                    Message::Reply { env, .. } => (
+                        env,
+                        None,
+                        format!("{:?} of {} for its call", env.entry_mode, canister),
+                    ),
+                    Message::CustomTask { env, .. } => {
+                        (env, None, format!("custom task on {}", canister))
+                    }
+                };
+
+                PendingMessage {
+                    canister_id: *canister_id,
+                    entry_mode: env.entry_mode,
+                    sender,
+                    method: env.method_name.clone(),
+                    arg: env.args.clone(),
+                    cycles: match env.entry_mode {
+                        EntryMode::ReplyCallback | EntryMode::RejectCallback => env.cycles_refunded,
+                        _ => env.cycles_available,
+                    },
+                    description,
+                }
+            })
+            .collect()
     }
 }
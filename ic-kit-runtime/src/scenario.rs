@@ -0,0 +1,241 @@
+//! A builder for the long integration tests, which declares the steps of a test as a sequence of
+//! calls, time changes, upgrades and checks. The steps are logged as they run, and a failing step
+//! is reported with its description and the steps which ran before it.
+//!
+//! ```ignore
+//! #[kit_test]
+//! async fn transfer_then_upgrade(replica: Replica) {
+//!     let ledger = replica.add_canister(LedgerV1::anonymous());
+//!
+//!     Scenario::new(&replica)
+//!         .call(ledger.new_call("transfer").with_caller(*users::ALICE).with_args((bob, 10u64)))
+//!         .advance_time(Duration::from_secs(60))
+//!         .expect_reply(ledger.new_call("balance_of").with_arg(bob), |reply| {
+//!             reply.decode_one::<u64>() == Ok(10)
+//!         })
+//!         .upgrade(LedgerV2::anonymous(), ())
+//!         .check("the supply did not change", |replica| {
+//!             Box::pin(async move { total_supply(replica).await == 1_000 })
+//!         })
+//!         .run()
+//!         .await;
+//! }
+//! ```
+
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::time::Duration;
+
+use candid::encode_args;
+use candid::utils::ArgumentEncoder;
+use futures::FutureExt;
+
+use crate::call::{CallBuilder, CallReply};
+use crate::canister::Canister;
+use crate::Replica;
+
+/// A predicate on the state of the replica, see [`Scenario::check`].
+pub type Check<'a> = Box<dyn Fn(&'a Replica) -> Pin<Box<dyn Future<Output = bool> + 'a>> + 'a>;
+
+/// A predicate on the reply of a call, see [`Scenario::expect_reply`].
+pub type Expect<'a> = Box<dyn Fn(&CallReply) -> bool + 'a>;
+
+/// A sequence of steps which are run on a replica, one after the other.
+pub struct Scenario<'a> {
+    replica: &'a Replica,
+    steps: Vec<(String, Step<'a>)>,
+}
+
+enum Step<'a> {
+    Call {
+        call: CallBuilder<'a>,
+        expect: Option<Expect<'a>>,
+    },
+    SetTime(u64),
+    AdvanceTime(Duration),
+    Upgrade {
+        canister: Box<Canister>,
+        arg: Vec<u8>,
+    },
+    Check(Check<'a>),
+}
+
+impl<'a> Scenario<'a> {
+    /// Create an empty scenario on the given replica.
+    pub fn new(replica: &'a Replica) -> Self {
+        Self {
+            replica,
+            steps: Vec::new(),
+        }
+    }
+
+    /// Perform the call, the step fails if the call is rejected.
+    pub fn call(mut self, call: CallBuilder<'a>) -> Self {
+        let description = call.describe();
+        self.steps
+            .push((description, Step::Call { call, expect: None }));
+        self
+    }
+
+    /// Perform the call, the step fails if the predicate does not hold for its reply.
+    pub fn expect_reply<F>(mut self, call: CallBuilder<'a>, expect: F) -> Self
+    where
+        F: Fn(&CallReply) -> bool + 'a,
+    {
+        let description = format!("{}, expecting a reply", call.describe());
+        self.steps.push((
+            description,
+            Step::Call {
+                call,
+                expect: Some(Box::new(expect)),
+            },
+        ));
+        self
+    }
+
+    /// Fix the time of the replica, see [`Replica::set_time`].
+    pub fn set_time(mut self, time: u64) -> Self {
+        self.steps
+            .push((format!("set the time to {}", time), Step::SetTime(time)));
+        self
+    }
+
+    /// Move the time of the replica forward, see [`Replica::advance_time`].
+    pub fn advance_time(mut self, duration: Duration) -> Self {
+        self.steps.push((
+            format!("advance the time by {:?}", duration),
+            Step::AdvanceTime(duration),
+        ));
+        self
+    }
+
+    /// Upgrade the canister with the same id to the given build, see
+    /// [`CanisterHandle::upgrade`]. The step fails if any of the upgrade hooks fail.
+    ///
+    /// [`CanisterHandle::upgrade`]: crate::handle::CanisterHandle::upgrade
+    pub fn upgrade<T: ArgumentEncoder>(mut self, canister: Canister, args: T) -> Self {
+        let description = format!("upgrade {}", self.replica.name_of(&canister.id()));
+        let arg = encode_args(args).expect("Failed to encode arguments.");
+        self.steps.push((
+            description,
+            Step::Upgrade {
+                canister: Box::new(canister),
+                arg,
+            },
+        ));
+        self
+    }
+
+    /// Check a predicate on the state of the replica, usually by making query calls, the step
+    /// fails if the predicate does not hold.
+    pub fn check<S, F>(mut self, description: S, check: F) -> Self
+    where
+        S: Into<String>,
+        F: Fn(&'a Replica) -> Pin<Box<dyn Future<Output = bool> + 'a>> + 'a,
+    {
+        self.steps.push((
+            format!("check that {}", description.into()),
+            Step::Check(Box::new(check)),
+        ));
+        self
+    }
+
+    /// Run each of the steps in order, printing each step before it runs.
+    ///
+    /// # Panics
+    ///
+    /// If a step fails or panics, with the description of the step and the steps before it.
+    pub async fn run(self) {
+        let replica = self.replica;
+        let mut done = Vec::with_capacity(self.steps.len());
+
+        for (index, (description, step)) in self.steps.into_iter().enumerate() {
+            println!("scenario step {}: {}", index + 1, description);
+
+            let result = AssertUnwindSafe(run_step(replica, step))
+                .catch_unwind()
+                .await
+                .unwrap_or_else(|payload| {
+                    Err(payload
+                        .downcast_ref::<String>()
+                        .cloned()
+                        .or_else(|| payload.downcast_ref::<&str>().map(|s| s.to_string()))
+                        .unwrap_or_default())
+                });
+
+            if let Err(e) = result {
+                let mut message = format!(
+                    "ic-kit-runtime: Step {} of the scenario failed: {}\n{}",
+                    index + 1,
+                    description,
+                    e
+                );
+
+                if !done.is_empty() {
+                    message.push_str("\nThe steps before it:");
+
+                    for (index, description) in done.iter().enumerate() {
+                        message.push_str(&format!("\n  {}. {}", index + 1, description));
+                    }
+                }
+
+                panic!("{}", message);
+            }
+
+            done.push(description);
+        }
+    }
+}
+
+/// Run the step, returns the reason it failed if it did.
+async fn run_step<'a>(replica: &'a Replica, step: Step<'a>) -> Result<(), String> {
+    match step {
+        Step::Call { call, expect: None } => match call.perform().await {
+            CallReply::Reject {
+                rejection_code,
+                rejection_message,
+                ..
+            } => Err(format!(
+                "The call was rejected with {:?}: {}",
+                rejection_code, rejection_message
+            )),
+            CallReply::Reply { .. } => Ok(()),
+        },
+        Step::Call {
+            call,
+            expect: Some(expect),
+        } => {
+            let reply = call.perform().await;
+
+            if expect(&reply) {
+                Ok(())
+            } else {
+                Err(format!("The reply did not match: {:?}", reply))
+            }
+        }
+        Step::SetTime(time) => {
+            replica.set_time(time);
+            Ok(())
+        }
+        Step::AdvanceTime(duration) => {
+            replica.advance_time(duration);
+            Ok(())
+        }
+        Step::Upgrade { canister, arg } => {
+            let handle = replica.get_canister(canister.id());
+
+            match handle.upgrade_raw(*canister, arg).await.rejection_message() {
+                Some(e) => Err(format!("The upgrade failed: {}", e)),
+                None => Ok(()),
+            }
+        }
+        Step::Check(check) => {
+            if check(replica).await {
+                Ok(())
+            } else {
+                Err("The check did not hold.".to_string())
+            }
+        }
+    }
+}
@@ -0,0 +1,198 @@
+//! Launch several identical canisters, such as the shards of a sharded service or a market
+//! canister per pair, and call all of them at once.
+//!
+//! ```ignore
+//! let pairs = ["ICP/USD", "BTC/USD", "ETH/USD"];
+//! let markets = replica
+//!     .install_many(pairs.len(), |i, id| MarketCanister::build(id).with_init_arg(pairs[i]))
+//!     .await;
+//!
+//! let volumes: Vec<u64> = replica
+//!     .broadcast(&markets, "volume")
+//!     .gather()
+//!     .await;
+//!
+//! replica
+//!     .broadcast(&markets, "place_order")
+//!     .with_arg_for(|i, _| Order::buy(pairs[i], 10))
+//!     .perform()
+//!     .await
+//!     .iter()
+//!     .for_each(CallReply::assert_ok);
+//! ```
+//!
+//! The shards get consecutive ids, after the ids of the shards installed before them, and their
+//! init hooks run in order once all of them are added to the replica. The calls of a broadcast are
+//! all sent before any of their replies is awaited, so their execution may interleave.
+
+use candid::utils::ArgumentEncoder;
+use candid::{CandidType, Principal};
+use serde::de::DeserializeOwned;
+
+use crate::call::{CallBuilder, CallReply};
+use crate::canister::Canister;
+use crate::handle::CanisterHandle;
+use crate::replica::canister_id;
+use crate::Replica;
+
+/// The index of the id of the first shard, see [`canister_id`].
+const SHARD_CANISTER_INDEX: u64 = 0x20_0000;
+
+/// The same call sent to several canisters, created by [`Replica::broadcast`].
+pub struct Broadcast<'a> {
+    calls: Vec<CallBuilder<'a>>,
+}
+
+/// Build `count` canisters with the given function, add them to the replica and run their init
+/// hooks in order, see [`Replica::install_many`].
+pub(crate) async fn install_many<F>(
+    replica: &Replica,
+    count: usize,
+    mut factory: F,
+) -> Vec<CanisterHandle<'_>>
+where
+    F: FnMut(usize, Principal) -> Canister,
+{
+    let existing = replica.canister_ids();
+    let mut ids = (SHARD_CANISTER_INDEX..)
+        .map(canister_id)
+        .filter(|id| !existing.contains(id));
+    let mut handles = Vec::with_capacity(count);
+
+    for index in 0..count {
+        let id = ids.next().unwrap();
+        let canister = factory(index, id);
+
+        if canister.id() != id {
+            panic!(
+                "ic-kit-runtime: Shard {} was built with the id '{}' instead of '{}'.",
+                index,
+                canister.id(),
+                id
+            );
+        }
+
+        handles.push(replica.add_canister(canister));
+    }
+
+    for (index, handle) in handles.iter().enumerate() {
+        if let Some(e) = handle.init_or_restore().await.rejection_message() {
+            panic!(
+                "ic-kit-runtime: The init hook of shard {} ({}) failed: {}",
+                index,
+                handle.canister_id(),
+                e
+            );
+        }
+    }
+
+    handles
+}
+
+impl<'a> Broadcast<'a> {
+    pub(crate) fn new(
+        replica: &'a Replica,
+        canisters: &[CanisterHandle<'_>],
+        method: String,
+    ) -> Self {
+        Self {
+            calls: canisters
+                .iter()
+                .map(|canister| CallBuilder::new(replica, canister.canister_id(), method.clone()))
+                .collect(),
+        }
+    }
+
+    /// Use the given arguments for each of the calls.
+    pub fn with_args<T: ArgumentEncoder + Clone>(self, arguments: T) -> Self {
+        self.map(|call, _, _| call.with_args(arguments.clone()))
+    }
+
+    /// Use the given argument for each of the calls.
+    pub fn with_arg<T: CandidType + Clone>(self, argument: T) -> Self {
+        self.map(|call, _, _| call.with_arg(argument.clone()))
+    }
+
+    /// Use the argument returned by the given function for each of the calls, which is given the
+    /// index of the canister and its id.
+    pub fn with_arg_for<T, F>(self, mut argument: F) -> Self
+    where
+        T: CandidType,
+        F: FnMut(usize, Principal) -> T,
+    {
+        self.map(|call, index, id| call.with_arg(argument(index, id)))
+    }
+
+    /// Use the given raw argument for each of the calls.
+    pub fn with_arg_raw<A: Into<Vec<u8>>>(self, argument: A) -> Self {
+        let argument = argument.into();
+        self.map(|call, _, _| call.with_arg_raw(argument.clone()))
+    }
+
+    /// Make the calls as the given principal.
+    pub fn with_caller<I: Into<Principal>>(self, caller: I) -> Self {
+        let caller = caller.into();
+        self.map(|call, _, _| call.with_caller(caller))
+    }
+
+    /// Send the given cycles with each of the calls.
+    pub fn with_payment(self, cycles: u128) -> Self {
+        self.map(|call, _, _| call.with_payment128(cycles))
+    }
+
+    /// Return the calls, in the order of the canisters.
+    pub fn calls(&self) -> &[CallBuilder<'a>] {
+        &self.calls
+    }
+
+    /// Send all of the calls, and return their replies in the order of the canisters.
+    pub async fn perform(&self) -> Vec<CallReply> {
+        let replies = self
+            .calls
+            .iter()
+            .map(|call| call.send())
+            .collect::<Vec<_>>();
+        futures::future::join_all(replies).await
+    }
+
+    /// Send all of the calls, and return the decoded replies in the order of the canisters.
+    ///
+    /// # Panics
+    ///
+    /// If one of the calls is rejected, or if its reply can not be decoded.
+    pub async fn gather<T: DeserializeOwned + CandidType>(&self) -> Vec<T> {
+        let replies = self.perform().await;
+
+        self.calls
+            .iter()
+            .zip(replies)
+            .map(|(call, reply)| {
+                reply.decode_one().unwrap_or_else(|e| {
+                    panic!(
+                        "ic-kit-runtime: The call to '{}' of {} failed: {:?}",
+                        call.method_name(),
+                        call.canister_id(),
+                        e
+                    )
+                })
+            })
+            .collect()
+    }
+
+    fn map<F>(self, mut f: F) -> Self
+    where
+        F: FnMut(CallBuilder<'a>, usize, Principal) -> CallBuilder<'a>,
+    {
+        Broadcast {
+            calls: self
+                .calls
+                .into_iter()
+                .enumerate()
+                .map(|(index, call)| {
+                    let id = call.canister_id();
+                    f(call, index, id)
+                })
+                .collect(),
+        }
+    }
+}
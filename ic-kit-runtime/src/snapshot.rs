@@ -0,0 +1,89 @@
+//! Snapshot assertions, which compare a readable rendering of a value against a file committed
+//! next to the tests, so any change in the behavior of a canister shows up in the review.
+//!
+//! The snapshots are stored in the `snapshots` directory of the crate being tested. A missing
+//! snapshot is recorded by the first run, and the existing snapshots can be updated after an
+//! intended change by running the tests with the `IC_KIT_BLESS=1` environment variable.
+
+use std::path::PathBuf;
+use std::{env, fmt, fs};
+
+use candid::{CandidType, IDLArgs};
+
+use crate::diff::to_value;
+
+/// The environment variable which makes the assertions overwrite the snapshots when it is set.
+pub const BLESS_ENV_VAR: &str = "IC_KIT_BLESS";
+
+/// A readable rendering of a value, which can be compared against a snapshot file.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Snapshot(String);
+
+impl Snapshot {
+    /// Create a snapshot from the given text.
+    pub fn new<S: Into<String>>(text: S) -> Self {
+        Self(text.into())
+    }
+
+    /// Render the value as candid text, using the field names from its type.
+    pub fn from_candid<T: CandidType>(value: &T) -> Self {
+        Self(IDLArgs::new(&[to_value(value)]).to_string())
+    }
+
+    /// Return the text of the snapshot.
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+
+    /// Assert the snapshot matches the one stored with the given name, the snapshot is recorded
+    /// if it does not exist yet, or if the `IC_KIT_BLESS` environment variable is set.
+    ///
+    /// # Panics
+    ///
+    /// If the stored snapshot is different, or if it can not be read or written.
+    pub fn assert_matches(&self, name: &str) {
+        let path = snapshot_path(name);
+        let bless = env::var_os(BLESS_ENV_VAR).is_some();
+
+        match fs::read_to_string(&path) {
+            Ok(stored) if stored.trim_end() == self.0.trim_end() => {}
+            Ok(stored) if !bless => panic!(
+                "The snapshot '{}' does not match.\n\nExpected:\n{}\n\nActual:\n{}\n\nRun the tests with {}=1 to accept the new snapshot.",
+                name,
+                stored.trim_end(),
+                self.0.trim_end(),
+                BLESS_ENV_VAR
+            ),
+            Ok(_) => self.write(&path),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => self.write(&path),
+            Err(e) => panic!("Could not read the snapshot '{}': {}", name, e),
+        }
+    }
+
+    fn write(&self, path: &PathBuf) {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).unwrap_or_else(|e| {
+                panic!("Could not create the directory '{}': {}", dir.display(), e)
+            });
+        }
+
+        fs::write(path, format!("{}\n", self.0.trim_end()))
+            .unwrap_or_else(|e| panic!("Could not write the snapshot '{}': {}", path.display(), e));
+    }
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Return the path of the snapshot with the given name, in the directory of the crate which is
+/// being tested.
+fn snapshot_path(name: &str) -> PathBuf {
+    let dir = env::var_os("CARGO_MANIFEST_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("."));
+
+    dir.join("snapshots").join(format!("{}.snap", name))
+}
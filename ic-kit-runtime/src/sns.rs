@@ -0,0 +1,981 @@
+//! Mocks of the root, governance, ledger and swap canisters of an SNS, to test the canisters of a
+//! dapp controlled by a DAO, see [`Replica::sns`].
+//!
+//! The canisters share their state: a proposal to transfer the tokens of the treasury is adopted
+//! and executed on the ledger as soon as it's submitted, and the motions are adopted right away.
+//! The upgrades and the generic functions need to call the canisters of the dapp, so they are
+//! executed by the tests with [`SnsMock::execute_upgrade`] and
+//! [`SnsMock::execute_generic_function`].
+//!
+//! ```ignore
+//! let sns = replica.sns();
+//! sns.register_dapp(dapp.canister_id());
+//! sns.mint_to_treasury(1_000_000u64);
+//!
+//! // The dapp, or a test, submits a proposal to the governance canister.
+//!
+//! let proposal = sns.proposals().pop().unwrap();
+//! sns.execute_upgrade(&dapp, proposal.id, DappV2::anonymous()).await.assert_ok();
+//! ```
+//!
+//! [`Replica::sns`]: crate::Replica::sns
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use candid::{encode_args, encode_one, CandidType, Deserialize, Nat, Principal};
+
+use ic_kit_sys::ic0;
+use ic_kit_sys::types::RejectionCode;
+
+use crate::call::{CallBuilder, CallReply};
+use crate::canister::Canister;
+use crate::handle::CanisterHandle;
+use crate::mock::{MockCall, MockCanister};
+use crate::replica::canister_id;
+use crate::Replica;
+
+/// The index of the first of the canisters of the SNS, which is far from the ones of the canisters
+/// added by the tests.
+const SNS_CANISTER_INDEX: u64 = 0x20_0000;
+
+/// The treasury of the token of the SNS, the value of `TransferFrom` in the governance canister.
+const SNS_TOKEN_TREASURY: i32 = 2;
+
+/// The lifecycle of a committed swap.
+const LIFECYCLE_COMMITTED: i32 = 3;
+
+/// The lifecycle of an open swap.
+const LIFECYCLE_OPEN: i32 = 2;
+
+const ROOT_CANDID: &str = r#"
+type ListSnsCanistersResponse = record {
+    root : opt principal;
+    governance : opt principal;
+    ledger : opt principal;
+    swap : opt principal;
+    index : opt principal;
+    dapps : vec principal;
+    archives : vec principal;
+};
+service : {
+    list_sns_canisters : (record {}) -> (ListSnsCanistersResponse) query;
+    register_dapp_canister : (record { canister_id : opt principal }) -> (record {});
+}
+"#;
+
+const GOVERNANCE_CANDID: &str = r#"
+type NeuronId = record { id : blob };
+type ProposalId = record { id : nat64 };
+type GovernanceError = record { error_message : text; error_type : int32 };
+type Action = variant {
+    Motion : record { motion_text : text };
+    UpgradeSnsControlledCanister : record {
+        canister_id : opt principal;
+        new_canister_wasm : blob;
+        canister_upgrade_arg : opt blob;
+        mode : opt int32;
+    };
+    TransferSnsTreasuryFunds : record {
+        from_treasury : int32;
+        to_principal : opt principal;
+        to_subaccount : opt record { subaccount : blob };
+        memo : opt nat64;
+        amount_e8s : nat64;
+    };
+    ExecuteGenericNervousSystemFunction : record { function_id : nat64; payload : blob };
+};
+type Proposal = record { title : text; summary : text; url : text; action : opt Action };
+type Command = variant {
+    MakeProposal : Proposal;
+    RegisterVote : record { vote : int32; proposal : opt ProposalId };
+};
+type GetProposal = record { proposal_id : opt ProposalId };
+type CommandResponse = variant {
+    Error : GovernanceError;
+    MakeProposal : GetProposal;
+    RegisterVote : record {};
+};
+type Tally = record { yes : nat64; no : nat64; total : nat64; timestamp_seconds : nat64 };
+type ProposalData = record {
+    id : opt ProposalId;
+    proposer : opt NeuronId;
+    proposal : opt Proposal;
+    proposal_creation_timestamp_seconds : nat64;
+    decided_timestamp_seconds : nat64;
+    executed_timestamp_seconds : nat64;
+    failed_timestamp_seconds : nat64;
+    failure_reason : opt GovernanceError;
+    latest_tally : opt Tally;
+};
+service : {
+    manage_neuron : (record { subaccount : blob; command : opt Command }) -> (record { command : opt CommandResponse });
+    get_proposal : (GetProposal) -> (record { result : opt variant { Error : GovernanceError; Proposal : ProposalData } }) query;
+}
+"#;
+
+const LEDGER_CANDID: &str = r#"
+type Account = record { owner : principal; subaccount : opt blob };
+type TransferArg = record {
+    from_subaccount : opt blob;
+    to : Account;
+    amount : nat;
+    fee : opt nat;
+    memo : opt blob;
+    created_at_time : opt nat64;
+};
+type TransferError = variant {
+    BadFee : record { expected_fee : nat };
+    InsufficientFunds : record { balance : nat };
+    GenericError : record { error_code : nat; message : text };
+};
+service : {
+    icrc1_name : () -> (text) query;
+    icrc1_symbol : () -> (text) query;
+    icrc1_decimals : () -> (nat8) query;
+    icrc1_fee : () -> (nat) query;
+    icrc1_total_supply : () -> (nat) query;
+    icrc1_balance_of : (Account) -> (nat) query;
+    icrc1_transfer : (TransferArg) -> (variant { Ok : nat; Err : TransferError });
+}
+"#;
+
+const SWAP_CANDID: &str = r#"
+type TransferableAmount = record {
+    amount_e8s : nat64;
+    transfer_start_timestamp_seconds : nat64;
+    transfer_success_timestamp_seconds : nat64;
+};
+service : {
+    get_lifecycle : (record {}) -> (record { lifecycle : opt int32 }) query;
+    refresh_buyer_tokens : (record { buyer : text; confirmation_text : opt text }) -> (record {
+        icp_accepted_participation_e8s : nat64;
+        icp_ledger_account_balance_e8s : nat64;
+    });
+    get_buyer_state : (record { principal_id : opt principal }) -> (record {
+        buyer_state : opt record { icp : opt TransferableAmount; has_created_neuron_recipes : opt bool };
+    }) query;
+}
+"#;
+
+/// The ids of the canisters of the SNS.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SnsCanisterIds {
+    pub root: Principal,
+    pub governance: Principal,
+    pub ledger: Principal,
+    pub swap: Principal,
+}
+
+/// A handle to the mocks of the canisters of an SNS.
+#[derive(Clone)]
+pub struct SnsMock {
+    ids: SnsCanisterIds,
+    state: Arc<Mutex<SnsState>>,
+}
+
+/// The action of a proposal, as encoded by the governance canister.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum SnsAction {
+    Motion {
+        motion_text: String,
+    },
+    UpgradeSnsControlledCanister {
+        canister_id: Option<Principal>,
+        new_canister_wasm: Vec<u8>,
+        canister_upgrade_arg: Option<Vec<u8>>,
+        mode: Option<i32>,
+    },
+    TransferSnsTreasuryFunds {
+        from_treasury: i32,
+        to_principal: Option<Principal>,
+        to_subaccount: Option<SnsSubaccount>,
+        memo: Option<u64>,
+        amount_e8s: u64,
+    },
+    ExecuteGenericNervousSystemFunction {
+        function_id: u64,
+        payload: Vec<u8>,
+    },
+}
+
+/// A subaccount, as encoded by the governance canister.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct SnsSubaccount {
+    pub subaccount: Vec<u8>,
+}
+
+/// A proposal submitted to the governance canister of the SNS.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SnsProposal {
+    /// The id of the proposal.
+    pub id: u64,
+    /// The id of the neuron which submitted the proposal.
+    pub proposer: Vec<u8>,
+    /// The title of the proposal.
+    pub title: String,
+    /// The action of the proposal.
+    pub action: SnsAction,
+    /// The time the proposal was submitted, in seconds since the unix epoch.
+    pub created_at: u64,
+    /// If the action was executed successfully.
+    pub executed: bool,
+    /// The reason the execution of the action failed, if it did.
+    pub failure: Option<String>,
+}
+
+#[derive(Default)]
+struct SnsState {
+    /// The balances of the ledger, by owner and subaccount.
+    balances: HashMap<(Principal, [u8; 32]), Nat>,
+    fee: Nat,
+    total_supply: Nat,
+    next_block: u64,
+    dapps: Vec<Principal>,
+    /// The method called by each generic function.
+    functions: HashMap<u64, (Principal, String)>,
+    proposals: Vec<SnsProposal>,
+    lifecycle: i32,
+    /// The ICP committed to the swap by each buyer.
+    participations: HashMap<Principal, u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct Account {
+    owner: Principal,
+    subaccount: Option<Vec<u8>>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct TransferArg {
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+}
+
+#[derive(CandidType)]
+enum TransferError {
+    BadFee { expected_fee: Nat },
+    InsufficientFunds { balance: Nat },
+}
+
+#[derive(CandidType, Deserialize)]
+struct ProposalId {
+    id: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+struct NeuronId {
+    id: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct Proposal {
+    title: String,
+    summary: String,
+    url: String,
+    action: Option<SnsAction>,
+}
+
+#[derive(CandidType, Deserialize)]
+enum Command {
+    MakeProposal(Proposal),
+    RegisterVote(Empty),
+}
+
+#[derive(CandidType, Deserialize)]
+struct ManageNeuron {
+    subaccount: Vec<u8>,
+    command: Option<Command>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct GetProposal {
+    proposal_id: Option<ProposalId>,
+}
+
+#[derive(CandidType)]
+struct GovernanceError {
+    error_message: String,
+    error_type: i32,
+}
+
+#[derive(CandidType, Deserialize)]
+struct Empty {}
+
+#[derive(CandidType)]
+enum CommandResponse {
+    Error(GovernanceError),
+    MakeProposal(GetProposal),
+    RegisterVote(Empty),
+}
+
+#[derive(CandidType)]
+struct Tally {
+    yes: u64,
+    no: u64,
+    total: u64,
+    timestamp_seconds: u64,
+}
+
+#[derive(CandidType)]
+struct ProposalData {
+    id: Option<ProposalId>,
+    proposer: Option<NeuronId>,
+    proposal: Option<Proposal>,
+    proposal_creation_timestamp_seconds: u64,
+    decided_timestamp_seconds: u64,
+    executed_timestamp_seconds: u64,
+    failed_timestamp_seconds: u64,
+    failure_reason: Option<GovernanceError>,
+    latest_tally: Option<Tally>,
+}
+
+#[derive(CandidType)]
+enum GetProposalResult {
+    Error(GovernanceError),
+    Proposal(Box<ProposalData>),
+}
+
+#[derive(CandidType, Deserialize)]
+struct RefreshBuyerTokensRequest {
+    buyer: String,
+}
+
+#[derive(CandidType)]
+struct TransferableAmount {
+    amount_e8s: u64,
+    transfer_start_timestamp_seconds: u64,
+    transfer_success_timestamp_seconds: u64,
+}
+
+#[derive(CandidType)]
+struct BuyerState {
+    icp: Option<TransferableAmount>,
+    has_created_neuron_recipes: Option<bool>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct GetBuyerStateRequest {
+    principal_id: Option<Principal>,
+}
+
+impl SnsMock {
+    /// Create the mock and the canisters which run it.
+    pub(crate) fn new() -> (Self, Vec<Canister>) {
+        let ids = SnsCanisterIds {
+            root: canister_id(SNS_CANISTER_INDEX),
+            governance: canister_id(SNS_CANISTER_INDEX + 1),
+            ledger: canister_id(SNS_CANISTER_INDEX + 2),
+            swap: canister_id(SNS_CANISTER_INDEX + 3),
+        };
+
+        let state = Arc::new(Mutex::new(SnsState {
+            fee: Nat::from(10_000u64),
+            lifecycle: LIFECYCLE_COMMITTED,
+            ..SnsState::default()
+        }));
+
+        let s = state.clone();
+        let root = MockCanister::new(ids.root, ROOT_CANDID).with_handler(
+            "list_sns_canisters",
+            move |_| {
+                let dapps = s.lock().unwrap().dapps.clone();
+                reply(ListSnsCanistersResponse {
+                    root: Some(ids.root),
+                    governance: Some(ids.governance),
+                    ledger: Some(ids.ledger),
+                    swap: Some(ids.swap),
+                    index: None,
+                    dapps,
+                    archives: vec![],
+                })
+            },
+        );
+        let s = state.clone();
+        let root = root.with_handler("register_dapp_canister", move |call| {
+            if call.caller != ids.governance {
+                return Err("Only the governance canister can register a dapp canister.".into());
+            }
+
+            let request = call
+                .decode_one::<RegisterDappCanisterRequest>()
+                .map_err(|e| e.to_string())?;
+
+            if let Some(canister_id) = request.canister_id {
+                s.lock().unwrap().register_dapp(canister_id);
+            }
+
+            reply(Empty {})
+        });
+
+        let s = state.clone();
+        let governance = MockCanister::new(ids.governance, GOVERNANCE_CANDID)
+            .with_handler("manage_neuron", move |call| {
+                manage_neuron(&mut s.lock().unwrap(), ids, call)
+            });
+        let s = state.clone();
+        let governance = governance.with_handler("get_proposal", move |call| {
+            get_proposal(&s.lock().unwrap(), call)
+        });
+
+        let ledger = MockCanister::new(ids.ledger, LEDGER_CANDID)
+            .with_handler("icrc1_name", |_| reply("SNS Token"))
+            .with_handler("icrc1_symbol", |_| reply("SNS"))
+            .with_handler("icrc1_decimals", |_| reply(8u8));
+        let s = state.clone();
+        let ledger =
+            ledger.with_handler("icrc1_fee", move |_| reply(s.lock().unwrap().fee.clone()));
+        let s = state.clone();
+        let ledger = ledger.with_handler("icrc1_total_supply", move |_| {
+            reply(s.lock().unwrap().total_supply.clone())
+        });
+        let s = state.clone();
+        let ledger = ledger.with_handler("icrc1_balance_of", move |call| {
+            let account = call.decode_one::<Account>().map_err(|e| e.to_string())?;
+            reply(
+                s.lock()
+                    .unwrap()
+                    .balance(account.owner, account.subaccount.as_deref()),
+            )
+        });
+        let s = state.clone();
+        let ledger = ledger.with_handler("icrc1_transfer", move |call| {
+            let arg = call
+                .decode_one::<TransferArg>()
+                .map_err(|e| e.to_string())?;
+            reply(s.lock().unwrap().transfer(
+                (call.caller, arg.from_subaccount.as_deref()),
+                (arg.to.owner, arg.to.subaccount.as_deref()),
+                arg.amount,
+                arg.fee,
+            ))
+        });
+
+        let s = state.clone();
+        let swap =
+            MockCanister::new(ids.swap, SWAP_CANDID).with_handler("get_lifecycle", move |_| {
+                reply(GetLifecycleResponse {
+                    lifecycle: Some(s.lock().unwrap().lifecycle),
+                })
+            });
+        let s = state.clone();
+        let swap = swap.with_handler("refresh_buyer_tokens", move |call| {
+            let request = call
+                .decode_one::<RefreshBuyerTokensRequest>()
+                .map_err(|e| e.to_string())?;
+            let buyer = Principal::from_text(&request.buyer).map_err(|e| e.to_string())?;
+            let state = s.lock().unwrap();
+
+            if state.lifecycle != LIFECYCLE_OPEN {
+                return Err("The swap is not open.".into());
+            }
+
+            let amount = state.participations.get(&buyer).copied().unwrap_or(0);
+            reply(RefreshBuyerTokensResponse {
+                icp_accepted_participation_e8s: amount,
+                icp_ledger_account_balance_e8s: amount,
+            })
+        });
+        let s = state.clone();
+        let swap = swap.with_handler("get_buyer_state", move |call| {
+            let request = call
+                .decode_one::<GetBuyerStateRequest>()
+                .map_err(|e| e.to_string())?;
+            let buyer = request.principal_id.unwrap_or(call.caller);
+            let amount = s.lock().unwrap().participations.get(&buyer).copied();
+
+            reply(GetBuyerStateResponse {
+                buyer_state: amount.map(|amount_e8s| BuyerState {
+                    icp: Some(TransferableAmount {
+                        amount_e8s,
+                        transfer_start_timestamp_seconds: 0,
+                        transfer_success_timestamp_seconds: 0,
+                    }),
+                    has_created_neuron_recipes: None,
+                }),
+            })
+        });
+
+        let canisters = vec![root.into(), governance.into(), ledger.into(), swap.into()];
+        (Self { ids, state }, canisters)
+    }
+
+    /// Return the ids of the canisters of the SNS.
+    pub fn ids(&self) -> SnsCanisterIds {
+        self.ids
+    }
+
+    /// Register a canister as a canister of the dapp controlled by the SNS, as if a proposal to
+    /// register it had been executed.
+    pub fn register_dapp(&self, canister_id: Principal) {
+        self.state.lock().unwrap().register_dapp(canister_id);
+    }
+
+    /// Return the canisters of the dapp controlled by the SNS.
+    pub fn dapps(&self) -> Vec<Principal> {
+        self.state.lock().unwrap().dapps.clone()
+    }
+
+    /// Register the method called by the proposals executing the generic function with the id.
+    pub fn add_generic_function<S: Into<String>>(
+        &self,
+        function_id: u64,
+        canister_id: Principal,
+        method: S,
+    ) {
+        self.state
+            .lock()
+            .unwrap()
+            .functions
+            .insert(function_id, (canister_id, method.into()));
+    }
+
+    /// Create the tokens of the SNS in the default account of the owner.
+    pub fn mint<T: Into<Nat>>(&self, owner: Principal, amount: T) {
+        let amount = amount.into();
+        let mut state = self.state.lock().unwrap();
+        let balance = state.balances.entry((owner, [0; 32])).or_default();
+        *balance += amount.clone();
+        state.total_supply += amount;
+    }
+
+    /// Create the tokens of the SNS in its treasury.
+    pub fn mint_to_treasury<T: Into<Nat>>(&self, amount: T) {
+        self.mint(self.ids.governance, amount);
+    }
+
+    /// Return the balance of the default account of the owner on the ledger.
+    pub fn balance_of(&self, owner: Principal) -> Nat {
+        self.state.lock().unwrap().balance(owner, None)
+    }
+
+    /// Return the balance of the treasury of the token of the SNS, which is the default account
+    /// of the governance canister in the mock.
+    pub fn treasury_balance(&self) -> Nat {
+        self.balance_of(self.ids.governance)
+    }
+
+    /// Set the fee of the transfers on the ledger, which is `10_000` by default.
+    pub fn set_fee<T: Into<Nat>>(&self, fee: T) {
+        self.state.lock().unwrap().fee = fee.into();
+    }
+
+    /// Set the lifecycle of the swap, which is committed by default, see the `lifecycle` module of
+    /// `ic_kit::interfaces::sns::swap`.
+    pub fn set_lifecycle(&self, lifecycle: i32) {
+        self.state.lock().unwrap().lifecycle = lifecycle;
+    }
+
+    /// Set the ICP committed to the swap by the buyer.
+    pub fn set_participation(&self, buyer: Principal, amount_e8s: u64) {
+        self.state
+            .lock()
+            .unwrap()
+            .participations
+            .insert(buyer, amount_e8s);
+    }
+
+    /// Return the proposals submitted to the governance canister, in order.
+    pub fn proposals(&self) -> Vec<SnsProposal> {
+        self.state.lock().unwrap().proposals.clone()
+    }
+
+    /// Return the proposal with the given id.
+    pub fn proposal(&self, id: u64) -> Option<SnsProposal> {
+        self.state
+            .lock()
+            .unwrap()
+            .proposals
+            .iter()
+            .find(|p| p.id == id)
+            .cloned()
+    }
+
+    /// Create a call to a canister made by the governance canister of the SNS, to test the
+    /// methods which only accept it as their caller.
+    pub fn call_as_governance<'a>(
+        &self,
+        handle: &'a CanisterHandle<'a>,
+        method: &str,
+    ) -> CallBuilder<'a> {
+        handle.new_call(method).with_caller(self.ids.governance)
+    }
+
+    /// Execute an adopted proposal upgrading a canister of the dapp, by upgrading it to the given
+    /// build with the argument of the proposal.
+    ///
+    /// # Panics
+    ///
+    /// If the proposal does not exist, is not an upgrade of the canister, or the canister is not
+    /// a canister of the dapp.
+    pub async fn execute_upgrade(
+        &self,
+        handle: &CanisterHandle<'_>,
+        proposal_id: u64,
+        canister: Canister,
+    ) -> CallReply {
+        let arg = match self.expect_proposal(proposal_id).action {
+            SnsAction::UpgradeSnsControlledCanister {
+                canister_id,
+                canister_upgrade_arg,
+                ..
+            } => {
+                assert_eq!(
+                    canister_id,
+                    Some(handle.canister_id()),
+                    "ic-kit-runtime: The proposal {} does not upgrade this canister.",
+                    proposal_id
+                );
+                canister_upgrade_arg.unwrap_or_else(|| encode_args(()).unwrap())
+            }
+            _ => panic!(
+                "ic-kit-runtime: The proposal {} is not an upgrade.",
+                proposal_id
+            ),
+        };
+
+        assert!(
+            self.dapps().contains(&handle.canister_id()),
+            "ic-kit-runtime: The canister {} is not registered as a dapp canister of the SNS.",
+            handle.canister_id()
+        );
+
+        let reply = handle.upgrade_raw(canister, arg).await;
+        self.complete(proposal_id, &reply);
+        reply
+    }
+
+    /// Execute an adopted proposal of a generic function, by calling the method registered with
+    /// [`SnsMock::add_generic_function`] with the payload, as the governance canister.
+    ///
+    /// # Panics
+    ///
+    /// If the proposal does not exist, is not a generic function, or the function is not
+    /// registered.
+    pub async fn execute_generic_function(&self, replica: &Replica, proposal_id: u64) -> CallReply {
+        let (function_id, payload) = match self.expect_proposal(proposal_id).action {
+            SnsAction::ExecuteGenericNervousSystemFunction {
+                function_id,
+                payload,
+            } => (function_id, payload),
+            _ => panic!(
+                "ic-kit-runtime: The proposal {} is not a generic function.",
+                proposal_id
+            ),
+        };
+
+        let (canister_id, method) = self
+            .state
+            .lock()
+            .unwrap()
+            .functions
+            .get(&function_id)
+            .cloned()
+            .unwrap_or_else(|| {
+                panic!(
+                    "ic-kit-runtime: The generic function {} is not registered.",
+                    function_id
+                )
+            });
+
+        let reply = CallBuilder::new(replica, canister_id, method)
+            .with_caller(self.ids.governance)
+            .with_arg_raw(payload)
+            .perform()
+            .await;
+        self.complete(proposal_id, &reply);
+        reply
+    }
+
+    fn expect_proposal(&self, proposal_id: u64) -> SnsProposal {
+        self.proposal(proposal_id).unwrap_or_else(|| {
+            panic!(
+                "ic-kit-runtime: The proposal {} does not exist.",
+                proposal_id
+            )
+        })
+    }
+
+    /// Record the result of the execution of a proposal.
+    fn complete(&self, proposal_id: u64, reply: &CallReply) {
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(proposal) = state.proposals.iter_mut().find(|p| p.id == proposal_id) {
+            match reply {
+                CallReply::Reply { .. } => {
+                    proposal.executed = true;
+                    proposal.failure = None;
+                }
+                CallReply::Reject {
+                    rejection_code,
+                    rejection_message,
+                    ..
+                } => {
+                    proposal.failure = Some(format!(
+                        "{}: {}",
+                        rejection_code_name(*rejection_code),
+                        rejection_message
+                    ));
+                }
+            }
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+struct RegisterDappCanisterRequest {
+    canister_id: Option<Principal>,
+}
+
+#[derive(CandidType)]
+struct ListSnsCanistersResponse {
+    root: Option<Principal>,
+    governance: Option<Principal>,
+    ledger: Option<Principal>,
+    swap: Option<Principal>,
+    index: Option<Principal>,
+    dapps: Vec<Principal>,
+    archives: Vec<Principal>,
+}
+
+#[derive(CandidType)]
+struct GetLifecycleResponse {
+    lifecycle: Option<i32>,
+}
+
+#[derive(CandidType)]
+struct RefreshBuyerTokensResponse {
+    icp_accepted_participation_e8s: u64,
+    icp_ledger_account_balance_e8s: u64,
+}
+
+#[derive(CandidType)]
+struct GetBuyerStateResponse {
+    buyer_state: Option<BuyerState>,
+}
+
+impl SnsState {
+    fn register_dapp(&mut self, canister_id: Principal) {
+        if !self.dapps.contains(&canister_id) {
+            self.dapps.push(canister_id);
+        }
+    }
+
+    fn balance(&self, owner: Principal, subaccount: Option<&[u8]>) -> Nat {
+        self.balances
+            .get(&(owner, to_subaccount(subaccount)))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Transfer the amount and burn the fee, and return the index of the block.
+    fn transfer(
+        &mut self,
+        from: (Principal, Option<&[u8]>),
+        to: (Principal, Option<&[u8]>),
+        amount: Nat,
+        fee: Option<Nat>,
+    ) -> Result<Nat, TransferError> {
+        if let Some(fee) = fee {
+            if fee != self.fee {
+                return Err(TransferError::BadFee {
+                    expected_fee: self.fee.clone(),
+                });
+            }
+        }
+
+        let from = (from.0, to_subaccount(from.1));
+        let to = (to.0, to_subaccount(to.1));
+        let debit = amount.clone() + self.fee.clone();
+        let balance = self.balances.get(&from).cloned().unwrap_or_default();
+
+        if balance < debit {
+            return Err(TransferError::InsufficientFunds { balance });
+        }
+
+        self.balances.insert(from, balance - debit);
+        *self.balances.entry(to).or_default() += amount;
+        self.total_supply -= self.fee.clone();
+
+        let block = self.next_block;
+        self.next_block += 1;
+        Ok(Nat::from(block))
+    }
+
+    /// Adopt the proposal and execute its action if it does not need to call another canister.
+    fn submit(
+        &mut self,
+        governance: Principal,
+        proposer: Vec<u8>,
+        proposal: Proposal,
+    ) -> Result<u64, String> {
+        let action = proposal
+            .action
+            .ok_or_else(|| "The proposal does not have an action.".to_string())?;
+
+        let mut executed = false;
+        let mut failure = None;
+
+        match &action {
+            SnsAction::Motion { .. } => executed = true,
+            SnsAction::TransferSnsTreasuryFunds {
+                from_treasury,
+                to_principal,
+                to_subaccount,
+                amount_e8s,
+                ..
+            } => {
+                let result = match (*from_treasury, to_principal) {
+                    (SNS_TOKEN_TREASURY, Some(to)) => self
+                        .transfer(
+                            (governance, None),
+                            (*to, to_subaccount.as_ref().map(|s| s.subaccount.as_slice())),
+                            Nat::from(*amount_e8s),
+                            None,
+                        )
+                        .map_err(|e| match e {
+                            TransferError::InsufficientFunds { balance } => {
+                                format!("The treasury only has {} tokens.", balance)
+                            }
+                            TransferError::BadFee { .. } => "The fee is invalid.".to_string(),
+                        }),
+                    (SNS_TOKEN_TREASURY, None) => Err("The recipient is missing.".to_string()),
+                    _ => {
+                        Err("The mock only holds the treasury of the token of the SNS.".to_string())
+                    }
+                };
+
+                match result {
+                    Ok(_) => executed = true,
+                    Err(e) => failure = Some(e),
+                }
+            }
+            SnsAction::UpgradeSnsControlledCanister { .. }
+            | SnsAction::ExecuteGenericNervousSystemFunction { .. } => {}
+        }
+
+        let id = self.proposals.len() as u64 + 1;
+
+        self.proposals.push(SnsProposal {
+            id,
+            proposer,
+            title: proposal.title,
+            action,
+            created_at: unsafe { ic0::time() } as u64 / 1_000_000_000,
+            executed,
+            failure,
+        });
+
+        Ok(id)
+    }
+}
+
+/// Reply to a call to `manage_neuron`, this runs on the thread of the governance canister.
+fn manage_neuron(
+    state: &mut SnsState,
+    ids: SnsCanisterIds,
+    call: &MockCall,
+) -> Result<Vec<u8>, String> {
+    let args = call
+        .decode_one::<ManageNeuron>()
+        .map_err(|e| e.to_string())?;
+
+    let response = match args.command {
+        Some(Command::MakeProposal(proposal)) => {
+            match state.submit(ids.governance, args.subaccount, proposal) {
+                Ok(id) => CommandResponse::MakeProposal(GetProposal {
+                    proposal_id: Some(ProposalId { id }),
+                }),
+                Err(error_message) => CommandResponse::Error(GovernanceError {
+                    error_message,
+                    error_type: 15,
+                }),
+            }
+        }
+        Some(Command::RegisterVote(_)) => CommandResponse::RegisterVote(Empty {}),
+        None => CommandResponse::Error(GovernanceError {
+            error_message: "The command is missing.".into(),
+            error_type: 15,
+        }),
+    };
+
+    reply(ManageNeuronResponse {
+        command: Some(response),
+    })
+}
+
+#[derive(CandidType)]
+struct ManageNeuronResponse {
+    command: Option<CommandResponse>,
+}
+
+#[derive(CandidType)]
+struct GetProposalResponse {
+    result: Option<GetProposalResult>,
+}
+
+/// Reply to a call to `get_proposal`, this runs on the thread of the governance canister.
+fn get_proposal(state: &SnsState, call: &MockCall) -> Result<Vec<u8>, String> {
+    let request = call
+        .decode_one::<GetProposal>()
+        .map_err(|e| e.to_string())?;
+    let id = request.proposal_id.map(|p| p.id).unwrap_or(0);
+
+    let result = match state.proposals.iter().find(|p| p.id == id) {
+        Some(p) => GetProposalResult::Proposal(Box::new(ProposalData {
+            id: Some(ProposalId { id: p.id }),
+            proposer: Some(NeuronId {
+                id: p.proposer.clone(),
+            }),
+            proposal: Some(Proposal {
+                title: p.title.clone(),
+                summary: String::new(),
+                url: String::new(),
+                action: Some(p.action.clone()),
+            }),
+            proposal_creation_timestamp_seconds: p.created_at,
+            decided_timestamp_seconds: p.created_at,
+            executed_timestamp_seconds: if p.executed { p.created_at } else { 0 },
+            failed_timestamp_seconds: if p.failure.is_some() { p.created_at } else { 0 },
+            failure_reason: p.failure.clone().map(|error_message| GovernanceError {
+                error_message,
+                error_type: 15,
+            }),
+            latest_tally: Some(Tally {
+                yes: 1,
+                no: 0,
+                total: 1,
+                timestamp_seconds: p.created_at,
+            }),
+        })),
+        None => GetProposalResult::Error(GovernanceError {
+            error_message: format!("The proposal {} does not exist.", id),
+            error_type: 3,
+        }),
+    };
+
+    reply(GetProposalResponse {
+        result: Some(result),
+    })
+}
+
+fn to_subaccount(subaccount: Option<&[u8]>) -> [u8; 32] {
+    let mut result = [0; 32];
+
+    if let Some(subaccount) = subaccount {
+        let len = subaccount.len().min(32);
+        result[..len].copy_from_slice(&subaccount[..len]);
+    }
+
+    result
+}
+
+fn rejection_code_name(code: RejectionCode) -> String {
+    format!("{:?}", code)
+}
+
+fn reply<T: CandidType>(value: T) -> Result<Vec<u8>, String> {
+    encode_one(value).map_err(|e| e.to_string())
+}
@@ -0,0 +1,127 @@
+//! The `tracing` spans of the messages executed by the canisters, enabled with the `tracing`
+//! feature.
+//!
+//! Each message is executed in a `message` span with the `canister_id`, `method`, `caller` and
+//! `cycles` fields, the callbacks of its calls are executed in `callback` spans nested under it,
+//! and the messages of the calls it makes are nested under the span they were made from. The
+//! calls made by a test are nested under the span of the test, so the output of a test can be
+//! filtered with the usual subscribers:
+//!
+//! ```text
+//! INFO test{name="transfer"}:message{canister_id=rrkah-... method="transfer" entry_mode=Update caller=2vxsx-fae cycles=0}:
+//!     message{canister_id=ryjl3-... method="append" entry_mode=Update caller=rrkah-... cycles=0}: archive: appended
+//! ```
+//!
+//! Without the feature the spans are zero sized and nothing is recorded.
+
+#[cfg(feature = "tracing")]
+use std::collections::HashMap;
+#[cfg(feature = "tracing")]
+use std::sync::Mutex;
+
+use candid::Principal;
+
+use crate::types::{Env, RequestId, Task};
+
+/// The span of a message, or of the code which made a call.
+#[derive(Clone, Default)]
+pub(crate) struct MessageSpan {
+    #[cfg(feature = "tracing")]
+    span: Option<tracing::Span>,
+}
+
+impl MessageSpan {
+    /// Return the span the current code is executed in, such as the span of a test.
+    pub fn current() -> Self {
+        Self {
+            #[cfg(feature = "tracing")]
+            span: Some(tracing::Span::current()),
+        }
+    }
+
+    /// Create the span of a message executed by the canister, nested under the span of the
+    /// caller if there is one.
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    pub fn message(parent: Option<&MessageSpan>, canister_id: &Principal, env: &Env) -> Self {
+        Self {
+            #[cfg(feature = "tracing")]
+            span: Some(tracing::info_span!(
+                parent: parent.and_then(|parent| parent.span.as_ref()).and_then(|span| span.id()),
+                "message",
+                canister_id = %canister_id,
+                method = env.method_name.as_deref().unwrap_or_default(),
+                entry_mode = ?env.entry_mode,
+                caller = %env.sender,
+                cycles = env.cycles_available,
+            )),
+        }
+    }
+
+    /// Create the span of a reply or a reject callback, nested under the span of the message
+    /// which made the call.
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    pub fn callback(&self, canister_id: &Principal, env: &Env) -> Self {
+        Self {
+            #[cfg(feature = "tracing")]
+            span: Some(tracing::info_span!(
+                parent: self.span.as_ref().and_then(|span| span.id()),
+                "callback",
+                canister_id = %canister_id,
+                entry_mode = ?env.entry_mode,
+                caller = %env.sender,
+                cycles = env.cycles_refunded,
+            )),
+        }
+    }
+
+    /// Wrap the task so it's executed in the span on the execution thread of the canister.
+    pub fn wrap(&self, task: Task) -> Task {
+        #[cfg(feature = "tracing")]
+        if let Some(span) = self.span.clone() {
+            let span = std::panic::AssertUnwindSafe(span);
+            return Task::Boxed(Box::new(move || {
+                // Move the whole wrapper into the closure, not only the span.
+                let span = span;
+                span.0.in_scope(|| task.run())
+            }));
+        }
+
+        task
+    }
+
+    /// Record that the message trapped.
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    pub fn trapped(&self, message: &str) {
+        #[cfg(feature = "tracing")]
+        if let Some(span) = &self.span {
+            tracing::warn!(parent: span, trap = message, "The canister trapped.");
+        }
+    }
+}
+
+/// The spans of the calls which are sent but not yet received by the callee, so the callee can
+/// nest the message of the call under them.
+#[derive(Default)]
+pub(crate) struct CallSpans {
+    #[cfg(feature = "tracing")]
+    spans: Mutex<HashMap<RequestId, MessageSpan>>,
+}
+
+impl CallSpans {
+    /// Record the span the call with the given request id was made from.
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    pub fn insert(&self, request_id: RequestId, span: MessageSpan) {
+        #[cfg(feature = "tracing")]
+        self.spans.lock().unwrap().insert(request_id, span);
+    }
+
+    /// Remove the span the call with the given request id was made from.
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    pub fn remove(&self, request_id: RequestId) -> Option<MessageSpan> {
+        #[cfg(feature = "tracing")]
+        return self.spans.lock().unwrap().remove(&request_id);
+
+        #[cfg(not(feature = "tracing"))]
+        None
+    }
+}
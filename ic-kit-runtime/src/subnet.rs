@@ -0,0 +1,228 @@
+//! The differences between the types of subnets, so the canisters which are deployed on a system
+//! subnet, such as the NNS canisters, can be tested with the behavior they get there.
+//!
+//! The canisters on a system subnet are not charged for the calls they make, so their balance does
+//! not change, while the canisters on an application subnet pay a fee for each call. The subnet of
+//! a replica is chosen with [`ReplicaBuilder::with_subnet`], and the limits can be adjusted:
+//!
+//! ```ignore
+//! let replica = ReplicaBuilder::new()
+//!     .with_subnet(SubnetConfig::system())
+//!     .with_canister(GovernanceCanister::anonymous())
+//!     .build()
+//!     .await;
+//! ```
+//!
+//! The canisters are executed natively, so the instructions of their code are not known. The
+//! runtime counts [`SubnetConfig::system_call_instructions`] for each system API call, plus one per
+//! byte it copies, which is what `performance_counter` returns and what the instruction limit of a
+//! message applies to. With [`SubnetConfig::with_slices`] the update calls, the callbacks and the
+//! system hooks are executed in slices as with deterministic time slicing: once a message used the
+//! instructions of a slice it's paused until the next round, and the other canisters execute their
+//! messages in between. The messages sent to the canister are queued until the long message is
+//! finished, and while the replica is paused each of the next slices is a message delivered by
+//! [`Replica::step`]:
+//!
+//! ```ignore
+//! let replica = ReplicaBuilder::new()
+//!     .with_subnet(SubnetConfig::application().with_slices(100_000))
+//!     .build()
+//!     .await;
+//! ```
+//!
+//! The messages of a canister are delivered in the order they are received, unless some of them
+//! are moved to the low priority lane with [`SubnetConfig::with_low_priority`], such as the
+//! heartbeats, in which case the [`LanePolicy`] decides when they are delivered while the other
+//! messages are waiting. This reproduces a canister whose maintenance work is starved by its users,
+//! or validates that it doesn't delay the calls of the users:
+//!
+//! ```ignore
+//! let replica = ReplicaBuilder::new()
+//!     .with_subnet(
+//!         SubnetConfig::application()
+//!             .with_low_priority(EntryMode::Heartbeat)
+//!             .with_lane_policy(LanePolicy::Strict),
+//!     )
+//!     .build()
+//!     .await;
+//! ```
+//!
+//! [`ReplicaBuilder::with_subnet`]: crate::ReplicaBuilder::with_subnet
+//! [`Replica::step`]: crate::Replica::step
+
+use ic_kit_sys::types::{MAX_QUERY_REPLY_SIZE, MAX_REPLY_SIZE};
+
+use crate::types::{EntryMode, Message};
+
+/// The cycles reserved from the balance of a canister on an application subnet for each call it
+/// makes, to pay for the processing of the response.
+pub const APPLICATION_CALL_FEE: u128 = 12;
+
+/// The instructions counted for each system API call by default.
+pub const DEFAULT_SYSTEM_CALL_INSTRUCTIONS: u64 = 1_000;
+
+/// The maximum number of instructions of an update call, a callback or a system hook, across all
+/// of its slices.
+pub const MAX_INSTRUCTIONS_PER_MESSAGE: u64 = 20_000_000_000;
+
+/// The maximum number of instructions of a query call, which is never sliced.
+pub const MAX_INSTRUCTIONS_PER_QUERY: u64 = 5_000_000_000;
+
+/// The type of a subnet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SubnetType {
+    /// A subnet hosting the canisters of the users, which pay for the resources they use.
+    Application,
+    /// A subnet hosting the canisters of the system, such as the NNS, which are not charged.
+    System,
+}
+
+/// When the messages of the low priority lane of a canister are delivered, see
+/// [`SubnetConfig::with_low_priority`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum LanePolicy {
+    /// The messages are delivered in the order they are received, whatever their lane.
+    Fifo,
+    /// A message of the low priority lane is only delivered when no other message is waiting for
+    /// the canister, so a canister which is always busy never gets to it.
+    Strict,
+    /// While both lanes have messages waiting, a message of the low priority lane is delivered
+    /// after every given number of other messages.
+    Weighted(u32),
+}
+
+/// The costs and the limits of the subnet the canisters of a replica run on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SubnetConfig {
+    /// The type of the subnet.
+    pub subnet_type: SubnetType,
+    /// The cycles charged to a canister for each call it makes.
+    pub call_fee: u128,
+    /// The maximum size of the reply to an update call, in bytes.
+    pub max_reply_size: usize,
+    /// The maximum size of the reply to a query call, in bytes.
+    pub max_query_reply_size: usize,
+    /// The maximum size of the reply to a call made by another canister, whether an update or a
+    /// query, in bytes.
+    pub max_inter_canister_reply_size: usize,
+    /// The instructions counted for each system API call made by a canister, in addition to one
+    /// per byte copied.
+    pub system_call_instructions: u64,
+    /// The maximum number of instructions of an update call, a callback or a system hook, once it's
+    /// reached the canister traps.
+    pub max_instructions_per_message: u64,
+    /// The maximum number of instructions of a query call.
+    pub max_instructions_per_query: u64,
+    /// If set, the messages which are not queries are executed in slices of this many
+    /// instructions, with the messages of the other canisters executed in between.
+    pub max_instructions_per_slice: Option<u64>,
+    /// The types of the messages which are in the low priority lane of the canisters.
+    pub low_priority: Vec<EntryMode>,
+    /// The update methods whose calls are in the low priority lane of the canisters.
+    pub low_priority_methods: Vec<String>,
+    /// When the messages of the low priority lane are delivered.
+    pub lane_policy: LanePolicy,
+}
+
+impl SubnetConfig {
+    /// The costs and the limits of an application subnet.
+    pub fn application() -> Self {
+        Self {
+            subnet_type: SubnetType::Application,
+            call_fee: APPLICATION_CALL_FEE,
+            max_reply_size: MAX_REPLY_SIZE,
+            max_query_reply_size: MAX_QUERY_REPLY_SIZE,
+            max_inter_canister_reply_size: MAX_REPLY_SIZE,
+            system_call_instructions: DEFAULT_SYSTEM_CALL_INSTRUCTIONS,
+            max_instructions_per_message: MAX_INSTRUCTIONS_PER_MESSAGE,
+            max_instructions_per_query: MAX_INSTRUCTIONS_PER_QUERY,
+            max_instructions_per_slice: None,
+            low_priority: Vec::new(),
+            low_priority_methods: Vec::new(),
+            lane_policy: LanePolicy::Fifo,
+        }
+    }
+
+    /// The costs and the limits of a system subnet, where the calls are free.
+    pub fn system() -> Self {
+        Self {
+            subnet_type: SubnetType::System,
+            call_fee: 0,
+            ..Self::application()
+        }
+    }
+
+    /// Execute the long messages in slices of the given number of instructions.
+    pub fn with_slices(mut self, instructions: u64) -> Self {
+        assert!(
+            instructions > 0,
+            "A slice must have at least one instruction."
+        );
+        self.max_instructions_per_slice = Some(instructions);
+        self
+    }
+
+    /// Limit the replies to the update calls and to the calls made by the other canisters to the
+    /// given number of bytes, such as to check that a canister still works once a lower limit is
+    /// reached.
+    pub fn with_max_reply_size(mut self, bytes: usize) -> Self {
+        self.max_reply_size = bytes;
+        self.max_inter_canister_reply_size = bytes;
+        self
+    }
+
+    /// Limit the replies to the query calls sent by the users to the given number of bytes.
+    pub fn with_max_query_reply_size(mut self, bytes: usize) -> Self {
+        self.max_query_reply_size = bytes;
+        self
+    }
+
+    /// Move the messages of the given type, such as the heartbeats, to the low priority lane.
+    pub fn with_low_priority(mut self, entry_mode: EntryMode) -> Self {
+        self.low_priority.push(entry_mode);
+        self
+    }
+
+    /// Move the calls to the given method, such as a method called by a job, to the low priority
+    /// lane.
+    pub fn with_low_priority_method<S: Into<String>>(mut self, method: S) -> Self {
+        self.low_priority_methods.push(method.into());
+        self
+    }
+
+    /// Deliver the messages of the low priority lane with the given policy, instead of in the
+    /// order they are received.
+    ///
+    /// # Panics
+    ///
+    /// If the policy is [`LanePolicy::Weighted`] with a weight of zero.
+    pub fn with_lane_policy(mut self, policy: LanePolicy) -> Self {
+        assert!(
+            policy != LanePolicy::Weighted(0),
+            "The weight of the lanes must be at least one."
+        );
+        self.lane_policy = policy;
+        self
+    }
+
+    /// Return true if the message is in the low priority lane.
+    pub(crate) fn is_low_priority(&self, message: &Message) -> bool {
+        let env = match message {
+            Message::CustomTask { .. } => return false,
+            Message::Request { env, .. } | Message::Reply { env, .. } => env,
+        };
+
+        self.low_priority.contains(&env.entry_mode)
+            || (env.entry_mode == EntryMode::Update
+                && env
+                    .method_name
+                    .as_ref()
+                    .map_or(false, |method| self.low_priority_methods.contains(method)))
+    }
+}
+
+impl Default for SubnetConfig {
+    fn default() -> Self {
+        Self::application()
+    }
+}
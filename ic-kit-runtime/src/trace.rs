@@ -0,0 +1,524 @@
+//! An opt-in log of the calls made during a test, printed as an indented tree where the calls made
+//! by a canister are nested under the call it was processing, and the principals are shown by the
+//! names given to them with [`Replica::name`].
+//!
+//! ```text
+//! alice -> ledger.transfer (68 bytes)
+//!   ledger -> archive.append (112 bytes)
+//!   ledger <- archive.append: reply of 9 bytes in 84µs
+//! alice <- ledger.transfer: reply of 17 bytes in 312µs
+//! ```
+//!
+//! The calls are tracked even if the log is not enabled, so the calls which were never replied to
+//! can be reported when the replica is shut down, see [`Replica::shutdown`], and the sizes of the
+//! arguments and the replies of the calls can be checked against the message limits, see
+//! [`Replica::payload_sizes`].
+//!
+//! The request ids are assigned in order by each replica, from a seed set with
+//! [`Replica::set_request_id_seed`], and the unresolved calls are reported in the order of their
+//! ids. With [`Replica::log_call_timings`] disabled the log does not show how long the calls took,
+//! so the log of a test is the same on every run and on every machine, and can be compared with a
+//! golden file.
+//!
+//! With [`Replica::log_call_values`] enabled the log shows the arguments and the replies of the
+//! calls as candid text instead of their sizes, see [`crate::pretty`].
+//!
+//! [`Replica::name`]: crate::Replica::name
+//! [`Replica::shutdown`]: crate::Replica::shutdown
+//! [`Replica::payload_sizes`]: crate::Replica::payload_sizes
+//! [`Replica::set_request_id_seed`]: crate::Replica::set_request_id_seed
+//! [`Replica::log_call_timings`]: crate::Replica::log_call_timings
+//! [`Replica::log_call_values`]: crate::Replica::log_call_values
+
+use std::collections::HashMap;
+use std::time::Instant;
+
+use candid::Principal;
+
+use crate::call::CallReply;
+use crate::cycles::{CyclesLedger, CyclesReport};
+use crate::otel::{self, OtelSpan, SpanIds, SpanRecorder};
+use crate::pretty::{pretty, Interface};
+use crate::types::{CanisterCall, RequestId};
+use crate::users;
+
+/// Keeps the names of the principals and the calls which are not resolved yet, and prints the calls
+/// if the log is enabled.
+pub(crate) struct CallTracer {
+    /// If set, the calls are printed.
+    enabled: bool,
+    /// If set, the log shows how long each call took.
+    timings: bool,
+    /// If set, the log shows the arguments and the replies of the calls.
+    values: bool,
+    /// The candid interface of each canister which provided one, to decode its calls.
+    interfaces: HashMap<Principal, Interface>,
+    /// The readable name of each principal.
+    names: HashMap<Principal, String>,
+    /// The depth of each of the calls in the tree.
+    depths: HashMap<RequestId, usize>,
+    /// The calls which are not replied to yet.
+    pending: HashMap<RequestId, PendingCall>,
+    /// The calls whose reply channel was dropped without a reply.
+    dropped: Vec<PendingCall>,
+    /// The cycles sent with the calls and the fees charged for them.
+    cycles: CyclesLedger,
+    /// The sizes of the payloads of the calls which were replied to, in order.
+    payloads: Vec<PayloadSize>,
+    /// The spans of the calls, if they are recorded.
+    spans: Option<SpanRecorder>,
+}
+
+struct PendingCall {
+    id: RequestId,
+    sender: Principal,
+    callee: Principal,
+    method: String,
+    /// The cycles sent with the call.
+    payment: u128,
+    /// The size of the argument in bytes.
+    arg_size: usize,
+    /// The method the sender was executing when it made the call, if the sender is a canister.
+    origin: Option<String>,
+    depth: usize,
+    start: Instant,
+    /// The ids of the span of the call, if the spans are recorded.
+    span: Option<SpanIds>,
+}
+
+/// A call which was not resolved when the replica was shut down, see [`Replica::shutdown`].
+///
+/// [`Replica::shutdown`]: crate::Replica::shutdown
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnresolvedCall {
+    /// The id of the request, assigned in order by the replica.
+    pub request_id: RequestId,
+    /// The principal which made the call.
+    pub sender: Principal,
+    /// The canister which was called.
+    pub callee: Principal,
+    /// The name of the method which was called.
+    pub method: String,
+    /// The canister and method that made the call, in the `canister.method` form, if the call was
+    /// made by a canister.
+    pub origin: Option<String>,
+    /// If set, the reply channel of the call was dropped without a reply, otherwise the call is
+    /// still waiting for its reply.
+    pub dropped: bool,
+}
+
+/// The sizes of the argument and of the reply of a call, see [`Replica::payload_sizes`].
+///
+/// [`Replica::payload_sizes`]: crate::Replica::payload_sizes
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PayloadSize {
+    /// The principal which made the call.
+    pub sender: Principal,
+    /// The canister which was called.
+    pub callee: Principal,
+    /// The name of the method which was called.
+    pub method: String,
+    /// The canister and method that made the call, in the `canister.method` form, if the call was
+    /// made by a canister.
+    pub origin: Option<String>,
+    /// The size of the encoded argument in bytes.
+    pub arg_size: usize,
+    /// The size of the reply in bytes, or of the rejection message if the call was rejected, see
+    /// [`CallReply::reply_size`].
+    pub reply_size: usize,
+}
+
+impl Default for CallTracer {
+    fn default() -> Self {
+        let names = [
+            (Principal::anonymous(), "anonymous"),
+            (Principal::management_canister(), "management"),
+            (*users::ALICE, "alice"),
+            (*users::BOB, "bob"),
+            (*users::JOHN, "john"),
+            (*users::PARSA, "parsa"),
+            (*users::OZ, "oz"),
+        ];
+
+        Self {
+            enabled: false,
+            timings: true,
+            values: false,
+            interfaces: HashMap::new(),
+            names: names
+                .into_iter()
+                .map(|(id, name)| (id, name.to_string()))
+                .collect(),
+            depths: HashMap::new(),
+            pending: HashMap::new(),
+            dropped: Vec::new(),
+            cycles: CyclesLedger::default(),
+            payloads: Vec::new(),
+            spans: None,
+        }
+    }
+}
+
+impl CallTracer {
+    /// Print the calls from now on, or stop printing them.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// Record the spans of the calls from now on, or stop recording them and drop the recorded
+    /// spans.
+    pub fn set_spans(&mut self, enabled: bool) {
+        match (enabled, &self.spans) {
+            (true, None) => self.spans = Some(SpanRecorder::new()),
+            (false, _) => self.spans = None,
+            (true, Some(_)) => {}
+        }
+    }
+
+    /// Return the spans of the calls resolved since they are recorded.
+    pub fn spans(&self) -> Vec<OtelSpan> {
+        match &self.spans {
+            Some(spans) => spans.spans().to_vec(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Use the given name for the principal.
+    pub fn set_name(&mut self, id: Principal, name: String) {
+        self.names.insert(id, name);
+    }
+
+    /// Show how long each call took in the log, or stop showing it.
+    pub fn set_timings(&mut self, timings: bool) {
+        self.timings = timings;
+    }
+
+    /// Show the arguments and the replies of the calls in the log instead of their sizes, or stop
+    /// showing them.
+    pub fn set_values(&mut self, values: bool) {
+        self.values = values;
+    }
+
+    /// Use the given candid interface to decode the calls to the canister.
+    pub fn set_interface(&mut self, id: Principal, interface: Interface) {
+        self.interfaces.insert(id, interface);
+    }
+
+    /// Return true if the calls are printed.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Return true if some of the calls are not replied to yet.
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Return true if the canister made calls which are not replied to yet, or was called and did
+    /// not reply yet.
+    pub fn has_open_calls(&self, id: Principal) -> bool {
+        self.pending
+            .values()
+            .any(|call| call.sender == id || call.callee == id)
+    }
+
+    /// Return the ids of the calls made by the canister which are not replied to yet.
+    pub fn pending_from(&self, id: Principal) -> Vec<RequestId> {
+        self.pending
+            .values()
+            .filter(|call| call.sender == id)
+            .map(|call| call.id)
+            .collect()
+    }
+
+    /// Return the name given to the principal, if it has one.
+    pub fn given_name(&self, id: &Principal) -> Option<String> {
+        self.names.get(id).cloned()
+    }
+
+    /// Return the name of the principal, or its textual form if it does not have one.
+    pub fn name(&self, id: &Principal) -> String {
+        self.names.get(id).cloned().unwrap_or_else(|| id.to_text())
+    }
+
+    /// Record a call, which is nested under the call that the sender was processing if any.
+    pub fn request(&mut self, call: &CanisterCall) {
+        let depth = call
+            .parent
+            .and_then(|parent| self.depths.get(&parent))
+            .map(|depth| depth + 1)
+            .unwrap_or(0);
+        let origin = call
+            .parent
+            .and_then(|parent| self.pending.get(&parent))
+            .map(|parent| format!("{}.{}", self.name(&parent.callee), parent.method));
+        let parent_span = call
+            .parent
+            .and_then(|parent| self.pending.get(&parent))
+            .and_then(|parent| parent.span);
+        let span = self
+            .spans
+            .as_mut()
+            .map(|spans| spans.start(parent_span.as_ref()));
+
+        if self.enabled {
+            let arg = match self.values {
+                true => self.args(&call.callee, &call.method, &call.arg),
+                false => format!("({} bytes)", call.arg.len()),
+            };
+
+            println!(
+                "{}{} -> {}.{} {}",
+                "  ".repeat(depth),
+                self.name(&call.sender),
+                self.name(&call.callee),
+                call.method,
+                arg
+            );
+        }
+
+        self.cycles.call(call.sender, call.callee, call.payment);
+        self.depths.insert(call.request_id, depth);
+        self.pending.insert(
+            call.request_id,
+            PendingCall {
+                id: call.request_id,
+                sender: call.sender,
+                callee: call.callee,
+                method: call.method.clone(),
+                payment: call.payment,
+                arg_size: call.arg.len(),
+                origin,
+                depth,
+                start: Instant::now(),
+                span,
+            },
+        );
+    }
+
+    /// Record the reply to a call.
+    pub fn reply(&mut self, id: RequestId, reply: &CallReply) {
+        let call = match self.pending.remove(&id) {
+            Some(call) => call,
+            None => return,
+        };
+
+        self.cycles.resolve(
+            call.sender,
+            call.callee,
+            call.payment,
+            reply.cycles_refunded(),
+        );
+
+        self.payloads.push(PayloadSize {
+            sender: call.sender,
+            callee: call.callee,
+            method: call.method.clone(),
+            origin: call.origin.clone(),
+            arg_size: call.arg_size,
+            reply_size: reply.reply_size(),
+        });
+
+        let error = match reply {
+            CallReply::Reply { .. } => None,
+            CallReply::Reject {
+                rejection_code,
+                rejection_message,
+                ..
+            } => Some(format!("{:?}: {}", rejection_code, rejection_message)),
+        };
+        self.finish_span(&call, reply.reply_size(), error);
+
+        if !self.enabled {
+            return;
+        }
+
+        let label = self.label(&call);
+        let elapsed = match self.timings {
+            true => format!(" in {:?}", call.start.elapsed()),
+            false => String::new(),
+        };
+
+        match reply {
+            CallReply::Reply { data, .. } if self.values => println!(
+                "{}: {}{}",
+                label,
+                self.reply_text(&call.callee, &call.method, data),
+                elapsed
+            ),
+            CallReply::Reply { data, .. } => {
+                println!("{}: reply of {} bytes{}", label, data.len(), elapsed)
+            }
+            CallReply::Reject {
+                rejection_code,
+                rejection_message,
+                ..
+            } => println!(
+                "{}: {:?}{}: {}",
+                label, rejection_code, elapsed, rejection_message
+            ),
+        }
+    }
+
+    /// Warn that the hook of the canister made a call, which traps on the Internet Computer.
+    pub fn hook_call(&self, canister: &Principal, callee: &Principal, method: &str, hook: &str) {
+        if self.enabled {
+            println!(
+                "warning: {} calls {}.{} from '{}', which traps on the Internet Computer",
+                self.name(canister),
+                self.name(callee),
+                method,
+                hook
+            );
+        }
+    }
+
+    /// Record that the reply channel of a call was dropped without a reply.
+    pub fn dropped(&mut self, id: RequestId) {
+        let call = match self.pending.remove(&id) {
+            Some(call) => call,
+            None => return,
+        };
+
+        // The caller gets a reject which refunds the cycles it sent.
+        self.cycles
+            .resolve(call.sender, call.callee, call.payment, call.payment);
+        self.finish_span(&call, 0, Some("Dropped without a reply".to_string()));
+
+        if self.enabled {
+            println!("{}: dropped without a reply", self.label(&call));
+        }
+
+        self.dropped.push(call);
+    }
+
+    /// Record the balance of a canister added to the replica, see [`CyclesLedger::add_canister`].
+    pub fn add_canister(&mut self, id: Principal, balance: u128) {
+        self.cycles.add_canister(id, balance);
+    }
+
+    /// Record a change of the balance of a canister made by the test.
+    pub fn adjust_balance(&mut self, id: Principal, delta: i128) {
+        self.cycles.adjust(id, delta);
+    }
+
+    /// Record the fee charged to a canister for a call.
+    pub fn burn(&mut self, id: Principal, fee: u128) {
+        self.cycles.burn(id, fee);
+    }
+
+    /// Return the flows of the cycles, with the given balances of the canisters.
+    pub fn cycles_report(&self, balances: HashMap<Principal, u128>) -> CyclesReport {
+        self.cycles.report(balances, self.names.clone())
+    }
+
+    /// Return the calls which were dropped without a reply, followed by the calls which are still
+    /// waiting for their reply in the order of their request ids.
+    pub fn unresolved(&self) -> Vec<UnresolvedCall> {
+        let mut pending = self.pending.values().collect::<Vec<_>>();
+        pending.sort_by_key(|call| call.id);
+
+        self.dropped
+            .iter()
+            .map(|call| (call, true))
+            .chain(pending.into_iter().map(|call| (call, false)))
+            .map(|(call, dropped)| UnresolvedCall {
+                request_id: call.id,
+                sender: call.sender,
+                callee: call.callee,
+                method: call.method.clone(),
+                origin: call.origin.clone(),
+                dropped,
+            })
+            .collect()
+    }
+
+    /// Return the sizes of the payloads of the calls replied to so far, starting from the given
+    /// index.
+    pub fn payload_sizes(&self, from: usize) -> Vec<PayloadSize> {
+        self.payloads.get(from..).unwrap_or_default().to_vec()
+    }
+
+    /// Return the number of calls replied to so far.
+    pub fn payload_count(&self) -> usize {
+        self.payloads.len()
+    }
+
+    /// Describe the unresolved call using the names of the principals.
+    pub fn describe(&self, call: &UnresolvedCall) -> String {
+        let origin = call
+            .origin
+            .as_ref()
+            .map(|origin| format!(" (made by {})", origin))
+            .unwrap_or_default();
+        let state = if call.dropped {
+            "dropped without a reply"
+        } else {
+            "never replied to"
+        };
+
+        format!(
+            "{} -> {}.{}{}: {}",
+            self.name(&call.sender),
+            self.name(&call.callee),
+            call.method,
+            origin,
+            state
+        )
+    }
+
+    /// Render the arguments of a call to the canister, with its interface if it's known.
+    fn args(&self, callee: &Principal, method: &str, bytes: &[u8]) -> String {
+        match self.interfaces.get(callee) {
+            Some(interface) => interface.args(method, bytes),
+            None => pretty(bytes),
+        }
+    }
+
+    /// Render the reply of the canister, with its interface if it's known.
+    fn reply_text(&self, callee: &Principal, method: &str, bytes: &[u8]) -> String {
+        match self.interfaces.get(callee) {
+            Some(interface) => interface.reply(method, bytes),
+            None => pretty(bytes),
+        }
+    }
+
+    /// Record the span of the resolved call, if the spans are recorded.
+    fn finish_span(&mut self, call: &PendingCall, reply_size: usize, error: Option<String>) {
+        let ids = match call.span {
+            Some(ids) => ids,
+            None => return,
+        };
+
+        let service = self.name(&call.callee);
+
+        if let Some(spans) = &mut self.spans {
+            spans.finish(OtelSpan {
+                trace_id: ids.trace_id,
+                span_id: ids.span_id,
+                parent_span_id: ids.parent_span_id,
+                service,
+                sender: call.sender,
+                callee: call.callee,
+                method: call.method.clone(),
+                payment: call.payment,
+                arg_size: call.arg_size,
+                reply_size,
+                start_time: ids.start_time,
+                end_time: otel::now(),
+                error,
+            });
+        }
+    }
+
+    /// The label of the reply to the call in the log.
+    fn label(&self, call: &PendingCall) -> String {
+        format!(
+            "{}{} <- {}.{}",
+            "  ".repeat(call.depth),
+            self.name(&call.sender),
+            self.name(&call.callee),
+            call.method
+        )
+    }
+}
@@ -0,0 +1,143 @@
+//! A harness to test the upgrade of a canister from one build to another: the first build is
+//! installed and a scenario runs on it, then the canister is upgraded to the second build, and the
+//! invariants are checked both before and after the upgrade.
+//!
+//! ```ignore
+//! #[kit_test]
+//! async fn upgrade_keeps_balances(replica: Replica) {
+//!     UpgradeTest::new(LedgerV1::anonymous(), LedgerV2::anonymous())
+//!         .with_init_args((owner, 1_000u64))
+//!         .scenario(|ledger| {
+//!             Box::pin(async move {
+//!                 ledger.new_call("transfer").with_arg(args).perform().await.assert_ok();
+//!             })
+//!         })
+//!         .invariant(|ledger| {
+//!             Box::pin(async move {
+//!                 let supply = ledger.new_call("total_supply").perform().await;
+//!                 assert_eq!(supply.decode_one::<u64>().unwrap(), 1_000);
+//!             })
+//!         })
+//!         .run(&replica)
+//!         .await;
+//! }
+//! ```
+
+use std::future::Future;
+use std::pin::Pin;
+
+use candid::encode_args;
+use candid::utils::ArgumentEncoder;
+use ic_kit_sys::types::CANDID_EMPTY_ARG;
+
+use crate::canister::Canister;
+use crate::handle::CanisterHandle;
+use crate::types::Env;
+use crate::Replica;
+
+/// A step of an [`UpgradeTest`], which is run with the handle of the canister.
+pub type Step =
+    Box<dyn for<'a> Fn(&'a CanisterHandle<'a>) -> Pin<Box<dyn Future<Output = ()> + 'a>>>;
+
+/// Tests the upgrade of a canister from one build to another.
+pub struct UpgradeTest {
+    from: Canister,
+    to: Canister,
+    init_arg: Vec<u8>,
+    upgrade_arg: Vec<u8>,
+    scenario: Vec<Step>,
+    invariants: Vec<Step>,
+}
+
+impl UpgradeTest {
+    /// Create a test of the upgrade from the first build of the canister to the second one, the
+    /// two builds must have the same id.
+    pub fn new(from: Canister, to: Canister) -> Self {
+        assert_eq!(
+            from.id(),
+            to.id(),
+            "ic-kit-runtime: The two builds of the canister must have the same id."
+        );
+
+        Self {
+            from,
+            to,
+            init_arg: CANDID_EMPTY_ARG.to_vec(),
+            upgrade_arg: CANDID_EMPTY_ARG.to_vec(),
+            scenario: Vec::new(),
+            invariants: Vec::new(),
+        }
+    }
+
+    /// Use the given arguments for the init hook of the first build.
+    pub fn with_init_args<T: ArgumentEncoder>(mut self, arguments: T) -> Self {
+        self.init_arg = encode_args(arguments).expect("Failed to encode arguments.");
+        self
+    }
+
+    /// Use the given arguments for the post_upgrade hook of the second build.
+    pub fn with_upgrade_args<T: ArgumentEncoder>(mut self, arguments: T) -> Self {
+        self.upgrade_arg = encode_args(arguments).expect("Failed to encode arguments.");
+        self
+    }
+
+    /// Add a step to the scenario which runs on the first build, the steps run in the order
+    /// they are added.
+    pub fn scenario<F>(mut self, step: F) -> Self
+    where
+        F: for<'a> Fn(&'a CanisterHandle<'a>) -> Pin<Box<dyn Future<Output = ()> + 'a>> + 'static,
+    {
+        self.scenario.push(Box::new(step));
+        self
+    }
+
+    /// Add an invariant, which is checked after the scenario and again after the upgrade.
+    pub fn invariant<F>(mut self, check: F) -> Self
+    where
+        F: for<'a> Fn(&'a CanisterHandle<'a>) -> Pin<Box<dyn Future<Output = ()> + 'a>> + 'static,
+    {
+        self.invariants.push(Box::new(check));
+        self
+    }
+
+    /// Install the first build on the replica, run the scenario and the invariants, upgrade the
+    /// canister to the second build and run the invariants again.
+    ///
+    /// # Panics
+    ///
+    /// If any of the hooks fail, or if any of the steps panic.
+    pub async fn run(self, replica: &Replica) {
+        let canister_id = self.from.id();
+        let handle = replica.add_canister(self.from);
+
+        let reply = handle
+            .run_env(Env::init().with_raw_args(self.init_arg))
+            .await;
+        if let Some(e) = reply.rejection_message() {
+            panic!(
+                "ic-kit-runtime: The init hook of canister '{}' failed: {}",
+                canister_id, e
+            );
+        }
+
+        for step in &self.scenario {
+            step(&handle).await;
+        }
+
+        for check in &self.invariants {
+            check(&handle).await;
+        }
+
+        let reply = handle.upgrade_raw(self.to, self.upgrade_arg).await;
+        if let Some(e) = reply.rejection_message() {
+            panic!(
+                "ic-kit-runtime: The upgrade of canister '{}' failed: {}",
+                canister_id, e
+            );
+        }
+
+        for check in &self.invariants {
+            check(&handle).await;
+        }
+    }
+}
@@ -0,0 +1,251 @@
+//! A mock of the exchange rate canister, whose rates are set by the tests so the canisters which
+//! depend on a price can be tested deterministically, see [`Replica::xrc`].
+//!
+//! The mock replies to `get_exchange_rate` with the rate set for the pair of assets, or with the
+//! inverse of the rate set for the reversed pair, and with the same errors as the exchange rate
+//! canister when the pair is unknown or the call does not attach enough cycles.
+//!
+//! ```ignore
+//! replica.xrc().set_rate("ICP/USDT", 12.34);
+//! ```
+//!
+//! [`Replica::xrc`]: crate::Replica::xrc
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use candid::{encode_one, CandidType, Deserialize, Principal};
+
+use ic_kit_sys::ic0;
+
+use crate::canister::Canister;
+use crate::mock::{MockCall, MockCanister, MockRecorder};
+
+/// The id of the exchange rate canister on the mainnet, `uf6dk-hyaaa-aaaaq-qaaaq-cai`.
+pub const XRC_CANISTER_ID: Principal = Principal::from_slice(&[0, 0, 0, 0, 2, 16, 0, 1, 1, 1]);
+
+/// The cycles the exchange rate canister charges for a call.
+pub const XRC_REQUEST_CYCLES_COST: u128 = 1_000_000_000;
+
+/// The number of decimals of the rates returned by the mock.
+const DECIMALS: u32 = 9;
+
+/// The candid interface of the exchange rate canister.
+const XRC_CANDID: &str = r#"
+type AssetClass = variant { Cryptocurrency; FiatCurrency };
+type Asset = record { symbol : text; class : AssetClass };
+type GetExchangeRateRequest = record {
+    base_asset : Asset;
+    quote_asset : Asset;
+    timestamp : opt nat64;
+};
+type ExchangeRateMetadata = record {
+    decimals : nat32;
+    base_asset_num_received_rates : nat64;
+    base_asset_num_queried_sources : nat64;
+    quote_asset_num_received_rates : nat64;
+    quote_asset_num_queried_sources : nat64;
+    standard_deviation : nat64;
+    forex_timestamp : opt nat64;
+};
+type ExchangeRate = record {
+    base_asset : Asset;
+    quote_asset : Asset;
+    timestamp : nat64;
+    rate : nat64;
+    metadata : ExchangeRateMetadata;
+};
+type OtherError = record { code : nat32; description : text };
+type ExchangeRateError = variant {
+    AnonymousPrincipalNotAllowed;
+    Pending;
+    CryptoBaseAssetNotFound;
+    CryptoQuoteAssetNotFound;
+    StablecoinRateNotFound;
+    StablecoinRateTooFewRates;
+    StablecoinRateZeroRate;
+    ForexInvalidTimestamp;
+    ForexBaseAssetNotFound;
+    ForexQuoteAssetNotFound;
+    ForexAssetsNotFound;
+    RateLimited;
+    NotEnoughCycles;
+    FailedToAcceptCycles;
+    InconsistentRatesReceived;
+    Other : OtherError;
+};
+type GetExchangeRateResult = variant { Ok : ExchangeRate; Err : ExchangeRateError };
+service : {
+    get_exchange_rate : (GetExchangeRateRequest) -> (GetExchangeRateResult);
+}
+"#;
+
+/// A handle to the mock of the exchange rate canister of a replica.
+#[derive(Clone)]
+pub struct XrcMock {
+    /// Map the symbols of the base and the quote assets to the rate.
+    rates: Arc<Mutex<HashMap<(String, String), f64>>>,
+    recorder: MockRecorder,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+enum AssetClass {
+    Cryptocurrency,
+    FiatCurrency,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+struct Asset {
+    symbol: String,
+    class: AssetClass,
+}
+
+#[derive(CandidType, Deserialize)]
+struct GetExchangeRateRequest {
+    base_asset: Asset,
+    quote_asset: Asset,
+    timestamp: Option<u64>,
+}
+
+#[derive(CandidType)]
+struct ExchangeRateMetadata {
+    decimals: u32,
+    base_asset_num_received_rates: u64,
+    base_asset_num_queried_sources: u64,
+    quote_asset_num_received_rates: u64,
+    quote_asset_num_queried_sources: u64,
+    standard_deviation: u64,
+    forex_timestamp: Option<u64>,
+}
+
+#[derive(CandidType)]
+struct ExchangeRate {
+    base_asset: Asset,
+    quote_asset: Asset,
+    timestamp: u64,
+    rate: u64,
+    metadata: ExchangeRateMetadata,
+}
+
+/// The errors returned by the mock, which are a subset of the ones of the exchange rate canister.
+#[derive(CandidType)]
+enum ExchangeRateError {
+    CryptoBaseAssetNotFound,
+    ForexBaseAssetNotFound,
+    NotEnoughCycles,
+}
+
+impl XrcMock {
+    /// Create the mock and the canister which runs it.
+    pub(crate) fn new() -> (Self, Canister) {
+        let rates = Arc::new(Mutex::new(HashMap::new()));
+        let handler_rates = rates.clone();
+
+        let mock = MockCanister::new(XRC_CANISTER_ID, XRC_CANDID)
+            .with_handler("get_exchange_rate", move |call| {
+                get_exchange_rate(&handler_rates.lock().unwrap(), call)
+            });
+        let recorder = mock.recorder();
+
+        (Self { rates, recorder }, mock.into())
+    }
+
+    /// Set the rate of the pair of assets, such as `ICP/USDT`, which is the price of one unit of the
+    /// base asset in the quote asset.
+    ///
+    /// # Panics
+    ///
+    /// If the pair is not made of two symbols separated by a `/`.
+    pub fn set_rate(&self, pair: &str, rate: f64) {
+        self.rates.lock().unwrap().insert(parse_pair(pair), rate);
+    }
+
+    /// Remove the rate of the pair of assets, so it's not found anymore.
+    pub fn remove_rate(&self, pair: &str) {
+        self.rates.lock().unwrap().remove(&parse_pair(pair));
+    }
+
+    /// Return the calls made to the exchange rate canister, in order.
+    pub fn calls(&self) -> Vec<MockCall> {
+        self.recorder.calls()
+    }
+}
+
+fn parse_pair(pair: &str) -> (String, String) {
+    match pair.split_once('/') {
+        Some((base, quote)) if !base.is_empty() && !quote.is_empty() => {
+            (base.to_string(), quote.to_string())
+        }
+        _ => panic!(
+            "ic-kit-runtime: The pair '{}' is not in the 'BASE/QUOTE' format.",
+            pair
+        ),
+    }
+}
+
+/// Reply to a call to `get_exchange_rate`, this runs on the thread of the canister.
+fn get_exchange_rate(
+    rates: &HashMap<(String, String), f64>,
+    call: &MockCall,
+) -> Result<Vec<u8>, String> {
+    let request = call
+        .decode_one::<GetExchangeRateRequest>()
+        .map_err(|e| e.to_string())?;
+
+    if call.cycles < XRC_REQUEST_CYCLES_COST {
+        return reply(Err(ExchangeRateError::NotEnoughCycles));
+    }
+
+    unsafe { ic0::msg_cycles_accept(XRC_REQUEST_CYCLES_COST as i64) };
+
+    let base = request.base_asset.symbol.as_str();
+    let quote = request.quote_asset.symbol.as_str();
+
+    let rate = if base == quote {
+        Some(1.0)
+    } else {
+        rates
+            .get(&(base.to_string(), quote.to_string()))
+            .copied()
+            .or_else(|| {
+                rates
+                    .get(&(quote.to_string(), base.to_string()))
+                    .map(|rate| 1.0 / rate)
+            })
+    };
+
+    let rate = match rate {
+        Some(rate) => rate,
+        None => {
+            return reply(Err(match request.base_asset.class {
+                AssetClass::Cryptocurrency => ExchangeRateError::CryptoBaseAssetNotFound,
+                AssetClass::FiatCurrency => ExchangeRateError::ForexBaseAssetNotFound,
+            }))
+        }
+    };
+
+    // The rates are given for the start of a minute.
+    let timestamp = request
+        .timestamp
+        .unwrap_or_else(|| unsafe { ic0::time() } as u64 / 1_000_000_000);
+
+    reply(Ok(ExchangeRate {
+        base_asset: request.base_asset,
+        quote_asset: request.quote_asset,
+        timestamp: timestamp - timestamp % 60,
+        rate: (rate * 10f64.powi(DECIMALS as i32)).round() as u64,
+        metadata: ExchangeRateMetadata {
+            decimals: DECIMALS,
+            base_asset_num_received_rates: 1,
+            base_asset_num_queried_sources: 1,
+            quote_asset_num_received_rates: 1,
+            quote_asset_num_queried_sources: 1,
+            standard_deviation: 0,
+            forex_timestamp: None,
+        },
+    }))
+}
+
+fn reply(result: Result<ExchangeRate, ExchangeRateError>) -> Result<Vec<u8>, String> {
+    encode_one(result).map_err(|e| e.to_string())
+}
@@ -224,7 +224,8 @@ macro_rules! ic0_module {
 // Copy-paste the spec section of the API here.
 // https://github.com/dfinity/interface-spec/blob/master/spec/ic0.txt
 // But change any i32 which is an address space to an isize, so we can work with this even when
-// on 64bit non-wasm runtimes.
+// on 64bit non-wasm runtimes. This is also the ABI of the wasm64 canisters, where the addresses
+// and the sizes are i64, except for the 32-bit stable memory API which they can not import.
 //
 // The comment after each function lists from where these functions may be invoked:
 // I: from canister_init or canister_post_upgrade
@@ -237,7 +238,8 @@ macro_rules! ic0_module {
 // s: the (start) module initialization function
 // F: from canister_inspect_message
 // H: from canister_heartbeat
-// * = I G U Q Ry Rt C F H (NB: Not (start))
+// T: from canister_global_timer
+// * = I G U Q Ry Rt C F H T (NB: Not (start))
 ic0_module! {
     ic0.msg_arg_data_size : () -> isize;                                               // I U Q Ry F
     ic0.msg_arg_data_copy : (dst : isize, offset : isize, size : isize) -> ();         // I U Q Ry F
@@ -264,6 +266,7 @@ ic0_module! {
     ic0.canister_cycle_balance : () -> i64;                                            // *
     ic0.canister_cycle_balance128 : (dst : isize) -> ();                               // *
     ic0.canister_status : () -> i32;                                                   // *
+    ic0.is_controller : (src : isize, size : isize) -> i32;                            // * s
 
     ic0.msg_method_name_size : () -> isize;                                            // F
     ic0.msg_method_name_copy : (dst : isize, offset : isize, size : isize) -> ();      // F
@@ -300,6 +303,7 @@ ic0_module! {
     ic0.data_certificate_copy : (dst: isize, offset: isize, size: isize) -> ();        // *
 
     ic0.time : () -> (timestamp : i64);                                                // *
+    ic0.global_timer_set : (timestamp : i64) -> (old_timestamp : i64);                 // I G U Ry Rt C H T
     ic0.performance_counter : (counter_type : i32) -> (counter : i64);                 // * s
 
     ic0.debug_print : (src : isize, size : isize) -> ();                               // * s
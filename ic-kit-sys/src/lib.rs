@@ -4,6 +4,13 @@
 //!
 //! This is a low level crate, and we don't recommend you to use this directly, and encourage you
 //! to look at ic-kit itself.
+//!
+//! The canisters built for the wasm64 target must enable the `wasm64` feature, their system API
+//! takes the same `isize` addresses and sizes, which are 64-bit wide on this target, but they can
+//! not use the 32-bit stable memory API.
+
+#[cfg(all(target_arch = "wasm64", not(feature = "wasm64")))]
+compile_error!("Building a canister for wasm64 requires the `wasm64` feature of ic-kit-sys, or the `experimental-wasm64` feature of ic-kit.");
 
 /// System APIs exposed by the Internet Computer's WASM runtime.
 pub mod ic0;
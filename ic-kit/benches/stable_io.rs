@@ -0,0 +1,106 @@
+//! Compare the number of calls made to the memory, which are the stable memory syscalls in a
+//! canister, and the time it takes to write a state made of many small entries through the
+//! buffered stable writer.
+//!
+//! Run with `cargo bench -p ic-kit --bench stable_io`.
+
+use ic_kit::stable::{BufferedStableWriter, Memory, StableMemoryError, VectorMemory};
+use std::cell::Cell;
+use std::io::{IoSlice, Write};
+use std::time::Instant;
+
+/// A memory on the heap which counts the calls made to it.
+#[derive(Default)]
+struct CountingMemory {
+    memory: VectorMemory,
+    writes: Cell<u64>,
+    grows: Cell<u64>,
+}
+
+impl Memory for CountingMemory {
+    fn size(&self) -> u64 {
+        self.memory.size()
+    }
+
+    fn grow(&self, pages: u64) -> Result<u64, StableMemoryError> {
+        self.grows.set(self.grows.get() + 1);
+        self.memory.grow(pages)
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) {
+        self.memory.read(offset, buf)
+    }
+
+    fn write(&self, offset: u64, buf: &[u8]) {
+        self.writes.set(self.writes.get() + 1);
+        self.memory.write(offset, buf)
+    }
+}
+
+/// A state with many small entries.
+fn state() -> Vec<(String, u64)> {
+    (0..100_000)
+        .map(|i| (format!("account-{}", i), i as u64))
+        .collect()
+}
+
+fn bench<F: FnOnce(&mut BufferedStableWriter<&CountingMemory>)>(name: &str, capacity: usize, f: F) {
+    let memory = CountingMemory::default();
+    let started_at = Instant::now();
+
+    {
+        let mut writer = BufferedStableWriter::with_capacity(&memory, 0, capacity);
+        f(&mut writer);
+    }
+
+    println!(
+        "{:<32} {:>10} writes {:>6} grows {:>10.2?}",
+        name,
+        memory.writes.get(),
+        memory.grows.get(),
+        started_at.elapsed()
+    );
+}
+
+fn main() {
+    let state = state();
+
+    // Length prefixed entries, written one slice at a time or with a single vectored write.
+    let lengths = state
+        .iter()
+        .map(|(key, _)| (key.len() as u32).to_le_bytes())
+        .collect::<Vec<_>>();
+    let values = state
+        .iter()
+        .map(|(_, value)| value.to_le_bytes())
+        .collect::<Vec<_>>();
+
+    for &capacity in &[0, 1 << 10, 1 << 16, 1 << 20] {
+        bench(
+            &format!("slices, buffer of {} bytes", capacity),
+            capacity,
+            |writer| {
+                for (i, (key, _)) in state.iter().enumerate() {
+                    writer.write_all(&lengths[i]).unwrap();
+                    writer.write_all(key.as_bytes()).unwrap();
+                    writer.write_all(&values[i]).unwrap();
+                }
+            },
+        );
+
+        bench(
+            &format!("vectored, buffer of {} bytes", capacity),
+            capacity,
+            |writer| {
+                for (i, (key, _)) in state.iter().enumerate() {
+                    let slices = [
+                        IoSlice::new(&lengths[i]),
+                        IoSlice::new(key.as_bytes()),
+                        IoSlice::new(&values[i]),
+                    ];
+                    writer.write_vectored(&slices).unwrap();
+                }
+            },
+        );
+    }
+}
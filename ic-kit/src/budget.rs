@@ -0,0 +1,289 @@
+//! A budget of work for the long-running loops, such as the maintenance tasks run from the
+//! heartbeat, so they stop before the instruction limit of a message and continue later.
+//!
+//! ```ignore
+//! #[heartbeat]
+//! fn heartbeat() {
+//!     let mut budget = WorkBudget::new(DEFAULT_INSTRUCTIONS);
+//!
+//!     // The expired entries which are not removed in this heartbeat are removed in the next one.
+//!     budget.run_while(|| with_mut(|s: &mut State| s.remove_one_expired()));
+//! }
+//!
+//! #[update]
+//! async fn reindex() {
+//!     let mut budget = WorkBudget::new(DEFAULT_INSTRUCTIONS);
+//!
+//!     while with_mut(|s: &mut State| s.reindex_one()) {
+//!         if !budget.consume() {
+//!             // Continue in a new message, with a new instruction limit.
+//!             budget.renew().await;
+//!         }
+//!     }
+//! }
+//! ```
+//!
+//! A scan of a collection which takes more than a message, such as the recomputation of the
+//! balances after a migration, keeps a [`ScanCursor`] in the state of the canister, and each
+//! message walks a chunk of the collection from it:
+//!
+//! ```ignore
+//! #[update]
+//! async fn recompute_supply() {
+//!     let mut budget = WorkBudget::new(DEFAULT_INSTRUCTIONS);
+//!
+//!     while !with_mut(|s: &mut State| {
+//!         let range = s.cursor.range();
+//!         for (_, balance) in s.cursor.chunk(&mut budget, s.balances.range(range), |(k, _)| *k) {
+//!             s.supply += balance;
+//!         }
+//!         s.cursor.is_done()
+//!     }) {
+//!         budget.renew().await;
+//!     }
+//! }
+//! ```
+
+use crate::ic::yield_now;
+use crate::utils::performance_counter;
+use candid::CandidType;
+use serde::Deserialize;
+use std::ops::Bound;
+
+/// A budget of instructions for a message, a quarter of the instruction limit of the heartbeat
+/// and of the update calls.
+pub const DEFAULT_INSTRUCTIONS: u64 = 5_000_000_000;
+
+/// The instructions and the items a task can use before it should stop.
+///
+/// The instructions are counted with the performance counter, which is reset at the start of each
+/// message. The canisters are executed natively by the ic-kit runtime so only the instructions of
+/// the system API calls are counted in the tests, and the limit on the items can be used to
+/// exercise the continuation.
+#[derive(Clone, Debug)]
+pub struct WorkBudget {
+    instructions: u64,
+    max_items: Option<u64>,
+    start: u64,
+    items: u64,
+    processed: u64,
+}
+
+impl WorkBudget {
+    /// Create a budget of the given number of instructions, counted from now.
+    pub fn new(instructions: u64) -> Self {
+        Self {
+            instructions,
+            max_items: None,
+            start: performance_counter(0),
+            items: 0,
+            processed: 0,
+        }
+    }
+
+    /// Also limit the number of items processed with the budget.
+    pub fn with_max_items(mut self, max_items: u64) -> Self {
+        self.max_items = Some(max_items);
+        self
+    }
+
+    /// Return the number of instructions used since the budget was created or renewed.
+    pub fn used(&self) -> u64 {
+        performance_counter(0).saturating_sub(self.start)
+    }
+
+    /// Return the number of instructions left.
+    pub fn remaining(&self) -> u64 {
+        self.instructions.saturating_sub(self.used())
+    }
+
+    /// Return the total number of items processed with the budget, including the ones before it
+    /// was renewed.
+    pub fn processed(&self) -> u64 {
+        self.processed
+    }
+
+    /// Return true if the task should stop.
+    pub fn is_exhausted(&self) -> bool {
+        self.used() >= self.instructions
+            || self
+                .max_items
+                .map(|max_items| self.items >= max_items)
+                .unwrap_or(false)
+    }
+
+    /// Record an item as processed. Returns false if the task should stop.
+    pub fn consume(&mut self) -> bool {
+        self.items += 1;
+        self.processed += 1;
+        !self.is_exhausted()
+    }
+
+    /// Call the function until it returns false because there is no more work, or until the
+    /// budget is exhausted. Returns true if all of the work is done.
+    pub fn run_while<F: FnMut() -> bool>(&mut self, mut step: F) -> bool {
+        while !self.is_exhausted() {
+            if !step() {
+                return true;
+            }
+
+            self.items += 1;
+            self.processed += 1;
+        }
+
+        false
+    }
+
+    /// Call the function with the items of the iterator until the budget is exhausted, the items
+    /// which are not processed are left in the iterator. Returns true if all of the items are
+    /// processed.
+    pub fn run<I, F>(&mut self, items: &mut I, mut f: F) -> bool
+    where
+        I: Iterator,
+        F: FnMut(I::Item),
+    {
+        self.run_while(|| match items.next() {
+            Some(item) => {
+                f(item);
+                true
+            }
+            None => false,
+        })
+    }
+
+    /// Wait for a new message and reset the budget, the instructions used before are not counted
+    /// against the instruction limit of the new message. The state changes made before are
+    /// committed, and the other calls can be executed in between.
+    pub async fn renew(&mut self) {
+        yield_now().await;
+        self.start = performance_counter(0);
+        self.items = 0;
+    }
+}
+
+/// The position of a scan of a collection which is spread across several messages, the last key
+/// or index processed. The cursor should be kept in the state of the canister, so it's saved with
+/// it if the canister is upgraded before the scan is done.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct ScanCursor<C> {
+    position: Option<C>,
+    scanned: u64,
+    done: bool,
+}
+
+/// The items of an iterator which can be processed with a budget, created by
+/// [`ScanCursor::chunk`]. The iterator ends when the budget is exhausted, and the cursor is moved
+/// to each item it returns.
+pub struct Chunk<'a, I, C, P> {
+    cursor: &'a mut ScanCursor<C>,
+    budget: &'a mut WorkBudget,
+    items: I,
+    position: P,
+}
+
+impl<C> Default for ScanCursor<C> {
+    fn default() -> Self {
+        Self {
+            position: None,
+            scanned: 0,
+            done: false,
+        }
+    }
+}
+
+impl<C> ScanCursor<C> {
+    /// Create a cursor at the start of the collection.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Return the position of the last item processed, `None` if the scan did not start yet.
+    pub fn position(&self) -> Option<&C> {
+        self.position.as_ref()
+    }
+
+    /// Return the number of items processed so far.
+    pub fn scanned(&self) -> u64 {
+        self.scanned
+    }
+
+    /// Return true once all of the items are processed.
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// Move the cursor back to the start of the collection, to scan it again.
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Walk the items with the budget, the iterator must start after the position of the cursor,
+    /// such as with [`ScanCursor::range`], and `position` returns the position of an item.
+    ///
+    /// The cursor is done once the iterator is exhausted, and otherwise it's moved to the last
+    /// item returned when the budget is exhausted, so the next chunk starts after it.
+    pub fn chunk<'a, I, P>(
+        &'a mut self,
+        budget: &'a mut WorkBudget,
+        items: I,
+        position: P,
+    ) -> Chunk<'a, I, C, P>
+    where
+        I: Iterator,
+        P: FnMut(&I::Item) -> C,
+    {
+        Chunk {
+            cursor: self,
+            budget,
+            items,
+            position,
+        }
+    }
+}
+
+impl<C: Clone> ScanCursor<C> {
+    /// Return the range of the keys after the position of the cursor, to start the iterator of
+    /// an ordered map such as the `StableBTreeMap`.
+    pub fn range(&self) -> (Bound<C>, Bound<C>) {
+        match &self.position {
+            Some(position) => (Bound::Excluded(position.clone()), Bound::Unbounded),
+            None => (Bound::Unbounded, Bound::Unbounded),
+        }
+    }
+}
+
+impl ScanCursor<u64> {
+    /// Return the index of the next item, to start the iterator of a sequence such as the
+    /// `StableLog` with `iter_from`.
+    pub fn next_index(&self) -> u64 {
+        self.position.map(|index| index + 1).unwrap_or(0)
+    }
+}
+
+impl<I, C, P> Iterator for Chunk<'_, I, C, P>
+where
+    I: Iterator,
+    P: FnMut(&I::Item) -> C,
+{
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.cursor.done || self.budget.is_exhausted() {
+            return None;
+        }
+
+        let item = match self.items.next() {
+            Some(item) => item,
+            None => {
+                self.cursor.done = true;
+                return None;
+            }
+        };
+
+        self.cursor.position = Some((self.position)(&item));
+        self.cursor.scanned += 1;
+        self.budget.consume();
+
+        Some(item)
+    }
+}
@@ -0,0 +1,421 @@
+//! A cache for the results of the calls to other canisters, such as the exchange rates or the
+//! registry records, whose entries expire after a time to live.
+//!
+//! The concurrent calls fetching the same key are deduplicated: the first one performs the fetch
+//! and the others wait for its result instead of calling the other canister again.
+//!
+//! ```ignore
+//! struct Rates(Cache<String, f64>);
+//!
+//! impl Default for Rates {
+//!     fn default() -> Self {
+//!         // Keep the rates for a minute.
+//!         Self(Cache::new(60_000_000_000))
+//!     }
+//! }
+//!
+//! #[update]
+//! async fn price(symbol: String) -> Result<f64, String> {
+//!     let cache = with(|rates: &Rates| rates.0.clone());
+//!     cache.get_or_fetch(symbol.clone(), || fetch_rate(symbol)).await
+//! }
+//! ```
+
+use crate::ic::{time, yield_now};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::rc::Rc;
+
+/// The number of entries kept in a cache by default.
+pub const DEFAULT_CAPACITY: usize = 1_000;
+
+struct CacheEntry<V> {
+    value: V,
+    expires_at: u64,
+}
+
+struct Inner<K, V> {
+    ttl: u64,
+    capacity: usize,
+    entries: HashMap<K, CacheEntry<V>>,
+    /// The keys being fetched, and the token of the call fetching each of them.
+    fetching: HashMap<K, u64>,
+    next_token: u64,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> Inner<K, V> {
+    fn get(&self, key: &K, now: u64) -> Option<V> {
+        self.entries
+            .get(key)
+            .filter(|entry| entry.expires_at > now)
+            .map(|entry| entry.value.clone())
+    }
+
+    fn insert(&mut self, key: K, value: V, expires_at: u64, now: u64) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.capacity {
+            self.entries.retain(|_, entry| entry.expires_at > now);
+        }
+
+        // Evict the entry which expires first.
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.capacity {
+            let first = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.expires_at)
+                .map(|(key, _)| key.clone());
+
+            if let Some(first) = first {
+                self.entries.remove(&first);
+            }
+        }
+
+        self.entries.insert(key, CacheEntry { value, expires_at });
+    }
+}
+
+/// A cache whose entries expire after a time to live, measured with `ic::time()`.
+///
+/// The cache is a handle to shared state, so it can be cloned out of the canister's state and
+/// used across awaits.
+pub struct Cache<K, V> {
+    inner: Rc<RefCell<Inner<K, V>>>,
+}
+
+impl<K, V> Clone for Cache<K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Rc::clone(&self.inner),
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> Cache<K, V> {
+    /// Create a cache whose entries expire after `ttl` nanoseconds.
+    pub fn new(ttl: u64) -> Self {
+        Self {
+            inner: Rc::new(RefCell::new(Inner {
+                ttl,
+                capacity: DEFAULT_CAPACITY,
+                entries: HashMap::new(),
+                fetching: HashMap::new(),
+                next_token: 0,
+            })),
+        }
+    }
+
+    /// Set the number of entries kept in the cache, the entries which expire first are evicted
+    /// once the cache is full.
+    pub fn with_capacity(self, capacity: usize) -> Self {
+        self.inner.borrow_mut().capacity = capacity;
+        self
+    }
+
+    /// Return the time to live of the entries in nanoseconds.
+    pub fn ttl(&self) -> u64 {
+        self.inner.borrow().ttl
+    }
+
+    /// Return the value of the key if it has not expired.
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.inner.borrow().get(key, time())
+    }
+
+    /// Insert the value with the time to live of the cache.
+    pub fn insert(&self, key: K, value: V) {
+        let ttl = self.ttl();
+        self.insert_with_ttl(key, value, ttl);
+    }
+
+    /// Insert the value with the given time to live in nanoseconds.
+    pub fn insert_with_ttl(&self, key: K, value: V, ttl: u64) {
+        let now = time();
+        self.inner
+            .borrow_mut()
+            .insert(key, value, now.saturating_add(ttl), now);
+    }
+
+    /// Remove the value of the key, the result of a fetch of the key in progress is not cached.
+    /// Returns true if the key had a value which had not expired.
+    pub fn invalidate(&self, key: &K) -> bool {
+        let now = time();
+        let mut inner = self.inner.borrow_mut();
+        inner.fetching.remove(key);
+        inner
+            .entries
+            .remove(key)
+            .map(|entry| entry.expires_at > now)
+            .unwrap_or(false)
+    }
+
+    /// Remove all the values, the results of the fetches in progress are not cached.
+    pub fn clear(&self) {
+        let mut inner = self.inner.borrow_mut();
+        inner.entries.clear();
+        inner.fetching.clear();
+    }
+
+    /// Return the number of entries, including the expired ones which were not pruned yet.
+    pub fn len(&self) -> usize {
+        self.inner.borrow().entries.len()
+    }
+
+    /// Return true if the cache has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Remove the expired entries. Returns the number of entries removed.
+    pub fn prune(&self) -> usize {
+        let now = time();
+        let mut inner = self.inner.borrow_mut();
+        let len = inner.entries.len();
+        inner.entries.retain(|_, entry| entry.expires_at > now);
+        len - inner.entries.len()
+    }
+
+    /// Return the value of the key, or fetch it and cache it if it's missing or expired. The
+    /// errors are not cached.
+    ///
+    /// If the key is already being fetched by another call, this waits for that fetch instead of
+    /// fetching it again, by yielding to the next round until the fetch completes. If that fetch
+    /// fails, one of the waiting calls fetches the key again.
+    pub async fn get_or_fetch<F, Fut, E>(&self, key: K, fetch: F) -> Result<V, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<V, E>>,
+    {
+        let token = loop {
+            let now = time();
+
+            {
+                let mut inner = self.inner.borrow_mut();
+
+                if let Some(value) = inner.get(&key, now) {
+                    return Ok(value);
+                }
+
+                if !inner.fetching.contains_key(&key) {
+                    let token = inner.next_token;
+                    inner.next_token += 1;
+                    inner.fetching.insert(key.clone(), token);
+                    break token;
+                }
+            }
+
+            yield_now().await;
+        };
+
+        let guard = FetchGuard {
+            cache: self,
+            key,
+            token,
+        };
+
+        let result = fetch().await;
+
+        if let Ok(value) = &result {
+            let now = time();
+            let mut inner = self.inner.borrow_mut();
+
+            if inner.fetching.get(&guard.key) == Some(&token) {
+                let expires_at = now.saturating_add(inner.ttl);
+                inner.insert(guard.key.clone(), value.clone(), expires_at, now);
+            }
+        }
+
+        result
+    }
+}
+
+/// Marks a key as being fetched, until the fetch completes or the call traps.
+struct FetchGuard<'a, K: Hash + Eq, V> {
+    cache: &'a Cache<K, V>,
+    key: K,
+    token: u64,
+}
+
+impl<'a, K: Hash + Eq, V> Drop for FetchGuard<'a, K, V> {
+    fn drop(&mut self) {
+        let mut inner = self.cache.inner.borrow_mut();
+
+        if inner.fetching.get(&self.key) == Some(&self.token) {
+            inner.fetching.remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ic::CallBuilder;
+    use crate::rt::context::MockContext;
+    use candid::Principal;
+    use std::cell::Cell;
+    use std::panic::{self, AssertUnwindSafe};
+
+    const START: u64 = 1_000_000_000_000;
+
+    type Outcome = Rc<RefCell<Option<Result<u64, String>>>>;
+
+    fn oracle() -> Principal {
+        Principal::from_slice(&[1])
+    }
+
+    fn canister() -> Principal {
+        Principal::from_slice(&[2])
+    }
+
+    async fn fetch_rate() -> Result<u64, String> {
+        CallBuilder::new(oracle(), "rate")
+            .perform_one::<u64>()
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    /// Fetch the key in a spawned call, whose result is set once its calls are resolved.
+    fn spawn_fetch(cache: &Cache<&'static str, u64>) -> Outcome {
+        let outcome = Outcome::default();
+        let result = outcome.clone();
+        let cache = cache.clone();
+
+        crate::ic::spawn(async move {
+            *result.borrow_mut() = Some(cache.get_or_fetch("ICP", fetch_rate).await);
+        });
+
+        outcome
+    }
+
+    #[test]
+    fn ttl_and_capacity() {
+        let ctx = MockContext::new().with_time(START).inject();
+        let cache = Cache::new(10).with_capacity(2);
+
+        cache.insert("ICP", 1);
+        cache.insert_with_ttl("BTC", 2, 20);
+        assert_eq!(cache.get(&"ICP"), Some(1));
+
+        let ctx = ctx.with_time(START + 10);
+        assert_eq!(cache.get(&"ICP"), None);
+        assert_eq!(cache.get(&"BTC"), Some(2));
+
+        // The expired entries are pruned to make room, and then the one expiring first is evicted.
+        cache.insert_with_ttl("ETH", 3, 15);
+        assert_eq!(cache.len(), 2);
+        cache.insert("SNS", 4);
+        assert_eq!(cache.get(&"BTC"), None);
+        assert_eq!(cache.get(&"SNS"), Some(4));
+
+        let _ctx = ctx.with_time(START + 25);
+        assert_eq!(cache.prune(), 2);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn single_flight() {
+        let ctx = MockContext::new()
+            .with_time(START)
+            .with_reply(oracle(), "rate", (42u64,))
+            .with_id(canister())
+            .with_reject(canister(), "__ic_kit_yield", "Yield.")
+            .inject();
+        let cache = Cache::new(10);
+
+        // The second call waits for the fetch of the first one.
+        let first = spawn_fetch(&cache);
+        let second = spawn_fetch(&cache);
+        ctx.resolve_calls();
+
+        assert_eq!(first.take(), Some(Ok(42)));
+        assert_eq!(second.take(), Some(Ok(42)));
+        assert_eq!(ctx.watcher().calls_to("rate").len(), 1);
+        assert_eq!(ctx.watcher().calls_to("__ic_kit_yield").len(), 1);
+        assert!(cache.inner.borrow().fetching.is_empty());
+
+        // The value is cached until it expires.
+        assert_eq!(ctx.block_on(cache.get_or_fetch("ICP", fetch_rate)), Ok(42));
+        assert_eq!(ctx.watcher().calls_to("rate").len(), 1);
+
+        let ctx = ctx.with_time(START + 10);
+        assert_eq!(ctx.block_on(cache.get_or_fetch("ICP", fetch_rate)), Ok(42));
+        assert_eq!(ctx.watcher().calls_to("rate").len(), 2);
+    }
+
+    #[test]
+    fn failed_fetch() {
+        let failed = Cell::new(false);
+        let ctx = MockContext::new()
+            .with_handler(oracle(), "rate", move |_| {
+                if failed.replace(true) {
+                    Ok(candid::encode_one(42u64).unwrap())
+                } else {
+                    Err("Unavailable.".into())
+                }
+            })
+            .with_id(canister())
+            .with_reject(canister(), "__ic_kit_yield", "Yield.")
+            .inject();
+        let cache = Cache::new(10);
+
+        // The error is not cached, and the waiting call fetches the key again.
+        let first = spawn_fetch(&cache);
+        let second = spawn_fetch(&cache);
+        ctx.resolve_calls();
+
+        assert!(first.take().unwrap().is_err());
+        assert_eq!(second.take(), Some(Ok(42)));
+        assert_eq!(ctx.watcher().calls_to("rate").len(), 2);
+    }
+
+    #[test]
+    fn invalidate_during_fetch() {
+        let ctx = MockContext::new()
+            .with_reply(oracle(), "rate", (42u64,))
+            .inject();
+        let cache = Cache::new(10);
+
+        // The result of the fetch started before the invalidation is not cached, but the one of
+        // the fetch started after it is.
+        let first = spawn_fetch(&cache);
+        assert!(!cache.invalidate(&"ICP"));
+        let second = spawn_fetch(&cache);
+
+        ctx.resolve_calls();
+        assert_eq!(first.take(), Some(Ok(42)));
+        assert_eq!(second.take(), Some(Ok(42)));
+        assert_eq!(ctx.watcher().calls_to("rate").len(), 2);
+        assert_eq!(cache.get(&"ICP"), Some(42));
+
+        let first = spawn_fetch(&cache);
+        assert_eq!(first.take(), Some(Ok(42)));
+        assert!(cache.invalidate(&"ICP"));
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn trapped_fetch() {
+        let ctx = MockContext::new()
+            .with_reply(oracle(), "rate", (42u64,))
+            .inject();
+        let cache = Cache::<&str, u64>::new(10);
+
+        // The key is released when the call fetching it traps, so the next call fetches it
+        // without waiting.
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            ctx.block_on(cache.get_or_fetch::<_, _, String>("ICP", || async {
+                panic!("The fetch trapped.");
+            }))
+        }));
+        assert!(result.is_err());
+        assert!(cache.inner.borrow().fetching.is_empty());
+
+        assert_eq!(ctx.block_on(cache.get_or_fetch("ICP", fetch_rate)), Ok(42));
+        assert_eq!(ctx.watcher().call_count(), 1);
+    }
+}
@@ -13,3 +13,17 @@ pub trait KitCanister {
     /// The candid description of the canister.
     fn candid() -> String;
 }
+
+/// A group of methods defined in a module, which can be included in a canister using the
+/// `#[mixins(...)]` attribute. This is implemented by the `export_mixin!` macro.
+pub trait KitMixin {
+    /// Add the methods of this mixin to the given canister.
+    #[cfg(not(target_family = "wasm"))]
+    fn register(canister: ic_kit_runtime::Canister) -> ic_kit_runtime::Canister;
+
+    /// Add the candid description of the methods of this mixin to the service.
+    fn candid_service(
+        env: &mut candid::types::internal::TypeContainer,
+        service: &mut Vec<(String, candid::types::Type)>,
+    );
+}
@@ -0,0 +1,184 @@
+use std::any::type_name;
+
+use candid::parser::value::{IDLArgs, IDLField, IDLValue};
+use candid::types::Label;
+use candid::{encode_one, CandidType, TypeEnv};
+use serde::Deserialize;
+use serde_json::{Map, Number, Value};
+
+use crate::ic::maybe_with;
+
+/// The format of a state dumped by the `__debug_dump` query exported by `export_debug!`.
+#[derive(CandidType, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DebugFormat {
+    /// The candid text format, such as `record { owner = principal "aaaaa-aa" }`.
+    Candid,
+    /// A JSON document, where the big numbers and the principals are strings.
+    Json,
+}
+
+/// Dump the state of the given type, or the part of it at the given path, in the given format.
+/// This is called by the `__debug_dump` query exported by `export_debug!`.
+///
+/// The path is a list of segments separated by `/`, such as `/users/0/name`, each segment is the
+/// name of a record field or of a variant, the index of a vector element, or the key of a map
+/// encoded as a vector of pairs, such as a principal in text. Options are looked through.
+pub fn dump<T: 'static + CandidType>(
+    format: DebugFormat,
+    path: Option<&str>,
+) -> Result<String, String> {
+    let bytes = maybe_with(|state: &T| encode_one(state))
+        .ok_or_else(|| format!("The state '{}' is not initialized.", type_name::<T>()))?
+        .map_err(|e| format!("Could not encode the state '{}': {}", type_name::<T>(), e))?;
+
+    let value = IDLArgs::from_bytes_with_types(&bytes, &TypeEnv::new(), &[T::ty()])
+        .map_err(|e| format!("Could not decode the state '{}': {}", type_name::<T>(), e))?
+        .args
+        .remove(0);
+
+    let value = match path {
+        Some(path) => select(value, path)?,
+        None => value,
+    };
+
+    match format {
+        DebugFormat::Candid => Ok(value.to_string()),
+        DebugFormat::Json => {
+            serde_json::to_string_pretty(&to_json(value)).map_err(|e| e.to_string())
+        }
+    }
+}
+
+/// Return the part of the value at the given path.
+fn select(mut value: IDLValue, path: &str) -> Result<IDLValue, String> {
+    for segment in path.split('/').filter(|s| !s.is_empty()) {
+        while let IDLValue::Opt(inner) = value {
+            value = *inner;
+        }
+
+        value = match value {
+            IDLValue::Record(fields) => fields
+                .into_iter()
+                .find(|f| label_matches(&f.id, segment))
+                .map(|f| f.val),
+            IDLValue::Variant(variant) if label_matches(&variant.0.id, segment) => {
+                Some(variant.0.val)
+            }
+            IDLValue::Vec(items) => select_item(items, segment),
+            _ => None,
+        }
+        .ok_or_else(|| format!("The path '{}' does not exist in the state.", path))?;
+    }
+
+    Ok(value)
+}
+
+/// Return the value of the map entry with the given key, or the element at the given index.
+fn select_item(mut items: Vec<IDLValue>, segment: &str) -> Option<IDLValue> {
+    let entry = items.iter().position(|item| match item {
+        IDLValue::Record(fields) if is_entry(item) => key_text(&fields[0].val) == segment,
+        _ => false,
+    });
+
+    match entry {
+        Some(i) => match items.swap_remove(i) {
+            IDLValue::Record(mut fields) => Some(fields.remove(1).val),
+            _ => None,
+        },
+        None => segment
+            .parse::<usize>()
+            .ok()
+            .and_then(|i| items.into_iter().nth(i)),
+    }
+}
+
+fn label_matches(label: &Label, segment: &str) -> bool {
+    match label {
+        Label::Named(name) => name == segment,
+        Label::Id(id) | Label::Unnamed(id) => id.to_string() == segment,
+    }
+}
+
+/// Returns true if the value is a pair, which is how the entries of a map are encoded.
+fn is_entry(value: &IDLValue) -> bool {
+    match value {
+        IDLValue::Record(fields) => fields.len() == 2 && is_tuple(fields),
+        _ => false,
+    }
+}
+
+/// Returns true if the fields are the ones of a tuple, whose labels are their positions.
+fn is_tuple(fields: &[IDLField]) -> bool {
+    !fields.is_empty()
+        && fields
+            .iter()
+            .enumerate()
+            .all(|(i, f)| !matches!(f.id, Label::Named(_)) && f.id.get_id() == i as u32)
+}
+
+/// The text of the key of a map entry, as it's written in a path.
+fn key_text(value: &IDLValue) -> String {
+    match value {
+        IDLValue::Text(text) | IDLValue::Number(text) => text.clone(),
+        IDLValue::Principal(principal) | IDLValue::Service(principal) => principal.to_text(),
+        IDLValue::Bool(b) => b.to_string(),
+        IDLValue::Nat(n) => n.0.to_string(),
+        IDLValue::Int(n) => n.0.to_string(),
+        IDLValue::Nat8(n) => n.to_string(),
+        IDLValue::Nat16(n) => n.to_string(),
+        IDLValue::Nat32(n) => n.to_string(),
+        IDLValue::Nat64(n) => n.to_string(),
+        IDLValue::Int8(n) => n.to_string(),
+        IDLValue::Int16(n) => n.to_string(),
+        IDLValue::Int32(n) => n.to_string(),
+        IDLValue::Int64(n) => n.to_string(),
+        value => value.to_string(),
+    }
+}
+
+fn to_json(value: IDLValue) -> Value {
+    match value {
+        IDLValue::Null | IDLValue::None | IDLValue::Reserved => Value::Null,
+        IDLValue::Bool(b) => Value::Bool(b),
+        IDLValue::Text(text) | IDLValue::Number(text) => Value::String(text),
+        IDLValue::Nat(n) => Value::String(n.0.to_string()),
+        IDLValue::Int(n) => Value::String(n.0.to_string()),
+        IDLValue::Nat8(n) => n.into(),
+        IDLValue::Nat16(n) => n.into(),
+        IDLValue::Nat32(n) => n.into(),
+        IDLValue::Nat64(n) => n.into(),
+        IDLValue::Int8(n) => n.into(),
+        IDLValue::Int16(n) => n.into(),
+        IDLValue::Int32(n) => n.into(),
+        IDLValue::Int64(n) => n.into(),
+        IDLValue::Float32(n) => Number::from_f64(n as f64).map_or(Value::Null, Value::Number),
+        IDLValue::Float64(n) => Number::from_f64(n).map_or(Value::Null, Value::Number),
+        IDLValue::Principal(principal) | IDLValue::Service(principal) => {
+            Value::String(principal.to_text())
+        }
+        IDLValue::Func(principal, method) => {
+            Value::String(format!("{}.{}", principal.to_text(), method))
+        }
+        IDLValue::Opt(inner) => to_json(*inner),
+        IDLValue::Vec(items) => Value::Array(items.into_iter().map(to_json).collect()),
+        IDLValue::Record(fields) if is_tuple(&fields) => {
+            Value::Array(fields.into_iter().map(|f| to_json(f.val)).collect())
+        }
+        IDLValue::Record(fields) => Value::Object(fields.into_iter().map(field_to_json).collect()),
+        IDLValue::Variant(variant) => {
+            let mut object = Map::new();
+            let (key, value) = field_to_json(*variant.0);
+            object.insert(key, value);
+            Value::Object(object)
+        }
+    }
+}
+
+fn field_to_json(field: IDLField) -> (String, Value) {
+    let key = match field.id {
+        Label::Named(name) => name,
+        Label::Id(id) | Label::Unnamed(id) => id.to_string(),
+    };
+
+    (key, to_json(field.val))
+}
@@ -0,0 +1,502 @@
+//! Deduplication of the update calls, so a call retried by a client with the same idempotency key
+//! is only executed once and receives the reply of the first call.
+//!
+//! The replies of the completed calls are kept in the stable memory until they expire, the number
+//! of entries is bounded and the oldest ones are evicted first. This must be initialized from the
+//! `init` and the `post_upgrade` hooks:
+//!
+//! ```ignore
+//! #[init]
+//! fn init() {
+//!     dedup::init(MemoryId::new(1), MemoryId::new(2));
+//! }
+//!
+//! // The calls with the same caller and arguments are deduplicated.
+//! #[update(dedup)]
+//! fn transfer(arg: TransferArg) -> Result<u64, TransferError> {
+//!     // ...
+//! }
+//!
+//! // Or with an explicit key.
+//! #[update]
+//! fn order(key: String, order: Order) -> Result<u64, String> {
+//!     let guard = match DedupGuard::begin(key.as_bytes()) {
+//!         Ok(guard) => guard,
+//!         Err(DedupError::Duplicate { reply, .. }) => return candid::decode_one(&reply).unwrap(),
+//!         Err(e) => return Err(e.to_string()),
+//!     };
+//!     let result = place_order(order);
+//!     guard.complete_with(&result);
+//!     result
+//! }
+//! ```
+
+use crate::ic::{caller, maybe_with, maybe_with_mut, swap, time, trap, with};
+use crate::stable::{
+    DefaultMemory, MemoryId, MemoryManager, StableBTreeMap, Storable, VirtualMemory,
+};
+use candid::{encode_one, CandidType, Principal};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::fmt;
+
+/// The number of expired entries removed at most by each call, so the cost of a call stays
+/// bounded.
+const PRUNE_BATCH: usize = 100;
+
+/// The reply of a completed call.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct DedupEntry {
+    /// The candid encoded reply.
+    pub reply: Vec<u8>,
+    /// The time the call completed, in nanoseconds.
+    pub completed_at: u64,
+    /// The time the entry expires, in nanoseconds.
+    pub expires_at: u64,
+}
+
+impl Storable for DedupEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+}
+
+/// The deduplication window and the bound on the number of entries.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct DedupConfig {
+    /// The time the reply of a call is kept, in nanoseconds.
+    pub ttl: u64,
+    /// The maximum number of replies kept, the oldest ones are evicted first.
+    pub capacity: u64,
+    /// The difference allowed between the clock of the client and the time of the canister, in
+    /// nanoseconds, when checking the `created_at_time` of a request.
+    pub permitted_drift: u64,
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        Self {
+            ttl: 24 * 3_600_000_000_000,
+            capacity: 100_000,
+            permitted_drift: 120_000_000_000,
+        }
+    }
+}
+
+/// The reason a call can not be executed.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum DedupError {
+    /// A call with the same key already completed, with the given candid encoded reply.
+    Duplicate { reply: Vec<u8>, completed_at: u64 },
+    /// A call with the same key is being executed.
+    InProgress,
+    /// The `created_at_time` of the request is older than the deduplication window.
+    TooOld,
+    /// The `created_at_time` of the request is in the future.
+    CreatedInFuture { now: u64 },
+}
+
+impl fmt::Display for DedupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DedupError::Duplicate { completed_at, .. } => {
+                write!(
+                    f,
+                    "The call is a duplicate of a call completed at {}.",
+                    completed_at
+                )
+            }
+            DedupError::InProgress => write!(f, "A call with the same key is in progress."),
+            DedupError::TooOld => write!(f, "The request is too old."),
+            DedupError::CreatedInFuture { now } => {
+                write!(
+                    f,
+                    "The request is created in the future, the time is {}.",
+                    now
+                )
+            }
+        }
+    }
+}
+
+/// The deduplication state of the canister, set by [`init`].
+struct Dedup {
+    entries: StableBTreeMap<Vec<u8>, DedupEntry, VirtualMemory<DefaultMemory>>,
+    /// The keys ordered by expiry, as the big-endian expiry time followed by the key.
+    expiries: StableBTreeMap<Vec<u8>, (), VirtualMemory<DefaultMemory>>,
+    in_progress: HashSet<Vec<u8>>,
+    config: DedupConfig,
+}
+
+impl Dedup {
+    fn insert(&mut self, key: Vec<u8>, entry: DedupEntry) {
+        if let Some(previous) = self.entries.insert(key.clone(), entry.clone()) {
+            self.expiries.remove(&expiry_key(previous.expires_at, &key));
+        }
+
+        self.expiries.insert(expiry_key(entry.expires_at, &key), ());
+
+        self.evict();
+    }
+
+    /// Remove the oldest entries until the capacity is not exceeded.
+    fn evict(&mut self) {
+        while self.entries.len() > self.config.capacity {
+            match self.expiries.first_key_value() {
+                Some((oldest, _)) => self.remove_expiry(oldest),
+                None => break,
+            }
+        }
+    }
+
+    fn remove_expiry(&mut self, expiry: Vec<u8>) {
+        self.expiries.remove(&expiry);
+        self.entries.remove(&expiry[8..].to_vec());
+    }
+
+    /// Remove at most `limit` entries which expired by the given time.
+    fn prune(&mut self, now: u64, limit: usize) -> usize {
+        let expired = self
+            .expiries
+            .range(..expiry_key(now.saturating_add(1), &[]))
+            .take(limit)
+            .map(|(expiry, _)| expiry)
+            .collect::<Vec<_>>();
+        let count = expired.len();
+
+        for expiry in expired {
+            self.remove_expiry(expiry);
+        }
+
+        count
+    }
+}
+
+fn expiry_key(expires_at: u64, key: &[u8]) -> Vec<u8> {
+    let mut result = expires_at.to_be_bytes().to_vec();
+    result.extend_from_slice(key);
+    result
+}
+
+/// Load the replies stored in the virtual memories with the given ids of the canister's
+/// `MemoryManager`, or create empty maps. This must be called from the `init` and the
+/// `post_upgrade` hooks.
+pub fn init(entries: MemoryId, expiries: MemoryId) {
+    let (entries, expiries) =
+        with(|manager: &MemoryManager| (manager.get(entries), manager.get(expiries)));
+
+    swap(Dedup {
+        entries: StableBTreeMap::init(entries),
+        expiries: StableBTreeMap::init(expiries),
+        in_progress: HashSet::new(),
+        config: DedupConfig::default(),
+    });
+}
+
+fn with_dedup<U, F: FnOnce(&mut Dedup) -> U>(f: F) -> U {
+    maybe_with_mut(f)
+        .unwrap_or_else(|| trap("The deduplication is not initialized, call dedup::init."))
+}
+
+/// Set the deduplication window and the bound on the number of entries.
+pub fn set_config(config: DedupConfig) {
+    with_dedup(|dedup| {
+        dedup.config = config;
+        dedup.evict();
+    });
+}
+
+/// Return the deduplication window and the bound on the number of entries.
+pub fn config() -> DedupConfig {
+    with_dedup(|dedup| dedup.config)
+}
+
+/// Return the number of replies kept.
+pub fn len() -> u64 {
+    maybe_with(|dedup: &Dedup| dedup.entries.len()).unwrap_or(0)
+}
+
+/// Remove the expired replies, this can be called periodically such as from the heartbeat since
+/// each call only removes a bounded number of them. Returns the number of replies removed.
+pub fn prune() -> usize {
+    with_dedup(|dedup| dedup.prune(time(), PRUNE_BATCH))
+}
+
+/// Check the `created_at_time` of a request against the deduplication window, like the ICRC-1
+/// ledgers do.
+pub fn check_created_at(created_at_time: u64) -> Result<(), DedupError> {
+    let config = config();
+    let now = time();
+
+    if created_at_time
+        .saturating_add(config.ttl)
+        .saturating_add(config.permitted_drift)
+        < now
+    {
+        return Err(DedupError::TooOld);
+    }
+
+    if created_at_time > now.saturating_add(config.permitted_drift) {
+        return Err(DedupError::CreatedInFuture { now });
+    }
+
+    Ok(())
+}
+
+/// Return the key of a call made by the principal with the given idempotency key.
+pub fn scoped_key(principal: &Principal, key: &[u8]) -> Vec<u8> {
+    let principal = principal.as_slice();
+    let mut hasher = Sha256::new();
+    hasher.update([principal.len() as u8]);
+    hasher.update(principal);
+    hasher.update(key);
+    hasher.finalize().to_vec()
+}
+
+/// A guard marking a call as in progress, the reply stored with [`DedupGuard::complete`] is
+/// returned to the later calls with the same key.
+///
+/// If the guard is dropped without being completed, such as when the method returns early or
+/// traps after an await, the key is released so the call can be retried.
+pub struct DedupGuard {
+    key: Vec<u8>,
+}
+
+impl DedupGuard {
+    /// Start a call with the idempotency key, which is scoped to the caller.
+    pub fn begin(key: &[u8]) -> Result<Self, DedupError> {
+        Self::begin_raw(scoped_key(&caller(), key))
+    }
+
+    /// Start a call whose request carries a `created_at_time`, the request is checked against
+    /// the deduplication window and deduplicated with the other requests of the caller, like the
+    /// ICRC-1 transfers.
+    pub fn begin_request<T: CandidType>(
+        created_at_time: u64,
+        request: &T,
+    ) -> Result<Self, DedupError> {
+        check_created_at(created_at_time)?;
+        let request = encode_one(request).expect("Could not encode the request.");
+        Self::begin(&request)
+    }
+
+    /// Start a call of the method with the raw argument of the current call, this is used by
+    /// `#[update(dedup)]`.
+    pub fn begin_call(method: &str) -> Result<Self, DedupError> {
+        let mut key = method.as_bytes().to_vec();
+        key.push(0);
+        key.extend_from_slice(&crate::utils::arg_data_raw());
+        Self::begin(&key)
+    }
+
+    /// Start a call with a key which is not scoped to the caller.
+    pub fn begin_raw(key: Vec<u8>) -> Result<Self, DedupError> {
+        with_dedup(|dedup| {
+            let now = time();
+            dedup.prune(now, PRUNE_BATCH);
+
+            // An expired entry which is not pruned yet is replaced once the call completes.
+            if let Some(entry) = dedup.entries.get(&key) {
+                if entry.expires_at > now {
+                    return Err(DedupError::Duplicate {
+                        reply: entry.reply,
+                        completed_at: entry.completed_at,
+                    });
+                }
+            }
+
+            if !dedup.in_progress.insert(key.clone()) {
+                return Err(DedupError::InProgress);
+            }
+
+            Ok(Self { key })
+        })
+    }
+
+    /// Return the key of the call.
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// Store the candid encoded reply of the call.
+    pub fn complete<R: Into<Vec<u8>>>(self, reply: R) {
+        let reply = reply.into();
+
+        with_dedup(|dedup| {
+            let completed_at = time();
+            let expires_at = completed_at.saturating_add(dedup.config.ttl);
+
+            dedup.insert(
+                self.key.clone(),
+                DedupEntry {
+                    reply,
+                    completed_at,
+                    expires_at,
+                },
+            );
+        });
+    }
+
+    /// Store the reply of the call.
+    pub fn complete_with<T: CandidType>(self, reply: &T) {
+        self.complete(encode_one(reply).expect("Could not encode the reply."));
+    }
+}
+
+impl Drop for DedupGuard {
+    fn drop(&mut self) {
+        maybe_with_mut(|dedup: &mut Dedup| dedup.in_progress.remove(&self.key));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rt::context::MockContext;
+
+    const START: u64 = 1_000_000_000_000;
+    const TTL: u64 = 60_000_000_000;
+
+    fn setup() -> MockContext {
+        let ctx = MockContext::new().with_time(START).inject();
+        init(MemoryId::new(0), MemoryId::new(1));
+        set_config(DedupConfig {
+            ttl: TTL,
+            capacity: 1_000,
+            permitted_drift: 1_000,
+        });
+        ctx
+    }
+
+    fn complete(key: &[u8], reply: u64) {
+        DedupGuard::begin_raw(key.to_vec())
+            .unwrap()
+            .complete_with(&reply);
+    }
+
+    fn duplicate(reply: u64, completed_at: u64) -> Result<(), DedupError> {
+        Err(DedupError::Duplicate {
+            reply: encode_one(reply).unwrap(),
+            completed_at,
+        })
+    }
+
+    #[test]
+    fn duplicate_in_window() {
+        let ctx = setup();
+        complete(b"a", 1);
+
+        let _ctx = ctx.with_time(START + TTL - 1);
+        assert_eq!(
+            DedupGuard::begin_raw(b"a".to_vec()).map(|_| ()),
+            duplicate(1, START)
+        );
+        assert_eq!(len(), 1);
+    }
+
+    #[test]
+    fn in_progress() {
+        let _ctx = setup();
+        let guard = DedupGuard::begin_raw(b"a".to_vec()).unwrap();
+        assert_eq!(
+            DedupGuard::begin_raw(b"a".to_vec()).map(|_| ()),
+            Err(DedupError::InProgress)
+        );
+
+        // The key is released when the guard is dropped without a reply.
+        drop(guard);
+        complete(b"a", 2);
+        assert_eq!(
+            DedupGuard::begin_raw(b"a".to_vec()).map(|_| ()),
+            duplicate(2, START)
+        );
+    }
+
+    #[test]
+    fn expired_after_window() {
+        let ctx = setup();
+        complete(b"a", 1);
+
+        let _ctx = ctx.with_time(START + TTL);
+        complete(b"a", 2);
+        assert_eq!(len(), 1);
+        assert_eq!(
+            DedupGuard::begin_raw(b"a".to_vec()).map(|_| ()),
+            duplicate(2, START + TTL)
+        );
+    }
+
+    #[test]
+    fn expired_not_pruned() {
+        let ctx = setup();
+        let keys = (0..PRUNE_BATCH as u32 + 50)
+            .map(|i| i.to_be_bytes().to_vec())
+            .collect::<Vec<_>>();
+
+        for (i, key) in keys.iter().enumerate() {
+            complete(key, i as u64);
+        }
+
+        // Starting a call prunes a batch of the expired entries, the others are still stored but
+        // are not duplicates anymore.
+        let _ctx = ctx.with_time(START + 2 * TTL);
+        let last = keys.last().unwrap();
+        let guard = DedupGuard::begin_raw(last.clone()).unwrap();
+        assert_eq!(len(), 50);
+
+        guard.complete_with(&7u64);
+        assert_eq!(len(), 50);
+
+        // The entry which was replaced is not pruned with the other expired ones.
+        assert_eq!(
+            DedupGuard::begin_raw(last.clone()).map(|_| ()),
+            duplicate(7, START + 2 * TTL)
+        );
+        assert_eq!(len(), 1);
+        assert_eq!(prune(), 0);
+    }
+
+    #[test]
+    fn evict_oldest() {
+        let ctx = setup();
+        set_config(DedupConfig {
+            capacity: 2,
+            ..config()
+        });
+
+        complete(b"a", 1);
+        let ctx = ctx.with_time(START + 1);
+        complete(b"b", 2);
+        let _ctx = ctx.with_time(START + 2);
+        complete(b"c", 3);
+
+        assert_eq!(len(), 2);
+        assert!(DedupGuard::begin_raw(b"a".to_vec()).is_ok());
+        assert_eq!(
+            DedupGuard::begin_raw(b"b".to_vec()).map(|_| ()),
+            duplicate(2, START + 1)
+        );
+    }
+
+    #[test]
+    fn created_at() {
+        let _ctx = setup();
+        assert_eq!(check_created_at(START), Ok(()));
+        assert_eq!(check_created_at(START - TTL - 1_000), Ok(()));
+        assert_eq!(
+            check_created_at(START - TTL - 1_001),
+            Err(DedupError::TooOld)
+        );
+        assert_eq!(check_created_at(START + 1_000), Ok(()));
+        assert_eq!(
+            check_created_at(START + 1_001),
+            Err(DedupError::CreatedInFuture { now: START })
+        );
+    }
+}
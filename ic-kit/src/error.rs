@@ -0,0 +1,149 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+use crate::dedup::DedupError;
+use crate::ic::{CallError, DecodeError};
+
+/// A structured error returned by the methods of a canister, with a code the clients can match
+/// on, a message for humans and optional details, so the canisters built with the kit respond
+/// with the same type of error.
+///
+/// A method returning `Result<T, ic_kit::Error>` can use `?` on the errors which convert to it,
+/// and with the `reply_errors` flag, the calls refused by its access control, its guard or the
+/// decoding of its arguments are replied with this error instead of being rejected.
+///
+/// ```ignore
+/// #[update(reply_errors, guard = "not_paused")]
+/// #[only_controller]
+/// async fn sweep(to: Principal) -> Result<u64, ic_kit::Error> {
+///     let balance: (u64,) = CallBuilder::new(ledger(), "balance").perform().await?;
+///     Ok(balance.0)
+/// }
+/// ```
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Error {
+    /// The kind of the error, such as [`Error::NOT_FOUND`].
+    pub code: String,
+    /// The description of the error.
+    pub message: String,
+    /// The details of the error by their name, such as the id of a missing entity.
+    pub details: Option<BTreeMap<String, String>>,
+}
+
+impl Error {
+    /// An unexpected failure of the canister.
+    pub const INTERNAL: &'static str = "internal";
+    /// The arguments of the call are invalid or could not be decoded.
+    pub const INVALID_ARGUMENT: &'static str = "invalid_argument";
+    /// An entity the call refers to does not exist.
+    pub const NOT_FOUND: &'static str = "not_found";
+    /// The caller is not allowed to make the call.
+    pub const UNAUTHORIZED: &'static str = "unauthorized";
+    /// The canister is not in a state which allows the call, such as when a guard refuses it.
+    pub const PRECONDITION_FAILED: &'static str = "precondition_failed";
+    /// The call conflicts with another one, such as a duplicate.
+    pub const CONFLICT: &'static str = "conflict";
+    /// A call made by the canister to another canister failed.
+    pub const CALL_FAILED: &'static str = "call_failed";
+
+    /// Create an error with the given code and message, and no details.
+    pub fn new<C: Into<String>, M: Into<String>>(code: C, message: M) -> Self {
+        Self {
+            code: code.into(),
+            message: message.into(),
+            details: None,
+        }
+    }
+
+    /// Shorthand for an error with the [`Error::INTERNAL`] code.
+    pub fn internal<M: Into<String>>(message: M) -> Self {
+        Self::new(Self::INTERNAL, message)
+    }
+
+    /// Shorthand for an error with the [`Error::INVALID_ARGUMENT`] code.
+    pub fn invalid_argument<M: Into<String>>(message: M) -> Self {
+        Self::new(Self::INVALID_ARGUMENT, message)
+    }
+
+    /// Shorthand for an error with the [`Error::NOT_FOUND`] code.
+    pub fn not_found<M: Into<String>>(message: M) -> Self {
+        Self::new(Self::NOT_FOUND, message)
+    }
+
+    /// Shorthand for an error with the [`Error::UNAUTHORIZED`] code.
+    pub fn unauthorized<M: Into<String>>(message: M) -> Self {
+        Self::new(Self::UNAUTHORIZED, message)
+    }
+
+    /// Add a detail to the error, replacing the one with the same name.
+    pub fn with_detail<K: Into<String>, V: ToString>(mut self, name: K, value: V) -> Self {
+        self.details
+            .get_or_insert_with(BTreeMap::new)
+            .insert(name.into(), value.to_string());
+        self
+    }
+
+    /// Return the detail with the given name, if the error has it.
+    pub fn detail(&self, name: &str) -> Option<&str> {
+        self.details.as_ref()?.get(name).map(String::as_str)
+    }
+
+    /// Returns true if the error has the given code.
+    pub fn is(&self, code: &str) -> bool {
+        self.code == code
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<String> for Error {
+    fn from(message: String) -> Self {
+        Self::internal(message)
+    }
+}
+
+impl From<&str> for Error {
+    fn from(message: &str) -> Self {
+        Self::internal(message)
+    }
+}
+
+impl From<candid::Error> for Error {
+    fn from(error: candid::Error) -> Self {
+        Self::internal(error.to_string())
+    }
+}
+
+impl From<CallError> for Error {
+    fn from(error: CallError) -> Self {
+        let message = error.to_string();
+
+        match error {
+            CallError::Rejected(code, _) => {
+                Self::new(Self::CALL_FAILED, message).with_detail("reject_code", code as i32)
+            }
+            _ => Self::new(Self::CALL_FAILED, message),
+        }
+    }
+}
+
+impl From<DecodeError> for Error {
+    fn from(error: DecodeError) -> Self {
+        Self::invalid_argument(error.to_string()).with_detail("method", error.method)
+    }
+}
+
+impl From<DedupError> for Error {
+    fn from(error: DedupError) -> Self {
+        Self::new(Self::CONFLICT, error.to_string())
+    }
+}
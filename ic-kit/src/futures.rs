@@ -9,9 +9,9 @@
 // needs.
 use candid::Principal;
 use ic_kit_sys::ic0;
+use std::cell::Cell;
 use std::future::Future;
 use std::pin::Pin;
-use std::sync::atomic::{AtomicBool, Ordering};
 use std::task::{Context, Poll, Waker};
 
 #[cfg(target_family = "wasm")]
@@ -200,6 +200,7 @@ pub(crate) unsafe fn call_new(canister_id: Principal, method: &str) -> CallFutur
 /// result and calls the waker. We cannot use a closure here because we pass raw
 /// pointers to the System and back.
 fn callback(state_ptr: *const InnerCell<CallFutureState>) {
+    crate::ic::begin_message();
     let state = unsafe { WasmCell::from_raw(state_ptr) };
     // Make sure to un-borrow_mut the state.
     {
@@ -217,6 +218,7 @@ fn callback(state_ptr: *const InnerCell<CallFutureState>) {
 /// We can't guarantee internal consistency at this point, but we can at least e.g. drop mutex guards.
 /// Waker is a very opaque API, so the best we can do is set a global flag and proceed normally.
 fn cleanup(state_ptr: *const InnerCell<CallFutureState>) {
+    crate::ic::begin_message();
     let state = unsafe { WasmCell::from_raw(state_ptr) };
     // We set the call result, even though it won't be read on the
     // default executor, because we can't guarantee it was called on
@@ -231,9 +233,9 @@ fn cleanup(state_ptr: *const InnerCell<CallFutureState>) {
     if let Some(waker) = w {
         // Flag that we do not want to actually wake the task - we
         // want to drop it *without* executing it.
-        CLEANUP.store(true, Ordering::Relaxed);
+        CLEANUP.with(|cleanup| cleanup.set(true));
         waker.wake();
-        CLEANUP.store(false, Ordering::Relaxed);
+        CLEANUP.with(|cleanup| cleanup.set(false));
     }
 }
 
@@ -255,6 +257,10 @@ fn cleanup(state_ptr: *const InnerCell<CallFutureState>) {
 /// fat pointer and pass it instead.
 #[inline]
 pub fn spawn<F: 'static + Future<Output = ()>>(future: F) {
+    let future = InCallContext {
+        context: crate::ic::call_context(),
+        future,
+    };
     let future_ptr = Box::into_raw(Box::new(future));
     let future_ptr_ptr: *mut *mut dyn Future<Output = ()> = Box::into_raw(Box::new(future_ptr));
     let mut pinned_future = unsafe { Pin::new_unchecked(&mut *future_ptr) };
@@ -272,7 +278,41 @@ pub fn spawn<F: 'static + Future<Output = ()>>(future: F) {
     }
 }
 
-pub(crate) static CLEANUP: AtomicBool = AtomicBool::new(false);
+/// A future which is polled in the call context it was spawned in, so the messages which resume
+/// it after an await are attributed to the incoming call which started it.
+struct InCallContext<F> {
+    context: u64,
+    future: F,
+}
+
+impl<F: Future> Future for InCallContext<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Self::Output> {
+        let previous = crate::ic::set_call_context(self.context);
+        // The future is never moved out of the struct, so it stays pinned.
+        let result = unsafe { self.map_unchecked_mut(|this| &mut this.future) }.poll(context);
+        crate::ic::set_call_context(previous);
+        result
+    }
+}
+
+thread_local! {
+    // Each canister of the runtime is executed on its own thread, so the flag is per thread for
+    // the cleanup of one canister to not drop the futures of another one.
+    pub(crate) static CLEANUP: Cell<bool> = const { Cell::new(false) };
+
+    // The number of times a future was resumed after an await, which the references to the state
+    // check to find out if they were held across an await.
+    #[cfg(debug_assertions)]
+    static RESUMES: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Return the number of times a future was resumed after an await.
+#[cfg(debug_assertions)]
+pub(crate) fn resumes() -> u64 {
+    RESUMES.with(|resumes| resumes.get())
+}
 
 // This module contains the implementation of a waker we're using for waking
 // top-level futures (the ones returned by canister methods). The waker polls
@@ -282,10 +322,7 @@ pub(crate) static CLEANUP: AtomicBool = AtomicBool::new(false);
 // waker was used as intended.
 mod waker {
     use super::*;
-    use std::{
-        sync::atomic::Ordering,
-        task::{RawWaker, RawWakerVTable, Waker},
-    };
+    use std::task::{RawWaker, RawWakerVTable, Waker};
     type FuturePtr = *mut dyn Future<Output = ()>;
 
     static MY_VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake_by_ref, drop);
@@ -312,7 +349,9 @@ mod waker {
         let future_ptr: FuturePtr = *boxed_future_ptr_ptr;
         let boxed_future = Box::from_raw(future_ptr);
         let mut pinned_future = Pin::new_unchecked(&mut *future_ptr);
-        if !CLEANUP.load(Ordering::Relaxed)
+        #[cfg(debug_assertions)]
+        RESUMES.with(|resumes| resumes.set(resumes.get() + 1));
+        if !CLEANUP.with(|cleanup| cleanup.get())
             && pinned_future
                 .as_mut()
                 .poll(&mut Context::from_waker(&waker::waker(ptr)))
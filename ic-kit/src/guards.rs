@@ -0,0 +1,652 @@
+use crate::ic::{caller, message_calls, time, trap, with, with_mut, yield_now};
+use candid::{CandidType, Principal};
+use serde::Deserialize;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::Hash;
+
+/// The keys that are currently locked by a [`ReentrancyGuard`].
+struct Locks<K>(HashSet<K>);
+
+impl<K> Default for Locks<K> {
+    fn default() -> Self {
+        Self(HashSet::new())
+    }
+}
+
+/// A lock which prevents the same key from being used by more than one call at a time, which is
+/// useful for async methods whose state could change between the awaits.
+///
+/// The lock is released once the guard is dropped, which also happens in the cleanup callback if
+/// the call traps after an await, so the key is never left locked.
+///
+/// ```ignore
+/// #[update]
+/// async fn withdraw(amount: u64) -> Result<(), String> {
+///     let _guard = ReentrancyGuard::caller()?;
+///     // ...
+/// }
+/// ```
+pub struct ReentrancyGuard<K: Hash + Eq + Clone + 'static = Principal> {
+    key: K,
+}
+
+impl ReentrancyGuard<Principal> {
+    /// Lock the caller of the current method.
+    pub fn caller() -> Result<Self, String> {
+        Self::new(caller())
+    }
+}
+
+impl<K: Hash + Eq + Clone + 'static> ReentrancyGuard<K> {
+    /// Lock the given key, returns an error if the key is already locked by another call.
+    pub fn new(key: K) -> Result<Self, String> {
+        let locked = with_mut(|locks: &mut Locks<K>| locks.0.insert(key.clone()));
+
+        if !locked {
+            return Err("Another call is already in progress for this key.".into());
+        }
+
+        Ok(Self { key })
+    }
+
+    /// Return the key locked by this guard.
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+}
+
+impl<K: Hash + Eq + Clone + 'static> Drop for ReentrancyGuard<K> {
+    fn drop(&mut self) {
+        with_mut(|locks: &mut Locks<K>| locks.0.remove(&self.key));
+    }
+}
+
+/// The calls running and waiting for each endpoint limited by a [`ConcurrencyLimit`].
+#[derive(Default)]
+struct Endpoints(HashMap<&'static str, Endpoint>);
+
+#[derive(Default)]
+struct Endpoint {
+    running: u32,
+    /// The tickets of the waiting calls, in the order they arrived.
+    waiting: VecDeque<u64>,
+    next_ticket: u64,
+}
+
+/// A limit on the number of calls of an endpoint which run at once, the calls over the limit are
+/// rejected, or wait in a queue of a bounded length for a running call to finish. This is used by
+/// the `max_concurrent` and `max_queued` options of `#[update]`, so a critical section spanning
+/// awaits such as a `rebalance` is never run twice at the same time:
+///
+/// ```ignore
+/// #[update(max_concurrent = 1, max_queued = 10)]
+/// async fn rebalance() {
+///     // ...
+/// }
+/// ```
+///
+/// The slot is released once the returned [`ConcurrencyPermit`] is dropped, which also happens
+/// in the cleanup callback if the call traps after an await. A waiting call checks for a free
+/// slot after each round with a call the canister makes to itself, since it can't be resumed by
+/// the call which releases the slot, and the calls get the slots in the order they arrived.
+#[derive(Copy, Clone, Debug)]
+pub struct ConcurrencyLimit {
+    endpoint: &'static str,
+    max_concurrent: u32,
+    max_queued: u32,
+}
+
+impl ConcurrencyLimit {
+    /// Allow at most `max_concurrent` calls of the endpoint at once, and reject the others.
+    pub fn new(endpoint: &'static str, max_concurrent: u32) -> Self {
+        assert!(
+            max_concurrent > 0,
+            "The concurrency limit of an endpoint must not be zero."
+        );
+
+        Self {
+            endpoint,
+            max_concurrent,
+            max_queued: 0,
+        }
+    }
+
+    /// Let up to `max_queued` calls over the limit wait for a slot instead of being rejected.
+    pub fn with_queue(mut self, max_queued: u32) -> Self {
+        self.max_queued = max_queued;
+        self
+    }
+
+    /// Take a slot, returns an error if all of the slots are taken. The waiting calls are served
+    /// first.
+    pub fn try_acquire(&self) -> Result<ConcurrencyPermit, String> {
+        let acquired = with_mut(|endpoints: &mut Endpoints| {
+            let endpoint = endpoints.0.entry(self.endpoint).or_default();
+
+            if endpoint.running < self.max_concurrent && endpoint.waiting.is_empty() {
+                endpoint.running += 1;
+                true
+            } else {
+                false
+            }
+        });
+
+        if !acquired {
+            return Err(format!(
+                "The method '{}' is at its limit of {} concurrent calls, try again later.",
+                self.endpoint, self.max_concurrent
+            ));
+        }
+
+        Ok(ConcurrencyPermit {
+            endpoint: self.endpoint,
+        })
+    }
+
+    /// Take a slot, waiting in the queue for one to be released if they are all taken. Returns an
+    /// error right away if the queue is full.
+    pub async fn acquire(&self) -> Result<ConcurrencyPermit, String> {
+        Ok(self.queue()?.wait().await)
+    }
+
+    /// Take a slot, or a place in the queue if they are all taken, returns an error if the queue
+    /// is full. This is the part of [`ConcurrencyLimit::acquire`] which does not wait, so the call
+    /// is refused before its arguments are read.
+    pub fn queue(&self) -> Result<QueuedCall, String> {
+        match self.try_acquire() {
+            Ok(permit) => {
+                return Ok(QueuedCall {
+                    limit: *self,
+                    place: Place::Acquired(permit),
+                })
+            }
+            Err(e) if self.max_queued == 0 => return Err(e),
+            Err(_) => {}
+        }
+
+        let ticket = with_mut(|endpoints: &mut Endpoints| {
+            let endpoint = endpoints.0.entry(self.endpoint).or_default();
+
+            if endpoint.waiting.len() >= self.max_queued as usize {
+                return None;
+            }
+
+            let ticket = endpoint.next_ticket;
+            endpoint.next_ticket += 1;
+            endpoint.waiting.push_back(ticket);
+            Some(ticket)
+        });
+
+        match ticket {
+            Some(ticket) => Ok(QueuedCall {
+                limit: *self,
+                place: Place::Waiting(Waiting {
+                    endpoint: self.endpoint,
+                    ticket,
+                }),
+            }),
+            None => Err(format!(
+                "The method '{}' is at its limit of {} concurrent calls and {} waiting calls, try \
+                 again later.",
+                self.endpoint, self.max_concurrent, self.max_queued
+            )),
+        }
+    }
+
+    /// Return the number of calls of the endpoint which are running.
+    pub fn running(&self) -> u32 {
+        with(
+            |endpoints: &Endpoints| match endpoints.0.get(self.endpoint) {
+                Some(endpoint) => endpoint.running,
+                None => 0,
+            },
+        )
+    }
+
+    /// Return the number of calls of the endpoint which are waiting for a slot.
+    pub fn queued(&self) -> u32 {
+        with(
+            |endpoints: &Endpoints| match endpoints.0.get(self.endpoint) {
+                Some(endpoint) => endpoint.waiting.len() as u32,
+                None => 0,
+            },
+        )
+    }
+}
+
+/// A call which has a slot of a [`ConcurrencyLimit`] or a place in its queue.
+pub struct QueuedCall {
+    limit: ConcurrencyLimit,
+    place: Place,
+}
+
+enum Place {
+    Acquired(ConcurrencyPermit),
+    Waiting(Waiting),
+}
+
+impl QueuedCall {
+    /// Wait for the slot of the call, the calls get the slots in the order they arrived. The slot
+    /// is taken right away if it was released since the call was queued, such as while its
+    /// arguments were read, otherwise the call checks again after each round.
+    pub async fn wait(self) -> ConcurrencyPermit {
+        let waiting = match self.place {
+            Place::Acquired(permit) => return permit,
+            Place::Waiting(waiting) => waiting,
+        };
+
+        let limit = self.limit;
+
+        loop {
+            let acquired = with_mut(|endpoints: &mut Endpoints| {
+                let endpoint = endpoints.0.entry(limit.endpoint).or_default();
+
+                if endpoint.running < limit.max_concurrent
+                    && endpoint.waiting.front() == Some(&waiting.ticket)
+                {
+                    endpoint.waiting.pop_front();
+                    endpoint.running += 1;
+                    true
+                } else {
+                    false
+                }
+            });
+
+            if acquired {
+                return ConcurrencyPermit {
+                    endpoint: limit.endpoint,
+                };
+            }
+
+            yield_now().await;
+        }
+    }
+}
+
+/// A slot of a [`ConcurrencyLimit`], which is released once it's dropped.
+pub struct ConcurrencyPermit {
+    endpoint: &'static str,
+}
+
+impl Drop for ConcurrencyPermit {
+    fn drop(&mut self) {
+        with_mut(|endpoints: &mut Endpoints| {
+            if let Some(endpoint) = endpoints.0.get_mut(self.endpoint) {
+                endpoint.running -= 1;
+            }
+        });
+    }
+}
+
+/// The place of a call in the queue of a [`ConcurrencyLimit`], which is left if the call is
+/// dropped while waiting, such as when it traps. It's already left once the call gets a slot.
+struct Waiting {
+    endpoint: &'static str,
+    ticket: u64,
+}
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        with_mut(|endpoints: &mut Endpoints| {
+            if let Some(endpoint) = endpoints.0.get_mut(self.endpoint) {
+                endpoint.waiting.retain(|ticket| *ticket != self.ticket);
+            }
+        });
+    }
+}
+
+/// The state of a single key in a [`RateLimiter`].
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct Bucket {
+    tokens: u64,
+    updated_at: u64,
+}
+
+/// A token bucket rate limiter, each key has a bucket of `capacity` tokens and every call takes a
+/// token from the bucket, one token is added back to the bucket every `refill_interval`
+/// nanoseconds.
+///
+/// The limiter should be kept as a part of the canister's state.
+///
+/// ```ignore
+/// struct Limiter(RateLimiter);
+///
+/// impl Default for Limiter {
+///     fn default() -> Self {
+///         // 10 calls, plus a call every second.
+///         Self(RateLimiter::new(10, 1_000_000_000))
+///     }
+/// }
+///
+/// fn rate_limit() -> Result<(), String> {
+///     with_mut(|limiter: &mut Limiter| limiter.0.check_caller())
+/// }
+///
+/// #[update(guard = "rate_limit")]
+/// fn mint() {
+///     // ...
+/// }
+/// ```
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct RateLimiter<K: Hash + Eq = Principal> {
+    capacity: u64,
+    refill_interval: u64,
+    buckets: HashMap<K, Bucket>,
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    /// Create a new rate limiter which allows `capacity` calls at once, and refills one call every
+    /// `refill_interval` nanoseconds.
+    pub fn new(capacity: u64, refill_interval: u64) -> Self {
+        assert!(
+            capacity > 0,
+            "The capacity of a rate limiter must not be zero."
+        );
+        assert!(
+            refill_interval > 0,
+            "The refill interval of a rate limiter must not be zero."
+        );
+
+        Self {
+            capacity,
+            refill_interval,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Take a token for the given key at the given time, returns an error if the key has run out
+    /// of tokens.
+    pub fn try_acquire(&mut self, key: K, now: u64) -> Result<(), String> {
+        let capacity = self.capacity;
+        let bucket = self.buckets.entry(key).or_insert(Bucket {
+            tokens: capacity,
+            updated_at: now,
+        });
+
+        let refilled = now.saturating_sub(bucket.updated_at) / self.refill_interval;
+        if bucket.tokens.saturating_add(refilled) >= capacity {
+            bucket.tokens = capacity;
+            bucket.updated_at = now;
+        } else {
+            // Only move the time by the refilled tokens, so the partial progress is not lost.
+            bucket.tokens += refilled;
+            bucket.updated_at += refilled * self.refill_interval;
+        }
+
+        if bucket.tokens == 0 {
+            return Err("Rate limit exceeded, try again later.".into());
+        }
+
+        bucket.tokens -= 1;
+        Ok(())
+    }
+
+    /// Remove the buckets that are refilled by the given time, they are recreated once the key is
+    /// used again. This can be called periodically to limit the memory used by the limiter.
+    pub fn prune(&mut self, now: u64) {
+        let capacity = self.capacity;
+        let refill_interval = self.refill_interval;
+
+        self.buckets.retain(|_, bucket| {
+            let refilled = now.saturating_sub(bucket.updated_at) / refill_interval;
+            bucket.tokens.saturating_add(refilled) < capacity
+        });
+    }
+}
+
+impl RateLimiter<Principal> {
+    /// Take a token for the caller of the current method.
+    pub fn check_caller(&mut self) -> Result<(), String> {
+        self.try_acquire(caller(), time())
+    }
+}
+
+/// What happens to a call made by a message which has used up its [`CallBudget`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CallBudgetPolicy {
+    /// Trap, so the message is rolled back along with the calls it made.
+    Trap,
+    /// Fail the call as if it could not be sent, `perform` returns `CallError::CouldNotSend`,
+    /// like when the output queue of the canister is full.
+    Fail,
+}
+
+thread_local! {
+    // The call context, the limit and the policy of each `CallBudget` which is alive, by its id.
+    static CALL_BUDGETS: RefCell<Vec<(u64, u64, u32, CallBudgetPolicy)>> =
+        const { RefCell::new(Vec::new()) };
+    static NEXT_CALL_BUDGET: Cell<u64> = const { Cell::new(0) };
+}
+
+/// A limit on the number of calls each message makes to the other canisters, including the calls
+/// made by the canister to itself such as `yield_now`, so a loop over a collection can't fill the
+/// output queue of the canister. The limit applies to the messages of the incoming call which
+/// created the guard while it's alive, the ones after an await included, and the smallest limit
+/// applies if there is more than one guard. The messages of the other calls executed by the
+/// canister in the meantime are not limited.
+///
+/// ```ignore
+/// #[update]
+/// async fn notify_all() {
+///     let _budget = CallBudget::new(50);
+///
+///     for subscriber in with(|s: &Subscribers| s.0.clone()) {
+///         // Traps at the 51st call.
+///         let _ = CallBuilder::new(subscriber, "notify").perform_one_way();
+///     }
+/// }
+/// ```
+pub struct CallBudget {
+    id: u64,
+    max_calls: u32,
+}
+
+impl CallBudget {
+    /// Limit the calls of each message to the given number, the message traps if it makes more.
+    pub fn new(max_calls: u32) -> Self {
+        Self::with_policy(max_calls, CallBudgetPolicy::Trap)
+    }
+
+    /// Limit the calls of each message to the given number, and apply the given policy to the
+    /// calls made over the limit.
+    pub fn with_policy(max_calls: u32, policy: CallBudgetPolicy) -> Self {
+        let id = NEXT_CALL_BUDGET.with(|next| next.replace(next.get() + 1));
+        let context = crate::ic::call_context();
+        CALL_BUDGETS.with(|budgets| budgets.borrow_mut().push((id, context, max_calls, policy)));
+        Self { id, max_calls }
+    }
+
+    /// Return the number of calls the current message can still make within this budget.
+    pub fn remaining(&self) -> u32 {
+        self.max_calls.saturating_sub(message_calls())
+    }
+
+    /// Return the number of budgets which are alive, those of the other call contexts included.
+    pub fn active() -> usize {
+        CALL_BUDGETS.with(|budgets| budgets.borrow().len())
+    }
+}
+
+impl Drop for CallBudget {
+    fn drop(&mut self) {
+        CALL_BUDGETS.with(|budgets| budgets.borrow_mut().retain(|(id, ..)| *id != self.id));
+    }
+}
+
+/// Returns true if the current message can make one more call, or traps if it can't and the
+/// budget of its call context with the smallest limit has the [`CallBudgetPolicy::Trap`] policy.
+pub(crate) fn check_call_budget(calls: u32) -> bool {
+    let context = crate::ic::call_context();
+    let budget = CALL_BUDGETS.with(|budgets| {
+        budgets
+            .borrow()
+            .iter()
+            .filter(|(_, budget_context, ..)| *budget_context == context)
+            .min_by_key(|(_, _, max_calls, _)| *max_calls)
+            .map(|(_, _, max_calls, policy)| (*max_calls, *policy))
+    });
+
+    match budget {
+        Some((max_calls, CallBudgetPolicy::Trap)) if calls >= max_calls => trap(&format!(
+            "The message made more than {} calls, the limit of its CallBudget.",
+            max_calls
+        )),
+        Some((max_calls, CallBudgetPolicy::Fail)) => calls < max_calls,
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rt::context::MockContext;
+
+    const START: u64 = 1_000_000_000_000;
+
+    fn alice() -> Principal {
+        Principal::from_slice(&[1])
+    }
+
+    fn bob() -> Principal {
+        Principal::from_slice(&[2])
+    }
+
+    #[test]
+    fn reentrancy_guard() {
+        MockContext::new().with_caller(alice()).inject();
+
+        let guard = ReentrancyGuard::caller().unwrap();
+        assert_eq!(guard.key(), &alice());
+        assert!(ReentrancyGuard::caller().is_err());
+        assert!(ReentrancyGuard::new(bob()).is_ok());
+
+        // The keys of another type are locked separately.
+        let _other = ReentrancyGuard::new(1u64).unwrap();
+        assert!(ReentrancyGuard::new(1u64).is_err());
+
+        drop(guard);
+        assert!(ReentrancyGuard::caller().is_ok());
+    }
+
+    #[test]
+    fn concurrency_permits() {
+        MockContext::new().inject();
+        let limit = ConcurrencyLimit::new("permits", 2);
+
+        let first = limit.try_acquire().unwrap();
+        let _second = limit.try_acquire().unwrap();
+        assert_eq!(limit.running(), 2);
+        assert_eq!(
+            limit.try_acquire().err().unwrap(),
+            "The method 'permits' is at its limit of 2 concurrent calls, try again later."
+        );
+
+        // Without a queue, the calls over the limit are refused.
+        assert!(limit.queue().is_err());
+        assert_eq!(limit.queued(), 0);
+
+        drop(first);
+        assert_eq!(limit.running(), 1);
+        assert!(limit.try_acquire().is_ok());
+        assert_eq!(limit.running(), 1);
+    }
+
+    #[test]
+    fn concurrency_queue() {
+        let ctx = MockContext::new().inject();
+        let limit = ConcurrencyLimit::new("queue", 1).with_queue(2);
+
+        let permit = limit.queue().unwrap();
+        let permit = ctx.block_on(permit.wait());
+        let first = limit.queue().unwrap();
+        let second = limit.queue().unwrap();
+        assert_eq!(limit.queued(), 2);
+        assert_eq!(
+            limit.queue().err().unwrap(),
+            "The method 'queue' is at its limit of 1 concurrent calls and 2 waiting calls, try \
+             again later."
+        );
+
+        // The waiting calls are served first, in the order they arrived.
+        drop(permit);
+        assert!(limit.try_acquire().is_err());
+
+        // The slot is taken right away, without waiting for another message.
+        let permit = ctx.block_on(first.wait());
+        assert_eq!(ctx.watcher().call_count(), 0);
+        assert_eq!(limit.running(), 1);
+        assert_eq!(limit.queued(), 1);
+
+        // A call which is dropped while waiting leaves the queue.
+        drop(second);
+        assert_eq!(limit.queued(), 0);
+        drop(permit);
+        assert_eq!(limit.running(), 0);
+        assert!(limit.try_acquire().is_ok());
+    }
+
+    #[test]
+    fn concurrency_wait() {
+        let ctx = MockContext::new().inject();
+        let limit = ConcurrencyLimit::new("wait", 1).with_queue(1);
+
+        let permit = limit.try_acquire().unwrap();
+        let queued = limit.queue().unwrap();
+
+        // The waiting call checks for its slot after each round, and gets it once the running
+        // call is done.
+        let released = Cell::new(Some(permit));
+        let ctx = ctx.with_handler(crate::ic::id(), "__ic_kit_yield", move |_| {
+            released.take();
+            Err("Yield.".into())
+        });
+        let _permit = ctx.block_on(queued.wait());
+        assert_eq!(ctx.watcher().calls_to("__ic_kit_yield").len(), 1);
+        assert_eq!(limit.running(), 1);
+        assert_eq!(limit.queued(), 0);
+    }
+
+    #[test]
+    fn rate_limiter() {
+        let mut limiter = RateLimiter::new(2, 10);
+
+        assert!(limiter.try_acquire(alice(), START).is_ok());
+        assert!(limiter.try_acquire(alice(), START).is_ok());
+        assert_eq!(
+            limiter.try_acquire(alice(), START + 9),
+            Err("Rate limit exceeded, try again later.".into())
+        );
+        assert!(limiter.try_acquire(bob(), START).is_ok());
+
+        // A token is added back every interval.
+        assert!(limiter.try_acquire(alice(), START + 10).is_ok());
+        assert!(limiter.try_acquire(alice(), START + 15).is_err());
+        assert!(limiter.try_acquire(alice(), START + 20).is_ok());
+
+        // The bucket is not refilled over its capacity.
+        assert!(limiter.try_acquire(alice(), START + 1_000).is_ok());
+        assert!(limiter.try_acquire(alice(), START + 1_000).is_ok());
+        assert!(limiter.try_acquire(alice(), START + 1_000).is_err());
+
+        // The buckets are pruned once they are refilled.
+        limiter.prune(START + 1_010);
+        assert_eq!(limiter.buckets.len(), 1);
+        limiter.prune(START + 1_020);
+        assert!(limiter.buckets.is_empty());
+    }
+
+    #[test]
+    fn rate_limiter_caller() {
+        let ctx = MockContext::new()
+            .with_caller(alice())
+            .with_time(START)
+            .inject();
+        let mut limiter = RateLimiter::new(1, 10);
+
+        assert!(limiter.check_caller().is_ok());
+        assert!(limiter.check_caller().is_err());
+        ctx.with_time(START + 10);
+        assert!(limiter.check_caller().is_ok());
+    }
+}
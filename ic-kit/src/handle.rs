@@ -0,0 +1,54 @@
+use crate::ic;
+use candid::parser::value::IDLValue;
+use candid::CandidType;
+use ic_kit_runtime::diff::to_value;
+use ic_kit_runtime::handle::CanisterHandle;
+use ic_kit_runtime::snapshot::Snapshot;
+use std::future::Future;
+use std::pin::Pin;
+
+/// Methods of the [`CanisterHandle`] which need access to the storage of the canister.
+pub trait KitHandle {
+    /// Render the value of the given type in the storage of the canister as candid text, which
+    /// can be compared against a stored snapshot with [`Snapshot::assert_matches`]. The default
+    /// value is used if the canister has not stored the type yet.
+    ///
+    /// ```ignore
+    /// handle.state_snapshot::<Ledger>().await.assert_matches("ledger_after_transfer");
+    /// ```
+    fn state_snapshot<T>(&self) -> Pin<Box<dyn Future<Output = Snapshot> + '_>>
+    where
+        T: 'static + Default + CandidType;
+
+    /// Return the candid representation of the value of the given type in the storage of the
+    /// canister, which can be compared with another capture using [`ValueDiff::between`]. The
+    /// default value is used if the canister has not stored the type yet.
+    ///
+    /// ```ignore
+    /// let before = handle.state_value::<Ledger>().await;
+    /// handle.new_call("transfer").with_args((bob, 10u64)).perform().await;
+    /// let after = handle.state_value::<Ledger>().await;
+    /// println!("{}", ValueDiff::between(&before, &after));
+    /// ```
+    ///
+    /// [`ValueDiff::between`]: ic_kit_runtime::diff::ValueDiff::between
+    fn state_value<T>(&self) -> Pin<Box<dyn Future<Output = IDLValue> + '_>>
+    where
+        T: 'static + Default + CandidType;
+}
+
+impl<'a> KitHandle for CanisterHandle<'a> {
+    fn state_snapshot<T>(&self) -> Pin<Box<dyn Future<Output = Snapshot> + '_>>
+    where
+        T: 'static + Default + CandidType,
+    {
+        Box::pin(self.run(|| ic::with(|state: &T| Snapshot::from_candid(state))))
+    }
+
+    fn state_value<T>(&self) -> Pin<Box<dyn Future<Output = IDLValue> + '_>>
+    where
+        T: 'static + Default + CandidType,
+    {
+        Box::pin(self.run(|| ic::with(|state: &T| to_value(state))))
+    }
+}
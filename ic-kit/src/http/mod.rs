@@ -0,0 +1,83 @@
+//! Serve HTTP from a canister through the `http_request` interface of the HTTP gateways.
+//!
+//! The gateway calls the `http_request` query of the canister, and calls `http_request_update`
+//! instead if the response asks for an upgrade, which the [`Router`] does for the routes that
+//! change the state of the canister. The bodies which don't fit in a single response are streamed
+//! with a callback, see [`HttpResponse::chunked`].
+//!
+//! ```ignore
+//! use ic_kit::http::{HttpRequest, HttpResponse, Params, Router};
+//!
+//! fn router() -> Router {
+//!     Router::new()
+//!         .get("/users/:id", |_, params: &Params| {
+//!             HttpResponse::ok(format!("user {}", params.get("id").unwrap()))
+//!         })
+//!         .post("/users", |req: &HttpRequest, _| create_user(&req.body))
+//! }
+//!
+//! #[query]
+//! fn http_request(req: HttpRequest) -> HttpResponse {
+//!     router().http_request(req)
+//! }
+//!
+//! #[update]
+//! fn http_request_update(req: HttpRequest) -> HttpResponse {
+//!     router().http_request_update(req)
+//! }
+//! ```
+
+mod router;
+mod types;
+
+pub use router::*;
+pub use types::*;
+
+/// A handler which serves the metrics of the canister, including the ones of the instrumented
+/// methods, in the Prometheus text exposition format so they can be scraped, see [`ic::Metrics`].
+///
+/// ```ignore
+/// let router = Router::new().get("/metrics", http::metrics);
+/// ```
+///
+/// [`ic::Metrics`]: crate::ic::Metrics
+pub fn metrics(_: &HttpRequest, _: &Params) -> HttpResponse {
+    HttpResponse::ok(crate::ic::metrics().to_prometheus())
+        .with_header("Content-Type", "text/plain; version=0.0.4; charset=utf-8")
+}
+
+/// Decode the `%XX` escapes of a part of an url, and the `+` as a space if `plus_as_space` is set,
+/// which is the case in the query strings.
+fn percent_decode(input: &str, plus_as_space: bool) -> String {
+    let bytes = input.as_bytes();
+    let mut output = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let decoded = if bytes[i] == b'%' {
+            bytes
+                .get(i + 1..i + 3)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        } else {
+            None
+        };
+
+        match decoded {
+            Some(byte) => {
+                output.push(byte);
+                i += 3;
+            }
+            None => {
+                output.push(if plus_as_space && bytes[i] == b'+' {
+                    b' '
+                } else {
+                    bytes[i]
+                });
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&output).into_owned()
+}
@@ -0,0 +1,395 @@
+use super::percent_decode;
+use super::{HttpRequest, HttpResponse};
+
+/// The function which handles the requests of a route.
+pub type Handler = dyn Fn(&HttpRequest, &Params) -> HttpResponse;
+
+/// The parameters extracted from the path of a request, such as the `id` of `/users/:id`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Params {
+    params: Vec<(String, String)>,
+}
+
+impl Params {
+    /// Return the decoded value of the parameter with the given name.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Return an iterator over the names and the values of the parameters, in order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.params
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+}
+
+/// A segment of the path of a route.
+enum Segment {
+    /// A segment which must be equal to the one of the request.
+    Literal(String),
+    /// A `:name` segment, which matches any segment of the request.
+    Param(String),
+    /// A `*name` segment, which matches the rest of the path and must be the last one.
+    Wildcard(String),
+}
+
+struct Route {
+    method: String,
+    segments: Vec<Segment>,
+    /// If set, the route changes the state of the canister and is only handled as an update call.
+    update: bool,
+    handler: Box<Handler>,
+}
+
+/// Dispatch the requests to a handler by their method and their path.
+///
+/// The segments of the path of a route starting with a `:` match any segment and are passed to the
+/// handler as a parameter, and a last segment starting with a `*` matches the rest of the path. The
+/// routes are tried in the order they were added.
+///
+/// The routes added with [`Router::route_update`] and the `post`, `put`, `patch` and `delete`
+/// methods are handled as update calls, the response to the `http_request` query asks the gateway
+/// to upgrade the call to `http_request_update`.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    fallback: Option<Box<Handler>>,
+}
+
+impl Router {
+    /// Create a router without any route.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a route which is handled in the `http_request` query.
+    ///
+    /// # Panics
+    ///
+    /// If a wildcard is not the last segment of the path.
+    pub fn route<F>(self, method: &str, path: &str, handler: F) -> Self
+    where
+        F: Fn(&HttpRequest, &Params) -> HttpResponse + 'static,
+    {
+        self.add(method, path, false, Box::new(handler))
+    }
+
+    /// Add a route which is handled in the `http_request_update` update call.
+    ///
+    /// # Panics
+    ///
+    /// If a wildcard is not the last segment of the path.
+    pub fn route_update<F>(self, method: &str, path: &str, handler: F) -> Self
+    where
+        F: Fn(&HttpRequest, &Params) -> HttpResponse + 'static,
+    {
+        self.add(method, path, true, Box::new(handler))
+    }
+
+    /// Add a `GET` route, which is handled in the `http_request` query.
+    pub fn get<F>(self, path: &str, handler: F) -> Self
+    where
+        F: Fn(&HttpRequest, &Params) -> HttpResponse + 'static,
+    {
+        self.route("GET", path, handler)
+    }
+
+    /// Add a `POST` route, which is handled in the `http_request_update` update call.
+    pub fn post<F>(self, path: &str, handler: F) -> Self
+    where
+        F: Fn(&HttpRequest, &Params) -> HttpResponse + 'static,
+    {
+        self.route_update("POST", path, handler)
+    }
+
+    /// Add a `PUT` route, which is handled in the `http_request_update` update call.
+    pub fn put<F>(self, path: &str, handler: F) -> Self
+    where
+        F: Fn(&HttpRequest, &Params) -> HttpResponse + 'static,
+    {
+        self.route_update("PUT", path, handler)
+    }
+
+    /// Add a `PATCH` route, which is handled in the `http_request_update` update call.
+    pub fn patch<F>(self, path: &str, handler: F) -> Self
+    where
+        F: Fn(&HttpRequest, &Params) -> HttpResponse + 'static,
+    {
+        self.route_update("PATCH", path, handler)
+    }
+
+    /// Add a `DELETE` route, which is handled in the `http_request_update` update call.
+    pub fn delete<F>(self, path: &str, handler: F) -> Self
+    where
+        F: Fn(&HttpRequest, &Params) -> HttpResponse + 'static,
+    {
+        self.route_update("DELETE", path, handler)
+    }
+
+    /// Set the handler of the requests which don't match any route, instead of replying with a
+    /// `404 Not Found`. It's called in the `http_request` query.
+    pub fn fallback<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(&HttpRequest, &Params) -> HttpResponse + 'static,
+    {
+        self.fallback = Some(Box::new(handler));
+        self
+    }
+
+    /// Handle a request in the `http_request` query, or ask the gateway to upgrade it to an update
+    /// call if it matches an update route.
+    pub fn http_request(&self, request: HttpRequest) -> HttpResponse {
+        self.dispatch(request, false)
+    }
+
+    /// Handle a request in the `http_request_update` update call.
+    pub fn http_request_update(&self, request: HttpRequest) -> HttpResponse {
+        self.dispatch(request, true)
+    }
+
+    fn add(mut self, method: &str, path: &str, update: bool, handler: Box<Handler>) -> Self {
+        let segments: Vec<Segment> = split_path(path)
+            .map(|segment| {
+                if let Some(name) = segment.strip_prefix(':') {
+                    Segment::Param(name.to_string())
+                } else if let Some(name) = segment.strip_prefix('*') {
+                    Segment::Wildcard(name.to_string())
+                } else {
+                    Segment::Literal(percent_decode(segment, false))
+                }
+            })
+            .collect();
+
+        let wildcard = segments
+            .iter()
+            .position(|segment| matches!(segment, Segment::Wildcard(_)));
+
+        if let Some(index) = wildcard {
+            assert_eq!(
+                index,
+                segments.len() - 1,
+                "The wildcard must be the last segment of the route '{}'.",
+                path
+            );
+        }
+
+        self.routes.push(Route {
+            method: method.to_ascii_uppercase(),
+            segments,
+            update,
+            handler,
+        });
+
+        self
+    }
+
+    fn dispatch(&self, request: HttpRequest, in_update: bool) -> HttpResponse {
+        let segments: Vec<String> = split_path(request.path())
+            .map(|segment| percent_decode(segment, false))
+            .collect();
+
+        let mut allowed: Vec<&str> = vec![];
+
+        for route in &self.routes {
+            let params = match match_segments(&route.segments, &segments) {
+                Some(params) => params,
+                None => continue,
+            };
+
+            if !route.method.eq_ignore_ascii_case(&request.method) {
+                if !allowed.contains(&route.method.as_str()) {
+                    allowed.push(&route.method);
+                }
+                continue;
+            }
+
+            if route.update && !in_update {
+                return HttpResponse::upgrade();
+            }
+
+            return (route.handler)(&request, &params);
+        }
+
+        if !allowed.is_empty() {
+            return HttpResponse::method_not_allowed(&allowed);
+        }
+
+        match &self.fallback {
+            Some(handler) => handler(&request, &Params::default()),
+            None => HttpResponse::not_found(),
+        }
+    }
+}
+
+/// Split a path in its non-empty segments, so the trailing and the repeated slashes are ignored.
+fn split_path(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|segment| !segment.is_empty())
+}
+
+fn match_segments(route: &[Segment], path: &[String]) -> Option<Params> {
+    let mut params = Params::default();
+
+    for (index, segment) in route.iter().enumerate() {
+        match segment {
+            Segment::Wildcard(name) => {
+                params
+                    .params
+                    .push((name.clone(), path[index.min(path.len())..].join("/")));
+                return Some(params);
+            }
+            Segment::Literal(literal) if path.get(index) == Some(literal) => {}
+            Segment::Param(name) => match path.get(index) {
+                Some(value) => params.params.push((name.clone(), value.clone())),
+                None => return None,
+            },
+            Segment::Literal(_) => return None,
+        }
+    }
+
+    if route.len() == path.len() {
+        Some(params)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A handler replying with the name of the route and its parameters.
+    fn reply(name: &'static str) -> impl Fn(&HttpRequest, &Params) -> HttpResponse {
+        move |_, params| {
+            let params = params
+                .iter()
+                .map(|(key, value)| format!(" {}={}", key, value))
+                .collect::<String>();
+            HttpResponse::ok(format!("{}{}", name, params))
+        }
+    }
+
+    fn body(router: &Router, method: &str, url: &str) -> String {
+        let response = router.http_request_update(HttpRequest::new(method, url));
+        assert_eq!(response.status_code, 200, "{} {}", method, url);
+        String::from_utf8(response.body).unwrap()
+    }
+
+    #[test]
+    fn params() {
+        let router = Router::new()
+            .get("/", reply("index"))
+            .get("/users/:id", reply("user"))
+            .get("/users/:id/posts/:post", reply("post"));
+
+        assert_eq!(body(&router, "GET", "/"), "index");
+        assert_eq!(body(&router, "GET", "/users/7"), "user id=7");
+        assert_eq!(body(&router, "GET", "/users/7/"), "user id=7");
+        assert_eq!(body(&router, "GET", "//users//7?page=2"), "user id=7");
+        assert_eq!(body(&router, "GET", "/users/a%20b"), "user id=a b");
+        assert_eq!(body(&router, "GET", "/users/7/posts/3"), "post id=7 post=3");
+
+        let not_found = HttpResponse::not_found();
+        assert_eq!(
+            router.http_request(HttpRequest::new("GET", "/users")),
+            not_found
+        );
+        assert_eq!(
+            router.http_request(HttpRequest::new("GET", "/users/7/posts")),
+            not_found
+        );
+    }
+
+    #[test]
+    fn wildcards() {
+        let router = Router::new()
+            .get("/assets/*path", reply("asset"))
+            .get("/*rest", reply("rest"));
+
+        assert_eq!(
+            body(&router, "GET", "/assets/css/main.css"),
+            "asset path=css/main.css"
+        );
+        assert_eq!(body(&router, "GET", "/assets"), "asset path=");
+        assert_eq!(body(&router, "GET", "/other/page"), "rest rest=other/page");
+        assert_eq!(body(&router, "GET", "/"), "rest rest=");
+    }
+
+    #[test]
+    #[should_panic(expected = "The wildcard must be the last segment of the route '/*path/edit'.")]
+    fn wildcard_not_last() {
+        Router::new().get("/*path/edit", reply("edit"));
+    }
+
+    #[test]
+    fn routes_in_order() {
+        // The literal segment only wins over the parameter if its route was added first.
+        let router = Router::new()
+            .get("/users/me", reply("me"))
+            .get("/users/:id", reply("user"));
+
+        assert_eq!(body(&router, "GET", "/users/me"), "me");
+        assert_eq!(body(&router, "GET", "/users/7"), "user id=7");
+
+        let router = Router::new()
+            .get("/users/:id", reply("user"))
+            .get("/users/me", reply("me"));
+
+        assert_eq!(body(&router, "GET", "/users/me"), "user id=me");
+    }
+
+    #[test]
+    fn methods() {
+        let router = Router::new()
+            .get("/users/:id", reply("get"))
+            .delete("/users/:id", reply("delete"))
+            .post("/users", reply("create"))
+            .fallback(|_, _| HttpResponse::new(418, "fallback"));
+
+        assert_eq!(body(&router, "get", "/users/7"), "get id=7");
+        assert_eq!(body(&router, "DELETE", "/users/7"), "delete id=7");
+
+        // The path matches a route of another method.
+        assert_eq!(
+            router.http_request(HttpRequest::new("PUT", "/users/7")),
+            HttpResponse::method_not_allowed(&["GET", "DELETE"])
+        );
+
+        // The path does not match any route.
+        assert_eq!(
+            router
+                .http_request(HttpRequest::new("GET", "/posts"))
+                .status_code,
+            418
+        );
+        assert_eq!(
+            Router::new().http_request(HttpRequest::new("GET", "/posts")),
+            HttpResponse::not_found()
+        );
+    }
+
+    #[test]
+    fn upgrade() {
+        let router = Router::new()
+            .get("/counter", reply("get"))
+            .post("/counter", reply("increment"))
+            .route_update("GET", "/counter/reset", reply("reset"));
+
+        assert_eq!(
+            router.http_request(HttpRequest::new("POST", "/counter")),
+            HttpResponse::upgrade()
+        );
+        assert_eq!(
+            router.http_request(HttpRequest::new("GET", "/counter/reset")),
+            HttpResponse::upgrade()
+        );
+        assert_eq!(
+            router.http_request(HttpRequest::new("GET", "/counter")),
+            HttpResponse::ok("get")
+        );
+        assert_eq!(body(&router, "POST", "/counter"), "increment");
+    }
+}
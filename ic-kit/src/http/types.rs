@@ -0,0 +1,245 @@
+use super::percent_decode;
+use crate::ic;
+use candid::{CandidType, Func};
+use serde::Deserialize;
+
+/// A header, as a pair of a name and a value.
+pub type HeaderField = (String, String);
+
+/// A request forwarded by the HTTP gateway to `http_request` or `http_request_update`.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct HttpRequest {
+    /// The method of the request, such as `GET`.
+    pub method: String,
+    /// The path and the query string of the request, such as `/users?page=2`.
+    pub url: String,
+    /// The headers of the request.
+    pub headers: Vec<HeaderField>,
+    /// The body of the request.
+    pub body: Vec<u8>,
+    /// The version of the response certification the gateway supports, if any.
+    pub certificate_version: Option<u16>,
+}
+
+impl HttpRequest {
+    /// Create a request without any header or body.
+    pub fn new<M: Into<String>, U: Into<String>>(method: M, url: U) -> Self {
+        Self {
+            method: method.into(),
+            url: url.into(),
+            headers: vec![],
+            body: vec![],
+            certificate_version: None,
+        }
+    }
+
+    /// Add a header to the request.
+    pub fn with_header<N: Into<String>, V: Into<String>>(mut self, name: N, value: V) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Set the body of the request.
+    pub fn with_body<B: Into<Vec<u8>>>(mut self, body: B) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// Return the path of the url, without the query string.
+    pub fn path(&self) -> &str {
+        match self.url.find('?') {
+            Some(index) => &self.url[..index],
+            None => &self.url,
+        }
+    }
+
+    /// Return the decoded value of the first parameter of the query string with the given name.
+    pub fn query(&self, name: &str) -> Option<String> {
+        self.query_params()
+            .into_iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value)
+    }
+
+    /// Return the decoded parameters of the query string, in order.
+    pub fn query_params(&self) -> Vec<(String, String)> {
+        let query = match self.url.find('?') {
+            Some(index) => &self.url[index + 1..],
+            None => return vec![],
+        };
+
+        query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (key, value) = match pair.find('=') {
+                    Some(index) => (&pair[..index], &pair[index + 1..]),
+                    None => (pair, ""),
+                };
+                (percent_decode(key, true), percent_decode(value, true))
+            })
+            .collect()
+    }
+
+    /// Return the value of the first header with the given name, which is case insensitive.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// The response to an [`HttpRequest`].
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct HttpResponse {
+    /// The status code of the response.
+    pub status_code: u16,
+    /// The headers of the response.
+    pub headers: Vec<HeaderField>,
+    /// The body of the response, or its first chunk if it's streamed.
+    pub body: Vec<u8>,
+    /// If set, the gateway makes the request again as an update call to `http_request_update`.
+    pub upgrade: Option<bool>,
+    /// The strategy to fetch the rest of the body.
+    pub streaming_strategy: Option<StreamingStrategy>,
+}
+
+impl HttpResponse {
+    /// Create a response with the given status code and body.
+    pub fn new<B: Into<Vec<u8>>>(status_code: u16, body: B) -> Self {
+        Self {
+            status_code,
+            headers: vec![],
+            body: body.into(),
+            upgrade: None,
+            streaming_strategy: None,
+        }
+    }
+
+    /// Create a `200 OK` response.
+    pub fn ok<B: Into<Vec<u8>>>(body: B) -> Self {
+        Self::new(200, body)
+    }
+
+    /// Create a `400 Bad Request` response.
+    pub fn bad_request<B: Into<Vec<u8>>>(body: B) -> Self {
+        Self::new(400, body)
+    }
+
+    /// Create a `404 Not Found` response.
+    pub fn not_found() -> Self {
+        Self::new(404, "Not Found")
+    }
+
+    /// Create a `405 Method Not Allowed` response with the list of the allowed methods.
+    pub fn method_not_allowed(allowed: &[&str]) -> Self {
+        Self::new(405, "Method Not Allowed").with_header("Allow", allowed.join(", "))
+    }
+
+    /// Create a response asking the gateway to make the request again as an update call.
+    pub fn upgrade() -> Self {
+        Self {
+            upgrade: Some(true),
+            ..Self::new(200, Vec::new())
+        }
+    }
+
+    /// Add a header to the response.
+    pub fn with_header<N: Into<String>, V: Into<String>>(mut self, name: N, value: V) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Stream the rest of the body with the given query method of this canister, which is called
+    /// with the token and returns a [`StreamingCallbackHttpResponse`].
+    pub fn with_streaming<M: Into<String>>(mut self, callback: M, token: StreamingToken) -> Self {
+        self.streaming_strategy = Some(StreamingStrategy::Callback {
+            callback: Func {
+                principal: ic::id(),
+                method: callback.into(),
+            },
+            token,
+        });
+        self
+    }
+
+    /// Create a `200 OK` response with the first chunk of the body, and the rest of it streamed by
+    /// the given callback, which should reply with [`StreamingCallbackHttpResponse::chunk`].
+    pub fn chunked<M: Into<String>>(
+        body: &[u8],
+        chunk_size: usize,
+        callback: M,
+        key: &str,
+    ) -> Self {
+        let token = StreamingToken {
+            key: key.to_string(),
+            index: 0,
+        };
+
+        match StreamingCallbackHttpResponse::chunk(body, chunk_size, token) {
+            StreamingCallbackHttpResponse {
+                body,
+                token: Some(token),
+            } => Self::ok(body).with_streaming(callback, token),
+            StreamingCallbackHttpResponse { body, token: None } => Self::ok(body),
+        }
+    }
+}
+
+/// The strategy used by the gateway to fetch the rest of a body.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum StreamingStrategy {
+    /// Call the query `callback` with the token until it does not return a new one.
+    Callback {
+        callback: Func,
+        token: StreamingToken,
+    },
+}
+
+/// The token passed to the streaming callback, which identifies the body and the next chunk.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, CandidType, Deserialize)]
+pub struct StreamingToken {
+    /// The key of the streamed body, such as the path of an asset.
+    pub key: String,
+    /// The index of the next chunk.
+    pub index: u64,
+}
+
+/// The response of a streaming callback.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct StreamingCallbackHttpResponse {
+    /// The chunk of the body.
+    pub body: Vec<u8>,
+    /// The token to fetch the next chunk, or `None` if this is the last one.
+    pub token: Option<StreamingToken>,
+}
+
+impl StreamingCallbackHttpResponse {
+    /// Return the chunk of the body at the index of the token, and the token of the next chunk if
+    /// there is one.
+    ///
+    /// # Panics
+    ///
+    /// If the chunk size is zero.
+    pub fn chunk(body: &[u8], chunk_size: usize, token: StreamingToken) -> Self {
+        assert!(chunk_size > 0, "The chunk size must not be zero.");
+
+        let start = (token.index as usize)
+            .saturating_mul(chunk_size)
+            .min(body.len());
+        let end = start.saturating_add(chunk_size).min(body.len());
+
+        Self {
+            body: body[start..end].to_vec(),
+            token: if end < body.len() {
+                Some(StreamingToken {
+                    key: token.key,
+                    index: token.index + 1,
+                })
+            } else {
+                None
+            },
+        }
+    }
+}
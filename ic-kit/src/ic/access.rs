@@ -0,0 +1,75 @@
+use crate::ic::{maybe_with, swap, with, with_mut};
+use candid::{CandidType, Principal};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+
+/// A type that decides which roles each principal has, this is used by the `#[only(...)]`
+/// attribute to check the caller. By default the [`Roles`] stored in the canister's memory are
+/// used, a custom provider can be set using [`set_role_provider`].
+pub trait RoleProvider {
+    /// Returns true if the principal has the given role.
+    fn has_role(&self, principal: &Principal, role: &str) -> bool;
+}
+
+/// The default role provider, which keeps the members of each role in the canister's memory.
+///
+/// The roles can be modified using [`grant_role`] and [`revoke_role`], and since this type
+/// implements [`CandidType`], it can be persisted across upgrades as a part of the canister's
+/// state.
+#[derive(Default, Clone, Debug, CandidType, Deserialize)]
+pub struct Roles {
+    roles: HashMap<String, HashSet<Principal>>,
+}
+
+impl Roles {
+    /// Give the role to the principal, returns false if the principal already had the role.
+    pub fn grant<S: Into<String>>(&mut self, role: S, principal: Principal) -> bool {
+        self.roles.entry(role.into()).or_default().insert(principal)
+    }
+
+    /// Remove the role from the principal, returns false if the principal did not have the role.
+    pub fn revoke(&mut self, role: &str, principal: &Principal) -> bool {
+        match self.roles.get_mut(role) {
+            Some(members) => members.remove(principal),
+            None => false,
+        }
+    }
+
+    /// Return the members of the given role.
+    pub fn members(&self, role: &str) -> impl Iterator<Item = &Principal> {
+        self.roles.get(role).into_iter().flatten()
+    }
+}
+
+impl RoleProvider for Roles {
+    fn has_role(&self, principal: &Principal, role: &str) -> bool {
+        self.roles
+            .get(role)
+            .map(|members| members.contains(principal))
+            .unwrap_or(false)
+    }
+}
+
+/// The custom role provider set by the canister.
+struct CustomRoleProvider(Box<dyn RoleProvider>);
+
+/// Use the given role provider instead of the default [`Roles`] to check the roles of the callers.
+pub fn set_role_provider<P: RoleProvider + 'static>(provider: P) {
+    swap(CustomRoleProvider(Box::new(provider)));
+}
+
+/// Returns true if the principal has the given role, using the current role provider.
+pub fn has_role(principal: &Principal, role: &str) -> bool {
+    maybe_with(|provider: &CustomRoleProvider| provider.0.has_role(principal, role))
+        .unwrap_or_else(|| with(|roles: &Roles| roles.has_role(principal, role)))
+}
+
+/// Give the role to the principal in the default [`Roles`] provider.
+pub fn grant_role<S: Into<String>>(role: S, principal: Principal) -> bool {
+    with_mut(|roles: &mut Roles| roles.grant(role, principal))
+}
+
+/// Remove the role from the principal in the default [`Roles`] provider.
+pub fn revoke_role(role: &str, principal: &Principal) -> bool {
+    with_mut(|roles: &mut Roles| roles.revoke(role, principal))
+}
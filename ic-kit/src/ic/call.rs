@@ -1,14 +1,75 @@
 use crate::futures;
 use crate::futures::CallFuture;
-use crate::ic::Cycles;
+use crate::ic::shared;
+use crate::ic::{spawn, time, Cycles};
 use crate::utils::arg_data_raw;
 use candid::utils::{ArgumentDecoder, ArgumentEncoder};
 use candid::{decode_args, decode_one, encode_args, encode_one, CandidType, Principal};
 use ic_kit_sys::ic0;
 use serde::de::DeserializeOwned;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::time::Duration;
+
+pub use ic_kit_sys::types::{CallError, RejectionCode, CANDID_EMPTY_ARG};
+
+thread_local! {
+    // The number of calls made by the message being executed.
+    static MESSAGE_CALLS: Cell<u32> = const { Cell::new(0) };
+    // The id of the incoming call whose message is being executed, and the id of the next one.
+    static CALL_CONTEXT: Cell<u64> = const { Cell::new(0) };
+    static NEXT_CALL_CONTEXT: Cell<u64> = const { Cell::new(1) };
+}
+
+/// Return the number of calls made so far by the message being executed, a method which awaits
+/// is executed in more than one message, and each of them starts from zero.
+pub fn message_calls() -> u32 {
+    MESSAGE_CALLS.with(|calls| calls.get())
+}
+
+/// Reset the number of calls made by the message and start a new call context, this is called at
+/// the start of each message by the methods generated by the macros and by the callbacks of the
+/// calls, the futures resumed by a callback then restore the context they were spawned in.
+#[doc(hidden)]
+pub fn begin_message() {
+    MESSAGE_CALLS.with(|calls| calls.set(0));
+    let context = NEXT_CALL_CONTEXT.with(|next| next.replace(next.get() + 1));
+    CALL_CONTEXT.with(|current| current.set(context));
+}
+
+/// Return the id of the incoming call the message being executed belongs to, which is the same
+/// for the messages executed after an await.
+pub(crate) fn call_context() -> u64 {
+    CALL_CONTEXT.with(|context| context.get())
+}
 
-use ic_kit_sys::types::RejectionCode;
-pub use ic_kit_sys::types::{CallError, CANDID_EMPTY_ARG};
+/// Set the id of the current call context, and return the previous one.
+pub(crate) fn set_call_context(context: u64) -> u64 {
+    CALL_CONTEXT.with(|current| current.replace(context))
+}
+
+/// Count a call the message is about to make, returns false if the `CallBudget` of the message
+/// fails the calls over its limit.
+fn acquire_call() -> bool {
+    MESSAGE_CALLS.with(|calls| {
+        let allowed = crate::guards::check_call_budget(calls.get());
+        if allowed {
+            calls.set(calls.get() + 1);
+        }
+        allowed
+    })
+}
+
+/// Create a call to a query method of another canister, which can only be performed from a
+/// `#[query(composite)]` method or from the callbacks of its calls. The callee runs the call with
+/// a query or a composite query method, its changes to the state are discarded, and the call can
+/// not attach cycles. The `shared` and `timeout` options make the canister call itself with an
+/// update, so they can not be used with a query call.
+pub fn query_call<S: Into<String>>(canister_id: Principal, method_name: S) -> CallBuilder {
+    let mut call = CallBuilder::new(canister_id, method_name);
+    call.query = true;
+    call
+}
 
 /// A call builder that let's you create an inter-canister call which can be then sent to the
 /// destination.
@@ -17,6 +78,9 @@ pub struct CallBuilder {
     method_name: String,
     payment: Cycles,
     arg: Option<Vec<u8>>,
+    shared: bool,
+    timeout: Option<Duration>,
+    query: bool,
 }
 
 impl CallBuilder {
@@ -28,6 +92,9 @@ impl CallBuilder {
             method_name: method_name.into(),
             payment: 0,
             arg: None,
+            shared: false,
+            timeout: None,
+            query: false,
         }
     }
 
@@ -102,11 +169,48 @@ impl CallBuilder {
         self
     }
 
+    /// Share the response of the call with the identical calls the canister makes while it's in
+    /// flight, so a hot lookup such as an exchange rate is only sent once. The calls with the same
+    /// callee, method, argument and payment which are made with this option before the response
+    /// arrives wait for it instead of being sent, and all of them get the same response. The
+    /// waiting calls check for the response after each round with a call the canister makes to
+    /// itself, which is cheaper than sending them to another canister.
+    ///
+    /// This only applies to the methods returning the response, such as `perform` and
+    /// `perform_raw`, and is meant for the calls which don't change the state of the callee.
+    pub fn shared(mut self) -> Self {
+        self.shared = true;
+        self
+    }
+
+    /// Stop waiting for the response of the call once the given duration has passed, and return
+    /// [`CallError::TimedOut`] instead.
+    ///
+    /// The call is not cancelled, since the Internet Computer can not cancel a call once it's
+    /// sent, it's still pending and may be executed by the callee, so the caller must treat its
+    /// outcome as unknown, such as by checking the state of the callee later. The response which
+    /// arrives after the timeout is dropped.
+    ///
+    /// Without a timer, the canister checks the time after each round with a call it makes to
+    /// itself, which costs cycles for as long as the call is pending, and the timeout is reported
+    /// on the first check after the deadline, in a later message.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
     /// Should be called after the `ic0::call_new` to set the call arguments.
     #[inline(always)]
     unsafe fn ic0_internal_call_perform(&self) -> i32 {
+        if self.query && self.payment > 0 {
+            crate::ic::trap("A query call can not attach cycles.");
+        }
+
+        // The cycles can not be added to the calls of a composite query, even zero.
         #[cfg(not(feature = "experimental-cycles128"))]
-        ic0::call_cycles_add(self.payment as i64);
+        if self.payment > 0 {
+            ic0::call_cycles_add(self.payment as i64);
+        }
 
         #[cfg(feature = "experimental-cycles128")]
         if self.payment > 0 && self.payment < (u64::MAX as u128) {
@@ -134,6 +238,10 @@ impl CallBuilder {
     /// This method traps if the amount determined in the `payment` is larger than the canister's
     /// balance at the time of invocation.
     pub fn perform_one_way(self) -> Result<(), RejectionCode> {
+        if !acquire_call() {
+            return Err(RejectionCode::SysTransient);
+        }
+
         let callee = self.canister_id.as_slice();
         let method = self.method_name.as_str();
 
@@ -189,6 +297,18 @@ impl CallBuilder {
     /// This method traps if the amount determined in the `payment` is larger than the canister's
     /// balance at the time of invocation.
     pub async fn perform_rejection(&self) -> Result<(), CallError> {
+        if self.timeout.is_some() {
+            return self.perform_raw_internal().await.map(|_| ());
+        }
+
+        self.perform_rejection_internal().await
+    }
+
+    async fn perform_rejection_internal(&self) -> Result<(), CallError> {
+        if !acquire_call() {
+            return Err(CallError::CouldNotSend);
+        }
+
         let future = self.perform_internal();
 
         // if the future is already ready, it indicates a `ic0::call_perform` non-zero response.
@@ -199,24 +319,14 @@ impl CallBuilder {
         // await for the call to comeback.
         future.await;
 
-        let rejection_code = unsafe { ic0::msg_reject_code() };
-        if rejection_code == 0 {
+        let rejection_code = crate::ic::reject_code();
+        if rejection_code == RejectionCode::NoError {
             return Ok(());
         }
 
-        let rejection_message_size = unsafe { ic0::msg_reject_msg_size() } as usize;
-        let mut bytes = vec![0u8; rejection_message_size];
-        unsafe {
-            ic0::msg_reject_msg_copy(
-                bytes.as_mut_ptr() as isize,
-                0,
-                rejection_message_size as isize,
-            );
-        }
-
         Err(CallError::Rejected(
-            rejection_code.into(),
-            String::from_utf8_lossy(&bytes).to_string(),
+            rejection_code,
+            crate::ic::reject_message(),
         ))
     }
 
@@ -227,8 +337,67 @@ impl CallBuilder {
     /// This method traps if the amount determined in the `payment` is larger than the canister's
     /// balance at the time of invocation.
     pub async fn perform_raw(&self) -> Result<Vec<u8>, CallError> {
-        self.perform_rejection().await?;
-        Ok(arg_data_raw())
+        if !self.shared {
+            return self.perform_raw_internal().await;
+        }
+
+        let key = (
+            self.canister_id,
+            self.method_name.clone(),
+            self.arg
+                .clone()
+                .unwrap_or_else(|| CANDID_EMPTY_ARG.to_vec()),
+            self.payment,
+        );
+
+        shared::perform_shared(key, || Box::pin(self.perform_raw_internal())).await
+    }
+
+    async fn perform_raw_internal(&self) -> Result<Vec<u8>, CallError> {
+        match self.timeout {
+            Some(timeout) => self.perform_with_timeout(timeout).await,
+            None => {
+                self.perform_rejection_internal().await?;
+                Ok(arg_data_raw())
+            }
+        }
+    }
+
+    /// Make the call in a task of its own, which stores the response when it arrives, so this
+    /// task can stop waiting for it and return once the deadline has passed.
+    async fn perform_with_timeout(&self, timeout: Duration) -> Result<Vec<u8>, CallError> {
+        let deadline = time().saturating_add(timeout.as_nanos() as u64);
+        let response = Rc::new(RefCell::new(None));
+
+        let call = CallBuilder {
+            canister_id: self.canister_id,
+            method_name: self.method_name.clone(),
+            payment: self.payment,
+            arg: self.arg.clone(),
+            shared: false,
+            timeout: None,
+            query: self.query,
+        };
+        let slot = response.clone();
+        spawn(async move {
+            let result = call
+                .perform_rejection_internal()
+                .await
+                .map(|_| arg_data_raw());
+            *slot.borrow_mut() = Some(result);
+        });
+
+        loop {
+            if let Some(result) = response.borrow_mut().take() {
+                return result;
+            }
+
+            if time() >= deadline {
+                return Err(CallError::TimedOut);
+            }
+
+            yield_now().await;
+        }
     }
 
     /// Perform the call and return a future which will resolve to the candid decoded response. Or
@@ -269,3 +438,12 @@ impl CallBuilder {
         }
     }
 }
+
+/// Wait for a later message, by calling a method which does not exist on the canister itself so
+/// the call is rejected right away. The code after the await runs in a new message, with a new
+/// instruction limit, and the other calls can be executed in between.
+pub async fn yield_now() {
+    let _ = CallBuilder::new(crate::ic::id(), "__ic_kit_yield")
+        .perform_rejection_internal()
+        .await;
+}
@@ -82,6 +82,13 @@ pub fn caller() -> Principal {
     Principal::try_from(&bytes).unwrap()
 }
 
+/// Returns true if the given principal is one of the controllers of the canister.
+#[inline(always)]
+pub fn is_controller(principal: &Principal) -> bool {
+    let bytes = principal.as_slice();
+    unsafe { ic0::is_controller(bytes.as_ptr() as isize, bytes.len() as isize) == 1 }
+}
+
 /// Set the certified data of the canister, this method traps if data.len > 32.
 #[inline(always)]
 pub fn set_certified_data(data: &[u8]) {
@@ -0,0 +1,67 @@
+use crate::ic::data_certificate;
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+/// The response of a certified query, which is a value along with the certificate of the data
+/// certified by the canister and the witness, the part of the hash tree of the canister which
+/// proves the value.
+///
+/// # Example
+///
+/// ```ignore
+/// use ic_kit::prelude::*;
+/// use ic_kit_certified::{AsHashTree, Map};
+///
+/// #[derive(Default)]
+/// struct Balances(Map<String, u64>);
+///
+/// #[update]
+/// fn mint(balances: &mut Balances, to: Principal, amount: u64) {
+///     balances.0.insert(to.to_text(), amount);
+///     ic::set_certified_data(&balances.0.root_hash());
+/// }
+///
+/// #[query]
+/// fn balance(balances: &Balances, user: Principal) -> Certified<u64> {
+///     let user = user.to_text();
+///     let amount = balances.0.get(&user).copied().unwrap_or_default();
+///     Certified::new(amount, &balances.0.witness(&user))
+/// }
+/// ```
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Certified<T> {
+    /// The value of the response.
+    pub value: T,
+    /// The certificate of the data certified by the canister, `None` if the query was executed
+    /// as an update, which is not certified this way.
+    pub certificate: Option<Vec<u8>>,
+    /// The CBOR encoding of the witness of the value.
+    pub witness: Vec<u8>,
+}
+
+impl<T> Certified<T> {
+    /// Create the response with the given hash tree as the witness of the value, this must be
+    /// called in a query to include the certificate.
+    pub fn new<W: Serialize>(value: T, witness: &W) -> Self {
+        Self {
+            value,
+            certificate: data_certificate(),
+            witness: serde_cbor::to_vec(witness).expect("Could not encode the witness."),
+        }
+    }
+
+    /// Verify the certificate and the witness of the response to a query made to the given
+    /// canister in the runtime, and return the witness, in which the value can be looked up.
+    #[cfg(not(target_family = "wasm"))]
+    pub fn verify(
+        &self,
+        canister_id: &candid::Principal,
+    ) -> Result<ic_kit_runtime::certification::HashTree, String> {
+        let certificate = self
+            .certificate
+            .as_ref()
+            .ok_or("The response does not have a certificate.")?;
+
+        ic_kit_runtime::certification::verify(canister_id, certificate, &self.witness)
+    }
+}
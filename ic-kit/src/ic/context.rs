@@ -0,0 +1,117 @@
+use crate::ic::{caller, msg_cycles_available, Cycles};
+use crate::utils::{arg_data_raw, method_name};
+use candid::Principal;
+
+/// The entry point of the canister which is handling a request.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum EntryMode {
+    Init,
+    PreUpgrade,
+    PostUpgrade,
+    InspectMessage,
+    Heartbeat,
+    Update,
+    Query,
+    CompositeQuery,
+}
+
+/// The context of the request handled by an entry point, which is passed to the methods that
+/// have a `RequestContext` parameter instead of them calling `ic::caller()` and the other system
+/// APIs, so they can be unit tested as plain functions.
+///
+/// # Example
+///
+/// ```ignore
+/// use ic_kit::prelude::*;
+///
+/// #[update]
+/// fn whoami(ctx: RequestContext) -> Principal {
+///     ctx.caller
+/// }
+///
+/// #[test]
+/// fn test_whoami() {
+///     let ctx = RequestContext::new("whoami", ic::EntryMode::Update).with_caller(*users::ALICE);
+///     assert_eq!(whoami(ctx), *users::ALICE);
+/// }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestContext {
+    /// The principal who has made the request, the anonymous principal in a heartbeat.
+    pub caller: Principal,
+    /// The raw candid arguments of the request, empty in the entry points that take none.
+    pub arg_data: Vec<u8>,
+    /// The cycles attached to the request before a payable method accepts them, zero unless the
+    /// request is an update call.
+    pub cycles_available: Cycles,
+    /// The candid name of the method, or the name of the lifecycle hook such as `init`, except in
+    /// `inspect_message`, where it's the name of the method being inspected.
+    pub method_name: String,
+    /// The entry point handling the request.
+    pub mode: EntryMode,
+}
+
+impl RequestContext {
+    /// Create a context for a request made by the anonymous principal with no arguments and no
+    /// cycles.
+    pub fn new<S: Into<String>>(method_name: S, mode: EntryMode) -> Self {
+        Self {
+            caller: Principal::anonymous(),
+            arg_data: Vec::new(),
+            cycles_available: 0,
+            method_name: method_name.into(),
+            mode,
+        }
+    }
+
+    /// Use the given principal as the caller.
+    pub fn with_caller(mut self, caller: Principal) -> Self {
+        self.caller = caller;
+        self
+    }
+
+    /// Use the given bytes as the raw arguments of the request.
+    pub fn with_arg_data(mut self, arg_data: Vec<u8>) -> Self {
+        self.arg_data = arg_data;
+        self
+    }
+
+    /// Attach the given amount of cycles to the request.
+    pub fn with_cycles(mut self, cycles: Cycles) -> Self {
+        self.cycles_available = cycles;
+        self
+    }
+
+    /// Return the context of the current request, only reading what the system makes available
+    /// to the given entry point, this is called by the methods generated by the macros.
+    #[doc(hidden)]
+    pub fn current(name: &str, mode: EntryMode) -> Self {
+        let caller = match mode {
+            EntryMode::Heartbeat => Principal::anonymous(),
+            _ => caller(),
+        };
+
+        let arg_data = match mode {
+            EntryMode::PreUpgrade | EntryMode::Heartbeat => Vec::new(),
+            _ => arg_data_raw(),
+        };
+
+        let cycles_available = match mode {
+            EntryMode::Update => msg_cycles_available(),
+            _ => 0,
+        };
+
+        let method_name = match mode {
+            EntryMode::InspectMessage => method_name(),
+            _ => name.to_string(),
+        };
+
+        Self {
+            caller,
+            arg_data,
+            cycles_available,
+            method_name,
+            mode,
+        }
+    }
+}
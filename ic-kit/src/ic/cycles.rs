@@ -65,3 +65,51 @@ pub fn msg_cycles_refunded() -> Cycles {
         u128::from_le(recv)
     }
 }
+
+/// The cycles accepted by a method marked as `payable`, a `payable` method can receive this
+/// amount by having a parameter of this type.
+///
+/// # Example
+///
+/// ```ignore
+/// use ic_kit::prelude::*;
+///
+/// #[update(payable)]
+/// fn deposit(payment: ic::Payment) -> Cycles {
+///     payment.amount()
+/// }
+/// ```
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Payment(pub Cycles);
+
+impl Payment {
+    /// Accept all of the cycles attached to the current message.
+    pub fn accept_all() -> Self {
+        Self(msg_cycles_accept(msg_cycles_available()))
+    }
+
+    /// Accept the cycles attached to the current message up to the given amount, the rest is
+    /// refunded to the caller.
+    pub fn accept_up_to(max_amount: Cycles) -> Self {
+        Self(msg_cycles_accept(max_amount))
+    }
+
+    /// The amount of the accepted cycles.
+    pub fn amount(&self) -> Cycles {
+        self.0
+    }
+}
+
+/// Used by the update methods that are not `payable`. When the `strict-payable` feature is
+/// enabled, this rejects the message and returns false if the caller has attached any cycles.
+#[doc(hidden)]
+#[inline(always)]
+pub fn check_no_payment() -> bool {
+    #[cfg(feature = "strict-payable")]
+    if msg_cycles_available() > 0 {
+        crate::utils::reject("This method does not accept cycles.");
+        return false;
+    }
+
+    true
+}
@@ -0,0 +1,108 @@
+use std::cell::Cell;
+use std::fmt;
+
+use crate::ic::trap;
+use crate::utils;
+
+/// The failure to decode the candid arguments of an incoming call.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DecodeError {
+    /// The candid name of the method which was called.
+    pub method: String,
+    /// The raw arguments of the call.
+    pub arg_data: Vec<u8>,
+    /// The error returned by candid.
+    pub message: String,
+}
+
+/// What the canister does with a call whose arguments could not be decoded.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DecodeErrorPolicy {
+    /// Trap, the call is rejected by the system with the message of the trap, which includes the
+    /// [`DecodeError`].
+    Trap,
+    /// Reject the call with the [`DecodeError`] as the message, this is the default.
+    Reject,
+}
+
+type Hook = fn(&DecodeError) -> DecodeErrorPolicy;
+
+thread_local! {
+    static HOOK: Cell<Option<Hook>> = Cell::new(None);
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Could not decode the arguments of method '{}' ({} bytes): {}",
+            self.method,
+            self.arg_data.len(),
+            self.message
+        )
+    }
+}
+
+/// Call the given function when the arguments of a call to a method of the canister can not be
+/// decoded, and apply the policy it returns, such as to log the error or to trap instead of
+/// rejecting the call.
+///
+/// The hook is not called for the methods which set the `decode_error` attribute, which picks the
+/// policy of the method or a fallback function which handles the call instead:
+///
+/// ```ignore
+/// #[update(decode_error = "trap")]
+/// fn transfer(to: Principal, amount: u64) {}
+///
+/// #[update(decode_error = "legacy_transfer")]
+/// fn transfer_v2(to: Principal, amount: u64, memo: u64) {}
+///
+/// // Must reply or reject, such as after decoding the arguments in an older format.
+/// fn legacy_transfer(error: DecodeError) {}
+/// ```
+///
+/// Registering another hook replaces the previous one.
+pub fn on_decode_error(hook: fn(&DecodeError) -> DecodeErrorPolicy) {
+    HOOK.with(|h| h.set(Some(hook)));
+}
+
+/// Return an error if the raw arguments of the current call are larger than the limit, so a method
+/// can refuse an oversized argument from its guard, which runs before the arguments are decoded,
+/// instead of spending the instructions to decode it. Unlike the `inspect_message` hook, the guard
+/// also runs for the calls made by the other canisters.
+///
+/// ```ignore
+/// fn small_chunk() -> Result<(), String> {
+///     ic::check_arg_size(64 * 1024)
+/// }
+///
+/// #[update(guard = "small_chunk")]
+/// fn upload(chunk: Vec<u8>) {}
+/// ```
+pub fn check_arg_size(limit: usize) -> Result<(), String> {
+    let size = utils::arg_data_size();
+
+    if size > limit {
+        return Err(format!(
+            "The argument of {} bytes exceeds the limit of {} bytes.",
+            size, limit
+        ));
+    }
+
+    Ok(())
+}
+
+/// Handle the error of a method which has no fallback function, with the policy set by its
+/// attribute, or the one returned by the hook, this is called by the methods generated by the
+/// macros.
+#[doc(hidden)]
+pub fn handle_decode_error(error: DecodeError, policy: Option<DecodeErrorPolicy>) {
+    let policy = policy
+        .or_else(|| HOOK.with(|h| h.get()).map(|hook| hook(&error)))
+        .unwrap_or(DecodeErrorPolicy::Reject);
+
+    match policy {
+        DecodeErrorPolicy::Trap => trap(&error.to_string()),
+        DecodeErrorPolicy::Reject => utils::reject(&error.to_string()),
+    }
+}
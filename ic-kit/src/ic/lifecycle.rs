@@ -0,0 +1,115 @@
+use std::any::TypeId;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::ic::maybe_with_mut;
+
+type Callback = Rc<dyn Fn()>;
+
+/// The scope of the value the callback is called with, if it's stored by a library crate, and the
+/// type of the value, see [`crate::ic::scoped`].
+pub(crate) type CallbackKey = (Option<TypeId>, TypeId);
+
+#[derive(Default)]
+struct Callbacks {
+    pre_upgrade: Vec<(CallbackKey, Callback)>,
+    post_upgrade: Vec<(CallbackKey, Callback)>,
+}
+
+thread_local! {
+    static CALLBACKS: RefCell<Callbacks> = RefCell::new(Callbacks::default());
+}
+
+/// Call the given function with the value of type `T` in the storage, before the `pre_upgrade`
+/// hook of the canister, so a component such as a cache can prepare its state for the upgrade
+/// without the canister having to call it from its own hook.
+///
+/// The callbacks run in the order they were registered, before the body of the `#[pre_upgrade]`
+/// function, and are skipped if there is no value of type `T`. A type only has one callback,
+/// registering another one replaces it.
+///
+/// # Example
+///
+/// ```
+/// use ic_kit::ic;
+///
+/// #[derive(Default)]
+/// struct Cache {
+///     entries: Vec<u64>,
+/// }
+///
+/// ic::on_pre_upgrade(|cache: &mut Cache| cache.entries.clear());
+/// ```
+pub fn on_pre_upgrade<T: 'static>(callback: fn(&mut T)) {
+    add_pre_upgrade((None, TypeId::of::<T>()), move || {
+        maybe_with_mut(callback);
+    });
+}
+
+/// Call the given function with the value of type `T` in the storage, after the `post_upgrade`
+/// hook of the canister.
+///
+/// The heap of the canister is lost during the upgrade, so only the callbacks registered by the
+/// new build run, which are the ones registered before the end of its `#[post_upgrade]` function,
+/// such as by a component set up from it. They run in the order they were registered, after the
+/// body of the function, and are skipped if there is no value of type `T`. A type only has one
+/// callback, registering another one replaces it.
+pub fn on_post_upgrade<T: 'static>(callback: fn(&mut T)) {
+    add_post_upgrade((None, TypeId::of::<T>()), move || {
+        maybe_with_mut(callback);
+    });
+}
+
+/// Register a callback which runs before the `pre_upgrade` hook, replacing the one with the
+/// same key.
+pub(crate) fn add_pre_upgrade<F: Fn() + 'static>(key: CallbackKey, callback: F) {
+    CALLBACKS.with(|callbacks| {
+        register(
+            &mut callbacks.borrow_mut().pre_upgrade,
+            key,
+            Rc::new(callback),
+        );
+    });
+}
+
+/// Register a callback which runs after the `post_upgrade` hook, replacing the one with the
+/// same key.
+pub(crate) fn add_post_upgrade<F: Fn() + 'static>(key: CallbackKey, callback: F) {
+    CALLBACKS.with(|callbacks| {
+        register(
+            &mut callbacks.borrow_mut().post_upgrade,
+            key,
+            Rc::new(callback),
+        );
+    });
+}
+
+fn register(callbacks: &mut Vec<(CallbackKey, Callback)>, key: CallbackKey, callback: Callback) {
+    match callbacks.iter_mut().find(|(other, _)| *other == key) {
+        Some((_, existing)) => *existing = callback,
+        None => callbacks.push((key, callback)),
+    }
+}
+
+/// Run the callbacks registered with [`on_pre_upgrade`], this is called by the `pre_upgrade`
+/// hook generated by the macros.
+#[doc(hidden)]
+pub fn run_pre_upgrade_callbacks() {
+    let callbacks = CALLBACKS.with(|callbacks| callbacks.borrow().pre_upgrade.clone());
+    run(callbacks);
+}
+
+/// Run the callbacks registered with [`on_post_upgrade`], this is called by the `post_upgrade`
+/// hook generated by the macros.
+#[doc(hidden)]
+pub fn run_post_upgrade_callbacks() {
+    let callbacks = CALLBACKS.with(|callbacks| callbacks.borrow().post_upgrade.clone());
+    run(callbacks);
+}
+
+// The list is cloned first so the callbacks can register other callbacks.
+fn run(callbacks: Vec<(CallbackKey, Callback)>) {
+    for (_, callback) in callbacks {
+        callback();
+    }
+}
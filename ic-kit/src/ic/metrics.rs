@@ -0,0 +1,560 @@
+use crate::ic::{time, with, with_mut};
+use crate::utils::performance_counter;
+use candid::CandidType;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// A metric family in the Prometheus exposition: its name, description and value getter.
+type Family = (&'static str, &'static str, fn(&EndpointMetrics) -> u64);
+
+/// The metrics collected for an endpoint marked with the `instrument` flag.
+#[derive(Default, Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct EndpointMetrics {
+    /// The number of calls to the endpoint.
+    pub calls: u64,
+    /// The number of calls that were rejected or returned an `Err`.
+    pub errors: u64,
+    /// The accumulated number of instructions used by the endpoint, for async endpoints only the
+    /// instructions executed after the last await are counted.
+    pub instructions: u64,
+    /// The accumulated time in nanoseconds between the start and the end of each call, which is
+    /// only non-zero for async endpoints.
+    pub latency: u64,
+}
+
+/// The names and the values of the labels of a series, sorted by name.
+type Labels = Vec<(String, String)>;
+
+/// The registry of the metrics of the canister, with the ones collected for the instrumented
+/// endpoints and the counters, gauges and histograms registered by the canister.
+///
+/// The registry is kept in the storage and can be updated with [`with_mut`], or saved to the
+/// stable storage with the rest of the state:
+///
+/// ```ignore
+/// with_mut(|metrics: &mut Metrics| {
+///     metrics
+///         .counter("transfers_total", "The number of transfers.")
+///         .inc_with(&[("token", "ICP")], 1);
+/// });
+/// ```
+#[derive(Default, Clone, Debug, CandidType, Deserialize)]
+pub struct Metrics {
+    endpoints: BTreeMap<String, EndpointMetrics>,
+    families: BTreeMap<String, MetricFamily>,
+}
+
+/// A metric registered in [`Metrics`].
+#[derive(Clone, Debug, CandidType, Deserialize)]
+enum MetricFamily {
+    Counter(Counter),
+    Gauge(Gauge),
+    Histogram(Histogram),
+}
+
+/// A value which only goes up, such as a number of requests.
+#[derive(Default, Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct Counter {
+    help: String,
+    series: BTreeMap<Labels, u64>,
+}
+
+/// A value which can go up and down, such as the size of a queue.
+#[derive(Default, Clone, Debug, PartialEq, CandidType, Deserialize)]
+pub struct Gauge {
+    help: String,
+    series: BTreeMap<Labels, f64>,
+}
+
+/// The distribution of the observed values, such as the sizes of the requests, counted in
+/// buckets.
+#[derive(Default, Clone, Debug, PartialEq, CandidType, Deserialize)]
+pub struct Histogram {
+    help: String,
+    /// The sorted upper bounds of the buckets, without the `+Inf` one.
+    buckets: Vec<f64>,
+    series: BTreeMap<Labels, HistogramSeries>,
+}
+
+#[derive(Default, Clone, Debug, PartialEq, CandidType, Deserialize)]
+struct HistogramSeries {
+    /// The number of values in each bucket, not cumulative.
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Counter {
+    /// Increment the counter by one.
+    pub fn inc(&mut self) {
+        self.inc_with(&[], 1);
+    }
+
+    /// Increment the counter by the given amount.
+    pub fn inc_by(&mut self, amount: u64) {
+        self.inc_with(&[], amount);
+    }
+
+    /// Increment the series of the counter with the given labels by the given amount.
+    pub fn inc_with(&mut self, labels: &[(&str, &str)], amount: u64) {
+        let value = self.series.entry(to_labels(labels)).or_default();
+        *value = value.saturating_add(amount);
+    }
+
+    /// Return the value of the counter.
+    pub fn get(&self) -> u64 {
+        self.get_with(&[])
+    }
+
+    /// Return the value of the series with the given labels.
+    pub fn get_with(&self, labels: &[(&str, &str)]) -> u64 {
+        self.series.get(&to_labels(labels)).copied().unwrap_or(0)
+    }
+}
+
+impl Gauge {
+    /// Set the value of the gauge.
+    pub fn set(&mut self, value: f64) {
+        self.set_with(&[], value);
+    }
+
+    /// Set the value of the series of the gauge with the given labels.
+    pub fn set_with(&mut self, labels: &[(&str, &str)], value: f64) {
+        self.series.insert(to_labels(labels), value);
+    }
+
+    /// Add the given amount to the gauge, which may be negative.
+    pub fn add(&mut self, amount: f64) {
+        self.add_with(&[], amount);
+    }
+
+    /// Add the given amount to the series of the gauge with the given labels.
+    pub fn add_with(&mut self, labels: &[(&str, &str)], amount: f64) {
+        *self.series.entry(to_labels(labels)).or_default() += amount;
+    }
+
+    /// Return the value of the gauge.
+    pub fn get(&self) -> f64 {
+        self.get_with(&[])
+    }
+
+    /// Return the value of the series with the given labels.
+    pub fn get_with(&self, labels: &[(&str, &str)]) -> f64 {
+        self.series.get(&to_labels(labels)).copied().unwrap_or(0.0)
+    }
+}
+
+impl Histogram {
+    /// Observe a value.
+    pub fn observe(&mut self, value: f64) {
+        self.observe_with(&[], value);
+    }
+
+    /// Observe a value in the series of the histogram with the given labels.
+    pub fn observe_with(&mut self, labels: &[(&str, &str)], value: f64) {
+        let buckets = &self.buckets;
+        let series = self
+            .series
+            .entry(to_labels(labels))
+            .or_insert_with(|| HistogramSeries {
+                counts: vec![0; buckets.len()],
+                sum: 0.0,
+                count: 0,
+            });
+
+        if let Some(index) = buckets.iter().position(|bound| value <= *bound) {
+            series.counts[index] += 1;
+        }
+
+        series.sum += value;
+        series.count += 1;
+    }
+
+    /// Return the number of observed values.
+    pub fn count(&self) -> u64 {
+        self.count_with(&[])
+    }
+
+    /// Return the number of values observed in the series with the given labels.
+    pub fn count_with(&self, labels: &[(&str, &str)]) -> u64 {
+        self.series
+            .get(&to_labels(labels))
+            .map(|series| series.count)
+            .unwrap_or(0)
+    }
+
+    /// Return the sum of the observed values.
+    pub fn sum(&self) -> f64 {
+        self.sum_with(&[])
+    }
+
+    /// Return the sum of the values observed in the series with the given labels.
+    pub fn sum_with(&self, labels: &[(&str, &str)]) -> f64 {
+        self.series
+            .get(&to_labels(labels))
+            .map(|series| series.sum)
+            .unwrap_or(0.0)
+    }
+}
+
+impl Metrics {
+    /// Return the metrics of the given endpoint.
+    pub fn get(&self, endpoint: &str) -> Option<&EndpointMetrics> {
+        self.endpoints.get(endpoint)
+    }
+
+    /// Iterate over the metrics of all of the endpoints.
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &EndpointMetrics)> {
+        self.endpoints.iter()
+    }
+
+    /// Return the counter with the given name, which is registered with the help text if it does
+    /// not exist yet.
+    ///
+    /// # Panics
+    ///
+    /// If the name is not a valid Prometheus metric name, or is already used by another type of
+    /// metric.
+    pub fn counter(&mut self, name: &str, help: &str) -> &mut Counter {
+        match self.family(name, || {
+            MetricFamily::Counter(Counter {
+                help: help.to_string(),
+                series: BTreeMap::new(),
+            })
+        }) {
+            MetricFamily::Counter(counter) => counter,
+            _ => panic!("The metric '{}' is not a counter.", name),
+        }
+    }
+
+    /// Return the gauge with the given name, which is registered with the help text if it does not
+    /// exist yet.
+    ///
+    /// # Panics
+    ///
+    /// If the name is not a valid Prometheus metric name, or is already used by another type of
+    /// metric.
+    pub fn gauge(&mut self, name: &str, help: &str) -> &mut Gauge {
+        match self.family(name, || {
+            MetricFamily::Gauge(Gauge {
+                help: help.to_string(),
+                series: BTreeMap::new(),
+            })
+        }) {
+            MetricFamily::Gauge(gauge) => gauge,
+            _ => panic!("The metric '{}' is not a gauge.", name),
+        }
+    }
+
+    /// Return the histogram with the given name, which is registered with the help text and the
+    /// upper bounds of its buckets if it does not exist yet.
+    ///
+    /// # Panics
+    ///
+    /// If the name is not a valid Prometheus metric name, or is already used by another type of
+    /// metric.
+    pub fn histogram(&mut self, name: &str, help: &str, buckets: &[f64]) -> &mut Histogram {
+        match self.family(name, || {
+            let mut buckets: Vec<f64> = buckets.iter().copied().filter(|b| b.is_finite()).collect();
+            buckets.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            buckets.dedup();
+
+            MetricFamily::Histogram(Histogram {
+                help: help.to_string(),
+                buckets,
+                series: BTreeMap::new(),
+            })
+        }) {
+            MetricFamily::Histogram(histogram) => histogram,
+            _ => panic!("The metric '{}' is not a histogram.", name),
+        }
+    }
+
+    fn family<F: FnOnce() -> MetricFamily>(&mut self, name: &str, create: F) -> &mut MetricFamily {
+        assert!(
+            is_valid_name(name),
+            "'{}' is not a valid metric name.",
+            name
+        );
+
+        self.families.entry(name.to_string()).or_insert_with(create)
+    }
+
+    /// Render the metrics as a human readable text with one line per endpoint.
+    pub fn to_text(&self) -> String {
+        let mut result = String::new();
+
+        for (name, m) in &self.endpoints {
+            writeln!(
+                result,
+                "{}: calls={} errors={} instructions={} latency={}ns",
+                name, m.calls, m.errors, m.instructions, m.latency
+            )
+            .unwrap();
+        }
+
+        for (name, family) in &self.families {
+            match family {
+                MetricFamily::Counter(counter) => {
+                    for (labels, value) in &counter.series {
+                        writeln!(result, "{}{}: {}", name, render_labels(labels, None), value)
+                            .unwrap();
+                    }
+                }
+                MetricFamily::Gauge(gauge) => {
+                    for (labels, value) in &gauge.series {
+                        writeln!(result, "{}{}: {}", name, render_labels(labels, None), value)
+                            .unwrap();
+                    }
+                }
+                MetricFamily::Histogram(histogram) => {
+                    for (labels, series) in &histogram.series {
+                        writeln!(
+                            result,
+                            "{}{}: count={} sum={}",
+                            name,
+                            render_labels(labels, None),
+                            series.count,
+                            series.sum
+                        )
+                        .unwrap();
+                    }
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Render the metrics in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut result = String::new();
+
+        let families: [Family; 4] = [
+            ("calls", "The number of calls to the endpoint.", |m| m.calls),
+            (
+                "errors",
+                "The number of failed calls to the endpoint.",
+                |m| m.errors,
+            ),
+            (
+                "instructions",
+                "The instructions used by the endpoint.",
+                |m| m.instructions,
+            ),
+            (
+                "latency_nanoseconds",
+                "The time spent by the endpoint.",
+                |m| m.latency,
+            ),
+        ];
+
+        for (name, help, value) in families.iter() {
+            writeln!(result, "# HELP canister_endpoint_{} {}", name, help).unwrap();
+            writeln!(result, "# TYPE canister_endpoint_{} counter", name).unwrap();
+
+            for (endpoint, m) in &self.endpoints {
+                writeln!(
+                    result,
+                    "canister_endpoint_{}{{endpoint=\"{}\"}} {}",
+                    name,
+                    endpoint,
+                    value(m)
+                )
+                .unwrap();
+            }
+        }
+
+        for (name, family) in &self.families {
+            let (kind, help) = match family {
+                MetricFamily::Counter(counter) => ("counter", &counter.help),
+                MetricFamily::Gauge(gauge) => ("gauge", &gauge.help),
+                MetricFamily::Histogram(histogram) => ("histogram", &histogram.help),
+            };
+
+            writeln!(result, "# HELP {} {}", name, escape(help, false)).unwrap();
+            writeln!(result, "# TYPE {} {}", name, kind).unwrap();
+
+            match family {
+                MetricFamily::Counter(counter) => {
+                    for (labels, value) in &counter.series {
+                        writeln!(result, "{}{} {}", name, render_labels(labels, None), value)
+                            .unwrap();
+                    }
+                }
+                MetricFamily::Gauge(gauge) => {
+                    for (labels, value) in &gauge.series {
+                        writeln!(
+                            result,
+                            "{}{} {}",
+                            name,
+                            render_labels(labels, None),
+                            render_f64(*value)
+                        )
+                        .unwrap();
+                    }
+                }
+                MetricFamily::Histogram(histogram) => {
+                    for (labels, series) in &histogram.series {
+                        let mut cumulative = 0;
+
+                        for (bound, count) in histogram.buckets.iter().zip(&series.counts) {
+                            cumulative += count;
+                            let le = render_f64(*bound);
+                            writeln!(
+                                result,
+                                "{}_bucket{} {}",
+                                name,
+                                render_labels(labels, Some(&le)),
+                                cumulative
+                            )
+                            .unwrap();
+                        }
+
+                        writeln!(
+                            result,
+                            "{}_bucket{} {}",
+                            name,
+                            render_labels(labels, Some("+Inf")),
+                            series.count
+                        )
+                        .unwrap();
+                        writeln!(
+                            result,
+                            "{}_sum{} {}",
+                            name,
+                            render_labels(labels, None),
+                            render_f64(series.sum)
+                        )
+                        .unwrap();
+                        writeln!(
+                            result,
+                            "{}_count{} {}",
+                            name,
+                            render_labels(labels, None),
+                            series.count
+                        )
+                        .unwrap();
+                    }
+                }
+            }
+        }
+
+        result
+    }
+}
+
+fn to_labels(labels: &[(&str, &str)]) -> Labels {
+    let mut labels: Labels = labels
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+    labels.sort();
+    labels
+}
+
+/// Check the name against the `[a-zA-Z_:][a-zA-Z0-9_:]*` format of the Prometheus metric names.
+fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' || c == ':' => {}
+        _ => return false,
+    }
+
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+/// Render the labels of a series between braces, with the `le` label of a histogram bucket.
+fn render_labels(labels: &[(String, String)], le: Option<&str>) -> String {
+    let mut parts: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape(value, true)))
+        .collect();
+
+    if let Some(le) = le {
+        parts.push(format!("le=\"{}\"", le));
+    }
+
+    if parts.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", parts.join(","))
+    }
+}
+
+/// Escape the backslashes and the line feeds of a help text, and the double quotes of a label
+/// value.
+fn escape(text: &str, quotes: bool) -> String {
+    let mut result = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '"' if quotes => result.push_str("\\\""),
+            c => result.push(c),
+        }
+    }
+
+    result
+}
+
+/// Render a float the way Prometheus parses it.
+fn render_f64(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value == f64::INFINITY {
+        "+Inf".to_string()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+/// Return a copy of the metrics collected so far.
+pub fn metrics() -> Metrics {
+    with(Metrics::clone)
+}
+
+/// Record a new call to the endpoint, returns the current time which should be passed to
+/// [`record_completion`]. This is used by the code generated for the `instrument` flag.
+#[doc(hidden)]
+pub fn record_call(endpoint: &str) -> u64 {
+    with_mut(|metrics: &mut Metrics| {
+        metrics
+            .endpoints
+            .entry(endpoint.to_string())
+            .or_default()
+            .calls += 1;
+    });
+
+    time()
+}
+
+/// Record a failed call to the endpoint.
+#[doc(hidden)]
+pub fn record_error(endpoint: &str) {
+    with_mut(|metrics: &mut Metrics| {
+        metrics
+            .endpoints
+            .entry(endpoint.to_string())
+            .or_default()
+            .errors += 1;
+    });
+}
+
+/// Record the end of a call to the endpoint which started at the given time.
+#[doc(hidden)]
+pub fn record_completion(endpoint: &str, started_at: u64) {
+    let instructions = performance_counter(0);
+    let latency = time().saturating_sub(started_at);
+
+    with_mut(|metrics: &mut Metrics| {
+        let m = metrics.endpoints.entry(endpoint.to_string()).or_default();
+        m.instructions += instructions;
+        m.latency += latency;
+    });
+}
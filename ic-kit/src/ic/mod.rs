@@ -1,13 +1,30 @@
+mod access;
 mod call;
 mod canister;
+mod certified;
+mod context;
 mod cycles;
+mod decode;
+mod lifecycle;
+mod metrics;
+mod random;
+mod reply;
+mod shared;
 mod spawn;
 mod stable;
 mod storage;
 
+pub use access::*;
 pub use call::*;
 pub use canister::*;
+pub use certified::*;
+pub use context::*;
 pub use cycles::*;
+pub use decode::*;
+pub use lifecycle::*;
+pub use metrics::*;
+pub use random::*;
+pub use reply::*;
 pub use spawn::*;
 pub use stable::*;
 pub use storage::*;
@@ -0,0 +1,60 @@
+use crate::ic::{scoped, time, CallBuilder, CallError};
+use candid::Principal;
+
+/// The scope of the entropy cached by [`round_entropy`].
+struct Random;
+
+/// The entropy of the last round [`round_entropy`] was called in.
+#[derive(Default)]
+struct RoundEntropy {
+    time: u64,
+    bytes: Option<[u8; 32]>,
+}
+
+/// Return 32 random bytes from the `raw_rand` method of the management canister.
+///
+/// In the tests the bytes come from the randomness beacon of the replica, so every call made in
+/// the same round returns the same bytes, and the bytes change with the round.
+pub async fn raw_rand() -> Result<Vec<u8>, CallError> {
+    CallBuilder::new(Principal::management_canister(), "raw_rand")
+        .perform_one::<Vec<u8>>()
+        .await
+}
+
+/// Return 32 random bytes which are the same for all of the messages executed in the current
+/// round, which is identified by [`time`]. The bytes are fetched with [`raw_rand`] in the first
+/// message of the round which needs them and are cached, so the following messages of the round
+/// do not make any call.
+///
+/// If several messages of a round wait for the bytes at once, the first reply is kept so they all
+/// get the same bytes.
+pub async fn round_entropy() -> Result<[u8; 32], CallError> {
+    let round = time();
+
+    if let Some(bytes) = cached(round) {
+        return Ok(bytes);
+    }
+
+    let bytes = raw_rand().await?;
+    let mut entropy = [0; 32];
+    let len = bytes.len().min(32);
+    entropy[..len].copy_from_slice(&bytes[..len]);
+
+    Ok(
+        scoped::<Random>().with_mut(|cache: &mut RoundEntropy| match cache.bytes {
+            Some(bytes) if cache.time == round => bytes,
+            _ => {
+                cache.time = round;
+                cache.bytes = Some(entropy);
+                entropy
+            }
+        }),
+    )
+}
+
+fn cached(round: u64) -> Option<[u8; 32]> {
+    scoped::<Random>().with(|cache: &RoundEntropy| match cache.bytes {
+        Some(bytes) if cache.time == round => Some(bytes),
+        _ => None,
+    })
+}
@@ -0,0 +1,141 @@
+use crate::utils;
+use candid::utils::ArgumentEncoder;
+use candid::{encode_args, encode_one, CandidType};
+use std::marker::PhantomData;
+
+use ic_kit_sys::types::RejectionCode;
+
+pub use ic_kit_sys::types::{MAX_QUERY_REPLY_SIZE, MAX_REPLY_SIZE};
+
+/// Reply to the current call with the given candid tuple.
+///
+/// This is only meant to be used in methods marked with `manual_reply`, since the other methods
+/// reply with their return value.
+///
+/// # Panics
+///
+/// If the value can not be encoded using candid.
+pub fn reply<T: ArgumentEncoder>(reply: T) {
+    let bytes = encode_args(reply).expect("Could not encode the reply.");
+    utils::reply(&bytes);
+}
+
+/// Reply to the current call using the provided raw buffer without doing any serialization.
+pub fn reply_raw(buf: &[u8]) {
+    utils::reply(buf);
+}
+
+/// Reject the current call with the given message.
+pub fn reject<S: AsRef<str>>(message: S) {
+    utils::reject(message.as_ref());
+}
+
+/// Return the code of the response to the call a callback is handling, which is
+/// [`RejectionCode::NoError`](crate::ic::RejectionCode::NoError) if the call was replied to.
+///
+/// This is only meant to be used when handling the response of a call manually, the results of
+/// the [`CallBuilder`](crate::ic::CallBuilder) methods already contain the rejection.
+pub fn reject_code() -> RejectionCode {
+    utils::reject_code().into()
+}
+
+/// Return the message of the rejection a callback is handling.
+///
+/// # Traps
+///
+/// If the call was replied to, since there is no message.
+pub fn reject_message() -> String {
+    utils::reject_message()
+}
+
+/// Return the size of the candid encoding of the value, which is the size of the reply of a
+/// method returning it.
+///
+/// # Panics
+///
+/// If the value can not be encoded using candid.
+pub fn encoded_size<T: CandidType + ?Sized>(value: &T) -> usize {
+    encode_one(value)
+        .expect("Could not encode the value.")
+        .len()
+}
+
+/// Return an error if the candid encoding of the value is larger than the limit, such as
+/// [`MAX_REPLY_SIZE`].
+pub fn check_reply_size<T: CandidType + ?Sized>(value: &T, limit: usize) -> Result<(), String> {
+    let size = encoded_size(value);
+
+    if size > limit {
+        return Err(format!(
+            "The reply of {} bytes exceeds the limit of {} bytes.",
+            size, limit
+        ));
+    }
+
+    Ok(())
+}
+
+/// Remove the last items of the vector until its candid encoding fits in the limit. Returns the
+/// number of items removed.
+pub fn truncate_to_fit<T: CandidType>(items: &mut Vec<T>, limit: usize) -> usize {
+    if encoded_size(items.as_slice()) <= limit {
+        return 0;
+    }
+
+    // The largest prefix which fits, the empty prefix is always kept.
+    let (mut low, mut high) = (0, items.len() - 1);
+    while low < high {
+        let mid = high - (high - low) / 2;
+
+        if encoded_size(&items[..mid]) <= limit {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+
+    let removed = items.len() - low;
+    items.truncate(low);
+    removed
+}
+
+/// The return type of a method with the `manual_reply` flag, the type `T` is only used to generate
+/// the candid interface of the method and the actual response must be sent using one of the
+/// [`reply`], [`reply_raw`] or [`reject`] methods.
+///
+/// # Example
+///
+/// ```ignore
+/// use ic_kit::prelude::*;
+///
+/// #[update(manual_reply)]
+/// fn get_name() -> ManualReply<String> {
+///     ManualReply::one("Alice")
+/// }
+/// ```
+pub struct ManualReply<T: ?Sized>(PhantomData<T>);
+
+impl<T: ?Sized> ManualReply<T> {
+    /// Return without sending a reply, the reply must be sent by some other code path later.
+    pub const fn empty() -> Self {
+        Self(PhantomData)
+    }
+
+    /// Reply with the given value and return.
+    pub fn one<U: CandidType>(value: U) -> Self {
+        reply((value,));
+        Self::empty()
+    }
+
+    /// Reply with the given candid tuple and return.
+    pub fn all<U: ArgumentEncoder>(value: U) -> Self {
+        reply(value);
+        Self::empty()
+    }
+
+    /// Reject the call with the given message and return.
+    pub fn reject<S: AsRef<str>>(message: S) -> Self {
+        reject(message);
+        Self::empty()
+    }
+}
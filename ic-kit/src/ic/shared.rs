@@ -0,0 +1,116 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use candid::Principal;
+
+use crate::ic::{yield_now, CallError, Cycles};
+
+type Response = Result<Vec<u8>, CallError>;
+
+/// The callee, the method, the argument and the payment of a call.
+pub(crate) type CallKey = (Principal, String, Vec<u8>, Cycles);
+
+#[derive(Default)]
+struct SharedCall {
+    response: Option<Response>,
+    abandoned: bool,
+}
+
+thread_local! {
+    static SHARED_CALLS: RefCell<HashMap<CallKey, Rc<RefCell<SharedCall>>>> =
+        RefCell::new(HashMap::new());
+}
+
+/// Return the response of the call in flight with the same key, or make the call with the given
+/// future and share its response with the identical calls made until it's resolved.
+///
+/// The code after a call can only run in a callback of the call context which made it, so the
+/// calls waiting for the response can't be resumed by the one which gets it. Instead they check
+/// for it after each round, with a call the canister makes to itself.
+pub(crate) async fn perform_shared<'a, F>(key: CallKey, perform: F) -> Response
+where
+    F: FnOnce() -> Pin<Box<dyn Future<Output = Response> + 'a>>,
+{
+    let call = loop {
+        let existing = SHARED_CALLS.with(|calls| calls.borrow().get(&key).cloned());
+
+        let call = match existing {
+            Some(call) => call,
+            None => break Rc::new(RefCell::new(SharedCall::default())),
+        };
+
+        loop {
+            if let Some(response) = &call.borrow().response {
+                return response.clone();
+            }
+
+            if call.borrow().abandoned {
+                break;
+            }
+
+            yield_now().await;
+        }
+    };
+
+    SHARED_CALLS.with(|calls| calls.borrow_mut().insert(key.clone(), call.clone()));
+
+    Lead {
+        key,
+        call,
+        future: perform(),
+        done: false,
+    }
+    .await
+}
+
+/// The future of the call which is actually made.
+struct Lead<'a> {
+    key: CallKey,
+    call: Rc<RefCell<SharedCall>>,
+    future: Pin<Box<dyn Future<Output = Response> + 'a>>,
+    done: bool,
+}
+
+impl Future for Lead<'_> {
+    type Output = Response;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let response = match self.future.as_mut().poll(cx) {
+            Poll::Ready(response) => response,
+            Poll::Pending => return Poll::Pending,
+        };
+
+        self.done = true;
+        self.call.borrow_mut().response = Some(response.clone());
+        remove(&self.key, &self.call);
+
+        Poll::Ready(response)
+    }
+}
+
+impl Drop for Lead<'_> {
+    // If the task is dropped before the response, such as when its callback traps, the calls
+    // waiting for it make the call again.
+    fn drop(&mut self) {
+        if !self.done {
+            self.call.borrow_mut().abandoned = true;
+            remove(&self.key, &self.call);
+        }
+    }
+}
+
+fn remove(key: &CallKey, call: &Rc<RefCell<SharedCall>>) {
+    SHARED_CALLS.with(|calls| {
+        let mut calls = calls.borrow_mut();
+        if calls
+            .get(key)
+            .map_or(false, |other| Rc::ptr_eq(other, call))
+        {
+            calls.remove(key);
+        }
+    });
+}
@@ -1,5 +1,15 @@
+use crate::ic::trap;
 use ic_kit_sys::ic0;
 use ic_kit_sys::types::StableMemoryError;
+use std::cell::{Cell, RefCell};
+use std::ops::Range;
+
+thread_local! {
+    // Whether the raw writes are checked against the reserved regions.
+    static CHECK_WRITES: Cell<bool> = const { Cell::new(false) };
+    // The regions of the stable memory used by the stable structures, sorted and merged.
+    static RESERVED: RefCell<Vec<Range<u64>>> = const { RefCell::new(Vec::new()) };
+}
 
 /// A type which represents either a page count or an offset in the stable storage, it's a u64
 /// when the `experimental-stable64` feature is enabled, otherwise a u32.
@@ -11,6 +21,14 @@ pub type StableSize = u64;
 #[cfg(not(feature = "experimental-stable64"))]
 pub type StableSize = u32;
 
+/// Whether the 32-bit stable memory API can be used for the given page count or offset, it's
+/// cheaper below 4GiB but it's not available to the wasm64 canisters.
+#[cfg(feature = "experimental-stable64")]
+#[inline(always)]
+fn fits_stable32(n: u64) -> bool {
+    !cfg!(feature = "experimental-wasm64") && n < (u32::MAX as u64 - 1)
+}
+
 /// Returns the current size of the stable memory in WebAssembly pages.
 /// Note: One WebAssembly page is 64KiB
 #[inline(always)]
@@ -44,7 +62,7 @@ pub fn stable_grow(new_pages: StableSize) -> Result<StableSize, StableMemoryErro
 
     #[cfg(feature = "experimental-stable64")]
     unsafe {
-        match if new_pages < (u32::MAX as u64 - 1) {
+        match if fits_stable32(new_pages) {
             ic0::stable_grow(new_pages as i32) as i64
         } else {
             ic0::stable64_grow(new_pages as i64) as i64
@@ -56,8 +74,81 @@ pub fn stable_grow(new_pages: StableSize) -> Result<StableSize, StableMemoryErro
 }
 
 /// Writes data to the stable memory location specified by an offset.
+///
+/// # Traps
+///
+/// If the check enabled by [`set_stable_write_check`] finds that the write overlaps a region
+/// used by the stable structures.
 #[inline(always)]
 pub fn stable_write(offset: StableSize, buf: &[u8]) {
+    if CHECK_WRITES.with(|check| check.get()) {
+        check_raw_write(offset as u64, buf.len() as u64);
+    }
+
+    write_reserved(offset, buf)
+}
+
+/// Trap on the calls to [`stable_write`] which overlap a region of the stable memory used by the
+/// stable structures, such as the header and the buckets of the `MemoryManager`, instead of
+/// letting them corrupt the structures. The check is disabled by default, it's meant for the
+/// tests and the staging deployments of the canisters which also write to the stable memory
+/// directly.
+pub fn set_stable_write_check(enabled: bool) {
+    CHECK_WRITES.with(|check| check.set(enabled));
+}
+
+/// Mark the given range of the stable memory as used by a stable structure, so the raw writes to
+/// it are caught by the check of [`set_stable_write_check`]. The stable structures stored
+/// directly in the stable memory reserve the space they grow to, a custom structure can reserve
+/// its own regions.
+pub fn reserve_stable_region(range: Range<u64>) {
+    if range.start >= range.end {
+        return;
+    }
+
+    RESERVED.with(|reserved| {
+        let mut reserved = reserved.borrow_mut();
+        reserved.push(range);
+        reserved.sort_by_key(|range| range.start);
+
+        let mut merged: Vec<Range<u64>> = Vec::with_capacity(reserved.len());
+        for range in reserved.drain(..) {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+
+        *reserved = merged;
+    });
+}
+
+/// Return the regions of the stable memory reserved by the stable structures, sorted and merged.
+pub fn reserved_stable_regions() -> Vec<Range<u64>> {
+    RESERVED.with(|reserved| reserved.borrow().clone())
+}
+
+fn check_raw_write(offset: u64, len: u64) {
+    let end = offset.saturating_add(len);
+    let collision = RESERVED.with(|reserved| {
+        reserved
+            .borrow()
+            .iter()
+            .find(|range| offset < range.end && range.start < end)
+            .cloned()
+    });
+
+    if let Some(range) = collision {
+        trap(&format!(
+            "stable_write of {} bytes at the offset {} overlaps the region {}..{} used by the stable structures.",
+            len, offset, range.start, range.end
+        ));
+    }
+}
+
+/// Same as [`stable_write`] without the check, used by the stable structures themselves.
+#[inline(always)]
+pub(crate) fn write_reserved(offset: StableSize, buf: &[u8]) {
     #[cfg(not(feature = "experimental-stable64"))]
     unsafe {
         ic0::stable_write(offset as i32, buf.as_ptr() as isize, buf.len() as isize)
@@ -65,7 +156,7 @@ pub fn stable_write(offset: StableSize, buf: &[u8]) {
 
     #[cfg(feature = "experimental-stable64")]
     unsafe {
-        if offset < (u32::MAX as u64 - 1) {
+        if fits_stable32(offset) {
             ic0::stable_write(offset as i32, buf.as_ptr() as isize, buf.len() as isize)
         } else {
             ic0::stable64_write(offset as i64, buf.as_ptr() as i64, buf.len() as i64)
@@ -83,7 +174,7 @@ pub fn stable_read(offset: StableSize, buf: &mut [u8]) {
 
     #[cfg(feature = "experimental-stable64")]
     unsafe {
-        if offset < (u32::MAX as u64 - 1) {
+        if fits_stable32(offset) {
             ic0::stable_read(buf.as_ptr() as isize, offset as i32, buf.len() as isize);
         } else {
             ic0::stable64_read(buf.as_ptr() as i64, offset as i64, buf.len() as i64);
@@ -91,12 +182,53 @@ pub fn stable_read(offset: StableSize, buf: &mut [u8]) {
     }
 }
 
+/// Reads `len` bytes of the stable memory starting at the given offset.
+pub fn stable_read_range(offset: StableSize, len: usize) -> Vec<u8> {
+    let mut bytes = vec![0; len];
+    stable_read(offset, &mut bytes);
+    bytes
+}
+
+/// Returns an iterator over the pages of the stable memory, so the whole memory can be processed
+/// without copying it to the heap at once.
+pub fn stable_pages() -> StablePages {
+    StablePages {
+        page: 0,
+        count: stable_size(),
+    }
+}
+
+/// An iterator over the pages of the stable memory, returned by [`stable_pages`]. Each item is
+/// the content of a page of 64KiB.
+pub struct StablePages {
+    page: StableSize,
+    count: StableSize,
+}
+
+impl Iterator for StablePages {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.page == self.count {
+            return None;
+        }
+
+        let bytes = stable_read_range(self.page << 16, 1 << 16);
+        self.page += 1;
+        Some(bytes)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = (self.count - self.page) as usize;
+        (remaining, Some(remaining))
+    }
+}
+
+impl ExactSizeIterator for StablePages {}
+
+/// Returns a copy of the entire stable memory, which should only be used for small memories, see
+/// [`stable_read_range`] and [`stable_pages`] otherwise.
 pub(crate) fn stable_bytes() -> Vec<u8> {
     let size = (stable_size() as usize) << 16;
-    let mut vec = Vec::with_capacity(size);
-    unsafe {
-        ic0::stable_read(vec.as_ptr() as isize, 0, size as isize);
-        vec.set_len(size);
-    }
-    vec
+    stable_read_range(0, size)
 }
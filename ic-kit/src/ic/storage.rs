@@ -1,7 +1,17 @@
+use std::any::TypeId;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::rc::Rc;
+
+use super::lifecycle::{add_post_upgrade, add_pre_upgrade};
 use crate::storage::{BorrowMany, BorrowMutMany, Storage};
 
+pub use crate::storage::{StateRef, StateRefMut};
+
 thread_local! {
     static STORAGE: Storage = Storage::default();
+    static SCOPES: RefCell<HashMap<TypeId, Rc<Storage>>> = RefCell::new(HashMap::new());
 }
 
 /// Pass an immutable reference to the value associated with the given type to the closure.
@@ -40,6 +50,34 @@ pub fn maybe_with_mut<T: 'static, U, F: FnOnce(&mut T) -> U>(callback: F) -> Opt
     STORAGE.with(|storage| storage.maybe_with_mut(callback))
 }
 
+/// Return a guard which holds an immutable reference to the value associated with the given type
+/// until it's dropped, the default value is inserted first if there is no value.
+///
+/// Unlike the reference passed to the closure of [`with`], the guard can be kept in an async
+/// method, but the value can not be borrowed mutably or taken while it's held, which panics with
+/// the name of the type. In the debug builds, using the guard after an await traps, since another
+/// message may have needed the value in the meantime:
+///
+/// ```ignore
+/// let balances = ic::borrow::<Balances>();
+/// let balance = balances.get(&user);
+/// drop(balances);
+///
+/// ledger.transfer(user, balance).await;
+///
+/// // Borrow the state again after the await.
+/// ic::borrow_mut::<Balances>().remove(&user);
+/// ```
+pub fn borrow<T: 'static + Default>() -> StateRef<T> {
+    STORAGE.with(|storage| storage.borrow())
+}
+
+/// Like [`borrow`], but the guard holds a mutable reference, and the value can not be borrowed at
+/// all while it's held.
+pub fn borrow_mut<T: 'static + Default>() -> StateRefMut<T> {
+    STORAGE.with(|storage| storage.borrow_mut())
+}
+
 /// Remove the current value associated with the type and return it.
 pub fn take<T: 'static>() -> Option<T> {
     STORAGE.with(|storage| storage.take::<T>())
@@ -102,3 +140,115 @@ pub fn with_many<A: BorrowMany, U, F: FnOnce(A) -> U>(callback: F) -> U {
 pub fn with_many_mut<A: BorrowMutMany, U, F: FnOnce(A) -> U>(callback: F) -> U {
     STORAGE.with(|storage| storage.with_many_mut(callback))
 }
+
+/// Return the storage of a library crate, which is identified by a type of the crate, such as a
+/// marker type. The values stored in a scope are apart from the ones stored by the canister and by
+/// the other scopes, so a library such as a cache or a rate limiter can keep its state in a type
+/// which the canister, or another library, may also store.
+///
+/// # Example
+///
+/// ```
+/// use ic_kit::ic;
+///
+/// /// The scope of the values stored by the rate limiter.
+/// struct RateLimiter;
+///
+/// #[derive(Default)]
+/// struct State {
+///     calls: u64,
+/// }
+///
+/// ic::scoped::<RateLimiter>().with_mut(|state: &mut State| state.calls += 1);
+///
+/// // The canister's own `State` is another value.
+/// assert_eq!(ic::with(|state: &State| state.calls), 0);
+/// ```
+pub fn scoped<S: 'static>() -> Scope<S> {
+    Scope {
+        _scope: PhantomData,
+    }
+}
+
+/// The storage of a library crate, see [`scoped`]. It has the same methods as the storage of the
+/// canister, such as [`with`] and [`borrow_mut`].
+pub struct Scope<S: 'static> {
+    _scope: PhantomData<S>,
+}
+
+impl<S: 'static> Scope<S> {
+    fn storage(&self) -> Rc<Storage> {
+        SCOPES.with(|scopes| {
+            scopes
+                .borrow_mut()
+                .entry(TypeId::of::<S>())
+                .or_default()
+                .clone()
+        })
+    }
+
+    /// Like [`with`], in this scope.
+    pub fn with<T: 'static + Default, U, F: FnOnce(&T) -> U>(&self, callback: F) -> U {
+        self.storage().with(callback)
+    }
+
+    /// Like [`maybe_with`], in this scope.
+    pub fn maybe_with<T: 'static, U, F: FnOnce(&T) -> U>(&self, callback: F) -> Option<U> {
+        self.storage().maybe_with(callback)
+    }
+
+    /// Like [`with_mut`], in this scope.
+    pub fn with_mut<T: 'static + Default, U, F: FnOnce(&mut T) -> U>(&self, callback: F) -> U {
+        self.storage().with_mut(callback)
+    }
+
+    /// Like [`maybe_with_mut`], in this scope.
+    pub fn maybe_with_mut<T: 'static, U, F: FnOnce(&mut T) -> U>(&self, callback: F) -> Option<U> {
+        self.storage().maybe_with_mut(callback)
+    }
+
+    /// Like [`borrow`], in this scope.
+    pub fn borrow<T: 'static + Default>(&self) -> StateRef<T> {
+        self.storage().borrow()
+    }
+
+    /// Like [`borrow_mut`], in this scope.
+    pub fn borrow_mut<T: 'static + Default>(&self) -> StateRefMut<T> {
+        self.storage().borrow_mut()
+    }
+
+    /// Like [`take`], in this scope.
+    pub fn take<T: 'static>(&self) -> Option<T> {
+        self.storage().take::<T>()
+    }
+
+    /// Like [`swap`], in this scope.
+    pub fn swap<T: 'static>(&self, value: T) -> Option<T> {
+        self.storage().swap(value)
+    }
+
+    /// Like [`with_many`], in this scope.
+    pub fn with_many<A: BorrowMany, U, F: FnOnce(A) -> U>(&self, callback: F) -> U {
+        self.storage().with_many(callback)
+    }
+
+    /// Like [`with_many_mut`], in this scope.
+    pub fn with_many_mut<A: BorrowMutMany, U, F: FnOnce(A) -> U>(&self, callback: F) -> U {
+        self.storage().with_many_mut(callback)
+    }
+
+    /// Like [`crate::ic::on_pre_upgrade`], with the value of type `T` in this scope. The callback
+    /// does not replace the one registered for the type `T` by the canister or by another scope.
+    pub fn on_pre_upgrade<T: 'static>(&self, callback: fn(&mut T)) {
+        add_pre_upgrade((Some(TypeId::of::<S>()), TypeId::of::<T>()), move || {
+            scoped::<S>().maybe_with_mut(callback);
+        });
+    }
+
+    /// Like [`crate::ic::on_post_upgrade`], with the value of type `T` in this scope.
+    pub fn on_post_upgrade<T: 'static>(&self, callback: fn(&mut T)) {
+        add_post_upgrade((Some(TypeId::of::<S>()), TypeId::of::<T>()), move || {
+            scoped::<S>().maybe_with_mut(callback);
+        });
+    }
+}
@@ -0,0 +1,150 @@
+//! The cycles minting canister, which converts ICP to cycles.
+//!
+//! A canister is topped up by transferring ICP on the ledger to the subaccount of the canister
+//! owned by the cycles minting canister, with the [`MEMO_TOP_UP_CANISTER`] memo, and by notifying
+//! the cycles minting canister of the block of the transfer, which [`CmcClient::top_up`] does.
+
+use super::ledger::{
+    AccountIdentifier, BlockIndex, LedgerClient, Memo, Subaccount, Tokens, TransferArgs,
+    TransferError,
+};
+use crate::ic::{CallBuilder, CallError};
+use candid::{CandidType, Nat, Principal};
+use serde::Deserialize;
+
+/// The id of the cycles minting canister on the mainnet, `rkp4c-7iaaa-aaaaa-aaaca-cai`.
+pub const MAINNET_CYCLES_MINTING_CANISTER_ID: Principal =
+    Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 4, 1, 1]);
+
+/// The memo of the transfers which top up a canister, `TPUP` in ASCII.
+pub const MEMO_TOP_UP_CANISTER: Memo = Memo(0x5055_5054);
+
+/// The arguments of `notify_top_up`.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct NotifyTopUpArg {
+    /// The index of the block of the transfer to the cycles minting canister.
+    pub block_index: BlockIndex,
+    /// The canister to top up.
+    pub canister_id: Principal,
+}
+
+/// The error of `notify_top_up`.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum NotifyError {
+    Refunded {
+        reason: String,
+        block_index: Option<BlockIndex>,
+    },
+    Processing,
+    TransactionTooOld(BlockIndex),
+    InvalidTransaction(String),
+    Other {
+        error_code: u64,
+        error_message: String,
+    },
+}
+
+/// The number of XDR for one ICP, which is the number of trillions of cycles one ICP converts to.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct IcpXdrConversionRate {
+    /// The time the rate was set, in seconds since the unix epoch.
+    pub timestamp_seconds: u64,
+    /// The number of 10^-4 XDR for one ICP.
+    pub xdr_permyriad_per_icp: u64,
+}
+
+/// The response of `get_icp_xdr_conversion_rate`.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct IcpXdrConversionRateResponse {
+    /// The conversion rate.
+    pub data: IcpXdrConversionRate,
+    /// The hash tree which certifies the rate.
+    pub hash_tree: Vec<u8>,
+    /// The certificate of the hash tree.
+    pub certificate: Vec<u8>,
+}
+
+/// The error of [`CmcClient::top_up`].
+#[derive(Debug)]
+pub enum TopUpError {
+    /// One of the calls failed.
+    Call(CallError),
+    /// The ledger rejected the transfer.
+    Transfer(TransferError),
+    /// The cycles minting canister rejected the notification.
+    Notify(NotifyError),
+}
+
+/// A client which calls the methods of the cycles minting canister.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CmcClient {
+    canister_id: Principal,
+}
+
+impl CmcClient {
+    /// Create a new client for the cycles minting canister with the given id.
+    pub fn new(canister_id: Principal) -> Self {
+        Self { canister_id }
+    }
+
+    /// Create a new client for the cycles minting canister on the mainnet.
+    pub fn mainnet() -> Self {
+        Self::new(MAINNET_CYCLES_MINTING_CANISTER_ID)
+    }
+
+    /// Return the id of the cycles minting canister this client calls.
+    pub fn canister_id(&self) -> Principal {
+        self.canister_id
+    }
+
+    /// Return the account the ICP must be transferred to in order to top up the canister.
+    pub fn top_up_account(&self, canister_id: Principal) -> AccountIdentifier {
+        AccountIdentifier::new(&self.canister_id, &Subaccount::from(canister_id))
+    }
+
+    /// Notify the cycles minting canister of a transfer to the top up account of the canister,
+    /// and return the amount of cycles the canister was topped up with.
+    pub async fn notify_top_up(
+        &self,
+        block_index: BlockIndex,
+        canister_id: Principal,
+    ) -> Result<Result<Nat, NotifyError>, CallError> {
+        CallBuilder::new(self.canister_id, "notify_top_up")
+            .with_arg(NotifyTopUpArg {
+                block_index,
+                canister_id,
+            })
+            .perform_one()
+            .await
+    }
+
+    /// Return the conversion rate between ICP and XDR.
+    pub async fn icp_xdr_conversion_rate(&self) -> Result<IcpXdrConversionRateResponse, CallError> {
+        CallBuilder::new(self.canister_id, "get_icp_xdr_conversion_rate")
+            .perform_one()
+            .await
+    }
+
+    /// Convert the amount of ICP from the default account of the caller to cycles for the
+    /// canister, and return the amount of cycles the canister was topped up with.
+    pub async fn top_up(
+        &self,
+        ledger: &LedgerClient,
+        canister_id: Principal,
+        amount: Tokens,
+    ) -> Result<Nat, TopUpError> {
+        let mut args = TransferArgs::new(self.top_up_account(canister_id), amount);
+        args.memo = MEMO_TOP_UP_CANISTER;
+
+        let block_index = ledger
+            .transfer(args)
+            .await
+            .map_err(TopUpError::Call)?
+            .map_err(TopUpError::Transfer)?;
+
+        self.notify_top_up(block_index, canister_id)
+            .await
+            .map_err(TopUpError::Call)?
+            .map_err(TopUpError::Notify)
+    }
+}
@@ -0,0 +1,419 @@
+//! The NNS governance canister, which manages the neurons and the proposals.
+//!
+//! The types only have the commonly used fields of the neurons and the proposals, the other
+//! fields are ignored when a response is decoded. The same goes for the commands of
+//! `manage_neuron`, which don't include the ones making or merging proposals.
+
+use super::ledger;
+use crate::ic::{CallBuilder, CallError};
+use candid::{CandidType, Principal};
+use serde::Deserialize;
+
+/// The id of the governance canister on the mainnet, `rrkah-fqaaa-aaaaa-aaaaq-cai`.
+pub const MAINNET_GOVERNANCE_CANISTER_ID: Principal =
+    Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 1, 1, 1]);
+
+/// The id of a neuron.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, CandidType, Deserialize)]
+pub struct NeuronId {
+    pub id: u64,
+}
+
+/// The id of a proposal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, CandidType, Deserialize)]
+pub struct ProposalId {
+    pub id: u64,
+}
+
+/// An empty record, the argument or the response of some commands.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, CandidType, Deserialize)]
+pub struct Empty {}
+
+/// An error returned by the governance canister.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct GovernanceError {
+    /// The message of the error.
+    pub error_message: String,
+    /// The type of the error, as the value of the `ErrorType` enum of the governance canister.
+    pub error_type: i32,
+}
+
+/// An account of the ICP ledger, as encoded by the governance canister.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, CandidType, Deserialize)]
+pub struct AccountIdentifier {
+    pub hash: Vec<u8>,
+}
+
+impl From<ledger::AccountIdentifier> for AccountIdentifier {
+    fn from(account: ledger::AccountIdentifier) -> Self {
+        Self {
+            hash: account.as_bytes().to_vec(),
+        }
+    }
+}
+
+/// An amount of ICP.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct Amount {
+    pub e8s: u64,
+}
+
+/// The state of the dissolve delay of a neuron.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum DissolveState {
+    /// The neuron is not dissolving, and has this dissolve delay.
+    DissolveDelaySeconds(u64),
+    /// The neuron is dissolving, and is dissolved at this time.
+    WhenDissolvedTimestampSeconds(u64),
+}
+
+/// A neuron, as returned to its controller and its hot keys.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct Neuron {
+    pub id: Option<NeuronId>,
+    /// The subaccount of the governance canister which holds the stake of the neuron.
+    pub account: Vec<u8>,
+    pub controller: Option<Principal>,
+    pub hot_keys: Vec<Principal>,
+    pub cached_neuron_stake_e8s: u64,
+    pub neuron_fees_e8s: u64,
+    pub maturity_e8s_equivalent: u64,
+    pub staked_maturity_e8s_equivalent: Option<u64>,
+    pub auto_stake_maturity: Option<bool>,
+    pub created_timestamp_seconds: u64,
+    pub aging_since_timestamp_seconds: u64,
+    pub dissolve_state: Option<DissolveState>,
+}
+
+/// The public information of a neuron.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct NeuronInfo {
+    pub dissolve_delay_seconds: u64,
+    pub created_timestamp_seconds: u64,
+    /// The state of the neuron, `1` if it's not dissolving, `2` if it's dissolving and `3` if it's
+    /// dissolved.
+    pub state: i32,
+    pub stake_e8s: u64,
+    pub voting_power: u64,
+    pub age_seconds: u64,
+    pub retrieved_at_timestamp_seconds: u64,
+    pub joined_community_fund_timestamp_seconds: Option<u64>,
+}
+
+/// The arguments of `list_neurons`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, CandidType, Deserialize)]
+pub struct ListNeurons {
+    /// The ids of the neurons to return.
+    pub neuron_ids: Vec<u64>,
+    /// Also return the neurons the caller controls or is a hot key of.
+    pub include_neurons_readable_by_caller: bool,
+    /// Also return the neurons of the caller without any stake or maturity.
+    pub include_empty_neurons_readable_by_caller: Option<bool>,
+}
+
+impl ListNeurons {
+    /// List the neurons the caller controls or is a hot key of.
+    pub fn readable_by_caller() -> Self {
+        Self {
+            include_neurons_readable_by_caller: true,
+            ..Self::default()
+        }
+    }
+}
+
+/// The response of `list_neurons`.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct ListNeuronsResponse {
+    /// The public information of all of the listed neurons.
+    pub neuron_infos: Vec<(u64, NeuronInfo)>,
+    /// The neurons the caller is allowed to read in full.
+    pub full_neurons: Vec<Neuron>,
+}
+
+/// A vote on a proposal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Vote {
+    Yes,
+    No,
+}
+
+impl From<Vote> for i32 {
+    fn from(vote: Vote) -> Self {
+        match vote {
+            Vote::Yes => 1,
+            Vote::No => 2,
+        }
+    }
+}
+
+/// The neuron a command is about.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum NeuronIdOrSubaccount {
+    Subaccount(Vec<u8>),
+    NeuronId(NeuronId),
+}
+
+/// How the neuron to claim or refresh is found.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum By {
+    /// The neuron given by the `neuron_id_or_subaccount` of the command.
+    NeuronIdOrSubaccount(Empty),
+    /// The neuron of the caller staked with this memo.
+    Memo(u64),
+    /// The neuron of the controller staked with the memo.
+    MemoAndController(ClaimOrRefreshNeuronFromAccount),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct ClaimOrRefreshNeuronFromAccount {
+    pub controller: Option<Principal>,
+    pub memo: u64,
+}
+
+/// A change to the configuration of a neuron.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum Operation {
+    AddHotKey {
+        new_hot_key: Option<Principal>,
+    },
+    RemoveHotKey {
+        hot_key_to_remove: Option<Principal>,
+    },
+    StartDissolving(Empty),
+    StopDissolving(Empty),
+    IncreaseDissolveDelay {
+        additional_dissolve_delay_seconds: u32,
+    },
+    SetDissolveTimestamp {
+        dissolve_timestamp_seconds: u64,
+    },
+    JoinCommunityFund(Empty),
+    LeaveCommunityFund(Empty),
+    ChangeAutoStakeMaturity {
+        requested_setting_for_auto_stake_maturity: bool,
+    },
+}
+
+/// A command of `manage_neuron`.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum Command {
+    /// Spawn a new neuron from the maturity of the neuron.
+    Spawn {
+        percentage_to_spawn: Option<u32>,
+        new_controller: Option<Principal>,
+        nonce: Option<u64>,
+    },
+    /// Split the stake of the neuron into a new neuron.
+    Split { amount_e8s: u64 },
+    /// Follow the given neurons on the proposals of a topic.
+    Follow {
+        topic: i32,
+        followees: Vec<NeuronId>,
+    },
+    /// Claim a new neuron or refresh the stake of a neuron.
+    ClaimOrRefresh { by: Option<By> },
+    /// Change the configuration of the neuron.
+    Configure { operation: Option<Operation> },
+    /// Vote on a proposal.
+    RegisterVote {
+        vote: i32,
+        proposal: Option<ProposalId>,
+    },
+    /// Disburse the stake of a dissolved neuron, to the account of the caller by default.
+    Disburse {
+        to_account: Option<AccountIdentifier>,
+        amount: Option<Amount>,
+    },
+    /// Stake the maturity of the neuron.
+    StakeMaturity { percentage_to_stake: Option<u32> },
+}
+
+/// The arguments of `manage_neuron`.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct ManageNeuron {
+    /// The id of the neuron, which is deprecated in favor of `neuron_id_or_subaccount`.
+    pub id: Option<NeuronId>,
+    pub neuron_id_or_subaccount: Option<NeuronIdOrSubaccount>,
+    pub command: Option<Command>,
+}
+
+impl ManageNeuron {
+    /// Run the command on the neuron with the given id.
+    pub fn new(neuron_id: u64, command: Command) -> Self {
+        Self {
+            id: None,
+            neuron_id_or_subaccount: Some(NeuronIdOrSubaccount::NeuronId(NeuronId {
+                id: neuron_id,
+            })),
+            command: Some(command),
+        }
+    }
+
+    /// Vote on the proposal with the neuron.
+    pub fn register_vote(neuron_id: u64, proposal_id: u64, vote: Vote) -> Self {
+        Self::new(
+            neuron_id,
+            Command::RegisterVote {
+                vote: vote.into(),
+                proposal: Some(ProposalId { id: proposal_id }),
+            },
+        )
+    }
+}
+
+/// The result of a command of `manage_neuron`.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum CommandResponse {
+    Error(GovernanceError),
+    Spawn {
+        created_neuron_id: Option<NeuronId>,
+    },
+    Split {
+        created_neuron_id: Option<NeuronId>,
+    },
+    Follow(Empty),
+    ClaimOrRefresh {
+        refreshed_neuron_id: Option<NeuronId>,
+    },
+    Configure(Empty),
+    RegisterVote(Empty),
+    Disburse {
+        transfer_block_height: u64,
+    },
+    StakeMaturity {
+        maturity_e8s: u64,
+        staked_maturity_e8s: u64,
+    },
+    DisburseToNeuron {
+        created_neuron_id: Option<NeuronId>,
+    },
+    MakeProposal {
+        proposal_id: Option<ProposalId>,
+        message: Option<String>,
+    },
+    MergeMaturity {
+        merged_maturity_e8s: u64,
+        new_stake_e8s: u64,
+    },
+}
+
+/// The response of `manage_neuron`.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct ManageNeuronResponse {
+    pub command: Option<CommandResponse>,
+}
+
+impl ManageNeuronResponse {
+    /// Return the result of the command, or the error of the governance canister.
+    pub fn into_result(self) -> Result<CommandResponse, GovernanceError> {
+        match self.command {
+            Some(CommandResponse::Error(error)) => Err(error),
+            Some(command) => Ok(command),
+            None => Err(GovernanceError {
+                error_message: "The response does not have a command.".into(),
+                error_type: 0,
+            }),
+        }
+    }
+}
+
+/// The votes on a proposal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct Tally {
+    pub yes: u64,
+    pub no: u64,
+    pub total: u64,
+    pub timestamp_seconds: u64,
+}
+
+/// The content of a proposal, without its action.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct Proposal {
+    pub title: Option<String>,
+    pub url: String,
+    pub summary: String,
+}
+
+/// The information of a proposal.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct ProposalInfo {
+    pub id: Option<ProposalId>,
+    pub proposer: Option<NeuronId>,
+    pub proposal: Option<Proposal>,
+    /// The topic of the proposal, as the value of the `Topic` enum of the governance canister.
+    pub topic: i32,
+    /// The status of the proposal, `1` if it's open, `2` if it's rejected, `3` if it's adopted,
+    /// `4` if it's executed and `5` if its execution failed.
+    pub status: i32,
+    pub reward_status: i32,
+    pub latest_tally: Option<Tally>,
+    pub proposal_timestamp_seconds: u64,
+    pub decided_timestamp_seconds: u64,
+    pub executed_timestamp_seconds: u64,
+    pub failed_timestamp_seconds: u64,
+    pub deadline_timestamp_seconds: Option<u64>,
+}
+
+/// A client which calls the methods of the governance canister.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GovernanceClient {
+    canister_id: Principal,
+}
+
+impl GovernanceClient {
+    /// Create a new client for the governance canister with the given id.
+    pub fn new(canister_id: Principal) -> Self {
+        Self { canister_id }
+    }
+
+    /// Create a new client for the governance canister on the mainnet.
+    pub fn mainnet() -> Self {
+        Self::new(MAINNET_GOVERNANCE_CANISTER_ID)
+    }
+
+    /// Return the id of the governance canister this client calls.
+    pub fn canister_id(&self) -> Principal {
+        self.canister_id
+    }
+
+    /// Return the neurons with the given ids, and the neurons of the caller if requested.
+    pub async fn list_neurons(&self, args: ListNeurons) -> Result<ListNeuronsResponse, CallError> {
+        CallBuilder::new(self.canister_id, "list_neurons")
+            .with_arg(args)
+            .perform_one()
+            .await
+    }
+
+    /// Run a command on a neuron of the caller.
+    pub async fn manage_neuron(
+        &self,
+        args: ManageNeuron,
+    ) -> Result<ManageNeuronResponse, CallError> {
+        CallBuilder::new(self.canister_id, "manage_neuron")
+            .with_arg(args)
+            .perform_one()
+            .await
+    }
+
+    /// Return the public information of the neuron.
+    pub async fn get_neuron_info(
+        &self,
+        neuron_id: u64,
+    ) -> Result<Result<NeuronInfo, GovernanceError>, CallError> {
+        CallBuilder::new(self.canister_id, "get_neuron_info")
+            .with_arg(neuron_id)
+            .perform_one()
+            .await
+    }
+
+    /// Return the information of the proposal, or `None` if it does not exist.
+    pub async fn get_proposal_info(
+        &self,
+        proposal_id: u64,
+    ) -> Result<Option<ProposalInfo>, CallError> {
+        CallBuilder::new(self.canister_id, "get_proposal_info")
+            .with_arg(proposal_id)
+            .perform_one()
+            .await
+    }
+}
@@ -0,0 +1,416 @@
+//! The [ICRC-1](https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-1) fungible token
+//! standard.
+
+use crate::ic::{CallBuilder, CallError};
+use candid::{CandidType, Int, Nat, Principal};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::fmt;
+use std::str::FromStr;
+
+/// The subaccount of an account, which is 32 bytes long.
+pub type Subaccount = Vec<u8>;
+
+/// The memo of a transaction, which is at most 32 bytes long by default.
+pub type Memo = Vec<u8>;
+
+/// The index of a block in the ledger.
+pub type BlockIndex = Nat;
+
+/// An account of the ledger.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, CandidType, Deserialize)]
+pub struct Account {
+    /// The principal which owns the account.
+    pub owner: Principal,
+    /// The subaccount of the owner, `None` is the same as the subaccount made of zeros.
+    pub subaccount: Option<Subaccount>,
+}
+
+impl Account {
+    /// Create the account with the subaccount of the owner.
+    pub fn new(owner: Principal, subaccount: Option<Subaccount>) -> Self {
+        Self { owner, subaccount }
+    }
+
+    /// Return the subaccount of the account as 32 bytes, the subaccount made of zeros if it's
+    /// `None`.
+    pub fn effective_subaccount(&self) -> [u8; 32] {
+        let mut subaccount = [0; 32];
+
+        if let Some(bytes) = &self.subaccount {
+            let len = bytes.len().min(32);
+            subaccount[..len].copy_from_slice(&bytes[..len]);
+        }
+
+        subaccount
+    }
+
+    /// Return true if this is the default account of the owner.
+    pub fn is_default(&self) -> bool {
+        self.effective_subaccount() == [0; 32]
+    }
+
+    /// Return the checksum of the textual encoding, the CRC32 of the owner and the subaccount
+    /// in base 32.
+    fn checksum(&self) -> String {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(self.owner.as_slice());
+        hasher.update(&self.effective_subaccount());
+        base32(&hasher.finalize().to_be_bytes())
+    }
+}
+
+impl From<Principal> for Account {
+    /// The default account of the principal.
+    fn from(owner: Principal) -> Self {
+        Self {
+            owner,
+            subaccount: None,
+        }
+    }
+}
+
+impl fmt::Display for Account {
+    /// The textual encoding of the account defined by ICRC-1, which is the owner for the default
+    /// account, and `{owner}-{checksum}.{subaccount}` otherwise, with the subaccount in hex
+    /// without its leading zeros.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_default() {
+            return f.write_str(&self.owner.to_text());
+        }
+
+        let hex = self
+            .effective_subaccount()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+
+        write!(
+            f,
+            "{}-{}.{}",
+            self.owner.to_text(),
+            self.checksum(),
+            hex.trim_start_matches('0')
+        )
+    }
+}
+
+impl FromStr for Account {
+    type Err = String;
+
+    /// Parse the textual encoding of the account defined by ICRC-1, and check its checksum.
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let (rest, hex) = match text.rsplit_once('.') {
+            Some(parts) => parts,
+            None => {
+                return Principal::from_text(text)
+                    .map(Account::from)
+                    .map_err(|e| format!("'{}' is not a valid account: {}", text, e));
+            }
+        };
+
+        let (owner, checksum) = rest.rsplit_once('-').ok_or_else(|| {
+            format!(
+                "'{}' is not a valid account: the checksum is missing.",
+                text
+            )
+        })?;
+
+        if hex.is_empty() || hex.len() > 64 || hex.starts_with('0') {
+            return Err(format!(
+                "'{}' is not a valid account: the subaccount is not in its canonical form.",
+                text
+            ));
+        }
+
+        let padded = format!("{:0>64}", hex);
+        let mut subaccount = vec![0; 32];
+
+        for (byte, pair) in subaccount.iter_mut().zip(padded.as_bytes().chunks(2)) {
+            *byte = std::str::from_utf8(pair)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| {
+                    format!(
+                        "'{}' is not a valid account: the subaccount is not hex.",
+                        text
+                    )
+                })?;
+        }
+
+        let account = Account {
+            owner: Principal::from_text(owner)
+                .map_err(|e| format!("'{}' is not a valid account: {}", text, e))?,
+            subaccount: Some(subaccount),
+        };
+
+        if account.checksum() != checksum {
+            return Err(format!(
+                "'{}' is not a valid account: the checksum is invalid.",
+                text
+            ));
+        }
+
+        Ok(account)
+    }
+}
+
+/// Encode the bytes in lowercase base 32 without padding, like the textual encoding of the
+/// principals.
+fn base32(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+    let mut result = String::new();
+    let mut buffer = 0u16;
+    let mut bits = 0;
+
+    for byte in bytes {
+        buffer = (buffer << 8) | *byte as u16;
+        bits += 8;
+
+        while bits >= 5 {
+            bits -= 5;
+            result.push(ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+
+    if bits > 0 {
+        result.push(ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+
+    result
+}
+
+/// The arguments of `icrc1_transfer`.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct TransferArg {
+    /// The subaccount of the caller to transfer the tokens from.
+    pub from_subaccount: Option<Subaccount>,
+    /// The account to transfer the tokens to.
+    pub to: Account,
+    /// The amount of tokens to transfer.
+    pub amount: Nat,
+    /// The fee the caller agrees to pay, the ledger rejects the transfer if it does not match.
+    pub fee: Option<Nat>,
+    /// An arbitrary memo which is recorded with the transaction.
+    pub memo: Option<Memo>,
+    /// The time of the transaction in nanoseconds, which is used for the deduplication.
+    pub created_at_time: Option<u64>,
+}
+
+impl TransferArg {
+    /// Transfer the amount of tokens from the default subaccount of the caller to the account.
+    pub fn new<A: Into<Account>, N: Into<Nat>>(to: A, amount: N) -> Self {
+        Self {
+            from_subaccount: None,
+            to: to.into(),
+            amount: amount.into(),
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        }
+    }
+}
+
+/// The error of `icrc1_transfer`.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    TemporarilyUnavailable,
+    Duplicate { duplicate_of: BlockIndex },
+    GenericError { error_code: Nat, message: String },
+}
+
+/// The value of a metadata entry of the ledger.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum MetadataValue {
+    Nat(Nat),
+    Int(Int),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+/// A standard supported by the ledger.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct StandardRecord {
+    /// The name of the standard, such as `ICRC-1`.
+    pub name: String,
+    /// The url of the specification of the standard.
+    pub url: String,
+}
+
+/// A client which calls the methods of an ICRC-1 ledger.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Icrc1Client {
+    canister_id: Principal,
+}
+
+impl Icrc1Client {
+    /// Create a new client for the ledger with the given id.
+    pub fn new(canister_id: Principal) -> Self {
+        Self { canister_id }
+    }
+
+    /// Return the id of the ledger this client calls.
+    pub fn canister_id(&self) -> Principal {
+        self.canister_id
+    }
+
+    /// Return the name of the token.
+    pub async fn name(&self) -> Result<String, CallError> {
+        self.query("icrc1_name").await
+    }
+
+    /// Return the symbol of the token.
+    pub async fn symbol(&self) -> Result<String, CallError> {
+        self.query("icrc1_symbol").await
+    }
+
+    /// Return the number of decimals of the token.
+    pub async fn decimals(&self) -> Result<u8, CallError> {
+        self.query("icrc1_decimals").await
+    }
+
+    /// Return the fee of a transfer.
+    pub async fn fee(&self) -> Result<Nat, CallError> {
+        self.query("icrc1_fee").await
+    }
+
+    /// Return the metadata of the ledger.
+    pub async fn metadata(&self) -> Result<Vec<(String, MetadataValue)>, CallError> {
+        self.query("icrc1_metadata").await
+    }
+
+    /// Return the total supply of the token.
+    pub async fn total_supply(&self) -> Result<Nat, CallError> {
+        self.query("icrc1_total_supply").await
+    }
+
+    /// Return the account which mints and burns the tokens, if the ledger has one.
+    pub async fn minting_account(&self) -> Result<Option<Account>, CallError> {
+        self.query("icrc1_minting_account").await
+    }
+
+    /// Return the balance of the account.
+    pub async fn balance_of(&self, account: Account) -> Result<Nat, CallError> {
+        CallBuilder::new(self.canister_id, "icrc1_balance_of")
+            .with_arg(account)
+            .perform_one()
+            .await
+    }
+
+    /// Transfer tokens from an account of the canister, and return the index of the block of the
+    /// transaction.
+    pub async fn transfer(
+        &self,
+        arg: TransferArg,
+    ) -> Result<Result<BlockIndex, TransferError>, CallError> {
+        CallBuilder::new(self.canister_id, "icrc1_transfer")
+            .with_arg(arg)
+            .perform_one()
+            .await
+    }
+
+    /// Return the standards supported by the ledger.
+    pub async fn supported_standards(&self) -> Result<Vec<StandardRecord>, CallError> {
+        self.query("icrc1_supported_standards").await
+    }
+
+    async fn query<T>(&self, method: &str) -> Result<T, CallError>
+    where
+        T: DeserializeOwned + CandidType,
+    {
+        CallBuilder::new(self.canister_id, method)
+            .perform_one()
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OWNER: &str = "k2t6j-2nvnp-4zjm3-25dtz-6xhaa-c7boj-5gayf-oj3xs-i43lp-teztq-6ae";
+
+    fn account(subaccount: Option<Subaccount>) -> Account {
+        Account::new(Principal::from_text(OWNER).unwrap(), subaccount)
+    }
+
+    #[test]
+    fn spec_vectors() {
+        let mut last_byte = vec![0; 32];
+        last_byte[31] = 1;
+
+        let vectors = [
+            (account(None), OWNER.to_string()),
+            (account(Some(last_byte)), format!("{}-6cc627i.1", OWNER)),
+            (
+                account(Some((1..=32).collect())),
+                format!(
+                    "{}-dfxgiyy.102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f20",
+                    OWNER
+                ),
+            ),
+        ];
+
+        for (account, text) in vectors {
+            assert_eq!(account.to_string(), text);
+
+            let parsed = Account::from_str(&text).unwrap();
+            assert_eq!(
+                parsed.effective_subaccount(),
+                account.effective_subaccount()
+            );
+            assert_eq!(parsed.to_string(), text);
+        }
+    }
+
+    #[test]
+    fn default_subaccount() {
+        assert_eq!(account(Some(vec![0; 32])).to_string(), OWNER);
+        assert!(account(Some(vec![0; 32])).is_default());
+    }
+
+    #[test]
+    fn reject_invalid() {
+        let default_checksum = account(None).checksum();
+
+        let invalid = [
+            // The checksum is wrong.
+            (format!("{}-7cc627i.1", OWNER), "the checksum is invalid."),
+            // The subaccount has a leading zero.
+            (
+                format!("{}-6cc627i.01", OWNER),
+                "the subaccount is not in its canonical form.",
+            ),
+            // The default subaccount is written explicitly.
+            (
+                format!("{}-{}.0", OWNER, default_checksum),
+                "the subaccount is not in its canonical form.",
+            ),
+            (
+                format!("{}-{}.", OWNER, default_checksum),
+                "the subaccount is not in its canonical form.",
+            ),
+            // The subaccount is not hex.
+            (
+                format!("{}-6cc627i.1g", OWNER),
+                "the subaccount is not hex.",
+            ),
+        ];
+
+        for (text, error) in invalid {
+            let result = Account::from_str(&text);
+            assert_eq!(
+                result,
+                Err(format!("'{}' is not a valid account: {}", text, error))
+            );
+        }
+
+        // The checksum is missing.
+        assert!(Account::from_str(&format!("{}.1", OWNER)).is_err());
+    }
+}
@@ -0,0 +1,181 @@
+//! The [ICRC-2](https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-2) approve and transfer
+//! from standard, which extends ICRC-1.
+
+use super::icrc1::{Account, BlockIndex, Memo, Subaccount};
+use crate::ic::{CallBuilder, CallError};
+use candid::{CandidType, Nat, Principal};
+use serde::Deserialize;
+
+/// The arguments of `icrc2_approve`.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct ApproveArgs {
+    /// The subaccount of the caller the spender can transfer the tokens from.
+    pub from_subaccount: Option<Subaccount>,
+    /// The account which is allowed to transfer the tokens.
+    pub spender: Account,
+    /// The amount of tokens the spender can transfer.
+    pub amount: Nat,
+    /// If set, the approval is rejected unless the current allowance is this amount.
+    pub expected_allowance: Option<Nat>,
+    /// The time in nanoseconds at which the approval expires.
+    pub expires_at: Option<u64>,
+    /// The fee the caller agrees to pay, the ledger rejects the approval if it does not match.
+    pub fee: Option<Nat>,
+    /// An arbitrary memo which is recorded with the transaction.
+    pub memo: Option<Memo>,
+    /// The time of the transaction in nanoseconds, which is used for the deduplication.
+    pub created_at_time: Option<u64>,
+}
+
+impl ApproveArgs {
+    /// Allow the spender to transfer the amount of tokens from the default subaccount of the
+    /// caller.
+    pub fn new<A: Into<Account>, N: Into<Nat>>(spender: A, amount: N) -> Self {
+        Self {
+            from_subaccount: None,
+            spender: spender.into(),
+            amount: amount.into(),
+            expected_allowance: None,
+            expires_at: None,
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        }
+    }
+}
+
+/// The error of `icrc2_approve`.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum ApproveError {
+    BadFee { expected_fee: Nat },
+    InsufficientFunds { balance: Nat },
+    AllowanceChanged { current_allowance: Nat },
+    Expired { ledger_time: u64 },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: BlockIndex },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+/// The arguments of `icrc2_transfer_from`.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct TransferFromArgs {
+    /// The subaccount of the caller which was approved as the spender.
+    pub spender_subaccount: Option<Subaccount>,
+    /// The account to transfer the tokens from.
+    pub from: Account,
+    /// The account to transfer the tokens to.
+    pub to: Account,
+    /// The amount of tokens to transfer.
+    pub amount: Nat,
+    /// The fee the caller agrees to pay, the ledger rejects the transfer if it does not match.
+    pub fee: Option<Nat>,
+    /// An arbitrary memo which is recorded with the transaction.
+    pub memo: Option<Memo>,
+    /// The time of the transaction in nanoseconds, which is used for the deduplication.
+    pub created_at_time: Option<u64>,
+}
+
+impl TransferFromArgs {
+    /// Transfer the amount of tokens between the accounts, using the allowance of the default
+    /// subaccount of the caller.
+    pub fn new<F: Into<Account>, T: Into<Account>, N: Into<Nat>>(
+        from: F,
+        to: T,
+        amount: N,
+    ) -> Self {
+        Self {
+            spender_subaccount: None,
+            from: from.into(),
+            to: to.into(),
+            amount: amount.into(),
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        }
+    }
+}
+
+/// The error of `icrc2_transfer_from`.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: BlockIndex },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+/// The arguments of `icrc2_allowance`.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct AllowanceArgs {
+    /// The account which approved the spender.
+    pub account: Account,
+    /// The account which is allowed to transfer the tokens.
+    pub spender: Account,
+}
+
+/// The allowance of a spender.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct Allowance {
+    /// The amount of tokens the spender can still transfer.
+    pub allowance: Nat,
+    /// The time in nanoseconds at which the allowance expires, if it does.
+    pub expires_at: Option<u64>,
+}
+
+/// A client which calls the ICRC-2 methods of a ledger, the ICRC-1 methods are called with an
+/// [`Icrc1Client`](super::icrc1::Icrc1Client).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Icrc2Client {
+    canister_id: Principal,
+}
+
+impl Icrc2Client {
+    /// Create a new client for the ledger with the given id.
+    pub fn new(canister_id: Principal) -> Self {
+        Self { canister_id }
+    }
+
+    /// Return the id of the ledger this client calls.
+    pub fn canister_id(&self) -> Principal {
+        self.canister_id
+    }
+
+    /// Allow the spender to transfer tokens from an account of the canister, and return the index
+    /// of the block of the transaction.
+    pub async fn approve(
+        &self,
+        args: ApproveArgs,
+    ) -> Result<Result<BlockIndex, ApproveError>, CallError> {
+        CallBuilder::new(self.canister_id, "icrc2_approve")
+            .with_arg(args)
+            .perform_one()
+            .await
+    }
+
+    /// Transfer tokens from an account which approved the canister, and return the index of the
+    /// block of the transaction.
+    pub async fn transfer_from(
+        &self,
+        args: TransferFromArgs,
+    ) -> Result<Result<BlockIndex, TransferFromError>, CallError> {
+        CallBuilder::new(self.canister_id, "icrc2_transfer_from")
+            .with_arg(args)
+            .perform_one()
+            .await
+    }
+
+    /// Return the amount of tokens the spender can transfer from the account.
+    pub async fn allowance(&self, args: AllowanceArgs) -> Result<Allowance, CallError> {
+        CallBuilder::new(self.canister_id, "icrc2_allowance")
+            .with_arg(args)
+            .perform_one()
+            .await
+    }
+}
@@ -0,0 +1,317 @@
+//! The [ICP ledger](https://internetcomputer.org/docs/current/references/ledger), and the account
+//! identifiers it uses instead of the ICRC-1 accounts.
+
+use super::icrc1::Account;
+use crate::ic::{CallBuilder, CallError};
+use candid::{CandidType, Principal};
+use serde::Deserialize;
+use sha2::{Digest, Sha224};
+use std::convert::TryFrom;
+use std::fmt;
+
+/// The id of the ICP ledger on the mainnet, `ryjl3-tyaaa-aaaaa-aaaba-cai`.
+pub const MAINNET_LEDGER_CANISTER_ID: Principal =
+    Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 2, 1, 1]);
+
+/// The fee of a transfer on the ICP ledger.
+pub const DEFAULT_FEE: Tokens = Tokens::from_e8s(10_000);
+
+/// The index of a block in the ledger.
+pub type BlockIndex = u64;
+
+/// An amount of ICP.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, CandidType, Deserialize,
+)]
+pub struct Tokens {
+    e8s: u64,
+}
+
+impl Tokens {
+    /// The number of e8s in one ICP.
+    pub const SUBDIVIDABLE_BY: u64 = 100_000_000;
+
+    /// Zero tokens.
+    pub const ZERO: Tokens = Tokens { e8s: 0 };
+
+    /// Create an amount from the number of e8s, which are 10^-8 ICP.
+    pub const fn from_e8s(e8s: u64) -> Self {
+        Self { e8s }
+    }
+
+    /// Return the number of e8s in the amount.
+    pub const fn e8s(&self) -> u64 {
+        self.e8s
+    }
+}
+
+/// The memo of a transaction, which is an arbitrary number chosen by the sender.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, CandidType, Deserialize)]
+pub struct Memo(pub u64);
+
+/// A time in nanoseconds since the unix epoch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, CandidType, Deserialize)]
+pub struct TimeStamp {
+    /// The number of nanoseconds since the unix epoch.
+    pub timestamp_nanos: u64,
+}
+
+/// The subaccount of an account, the zero subaccount is the default one.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, CandidType, Deserialize)]
+pub struct Subaccount(pub [u8; 32]);
+
+impl Subaccount {
+    /// The subaccount assigned to a principal, which is the length of the principal followed by
+    /// its bytes. This is the subaccount the cycles minting canister expects for a top-up, and
+    /// the one commonly used to give each user of a canister a deposit account.
+    pub fn from_principal(principal: &Principal) -> Self {
+        let bytes = principal.as_slice();
+        let mut subaccount = [0; 32];
+        subaccount[0] = bytes.len() as u8;
+        subaccount[1..1 + bytes.len()].copy_from_slice(bytes);
+        Self(subaccount)
+    }
+
+    /// The subaccount with the nonce in big-endian in its last 8 bytes, to derive a sequence of
+    /// subaccounts such as one per invoice.
+    pub fn from_nonce(nonce: u64) -> Self {
+        let mut subaccount = [0; 32];
+        subaccount[24..].copy_from_slice(&nonce.to_be_bytes());
+        Self(subaccount)
+    }
+
+    /// Return true if this is the default subaccount, which is made of zeros.
+    pub fn is_default(&self) -> bool {
+        self.0 == [0; 32]
+    }
+
+    /// Return the bytes of the subaccount.
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl From<Principal> for Subaccount {
+    /// The subaccount assigned to a principal, see [`Subaccount::from_principal`].
+    fn from(principal: Principal) -> Self {
+        Self::from_principal(&principal)
+    }
+}
+
+impl TryFrom<&[u8]> for Subaccount {
+    type Error = String;
+
+    /// Create the subaccount from the 32 bytes of a subaccount of an ICRC-1 account.
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        <[u8; 32]>::try_from(bytes)
+            .map(Self)
+            .map_err(|_| format!("A subaccount has 32 bytes, got {}.", bytes.len()))
+    }
+}
+
+impl From<Subaccount> for Vec<u8> {
+    /// The subaccount of an ICRC-1 account.
+    fn from(subaccount: Subaccount) -> Self {
+        subaccount.0.to_vec()
+    }
+}
+
+/// The identifier of an account on the ICP ledger: the CRC32 checksum of the hash of the owner
+/// and the subaccount, followed by that hash.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, CandidType, Deserialize)]
+pub struct AccountIdentifier([u8; 32]);
+
+impl AccountIdentifier {
+    /// Return the identifier of the subaccount of the owner.
+    pub fn new(owner: &Principal, subaccount: &Subaccount) -> Self {
+        let mut hasher = Sha224::new();
+        hasher.update(b"\x0Aaccount-id");
+        hasher.update(owner.as_slice());
+        hasher.update(&subaccount.0[..]);
+        let hash = hasher.finalize();
+
+        let mut bytes = [0; 32];
+        bytes[..4].copy_from_slice(&crc32fast::hash(&hash).to_be_bytes());
+        bytes[4..].copy_from_slice(&hash);
+        Self(bytes)
+    }
+
+    /// Parse the identifier from its 64 hex digits, and check its checksum.
+    pub fn from_hex(hex: &str) -> Result<Self, String> {
+        if hex.len() != 64 {
+            return Err(format!(
+                "An account identifier has 64 hex digits, got {}.",
+                hex.len()
+            ));
+        }
+
+        let mut bytes = [0; 32];
+
+        for (byte, pair) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+            *byte = std::str::from_utf8(pair)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| format!("'{}' is not a valid account identifier.", hex))?;
+        }
+
+        Self::from_bytes(bytes)
+    }
+
+    /// Create the identifier from its bytes, and check its checksum.
+    pub fn from_bytes(bytes: [u8; 32]) -> Result<Self, String> {
+        if bytes[..4] != crc32fast::hash(&bytes[4..]).to_be_bytes() {
+            return Err("The checksum of the account identifier is invalid.".into());
+        }
+
+        Ok(Self(bytes))
+    }
+
+    /// Return the bytes of the identifier.
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// Return the identifier as 64 hex digits, which is how it's displayed by the wallets.
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+impl From<Principal> for AccountIdentifier {
+    /// The identifier of the default account of the principal.
+    fn from(owner: Principal) -> Self {
+        Self::new(&owner, &Subaccount::default())
+    }
+}
+
+impl From<&Account> for AccountIdentifier {
+    /// The identifier of the ICRC-1 account on the ICP ledger, which supports both standards.
+    fn from(account: &Account) -> Self {
+        Self::new(&account.owner, &Subaccount(account.effective_subaccount()))
+    }
+}
+
+impl From<Account> for AccountIdentifier {
+    fn from(account: Account) -> Self {
+        Self::from(&account)
+    }
+}
+
+impl fmt::Display for AccountIdentifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_hex())
+    }
+}
+
+impl fmt::Debug for AccountIdentifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AccountIdentifier({})", self.to_hex())
+    }
+}
+
+/// The arguments of `transfer`.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct TransferArgs {
+    /// The memo of the transaction.
+    pub memo: Memo,
+    /// The amount to transfer.
+    pub amount: Tokens,
+    /// The fee the caller agrees to pay, which must be [`DEFAULT_FEE`].
+    pub fee: Tokens,
+    /// The subaccount of the caller to transfer the tokens from.
+    pub from_subaccount: Option<Subaccount>,
+    /// The account to transfer the tokens to.
+    pub to: AccountIdentifier,
+    /// The time of the transaction, which is used for the deduplication.
+    pub created_at_time: Option<TimeStamp>,
+}
+
+impl TransferArgs {
+    /// Transfer the amount from the default subaccount of the caller to the account, with the
+    /// default fee.
+    pub fn new<A: Into<AccountIdentifier>>(to: A, amount: Tokens) -> Self {
+        Self {
+            memo: Memo::default(),
+            amount,
+            fee: DEFAULT_FEE,
+            from_subaccount: None,
+            to: to.into(),
+            created_at_time: None,
+        }
+    }
+}
+
+/// The error of `transfer`.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum TransferError {
+    BadFee { expected_fee: Tokens },
+    InsufficientFunds { balance: Tokens },
+    TxTooOld { allowed_window_nanos: u64 },
+    TxCreatedInFuture,
+    TxDuplicate { duplicate_of: BlockIndex },
+}
+
+#[derive(CandidType, Deserialize)]
+struct AccountBalanceArgs {
+    account: AccountIdentifier,
+}
+
+#[derive(CandidType, Deserialize)]
+struct TransferFeeArg {}
+
+#[derive(CandidType, Deserialize)]
+struct TransferFee {
+    transfer_fee: Tokens,
+}
+
+/// A client which calls the methods of the ICP ledger.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LedgerClient {
+    canister_id: Principal,
+}
+
+impl LedgerClient {
+    /// Create a new client for the ledger with the given id.
+    pub fn new(canister_id: Principal) -> Self {
+        Self { canister_id }
+    }
+
+    /// Create a new client for the ledger on the mainnet.
+    pub fn mainnet() -> Self {
+        Self::new(MAINNET_LEDGER_CANISTER_ID)
+    }
+
+    /// Return the id of the ledger this client calls.
+    pub fn canister_id(&self) -> Principal {
+        self.canister_id
+    }
+
+    /// Return the balance of the account.
+    pub async fn account_balance(&self, account: AccountIdentifier) -> Result<Tokens, CallError> {
+        CallBuilder::new(self.canister_id, "account_balance")
+            .with_arg(AccountBalanceArgs { account })
+            .perform_one()
+            .await
+    }
+
+    /// Transfer tokens from an account of the canister, and return the index of the block of the
+    /// transaction.
+    pub async fn transfer(
+        &self,
+        args: TransferArgs,
+    ) -> Result<Result<BlockIndex, TransferError>, CallError> {
+        CallBuilder::new(self.canister_id, "transfer")
+            .with_arg(args)
+            .perform_one()
+            .await
+    }
+
+    /// Return the fee of a transfer.
+    pub async fn transfer_fee(&self) -> Result<Tokens, CallError> {
+        CallBuilder::new(self.canister_id, "transfer_fee")
+            .with_arg(TransferFeeArg {})
+            .perform_one::<TransferFee>()
+            .await
+            .map(|fee| fee.transfer_fee)
+    }
+}
@@ -0,0 +1,18 @@
+//! The types and the clients of the standard canister interfaces, so the canisters integrating a
+//! standard don't have to copy its type definitions.
+//!
+//! ```ignore
+//! use ic_kit::interfaces::icrc1::{Account, Icrc1Client, TransferArg};
+//!
+//! let ledger = Icrc1Client::new(ledger_id);
+//! let balance = ledger.balance_of(Account::from(ic::caller())).await?;
+//! ```
+
+pub mod cmc;
+pub mod governance;
+pub mod icrc1;
+pub mod icrc2;
+pub mod ledger;
+pub mod registry;
+pub mod sns;
+pub mod xrc;
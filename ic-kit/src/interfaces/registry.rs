@@ -0,0 +1,78 @@
+//! The NNS registry canister, which holds the configuration of the network.
+//!
+//! Only the queries with a candid interface are bound, most of the other methods of the registry
+//! use protocol buffers.
+
+use crate::ic::{CallBuilder, CallError};
+use candid::{CandidType, Principal};
+use serde::Deserialize;
+
+/// The id of the registry canister on the mainnet, `rwlgt-iiaaa-aaaaa-aaaaa-cai`.
+pub const MAINNET_REGISTRY_CANISTER_ID: Principal =
+    Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 1, 1]);
+
+/// The arguments of `get_subnet_for_canister`.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct GetSubnetForCanisterRequest {
+    pub principal: Option<Principal>,
+}
+
+/// The response of `get_subnet_for_canister`.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct SubnetForCanister {
+    pub subnet_id: Option<Principal>,
+}
+
+/// The rewards of the node providers for the current month.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct NodeProvidersMonthlyXdrRewards {
+    /// The rewards in XDR permyriad, by the principal of the node provider as text.
+    pub rewards: Vec<(String, u64)>,
+    /// The version of the registry the rewards were computed at.
+    pub registry_version: Option<u64>,
+}
+
+/// A client which calls the queries of the registry canister.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RegistryClient {
+    canister_id: Principal,
+}
+
+impl RegistryClient {
+    /// Create a new client for the registry canister with the given id.
+    pub fn new(canister_id: Principal) -> Self {
+        Self { canister_id }
+    }
+
+    /// Create a new client for the registry canister on the mainnet.
+    pub fn mainnet() -> Self {
+        Self::new(MAINNET_REGISTRY_CANISTER_ID)
+    }
+
+    /// Return the id of the registry canister this client calls.
+    pub fn canister_id(&self) -> Principal {
+        self.canister_id
+    }
+
+    /// Return the subnet the canister is hosted on.
+    pub async fn get_subnet_for_canister(
+        &self,
+        canister_id: Principal,
+    ) -> Result<Result<SubnetForCanister, String>, CallError> {
+        CallBuilder::new(self.canister_id, "get_subnet_for_canister")
+            .with_arg(GetSubnetForCanisterRequest {
+                principal: Some(canister_id),
+            })
+            .perform_one()
+            .await
+    }
+
+    /// Return the rewards of the node providers for the current month.
+    pub async fn get_node_providers_monthly_xdr_rewards(
+        &self,
+    ) -> Result<Result<NodeProvidersMonthlyXdrRewards, String>, CallError> {
+        CallBuilder::new(self.canister_id, "get_node_providers_monthly_xdr_rewards")
+            .perform_one()
+            .await
+    }
+}
@@ -0,0 +1,278 @@
+//! The governance canister of an SNS, which manages the neurons and the proposals of the DAO.
+//!
+//! The proposals only have the actions used to manage a dapp, which are the motions, the upgrades
+//! of the canisters of the dapp, the transfers from the treasury and the generic functions.
+
+use crate::ic::{CallBuilder, CallError};
+use candid::{CandidType, Principal};
+use serde::Deserialize;
+
+/// The treasury a transfer is made from, as the value of the `TransferFrom` enum of the
+/// governance canister.
+pub mod treasury {
+    /// The ICP treasury of the SNS.
+    pub const ICP: i32 = 1;
+    /// The treasury of the token of the SNS.
+    pub const SNS_TOKEN: i32 = 2;
+}
+
+/// The id of a neuron, which is the subaccount of the governance canister holding its stake.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, CandidType, Deserialize)]
+pub struct NeuronId {
+    pub id: Vec<u8>,
+}
+
+/// The id of a proposal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, CandidType, Deserialize)]
+pub struct ProposalId {
+    pub id: u64,
+}
+
+/// An empty record, the response of some commands.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, CandidType, Deserialize)]
+pub struct Empty {}
+
+/// An error returned by the governance canister.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct GovernanceError {
+    pub error_message: String,
+    pub error_type: i32,
+}
+
+/// A subaccount, as encoded by the governance canister.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, CandidType, Deserialize)]
+pub struct Subaccount {
+    pub subaccount: Vec<u8>,
+}
+
+/// A motion, which has no effect once adopted.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct Motion {
+    pub motion_text: String,
+}
+
+/// Upgrade a canister of the dapp, which is done by the root canister.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct UpgradeSnsControlledCanister {
+    pub canister_id: Option<Principal>,
+    pub new_canister_wasm: Vec<u8>,
+    /// The argument of the `post_upgrade` hook.
+    pub canister_upgrade_arg: Option<Vec<u8>>,
+    /// The install mode, `3` for an upgrade by default.
+    pub mode: Option<i32>,
+}
+
+/// Transfer tokens from one of the treasuries of the SNS.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct TransferSnsTreasuryFunds {
+    /// The treasury the tokens are transferred from, see [`treasury`].
+    pub from_treasury: i32,
+    pub to_principal: Option<Principal>,
+    pub to_subaccount: Option<Subaccount>,
+    pub memo: Option<u64>,
+    pub amount_e8s: u64,
+}
+
+/// Call a method registered as a generic function of the SNS with the payload.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct ExecuteGenericNervousSystemFunction {
+    pub function_id: u64,
+    /// The candid encoded arguments of the method.
+    pub payload: Vec<u8>,
+}
+
+/// The action of a proposal.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum Action {
+    Motion(Motion),
+    UpgradeSnsControlledCanister(UpgradeSnsControlledCanister),
+    TransferSnsTreasuryFunds(TransferSnsTreasuryFunds),
+    ExecuteGenericNervousSystemFunction(ExecuteGenericNervousSystemFunction),
+}
+
+/// A proposal.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct Proposal {
+    pub title: String,
+    pub summary: String,
+    pub url: String,
+    pub action: Option<Action>,
+}
+
+impl Proposal {
+    /// Create a proposal with the title and the action, without a summary or an url.
+    pub fn new<S: Into<String>>(title: S, action: Action) -> Self {
+        Self {
+            title: title.into(),
+            summary: String::new(),
+            url: String::new(),
+            action: Some(action),
+        }
+    }
+}
+
+/// A vote on a proposal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Vote {
+    Yes,
+    No,
+}
+
+impl From<Vote> for i32 {
+    fn from(vote: Vote) -> Self {
+        match vote {
+            Vote::Yes => 1,
+            Vote::No => 2,
+        }
+    }
+}
+
+/// A command of `manage_neuron`.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum Command {
+    /// Submit a proposal.
+    MakeProposal(Proposal),
+    /// Vote on a proposal.
+    RegisterVote {
+        vote: i32,
+        proposal: Option<ProposalId>,
+    },
+}
+
+/// The arguments of `manage_neuron`.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct ManageNeuron {
+    /// The id of the neuron.
+    pub subaccount: Vec<u8>,
+    pub command: Option<Command>,
+}
+
+impl ManageNeuron {
+    /// Run the command on the neuron.
+    pub fn new(neuron_id: NeuronId, command: Command) -> Self {
+        Self {
+            subaccount: neuron_id.id,
+            command: Some(command),
+        }
+    }
+}
+
+/// The response of the `MakeProposal` command and the arguments of `get_proposal`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct GetProposal {
+    pub proposal_id: Option<ProposalId>,
+}
+
+/// The result of a command of `manage_neuron`.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum CommandResponse {
+    Error(GovernanceError),
+    MakeProposal(GetProposal),
+    RegisterVote(Empty),
+}
+
+/// The response of `manage_neuron`.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct ManageNeuronResponse {
+    pub command: Option<CommandResponse>,
+}
+
+/// The votes on a proposal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct Tally {
+    pub yes: u64,
+    pub no: u64,
+    pub total: u64,
+    pub timestamp_seconds: u64,
+}
+
+/// A proposal and its state.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct ProposalData {
+    pub id: Option<ProposalId>,
+    pub proposer: Option<NeuronId>,
+    pub proposal: Option<Proposal>,
+    pub proposal_creation_timestamp_seconds: u64,
+    pub decided_timestamp_seconds: u64,
+    pub executed_timestamp_seconds: u64,
+    pub failed_timestamp_seconds: u64,
+    pub failure_reason: Option<GovernanceError>,
+    pub latest_tally: Option<Tally>,
+}
+
+/// The result of `get_proposal`.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum GetProposalResult {
+    Error(GovernanceError),
+    Proposal(Box<ProposalData>),
+}
+
+/// The response of `get_proposal`.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct GetProposalResponse {
+    pub result: Option<GetProposalResult>,
+}
+
+/// A client which calls the methods of the governance canister of an SNS.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SnsGovernanceClient {
+    canister_id: Principal,
+}
+
+impl SnsGovernanceClient {
+    /// Create a new client for the governance canister with the given id.
+    pub fn new(canister_id: Principal) -> Self {
+        Self { canister_id }
+    }
+
+    /// Return the id of the governance canister this client calls.
+    pub fn canister_id(&self) -> Principal {
+        self.canister_id
+    }
+
+    /// Run a command on a neuron of the caller.
+    pub async fn manage_neuron(
+        &self,
+        args: ManageNeuron,
+    ) -> Result<ManageNeuronResponse, CallError> {
+        CallBuilder::new(self.canister_id, "manage_neuron")
+            .with_arg(args)
+            .perform_one()
+            .await
+    }
+
+    /// Submit a proposal with the neuron, and return the id of the proposal.
+    pub async fn make_proposal(
+        &self,
+        neuron_id: NeuronId,
+        proposal: Proposal,
+    ) -> Result<Result<ProposalId, GovernanceError>, CallError> {
+        let response = self
+            .manage_neuron(ManageNeuron::new(
+                neuron_id,
+                Command::MakeProposal(proposal),
+            ))
+            .await?;
+
+        Ok(match response.command {
+            Some(CommandResponse::MakeProposal(GetProposal {
+                proposal_id: Some(id),
+            })) => Ok(id),
+            Some(CommandResponse::Error(error)) => Err(error),
+            _ => Err(GovernanceError {
+                error_message: "The response does not have a proposal id.".into(),
+                error_type: 0,
+            }),
+        })
+    }
+
+    /// Return the proposal with the given id.
+    pub async fn get_proposal(&self, proposal_id: u64) -> Result<GetProposalResponse, CallError> {
+        CallBuilder::new(self.canister_id, "get_proposal")
+            .with_arg(GetProposal {
+                proposal_id: Some(ProposalId { id: proposal_id }),
+            })
+            .perform_one()
+            .await
+    }
+}
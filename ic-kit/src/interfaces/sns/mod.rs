@@ -0,0 +1,11 @@
+//! The canisters of a service nervous system (SNS), which is the DAO controlling a dapp.
+//!
+//! The root canister controls the canisters of the dapp and upgrades them, the governance canister
+//! manages the neurons and the proposals, and the swap canister runs the decentralization swap.
+//! The ledger of an SNS is an ICRC-1 ledger, whose client is [`Icrc1Client`].
+//!
+//! [`Icrc1Client`]: super::icrc1::Icrc1Client
+
+pub mod governance;
+pub mod root;
+pub mod swap;
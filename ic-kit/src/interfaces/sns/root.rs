@@ -0,0 +1,49 @@
+//! The root canister of an SNS, which controls the other canisters of the SNS and of the dapp.
+
+use crate::ic::{CallBuilder, CallError};
+use candid::{CandidType, Principal};
+use serde::Deserialize;
+
+/// The arguments of `list_sns_canisters`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, CandidType, Deserialize)]
+pub struct ListSnsCanistersRequest {}
+
+/// The canisters of the SNS and of the dapp it controls.
+#[derive(Clone, Debug, Default, PartialEq, Eq, CandidType, Deserialize)]
+pub struct ListSnsCanistersResponse {
+    pub root: Option<Principal>,
+    pub governance: Option<Principal>,
+    pub ledger: Option<Principal>,
+    pub swap: Option<Principal>,
+    pub index: Option<Principal>,
+    /// The canisters of the dapp.
+    pub dapps: Vec<Principal>,
+    /// The archives of the ledger.
+    pub archives: Vec<Principal>,
+}
+
+/// A client which calls the methods of the root canister of an SNS.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SnsRootClient {
+    canister_id: Principal,
+}
+
+impl SnsRootClient {
+    /// Create a new client for the root canister with the given id.
+    pub fn new(canister_id: Principal) -> Self {
+        Self { canister_id }
+    }
+
+    /// Return the id of the root canister this client calls.
+    pub fn canister_id(&self) -> Principal {
+        self.canister_id
+    }
+
+    /// Return the canisters of the SNS and of the dapp it controls.
+    pub async fn list_sns_canisters(&self) -> Result<ListSnsCanistersResponse, CallError> {
+        CallBuilder::new(self.canister_id, "list_sns_canisters")
+            .with_arg(ListSnsCanistersRequest {})
+            .perform_one()
+            .await
+    }
+}
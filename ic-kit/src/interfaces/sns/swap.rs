@@ -0,0 +1,127 @@
+//! The swap canister of an SNS, which sells the tokens of the SNS for ICP during the
+//! decentralization swap.
+
+use crate::ic::{CallBuilder, CallError};
+use candid::{CandidType, Principal};
+use serde::Deserialize;
+
+/// The lifecycle of a swap, as the value of the `Lifecycle` enum of the swap canister.
+pub mod lifecycle {
+    pub const PENDING: i32 = 1;
+    pub const OPEN: i32 = 2;
+    pub const COMMITTED: i32 = 3;
+    pub const ABORTED: i32 = 4;
+    pub const ADOPTED: i32 = 5;
+}
+
+/// The arguments of `get_lifecycle`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, CandidType, Deserialize)]
+pub struct GetLifecycleRequest {}
+
+/// The response of `get_lifecycle`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, CandidType, Deserialize)]
+pub struct GetLifecycleResponse {
+    /// The lifecycle of the swap, see [`lifecycle`].
+    pub lifecycle: Option<i32>,
+    pub decentralization_sale_open_timestamp_seconds: Option<u64>,
+    pub decentralization_swap_termination_timestamp_seconds: Option<u64>,
+}
+
+/// The arguments of `refresh_buyer_tokens`.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct RefreshBuyerTokensRequest {
+    /// The principal of the buyer, as text.
+    pub buyer: String,
+    /// The confirmation text of the swap, if it requires one.
+    pub confirmation_text: Option<String>,
+}
+
+/// The response of `refresh_buyer_tokens`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct RefreshBuyerTokensResponse {
+    /// The participation of the buyer accepted by the swap.
+    pub icp_accepted_participation_e8s: u64,
+    /// The balance of the account of the buyer on the ICP ledger.
+    pub icp_ledger_account_balance_e8s: u64,
+}
+
+/// The arguments of `get_buyer_state`.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct GetBuyerStateRequest {
+    pub principal_id: Option<Principal>,
+}
+
+/// An amount of ICP committed by a buyer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct TransferableAmount {
+    pub amount_e8s: u64,
+    pub transfer_start_timestamp_seconds: u64,
+    pub transfer_success_timestamp_seconds: u64,
+}
+
+/// The participation of a buyer.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct BuyerState {
+    pub icp: Option<TransferableAmount>,
+    pub has_created_neuron_recipes: Option<bool>,
+}
+
+/// The response of `get_buyer_state`.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct GetBuyerStateResponse {
+    pub buyer_state: Option<BuyerState>,
+}
+
+/// A client which calls the methods of the swap canister of an SNS.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SnsSwapClient {
+    canister_id: Principal,
+}
+
+impl SnsSwapClient {
+    /// Create a new client for the swap canister with the given id.
+    pub fn new(canister_id: Principal) -> Self {
+        Self { canister_id }
+    }
+
+    /// Return the id of the swap canister this client calls.
+    pub fn canister_id(&self) -> Principal {
+        self.canister_id
+    }
+
+    /// Return the lifecycle of the swap.
+    pub async fn get_lifecycle(&self) -> Result<GetLifecycleResponse, CallError> {
+        CallBuilder::new(self.canister_id, "get_lifecycle")
+            .with_arg(GetLifecycleRequest {})
+            .perform_one()
+            .await
+    }
+
+    /// Notify the swap of the ICP the buyer transferred to its account on the swap.
+    pub async fn refresh_buyer_tokens(
+        &self,
+        buyer: Principal,
+        confirmation_text: Option<String>,
+    ) -> Result<RefreshBuyerTokensResponse, CallError> {
+        CallBuilder::new(self.canister_id, "refresh_buyer_tokens")
+            .with_arg(RefreshBuyerTokensRequest {
+                buyer: buyer.to_text(),
+                confirmation_text,
+            })
+            .perform_one()
+            .await
+    }
+
+    /// Return the participation of the buyer.
+    pub async fn get_buyer_state(
+        &self,
+        buyer: Principal,
+    ) -> Result<GetBuyerStateResponse, CallError> {
+        CallBuilder::new(self.canister_id, "get_buyer_state")
+            .with_arg(GetBuyerStateRequest {
+                principal_id: Some(buyer),
+            })
+            .perform_one()
+            .await
+    }
+}
@@ -0,0 +1,180 @@
+//! The [exchange rate canister](https://github.com/dfinity/exchange-rate-canister), which returns
+//! the rates between cryptocurrencies and fiat currencies.
+
+use crate::ic::{CallBuilder, CallError, Cycles};
+use candid::{CandidType, Principal};
+use serde::Deserialize;
+
+/// The id of the exchange rate canister on the mainnet, `uf6dk-hyaaa-aaaaq-qaaaq-cai`.
+pub const XRC_CANISTER_ID: Principal = Principal::from_slice(&[0, 0, 0, 0, 2, 16, 0, 1, 1, 1]);
+
+/// The cycles attached to each call, the exchange rate canister refunds the ones it does not use.
+pub const XRC_REQUEST_CYCLES_COST: Cycles = 1_000_000_000;
+
+/// The class of an asset.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, CandidType, Deserialize)]
+pub enum AssetClass {
+    Cryptocurrency,
+    FiatCurrency,
+}
+
+/// An asset, identified by its symbol such as `ICP` or `USD`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, CandidType, Deserialize)]
+pub struct Asset {
+    /// The symbol of the asset.
+    pub symbol: String,
+    /// The class of the asset.
+    pub class: AssetClass,
+}
+
+impl Asset {
+    /// Create a cryptocurrency asset.
+    pub fn crypto<S: Into<String>>(symbol: S) -> Self {
+        Self {
+            symbol: symbol.into(),
+            class: AssetClass::Cryptocurrency,
+        }
+    }
+
+    /// Create a fiat currency asset.
+    pub fn fiat<S: Into<String>>(symbol: S) -> Self {
+        Self {
+            symbol: symbol.into(),
+            class: AssetClass::FiatCurrency,
+        }
+    }
+}
+
+/// The arguments of `get_exchange_rate`.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct GetExchangeRateRequest {
+    /// The asset whose price is returned.
+    pub base_asset: Asset,
+    /// The asset the price is expressed in.
+    pub quote_asset: Asset,
+    /// The time in seconds since the unix epoch the rate is requested for, the start of the
+    /// current minute by default.
+    pub timestamp: Option<u64>,
+}
+
+impl GetExchangeRateRequest {
+    /// Request the current rate of the pair of assets.
+    pub fn new(base_asset: Asset, quote_asset: Asset) -> Self {
+        Self {
+            base_asset,
+            quote_asset,
+            timestamp: None,
+        }
+    }
+}
+
+/// The metadata of a rate.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct ExchangeRateMetadata {
+    /// The number of decimals of the rate.
+    pub decimals: u32,
+    pub base_asset_num_received_rates: u64,
+    pub base_asset_num_queried_sources: u64,
+    pub quote_asset_num_received_rates: u64,
+    pub quote_asset_num_queried_sources: u64,
+    /// The standard deviation of the received rates, with the decimals of the rate.
+    pub standard_deviation: u64,
+    /// The time of the forex rates which were used, if any.
+    pub forex_timestamp: Option<u64>,
+}
+
+/// The rate of a pair of assets.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct ExchangeRate {
+    /// The asset whose price is returned.
+    pub base_asset: Asset,
+    /// The asset the price is expressed in.
+    pub quote_asset: Asset,
+    /// The time of the rate in seconds since the unix epoch.
+    pub timestamp: u64,
+    /// The price of one unit of the base asset, with `metadata.decimals` decimals.
+    pub rate: u64,
+    /// The metadata of the rate.
+    pub metadata: ExchangeRateMetadata,
+}
+
+impl ExchangeRate {
+    /// Return the rate as a float, which is only suitable for displaying it.
+    pub fn as_f64(&self) -> f64 {
+        self.rate as f64 / 10f64.powi(self.metadata.decimals as i32)
+    }
+}
+
+/// An error of the exchange rate canister which does not have its own variant.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct OtherError {
+    pub code: u32,
+    pub description: String,
+}
+
+/// The error of `get_exchange_rate`.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum ExchangeRateError {
+    AnonymousPrincipalNotAllowed,
+    Pending,
+    CryptoBaseAssetNotFound,
+    CryptoQuoteAssetNotFound,
+    StablecoinRateNotFound,
+    StablecoinRateTooFewRates,
+    StablecoinRateZeroRate,
+    ForexInvalidTimestamp,
+    ForexBaseAssetNotFound,
+    ForexQuoteAssetNotFound,
+    ForexAssetsNotFound,
+    RateLimited,
+    NotEnoughCycles,
+    FailedToAcceptCycles,
+    InconsistentRatesReceived,
+    Other(OtherError),
+}
+
+/// A client which calls the exchange rate canister, and attaches the cycles it charges to each
+/// call.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct XrcClient {
+    canister_id: Principal,
+    cycles: Cycles,
+}
+
+impl XrcClient {
+    /// Create a new client for the exchange rate canister with the given id.
+    pub fn new(canister_id: Principal) -> Self {
+        Self {
+            canister_id,
+            cycles: XRC_REQUEST_CYCLES_COST,
+        }
+    }
+
+    /// Create a new client for the exchange rate canister on the mainnet.
+    pub fn mainnet() -> Self {
+        Self::new(XRC_CANISTER_ID)
+    }
+
+    /// Attach the given amount of cycles to each call instead of [`XRC_REQUEST_CYCLES_COST`].
+    pub fn with_cycles(mut self, cycles: Cycles) -> Self {
+        self.cycles = cycles;
+        self
+    }
+
+    /// Return the id of the exchange rate canister this client calls.
+    pub fn canister_id(&self) -> Principal {
+        self.canister_id
+    }
+
+    /// Return the rate of the pair of assets.
+    pub async fn get_exchange_rate(
+        &self,
+        request: GetExchangeRateRequest,
+    ) -> Result<Result<ExchangeRate, ExchangeRateError>, CallError> {
+        CallBuilder::new(self.canister_id, "get_exchange_rate")
+            .with_arg(request)
+            .with_payment(self.cycles)
+            .perform_one()
+            .await
+    }
+}
@@ -0,0 +1,366 @@
+//! An append-only journal of the events of the canister, kept in the stable memory, which gives an
+//! audit trail of the changes of its state, and shows what happened in a test.
+//!
+//! The events are typed like the ones of [`crate::pubsub`], their kind is the topic given with
+//! `#[derive(Event)]`, and each entry records the time and the caller of the message which
+//! appended it. The oldest entries are pruned according to the [`JournalConfig`].
+//!
+//! ```ignore
+//! #[derive(CandidType, Deserialize, Event)]
+//! #[topic("ledger.transfer")]
+//! pub struct Transferred {
+//!     to: Principal,
+//!     amount: u64,
+//! }
+//!
+//! #[post_upgrade]
+//! fn post_upgrade() {
+//!     journal::init(MemoryId::new(3));
+//! }
+//!
+//! #[update]
+//! fn transfer(to: Principal, amount: u64) {
+//!     // ...
+//!     journal::record(&Transferred { to, amount });
+//! }
+//!
+//! export_journal!();
+//! ```
+
+use crate::ic::{caller, maybe_with, maybe_with_mut, swap, time, trap, with};
+use crate::pagination::{decode_cursor, Page, PageLimits, PageRequest};
+use crate::pubsub::Event;
+use crate::stable::{
+    DefaultMemory, MemoryId, MemoryManager, StableBTreeMap, Storable, VirtualMemory,
+};
+use candid::{decode_one, encode_one, CandidType, Principal};
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::ops::Bound;
+
+/// The number of entries kept by default, the oldest ones are pruned first.
+pub const DEFAULT_MAX_ENTRIES: u64 = 100_000;
+
+/// An event in the journal.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct JournalEntry {
+    /// The sequence number of the entry, which is never reused, even after the entry is pruned.
+    pub seq: u64,
+    /// The time the entry was appended, in nanoseconds.
+    pub time: u64,
+    /// The caller of the message which appended the entry.
+    pub caller: Principal,
+    /// The kind of the event, which is the topic of its type.
+    pub kind: String,
+    /// The candid encoded event.
+    pub data: Vec<u8>,
+}
+
+impl JournalEntry {
+    /// Decode the event, or return `None` if the entry is not an event of this type.
+    pub fn decode<E: Event>(&self) -> Option<E> {
+        if self.kind != E::TOPIC {
+            return None;
+        }
+
+        decode_one(&self.data).ok()
+    }
+}
+
+impl Storable for JournalEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        decode_one(&bytes).unwrap()
+    }
+}
+
+/// The pruning policy of the journal, the entries are pruned as soon as one of the limits is
+/// exceeded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct JournalConfig {
+    /// The maximum number of entries, the oldest ones are pruned first.
+    pub max_entries: Option<u64>,
+    /// The maximum age of an entry in nanoseconds.
+    pub max_age: Option<u64>,
+}
+
+impl Default for JournalConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: Some(DEFAULT_MAX_ENTRIES),
+            max_age: None,
+        }
+    }
+}
+
+/// The journal of the canister, set by [`init`].
+struct Journal {
+    entries: StableBTreeMap<u64, JournalEntry, VirtualMemory<DefaultMemory>>,
+    next_seq: u64,
+    config: JournalConfig,
+}
+
+/// Load the journal stored in the virtual memory with the given id of the canister's
+/// `MemoryManager`, or create an empty one. This must be called from the `init` and the
+/// `post_upgrade` hooks.
+pub fn init(id: MemoryId) {
+    let memory = with(|manager: &MemoryManager| manager.get(id));
+    let entries = StableBTreeMap::<u64, JournalEntry, _>::init(memory);
+    let next_seq = entries
+        .last_key_value()
+        .map(|(seq, _)| seq + 1)
+        .unwrap_or(0);
+
+    swap(Journal {
+        entries,
+        next_seq,
+        config: JournalConfig::default(),
+    });
+}
+
+fn with_journal<U, F: FnOnce(&mut Journal) -> U>(f: F) -> U {
+    maybe_with_mut(f).unwrap_or_else(|| trap("The journal is not initialized, call journal::init."))
+}
+
+/// Set the pruning policy of the journal, the entries which exceed it are pruned right away.
+pub fn set_config(config: JournalConfig) {
+    with_journal(|journal| {
+        journal.config = config;
+        journal.prune();
+    });
+}
+
+/// Append the event to the journal, and prune the entries which exceed the policy. Returns the
+/// sequence number of the entry.
+pub fn record<E: Event>(event: &E) -> u64 {
+    let data = encode_one(event).expect("Could not encode the event.");
+
+    with_journal(|journal| {
+        let seq = journal.next_seq;
+        journal.next_seq += 1;
+
+        journal.entries.insert(
+            seq,
+            JournalEntry {
+                seq,
+                time: time(),
+                caller: caller(),
+                kind: E::TOPIC.to_string(),
+                data,
+            },
+        );
+
+        journal.prune();
+        seq
+    })
+}
+
+/// Prune the entries which exceed the policy, such as the ones which got too old since the last
+/// event. This is meant to be called periodically such as from the heartbeat. Returns the number
+/// of entries pruned.
+pub fn prune() -> u64 {
+    with_journal(|journal| journal.prune())
+}
+
+impl Journal {
+    fn prune(&mut self) -> u64 {
+        let oldest = self
+            .config
+            .max_age
+            .map(|max_age| time().saturating_sub(max_age));
+        let mut pruned = 0;
+
+        while let Some((seq, entry)) = self.entries.first_key_value() {
+            let too_many = matches!(self.config.max_entries, Some(max) if self.entries.len() > max);
+            let too_old = matches!(oldest, Some(oldest) if entry.time < oldest);
+
+            if !too_many && !too_old {
+                break;
+            }
+
+            self.entries.remove(&seq);
+            pruned += 1;
+        }
+
+        pruned
+    }
+}
+
+/// Return the entry with the given sequence number, if it was not pruned.
+pub fn get(seq: u64) -> Option<JournalEntry> {
+    with_journal(|journal| journal.entries.get(&seq))
+}
+
+/// Return the number of entries in the journal.
+pub fn len() -> u64 {
+    maybe_with(|journal: &Journal| journal.entries.len()).unwrap_or(0)
+}
+
+/// Return the events of the given type with their sequence numbers, in the order they were
+/// recorded.
+pub fn events<E: Event>() -> Vec<(u64, E)> {
+    with_journal(|journal| {
+        journal
+            .entries
+            .iter()
+            .filter_map(|(seq, entry)| Some((seq, entry.decode::<E>()?)))
+            .collect()
+    })
+}
+
+/// Return the requested page of the entries, in the order they were recorded, only with the
+/// events of the given kind if it's set.
+pub fn page(
+    request: &PageRequest,
+    kind: Option<&str>,
+    limits: PageLimits,
+) -> Result<Page<JournalEntry>, String> {
+    let start = match &request.cursor {
+        Some(cursor) => Bound::Excluded(decode_cursor::<u64>(cursor)?),
+        None => Bound::Unbounded,
+    };
+    let size = limits.page_size(request);
+
+    with_journal(|journal| {
+        let entries = journal
+            .entries
+            .range((start, Bound::Unbounded))
+            .filter(|(_, entry)| match kind {
+                Some(kind) => entry.kind == kind,
+                None => true,
+            });
+
+        Page::from_entries_within(entries, size, limits.max_bytes)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rt::context::MockContext;
+
+    const START: u64 = 1_000_000_000_000;
+
+    #[derive(Clone, Debug, PartialEq, CandidType, Deserialize)]
+    struct Transferred {
+        amount: u64,
+    }
+
+    impl Event for Transferred {
+        const TOPIC: &'static str = "ledger.transfer";
+    }
+
+    #[derive(Clone, Debug, PartialEq, CandidType, Deserialize)]
+    struct Paused;
+
+    impl Event for Paused {
+        const TOPIC: &'static str = "ledger.paused";
+    }
+
+    fn seqs(page: &Page<JournalEntry>) -> Vec<u64> {
+        page.items.iter().map(|entry| entry.seq).collect()
+    }
+
+    #[test]
+    fn record_events() {
+        let alice = Principal::from_slice(&[1]);
+        MockContext::new()
+            .with_time(START)
+            .with_caller(alice)
+            .inject();
+        init(MemoryId::new(0));
+
+        assert_eq!(record(&Transferred { amount: 10 }), 0);
+        assert_eq!(record(&Paused), 1);
+        assert_eq!(record(&Transferred { amount: 20 }), 2);
+
+        let entry = get(1).unwrap();
+        assert_eq!((entry.time, entry.caller), (START, alice));
+        assert_eq!(entry.decode::<Paused>(), Some(Paused));
+        assert_eq!(entry.decode::<Transferred>(), None);
+        assert_eq!(
+            events::<Transferred>(),
+            vec![
+                (0, Transferred { amount: 10 }),
+                (2, Transferred { amount: 20 })
+            ]
+        );
+
+        // The journal is reloaded from the stable memory after an upgrade.
+        init(MemoryId::new(0));
+        assert_eq!(len(), 3);
+        assert_eq!(record(&Paused), 3);
+    }
+
+    #[test]
+    fn pruning() {
+        let ctx = MockContext::new().with_time(START).inject();
+        init(MemoryId::new(0));
+        set_config(JournalConfig {
+            max_entries: Some(3),
+            max_age: Some(100),
+        });
+
+        for amount in 0..5 {
+            record(&Transferred { amount });
+        }
+
+        // The oldest entries are pruned, and the sequence numbers are not reused.
+        assert_eq!(len(), 3);
+        assert_eq!(get(1), None);
+        assert_eq!(events::<Transferred>()[0].0, 2);
+
+        let ctx = ctx.with_time(START + 50);
+        record(&Paused);
+        assert_eq!(len(), 3);
+        assert_eq!(prune(), 0);
+
+        let _ctx = ctx.with_time(START + 101);
+        assert_eq!(prune(), 2);
+        assert_eq!(len(), 1);
+        assert_eq!(get(5).unwrap().decode::<Paused>(), Some(Paused));
+
+        set_config(JournalConfig {
+            max_entries: Some(0),
+            max_age: None,
+        });
+        assert_eq!(len(), 0);
+    }
+
+    #[test]
+    fn pagination() {
+        MockContext::new().with_time(START).inject();
+        init(MemoryId::new(0));
+
+        for amount in 0..5 {
+            record(&Transferred { amount });
+            record(&Paused);
+        }
+
+        let limits = PageLimits::new(4, 6);
+        let first = page(&PageRequest::new(), None, limits).unwrap();
+        assert_eq!(seqs(&first), vec![0, 1, 2, 3]);
+
+        let request = PageRequest::new()
+            .with_cursor(first.next_cursor.unwrap())
+            .with_limit(100);
+        let second = page(&request, None, limits).unwrap();
+        assert_eq!(seqs(&second), vec![4, 5, 6, 7, 8, 9]);
+        assert!(second.is_last());
+
+        // The pages can be filtered by the kind of the events.
+        let request = PageRequest::new().with_limit(3);
+        let transfers = page(&request, Some(Transferred::TOPIC), limits).unwrap();
+        assert_eq!(seqs(&transfers), vec![0, 2, 4]);
+
+        let request = request.with_cursor(transfers.next_cursor.unwrap());
+        let transfers = page(&request, Some(Transferred::TOPIC), limits).unwrap();
+        assert_eq!(seqs(&transfers), vec![6, 8]);
+        assert!(transfers.is_last());
+
+        assert!(page(&PageRequest::new().with_cursor("?"), None, limits).is_err());
+    }
+}
@@ -0,0 +1,460 @@
+//! A key-value store trait implemented on the heap and in the stable memory, so the business logic
+//! of a canister is written once and the backend is picked per deployment.
+//!
+//! ```ignore
+//! #[derive(CandidType, Deserialize)]
+//! pub struct InitArg {
+//!     backend: KvBackend,
+//! }
+//!
+//! #[init]
+//! fn init(arg: InitArg) {
+//!     ic::swap(Kv::<Principal, u64>::new(arg.backend));
+//! }
+//!
+//! fn credit<S: KvStore<Principal, u64>>(balances: &mut S, user: Principal, amount: u64) {
+//!     let balance = balances.get(&user).unwrap_or_default();
+//!     balances.insert(user, balance + amount);
+//! }
+//! ```
+//!
+//! The heap backend is not kept across upgrades by itself, its entries are saved with the rest of
+//! the heap state, see [`KvStore::entries`] and [`Kv::from_entries`]. A store can be moved to the other
+//! backend with [`Kv::migrate_to`].
+
+use crate::ic::with;
+use crate::stable::{
+    DefaultMemory, MemoryId, MemoryManager, StableBTreeMap, Storable, VirtualMemory,
+};
+use candid::CandidType;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::hash::{BuildHasher, Hash};
+
+/// A map from the keys to the values, implemented by the heap and the stable backends.
+pub trait KvStore<K, V> {
+    /// Return the value of the key.
+    fn get(&self, key: &K) -> Option<V>;
+
+    /// Insert the value, and return the previous value of the key.
+    fn insert(&mut self, key: K, value: V) -> Option<V>;
+
+    /// Remove the key, and return its value.
+    fn remove(&mut self, key: &K) -> Option<V>;
+
+    /// Return the number of the entries.
+    fn len(&self) -> u64;
+
+    /// Return all of the entries in the order of the keys.
+    fn entries(&self) -> Vec<(K, V)>;
+
+    /// Remove all of the entries.
+    fn clear(&mut self);
+
+    /// Return true if the key has a value.
+    fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Return true if there are no entries.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<K, V, S> KvStore<K, V> for HashMap<K, V, S>
+where
+    K: Hash + Ord + Clone,
+    V: Clone,
+    S: BuildHasher,
+{
+    fn get(&self, key: &K) -> Option<V> {
+        HashMap::get(self, key).cloned()
+    }
+
+    fn insert(&mut self, key: K, value: V) -> Option<V> {
+        HashMap::insert(self, key, value)
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        HashMap::remove(self, key)
+    }
+
+    fn len(&self) -> u64 {
+        HashMap::len(self) as u64
+    }
+
+    fn entries(&self) -> Vec<(K, V)> {
+        let mut entries = self
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect::<Vec<_>>();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries
+    }
+
+    fn clear(&mut self) {
+        HashMap::clear(self)
+    }
+}
+
+impl<K, V> KvStore<K, V> for BTreeMap<K, V>
+where
+    K: Ord + Clone,
+    V: Clone,
+{
+    fn get(&self, key: &K) -> Option<V> {
+        BTreeMap::get(self, key).cloned()
+    }
+
+    fn insert(&mut self, key: K, value: V) -> Option<V> {
+        BTreeMap::insert(self, key, value)
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        BTreeMap::remove(self, key)
+    }
+
+    fn len(&self) -> u64 {
+        BTreeMap::len(self) as u64
+    }
+
+    fn entries(&self) -> Vec<(K, V)> {
+        self.iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+
+    fn clear(&mut self) {
+        BTreeMap::clear(self)
+    }
+}
+
+impl<K, V, M> KvStore<K, V> for StableBTreeMap<K, V, M>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: crate::stable::Memory,
+{
+    fn get(&self, key: &K) -> Option<V> {
+        StableBTreeMap::get(self, key)
+    }
+
+    fn insert(&mut self, key: K, value: V) -> Option<V> {
+        StableBTreeMap::insert(self, key, value)
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        StableBTreeMap::remove(self, key)
+    }
+
+    fn len(&self) -> u64 {
+        StableBTreeMap::len(self)
+    }
+
+    fn entries(&self) -> Vec<(K, V)> {
+        self.iter().collect()
+    }
+
+    fn clear(&mut self) {
+        StableBTreeMap::clear(self)
+    }
+
+    fn contains_key(&self, key: &K) -> bool {
+        StableBTreeMap::contains_key(self, key)
+    }
+}
+
+/// The backend of a [`Kv`], which can be part of the init arguments of the canister.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum KvBackend {
+    /// A `HashMap` on the heap.
+    Heap,
+    /// A `StableBTreeMap` in the virtual memory with the given id of the canister's
+    /// `MemoryManager`.
+    Stable(u8),
+}
+
+/// A key-value store whose backend is chosen when it's created.
+pub struct Kv<K, V> {
+    backend: KvBackend,
+    store: Store<K, V>,
+}
+
+enum Store<K, V> {
+    Heap(HashMap<K, V>),
+    Stable(Box<StableBTreeMap<K, V, VirtualMemory<DefaultMemory>>>),
+}
+
+impl<K, V> Kv<K, V>
+where
+    K: Storable + Hash + Ord + Clone,
+    V: Storable + Clone,
+{
+    /// Create a store with the given backend, the stable backend loads the entries already in its
+    /// virtual memory.
+    pub fn new(backend: KvBackend) -> Self {
+        let store = match backend {
+            KvBackend::Heap => Store::Heap(HashMap::new()),
+            KvBackend::Stable(id) => {
+                let memory = with(|manager: &MemoryManager| manager.get(MemoryId::new(id)));
+                Store::Stable(Box::new(StableBTreeMap::init(memory)))
+            }
+        };
+
+        Self { backend, store }
+    }
+
+    /// Create a store with the given backend and insert the entries, such as the entries of a
+    /// heap store saved before an upgrade.
+    pub fn from_entries<I: IntoIterator<Item = (K, V)>>(backend: KvBackend, entries: I) -> Self {
+        let mut store = Self::new(backend);
+        for (key, value) in entries {
+            store.insert(key, value);
+        }
+        store
+    }
+
+    /// Return the backend of the store.
+    pub fn backend(&self) -> KvBackend {
+        self.backend
+    }
+
+    /// Move all of the entries to a store with the given backend, which replaces this one. The
+    /// entries left in the memory of a previous stable backend are cleared.
+    ///
+    /// # Panics
+    ///
+    /// If the virtual memory of the new stable backend already contains entries.
+    pub fn migrate_to(&mut self, backend: KvBackend) {
+        if self.backend == backend {
+            return;
+        }
+
+        let entries = self.entries();
+        let mut store = Self::new(backend);
+        assert!(
+            store.is_empty(),
+            "The memory of the new backend already contains entries."
+        );
+
+        for (key, value) in entries {
+            store.insert(key, value);
+        }
+
+        self.clear();
+        *self = store;
+    }
+}
+
+impl<K, V> KvStore<K, V> for Kv<K, V>
+where
+    K: Storable + Hash + Ord + Clone,
+    V: Storable + Clone,
+{
+    fn get(&self, key: &K) -> Option<V> {
+        match &self.store {
+            Store::Heap(map) => KvStore::get(map, key),
+            Store::Stable(map) => KvStore::get(&**map, key),
+        }
+    }
+
+    fn insert(&mut self, key: K, value: V) -> Option<V> {
+        match &mut self.store {
+            Store::Heap(map) => KvStore::insert(map, key, value),
+            Store::Stable(map) => KvStore::insert(&mut **map, key, value),
+        }
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        match &mut self.store {
+            Store::Heap(map) => KvStore::remove(map, key),
+            Store::Stable(map) => KvStore::remove(&mut **map, key),
+        }
+    }
+
+    fn len(&self) -> u64 {
+        match &self.store {
+            Store::Heap(map) => KvStore::len(map),
+            Store::Stable(map) => KvStore::len(&**map),
+        }
+    }
+
+    fn entries(&self) -> Vec<(K, V)> {
+        match &self.store {
+            Store::Heap(map) => KvStore::entries(map),
+            Store::Stable(map) => KvStore::entries(&**map),
+        }
+    }
+
+    fn clear(&mut self) {
+        match &mut self.store {
+            Store::Heap(map) => KvStore::clear(map),
+            Store::Stable(map) => KvStore::clear(&mut **map),
+        }
+    }
+}
+
+/// An operation on a store, see [`check_equivalence`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KvOp<K, V> {
+    Get(K),
+    Insert(K, V),
+    Remove(K),
+    ContainsKey(K),
+    Clear,
+}
+
+/// Apply the operations to the store and to a `BTreeMap`, and return an error describing the
+/// first operation whose result differs, or the first difference of the entries at the end. The
+/// tests of a canister can run the same operations on each backend, to make sure switching the
+/// backend does not change the behavior of the canister.
+///
+/// ```ignore
+/// let ops = vec![KvOp::Insert(1, 10), KvOp::Remove(1), KvOp::Get(1)];
+/// check_equivalence(&mut HashMap::new(), &ops).unwrap();
+/// check_equivalence(&mut Kv::new(KvBackend::Stable(0)), &ops).unwrap();
+/// ```
+pub fn check_equivalence<K, V, S>(store: &mut S, ops: &[KvOp<K, V>]) -> Result<(), String>
+where
+    K: Ord + Clone + Debug,
+    V: Clone + PartialEq + Debug,
+    S: KvStore<K, V>,
+{
+    let mut reference = BTreeMap::new();
+
+    for (index, op) in ops.iter().enumerate() {
+        let (expected, actual) = match op {
+            KvOp::Get(key) => (
+                format!("{:?}", KvStore::get(&reference, key)),
+                format!("{:?}", store.get(key)),
+            ),
+            KvOp::Insert(key, value) => (
+                format!(
+                    "{:?}",
+                    KvStore::insert(&mut reference, key.clone(), value.clone())
+                ),
+                format!("{:?}", store.insert(key.clone(), value.clone())),
+            ),
+            KvOp::Remove(key) => (
+                format!("{:?}", KvStore::remove(&mut reference, key)),
+                format!("{:?}", store.remove(key)),
+            ),
+            KvOp::ContainsKey(key) => (
+                format!("{:?}", reference.contains_key(key)),
+                format!("{:?}", store.contains_key(key)),
+            ),
+            KvOp::Clear => {
+                reference.clear();
+                store.clear();
+                (String::new(), String::new())
+            }
+        };
+
+        if expected != actual {
+            return Err(format!(
+                "Operation {} ({:?}) returned {} instead of {}.",
+                index, op, actual, expected
+            ));
+        }
+
+        if store.len() != reference.len() as u64 {
+            return Err(format!(
+                "The store has {} entries instead of {} after operation {} ({:?}).",
+                store.len(),
+                reference.len(),
+                index,
+                op
+            ));
+        }
+    }
+
+    let expected = KvStore::entries(&reference);
+    let actual = store.entries();
+    if expected != actual {
+        return Err(format!(
+            "The store has the entries {:?} instead of {:?}.",
+            actual, expected
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rt::context::MockContext;
+
+    fn ops() -> Vec<KvOp<u64, u64>> {
+        vec![
+            KvOp::Insert(1, 10),
+            KvOp::Insert(2, 20),
+            KvOp::Insert(1, 11),
+            KvOp::Get(1),
+            KvOp::Remove(2),
+            KvOp::Remove(2),
+            KvOp::Get(2),
+            KvOp::ContainsKey(1),
+            KvOp::ContainsKey(2),
+            KvOp::Insert(3, 30),
+            KvOp::Clear,
+            KvOp::Get(3),
+            KvOp::Insert(4, 40),
+        ]
+    }
+
+    #[test]
+    fn equivalence() {
+        MockContext::new().inject();
+
+        check_equivalence(&mut HashMap::new(), &ops()).unwrap();
+        check_equivalence(&mut BTreeMap::new(), &ops()).unwrap();
+        check_equivalence(&mut Kv::new(KvBackend::Heap), &ops()).unwrap();
+        check_equivalence(&mut Kv::new(KvBackend::Stable(0)), &ops()).unwrap();
+    }
+
+    #[test]
+    fn equivalence_error() {
+        let mut store = HashMap::new();
+        store.insert(1, 10);
+
+        assert_eq!(
+            check_equivalence(&mut store, &[KvOp::Get(1u64)]),
+            Err("Operation 0 (Get(1)) returned Some(10) instead of None.".to_string())
+        );
+    }
+
+    #[test]
+    fn migrate() {
+        MockContext::new().inject();
+
+        let mut store = Kv::from_entries(KvBackend::Heap, vec![(1u64, 10u64), (2, 20)]);
+        store.migrate_to(KvBackend::Stable(0));
+        assert_eq!(store.backend(), KvBackend::Stable(0));
+        assert_eq!(store.entries(), vec![(1, 10), (2, 20)]);
+
+        // The entries are loaded from the memory by a new store.
+        assert_eq!(
+            Kv::<u64, u64>::new(KvBackend::Stable(0)).entries(),
+            vec![(1, 10), (2, 20)]
+        );
+
+        store.migrate_to(KvBackend::Heap);
+        assert_eq!(store.backend(), KvBackend::Heap);
+        assert_eq!(store.entries(), vec![(1, 10), (2, 20)]);
+        assert!(Kv::<u64, u64>::new(KvBackend::Stable(0)).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "The memory of the new backend already contains entries.")]
+    fn migrate_to_used_memory() {
+        MockContext::new().inject();
+
+        Kv::from_entries(KvBackend::Stable(1), vec![(1u64, 10u64)]);
+
+        let mut store = Kv::from_entries(KvBackend::Heap, vec![(2u64, 20u64)]);
+        store.migrate_to(KvBackend::Stable(1));
+    }
+}
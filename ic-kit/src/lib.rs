@@ -1,14 +1,72 @@
 mod canister;
+mod error;
 mod futures;
+#[cfg(not(target_family = "wasm"))]
+mod handle;
 mod setup;
 mod storage;
 
+/// A budget of work so the long-running loops stop before the instruction limit of a message.
+pub mod budget;
+
+/// A cache for the results of the calls to other canisters, with expiring entries.
+pub mod cache;
+
+/// Deduplication of the update calls retried with the same idempotency key.
+pub mod dedup;
+
+/// Dump the state of the canister for the controllers, with the endpoints of `export_debug!`.
+#[cfg(feature = "debug-endpoints")]
+pub mod debug;
+
+/// Re-entrancy, concurrency and rate limiting guards for the canister methods.
+pub mod guards;
+
 /// System APIs for the Internet Computer.
 pub mod ic;
 
+/// The types and the router to serve HTTP requests from a canister.
+pub mod http;
+
+/// The types and the clients of the standard canister interfaces.
+pub mod interfaces;
+
+/// An append-only journal of typed events kept in the stable memory, as an audit trail.
+pub mod journal;
+
+/// A key-value store trait with the heap and the stable backends, picked per deployment.
+pub mod kv;
+
+/// A queue of outgoing calls kept in the stable memory, which are retried until they succeed.
+pub mod outbox;
+
+/// Bounded, cursor based pagination for the methods listing a collection.
+pub mod pagination;
+
+/// Publish events to the subscribed canisters, and subscribe to the events of other canisters.
+pub mod pubsub;
+
+/// Serve the replies larger than the message limit from a query as pages, with `#[stream_query]`.
+pub mod reply_stream;
+
+/// A scheduler of background jobs run on the timers, whose schedules survive the upgrades.
+pub mod scheduler;
+
+/// Expiring single-use nonces issued per caller, for the challenge-response authentication flows.
+pub mod session;
+
 /// Helper methods around the stable storage.
 pub mod stable;
 
+/// Transfer blobs larger than the message limit to another canister as a stream of chunks.
+pub mod stream;
+
+/// Timers running a function once a time has passed, on top of the global timer.
+pub mod timers;
+
+/// Changes to the canister's state which are kept or undone depending on the outcome of a flow.
+pub mod transaction;
+
 /// Internal utility methods to deal with reading data.
 pub mod utils;
 
@@ -17,22 +75,50 @@ pub use candid::{self, CandidType, Nat, Principal};
 pub use ic_kit_macros as macros;
 pub use setup::setup_hooks;
 
+// The structured error of the canister methods.
+pub use error::Error;
+
+/// Used by `export_debug!`, its endpoints are only compiled with the `debug-endpoints` feature.
+#[cfg(feature = "debug-endpoints")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __debug_endpoints {
+    ($($item:item)*) => {
+        $($item)*
+    };
+}
+
+#[cfg(not(feature = "debug-endpoints"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __debug_endpoints {
+    ($($item:item)*) => {
+        compile_error!("export_debug! requires the `debug-endpoints` feature of ic-kit, so the debug endpoints are not exposed by accident.");
+    };
+}
+
 // The KitCanister derive macro.
-pub use canister::KitCanister;
+pub use canister::{KitCanister, KitMixin};
 pub use ic_kit_macros::KitCanister;
 
 /// The IC-kit runtime, which can be used for testing the canister in non-wasm environments.
 #[cfg(not(target_family = "wasm"))]
 pub use ic_kit_runtime as rt;
 
+#[cfg(not(target_family = "wasm"))]
+pub use handle::KitHandle;
+
 /// The famous prelude module which re exports the most useful methods.
 pub mod prelude {
-    pub use super::canister::KitCanister;
+    pub use super::canister::{KitCanister, KitMixin};
+    pub use super::guards::{
+        CallBudget, CallBudgetPolicy, ConcurrencyLimit, RateLimiter, ReentrancyGuard,
+    };
     pub use super::ic;
     pub use super::ic::CallBuilder;
     pub use super::ic::{balance, caller, id, spawn};
     pub use super::ic::{maybe_with, maybe_with_mut, swap, take, with, with_mut};
-    pub use super::ic::{Cycles, StableSize};
+    pub use super::ic::{Cycles, ManualReply, Payment, RequestContext, StableSize};
     pub use candid::{CandidType, Nat, Principal};
     pub use serde::{Deserialize, Serialize};
 
@@ -41,6 +127,9 @@ pub mod prelude {
     #[cfg(not(target_family = "wasm"))]
     pub use ic_kit_runtime as rt;
 
+    #[cfg(not(target_family = "wasm"))]
+    pub use super::handle::KitHandle;
+
     #[cfg(not(target_family = "wasm"))]
     pub use ic_kit_runtime::prelude::*;
 }
@@ -0,0 +1,468 @@
+use crate::ic::{maybe_with, maybe_with_mut, spawn, swap, time, trap, with};
+use crate::ic::{CallBuilder, CallError, Cycles};
+use crate::stable::{
+    DefaultMemory, MemoryId, MemoryManager, StableBTreeMap, Storable, VirtualMemory,
+};
+use candid::utils::ArgumentEncoder;
+use candid::{encode_args, encode_one, CandidType, Principal};
+use serde::Deserialize;
+use std::borrow::Cow;
+
+/// The state of an entry of the outbox.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum OutboxStatus {
+    /// The call is waiting to be sent, at `next_attempt_at`.
+    Pending,
+    /// The call was sent and its reply has not been received yet.
+    InFlight,
+    /// The call failed `max_attempts` times, it's kept until it's retried or removed.
+    Failed,
+}
+
+/// A call to send through the outbox.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct OutgoingCall {
+    pub canister_id: Principal,
+    pub method: String,
+    /// The candid encoded arguments.
+    pub arg: Vec<u8>,
+    /// The cycles attached to the call.
+    pub payment: Cycles,
+}
+
+impl OutgoingCall {
+    /// Create a call to the method of the canister without arguments.
+    pub fn new<S: Into<String>>(canister_id: Principal, method: S) -> Self {
+        Self {
+            canister_id,
+            method: method.into(),
+            arg: encode_args(()).unwrap(),
+            payment: 0,
+        }
+    }
+
+    /// Use the given arguments for the call.
+    pub fn with_args<T: ArgumentEncoder>(mut self, arguments: T) -> Self {
+        self.arg = encode_args(arguments).expect("Could not encode the arguments.");
+        self
+    }
+
+    /// Use the given argument for the call.
+    pub fn with_arg<T: CandidType>(mut self, argument: T) -> Self {
+        self.arg = encode_one(argument).expect("Could not encode the argument.");
+        self
+    }
+
+    /// Use the candid encoded arguments for the call.
+    pub fn with_arg_raw<A: Into<Vec<u8>>>(mut self, argument: A) -> Self {
+        self.arg = argument.into();
+        self
+    }
+
+    /// Attach the cycles to the call.
+    pub fn with_payment(mut self, payment: Cycles) -> Self {
+        self.payment = payment;
+        self
+    }
+}
+
+/// A call in the outbox.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct OutboxEntry {
+    pub id: u64,
+    pub call: OutgoingCall,
+    pub status: OutboxStatus,
+    /// The time the call was enqueued, in nanoseconds.
+    pub created_at: u64,
+    /// The number of failed attempts.
+    pub attempts: u32,
+    /// The time the call can be sent again, in nanoseconds.
+    pub next_attempt_at: u64,
+    /// The error of the last failed attempt.
+    pub last_error: Option<String>,
+}
+
+impl Storable for OutboxEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+}
+
+/// The retry policy of the outbox.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct OutboxConfig {
+    /// The number of attempts before a call is marked as failed.
+    pub max_attempts: u32,
+    /// The delay before the first retry in nanoseconds, it doubles after each attempt.
+    pub initial_backoff: u64,
+    /// The maximum delay between two attempts in nanoseconds.
+    pub max_backoff: u64,
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 10,
+            initial_backoff: 1_000_000_000,
+            max_backoff: 3_600_000_000_000,
+        }
+    }
+}
+
+impl OutboxConfig {
+    /// Return the delay before the next attempt after the given number of failed attempts.
+    fn backoff(&self, attempts: u32) -> u64 {
+        let factor = 1u64
+            .checked_shl(attempts.saturating_sub(1))
+            .unwrap_or(u64::MAX);
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+}
+
+/// The function called once a call of the outbox succeeded, or failed for the last time.
+pub type OutboxHandler = fn(&OutboxEntry, Result<Vec<u8>, CallError>);
+
+/// The outbox of the canister, set by [`init`].
+struct Outbox {
+    entries: StableBTreeMap<u64, OutboxEntry, VirtualMemory<DefaultMemory>>,
+    next_id: u64,
+    config: OutboxConfig,
+    handler: Option<OutboxHandler>,
+    in_flight: usize,
+}
+
+/// Load the outbox stored in the virtual memory with the given id of the canister's
+/// `MemoryManager`, or create an empty one. This must be called from the `init` and the
+/// `post_upgrade` hooks.
+///
+/// The calls which were in flight during the upgrade are sent again, since their replies were
+/// lost with the call contexts. The receivers should deduplicate them, for example using the
+/// `created_at_time` of the ICRC-1 transfers.
+pub fn init(id: MemoryId) {
+    let memory = with(|manager: &MemoryManager| manager.get(id));
+    let mut entries = StableBTreeMap::<u64, OutboxEntry, _>::init(memory);
+    let next_id = entries.last_key_value().map(|(id, _)| id + 1).unwrap_or(0);
+
+    let in_flight = entries
+        .iter()
+        .filter(|(_, entry)| entry.status == OutboxStatus::InFlight)
+        .collect::<Vec<_>>();
+
+    for (id, mut entry) in in_flight {
+        entry.status = OutboxStatus::Pending;
+        entries.insert(id, entry);
+    }
+
+    swap(Outbox {
+        entries,
+        next_id,
+        config: OutboxConfig::default(),
+        handler: None,
+        in_flight: 0,
+    });
+}
+
+fn with_outbox<U, F: FnOnce(&mut Outbox) -> U>(f: F) -> U {
+    maybe_with_mut(f).unwrap_or_else(|| trap("The outbox is not initialized, call outbox::init."))
+}
+
+/// Set the retry policy of the outbox.
+pub fn set_config(config: OutboxConfig) {
+    with_outbox(|outbox| outbox.config = config);
+}
+
+/// Set the function called once a call succeeded and is removed from the outbox, or failed for
+/// the last time.
+pub fn set_handler(handler: OutboxHandler) {
+    with_outbox(|outbox| outbox.handler = Some(handler));
+}
+
+/// Add the call to the outbox, it's sent by the next [`drain`]. Returns the id of the entry.
+pub fn enqueue(call: OutgoingCall) -> u64 {
+    with_outbox(|outbox| {
+        let id = outbox.next_id;
+        outbox.next_id += 1;
+
+        let now = time();
+        outbox.entries.insert(
+            id,
+            OutboxEntry {
+                id,
+                call,
+                status: OutboxStatus::Pending,
+                created_at: now,
+                attempts: 0,
+                next_attempt_at: now,
+                last_error: None,
+            },
+        );
+
+        id
+    })
+}
+
+/// Send the pending calls which are due, in the order they were enqueued, so that at most
+/// `max_concurrency` calls are in flight. This is meant to be called periodically such as from
+/// the heartbeat. Returns the number of calls sent.
+pub fn drain(max_concurrency: usize) -> usize {
+    let due = with_outbox(|outbox| {
+        let available = max_concurrency.saturating_sub(outbox.in_flight);
+        let now = time();
+
+        let due = outbox
+            .entries
+            .iter()
+            .filter(|(_, e)| e.status == OutboxStatus::Pending && e.next_attempt_at <= now)
+            .take(available)
+            .map(|(_, mut entry)| {
+                entry.status = OutboxStatus::InFlight;
+                entry
+            })
+            .collect::<Vec<_>>();
+
+        for entry in &due {
+            outbox.entries.insert(entry.id, entry.clone());
+        }
+
+        outbox.in_flight += due.len();
+        due
+    });
+
+    let count = due.len();
+
+    for entry in due {
+        spawn(send(entry));
+    }
+
+    count
+}
+
+async fn send(entry: OutboxEntry) {
+    let result = CallBuilder::new(entry.call.canister_id, &entry.call.method)
+        .with_arg_raw(entry.call.arg.clone())
+        .with_payment(entry.call.payment)
+        .perform_raw()
+        .await;
+
+    let (entry, handler) = with_outbox(|outbox| {
+        outbox.in_flight = outbox.in_flight.saturating_sub(1);
+
+        // The entry might have been removed while it was in flight.
+        let mut entry = outbox.entries.get(&entry.id)?;
+
+        match &result {
+            Ok(_) => {
+                outbox.entries.remove(&entry.id);
+            }
+            Err(error) => {
+                entry.attempts += 1;
+                entry.last_error = Some(error.to_string());
+
+                if entry.attempts >= outbox.config.max_attempts {
+                    entry.status = OutboxStatus::Failed;
+                } else {
+                    entry.status = OutboxStatus::Pending;
+                    entry.next_attempt_at = time() + outbox.config.backoff(entry.attempts);
+                }
+
+                outbox.entries.insert(entry.id, entry.clone());
+            }
+        }
+
+        Some((entry, outbox.handler))
+    })
+    .unwrap_or((entry, None));
+
+    if let Some(handler) = handler {
+        if result.is_ok() || entry.status == OutboxStatus::Failed {
+            handler(&entry, result);
+        }
+    }
+}
+
+/// Return the entries of the outbox, in the order they were enqueued.
+pub fn entries() -> Vec<OutboxEntry> {
+    with_outbox(|outbox| outbox.entries.iter().map(|(_, entry)| entry).collect())
+}
+
+/// Return the entry with the given id.
+pub fn get(id: u64) -> Option<OutboxEntry> {
+    with_outbox(|outbox| outbox.entries.get(&id))
+}
+
+/// Return the number of entries in the outbox.
+pub fn len() -> u64 {
+    maybe_with(|outbox: &Outbox| outbox.entries.len()).unwrap_or(0)
+}
+
+/// Send a failed call again by the next [`drain`], with a new budget of attempts. Returns false
+/// if the entry does not exist or has not failed.
+pub fn retry(id: u64) -> bool {
+    with_outbox(|outbox| match outbox.entries.get(&id) {
+        Some(mut entry) if entry.status == OutboxStatus::Failed => {
+            entry.status = OutboxStatus::Pending;
+            entry.attempts = 0;
+            entry.next_attempt_at = time();
+            outbox.entries.insert(id, entry);
+            true
+        }
+        _ => false,
+    })
+}
+
+/// Remove the entry from the outbox, the reply of a call in flight is then ignored.
+pub fn remove(id: u64) -> Option<OutboxEntry> {
+    with_outbox(|outbox| outbox.entries.remove(&id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rt::context::MockContext;
+    use std::cell::RefCell;
+
+    const START: u64 = 1_000_000_000_000;
+
+    thread_local! {
+        static HANDLED: RefCell<Vec<(u64, bool)>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn record(entry: &OutboxEntry, result: Result<Vec<u8>, CallError>) {
+        HANDLED.with(|handled| handled.borrow_mut().push((entry.id, result.is_ok())));
+    }
+
+    fn handled() -> Vec<(u64, bool)> {
+        HANDLED.with(|handled| handled.borrow().clone())
+    }
+
+    fn ledger() -> Principal {
+        Principal::from_slice(&[1])
+    }
+
+    /// A ledger which rejects the credits of an odd amount.
+    fn context() -> MockContext {
+        let ctx = MockContext::new()
+            .with_time(START)
+            .with_handler(ledger(), "credit", |call| {
+                match call.decode_one::<u64>().unwrap() % 2 {
+                    0 => Ok(encode_one(()).unwrap()),
+                    _ => Err("Odd amount.".into()),
+                }
+            })
+            .inject();
+
+        init(MemoryId::new(0));
+        set_handler(record);
+        set_config(OutboxConfig {
+            max_attempts: 2,
+            initial_backoff: 10,
+            max_backoff: 15,
+        });
+
+        ctx
+    }
+
+    fn credit(amount: u64) -> u64 {
+        enqueue(OutgoingCall::new(ledger(), "credit").with_arg(amount))
+    }
+
+    fn status(id: u64) -> Option<(OutboxStatus, u32)> {
+        get(id).map(|entry| (entry.status, entry.attempts))
+    }
+
+    #[test]
+    fn concurrency() {
+        let ctx = context();
+        let ids: Vec<u64> = [2, 4, 6].iter().map(|amount| credit(*amount)).collect();
+        assert_eq!(ids, vec![0, 1, 2]);
+
+        // At most two calls are in flight, in the order they were enqueued.
+        assert_eq!(drain(2), 2);
+        assert_eq!(status(0), Some((OutboxStatus::InFlight, 0)));
+        assert_eq!(status(2), Some((OutboxStatus::Pending, 0)));
+        assert_eq!(drain(2), 0);
+
+        ctx.resolve_calls();
+        assert_eq!(len(), 1);
+        assert_eq!(drain(2), 1);
+        ctx.resolve_calls();
+
+        assert_eq!(len(), 0);
+        assert_eq!(handled(), vec![(0, true), (1, true), (2, true)]);
+        let amounts: Vec<u64> = ctx
+            .watcher()
+            .calls_to("credit")
+            .iter()
+            .map(|call| call.decode_one().unwrap())
+            .collect();
+        assert_eq!(amounts, vec![2, 4, 6]);
+    }
+
+    #[test]
+    fn retry_with_backoff() {
+        let ctx = context();
+        let id = credit(3);
+
+        drain(10);
+        ctx.resolve_calls();
+        let entry = get(id).unwrap();
+        assert_eq!((entry.status, entry.attempts), (OutboxStatus::Pending, 1));
+        assert_eq!(entry.next_attempt_at, START + 10);
+        assert!(entry.last_error.unwrap().contains("Odd amount."));
+        assert!(handled().is_empty());
+
+        // The call is sent again once the backoff has passed, and fails for the last time.
+        assert_eq!(drain(10), 0);
+        let ctx = ctx.with_time(START + 10);
+        assert_eq!(drain(10), 1);
+        ctx.resolve_calls();
+        assert_eq!(status(id), Some((OutboxStatus::Failed, 2)));
+        assert_eq!(handled(), vec![(id, false)]);
+
+        let ctx = ctx.with_time(START + 100);
+        assert_eq!(drain(10), 0);
+
+        // A failed call is kept until it's retried or removed.
+        assert!(retry(id));
+        assert!(!retry(id));
+        assert_eq!(status(id), Some((OutboxStatus::Pending, 0)));
+        assert_eq!(drain(10), 1);
+        assert!(remove(id).is_some());
+        ctx.resolve_calls();
+        assert_eq!(len(), 0);
+        assert_eq!(ctx.watcher().calls_to("credit").len(), 3);
+    }
+
+    #[test]
+    fn backoff() {
+        let config = OutboxConfig {
+            max_attempts: 10,
+            initial_backoff: 10,
+            max_backoff: 50,
+        };
+
+        let delays: Vec<u64> = (1..=5).map(|attempts| config.backoff(attempts)).collect();
+        assert_eq!(delays, vec![10, 20, 40, 50, 50]);
+        assert_eq!(config.backoff(100), 50);
+    }
+
+    #[test]
+    fn reload() {
+        let _ctx = context();
+        credit(2);
+        credit(4);
+        drain(1);
+
+        // The calls in flight during an upgrade are sent again.
+        init(MemoryId::new(0));
+        assert_eq!(status(0), Some((OutboxStatus::Pending, 0)));
+        assert_eq!(credit(6), 2);
+        assert_eq!(drain(10), 3);
+    }
+}
@@ -0,0 +1,466 @@
+//! Cursor based pagination for the methods listing the entries of a collection.
+//!
+//! A page is requested with a [`PageRequest`], whose limit is clamped by the [`PageLimits`] of the
+//! method so a caller can never ask for an unbounded amount of work, and the pages end before
+//! their encoded size exceeds the limit of a reply. The cursor of the next page is an opaque
+//! string encoding the key of the last entry returned, so the pages stay consistent when entries
+//! are inserted or removed between two calls. A cursor is at most [`MAX_CURSOR_LEN`] characters
+//! long, so a page which would end on a key whose candid encoding is longer than half of it is an
+//! error, instead of returning a cursor which can't be decoded.
+//!
+//! ```ignore
+//! #[query]
+//! fn list_users(request: PageRequest) -> Result<Page<User>, String> {
+//!     with(|users: &Users| pagination::paginate_map(&users.0, &request, PageLimits::default()))
+//!         .map(|page| page.map(|(_, user)| user))
+//! }
+//! ```
+
+use crate::ic::{encoded_size, MAX_REPLY_SIZE};
+use crate::stable::{Memory, StableBTreeMap, Storable};
+use candid::{decode_one, encode_one, CandidType};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+/// The number of entries of a page when the request does not set a limit.
+pub const DEFAULT_PAGE_SIZE: u32 = 100;
+
+/// The maximum number of entries of a page.
+pub const MAX_PAGE_SIZE: u32 = 1_000;
+
+/// The maximum size of the encoded items of a page by default, which leaves room in the reply for
+/// the other values returned with the page.
+pub const DEFAULT_MAX_PAGE_BYTES: usize = MAX_REPLY_SIZE / 2;
+
+/// The maximum length of a cursor, the longer ones are rejected before being decoded.
+pub const MAX_CURSOR_LEN: usize = 512;
+
+/// A request for a page of a collection.
+#[derive(Clone, Debug, Default, PartialEq, Eq, CandidType, Deserialize)]
+pub struct PageRequest {
+    /// The cursor returned with the previous page, or none for the first page.
+    pub cursor: Option<String>,
+    /// The number of entries to return, it's clamped by the limits of the method.
+    pub limit: Option<u32>,
+}
+
+impl PageRequest {
+    /// Create a request for the first page.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Request the page following the one which returned the cursor.
+    pub fn with_cursor<S: Into<String>>(mut self, cursor: S) -> Self {
+        self.cursor = Some(cursor.into());
+        self
+    }
+
+    /// Request at most the given number of entries.
+    pub fn with_limit(mut self, limit: u32) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+}
+
+/// A page of a collection.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// The cursor to request the next page, or none if this is the last page.
+    pub next_cursor: Option<String>,
+}
+
+impl<T> Default for Page<T> {
+    fn default() -> Self {
+        Self {
+            items: Vec::new(),
+            next_cursor: None,
+        }
+    }
+}
+
+impl<T> Page<T> {
+    /// Collect a page from an iterator over the entries following the cursor, in the order of
+    /// their keys. At most `size` entries are taken, and the cursor of the next page is the key
+    /// of the last one if the iterator has more entries. Returns an error if that key is too large
+    /// for a cursor, see [`encode_cursor`].
+    pub fn from_entries<K, I>(entries: I, size: usize) -> Result<Self, String>
+    where
+        K: CandidType,
+        I: IntoIterator<Item = (K, T)>,
+    {
+        Self::collect(entries, size, |_| true)
+    }
+
+    /// Like [`Page::from_entries`], but also stop before the encoded size of the items exceeds
+    /// `max_bytes`, so the page fits in a reply. The first entry is always taken so the pages make
+    /// progress.
+    pub fn from_entries_within<K, I>(
+        entries: I,
+        size: usize,
+        max_bytes: usize,
+    ) -> Result<Self, String>
+    where
+        T: CandidType,
+        K: CandidType,
+        I: IntoIterator<Item = (K, T)>,
+    {
+        let mut bytes = 0usize;
+
+        Self::collect(entries, size, |item| {
+            bytes = bytes.saturating_add(encoded_size(item));
+            bytes <= max_bytes
+        })
+    }
+
+    fn collect<K, I, F>(entries: I, size: usize, mut fits: F) -> Result<Self, String>
+    where
+        K: CandidType,
+        I: IntoIterator<Item = (K, T)>,
+        F: FnMut(&T) -> bool,
+    {
+        let mut entries = entries.into_iter().peekable();
+        // The size is given by the caller, so only a bounded capacity is reserved ahead.
+        let mut items = Vec::with_capacity(size.min(MAX_PAGE_SIZE as usize));
+        let mut last = None;
+
+        while items.len() < size {
+            // The size of the first item is counted too, even though it's always taken.
+            match entries.peek() {
+                Some((_, item)) if fits(item) || items.is_empty() => {}
+                _ => break,
+            }
+
+            let (key, item) = entries.next().unwrap();
+            items.push(item);
+            last = Some(key);
+        }
+
+        let next_cursor = match (entries.peek(), &last) {
+            (Some(_), Some(last)) => Some(encode_cursor(last)?),
+            _ => None,
+        };
+
+        Ok(Self { items, next_cursor })
+    }
+
+    /// Return true if there is no page after this one.
+    pub fn is_last(&self) -> bool {
+        self.next_cursor.is_none()
+    }
+
+    /// Map the items of the page, keeping the cursor.
+    pub fn map<U, F: FnMut(T) -> U>(self, f: F) -> Page<U> {
+        Page {
+            items: self.items.into_iter().map(f).collect(),
+            next_cursor: self.next_cursor,
+        }
+    }
+}
+
+/// The page sizes accepted by a method.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PageLimits {
+    /// The number of entries of a page when the request does not set a limit.
+    pub default: u32,
+    /// The maximum number of entries of a page, the larger limits are lowered to it.
+    pub max: u32,
+    /// The maximum size of the encoded items of a page, the page ends before the entry which would
+    /// exceed it.
+    pub max_bytes: usize,
+}
+
+impl Default for PageLimits {
+    fn default() -> Self {
+        Self {
+            default: DEFAULT_PAGE_SIZE,
+            max: MAX_PAGE_SIZE,
+            max_bytes: DEFAULT_MAX_PAGE_BYTES,
+        }
+    }
+}
+
+impl PageLimits {
+    /// Create the limits, the default size is lowered to the maximum size if it's larger.
+    pub fn new(default: u32, max: u32) -> Self {
+        Self {
+            default: default.min(max),
+            max,
+            max_bytes: DEFAULT_MAX_PAGE_BYTES,
+        }
+    }
+
+    /// Set the maximum size of the encoded items of a page.
+    pub fn with_max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Return the number of entries to return for the request, which is at least one and at most
+    /// the maximum page size.
+    pub fn page_size(&self, request: &PageRequest) -> usize {
+        let max = self.max.clamp(1, MAX_PAGE_SIZE);
+        request.limit.unwrap_or(self.default).clamp(1, max) as usize
+    }
+}
+
+/// Encode the key of the last entry of a page as the cursor of the next page. Returns an error if
+/// the cursor would be longer than [`MAX_CURSOR_LEN`], since [`decode_cursor`] rejects it.
+pub fn encode_cursor<K: CandidType>(key: &K) -> Result<String, String> {
+    let bytes = encode_one(key).expect("Could not encode the cursor.");
+
+    if bytes.len() * 2 > MAX_CURSOR_LEN {
+        return Err(format!(
+            "The key is too large for a cursor, its encoding is {} bytes long and the maximum is {}.",
+            bytes.len(),
+            MAX_CURSOR_LEN / 2
+        ));
+    }
+
+    let mut cursor = String::with_capacity(bytes.len() * 2);
+
+    for byte in bytes {
+        cursor.push_str(&format!("{:02x}", byte));
+    }
+
+    Ok(cursor)
+}
+
+/// Decode the key encoded in the cursor.
+pub fn decode_cursor<K: CandidType + DeserializeOwned>(cursor: &str) -> Result<K, String> {
+    if cursor.len() > MAX_CURSOR_LEN {
+        return Err(format!(
+            "The cursor is too long, the maximum length is {}.",
+            MAX_CURSOR_LEN
+        ));
+    }
+
+    let bytes = cursor
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| match std::str::from_utf8(pair) {
+            Ok(hex) if hex.len() == 2 => u8::from_str_radix(hex, 16).ok(),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| "Invalid cursor.".to_string())?;
+
+    decode_one(&bytes).map_err(|e| format!("Invalid cursor: {}", e))
+}
+
+/// Return the bound of the entries following the cursor of the request.
+fn start_bound<K: CandidType + DeserializeOwned>(
+    request: &PageRequest,
+) -> Result<Bound<K>, String> {
+    match &request.cursor {
+        Some(cursor) => decode_cursor(cursor).map(Bound::Excluded),
+        None => Ok(Bound::Unbounded),
+    }
+}
+
+/// Return the requested page of the entries of the map.
+pub fn paginate_map<K, V>(
+    map: &BTreeMap<K, V>,
+    request: &PageRequest,
+    limits: PageLimits,
+) -> Result<Page<(K, V)>, String>
+where
+    K: Ord + Clone + CandidType + DeserializeOwned,
+    V: Clone + CandidType,
+{
+    let start = start_bound::<K>(request)?;
+    let size = limits.page_size(request);
+    let entries = map
+        .range((start, Bound::Unbounded))
+        .map(|(k, v)| (k.clone(), (k.clone(), v.clone())));
+
+    Page::from_entries_within(entries, size, limits.max_bytes)
+}
+
+/// Return the requested page of the entries of the stable map.
+pub fn paginate_stable_map<K, V, M>(
+    map: &StableBTreeMap<K, V, M>,
+    request: &PageRequest,
+    limits: PageLimits,
+) -> Result<Page<(K, V)>, String>
+where
+    K: Storable + Ord + Clone + CandidType + DeserializeOwned,
+    V: Storable + CandidType,
+    M: Memory,
+{
+    let start = start_bound::<K>(request)?;
+    let size = limits.page_size(request);
+    let entries = map
+        .range((start, Bound::Unbounded))
+        .map(|(k, v)| (k.clone(), (k, v)));
+
+    Page::from_entries_within(entries, size, limits.max_bytes)
+}
+
+/// Return the requested page of the items of the slice, the cursors are the indices of the items
+/// so the pages are only consistent if the items are not removed or reordered.
+pub fn paginate_slice<T: Clone + CandidType>(
+    items: &[T],
+    request: &PageRequest,
+    limits: PageLimits,
+) -> Result<Page<T>, String> {
+    let start = match start_bound::<u64>(request)? {
+        Bound::Excluded(index) => (index as usize).saturating_add(1),
+        _ => 0,
+    };
+    let size = limits.page_size(request);
+    let entries = items
+        .iter()
+        .enumerate()
+        .skip(start)
+        .map(|(i, item)| (i as u64, item.clone()));
+
+    Page::from_entries_within(entries, size, limits.max_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stable::VectorMemory;
+
+    fn all_pages<T, F>(mut page: F) -> Vec<Page<T>>
+    where
+        F: FnMut(&PageRequest) -> Result<Page<T>, String>,
+    {
+        let mut pages = Vec::new();
+        let mut request = PageRequest::new().with_limit(3);
+
+        loop {
+            let next = page(&request).unwrap();
+            let cursor = next.next_cursor.clone();
+            pages.push(next);
+
+            match cursor {
+                Some(cursor) => request = request.with_cursor(cursor),
+                None => return pages,
+            }
+        }
+    }
+
+    #[test]
+    fn cursor_round_trip() {
+        let key = (42u64, "alice".to_string());
+        let cursor = encode_cursor(&key).unwrap();
+        assert_eq!(decode_cursor::<(u64, String)>(&cursor), Ok(key));
+
+        assert_eq!(
+            decode_cursor::<u64>("0g"),
+            Err("Invalid cursor.".to_string())
+        );
+        assert_eq!(
+            decode_cursor::<u64>("abc"),
+            Err("Invalid cursor.".to_string())
+        );
+        assert!(decode_cursor::<u64>(&encode_cursor(&"text").unwrap()).is_err());
+        assert_eq!(
+            decode_cursor::<u64>(&"0".repeat(MAX_CURSOR_LEN + 2)),
+            Err("The cursor is too long, the maximum length is 512.".to_string())
+        );
+    }
+
+    #[test]
+    fn oversized_key() {
+        let long = "a".repeat(300);
+        assert!(encode_cursor(&long)
+            .unwrap_err()
+            .starts_with("The key is too large for a cursor"));
+
+        // The page whose last key can not be a cursor is an error instead of a dead end.
+        let map: BTreeMap<String, u8> = vec![(long, 1), ("b".repeat(300), 2)].into_iter().collect();
+        let request = PageRequest::new().with_limit(1);
+        assert!(paginate_map(&map, &request, PageLimits::default()).is_err());
+
+        // The last page does not need a cursor.
+        let request = PageRequest::new().with_limit(2);
+        assert_eq!(
+            paginate_map(&map, &request, PageLimits::default())
+                .unwrap()
+                .items
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn map_pages() {
+        let map: BTreeMap<u64, u64> = (0..10).map(|i| (i, i * 10)).collect();
+        let pages = all_pages(|request| paginate_map(&map, request, PageLimits::default()));
+
+        assert_eq!(pages.len(), 4);
+        assert_eq!(pages[0].items, vec![(0, 0), (1, 10), (2, 20)]);
+        assert_eq!(pages[3].items, vec![(9, 90)]);
+        assert!(pages[3].is_last());
+
+        let items: Vec<_> = pages.into_iter().flat_map(|page| page.items).collect();
+        assert_eq!(items, map.into_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn last_page() {
+        // A collection which ends exactly on a page has no empty page after it.
+        let items: Vec<u32> = (0..6).collect();
+        let pages = all_pages(|request| paginate_slice(&items, request, PageLimits::default()));
+
+        assert_eq!(pages.len(), 2);
+        assert_eq!(pages[1].items, vec![3, 4, 5]);
+        assert!(pages[1].is_last());
+
+        let empty: Vec<u32> = Vec::new();
+        let page = paginate_slice(&empty, &PageRequest::new(), PageLimits::default()).unwrap();
+        assert_eq!(page, Page::default());
+    }
+
+    #[test]
+    fn stable_map_pages() {
+        let mut map = StableBTreeMap::<u64, u64, _>::init(VectorMemory::default());
+        for i in 0..7 {
+            map.insert(i, i);
+        }
+
+        let pages = all_pages(|request| paginate_stable_map(&map, request, PageLimits::default()));
+        let keys: Vec<u64> = pages
+            .into_iter()
+            .flat_map(|page| page.items)
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(keys, (0..7).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn limits() {
+        let limits = PageLimits::new(500, 20);
+        assert_eq!(limits.page_size(&PageRequest::new()), 20);
+        assert_eq!(limits.page_size(&PageRequest::new().with_limit(0)), 1);
+        assert_eq!(limits.page_size(&PageRequest::new().with_limit(5)), 5);
+        assert_eq!(
+            PageLimits::new(10, u32::MAX).page_size(&PageRequest::new().with_limit(u32::MAX)),
+            MAX_PAGE_SIZE as usize
+        );
+
+        // The page ends before the items exceed the byte budget, but takes at least one.
+        let items = vec!["x".repeat(100); 5];
+        let limits = PageLimits::default().with_max_bytes(250);
+        let page = paginate_slice(&items, &PageRequest::new(), limits).unwrap();
+        assert_eq!(page.items.len(), 2);
+
+        let limits = PageLimits::default().with_max_bytes(1);
+        let page = paginate_slice(&items, &PageRequest::new(), limits).unwrap();
+        assert_eq!(page.items.len(), 1);
+        assert!(!page.is_last());
+    }
+
+    #[test]
+    fn large_size_from_entries() {
+        let page = Page::from_entries(vec![(1u8, 'a'), (2, 'b')], usize::MAX).unwrap();
+        assert_eq!(page.items, vec!['a', 'b']);
+        assert!(page.is_last());
+    }
+}
@@ -0,0 +1,442 @@
+use crate::ic::{spawn, with, with_mut, CallBuilder, CallError};
+use candid::{encode_one, CandidType, Principal};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::{BTreeMap, VecDeque};
+
+/// The number of times the delivery of an event is attempted by default before it's dropped.
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+
+/// The number of events kept in the backlog by default, the oldest ones are dropped first.
+pub const DEFAULT_MAX_BACKLOG: usize = 1_000;
+
+/// An event which can be published to the subscribed canisters, use `#[derive(Event)]` to
+/// implement it.
+pub trait Event: CandidType + DeserializeOwned {
+    /// The topic the subscribers register for to receive the events of this type.
+    const TOPIC: &'static str;
+}
+
+/// A canister subscribed to a topic, and the update method called with each event.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct Subscription {
+    pub subscriber: Principal,
+    pub method: String,
+}
+
+/// An event whose delivery to a subscriber failed, and which is retried by [`retry_backlog`].
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct PendingEvent {
+    pub topic: String,
+    pub subscription: Subscription,
+    /// The candid encoded event.
+    pub payload: Vec<u8>,
+    /// The number of failed deliveries.
+    pub attempts: u32,
+    /// The error of the last delivery.
+    pub last_error: String,
+}
+
+/// The state of a publisher: the subscriptions of each topic and the backlog of the events which
+/// could not be delivered.
+///
+/// Since this type implements [`CandidType`], it can be persisted across upgrades as a part of the
+/// canister's state, the deliveries in flight during the upgrade are not retried.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Publisher {
+    subscriptions: BTreeMap<String, Vec<Subscription>>,
+    backlog: VecDeque<PendingEvent>,
+    max_attempts: u32,
+    max_backlog: usize,
+    /// The number of events dropped after too many attempts or because the backlog was full.
+    dropped: u64,
+}
+
+impl Default for Publisher {
+    fn default() -> Self {
+        Self {
+            subscriptions: BTreeMap::new(),
+            backlog: VecDeque::new(),
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            max_backlog: DEFAULT_MAX_BACKLOG,
+            dropped: 0,
+        }
+    }
+}
+
+impl Publisher {
+    /// Subscribe the canister to the topic, the method replaces the one of an existing
+    /// subscription of the canister to the topic.
+    pub fn subscribe<S: Into<String>>(&mut self, subscriber: Principal, topic: S, method: S) {
+        let subscriptions = self.subscriptions.entry(topic.into()).or_default();
+        let method = method.into();
+
+        match subscriptions
+            .iter_mut()
+            .find(|s| s.subscriber == subscriber)
+        {
+            Some(subscription) => subscription.method = method,
+            None => subscriptions.push(Subscription { subscriber, method }),
+        }
+    }
+
+    /// Unsubscribe the canister from the topic, and drop its pending events of this topic.
+    /// Returns false if the canister was not subscribed.
+    pub fn unsubscribe(&mut self, subscriber: Principal, topic: &str) -> bool {
+        let removed = match self.subscriptions.get_mut(topic) {
+            Some(subscriptions) => {
+                let len = subscriptions.len();
+                subscriptions.retain(|s| s.subscriber != subscriber);
+                subscriptions.len() != len
+            }
+            None => false,
+        };
+
+        self.backlog
+            .retain(|e| e.topic != topic || e.subscription.subscriber != subscriber);
+
+        removed
+    }
+
+    /// Return the subscriptions to the topic.
+    pub fn subscriptions(&self, topic: &str) -> &[Subscription] {
+        self.subscriptions
+            .get(topic)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// Return the events waiting to be delivered again.
+    pub fn backlog(&self) -> impl Iterator<Item = &PendingEvent> {
+        self.backlog.iter()
+    }
+
+    /// Return the number of events which were dropped, after too many attempts or because the
+    /// backlog was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Set the number of times the delivery of an event is attempted before it's dropped.
+    pub fn set_max_attempts(&mut self, max_attempts: u32) {
+        self.max_attempts = max_attempts.max(1);
+    }
+
+    /// Set the number of events kept in the backlog.
+    pub fn set_max_backlog(&mut self, max_backlog: usize) {
+        self.max_backlog = max_backlog;
+
+        while self.backlog.len() > self.max_backlog {
+            self.backlog.pop_front();
+            self.dropped += 1;
+        }
+    }
+
+    /// Record the failed delivery of an event.
+    fn failed(&mut self, mut event: PendingEvent, error: CallError) {
+        event.attempts += 1;
+        event.last_error = error.to_string();
+
+        // The subscriber might have unsubscribed while the event was in flight.
+        let subscribed = self
+            .subscriptions(&event.topic)
+            .iter()
+            .any(|s| s.subscriber == event.subscription.subscriber);
+
+        if !subscribed {
+            return;
+        }
+
+        if event.attempts >= self.max_attempts || self.max_backlog == 0 {
+            self.dropped += 1;
+            return;
+        }
+
+        if self.backlog.len() >= self.max_backlog {
+            self.backlog.pop_front();
+            self.dropped += 1;
+        }
+
+        self.backlog.push_back(event);
+    }
+}
+
+/// Subscribe the canister to the topic of the publisher running this code, this is called by the
+/// `__pubsub_subscribe` update exported by `export_pubsub!`.
+pub fn subscribe(subscriber: Principal, topic: String, method: String) {
+    with_mut(|publisher: &mut Publisher| publisher.subscribe(subscriber, topic, method));
+}
+
+/// Unsubscribe the canister from the topic of the publisher running this code, this is called by
+/// the `__pubsub_unsubscribe` update exported by `export_pubsub!`.
+pub fn unsubscribe(subscriber: Principal, topic: &str) -> bool {
+    with_mut(|publisher: &mut Publisher| publisher.unsubscribe(subscriber, topic))
+}
+
+/// Return the subscriptions to the topic of the event.
+pub fn subscribers<E: Event>() -> Vec<Subscription> {
+    with(|publisher: &Publisher| publisher.subscriptions(E::TOPIC).to_vec())
+}
+
+/// Send the event to each canister subscribed to its topic, as a call to the method of the
+/// subscription. The calls are not awaited by the caller, the events whose delivery fails are
+/// kept in the backlog to be sent again by [`retry_backlog`].
+///
+/// Returns the number of subscribers the event is sent to.
+///
+/// # Traps
+///
+/// If the event can not be encoded.
+pub fn publish<E: Event>(event: &E) -> usize {
+    let payload = encode_one(event)
+        .unwrap_or_else(|e| crate::ic::trap(&format!("Could not encode the event: {}", e)));
+    let subscriptions = with(|publisher: &Publisher| publisher.subscriptions(E::TOPIC).to_vec());
+    let count = subscriptions.len();
+
+    for subscription in subscriptions {
+        deliver(PendingEvent {
+            topic: E::TOPIC.to_string(),
+            subscription,
+            payload: payload.clone(),
+            attempts: 0,
+            last_error: String::new(),
+        });
+    }
+
+    count
+}
+
+/// Send the events of the backlog again, this is meant to be called periodically such as from
+/// the heartbeat. Returns the number of events sent.
+pub fn retry_backlog() -> usize {
+    let backlog = with_mut(|publisher: &mut Publisher| std::mem::take(&mut publisher.backlog));
+    let count = backlog.len();
+
+    for event in backlog {
+        deliver(event);
+    }
+
+    count
+}
+
+/// Return the number of events waiting to be sent again.
+pub fn backlog_len() -> usize {
+    with(|publisher: &Publisher| publisher.backlog.len())
+}
+
+fn deliver(event: PendingEvent) {
+    spawn(async move {
+        let result = CallBuilder::new(event.subscription.subscriber, &event.subscription.method)
+            .with_arg_raw(event.payload.clone())
+            .perform_rejection()
+            .await;
+
+        if let Err(error) = result {
+            with_mut(|publisher: &mut Publisher| publisher.failed(event, error));
+        }
+    });
+}
+
+/// The publishers a subscriber registered with, which is used to check the caller of the method
+/// receiving the events.
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct Registrations {
+    publishers: BTreeMap<String, Vec<Principal>>,
+}
+
+impl Registrations {
+    /// Return true if the subscriber registered with the publisher for the topic.
+    pub fn contains(&self, publisher: &Principal, topic: &str) -> bool {
+        self.publishers
+            .get(topic)
+            .map(|publishers| publishers.contains(publisher))
+            .unwrap_or(false)
+    }
+}
+
+/// Subscribe this canister to the events of type `E` of the publisher, which are delivered as
+/// calls to the update method with the given name.
+///
+/// ```ignore
+/// #[update]
+/// async fn follow(ledger: Principal) -> Result<(), String> {
+///     pubsub::register::<Transferred>(ledger, "on_transfer").await.map_err(|e| e.to_string())
+/// }
+///
+/// #[update]
+/// fn on_transfer(event: Transferred) {
+///     if !pubsub::is_publisher::<Transferred>(&caller()) {
+///         ic::trap("Not a publisher.");
+///     }
+///     // ...
+/// }
+/// ```
+pub async fn register<E: Event>(publisher: Principal, method: &str) -> Result<(), CallError> {
+    CallBuilder::new(publisher, "__pubsub_subscribe")
+        .with_args((E::TOPIC.to_string(), method.to_string()))
+        .perform_rejection()
+        .await?;
+
+    with_mut(|registrations: &mut Registrations| {
+        let publishers = registrations
+            .publishers
+            .entry(E::TOPIC.to_string())
+            .or_default();
+
+        if !publishers.contains(&publisher) {
+            publishers.push(publisher);
+        }
+    });
+
+    Ok(())
+}
+
+/// Unsubscribe this canister from the events of type `E` of the publisher.
+pub async fn unregister<E: Event>(publisher: Principal) -> Result<(), CallError> {
+    CallBuilder::new(publisher, "__pubsub_unsubscribe")
+        .with_arg(E::TOPIC.to_string())
+        .perform_rejection()
+        .await?;
+
+    with_mut(|registrations: &mut Registrations| {
+        if let Some(publishers) = registrations.publishers.get_mut(E::TOPIC) {
+            publishers.retain(|p| p != &publisher);
+        }
+    });
+
+    Ok(())
+}
+
+/// Return true if this canister registered with the principal for the events of type `E`.
+pub fn is_publisher<E: Event>(principal: &Principal) -> bool {
+    with(|registrations: &Registrations| registrations.contains(principal, E::TOPIC))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rt::context::MockContext;
+
+    #[derive(Clone, Debug, PartialEq, CandidType, Deserialize)]
+    struct Transferred {
+        amount: u64,
+    }
+
+    impl Event for Transferred {
+        const TOPIC: &'static str = "transfers";
+    }
+
+    fn canister(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    fn backlog() -> Vec<(Principal, u32)> {
+        with(|publisher: &Publisher| {
+            publisher
+                .backlog()
+                .map(|e| (e.subscription.subscriber, e.attempts))
+                .collect()
+        })
+    }
+
+    #[test]
+    fn fan_out() {
+        let ctx = MockContext::new()
+            .with_reply(canister(1), "on_transfer", ())
+            .with_reject(canister(2), "on_transfer", "Stopped.")
+            .inject();
+
+        subscribe(canister(1), "transfers".into(), "on_transfer".into());
+        subscribe(canister(2), "transfers".into(), "on_transfer".into());
+        subscribe(canister(3), "mints".into(), "on_mint".into());
+        assert_eq!(subscribers::<Transferred>().len(), 2);
+
+        // Each subscriber of the topic gets the event, and the failed delivery is kept.
+        assert_eq!(publish(&Transferred { amount: 10 }), 2);
+        ctx.resolve_calls();
+
+        let calls = ctx.watcher().calls_to("on_transfer");
+        assert_eq!(calls.len(), 2);
+        assert_eq!(
+            calls[0].decode_one::<Transferred>().unwrap(),
+            Transferred { amount: 10 }
+        );
+        assert_eq!(backlog(), vec![(canister(2), 1)]);
+        with(|publisher: &Publisher| {
+            let event = publisher.backlog().next().unwrap();
+            assert!(event.last_error.contains("Stopped."));
+        });
+
+        // An unsubscribed canister does not get the events, and its pending ones are dropped.
+        assert!(unsubscribe(canister(2), "transfers"));
+        assert!(!unsubscribe(canister(2), "transfers"));
+        assert_eq!(backlog_len(), 0);
+        assert_eq!(publish(&Transferred { amount: 20 }), 1);
+    }
+
+    #[test]
+    fn retry_and_drop() {
+        let ctx = MockContext::new().inject();
+        with_mut(|publisher: &mut Publisher| publisher.set_max_attempts(2));
+        subscribe(canister(1), "transfers".into(), "on_transfer".into());
+
+        publish(&Transferred { amount: 10 });
+        ctx.resolve_calls();
+        assert_eq!(backlog(), vec![(canister(1), 1)]);
+
+        // The event is dropped after too many attempts.
+        assert_eq!(retry_backlog(), 1);
+        assert_eq!(backlog_len(), 0);
+        ctx.resolve_calls();
+        assert_eq!(backlog_len(), 0);
+        assert_eq!(with(|publisher: &Publisher| publisher.dropped()), 1);
+        assert_eq!(ctx.watcher().calls_to("on_transfer").len(), 2);
+    }
+
+    #[test]
+    fn backlog_limit() {
+        let ctx = MockContext::new().inject();
+        with_mut(|publisher: &mut Publisher| publisher.set_max_backlog(2));
+        for id in 1..=3 {
+            subscribe(canister(id), "transfers".into(), "on_transfer".into());
+        }
+
+        // The oldest events are dropped once the backlog is full.
+        publish(&Transferred { amount: 10 });
+        ctx.resolve_calls();
+        assert_eq!(backlog(), vec![(canister(2), 1), (canister(3), 1)]);
+        assert_eq!(with(|publisher: &Publisher| publisher.dropped()), 1);
+
+        with_mut(|publisher: &mut Publisher| publisher.set_max_backlog(1));
+        assert_eq!(backlog(), vec![(canister(3), 1)]);
+        assert_eq!(with(|publisher: &Publisher| publisher.dropped()), 2);
+    }
+
+    #[test]
+    fn registrations() {
+        let publisher = canister(1);
+        let ctx = MockContext::new()
+            .with_reply(publisher, "__pubsub_subscribe", ())
+            .with_reply(publisher, "__pubsub_unsubscribe", (true,))
+            .inject();
+
+        ctx.block_on(register::<Transferred>(publisher, "on_transfer"))
+            .unwrap();
+        let call = &ctx.watcher().calls_to("__pubsub_subscribe")[0];
+        assert_eq!(
+            call.decode::<(String, String)>().unwrap(),
+            ("transfers".to_string(), "on_transfer".to_string())
+        );
+        assert!(is_publisher::<Transferred>(&publisher));
+        assert!(!is_publisher::<Transferred>(&canister(2)));
+
+        ctx.block_on(unregister::<Transferred>(publisher)).unwrap();
+        assert!(!is_publisher::<Transferred>(&publisher));
+
+        // The registration fails when the publisher rejects it.
+        assert!(ctx
+            .block_on(register::<Transferred>(canister(2), "on_transfer"))
+            .is_err());
+        assert!(!is_publisher::<Transferred>(&canister(2)));
+    }
+}
@@ -0,0 +1,249 @@
+//! Serve the replies larger than the message limit from a query as pages, which the callers fetch
+//! one after the other with a continuation token and reassemble.
+//!
+//! A method marked with `#[stream_query]` is exported as a query which returns the first page of
+//! its candid encoded reply, and as a `{name}_next` query which returns the page following a
+//! token. A query can not keep any state, so each page runs the method again with the arguments
+//! carried by the token and returns the next slice of its reply. The token also carries the hash
+//! of the reply, so a reply which changed between two pages is reported instead of being mixed.
+//!
+//! ```ignore
+//! #[stream_query]
+//! fn blocks(from: u64) -> Vec<Block> {
+//!     with(|chain: &Chain| chain.blocks[from as usize..].to_vec())
+//! }
+//!
+//! // In another canister.
+//! let blocks: Vec<Block> = reply_stream::fetch(ledger_id, "blocks", (0u64,)).await?;
+//! ```
+
+use crate::ic::{CallBuilder, MAX_REPLY_SIZE};
+use candid::utils::ArgumentEncoder;
+use candid::{decode_one, CandidType, Principal};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+/// The size of the pages by default, half of the message limit so the page and the token always
+/// fit in a reply.
+pub const DEFAULT_PAGE_SIZE: usize = MAX_REPLY_SIZE / 2;
+
+/// The largest page size, which leaves room in the reply for the candid encoding of the page.
+pub const MAX_PAGE_SIZE: usize = MAX_REPLY_SIZE - 1024;
+
+/// The token to request the page of a reply which follows the previous one.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct StreamToken {
+    /// The candid encoded arguments the method is called with again.
+    pub args: Vec<u8>,
+    /// The offset of the next page in the reply.
+    pub offset: u64,
+    /// The length of the whole reply in bytes.
+    pub total_len: u64,
+    /// The sha256 hash of the whole reply.
+    pub hash: Vec<u8>,
+}
+
+/// A page of the candid encoded reply of a method.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct StreamPage {
+    /// The bytes of this page.
+    pub data: Vec<u8>,
+    /// The length of the whole reply in bytes.
+    pub total_len: u64,
+    /// The token to request the next page, or none if this is the last page.
+    pub next: Option<StreamToken>,
+}
+
+/// Return the name of the query which returns the pages following the first one of the method.
+pub fn continuation_method(method: &str) -> String {
+    format!("{}_next", method)
+}
+
+/// Return the first page of the reply, this is called by the query generated by
+/// `#[stream_query]` with the encoded arguments and reply of the method.
+///
+/// # Panics
+///
+/// If the page size is `0` or larger than [`MAX_PAGE_SIZE`].
+pub fn first_page(args: Vec<u8>, reply: Vec<u8>, page_size: usize) -> StreamPage {
+    let hash = Sha256::digest(&reply).to_vec();
+    page(args, reply, hash, 0, page_size)
+}
+
+/// Return the page following the token, this is called by the `{name}_next` query generated by
+/// `#[stream_query]` with the reply of the method called again with the arguments of the token.
+pub fn next_page(
+    token: StreamToken,
+    reply: Vec<u8>,
+    page_size: usize,
+) -> Result<StreamPage, String> {
+    if reply.len() as u64 != token.total_len || Sha256::digest(&reply)[..] != token.hash[..] {
+        return Err(
+            "The reply changed since the first page was returned, the stream must be restarted."
+                .to_string(),
+        );
+    }
+
+    if token.offset >= token.total_len {
+        return Err(format!(
+            "The offset {} is past the end of the reply of {} bytes.",
+            token.offset, token.total_len
+        ));
+    }
+
+    Ok(page(
+        token.args,
+        reply,
+        token.hash,
+        token.offset as usize,
+        page_size,
+    ))
+}
+
+fn page(
+    args: Vec<u8>,
+    mut reply: Vec<u8>,
+    hash: Vec<u8>,
+    offset: usize,
+    page_size: usize,
+) -> StreamPage {
+    assert!(
+        page_size > 0 && page_size <= MAX_PAGE_SIZE,
+        "The page size must be between 1 and {} bytes.",
+        MAX_PAGE_SIZE
+    );
+
+    let total_len = reply.len();
+    let end = (offset + page_size).min(total_len);
+    reply.truncate(end);
+    let data = reply.split_off(offset);
+
+    let next = if end < total_len {
+        Some(StreamToken {
+            args,
+            offset: end as u64,
+            total_len: total_len as u64,
+            hash,
+        })
+    } else {
+        None
+    };
+
+    StreamPage {
+        data,
+        total_len: total_len as u64,
+        next,
+    }
+}
+
+/// Reassemble the pages of a reply, which are fetched by the caller with the token returned by
+/// [`StreamReader::next_token`], such as from the tests. [`fetch`] does it from a canister.
+#[derive(Clone, Debug)]
+pub struct StreamReader {
+    data: Vec<u8>,
+    total_len: u64,
+    hash: Option<Vec<u8>>,
+    next: Option<StreamToken>,
+}
+
+impl StreamReader {
+    /// Start reading a reply from its first page.
+    pub fn new(first: StreamPage) -> Self {
+        Self {
+            hash: first.next.as_ref().map(|token| token.hash.clone()),
+            data: first.data,
+            total_len: first.total_len,
+            next: first.next,
+        }
+    }
+
+    /// Return the token of the next page, or none if the whole reply was read.
+    pub fn next_token(&self) -> Option<&StreamToken> {
+        self.next.as_ref()
+    }
+
+    /// Return whether the whole reply was read.
+    pub fn is_complete(&self) -> bool {
+        self.next.is_none()
+    }
+
+    /// Append the page which follows the pages read so far.
+    pub fn push(&mut self, page: StreamPage) -> Result<(), String> {
+        if self.next.is_none() {
+            return Err("The whole reply was already read.".to_string());
+        }
+
+        if page.total_len != self.total_len {
+            return Err("The page does not belong to the reply being read.".to_string());
+        }
+
+        if self.data.len() as u64 + page.data.len() as u64 > self.total_len {
+            return Err("The page exceeds the length of the reply.".to_string());
+        }
+
+        self.data.extend_from_slice(&page.data);
+        self.next = page.next;
+
+        match &self.next {
+            Some(token) if token.offset != self.data.len() as u64 => {
+                Err("The pages of the reply are not contiguous.".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Return the candid encoded reply once it was completely read and matches its hash.
+    pub fn into_bytes(self) -> Result<Vec<u8>, String> {
+        if self.next.is_some() {
+            return Err("The reply was not completely read.".to_string());
+        }
+
+        if self.data.len() as u64 != self.total_len {
+            return Err("The reply does not match its length.".to_string());
+        }
+
+        if let Some(hash) = &self.hash {
+            if Sha256::digest(&self.data)[..] != hash[..] {
+                return Err("The reply does not match its hash.".to_string());
+            }
+        }
+
+        Ok(self.data)
+    }
+
+    /// Decode the reply once it was completely read.
+    pub fn decode<T: CandidType + DeserializeOwned>(self) -> Result<T, String> {
+        let bytes = self.into_bytes()?;
+        decode_one(&bytes).map_err(|e| e.to_string())
+    }
+}
+
+/// Call a method exported with `#[stream_query]` on another canister, fetch all of the pages of
+/// its reply and decode it.
+pub async fn fetch<A: ArgumentEncoder, T: CandidType + DeserializeOwned>(
+    canister_id: Principal,
+    method: &str,
+    args: A,
+) -> Result<T, String> {
+    let first = CallBuilder::new(canister_id, method)
+        .with_args(args)
+        .perform_one::<StreamPage>()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let continuation = continuation_method(method);
+    let mut reader = StreamReader::new(first);
+
+    while let Some(token) = reader.next_token() {
+        let page = CallBuilder::new(canister_id, &continuation)
+            .with_arg(token.clone())
+            .perform_one::<Result<StreamPage, String>>()
+            .await
+            .map_err(|e| e.to_string())??;
+
+        reader.push(page)?;
+    }
+
+    reader.decode()
+}
@@ -0,0 +1,822 @@
+//! A scheduler of background jobs, run on cron-like schedules or at jittered intervals.
+//!
+//! The schedules are kept in the stable memory so they survive the upgrades, while the handlers
+//! of the jobs are registered by name from the `init` and the `post_upgrade` hooks. The next run
+//! of each job is a timer of the [`timers`](crate::timers) module, which is set again after each
+//! run, so the scheduler follows the time of the replica and can be driven in the tests by
+//! advancing the time of the runtime and running the global timer of the canister.
+//!
+//! ```ignore
+//! fn sync_rates() -> JobFuture {
+//!     Box::pin(async {
+//!         // ...
+//!         Ok(())
+//!     })
+//! }
+//!
+//! #[init]
+//! fn init() {
+//!     scheduler::init(MemoryId::new(1));
+//!     scheduler::register("sync_rates", sync_rates);
+//!     scheduler::schedule("sync_rates", Schedule::Cron("*/5 * * * *".into())).unwrap();
+//! }
+//!
+//! #[kit_test]
+//! async fn sync_rates_every_five_minutes(replica: Replica) {
+//!     // ...
+//!     replica.advance_time(Duration::from_secs(5 * 60));
+//!     canister.global_timer().await.assert_ok();
+//! }
+//! ```
+
+use crate::ic::{maybe_with, maybe_with_mut, spawn, swap, time, trap, with};
+use crate::stable::{
+    DefaultMemory, MemoryId, MemoryManager, StableBTreeMap, Storable, VirtualMemory,
+};
+use crate::timers::{self, TimerId};
+use candid::{encode_one, CandidType};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+
+const NANOS_PER_MINUTE: u64 = 60_000_000_000;
+const MINUTES_PER_DAY: u64 = 24 * 60;
+
+/// The number of days searched for the next time matching a cron expression, which covers the
+/// expressions matching only on the 29th of February.
+const CRON_SEARCH_DAYS: u64 = 8 * 366;
+
+/// The future of a run of a job.
+pub type JobFuture = Pin<Box<dyn Future<Output = Result<(), String>>>>;
+
+/// The function starting a run of a job.
+pub type JobHandler = fn() -> JobFuture;
+
+/// When a job runs.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum Schedule {
+    /// Run every `period` nanoseconds, each run is delayed by a random duration of up to
+    /// `jitter` nanoseconds so the jobs of many canisters do not run at the same time.
+    Interval { period: u64, jitter: u64 },
+    /// Run at the times matching the cron expression, in UTC.
+    Cron(String),
+    /// Run once at the given time, in nanoseconds.
+    Once(u64),
+}
+
+impl Schedule {
+    /// Return an error if the schedule is not valid.
+    fn validate(&self) -> Result<(), String> {
+        match self {
+            Schedule::Interval { period: 0, .. } => {
+                Err("The period of an interval must not be zero.".into())
+            }
+            Schedule::Cron(expression) => Cron::from_str(expression).map(|_| ()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Return the time of the run following the one at `now`, the first run if `first` is true.
+    fn next_run(&self, name: &str, now: u64, first: bool) -> Option<u64> {
+        match self {
+            Schedule::Interval { period, jitter } => Some(
+                now.saturating_add(*period)
+                    .saturating_add(jitter_of(name, now, *jitter)),
+            ),
+            Schedule::Cron(expression) => Cron::from_str(expression).ok()?.next_after(now),
+            Schedule::Once(at) if first => Some(*at),
+            Schedule::Once(_) => None,
+        }
+    }
+}
+
+/// Return a pseudo random duration of at most `jitter` derived from the name of the job and the
+/// time, so it's the same in every replica.
+fn jitter_of(name: &str, now: u64, jitter: u64) -> u64 {
+    if jitter == 0 {
+        return 0;
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.update(name.as_bytes());
+    hasher.update(now.to_be_bytes());
+    let hash = hasher.finalize();
+
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&hash[..8]);
+    u64::from_be_bytes(bytes) % jitter.saturating_add(1)
+}
+
+/// A scheduled job.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct Job {
+    pub name: String,
+    pub schedule: Schedule,
+    /// The number of runs of the job which can be in progress at the same time.
+    pub max_concurrency: u32,
+    /// A paused job is not started until it's resumed.
+    pub paused: bool,
+    /// The time of the next run, or none if the job will not run again.
+    pub next_run_at: Option<u64>,
+    /// The time the last run started.
+    pub last_run_at: Option<u64>,
+    /// The number of completed runs.
+    pub runs: u64,
+    /// The number of failed runs.
+    pub failures: u64,
+    /// The error of the last failed run.
+    pub last_error: Option<String>,
+}
+
+impl Storable for Job {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+}
+
+/// The scheduler of the canister, set by [`init`].
+struct Scheduler {
+    jobs: StableBTreeMap<String, Job, VirtualMemory<DefaultMemory>>,
+    handlers: HashMap<String, JobHandler>,
+    running: HashMap<String, u32>,
+    timers: HashMap<String, TimerId>,
+}
+
+/// Load the jobs stored in the virtual memory with the given id of the canister's
+/// `MemoryManager`, or create an empty scheduler. This must be called from the `init` and the
+/// `post_upgrade` hooks, before the handlers are registered.
+pub fn init(id: MemoryId) {
+    let memory = with(|manager: &MemoryManager| manager.get(id));
+
+    swap(Scheduler {
+        jobs: StableBTreeMap::init(memory),
+        handlers: HashMap::new(),
+        running: HashMap::new(),
+        timers: HashMap::new(),
+    });
+}
+
+fn with_scheduler<U, F: FnOnce(&mut Scheduler) -> U>(f: F) -> U {
+    maybe_with_mut(f)
+        .unwrap_or_else(|| trap("The scheduler is not initialized, call scheduler::init."))
+}
+
+/// Register the function which runs the job with the given name. The handlers are not persisted,
+/// so they must be registered again from the `post_upgrade` hook.
+pub fn register<S: Into<String>>(name: S, handler: JobHandler) {
+    let name = name.into();
+
+    with_scheduler(|scheduler| {
+        scheduler.handlers.insert(name.clone(), handler);
+        arm(scheduler, &name);
+    });
+}
+
+/// Schedule the job, or change the schedule of an existing job. Scheduling a job again with the
+/// same schedule keeps its next run, so this can be called from the `post_upgrade` hook.
+pub fn schedule<S: Into<String>>(name: S, schedule: Schedule) -> Result<(), String> {
+    schedule.validate()?;
+    let name = name.into();
+
+    with_scheduler(|scheduler| {
+        let job = match scheduler.jobs.get(&name) {
+            Some(job) if job.schedule == schedule => job,
+            Some(job) => Job {
+                next_run_at: schedule.next_run(&name, time(), true),
+                schedule,
+                ..job
+            },
+            None => Job {
+                next_run_at: schedule.next_run(&name, time(), true),
+                name: name.clone(),
+                schedule,
+                max_concurrency: 1,
+                paused: false,
+                last_run_at: None,
+                runs: 0,
+                failures: 0,
+                last_error: None,
+            },
+        };
+
+        scheduler.jobs.insert(name.clone(), job);
+        arm(scheduler, &name);
+    });
+
+    Ok(())
+}
+
+/// Remove the job, the runs in progress are not cancelled.
+pub fn unschedule(name: &str) -> Option<Job> {
+    with_scheduler(|scheduler| {
+        let job = scheduler.jobs.remove(&name.to_string());
+        arm(scheduler, name);
+        job
+    })
+}
+
+fn update(name: &str, f: impl FnOnce(&mut Job)) -> bool {
+    with_scheduler(|scheduler| match scheduler.jobs.get(&name.to_string()) {
+        Some(mut job) => {
+            f(&mut job);
+            scheduler.jobs.insert(name.to_string(), job);
+            arm(scheduler, name);
+            true
+        }
+        None => false,
+    })
+}
+
+/// Set the number of runs of the job which can be in progress at the same time, the runs which
+/// are due while the limit is reached are skipped. Returns false if the job does not exist.
+pub fn set_max_concurrency(name: &str, max_concurrency: u32) -> bool {
+    update(name, |job| job.max_concurrency = max_concurrency)
+}
+
+/// Pause the job. Returns false if the job does not exist.
+pub fn pause(name: &str) -> bool {
+    update(name, |job| job.paused = true)
+}
+
+/// Resume the job, its runs which were due while it was paused are skipped. Returns false if the
+/// job does not exist.
+pub fn resume(name: &str) -> bool {
+    update(name, |job| {
+        let now = time();
+        job.paused = false;
+
+        if job.next_run_at.map(|at| at < now).unwrap_or(false) {
+            job.next_run_at = job.schedule.next_run(&job.name, now, false);
+        }
+    })
+}
+
+/// Run the job again with the next global timer, such as a job which stopped before the end of its
+/// work because its [`WorkBudget`] was exhausted. Returns false if the job does not exist.
+///
+/// [`WorkBudget`]: crate::budget::WorkBudget
+pub fn trigger(name: &str) -> bool {
+    update(name, |job| job.next_run_at = Some(time()))
+}
+
+/// Return the scheduled jobs, in the order of their names.
+pub fn jobs() -> Vec<Job> {
+    with_scheduler(|scheduler| scheduler.jobs.iter().map(|(_, job)| job).collect())
+}
+
+/// Return the job with the given name.
+pub fn job(name: &str) -> Option<Job> {
+    with_scheduler(|scheduler| scheduler.jobs.get(&name.to_string()))
+}
+
+/// Return the number of runs of the job in progress.
+pub fn running(name: &str) -> u32 {
+    maybe_with(|scheduler: &Scheduler| scheduler.running.get(name).copied().unwrap_or(0))
+        .unwrap_or(0)
+}
+
+/// Set the timer of the next run of the job, in place of its previous timer. The paused jobs, the
+/// jobs without a handler and the jobs which will not run again have no timer.
+fn arm(scheduler: &mut Scheduler, name: &str) {
+    if let Some(id) = scheduler.timers.remove(name) {
+        timers::clear_timer(id);
+    }
+
+    let next_run_at = match scheduler.jobs.get(&name.to_string()) {
+        Some(job) if !job.paused && scheduler.handlers.contains_key(name) => job.next_run_at,
+        _ => None,
+    };
+
+    if let Some(at) = next_run_at {
+        let job = name.to_string();
+        let id = timers::set_timer_at(at, move || start(&job));
+        scheduler.timers.insert(name.to_string(), id);
+    }
+}
+
+/// Start a run of the job if it's due and set the timer of its next run. The next run is computed
+/// from the current time, so the runs missed while the canister was stopped are not run all at
+/// once, and the runs due while the job is at its maximum concurrency are skipped.
+fn start(name: &str) {
+    let handler = with_scheduler(|scheduler| {
+        let now = time();
+        let mut job = scheduler.jobs.get(&name.to_string())?;
+        let handler = scheduler.handlers.get(name).copied()?;
+
+        if job.paused || !job.next_run_at.map(|at| at <= now).unwrap_or(false) {
+            return None;
+        }
+
+        let running = scheduler.running.get(name).copied().unwrap_or(0);
+        let started = running < job.max_concurrency;
+        job.next_run_at = job.schedule.next_run(name, now, false);
+
+        if started {
+            job.last_run_at = Some(now);
+            scheduler.running.insert(name.to_string(), running + 1);
+        }
+
+        scheduler.jobs.insert(name.to_string(), job);
+        arm(scheduler, name);
+
+        if started {
+            Some(handler)
+        } else {
+            None
+        }
+    });
+
+    if let Some(handler) = handler {
+        spawn(run(name.to_string(), handler));
+    }
+}
+
+async fn run(name: String, handler: JobHandler) {
+    let result = handler().await;
+
+    with_scheduler(|scheduler| {
+        if let Some(running) = scheduler.running.get_mut(&name) {
+            *running = running.saturating_sub(1);
+        }
+
+        // The job might have been removed while it was running.
+        if let Some(mut job) = scheduler.jobs.get(&name) {
+            job.runs += 1;
+
+            if let Err(error) = result {
+                job.failures += 1;
+                job.last_error = Some(error);
+            }
+
+            scheduler.jobs.insert(name, job);
+        }
+    });
+}
+
+/// A parsed cron expression with the five fields `minute hour day-of-month month day-of-week`,
+/// each field is a `*`, a value, a range `a-b`, a step `*/n` or `a-b/n`, or a list of them
+/// separated by commas. The day of the week is from 0 to 7, where both 0 and 7 are Sunday.
+///
+/// The aliases `@hourly`, `@daily`, `@weekly`, `@monthly` and `@yearly` are also accepted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// True if the day of the month is restricted, if both days are restricted either of them
+    /// has to match like in the standard cron.
+    restrict_days: bool,
+    restrict_weekdays: bool,
+}
+
+impl FromStr for Cron {
+    type Err = String;
+
+    fn from_str(expression: &str) -> Result<Self, Self::Err> {
+        let expression = match expression.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            expression => expression,
+        };
+
+        let fields = expression.split_whitespace().collect::<Vec<_>>();
+
+        if fields.len() != 5 {
+            return Err(format!(
+                "Invalid cron expression '{}', expected 5 fields.",
+                expression
+            ));
+        }
+
+        let mut weekdays = parse_field(fields[4], 0, 7)?;
+
+        // Sunday is both 0 and 7.
+        if weekdays & (1 << 7) != 0 {
+            weekdays = (weekdays & !(1 << 7)) | 1;
+        }
+
+        let cron = Self {
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            weekdays,
+            restrict_days: !fields[2].starts_with('*'),
+            restrict_weekdays: !fields[4].starts_with('*'),
+        };
+
+        // The fields can be valid and never match a date together, such as the 31st of February.
+        if cron.next_after(0).is_none() {
+            return Err(format!(
+                "The cron expression '{}' never matches a date.",
+                expression
+            ));
+        }
+
+        Ok(cron)
+    }
+}
+
+/// Parse a field of a cron expression as a bit set of the values it matches.
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let invalid = || format!("Invalid cron field '{}'.", field);
+    let mut result = 0u64;
+
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().map_err(|_| invalid())?),
+            None => (part, 1),
+        };
+
+        if step == 0 {
+            return Err(invalid());
+        }
+
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (
+                start.parse::<u32>().map_err(|_| invalid())?,
+                end.parse::<u32>().map_err(|_| invalid())?,
+            )
+        } else {
+            let value = range.parse::<u32>().map_err(|_| invalid())?;
+            // A single value with a step such as `5/15` runs from the value to the maximum.
+            (value, if part.contains('/') { max } else { value })
+        };
+
+        if start < min || end > max || start > end {
+            return Err(invalid());
+        }
+
+        for value in (start..=end).step_by(step as usize) {
+            result |= 1 << value;
+        }
+    }
+
+    Ok(result)
+}
+
+impl Cron {
+    /// Return the first time strictly after the given time matching the expression, both in
+    /// nanoseconds, at the start of a minute.
+    pub fn next_after(&self, time: u64) -> Option<u64> {
+        let minute = time / NANOS_PER_MINUTE + 1;
+        let first_day = minute / MINUTES_PER_DAY;
+        let first_minute = minute % MINUTES_PER_DAY;
+
+        for day in first_day..first_day + CRON_SEARCH_DAYS {
+            if !self.matches_day(day) {
+                continue;
+            }
+
+            let from = if day == first_day { first_minute } else { 0 };
+
+            for minute_of_day in from..MINUTES_PER_DAY {
+                let (hour, minute) = (minute_of_day / 60, minute_of_day % 60);
+
+                if self.hours & (1 << hour) != 0 && self.minutes & (1 << minute) != 0 {
+                    return Some((day * MINUTES_PER_DAY + minute_of_day) * NANOS_PER_MINUTE);
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Return true if the day, as the number of days since the epoch, matches the expression.
+    fn matches_day(&self, day: u64) -> bool {
+        let (month, day_of_month) = month_and_day(day);
+        // The 1st of January 1970 was a Thursday.
+        let weekday = (day + 4) % 7;
+
+        if self.months & (1 << month) == 0 {
+            return false;
+        }
+
+        let day_matches = self.days & (1 << day_of_month) != 0;
+        let weekday_matches = self.weekdays & (1 << weekday) != 0;
+
+        match (self.restrict_days, self.restrict_weekdays) {
+            (true, true) => day_matches || weekday_matches,
+            _ => day_matches && weekday_matches,
+        }
+    }
+}
+
+/// Return the month and the day of the month of the day, as the number of days since the epoch.
+fn month_and_day(day: u64) -> (u64, u64) {
+    // From Howard Hinnant's `civil_from_days`.
+    let z = day + 719_468;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day_of_month = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    (month, day_of_month)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rt::context::MockContext;
+    use std::cell::Cell;
+
+    const MINUTE: u64 = NANOS_PER_MINUTE;
+    const DAY: u64 = MINUTES_PER_DAY * MINUTE;
+    /// The 1st of January 2024 at midnight, a Monday.
+    const START: u64 = 19_723 * DAY;
+
+    thread_local! {
+        static RUNS: Cell<u32> = Cell::new(0);
+    }
+
+    fn count() -> JobFuture {
+        Box::pin(async {
+            RUNS.with(|runs| runs.set(runs.get() + 1));
+            Ok(())
+        })
+    }
+
+    fn fail() -> JobFuture {
+        Box::pin(async { Err("Failed.".to_string()) })
+    }
+
+    fn never() -> JobFuture {
+        Box::pin(std::future::pending())
+    }
+
+    fn runs() -> u32 {
+        RUNS.with(Cell::get)
+    }
+
+    fn cron(expression: &str) -> Cron {
+        Cron::from_str(expression).unwrap()
+    }
+
+    #[test]
+    fn parse_cron() {
+        assert_eq!(cron("@daily"), cron("0 0 * * *"));
+        assert_eq!(cron("@weekly"), cron("0 0 * * 7"));
+        assert_eq!(cron("@yearly"), cron("0 0 1 1 *"));
+        assert_eq!(cron("0-10/5 * * * *"), cron("0,5,10 * * * *"));
+        assert_eq!(cron("50/5 * * * *"), cron("50,55 * * * *"));
+
+        for expression in [
+            "* * * *",
+            "* * * * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "* * * 13 *",
+            "* * * * 8",
+            "*/0 * * * *",
+            "10-5 * * * *",
+            "a * * * *",
+            "@often",
+        ] {
+            assert!(Cron::from_str(expression).is_err(), "{}", expression);
+        }
+
+        assert_eq!(
+            Cron::from_str("1-2-3 * * * *"),
+            Err("Invalid cron field '1-2-3'.".to_string())
+        );
+    }
+
+    #[test]
+    fn parse_never_matching_cron() {
+        for expression in ["0 0 31 2 *", "0 0 30 2 *", "0 0 31 4,6,9,11 *"] {
+            assert_eq!(
+                Cron::from_str(expression),
+                Err(format!(
+                    "The cron expression '{}' never matches a date.",
+                    expression
+                ))
+            );
+            assert!(Schedule::Cron(expression.into()).validate().is_err());
+        }
+
+        // Any day of the month matches when the day of the week is also restricted.
+        assert!(Cron::from_str("0 0 31 2 1").is_ok());
+        // The 29th of February only matches in the leap years.
+        assert_eq!(cron("0 0 29 2 *").next_after(0), Some(789 * DAY));
+    }
+
+    #[test]
+    fn next_after() {
+        let cases = [
+            ("*/15 * * * *", 7 * MINUTE + 30_000_000_000, 15 * MINUTE),
+            ("*/15 * * * *", 15 * MINUTE, 30 * MINUTE),
+            ("@hourly", 0, 60 * MINUTE),
+            ("@daily", 1, DAY),
+            // The 1st of January 1970 was a Thursday.
+            ("0 9 * * 1", 0, 4 * DAY + 9 * 60 * MINUTE),
+            ("0 0 * * 0", 0, 3 * DAY),
+            ("0 0 * * 7", 0, 3 * DAY),
+            // Either the day of the month or the day of the week matches.
+            ("0 0 13 * 5", 0, DAY),
+            ("0 0 13 * 5", 2 * DAY, 8 * DAY),
+            ("0 0 13 * 5", 8 * DAY, 12 * DAY),
+            ("30 23 31 12 *", 0, 364 * DAY + (23 * 60 + 30) * MINUTE),
+            ("@monthly", START, START + 31 * DAY),
+            ("0 12 * 2 1-5", START, START + 31 * DAY + 12 * 60 * MINUTE),
+        ];
+
+        for (expression, time, expected) in cases {
+            assert_eq!(
+                cron(expression).next_after(time),
+                Some(expected),
+                "{} after {}",
+                expression,
+                time
+            );
+        }
+    }
+
+    #[test]
+    fn next_run() {
+        let cron = Schedule::Cron("*/5 * * * *".into());
+        assert_eq!(cron.next_run("job", START, true), Some(START + 5 * MINUTE));
+        assert_eq!(
+            cron.next_run("job", START + MINUTE, false),
+            Some(START + 5 * MINUTE)
+        );
+
+        let once = Schedule::Once(START + DAY);
+        assert_eq!(once.next_run("job", START, true), Some(START + DAY));
+        assert_eq!(once.next_run("job", START + DAY, false), None);
+
+        let interval = Schedule::Interval {
+            period: MINUTE,
+            jitter: 0,
+        };
+        assert_eq!(interval.next_run("job", START, true), Some(START + MINUTE));
+        assert_eq!(
+            interval.next_run("job", u64::MAX - 1, false),
+            Some(u64::MAX)
+        );
+    }
+
+    #[test]
+    fn jitter() {
+        let jitter = 10 * MINUTE;
+        let interval = Schedule::Interval {
+            period: MINUTE,
+            jitter,
+        };
+
+        let delays = (0..100)
+            .map(|i| {
+                interval.next_run("job", START + i * MINUTE, false).unwrap() - START - i * MINUTE
+            })
+            .collect::<Vec<_>>();
+
+        assert!(delays
+            .iter()
+            .all(|delay| (MINUTE..=MINUTE + jitter).contains(delay)));
+        assert!(delays.iter().any(|delay| *delay != delays[0]));
+
+        // The jitter is the same in every replica, and differs between the jobs.
+        assert_eq!(
+            jitter_of("job", START, jitter),
+            jitter_of("job", START, jitter)
+        );
+        assert_ne!(
+            jitter_of("job", START, jitter),
+            jitter_of("other", START, jitter)
+        );
+        assert_eq!(jitter_of("job", START, 0), 0);
+    }
+
+    #[test]
+    fn run_on_timers() {
+        let ctx = MockContext::new().with_time(START).inject();
+        init(MemoryId::new(0));
+
+        let interval = Schedule::Interval {
+            period: MINUTE,
+            jitter: 0,
+        };
+        schedule("count", interval).unwrap();
+
+        // The job has no timer until its handler is registered.
+        assert_eq!(ctx.global_timer(), 0);
+        register("count", count);
+        assert_eq!(ctx.global_timer(), START + MINUTE);
+
+        timers::run_expired();
+        assert_eq!(runs(), 0);
+
+        let ctx = ctx.with_time(START + MINUTE);
+        timers::run_expired();
+        assert_eq!(runs(), 1);
+        assert_eq!(ctx.global_timer(), START + 2 * MINUTE);
+
+        let count = job("count").unwrap();
+        assert_eq!(count.runs, 1);
+        assert_eq!(count.last_run_at, Some(START + MINUTE));
+        assert_eq!(count.next_run_at, Some(START + 2 * MINUTE));
+
+        // The missed runs are not run all at once.
+        let ctx = ctx.with_time(START + 10 * MINUTE);
+        timers::run_expired();
+        assert_eq!(runs(), 2);
+        assert_eq!(ctx.global_timer(), START + 11 * MINUTE);
+
+        // A paused job has no timer, and skips the runs missed while it was paused.
+        assert!(pause("count"));
+        assert_eq!(ctx.global_timer(), 0);
+
+        let ctx = ctx.with_time(START + 20 * MINUTE);
+        timers::run_expired();
+        assert_eq!(runs(), 2);
+
+        assert!(resume("count"));
+        assert_eq!(ctx.global_timer(), START + 21 * MINUTE);
+
+        assert!(trigger("count"));
+        assert_eq!(ctx.global_timer(), START + 20 * MINUTE);
+        timers::run_expired();
+        assert_eq!(runs(), 3);
+        assert_eq!(ctx.global_timer(), START + 21 * MINUTE);
+
+        assert!(unschedule("count").is_some());
+        assert_eq!(ctx.global_timer(), 0);
+        assert!(!pause("count"));
+    }
+
+    #[test]
+    fn failures_and_concurrency() {
+        let ctx = MockContext::new().with_time(START).inject();
+        init(MemoryId::new(0));
+
+        register("fail", fail);
+        register("never", never);
+        schedule("fail", Schedule::Once(START)).unwrap();
+        schedule("never", Schedule::Cron("* * * * *".into())).unwrap();
+
+        timers::run_expired();
+        let failed = job("fail").unwrap();
+        assert_eq!((failed.runs, failed.failures), (1, 1));
+        assert_eq!(failed.last_error, Some("Failed.".to_string()));
+        assert_eq!(failed.next_run_at, None);
+        assert_eq!(ctx.global_timer(), START + MINUTE);
+
+        let ctx = ctx.with_time(START + MINUTE);
+        timers::run_expired();
+        assert_eq!(running("never"), 1);
+
+        // The run due while the previous one is in progress is skipped.
+        let ctx = ctx.with_time(START + 2 * MINUTE);
+        timers::run_expired();
+        assert_eq!(running("never"), 1);
+        assert_eq!(job("never").unwrap().last_run_at, Some(START + MINUTE));
+        assert_eq!(ctx.global_timer(), START + 3 * MINUTE);
+
+        assert!(set_max_concurrency("never", 2));
+        let ctx = ctx.with_time(START + 3 * MINUTE);
+        timers::run_expired();
+        assert_eq!(running("never"), 2);
+        assert_eq!(ctx.global_timer(), START + 4 * MINUTE);
+    }
+
+    #[test]
+    fn reload() {
+        let ctx = MockContext::new().with_time(START).inject();
+        init(MemoryId::new(0));
+        register("count", count);
+        schedule("count", Schedule::Cron("*/5 * * * *".into())).unwrap();
+        assert_eq!(ctx.global_timer(), START + 5 * MINUTE);
+
+        // An upgrade loses the handlers and the timers, which are set again by the post_upgrade
+        // hook from the schedules in the stable memory.
+        let ctx = ctx.with_time(START + 2 * MINUTE);
+        timers::clear_timer(with_scheduler(|scheduler| scheduler.timers["count"]));
+        init(MemoryId::new(0));
+        assert_eq!(ctx.global_timer(), 0);
+
+        register("count", count);
+        schedule("count", Schedule::Cron("*/5 * * * *".into())).unwrap();
+        assert_eq!(ctx.global_timer(), START + 5 * MINUTE);
+        assert_eq!(jobs().len(), 1);
+
+        // A new schedule replaces the next run.
+        schedule("count", Schedule::Cron("@hourly".into())).unwrap();
+        assert_eq!(ctx.global_timer(), START + 60 * MINUTE);
+    }
+}
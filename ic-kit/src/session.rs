@@ -0,0 +1,401 @@
+//! Expiring single-use nonces issued per caller, the building block of the challenge-response
+//! authentication flows, where the client signs a challenge issued by the canister to prove it
+//! holds a key, and the canister makes sure the challenge is fresh and not replayed.
+//!
+//! The sessions should be kept as a part of the canister's state, and pruned periodically with
+//! [`prune_every`], which sets a timer of [`crate::timers`], or from a job of the
+//! [`crate::scheduler`]. A method which takes the nonce as its first argument can consume it from
+//! its guard with [`Sessions::verify_caller_arg`].
+//!
+//! ```ignore
+//! struct Challenges(Sessions);
+//!
+//! impl Default for Challenges {
+//!     fn default() -> Self {
+//!         // The challenges expire after 5 minutes.
+//!         Self(Sessions::new(300_000_000_000))
+//!     }
+//! }
+//!
+//! #[update]
+//! fn challenge(challenges: &mut Challenges) -> Nonce {
+//!     challenges.0.issue_for_caller()
+//! }
+//!
+//! fn fresh_challenge() -> Result<(), String> {
+//!     with_mut(|challenges: &mut Challenges| challenges.0.verify_caller_arg())
+//! }
+//!
+//! #[update(guard = "fresh_challenge")]
+//! fn login(nonce: Vec<u8>, signature: Vec<u8>) -> Result<(), String> {
+//!     // Verify the signature of the nonce...
+//!     Ok(())
+//! }
+//!
+//! #[init]
+//! fn init() {
+//!     session::prune_every(Duration::from_secs(60), |c: &mut Challenges| &mut c.0);
+//! }
+//!
+//! #[post_upgrade]
+//! fn post_upgrade() {
+//!     session::prune_every(Duration::from_secs(60), |c: &mut Challenges| &mut c.0);
+//! }
+//! ```
+
+use crate::ic::{caller, id, time, with_mut};
+use crate::{timers, utils};
+use candid::de::IDLDeserialize;
+use candid::{CandidType, Principal};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::Duration;
+
+/// The number of nonces each key can have at once by default, issuing more drops the oldest one.
+pub const DEFAULT_MAX_PER_KEY: usize = 4;
+
+/// A nonce issued by [`Sessions::issue`].
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct Nonce {
+    /// The 32 bytes of the nonce.
+    pub value: Vec<u8>,
+    /// The time the nonce expires at, in nanoseconds.
+    pub expires_at: u64,
+}
+
+/// The nonces issued to each key, which are valid until they are used once or expire.
+///
+/// The nonces are unique, they are the hash of a counter and the time mixed with the seed given
+/// to [`Sessions::set_seed`]. They are not secret unless the seed is, such as the bytes returned
+/// by `raw_rand`, which does not matter for a challenge which is signed by the client.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Sessions<K: Hash + Eq = Principal> {
+    ttl: u64,
+    max_per_key: usize,
+    seed: Vec<u8>,
+    counter: u64,
+    nonces: HashMap<K, Vec<Nonce>>,
+}
+
+impl<K: Hash + Eq> Sessions<K> {
+    /// Create the sessions whose nonces expire `ttl` nanoseconds after they are issued.
+    pub fn new(ttl: u64) -> Self {
+        assert!(ttl > 0, "The ttl of the nonces must not be zero.");
+
+        Self {
+            ttl,
+            max_per_key: DEFAULT_MAX_PER_KEY,
+            seed: Vec::new(),
+            counter: 0,
+            nonces: HashMap::new(),
+        }
+    }
+
+    /// Set the number of nonces each key can have at once, issuing more drops the oldest one.
+    pub fn with_max_per_key(mut self, max_per_key: usize) -> Self {
+        assert!(
+            max_per_key > 0,
+            "The number of nonces per key must not be zero."
+        );
+        self.max_per_key = max_per_key;
+        self
+    }
+
+    /// Mix the given bytes in the nonces issued from now on, such as the random bytes returned by
+    /// `raw_rand`, so they can not be predicted.
+    pub fn set_seed(&mut self, seed: &[u8]) {
+        let mut hasher = Sha256::new();
+        hasher.update(&self.seed);
+        hasher.update(seed);
+        self.seed = hasher.finalize().to_vec();
+    }
+
+    /// Issue a new nonce to the key at the given time.
+    pub fn issue(&mut self, key: K, now: u64) -> Nonce {
+        self.counter += 1;
+
+        let mut hasher = Sha256::new();
+        hasher.update(b"ic-kit-session");
+        hasher.update(&self.seed);
+        hasher.update(self.counter.to_le_bytes());
+        hasher.update(now.to_le_bytes());
+
+        let nonce = Nonce {
+            value: hasher.finalize().to_vec(),
+            expires_at: now.saturating_add(self.ttl),
+        };
+
+        let nonces = self.nonces.entry(key).or_default();
+        nonces.retain(|nonce| nonce.expires_at > now);
+        if nonces.len() >= self.max_per_key {
+            nonces.remove(0);
+        }
+        nonces.push(nonce.clone());
+
+        nonce
+    }
+
+    /// Consume the nonce issued to the key, returns an error if it was not issued to the key, if
+    /// it was already used, or if it has expired by the given time.
+    pub fn verify(&mut self, key: &K, nonce: &[u8], now: u64) -> Result<(), String> {
+        let nonces = self
+            .nonces
+            .get_mut(key)
+            .ok_or_else(|| "The nonce is unknown or was already used.".to_string())?;
+
+        let index = nonces
+            .iter()
+            .position(|issued| issued.value == nonce)
+            .ok_or_else(|| "The nonce is unknown or was already used.".to_string())?;
+
+        let issued = nonces.remove(index);
+        if nonces.is_empty() {
+            self.nonces.remove(key);
+        }
+
+        if issued.expires_at <= now {
+            return Err("The nonce has expired.".into());
+        }
+
+        Ok(())
+    }
+
+    /// Return the number of the nonces of the key which are not used yet, the expired ones
+    /// included until they are pruned.
+    pub fn pending(&self, key: &K) -> usize {
+        self.nonces.get(key).map(Vec::len).unwrap_or(0)
+    }
+
+    /// Remove the nonces which have expired by the given time, and return their number. This
+    /// should be called periodically to limit the memory used by the sessions.
+    pub fn prune(&mut self, now: u64) -> usize {
+        let mut pruned = 0;
+
+        self.nonces.retain(|_, nonces| {
+            let len = nonces.len();
+            nonces.retain(|nonce| nonce.expires_at > now);
+            pruned += len - nonces.len();
+            !nonces.is_empty()
+        });
+
+        pruned
+    }
+}
+
+impl Sessions<Principal> {
+    /// Issue a new nonce to the caller of the current method, the canister's id is mixed in the
+    /// nonce so the nonces of different canisters do not collide.
+    pub fn issue_for_caller(&mut self) -> Nonce {
+        if self.seed.is_empty() {
+            self.set_seed(id().as_slice());
+        }
+
+        self.issue(caller(), time())
+    }
+
+    /// Consume the nonce issued to the caller of the current method.
+    pub fn verify_caller(&mut self, nonce: &[u8]) -> Result<(), String> {
+        self.verify(&caller(), nonce, time())
+    }
+
+    /// Consume the nonce issued to the caller, which is the first argument of the current method,
+    /// a `blob`. This is meant for the guard of the method, which runs before its arguments are
+    /// decoded, so the method is only called with a fresh nonce.
+    pub fn verify_caller_arg(&mut self) -> Result<(), String> {
+        let arg = utils::arg_data_raw();
+        let nonce = IDLDeserialize::new(&arg)
+            .and_then(|mut de| de.get_value::<Vec<u8>>())
+            .map_err(|_| "The first argument must be the nonce.".to_string())?;
+
+        self.verify_caller(&nonce)
+    }
+}
+
+/// Prune the sessions returned by the given function from the state of the canister every
+/// `interval`, with a timer which sets the next one once it runs. The timers are lost on the
+/// upgrades, so this should be called from both the `init` and the `post_upgrade` hooks.
+pub fn prune_every<T: 'static + Default, K: Hash + Eq + 'static>(
+    interval: Duration,
+    sessions: fn(&mut T) -> &mut Sessions<K>,
+) {
+    assert!(
+        interval.as_nanos() > 0,
+        "The interval of the pruning must not be zero."
+    );
+
+    timers::set_timer(interval, move || {
+        with_mut(|state: &mut T| sessions(state).prune(time()));
+        prune_every(interval, sessions);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ic::with;
+    use crate::rt::context::MockContext;
+
+    const START: u64 = 1_000_000_000_000;
+    const TTL: u64 = 60_000_000_000;
+
+    fn alice() -> Principal {
+        Principal::from_slice(&[1])
+    }
+
+    fn bob() -> Principal {
+        Principal::from_slice(&[2])
+    }
+
+    #[test]
+    fn expiry() {
+        let mut sessions = Sessions::new(TTL);
+
+        let nonce = sessions.issue(alice(), START);
+        assert_eq!(nonce.value.len(), 32);
+        assert_eq!(nonce.expires_at, START + TTL);
+        assert_eq!(
+            sessions.verify(&alice(), &nonce.value, START + TTL),
+            Err("The nonce has expired.".into())
+        );
+
+        let nonce = sessions.issue(alice(), START);
+        assert_eq!(
+            sessions.verify(&alice(), &nonce.value, START + TTL - 1),
+            Ok(())
+        );
+
+        // The expired nonces are kept until they are pruned.
+        sessions.issue(alice(), START);
+        sessions.issue(bob(), START + TTL);
+        assert_eq!(sessions.pending(&alice()), 1);
+        assert_eq!(sessions.prune(START + TTL - 1), 0);
+        assert_eq!(sessions.prune(START + TTL), 1);
+        assert_eq!(sessions.pending(&alice()), 0);
+        assert_eq!(sessions.pending(&bob()), 1);
+    }
+
+    #[test]
+    fn replay() {
+        let mut sessions = Sessions::new(TTL);
+        let nonce = sessions.issue(alice(), START);
+
+        // The nonce is bound to the key it was issued to.
+        assert_eq!(
+            sessions.verify(&bob(), &nonce.value, START),
+            Err("The nonce is unknown or was already used.".into())
+        );
+
+        assert_eq!(sessions.verify(&alice(), &nonce.value, START), Ok(()));
+        assert_eq!(
+            sessions.verify(&alice(), &nonce.value, START),
+            Err("The nonce is unknown or was already used.".into())
+        );
+        assert_eq!(
+            sessions.verify(&alice(), &[0; 32], START),
+            Err("The nonce is unknown or was already used.".into())
+        );
+    }
+
+    #[test]
+    fn max_per_key() {
+        let mut sessions = Sessions::new(TTL).with_max_per_key(2);
+
+        let first = sessions.issue(alice(), START);
+        let second = sessions.issue(alice(), START + 1);
+        let third = sessions.issue(alice(), START + 2);
+        assert_ne!(first.value, second.value);
+        assert_eq!(sessions.pending(&alice()), 2);
+
+        // The oldest nonce is dropped.
+        assert!(sessions.verify(&alice(), &first.value, START + 3).is_err());
+        assert_eq!(sessions.verify(&alice(), &second.value, START + 3), Ok(()));
+        assert_eq!(sessions.verify(&alice(), &third.value, START + 3), Ok(()));
+
+        // The expired nonces make room for the new ones before the oldest is dropped.
+        let old = sessions.issue(alice(), START);
+        let kept = sessions.issue(alice(), START + TTL - 1);
+        sessions.issue(alice(), START + TTL);
+        assert!(sessions.verify(&alice(), &old.value, START + TTL).is_err());
+        assert_eq!(sessions.verify(&alice(), &kept.value, START + TTL), Ok(()));
+    }
+
+    #[test]
+    fn seed() {
+        let mut a = Sessions::new(TTL);
+        let mut b = Sessions::new(TTL);
+        assert_eq!(a.issue(alice(), START), b.issue(alice(), START));
+
+        b.set_seed(b"random");
+        assert_ne!(a.issue(alice(), START), b.issue(alice(), START));
+    }
+
+    #[derive(Default)]
+    struct Challenges(Option<Sessions>);
+
+    impl Challenges {
+        fn sessions(&mut self) -> &mut Sessions {
+            self.0.get_or_insert_with(|| Sessions::new(TTL))
+        }
+    }
+
+    #[test]
+    fn guard() {
+        let ctx = MockContext::new()
+            .with_caller(alice())
+            .with_time(START)
+            .inject();
+
+        let nonce = with_mut(|c: &mut Challenges| c.sessions().issue_for_caller());
+
+        let ctx = ctx.with_args((vec![0u8; 32], 1u64));
+        assert!(with_mut(|c: &mut Challenges| c.sessions().verify_caller_arg()).is_err());
+
+        let ctx = ctx.with_args((nonce.value.clone(), 1u64));
+        assert_eq!(
+            with_mut(|c: &mut Challenges| c.sessions().verify_caller_arg()),
+            Ok(())
+        );
+        assert!(with_mut(|c: &mut Challenges| c.sessions().verify_caller_arg()).is_err());
+
+        let nonce = with_mut(|c: &mut Challenges| c.sessions().issue_for_caller());
+        let ctx = ctx.with_caller(bob()).with_args((nonce.value, 1u64));
+        assert!(with_mut(|c: &mut Challenges| c.sessions().verify_caller_arg()).is_err());
+
+        ctx.with_arg(1u64);
+        assert_eq!(
+            with_mut(|c: &mut Challenges| c.sessions().verify_caller_arg()),
+            Err("The first argument must be the nonce.".into())
+        );
+    }
+
+    #[test]
+    fn prune_on_timers() {
+        let ctx = MockContext::new()
+            .with_caller(alice())
+            .with_time(START)
+            .inject();
+
+        with_mut(|c: &mut Challenges| c.sessions().issue_for_caller());
+        prune_every(Duration::from_secs(60), |c: &mut Challenges| c.sessions());
+        assert_eq!(ctx.global_timer(), START + TTL);
+
+        let ctx = ctx.with_time(START + TTL);
+        timers::run_expired();
+        assert_eq!(
+            with(|c: &Challenges| c.0.as_ref().unwrap().pending(&alice())),
+            0
+        );
+
+        // The pruning runs again after the interval.
+        with_mut(|c: &mut Challenges| c.sessions().issue_for_caller());
+        assert_eq!(ctx.global_timer(), START + 2 * TTL);
+        ctx.with_time(START + 2 * TTL);
+        timers::run_expired();
+        assert_eq!(
+            with(|c: &Challenges| c.0.as_ref().unwrap().pending(&alice())),
+            0
+        );
+    }
+}
@@ -1,115 +1,39 @@
 /// Provides utility methods to deal with stable storage on your canister.
 // This file is copied from ic_cdk, but changed so that it works with IC-Kit.
-use crate::ic::{stable_bytes, stable_grow, stable_read, stable_size, stable_write, StableSize};
+use crate::ic::stable_bytes;
 use candid::utils::{ArgumentDecoder, ArgumentEncoder};
-use std::io;
+use candid::CandidType;
+use serde::Deserialize;
+use std::io::Read;
+
+mod btreemap;
+mod cell;
+mod compression;
+mod io;
+mod key;
+mod log;
+mod memory;
+mod memory_manager;
+mod migration;
+mod snapshot;
+mod storable;
+mod vec;
+
+pub use btreemap::*;
+pub use cell::*;
+pub use compression::*;
+pub use io::*;
+pub use key::*;
+pub use log::*;
+pub use memory::*;
+pub use memory_manager::*;
+pub use migration::*;
+pub use snapshot::*;
+pub use storable::*;
+pub use vec::*;
 
 pub use ic_kit_sys::types::StableMemoryError;
 
-/// A writer to the stable memory.
-///
-/// Will attempt to grow the memory as it writes,
-/// and keep offsets and total capacity.
-pub struct StableWriter {
-    /// The offset of the next write.
-    offset: StableSize,
-    /// The capacity, in pages.
-    capacity: StableSize,
-}
-
-impl Default for StableWriter {
-    fn default() -> Self {
-        let capacity = stable_size();
-
-        Self {
-            offset: 0,
-            capacity,
-        }
-    }
-}
-
-impl StableWriter {
-    /// Create a new stable writer that writes from the given offset forward.
-    pub fn new(offset: StableSize) -> Self {
-        StableWriter {
-            offset,
-            capacity: stable_size(),
-        }
-    }
-
-    /// Returns the current offset of the writer.
-    pub fn offset(&self) -> StableSize {
-        self.offset
-    }
-
-    /// Attempts to grow the memory by adding new pages.
-    pub fn grow(&mut self, added_pages: StableSize) -> Result<(), StableMemoryError> {
-        let old_page_count = stable_grow(added_pages)?;
-        self.capacity = old_page_count + added_pages;
-        Ok(())
-    }
-
-    /// Writes a byte slice to the buffer.
-    ///
-    /// The only condition where this will error out is if it cannot grow the memory.
-    pub fn write(&mut self, buf: &[u8]) -> Result<usize, StableMemoryError> {
-        if self.offset + (buf.len() as StableSize) > (self.capacity << 16) {
-            self.grow((buf.len() >> 16) as StableSize + 1)?;
-        }
-
-        stable_write(self.offset, buf);
-        self.offset += buf.len() as StableSize;
-        Ok(buf.len())
-    }
-}
-
-impl io::Write for StableWriter {
-    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
-        self.write(buf)
-            .map_err(|_| io::Error::new(io::ErrorKind::Other, "Out Of Memory"))
-    }
-
-    fn flush(&mut self) -> Result<(), io::Error> {
-        // Noop.
-        Ok(())
-    }
-}
-
-/// A reader to the stable memory.
-///
-/// Keeps an offset and reads off stable memory consecutively.
-pub struct StableReader {
-    /// The offset of the next write.
-    offset: StableSize,
-}
-
-impl Default for StableReader {
-    fn default() -> Self {
-        Self { offset: 0 }
-    }
-}
-
-impl StableReader {
-    /// Create a new stable reader that reads from the given offset forward.
-    pub fn new(offset: StableSize) -> Self {
-        StableReader { offset }
-    }
-
-    /// Reads data from the stable memory location specified by an offset.
-    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, StableMemoryError> {
-        stable_read(self.offset, buf);
-        self.offset += buf.len() as StableSize;
-        Ok(buf.len())
-    }
-}
-
-impl io::Read for StableReader {
-    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
-        self.read(buf)
-            .map_err(|_| io::Error::new(io::ErrorKind::Other, "Unexpected error."))
-    }
-}
-
 /// Store the given data to the stable storage.
 #[deprecated(
     since = "0.5.0",
@@ -138,3 +62,169 @@ where
     let res = ArgumentDecoder::decode(&mut de).map_err(|e| format!("{:?}", e))?;
     Ok(res)
 }
+
+/// The magic bytes at the beginning of a snapshot written by [`stable_save_versioned`].
+const VERSIONED_MAGIC: &[u8; 4] = b"KITS";
+
+/// The magic bytes at the beginning of a snapshot written by [`stable_save_compressed`].
+const COMPRESSED_MAGIC: &[u8; 4] = b"KITC";
+
+/// The size of the header of a versioned snapshot: the magic bytes, the version as a u32 and the
+/// length of the candid encoded data as a u64, all in little endian.
+const VERSIONED_HEADER_SIZE: usize = 16;
+
+/// The size of the header of a compressed snapshot, which is the header of a versioned snapshot
+/// followed by the tag of the compression algorithm, padded to 8 bytes.
+const COMPRESSED_HEADER_SIZE: usize = 24;
+
+/// Store the given value to the stable storage using candid, prefixed with a header containing
+/// the provided version so that it can be checked when restoring the data.
+pub fn stable_save_versioned<T>(version: u32, data: &T) -> Result<(), String>
+where
+    T: CandidType,
+{
+    stable_save_versioned_to(&DefaultMemory, version, data)
+}
+
+/// Restore a value stored using [`stable_save_versioned`] or [`stable_save_compressed`]. Returns
+/// `None` if the stable storage is empty, and an error if the data was saved with a different
+/// version.
+pub fn stable_restore_versioned<T>(version: u32) -> Result<Option<T>, String>
+where
+    T: CandidType + for<'de> Deserialize<'de>,
+{
+    stable_restore_versioned_from(&DefaultMemory, version)
+}
+
+/// Same as [`stable_save_versioned`], but compresses the candid encoded data with the given
+/// algorithm, which is recorded in the header so [`stable_restore_versioned`] can decompress it.
+pub fn stable_save_compressed<T>(
+    version: u32,
+    data: &T,
+    compression: Compression,
+) -> Result<(), String>
+where
+    T: CandidType,
+{
+    stable_save_compressed_to(&DefaultMemory, version, data, compression)
+}
+
+/// Same as [`stable_save_versioned`], but stores the value in the given memory, such as a
+/// virtual memory of the [`MemoryManager`] so the snapshot can live along the stable structures.
+pub fn stable_save_versioned_to<M, T>(memory: &M, version: u32, data: &T) -> Result<(), String>
+where
+    M: Memory,
+    T: CandidType,
+{
+    // The data is streamed to the memory after the header, which is written last once the length
+    // of the data is known.
+    let mut writer = StableWriter64::new(memory, VERSIONED_HEADER_SIZE as u64);
+    candid::ser::IDLBuilder::new()
+        .arg(data)
+        .and_then(|builder| builder.serialize(&mut writer))
+        .map_err(|e| format!("{:?}", e))?;
+    let len = writer.offset() - VERSIONED_HEADER_SIZE as u64;
+    drop(writer);
+
+    let mut header = [0u8; VERSIONED_HEADER_SIZE];
+    header[0..4].copy_from_slice(VERSIONED_MAGIC);
+    header[4..8].copy_from_slice(&version.to_le_bytes());
+    header[8..16].copy_from_slice(&len.to_le_bytes());
+    memory.write(0, &header);
+
+    Ok(())
+}
+
+/// Same as [`stable_save_compressed`], but stores the value in the given memory.
+pub fn stable_save_compressed_to<M, T>(
+    memory: &M,
+    version: u32,
+    data: &T,
+    compression: Compression,
+) -> Result<(), String>
+where
+    M: Memory,
+    T: CandidType,
+{
+    let mut writer = StableWriter64::new(memory, COMPRESSED_HEADER_SIZE as u64);
+    let mut encoder =
+        CompressionWriter::new(&mut writer, compression).map_err(|e| format!("{:?}", e))?;
+    candid::ser::IDLBuilder::new()
+        .arg(data)
+        .and_then(|builder| builder.serialize(&mut encoder))
+        .map_err(|e| format!("{:?}", e))?;
+    encoder.finish().map_err(|e| format!("{:?}", e))?;
+    let len = writer.offset() - COMPRESSED_HEADER_SIZE as u64;
+    drop(writer);
+
+    let mut header = [0u8; COMPRESSED_HEADER_SIZE];
+    header[0..4].copy_from_slice(COMPRESSED_MAGIC);
+    header[4..8].copy_from_slice(&version.to_le_bytes());
+    header[8..16].copy_from_slice(&len.to_le_bytes());
+    header[16] = compression.tag();
+    memory.write(0, &header);
+
+    Ok(())
+}
+
+/// Same as [`stable_restore_versioned`], but restores the value from the given memory.
+pub fn stable_restore_versioned_from<M, T>(memory: &M, version: u32) -> Result<Option<T>, String>
+where
+    M: Memory,
+    T: CandidType + for<'de> Deserialize<'de>,
+{
+    if memory.size() == 0 {
+        return Ok(None);
+    }
+
+    let mut header = [0u8; COMPRESSED_HEADER_SIZE];
+    memory.read(0, &mut header);
+
+    let (header_size, compression) = match &header[0..4] {
+        magic if magic == VERSIONED_MAGIC => (VERSIONED_HEADER_SIZE, Compression::None),
+        magic if magic == COMPRESSED_MAGIC => (
+            COMPRESSED_HEADER_SIZE,
+            Compression::from_tag(header[16]).ok_or_else(|| {
+                format!(
+                    "Unknown compression algorithm {} in the snapshot.",
+                    header[16]
+                )
+            })?,
+        ),
+        _ => return Err("The stable storage does not contain a versioned snapshot.".into()),
+    };
+
+    let mut version_bytes = [0u8; 4];
+    version_bytes.copy_from_slice(&header[4..8]);
+    let stored_version = u32::from_le_bytes(version_bytes);
+
+    let mut len_bytes = [0u8; 8];
+    len_bytes.copy_from_slice(&header[8..16]);
+    let len = u64::from_le_bytes(len_bytes);
+
+    if stored_version != version {
+        return Err(format!(
+            "Expected the stable snapshot version to be {} but found {}.",
+            version, stored_version
+        ));
+    }
+
+    let bytes = if compression == Compression::None {
+        let mut bytes = vec![0; len as usize];
+        memory.read(header_size as u64, &mut bytes);
+        bytes
+    } else {
+        let reader = StableReader64::new(memory, header_size as u64).take(len);
+        let mut decoder =
+            DecompressionReader::new(reader, compression).map_err(|e| format!("{:?}", e))?;
+        let mut bytes = Vec::new();
+        decoder
+            .read_to_end(&mut bytes)
+            .map_err(|e| format!("{:?}", e))?;
+        bytes
+    };
+
+    let data = candid::decode_one(&bytes).map_err(|e| format!("{:?}", e))?;
+
+    Ok(Some(data))
+}
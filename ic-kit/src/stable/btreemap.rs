@@ -0,0 +1,1002 @@
+use super::memory::{ensure_size, read_u64, write_u64, DefaultMemory, Memory};
+use super::storable::Storable;
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+
+/// The magic bytes at the beginning of the memory of a map.
+const MAGIC: &[u8; 4] = b"KBTM";
+
+// The layout of the header: the magic bytes, followed by the address of the root node, the number
+// of entries, the end of the allocated space and the heads of the free lists, as u64s.
+const ROOT_OFFSET: u64 = 8;
+const LEN_OFFSET: u64 = 16;
+const BUMP_OFFSET: u64 = 24;
+const FREE_LISTS_OFFSET: u64 = 32;
+const HEADER_SIZE: u64 = FREE_LISTS_OFFSET + 8 * SIZE_CLASSES as u64;
+
+/// The number of the block size classes of the allocator, the sizes are powers of two starting
+/// from [`MIN_BLOCK_SIZE`].
+const SIZE_CLASSES: usize = 40;
+const MIN_BLOCK_SIZE: u64 = 32;
+
+/// The minimum degree of the tree, every node except the root has at least `B - 1` entries.
+const B: usize = 6;
+const CAPACITY: usize = 2 * B - 1;
+
+// The layout of a node: the node kind and the number of entries, followed by the entries as the
+// address and length of the key and the value, and the addresses of the children.
+const ENTRY_SIZE: usize = 32;
+const ENTRIES_OFFSET: usize = 8;
+const CHILDREN_OFFSET: usize = ENTRIES_OFFSET + CAPACITY * ENTRY_SIZE;
+const NODE_SIZE: usize = CHILDREN_OFFSET + (CAPACITY + 1) * 8;
+
+/// A byte array stored in the memory, a blob with the length of zero is not allocated.
+#[derive(Copy, Clone)]
+struct Blob {
+    addr: u64,
+    len: u64,
+}
+
+/// The key and the value of an entry.
+#[derive(Copy, Clone)]
+struct Entry {
+    key: Blob,
+    value: Blob,
+}
+
+/// A node of the tree loaded in the heap, along with the decoded keys.
+struct Node<K> {
+    addr: u64,
+    leaf: bool,
+    keys: Vec<K>,
+    entries: Vec<Entry>,
+    children: Vec<u64>,
+}
+
+/// A simple allocator which keeps a free list for each power of two block size.
+struct Allocator {
+    bump: u64,
+    free: [u64; SIZE_CLASSES],
+}
+
+impl Allocator {
+    fn size_class(size: u64) -> usize {
+        let mut class = 0;
+        while (MIN_BLOCK_SIZE << class) < size {
+            class += 1;
+        }
+        class
+    }
+
+    fn allocate<M: Memory>(&mut self, memory: &M, size: u64) -> u64 {
+        let class = Self::size_class(size);
+        let head = self.free[class];
+
+        if head != 0 {
+            self.free[class] = read_u64(memory, head);
+            write_u64(
+                memory,
+                FREE_LISTS_OFFSET + 8 * class as u64,
+                self.free[class],
+            );
+            return head;
+        }
+
+        let addr = self.bump;
+        ensure_size(memory, addr + (MIN_BLOCK_SIZE << class))
+            .expect("Could not grow the stable memory for the map.");
+        self.bump = addr + (MIN_BLOCK_SIZE << class);
+        write_u64(memory, BUMP_OFFSET, self.bump);
+        addr
+    }
+
+    fn free<M: Memory>(&mut self, memory: &M, addr: u64, size: u64) {
+        let class = Self::size_class(size);
+        write_u64(memory, addr, self.free[class]);
+        self.free[class] = addr;
+        write_u64(memory, FREE_LISTS_OFFSET + 8 * class as u64, addr);
+    }
+}
+
+/// A map stored directly in the stable memory, so it does not have to be serialized in the
+/// `pre_upgrade` hook and can grow beyond the size of the heap.
+///
+/// The entries are kept in a B-tree ordered by the keys, the keys and the values are converted
+/// to bytes using [`Storable`] and can be of any size. Each operation only reads the nodes on the
+/// path to the entry, so the map can hold a large number of entries.
+///
+/// ```ignore
+/// use ic_kit::stable::{DefaultMemory, StableBTreeMap};
+///
+/// let mut balances = StableBTreeMap::<Principal, u64>::init(DefaultMemory);
+/// balances.insert(caller(), 100);
+/// ```
+pub struct StableBTreeMap<K, V, M: Memory = DefaultMemory> {
+    memory: M,
+    root: u64,
+    len: u64,
+    allocator: Allocator,
+    _marker: PhantomData<(K, V)>,
+}
+
+impl<K, V, M> StableBTreeMap<K, V, M>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+{
+    /// Load the map stored in the memory, or create a new one if the memory is empty.
+    ///
+    /// # Panics
+    ///
+    /// If the memory is not empty and does not contain a map.
+    pub fn init(memory: M) -> Self {
+        if memory.size() == 0 {
+            return Self::new(memory);
+        }
+
+        Self::load(memory)
+    }
+
+    /// Create a new empty map in the memory, overwriting its content.
+    ///
+    /// # Panics
+    ///
+    /// If the memory can not grow to store the header of the map.
+    pub fn new(memory: M) -> Self {
+        ensure_size(&memory, HEADER_SIZE).expect("Could not grow the stable memory for the map.");
+
+        let mut map = Self {
+            memory,
+            root: 0,
+            len: 0,
+            allocator: Allocator {
+                bump: HEADER_SIZE,
+                free: [0; SIZE_CLASSES],
+            },
+            _marker: PhantomData,
+        };
+
+        map.clear();
+        map
+    }
+
+    /// Load the map stored in the memory.
+    ///
+    /// # Panics
+    ///
+    /// If the memory does not contain a map.
+    pub fn load(memory: M) -> Self {
+        let mut magic = [0; 4];
+        memory.read(0, &mut magic);
+        assert_eq!(
+            &magic, MAGIC,
+            "The memory does not contain a StableBTreeMap."
+        );
+
+        let mut free = [0; SIZE_CLASSES];
+        for (class, head) in free.iter_mut().enumerate() {
+            *head = read_u64(&memory, FREE_LISTS_OFFSET + 8 * class as u64);
+        }
+
+        Self {
+            root: read_u64(&memory, ROOT_OFFSET),
+            len: read_u64(&memory, LEN_OFFSET),
+            allocator: Allocator {
+                bump: read_u64(&memory, BUMP_OFFSET),
+                free,
+            },
+            memory,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Return the number of entries in the map.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns true if the map does not have any entries.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Return the value of the given key.
+    pub fn get(&self, key: &K) -> Option<V> {
+        if self.root == 0 {
+            return None;
+        }
+
+        let mut node = self.load_node(self.root);
+
+        loop {
+            match node.keys.binary_search(key) {
+                Ok(i) => return Some(V::from_bytes(self.read_blob(node.entries[i].value))),
+                Err(_) if node.leaf => return None,
+                Err(i) => node = self.load_node(node.children[i]),
+            }
+        }
+    }
+
+    /// Returns true if the map contains the given key.
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Insert the value with the given key, and return the previous value of the key.
+    ///
+    /// # Panics
+    ///
+    /// If the memory can not grow to store the entry.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let value = self.write_blob(&value.to_bytes());
+
+        if self.root == 0 {
+            let mut root = self.new_node(true);
+            root.entries.push(Entry {
+                key: self.write_blob(&key.to_bytes()),
+                value,
+            });
+            root.keys.push(key);
+            self.save_node(&root);
+            self.set_root(root.addr);
+            self.set_len(1);
+            return None;
+        }
+
+        let mut root = self.load_node(self.root);
+
+        // Split the root before it is full, so the tree grows from the top.
+        if root.keys.len() == CAPACITY {
+            let mut new_root = self.new_node(false);
+            new_root.children.push(root.addr);
+            self.split_child(&mut new_root, 0, root);
+            self.set_root(new_root.addr);
+            root = new_root;
+        }
+
+        match self.insert_non_full(root, key, value) {
+            Some(previous) => {
+                let bytes = self.read_blob(previous);
+                self.free_blob(previous);
+                Some(V::from_bytes(bytes))
+            }
+            None => {
+                self.set_len(self.len + 1);
+                None
+            }
+        }
+    }
+
+    /// Remove the key from the map, and return its value.
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        if self.root == 0 {
+            return None;
+        }
+
+        let root = self.load_node(self.root);
+        let removed = self.remove_from(root, key);
+
+        // Shrink the tree from the top once the root becomes empty.
+        let root = self.load_node(self.root);
+        if root.keys.is_empty() {
+            let new_root = if root.leaf { 0 } else { root.children[0] };
+            self.free_node(root.addr);
+            self.set_root(new_root);
+        }
+
+        removed.map(|entry| {
+            let bytes = self.read_blob(entry.value);
+            self.free_blob(entry.key);
+            self.free_blob(entry.value);
+            self.set_len(self.len - 1);
+            V::from_bytes(bytes)
+        })
+    }
+
+    /// Return the entry with the smallest key.
+    pub fn first_key_value(&self) -> Option<(K, V)> {
+        self.range(..).next()
+    }
+
+    /// Return the entry with the largest key.
+    pub fn last_key_value(&self) -> Option<(K, V)> {
+        if self.root == 0 {
+            return None;
+        }
+
+        let mut node = self.load_node(self.root);
+        while !node.leaf {
+            node = self.load_node(*node.children.last().unwrap());
+        }
+
+        let i = node.keys.len() - 1;
+        let value = V::from_bytes(self.read_blob(node.entries[i].value));
+        Some((node.keys.swap_remove(i), value))
+    }
+
+    /// Iterate over the entries of the map in the order of the keys.
+    pub fn iter(&self) -> MapIter<'_, K, V, M> {
+        self.range(..)
+    }
+
+    /// Iterate over the entries with a key in the given range, in the order of the keys.
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> MapIter<'_, K, V, M> {
+        let mut stack = Vec::new();
+        let mut addr = self.root;
+
+        // Find the path to the first entry in the range, each node on the stack is paired with the
+        // index of the next entry to return from it.
+        while addr != 0 {
+            let node = self.load_node(addr);
+            let (index, found) = match range.start_bound() {
+                Bound::Unbounded => (0, false),
+                Bound::Included(key) => match node.keys.binary_search(key) {
+                    Ok(i) => (i, true),
+                    Err(i) => (i, false),
+                },
+                Bound::Excluded(key) => match node.keys.binary_search(key) {
+                    Ok(i) => (i + 1, false),
+                    Err(i) => (i, false),
+                },
+            };
+
+            addr = if found || node.leaf {
+                0
+            } else {
+                node.children[index]
+            };
+            stack.push((node, index));
+        }
+
+        MapIter {
+            map: self,
+            stack,
+            end: match range.end_bound() {
+                Bound::Included(key) => Bound::Included(key.clone()),
+                Bound::Excluded(key) => Bound::Excluded(key.clone()),
+                Bound::Unbounded => Bound::Unbounded,
+            },
+        }
+    }
+
+    /// Remove all of the entries from the map, the space used by the entries is reused for the new
+    /// entries but the memory does not shrink.
+    pub fn clear(&mut self) {
+        let mut header = vec![0; HEADER_SIZE as usize];
+        header[0..4].copy_from_slice(MAGIC);
+        header[BUMP_OFFSET as usize..FREE_LISTS_OFFSET as usize]
+            .copy_from_slice(&HEADER_SIZE.to_le_bytes());
+        self.memory.write(0, &header);
+
+        self.root = 0;
+        self.len = 0;
+        self.allocator = Allocator {
+            bump: HEADER_SIZE,
+            free: [0; SIZE_CLASSES],
+        };
+    }
+
+    /// Return the memory of the map.
+    pub fn into_memory(self) -> M {
+        self.memory
+    }
+
+    fn set_root(&mut self, root: u64) {
+        self.root = root;
+        write_u64(&self.memory, ROOT_OFFSET, root);
+    }
+
+    fn set_len(&mut self, len: u64) {
+        self.len = len;
+        write_u64(&self.memory, LEN_OFFSET, len);
+    }
+
+    fn write_blob(&mut self, bytes: &[u8]) -> Blob {
+        if bytes.is_empty() {
+            return Blob { addr: 0, len: 0 };
+        }
+
+        let len = bytes.len() as u64;
+        let addr = self.allocator.allocate(&self.memory, len);
+        self.memory.write(addr, bytes);
+        Blob { addr, len }
+    }
+
+    fn read_blob(&self, blob: Blob) -> Vec<u8> {
+        let mut bytes = vec![0; blob.len as usize];
+        if blob.len > 0 {
+            self.memory.read(blob.addr, &mut bytes);
+        }
+        bytes
+    }
+
+    fn free_blob(&mut self, blob: Blob) {
+        if blob.len > 0 {
+            self.allocator.free(&self.memory, blob.addr, blob.len);
+        }
+    }
+
+    fn new_node(&mut self, leaf: bool) -> Node<K> {
+        Node {
+            addr: self.allocator.allocate(&self.memory, NODE_SIZE as u64),
+            leaf,
+            keys: Vec::with_capacity(CAPACITY),
+            entries: Vec::with_capacity(CAPACITY),
+            children: Vec::with_capacity(CAPACITY + 1),
+        }
+    }
+
+    fn free_node(&mut self, addr: u64) {
+        self.allocator.free(&self.memory, addr, NODE_SIZE as u64);
+    }
+
+    fn load_node(&self, addr: u64) -> Node<K> {
+        let mut buf = vec![0; NODE_SIZE];
+        self.memory.read(addr, &mut buf);
+
+        let u64_at = |offset: usize| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&buf[offset..offset + 8]);
+            u64::from_le_bytes(bytes)
+        };
+
+        let leaf = buf[0] == 0;
+        let len = buf[1] as usize;
+
+        let entries = (0..len)
+            .map(|i| {
+                let offset = ENTRIES_OFFSET + i * ENTRY_SIZE;
+                Entry {
+                    key: Blob {
+                        addr: u64_at(offset),
+                        len: u64_at(offset + 8),
+                    },
+                    value: Blob {
+                        addr: u64_at(offset + 16),
+                        len: u64_at(offset + 24),
+                    },
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let children = if leaf {
+            Vec::new()
+        } else {
+            (0..=len).map(|i| u64_at(CHILDREN_OFFSET + i * 8)).collect()
+        };
+
+        let keys = entries
+            .iter()
+            .map(|entry| K::from_bytes(self.read_blob(entry.key)))
+            .collect();
+
+        Node {
+            addr,
+            leaf,
+            keys,
+            entries,
+            children,
+        }
+    }
+
+    fn save_node(&self, node: &Node<K>) {
+        let mut buf = vec![0; NODE_SIZE];
+        buf[0] = if node.leaf { 0 } else { 1 };
+        buf[1] = node.entries.len() as u8;
+
+        for (i, entry) in node.entries.iter().enumerate() {
+            let offset = ENTRIES_OFFSET + i * ENTRY_SIZE;
+            buf[offset..offset + 8].copy_from_slice(&entry.key.addr.to_le_bytes());
+            buf[offset + 8..offset + 16].copy_from_slice(&entry.key.len.to_le_bytes());
+            buf[offset + 16..offset + 24].copy_from_slice(&entry.value.addr.to_le_bytes());
+            buf[offset + 24..offset + 32].copy_from_slice(&entry.value.len.to_le_bytes());
+        }
+
+        for (i, child) in node.children.iter().enumerate() {
+            let offset = CHILDREN_OFFSET + i * 8;
+            buf[offset..offset + 8].copy_from_slice(&child.to_le_bytes());
+        }
+
+        self.memory.write(node.addr, &buf);
+    }
+
+    /// Split the full child at the given index of the parent into two nodes, moving its median
+    /// entry to the parent. Returns the two halves.
+    fn split_child(
+        &mut self,
+        parent: &mut Node<K>,
+        index: usize,
+        mut child: Node<K>,
+    ) -> (Node<K>, Node<K>) {
+        let mut right = self.new_node(child.leaf);
+        right.keys = child.keys.split_off(B);
+        right.entries = child.entries.split_off(B);
+        if !child.leaf {
+            right.children = child.children.split_off(B);
+        }
+
+        parent.keys.insert(index, child.keys.pop().unwrap());
+        parent.entries.insert(index, child.entries.pop().unwrap());
+        parent.children.insert(index + 1, right.addr);
+
+        self.save_node(&child);
+        self.save_node(&right);
+        self.save_node(parent);
+
+        (child, right)
+    }
+
+    /// Insert the entry in the subtree of a node that is not full, returns the previous value if
+    /// the key was already in the map.
+    fn insert_non_full(&mut self, mut node: Node<K>, key: K, value: Blob) -> Option<Blob> {
+        loop {
+            let index = match node.keys.binary_search(&key) {
+                Ok(i) => {
+                    let previous = std::mem::replace(&mut node.entries[i].value, value);
+                    self.save_node(&node);
+                    return Some(previous);
+                }
+                Err(i) => i,
+            };
+
+            if node.leaf {
+                node.entries.insert(
+                    index,
+                    Entry {
+                        key: self.write_blob(&key.to_bytes()),
+                        value,
+                    },
+                );
+                node.keys.insert(index, key);
+                self.save_node(&node);
+                return None;
+            }
+
+            let child = self.load_node(node.children[index]);
+
+            if child.keys.len() < CAPACITY {
+                node = child;
+                continue;
+            }
+
+            // Split the full child before descending, so there is always room for the entry.
+            let (left, right) = self.split_child(&mut node, index, child);
+            node = match key.cmp(&node.keys[index]) {
+                std::cmp::Ordering::Less => left,
+                std::cmp::Ordering::Greater => right,
+                std::cmp::Ordering::Equal => {
+                    let previous = std::mem::replace(&mut node.entries[index].value, value);
+                    self.save_node(&node);
+                    return Some(previous);
+                }
+            };
+        }
+    }
+
+    /// Remove the key from the subtree of a node that has at least `B` entries, or is the root.
+    fn remove_from(&mut self, mut node: Node<K>, key: &K) -> Option<Entry> {
+        loop {
+            let index = match node.keys.binary_search(key) {
+                Ok(i) if node.leaf => {
+                    node.keys.remove(i);
+                    let entry = node.entries.remove(i);
+                    self.save_node(&node);
+                    return Some(entry);
+                }
+                Ok(i) => i,
+                Err(_) if node.leaf => return None,
+                Err(i) => {
+                    node = self.prepare_child(&mut node, i);
+                    continue;
+                }
+            };
+
+            // The key is in an internal node, replace it with its predecessor or successor, or
+            // merge the children around it if neither of them can lose an entry.
+            let left = self.load_node(node.children[index]);
+            if left.keys.len() >= B {
+                let (key, entry) = self.pop_last(left);
+                node.keys[index] = key;
+                let removed = std::mem::replace(&mut node.entries[index], entry);
+                self.save_node(&node);
+                return Some(removed);
+            }
+
+            let right = self.load_node(node.children[index + 1]);
+            if right.keys.len() >= B {
+                let (key, entry) = self.pop_first(right);
+                node.keys[index] = key;
+                let removed = std::mem::replace(&mut node.entries[index], entry);
+                self.save_node(&node);
+                return Some(removed);
+            }
+
+            node = self.merge(&mut node, index, left, right);
+        }
+    }
+
+    /// Remove the last entry from the subtree of a node with at least `B` entries.
+    fn pop_last(&mut self, mut node: Node<K>) -> (K, Entry) {
+        while !node.leaf {
+            let index = node.keys.len();
+            node = self.prepare_child(&mut node, index);
+        }
+
+        let key = node.keys.pop().unwrap();
+        let entry = node.entries.pop().unwrap();
+        self.save_node(&node);
+        (key, entry)
+    }
+
+    /// Remove the first entry from the subtree of a node with at least `B` entries.
+    fn pop_first(&mut self, mut node: Node<K>) -> (K, Entry) {
+        while !node.leaf {
+            node = self.prepare_child(&mut node, 0);
+        }
+
+        let key = node.keys.remove(0);
+        let entry = node.entries.remove(0);
+        self.save_node(&node);
+        (key, entry)
+    }
+
+    /// Make sure the child at the given index has at least `B` entries before descending into it,
+    /// by moving an entry from one of its siblings or merging it with a sibling.
+    fn prepare_child(&mut self, parent: &mut Node<K>, index: usize) -> Node<K> {
+        let mut child = self.load_node(parent.children[index]);
+        if child.keys.len() >= B {
+            return child;
+        }
+
+        let left = if index > 0 {
+            let mut left = self.load_node(parent.children[index - 1]);
+
+            if left.keys.len() >= B {
+                let key = std::mem::replace(&mut parent.keys[index - 1], left.keys.pop().unwrap());
+                let entry =
+                    std::mem::replace(&mut parent.entries[index - 1], left.entries.pop().unwrap());
+                child.keys.insert(0, key);
+                child.entries.insert(0, entry);
+                if !left.leaf {
+                    child.children.insert(0, left.children.pop().unwrap());
+                }
+
+                self.save_node(&left);
+                self.save_node(&child);
+                self.save_node(parent);
+                return child;
+            }
+
+            Some(left)
+        } else {
+            None
+        };
+
+        if index < parent.keys.len() {
+            let mut right = self.load_node(parent.children[index + 1]);
+
+            if right.keys.len() >= B {
+                let key = std::mem::replace(&mut parent.keys[index], right.keys.remove(0));
+                let entry = std::mem::replace(&mut parent.entries[index], right.entries.remove(0));
+                child.keys.push(key);
+                child.entries.push(entry);
+                if !right.leaf {
+                    child.children.push(right.children.remove(0));
+                }
+
+                self.save_node(&right);
+                self.save_node(&child);
+                self.save_node(parent);
+                return child;
+            }
+
+            return self.merge(parent, index, child, right);
+        }
+
+        self.merge(parent, index - 1, left.unwrap(), child)
+    }
+
+    /// Merge the children at `index` and `index + 1` along with the entry between them.
+    fn merge(
+        &mut self,
+        parent: &mut Node<K>,
+        index: usize,
+        mut left: Node<K>,
+        right: Node<K>,
+    ) -> Node<K> {
+        left.keys.push(parent.keys.remove(index));
+        left.entries.push(parent.entries.remove(index));
+        parent.children.remove(index + 1);
+
+        left.keys.extend(right.keys);
+        left.entries.extend(right.entries);
+        left.children.extend(right.children);
+
+        self.free_node(right.addr);
+        self.save_node(&left);
+        self.save_node(parent);
+        left
+    }
+}
+
+/// An iterator over the entries of a [`StableBTreeMap`].
+pub struct MapIter<'a, K, V, M: Memory> {
+    map: &'a StableBTreeMap<K, V, M>,
+    // The path to the next entry, with the index of the next entry of each node.
+    stack: Vec<(Node<K>, usize)>,
+    end: Bound<K>,
+}
+
+impl<'a, K, V, M> Iterator for MapIter<'a, K, V, M>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+{
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((node, index)) = self.stack.pop() {
+            if index >= node.keys.len() {
+                continue;
+            }
+
+            let key = node.keys[index].clone();
+            let in_range = match &self.end {
+                Bound::Included(end) => key <= *end,
+                Bound::Excluded(end) => key < *end,
+                Bound::Unbounded => true,
+            };
+
+            if !in_range {
+                self.stack.clear();
+                return None;
+            }
+
+            let value = node.entries[index].value;
+            let mut child = if node.leaf {
+                0
+            } else {
+                node.children[index + 1]
+            };
+            self.stack.push((node, index + 1));
+
+            // The entries of the next child come before the next entry of this node.
+            while child != 0 {
+                let node = self.map.load_node(child);
+                child = if node.leaf { 0 } else { node.children[0] };
+                self.stack.push((node, 0));
+            }
+
+            return Some((key, V::from_bytes(self.map.read_blob(value))));
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stable::VectorMemory;
+    use std::collections::BTreeMap;
+
+    type Map = StableBTreeMap<u64, Vec<u8>, VectorMemory>;
+
+    /// A linear congruential generator, so the operations are the same on every run.
+    fn next(seed: &mut u64) -> u64 {
+        *seed = seed
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(1_442_695_040_888_963_407);
+        *seed >> 33
+    }
+
+    /// Check the entries and the structure of the tree: the keys of each node are sorted and
+    /// between the keys of its parent, each node but the root has at least `B - 1` entries, and
+    /// all of the leaves are at the same depth, which is returned.
+    fn check(map: &Map, model: &BTreeMap<u64, Vec<u8>>) -> usize {
+        assert_eq!(map.len(), model.len() as u64);
+        assert_eq!(
+            map.iter().collect::<Vec<_>>(),
+            model.clone().into_iter().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            map.first_key_value(),
+            model.iter().next().map(|(k, v)| (*k, v.clone()))
+        );
+        assert_eq!(
+            map.last_key_value(),
+            model.iter().next_back().map(|(k, v)| (*k, v.clone()))
+        );
+
+        fn check_node(
+            map: &Map,
+            addr: u64,
+            bounds: (Option<u64>, Option<u64>),
+            root: bool,
+        ) -> usize {
+            let node = map.load_node(addr);
+            assert!(node.keys.len() <= CAPACITY);
+            assert!(root || node.keys.len() >= B - 1);
+            assert!(node.keys.windows(2).all(|pair| pair[0] < pair[1]));
+            if let Some(low) = bounds.0 {
+                assert!(node.keys[0] > low);
+            }
+            if let Some(high) = bounds.1 {
+                assert!(*node.keys.last().unwrap() < high);
+            }
+
+            if node.leaf {
+                return 1;
+            }
+
+            assert_eq!(node.children.len(), node.keys.len() + 1);
+            let depths = (0..node.children.len())
+                .map(|i| {
+                    let low = if i == 0 {
+                        bounds.0
+                    } else {
+                        Some(node.keys[i - 1])
+                    };
+                    let high = node.keys.get(i).copied().or(bounds.1);
+                    check_node(map, node.children[i], (low, high), false)
+                })
+                .collect::<Vec<_>>();
+            assert!(depths.iter().all(|depth| *depth == depths[0]));
+            depths[0] + 1
+        }
+
+        if map.root == 0 {
+            assert!(model.is_empty());
+            return 0;
+        }
+
+        check_node(map, map.root, (None, None), true)
+    }
+
+    #[test]
+    fn model() {
+        let memory = VectorMemory::default();
+        let mut map = Map::init(memory.clone());
+        let mut model = BTreeMap::new();
+        let mut seed = 42;
+
+        for i in 0..5_000 {
+            let key = next(&mut seed) % 1_000;
+            match next(&mut seed) % 3 {
+                0 => assert_eq!(map.remove(&key), model.remove(&key)),
+                _ => {
+                    let value = vec![i as u8; (next(&mut seed) % 100) as usize];
+                    assert_eq!(map.insert(key, value.clone()), model.insert(key, value));
+                }
+            }
+
+            assert_eq!(map.get(&key), model.get(&key).cloned());
+            assert_eq!(map.contains_key(&key), model.contains_key(&key));
+        }
+
+        assert!(check(&map, &model) >= 3);
+
+        // Remove all of the entries, which merges the nodes until the tree is empty.
+        let keys = model.keys().copied().collect::<Vec<_>>();
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(map.remove(key), model.remove(key));
+            if i % 100 == 0 {
+                check(&map, &model);
+            }
+        }
+
+        assert_eq!(check(&map, &model), 0);
+        assert_eq!(map.remove(&0), None);
+    }
+
+    #[test]
+    fn range() {
+        let mut map = Map::new(VectorMemory::default());
+        let mut model = BTreeMap::new();
+
+        for key in (0..600).step_by(3) {
+            map.insert(key, key.to_be_bytes().to_vec());
+            model.insert(key, key.to_be_bytes().to_vec());
+        }
+
+        let collect = |iter: MapIter<'_, u64, Vec<u8>, VectorMemory>| iter.collect::<Vec<_>>();
+        let expected = |iter: std::collections::btree_map::Range<'_, u64, Vec<u8>>| {
+            iter.map(|(k, v)| (*k, v.clone())).collect::<Vec<_>>()
+        };
+
+        for start in [0, 1, 3, 100, 299, 300, 597, 598, 700] {
+            for end in [0, 2, 3, 150, 301, 597, 599, 1_000] {
+                assert_eq!(collect(map.range(start..)), expected(model.range(start..)));
+                assert_eq!(collect(map.range(..end)), expected(model.range(..end)));
+
+                // The range of the model panics if the start is after the end.
+                if start > end {
+                    assert!(map.range(start..=end).next().is_none());
+                    continue;
+                }
+
+                assert_eq!(
+                    collect(map.range(start..=end)),
+                    expected(model.range(start..=end))
+                );
+                assert_eq!(
+                    collect(map.range(start..end)),
+                    expected(model.range(start..end))
+                );
+                assert_eq!(
+                    collect(map.range((Bound::Excluded(start), Bound::Included(end)))),
+                    expected(model.range((Bound::Excluded(start), Bound::Included(end))))
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn reload() {
+        let memory = VectorMemory::default();
+        let mut map = Map::init(memory.clone());
+        let mut model = BTreeMap::new();
+        let mut seed = 7;
+
+        for _ in 0..2_000 {
+            let key = next(&mut seed) % 500;
+            let value = vec![key as u8; (next(&mut seed) % 300) as usize];
+            map.insert(key, value.clone());
+            model.insert(key, value);
+        }
+
+        for key in (0..500).step_by(2) {
+            map.remove(&key);
+            model.remove(&key);
+        }
+
+        drop(map);
+        let mut map = Map::init(memory.clone());
+        check(&map, &model);
+
+        // The map keeps working from the state it was loaded with.
+        for key in 500..700 {
+            map.insert(key, vec![1; 10]);
+            model.insert(key, vec![1; 10]);
+        }
+
+        check(&Map::load(memory), &model);
+    }
+
+    #[test]
+    fn reuse_freed_space() {
+        let memory = VectorMemory::default();
+        let mut map = Map::new(memory.clone());
+
+        for key in 0..1_000 {
+            map.insert(key, vec![0; (key % 200) as usize]);
+        }
+        let bump = map.allocator.bump;
+        let size = memory.size();
+
+        for key in 0..1_000 {
+            map.remove(&key);
+        }
+        for key in 0..1_000 {
+            map.insert(key, vec![0; (key % 200) as usize]);
+        }
+
+        assert_eq!(map.allocator.bump, bump);
+        assert_eq!(memory.size(), size);
+
+        map.clear();
+        assert!(map.is_empty());
+        assert_eq!(map.get(&1), None);
+        assert!(Map::load(memory).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "The memory does not contain a StableBTreeMap.")]
+    fn load_other_memory() {
+        let memory = VectorMemory::default();
+        memory.grow(1).unwrap();
+        Map::init(memory);
+    }
+}
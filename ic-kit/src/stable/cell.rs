@@ -0,0 +1,187 @@
+use super::memory::{ensure_size, read_u64, write_u64, DefaultMemory, Memory};
+use super::storable::Storable;
+use ic_kit_sys::types::StableMemoryError;
+
+/// The magic bytes at the beginning of the memory of a cell.
+const MAGIC: &[u8; 4] = b"KCEL";
+
+// The layout of the memory: the magic bytes, followed by the length of the value as a u64 and the
+// bytes of the value.
+const LEN_OFFSET: u64 = 8;
+const VALUE_OFFSET: u64 = 16;
+
+/// A single value stored in the stable memory, such as the configuration of the canister, which
+/// survives the upgrades without being a part of the `pre_upgrade` snapshot.
+///
+/// The value is also kept on the heap, so reading it is free. Replacing it either stores the new
+/// value completely or, if the memory can not grow, keeps the old value intact.
+///
+/// ```ignore
+/// use ic_kit::stable::{DefaultMemory, StableCell};
+///
+/// let mut fee = StableCell::<u64>::init(DefaultMemory, 10_000);
+/// fee.set(20_000).unwrap();
+/// ```
+pub struct StableCell<T, M: Memory = DefaultMemory> {
+    memory: M,
+    value: T,
+}
+
+impl<T: Storable, M: Memory> StableCell<T, M> {
+    /// Load the value stored in the memory, or store the default value if the memory is empty.
+    ///
+    /// # Panics
+    ///
+    /// If the memory is not empty and does not contain a cell, or if the memory can not grow to
+    /// store the default value.
+    pub fn init(memory: M, default: T) -> Self {
+        if memory.size() == 0 {
+            return Self::new(memory, default);
+        }
+
+        Self::load(memory)
+    }
+
+    /// Create a new cell with the given value, overwriting the content of the memory.
+    ///
+    /// # Panics
+    ///
+    /// If the memory can not grow to store the value.
+    pub fn new(memory: M, value: T) -> Self {
+        write_value(&memory, &value.to_bytes())
+            .expect("Could not grow the stable memory for the cell.");
+        memory.write(0, MAGIC);
+
+        Self { memory, value }
+    }
+
+    /// Load the value stored in the memory.
+    ///
+    /// # Panics
+    ///
+    /// If the memory does not contain a cell.
+    pub fn load(memory: M) -> Self {
+        let mut magic = [0; 4];
+        memory.read(0, &mut magic);
+        assert_eq!(&magic, MAGIC, "The memory does not contain a StableCell.");
+
+        let mut bytes = vec![0; read_u64(&memory, LEN_OFFSET) as usize];
+        memory.read(VALUE_OFFSET, &mut bytes);
+
+        Self {
+            value: T::from_bytes(bytes),
+            memory,
+        }
+    }
+
+    /// Return the current value.
+    pub fn get(&self) -> &T {
+        &self.value
+    }
+
+    /// Replace the value and return the previous one, returns an error without changing the
+    /// value if the memory can not grow to store it.
+    pub fn set(&mut self, value: T) -> Result<T, StableMemoryError> {
+        write_value(&self.memory, &value.to_bytes())?;
+        Ok(std::mem::replace(&mut self.value, value))
+    }
+
+    /// Return the memory of the cell.
+    pub fn into_memory(self) -> M {
+        self.memory
+    }
+}
+
+/// Write the value to the memory, the memory is grown before anything is written so a failure
+/// leaves the previous value in place.
+fn write_value<M: Memory>(memory: &M, bytes: &[u8]) -> Result<(), StableMemoryError> {
+    ensure_size(memory, VALUE_OFFSET + bytes.len() as u64)?;
+    memory.write(VALUE_OFFSET, bytes);
+    write_u64(memory, LEN_OFFSET, bytes.len() as u64);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stable::{VectorMemory, WASM_PAGE_SIZE};
+
+    /// A memory that can not grow past the given number of pages.
+    struct BoundedMemory(VectorMemory, u64);
+
+    impl Memory for BoundedMemory {
+        fn size(&self) -> u64 {
+            self.0.size()
+        }
+
+        fn grow(&self, pages: u64) -> Result<u64, StableMemoryError> {
+            if self.0.size() + pages > self.1 {
+                return Err(StableMemoryError::OutOfMemory);
+            }
+
+            self.0.grow(pages)
+        }
+
+        fn read(&self, offset: u64, buf: &mut [u8]) {
+            self.0.read(offset, buf)
+        }
+
+        fn write(&self, offset: u64, buf: &[u8]) {
+            self.0.write(offset, buf)
+        }
+    }
+
+    #[test]
+    fn init_and_set() {
+        let memory = VectorMemory::default();
+        let mut cell = StableCell::init(memory.clone(), 10_000u64);
+        assert_eq!(*cell.get(), 10_000);
+        assert_eq!(cell.set(20_000), Ok(10_000));
+        drop(cell);
+
+        // The default value is only used for an empty memory.
+        let cell = StableCell::init(memory, 0u64);
+        assert_eq!(*cell.get(), 20_000);
+    }
+
+    #[test]
+    fn reload_after_grow() {
+        let memory = VectorMemory::default();
+        let mut cell = StableCell::init(memory.clone(), "short".to_string());
+        assert_eq!(memory.size(), 1);
+
+        let long = "x".repeat(3 * WASM_PAGE_SIZE as usize);
+        cell.set(long.clone()).unwrap();
+        assert_eq!(memory.size(), 4);
+        drop(cell);
+
+        let mut cell = StableCell::<String, _>::load(memory.clone());
+        assert_eq!(cell.get(), &long);
+
+        // Storing a shorter value does not leave the end of the previous one behind.
+        cell.set("shorter".to_string()).unwrap();
+        let cell = StableCell::<String, _>::load(memory);
+        assert_eq!(cell.get(), "shorter");
+    }
+
+    #[test]
+    fn set_without_memory() {
+        let memory = VectorMemory::default();
+        let mut cell = StableCell::init(BoundedMemory(memory.clone(), 1), vec![1u8; 100]);
+
+        let result = cell.set(vec![2; WASM_PAGE_SIZE as usize]);
+        assert_eq!(result, Err(StableMemoryError::OutOfMemory));
+        assert_eq!(cell.get(), &vec![1; 100]);
+
+        let cell = StableCell::<Vec<u8>, _>::load(memory);
+        assert_eq!(cell.get(), &vec![1; 100]);
+    }
+
+    #[test]
+    #[should_panic(expected = "The memory does not contain a StableCell.")]
+    fn load_other_memory() {
+        let memory = VectorMemory::default();
+        memory.grow(1).unwrap();
+        StableCell::<u64, _>::init(memory, 0);
+    }
+}
@@ -0,0 +1,262 @@
+use std::io;
+
+/// The compression algorithm of the data written to the stable memory, each algorithm is only
+/// available if the corresponding feature of the crate is enabled:
+///
+/// - `Deflate` requires the `compression-deflate` feature.
+/// - `Zstd` requires the `compression-zstd` feature, and a C compiler for the wasm target.
+///
+/// Compression trades instructions for stable memory, which is worth it for large states.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Compression {
+    None,
+    Deflate,
+    Zstd,
+}
+
+impl Compression {
+    /// The byte that identifies the algorithm in a header.
+    pub fn tag(&self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Deflate => 1,
+            Compression::Zstd => 2,
+        }
+    }
+
+    /// Return the algorithm identified by the byte, if it's known.
+    pub fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Deflate),
+            2 => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+}
+
+/// The error returned when the feature of an algorithm is not enabled.
+#[cfg(not(all(feature = "compression-deflate", feature = "compression-zstd")))]
+fn unsupported(feature: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("The `{}` feature of ic-kit is not enabled.", feature),
+    )
+}
+
+enum Encoder<W: io::Write> {
+    None(W),
+    #[cfg(feature = "compression-deflate")]
+    Deflate(flate2::write::DeflateEncoder<W>),
+    #[cfg(feature = "compression-zstd")]
+    Zstd(zstd::stream::write::Encoder<'static, W>),
+}
+
+/// A writer which compresses the data before passing it to the inner writer, such as a
+/// [`super::StableWriter64`]. The writer must be finished with [`CompressionWriter::finish`] to
+/// write the end of the compressed stream.
+pub struct CompressionWriter<W: io::Write> {
+    encoder: Encoder<W>,
+}
+
+impl<W: io::Write> CompressionWriter<W> {
+    /// Create a writer with the given algorithm, returns an error if its feature is not enabled.
+    pub fn new(inner: W, compression: Compression) -> io::Result<Self> {
+        let encoder = match compression {
+            Compression::None => Encoder::None(inner),
+            #[cfg(feature = "compression-deflate")]
+            Compression::Deflate => Encoder::Deflate(flate2::write::DeflateEncoder::new(
+                inner,
+                flate2::Compression::default(),
+            )),
+            #[cfg(not(feature = "compression-deflate"))]
+            Compression::Deflate => return Err(unsupported("compression-deflate")),
+            #[cfg(feature = "compression-zstd")]
+            Compression::Zstd => Encoder::Zstd(zstd::stream::write::Encoder::new(inner, 0)?),
+            #[cfg(not(feature = "compression-zstd"))]
+            Compression::Zstd => return Err(unsupported("compression-zstd")),
+        };
+
+        Ok(Self { encoder })
+    }
+
+    /// Write the end of the compressed stream, and return the inner writer.
+    pub fn finish(self) -> io::Result<W> {
+        match self.encoder {
+            Encoder::None(inner) => Ok(inner),
+            #[cfg(feature = "compression-deflate")]
+            Encoder::Deflate(encoder) => encoder.finish(),
+            #[cfg(feature = "compression-zstd")]
+            Encoder::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: io::Write> io::Write for CompressionWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.encoder {
+            Encoder::None(inner) => inner.write(buf),
+            #[cfg(feature = "compression-deflate")]
+            Encoder::Deflate(encoder) => encoder.write(buf),
+            #[cfg(feature = "compression-zstd")]
+            Encoder::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.encoder {
+            Encoder::None(inner) => inner.flush(),
+            #[cfg(feature = "compression-deflate")]
+            Encoder::Deflate(encoder) => encoder.flush(),
+            #[cfg(feature = "compression-zstd")]
+            Encoder::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+enum Decoder<R: io::Read> {
+    None(R),
+    #[cfg(feature = "compression-deflate")]
+    Deflate(flate2::read::DeflateDecoder<R>),
+    #[cfg(feature = "compression-zstd")]
+    Zstd(zstd::stream::read::Decoder<'static, io::BufReader<R>>),
+}
+
+/// A reader which decompresses the data read from the inner reader, such as a
+/// [`super::StableReader64`].
+pub struct DecompressionReader<R: io::Read> {
+    decoder: Decoder<R>,
+}
+
+impl<R: io::Read> DecompressionReader<R> {
+    /// Create a reader with the given algorithm, returns an error if its feature is not enabled.
+    pub fn new(inner: R, compression: Compression) -> io::Result<Self> {
+        let decoder = match compression {
+            Compression::None => Decoder::None(inner),
+            #[cfg(feature = "compression-deflate")]
+            Compression::Deflate => Decoder::Deflate(flate2::read::DeflateDecoder::new(inner)),
+            #[cfg(not(feature = "compression-deflate"))]
+            Compression::Deflate => return Err(unsupported("compression-deflate")),
+            #[cfg(feature = "compression-zstd")]
+            Compression::Zstd => Decoder::Zstd(zstd::stream::read::Decoder::new(inner)?),
+            #[cfg(not(feature = "compression-zstd"))]
+            Compression::Zstd => return Err(unsupported("compression-zstd")),
+        };
+
+        Ok(Self { decoder })
+    }
+}
+
+impl<R: io::Read> io::Read for DecompressionReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.decoder {
+            Decoder::None(inner) => inner.read(buf),
+            #[cfg(feature = "compression-deflate")]
+            Decoder::Deflate(decoder) => decoder.read(buf),
+            #[cfg(feature = "compression-zstd")]
+            Decoder::Zstd(decoder) => decoder.read(buf),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stable::{
+        stable_restore_versioned_from, stable_save_compressed_to, Memory, VectorMemory,
+    };
+
+    /// A state that compresses well, larger than a page once encoded.
+    fn state() -> Vec<u64> {
+        (0..50_000).map(|i| i % 16).collect()
+    }
+
+    /// Save the state with the algorithm, check the header and restore it, returns the length of
+    /// the stored data.
+    fn round_trip(compression: Compression) -> u64 {
+        let memory = VectorMemory::default();
+        stable_save_compressed_to(&memory, 3, &state(), compression).unwrap();
+
+        let mut header = [0u8; 24];
+        memory.read(0, &mut header);
+        assert_eq!(&header[0..4], b"KITC");
+        assert_eq!(&header[4..8], &3u32.to_le_bytes());
+        assert_eq!(header[16], compression.tag());
+        assert_eq!(&header[17..24], &[0; 7]);
+
+        let mut len = [0u8; 8];
+        len.copy_from_slice(&header[8..16]);
+        let len = u64::from_le_bytes(len);
+        assert!(24 + len <= memory.borrow().len() as u64);
+
+        assert_eq!(
+            stable_restore_versioned_from::<_, Vec<u64>>(&memory, 3),
+            Ok(Some(state()))
+        );
+        assert_eq!(
+            stable_restore_versioned_from::<_, Vec<u64>>(&memory, 4).err(),
+            Some("Expected the stable snapshot version to be 4 but found 3.".into())
+        );
+
+        len
+    }
+
+    #[test]
+    fn tags() {
+        for compression in [Compression::None, Compression::Deflate, Compression::Zstd] {
+            assert_eq!(Compression::from_tag(compression.tag()), Some(compression));
+        }
+
+        assert_eq!(Compression::from_tag(3), None);
+    }
+
+    #[test]
+    fn none() {
+        let len = round_trip(Compression::None);
+        assert_eq!(len, candid::encode_one(state()).unwrap().len() as u64);
+    }
+
+    #[test]
+    #[cfg(feature = "compression-deflate")]
+    fn deflate() {
+        let len = round_trip(Compression::Deflate);
+        assert!(len < candid::encode_one(state()).unwrap().len() as u64 / 10);
+    }
+
+    #[test]
+    #[cfg(feature = "compression-zstd")]
+    fn zstd() {
+        let len = round_trip(Compression::Zstd);
+        assert!(len < candid::encode_one(state()).unwrap().len() as u64 / 10);
+    }
+
+    #[test]
+    #[cfg(not(feature = "compression-deflate"))]
+    fn deflate_disabled() {
+        let error =
+            stable_save_compressed_to(&VectorMemory::default(), 1, &state(), Compression::Deflate)
+                .unwrap_err();
+        assert!(error.contains("The `compression-deflate` feature of ic-kit is not enabled."));
+    }
+
+    #[test]
+    #[cfg(not(feature = "compression-zstd"))]
+    fn zstd_disabled() {
+        let error =
+            stable_save_compressed_to(&VectorMemory::default(), 1, &state(), Compression::Zstd)
+                .unwrap_err();
+        assert!(error.contains("The `compression-zstd` feature of ic-kit is not enabled."));
+    }
+
+    #[test]
+    fn bad_tag() {
+        let memory = VectorMemory::default();
+        stable_save_compressed_to(&memory, 1, &state(), Compression::None).unwrap();
+        memory.write(16, &[9]);
+
+        assert_eq!(
+            stable_restore_versioned_from::<_, Vec<u64>>(&memory, 1).err(),
+            Some("Unknown compression algorithm 9 in the snapshot.".into())
+        );
+    }
+}
@@ -0,0 +1,513 @@
+use super::memory::{DefaultMemory, Memory, WASM_PAGE_SIZE};
+use crate::ic::StableSize;
+use ic_kit_sys::types::StableMemoryError;
+use std::io;
+
+/// The default size of the buffer of the stable readers and writers, which is a WebAssembly page.
+const DEFAULT_BUFFER_CAPACITY: usize = WASM_PAGE_SIZE as usize;
+
+/// Compute the new offset for a seek, given the current offset and the end of the memory.
+fn seek_offset(pos: io::SeekFrom, current: u64, end: u64) -> io::Result<u64> {
+    let (base, delta) = match pos {
+        io::SeekFrom::Start(offset) => return Ok(offset),
+        io::SeekFrom::Current(delta) => (current, delta),
+        io::SeekFrom::End(delta) => (end, delta),
+    };
+
+    if delta >= 0 {
+        Ok(base + delta as u64)
+    } else {
+        base.checked_sub(delta.unsigned_abs()).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid seek to a negative offset.",
+            )
+        })
+    }
+}
+
+/// The error returned by the writers when the memory can not be grown.
+fn out_of_memory() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "Out Of Memory")
+}
+
+/// A buffered writer to a memory with 64-bit offsets.
+///
+/// The memory is grown as the data is written, so a write only fails if the memory can not be
+/// grown, while the data itself is buffered and written to the memory in large chunks once the
+/// buffer is full, the writer is flushed or seeks, or when it is dropped.
+pub struct StableWriter64<M: Memory = DefaultMemory> {
+    memory: M,
+    /// The offset of the next write, including the buffered data.
+    offset: u64,
+    /// The size of the memory in bytes.
+    capacity: u64,
+    /// The data that is not written to the memory yet, which starts at `offset - buffer.len()`.
+    buffer: Vec<u8>,
+    buffer_capacity: usize,
+}
+
+impl Default for StableWriter64<DefaultMemory> {
+    fn default() -> Self {
+        Self::new(DefaultMemory, 0)
+    }
+}
+
+impl<M: Memory> StableWriter64<M> {
+    /// Create a new writer that writes to the memory from the given offset forward.
+    pub fn new(memory: M, offset: u64) -> Self {
+        Self::with_capacity(memory, offset, DEFAULT_BUFFER_CAPACITY)
+    }
+
+    /// Create a new writer that buffers up to `buffer_capacity` bytes before writing them.
+    pub fn with_capacity(memory: M, offset: u64, buffer_capacity: usize) -> Self {
+        let capacity = memory.size() * WASM_PAGE_SIZE;
+
+        Self {
+            memory,
+            offset,
+            capacity,
+            buffer: Vec::with_capacity(buffer_capacity),
+            buffer_capacity,
+        }
+    }
+
+    /// Returns the current offset of the writer.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Attempts to grow the memory by adding new pages.
+    pub fn grow(&mut self, added_pages: u64) -> Result<(), StableMemoryError> {
+        let old_page_count = self.memory.grow(added_pages)?;
+        self.capacity = (old_page_count + added_pages) * WASM_PAGE_SIZE;
+        Ok(())
+    }
+
+    /// Writes a byte slice to the buffer.
+    ///
+    /// The only condition where this will error out is if it cannot grow the memory.
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize, StableMemoryError> {
+        self.reserve(buf.len() as u64)?;
+        self.write_reserved(buf);
+        Ok(buf.len())
+    }
+
+    /// Writes all of the slices one after another, the memory is grown once for all of them and
+    /// unless they are larger than a page, the slices are gathered and written with a single call
+    /// even if they do not fit in the buffer.
+    pub fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> Result<usize, StableMemoryError> {
+        let len = bufs.iter().map(|buf| buf.len()).sum::<usize>();
+        self.reserve(len as u64)?;
+
+        if len > self.buffer_capacity.max(DEFAULT_BUFFER_CAPACITY) {
+            for buf in bufs {
+                self.write_reserved(buf);
+            }
+
+            return Ok(len);
+        }
+
+        if self.buffer.len() + len > self.buffer_capacity {
+            self.flush_buffer();
+        }
+
+        for buf in bufs {
+            self.buffer.extend_from_slice(buf);
+        }
+
+        self.offset += len as u64;
+
+        if self.buffer.len() >= self.buffer_capacity {
+            self.flush_buffer();
+        }
+
+        Ok(len)
+    }
+
+    /// Grow the memory so that `len` more bytes can be written after the offset.
+    fn reserve(&mut self, len: u64) -> Result<(), StableMemoryError> {
+        let end = self.offset + len;
+        if end > self.capacity {
+            self.grow((end - self.capacity - 1) / WASM_PAGE_SIZE + 1)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the data, the memory must already be large enough.
+    fn write_reserved(&mut self, buf: &[u8]) {
+        if self.buffer.len() + buf.len() > self.buffer_capacity {
+            self.flush_buffer();
+        }
+
+        // Large writes skip the buffer, there is nothing to gain from copying them.
+        if buf.len() >= self.buffer_capacity {
+            self.memory.write(self.offset, buf);
+        } else {
+            self.buffer.extend_from_slice(buf);
+        }
+
+        self.offset += buf.len() as u64;
+    }
+
+    /// Write the buffered data to the memory.
+    fn flush_buffer(&mut self) {
+        if !self.buffer.is_empty() {
+            let start = self.offset - self.buffer.len() as u64;
+            self.memory.write(start, &self.buffer);
+            self.buffer.clear();
+        }
+    }
+}
+
+impl<M: Memory> io::Write for StableWriter64<M> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        self.write(buf).map_err(|_| out_of_memory())
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> Result<usize, io::Error> {
+        self.write_vectored(bufs).map_err(|_| out_of_memory())
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        self.flush_buffer();
+        Ok(())
+    }
+}
+
+impl<M: Memory> io::Seek for StableWriter64<M> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.flush_buffer();
+        self.offset = seek_offset(pos, self.offset, self.capacity)?;
+        Ok(self.offset)
+    }
+}
+
+impl<M: Memory> Drop for StableWriter64<M> {
+    fn drop(&mut self) {
+        self.flush_buffer();
+    }
+}
+
+/// A [`StableWriter64`] with the capacity of its buffer chosen with
+/// [`BufferedStableWriter::with_capacity`], a larger buffer means fewer calls to the memory, which
+/// keeps the instructions used to serialize a large state during an upgrade down.
+///
+/// ```ignore
+/// // Write the snapshot to the stable memory in chunks of 1MiB.
+/// let mut writer = BufferedStableWriter::with_capacity(DefaultMemory, 0, 1 << 20);
+/// ```
+pub type BufferedStableWriter<M = DefaultMemory> = StableWriter64<M>;
+
+/// A buffered reader from a memory with 64-bit offsets.
+///
+/// The data is read from the memory in large chunks, and the reader stops at the end of the
+/// memory instead of reading out of its bounds.
+pub struct StableReader64<M: Memory = DefaultMemory> {
+    memory: M,
+    /// The offset of the next byte returned by the reader.
+    offset: u64,
+    /// The data read from the memory, `buffer[position..]` starts at `offset`.
+    buffer: Vec<u8>,
+    position: usize,
+    buffer_capacity: usize,
+}
+
+impl Default for StableReader64<DefaultMemory> {
+    fn default() -> Self {
+        Self::new(DefaultMemory, 0)
+    }
+}
+
+impl<M: Memory> StableReader64<M> {
+    /// Create a new reader that reads from the memory from the given offset forward.
+    pub fn new(memory: M, offset: u64) -> Self {
+        Self::with_capacity(memory, offset, DEFAULT_BUFFER_CAPACITY)
+    }
+
+    /// Create a new reader that reads up to `buffer_capacity` bytes from the memory at once.
+    pub fn with_capacity(memory: M, offset: u64, buffer_capacity: usize) -> Self {
+        Self {
+            memory,
+            offset,
+            buffer: Vec::new(),
+            position: 0,
+            buffer_capacity,
+        }
+    }
+
+    /// Returns the current offset of the reader.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Reads data from the memory into the buffer, returns the number of bytes read which is only
+    /// less than the length of the buffer at the end of the memory.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, StableMemoryError> {
+        // Large reads skip the buffer, there is nothing to gain from copying them.
+        if self.position == self.buffer.len() && buf.len() >= self.buffer_capacity {
+            let len = (buf.len() as u64).min(self.remaining()) as usize;
+            self.memory.read(self.offset, &mut buf[..len]);
+            self.offset += len as u64;
+            return Ok(len);
+        }
+
+        let available = self.fill();
+        let len = buf.len().min(available.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.consume(len);
+        Ok(len)
+    }
+
+    /// The number of bytes between the offset and the end of the memory.
+    fn remaining(&self) -> u64 {
+        (self.memory.size() * WASM_PAGE_SIZE).saturating_sub(self.offset)
+    }
+
+    /// Return the buffered data, reading the next chunk from the memory if the buffer is empty.
+    fn fill(&mut self) -> &[u8] {
+        if self.position == self.buffer.len() {
+            let len = (self.buffer_capacity as u64).min(self.remaining()) as usize;
+            self.buffer.resize(len, 0);
+            self.memory.read(self.offset, &mut self.buffer);
+            self.position = 0;
+        }
+
+        &self.buffer[self.position..]
+    }
+
+    fn consume(&mut self, amt: usize) {
+        let amt = amt.min(self.buffer.len() - self.position);
+        self.position += amt;
+        self.offset += amt as u64;
+    }
+}
+
+impl<M: Memory> io::Read for StableReader64<M> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        self.read(buf)
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "Unexpected error."))
+    }
+}
+
+impl<M: Memory> io::BufRead for StableReader64<M> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        Ok(self.fill())
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.consume(amt)
+    }
+}
+
+impl<M: Memory> io::Seek for StableReader64<M> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let end = self.memory.size() * WASM_PAGE_SIZE;
+        self.offset = seek_offset(pos, self.offset, end)?;
+        self.buffer.clear();
+        self.position = 0;
+        Ok(self.offset)
+    }
+}
+
+/// A writer to the stable memory.
+///
+/// Will attempt to grow the memory as it writes, and keep offsets and total capacity. The data is
+/// buffered and written once the writer is flushed or dropped, see [`StableWriter64`].
+#[derive(Default)]
+pub struct StableWriter(StableWriter64<DefaultMemory>);
+
+impl StableWriter {
+    /// Create a new stable writer that writes from the given offset forward.
+    pub fn new(offset: StableSize) -> Self {
+        StableWriter(StableWriter64::new(DefaultMemory, offset as u64))
+    }
+
+    /// Returns the current offset of the writer.
+    pub fn offset(&self) -> StableSize {
+        self.0.offset() as StableSize
+    }
+
+    /// Attempts to grow the memory by adding new pages.
+    pub fn grow(&mut self, added_pages: StableSize) -> Result<(), StableMemoryError> {
+        self.0.grow(added_pages as u64)
+    }
+
+    /// Writes a byte slice to the buffer.
+    ///
+    /// The only condition where this will error out is if it cannot grow the memory.
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize, StableMemoryError> {
+        self.0.write(buf)
+    }
+
+    /// Writes all of the slices one after another, see [`StableWriter64::write_vectored`].
+    pub fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> Result<usize, StableMemoryError> {
+        self.0.write_vectored(bufs)
+    }
+}
+
+impl io::Write for StableWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        io::Write::write(&mut self.0, buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> Result<usize, io::Error> {
+        io::Write::write_vectored(&mut self.0, bufs)
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        io::Write::flush(&mut self.0)
+    }
+}
+
+impl io::Seek for StableWriter {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        io::Seek::seek(&mut self.0, pos)
+    }
+}
+
+/// A reader to the stable memory.
+///
+/// Keeps an offset and reads off stable memory consecutively, see [`StableReader64`].
+#[derive(Default)]
+pub struct StableReader(StableReader64<DefaultMemory>);
+
+impl StableReader {
+    /// Create a new stable reader that reads from the given offset forward.
+    pub fn new(offset: StableSize) -> Self {
+        StableReader(StableReader64::new(DefaultMemory, offset as u64))
+    }
+
+    /// Returns the current offset of the reader.
+    pub fn offset(&self) -> StableSize {
+        self.0.offset() as StableSize
+    }
+
+    /// Reads data from the stable memory location specified by an offset.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, StableMemoryError> {
+        self.0.read(buf)
+    }
+}
+
+impl io::Read for StableReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        io::Read::read(&mut self.0, buf)
+    }
+}
+
+impl io::BufRead for StableReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        io::BufRead::fill_buf(&mut self.0)
+    }
+
+    fn consume(&mut self, amt: usize) {
+        io::BufRead::consume(&mut self.0, amt)
+    }
+}
+
+impl io::Seek for StableReader {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        io::Seek::seek(&mut self.0, pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stable::VectorMemory;
+    use std::io::{BufRead, Read, Seek, SeekFrom, Write};
+
+    const PAGE: u64 = WASM_PAGE_SIZE;
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn write_across_pages() {
+        let memory = VectorMemory::default();
+        let data = pattern(3 * PAGE as usize);
+
+        // Write through a small buffer in chunks that do not line up with the pages.
+        let mut writer = StableWriter64::with_capacity(&memory, 10, 1000);
+        for chunk in data.chunks(777) {
+            assert_eq!(Write::write(&mut writer, chunk).unwrap(), chunk.len());
+        }
+        assert_eq!(writer.offset(), 10 + 3 * PAGE);
+        drop(writer);
+
+        assert_eq!(memory.size(), 4);
+        assert_eq!(&memory.borrow()[10..10 + data.len()], &data[..]);
+    }
+
+    #[test]
+    fn write_vectored_across_pages() {
+        let memory = VectorMemory::default();
+        let mut writer = StableWriter64::with_capacity(&memory, PAGE - 3, 16);
+        let bufs = [io::IoSlice::new(b"abcd"), io::IoSlice::new(b"efgh")];
+        assert_eq!(writer.write_vectored(&bufs), Ok(8));
+        writer.flush().unwrap();
+
+        let big = pattern(PAGE as usize);
+        let bufs = [io::IoSlice::new(b"ij"), io::IoSlice::new(&big)];
+        assert_eq!(writer.write_vectored(&bufs), Ok(PAGE as usize + 2));
+        drop(writer);
+
+        assert_eq!(memory.size(), 3);
+        let bytes = memory.borrow();
+        let start = (PAGE - 3) as usize;
+        assert_eq!(&bytes[start..start + 10], b"abcdefghij");
+        assert_eq!(&bytes[start + 10..start + 10 + big.len()], &big[..]);
+    }
+
+    #[test]
+    fn seek_and_overwrite() {
+        let memory = VectorMemory::default();
+        let mut writer = StableWriter64::with_capacity(&memory, 0, 100);
+        writer.write_all(&pattern(2 * PAGE as usize)).unwrap();
+
+        // The buffered data is flushed before the seek, then the write crosses the page boundary.
+        assert_eq!(writer.seek(SeekFrom::Start(PAGE - 2)).unwrap(), PAGE - 2);
+        writer.write_all(b"XXXX").unwrap();
+        assert_eq!(writer.seek(SeekFrom::Current(-4)).unwrap(), PAGE - 2);
+        assert_eq!(writer.seek(SeekFrom::End(-1)).unwrap(), 2 * PAGE - 1);
+        assert!(writer.seek(SeekFrom::Current(-(2 * PAGE as i64))).is_err());
+        drop(writer);
+
+        assert_eq!(memory.size(), 2);
+        let bytes = memory.borrow();
+        assert_eq!(&bytes[(PAGE - 2) as usize..(PAGE + 2) as usize], b"XXXX");
+        assert_eq!(bytes[(PAGE + 2) as usize], ((PAGE + 2) % 251) as u8);
+    }
+
+    #[test]
+    fn read_across_pages() {
+        let memory = VectorMemory::default();
+        let data = pattern(2 * PAGE as usize);
+        StableWriter64::new(&memory, 0).write_all(&data).unwrap();
+
+        let mut reader = StableReader64::with_capacity(&memory, PAGE - 5, 8);
+        let mut buf = [0; 10];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &data[(PAGE - 5) as usize..(PAGE + 5) as usize]);
+        assert_eq!(reader.offset(), PAGE + 5);
+
+        // A large read skips the buffer and stops at the end of the memory.
+        let mut rest = Vec::new();
+        assert_eq!(reader.read_to_end(&mut rest).unwrap(), (PAGE - 5) as usize);
+        assert_eq!(&rest[..], &data[(PAGE + 5) as usize..]);
+        assert_eq!(Read::read(&mut reader, &mut buf).unwrap(), 0);
+
+        assert_eq!(reader.seek(SeekFrom::End(-3)).unwrap(), 2 * PAGE - 3);
+        assert_eq!(reader.fill_buf().unwrap(), &data[data.len() - 3..]);
+        reader.consume(1);
+        assert_eq!(
+            reader.seek(SeekFrom::Current(-(PAGE as i64))).unwrap(),
+            PAGE - 2
+        );
+        reader.read_exact(&mut buf[..4]).unwrap();
+        assert_eq!(&buf[..4], &data[(PAGE - 2) as usize..(PAGE + 2) as usize]);
+        assert!(reader.seek(SeekFrom::End(-(3 * PAGE as i64))).is_err());
+    }
+}
@@ -0,0 +1,384 @@
+use candid::Principal;
+
+/// A type which is used as the key of a stable structure, with a fixed-size encoding whose byte
+/// order matches the order of the values, so the keys of a `StableBTreeMap` are iterated in the
+/// order of the type. Use `#[derive(StorageKey)]` to implement it for a struct or an enum, which
+/// also implements [`Storable`] and [`BoundedStorable`] with this encoding:
+///
+/// ```ignore
+/// #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, StorageKey)]
+/// #[key_version(1)]
+/// #[key_layout(0x5c1b_27d9_e04a_8f36)]
+/// pub struct AllowanceKey {
+///     owner: Principal,
+///     spender: Principal,
+/// }
+///
+/// let mut allowances = StableBTreeMap::<AllowanceKey, u64, _>::init(memory);
+/// ```
+///
+/// The fields of a struct are encoded in order, and a variant of an enum is encoded as its index
+/// followed by its fields, padded to the size of the largest variant. With `#[key_version(n)]` the
+/// keys start with the version, which is checked when they are decoded. The [`LAYOUT`] of the type
+/// is a fingerprint of its encoding, it changes with the types, the order and the number of the
+/// fields and of the variants, but not with their names. With `#[key_layout(...)]` the derive
+/// fails to compile once the layout changes, so the keys written by the previous build are not
+/// silently orphaned, the new layout must be pinned along with a new version.
+///
+/// [`Storable`]: super::Storable
+/// [`BoundedStorable`]: super::BoundedStorable
+/// [`LAYOUT`]: StorageKey::LAYOUT
+pub trait StorageKey: Sized {
+    /// The size of the encoding in bytes.
+    const SIZE: usize;
+
+    /// The fingerprint of the encoding, see [`layout_hash`].
+    const LAYOUT: u64;
+
+    /// Append the encoding of the key to the buffer, which is always [`StorageKey::SIZE`] bytes.
+    fn encode_key(&self, buf: &mut Vec<u8>);
+
+    /// Decode the key from the first [`StorageKey::SIZE`] bytes.
+    fn decode_key(bytes: &[u8]) -> Self;
+}
+
+/// The maximum length of a principal in bytes.
+const PRINCIPAL_MAX_LENGTH: usize = 29;
+
+/// The offset basis of the 64 bits FNV-1a hash.
+#[doc(hidden)]
+pub const LAYOUT_SEED: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// Hash the description of a part of the layout of a key into the given hash, this is the 64 bits
+/// FNV-1a hash, which can be computed at compile time.
+pub const fn layout_hash(hash: u64, description: &str) -> u64 {
+    let bytes = description.as_bytes();
+    let mut hash = hash;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        hash ^= bytes[i] as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        i += 1;
+    }
+
+    hash
+}
+
+/// Hash the layout of a nested key into the given hash.
+pub const fn layout_combine(hash: u64, layout: u64) -> u64 {
+    let bytes = layout.to_be_bytes();
+    let mut hash = hash;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        hash ^= bytes[i] as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        i += 1;
+    }
+
+    hash
+}
+
+impl StorageKey for () {
+    const SIZE: usize = 0;
+    const LAYOUT: u64 = layout_hash(LAYOUT_SEED, "()");
+
+    fn encode_key(&self, _: &mut Vec<u8>) {}
+
+    fn decode_key(_: &[u8]) -> Self {}
+}
+
+impl StorageKey for bool {
+    const SIZE: usize = 1;
+    const LAYOUT: u64 = layout_hash(LAYOUT_SEED, "bool");
+
+    fn encode_key(&self, buf: &mut Vec<u8>) {
+        buf.push(*self as u8);
+    }
+
+    fn decode_key(bytes: &[u8]) -> Self {
+        bytes[0] != 0
+    }
+}
+
+macro_rules! impl_storage_key_uint {
+    ($($t:ty),*) => {
+        $(
+            impl StorageKey for $t {
+                const SIZE: usize = std::mem::size_of::<$t>();
+                const LAYOUT: u64 = layout_hash(LAYOUT_SEED, stringify!($t));
+
+                fn encode_key(&self, buf: &mut Vec<u8>) {
+                    buf.extend_from_slice(&self.to_be_bytes());
+                }
+
+                fn decode_key(bytes: &[u8]) -> Self {
+                    let mut buf = [0; std::mem::size_of::<$t>()];
+                    buf.copy_from_slice(&bytes[..Self::SIZE]);
+                    <$t>::from_be_bytes(buf)
+                }
+            }
+        )*
+    };
+}
+
+impl_storage_key_uint!(u8, u16, u32, u64, u128);
+
+// The sign bit is flipped, so the negative numbers are ordered before the positive ones.
+macro_rules! impl_storage_key_int {
+    ($($t:ty => $u:ty),*) => {
+        $(
+            impl StorageKey for $t {
+                const SIZE: usize = std::mem::size_of::<$t>();
+                const LAYOUT: u64 = layout_hash(LAYOUT_SEED, stringify!($t));
+
+                fn encode_key(&self, buf: &mut Vec<u8>) {
+                    let flipped = (*self as $u) ^ (1 << (<$u>::BITS - 1));
+                    buf.extend_from_slice(&flipped.to_be_bytes());
+                }
+
+                fn decode_key(bytes: &[u8]) -> Self {
+                    let flipped = <$u as StorageKey>::decode_key(bytes);
+                    (flipped ^ (1 << (<$u>::BITS - 1))) as $t
+                }
+            }
+        )*
+    };
+}
+
+impl_storage_key_int!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128);
+
+impl<const N: usize> StorageKey for [u8; N] {
+    const SIZE: usize = N;
+    const LAYOUT: u64 = layout_combine(layout_hash(LAYOUT_SEED, "bytes"), N as u64);
+
+    fn encode_key(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(self);
+    }
+
+    fn decode_key(bytes: &[u8]) -> Self {
+        let mut key = [0; N];
+        key.copy_from_slice(&bytes[..N]);
+        key
+    }
+}
+
+// A principal is ordered by its length first, and then by its bytes.
+impl StorageKey for Principal {
+    const SIZE: usize = 1 + PRINCIPAL_MAX_LENGTH;
+    const LAYOUT: u64 = layout_hash(LAYOUT_SEED, "principal");
+
+    fn encode_key(&self, buf: &mut Vec<u8>) {
+        let bytes = self.as_slice();
+        buf.push(bytes.len() as u8);
+        buf.extend_from_slice(bytes);
+        buf.resize(buf.len() + PRINCIPAL_MAX_LENGTH - bytes.len(), 0);
+    }
+
+    fn decode_key(bytes: &[u8]) -> Self {
+        let len = bytes[0] as usize;
+        Principal::from_slice(&bytes[1..1 + len])
+    }
+}
+
+// `None` is encoded as zeros, which are ordered before the tag of `Some`.
+impl<T: StorageKey> StorageKey for Option<T> {
+    const SIZE: usize = 1 + T::SIZE;
+    const LAYOUT: u64 = layout_combine(layout_hash(LAYOUT_SEED, "option"), T::LAYOUT);
+
+    fn encode_key(&self, buf: &mut Vec<u8>) {
+        match self {
+            Some(value) => {
+                buf.push(1);
+                value.encode_key(buf);
+            }
+            None => buf.resize(buf.len() + Self::SIZE, 0),
+        }
+    }
+
+    fn decode_key(bytes: &[u8]) -> Self {
+        match bytes[0] {
+            0 => None,
+            _ => Some(T::decode_key(&bytes[1..])),
+        }
+    }
+}
+
+macro_rules! impl_storage_key_tuple {
+    ($($name:ident $index:tt)+) => {
+        impl<$($name: StorageKey),+> StorageKey for ($($name,)+) {
+            const SIZE: usize = 0 $(+ $name::SIZE)+;
+            const LAYOUT: u64 = {
+                let hash = layout_hash(LAYOUT_SEED, "tuple");
+                $(let hash = layout_combine(hash, $name::LAYOUT);)+
+                hash
+            };
+
+            fn encode_key(&self, buf: &mut Vec<u8>) {
+                $(self.$index.encode_key(buf);)+
+            }
+
+            #[allow(unused_assignments)]
+            fn decode_key(bytes: &[u8]) -> Self {
+                let mut offset = 0;
+                ($({
+                    let value = $name::decode_key(&bytes[offset..]);
+                    offset += $name::SIZE;
+                    value
+                },)+)
+            }
+        }
+    };
+}
+
+impl_storage_key_tuple!(A 0);
+impl_storage_key_tuple!(A 0 B 1);
+impl_storage_key_tuple!(A 0 B 1 C 2);
+impl_storage_key_tuple!(A 0 B 1 C 2 D 3);
+
+/// Decode a key and check its version, this is called by the `Storable` implementation generated
+/// by `#[derive(StorageKey)]`.
+#[doc(hidden)]
+pub fn decode_versioned_key<K: StorageKey>(bytes: &[u8], version: Option<u8>) -> K {
+    let expected = K::SIZE + version.is_some() as usize;
+    if bytes.len() != expected {
+        panic!(
+            "The key of type {} is {} bytes long, not {}.",
+            std::any::type_name::<K>(),
+            bytes.len(),
+            expected
+        );
+    }
+
+    match version {
+        Some(version) if bytes[0] != version => panic!(
+            "The key of type {} has the version {}, not {}.",
+            std::any::type_name::<K>(),
+            bytes[0],
+            version
+        ),
+        Some(_) => K::decode_key(&bytes[1..]),
+        None => K::decode_key(bytes),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode<K: StorageKey>(key: &K) -> Vec<u8> {
+        let mut buf = Vec::new();
+        key.encode_key(&mut buf);
+        assert_eq!(buf.len(), K::SIZE);
+        buf
+    }
+
+    /// Check that the keys, given in order, are encoded in the same order and decoded back.
+    fn check_order<K: StorageKey + Ord + std::fmt::Debug>(keys: &[K]) {
+        for pair in keys.windows(2) {
+            assert!(pair[0] < pair[1], "{:?} < {:?}", pair[0], pair[1]);
+            assert!(
+                encode(&pair[0]) < encode(&pair[1]),
+                "{:?} < {:?}",
+                pair[0],
+                pair[1]
+            );
+        }
+
+        for key in keys {
+            assert_eq!(&K::decode_key(&encode(key)), key);
+        }
+    }
+
+    #[test]
+    fn integers() {
+        assert_eq!(encode(&0x0102u16), vec![1, 2]);
+        assert_eq!(encode(&-1i8), vec![0x7f]);
+        assert_eq!(encode(&0i8), vec![0x80]);
+
+        check_order(&[0u8, 1, 127, 128, 255]);
+        check_order(&[0u64, 1, 256, u32::MAX as u64, u64::MAX]);
+        check_order(&[i32::MIN, -256, -1, 0, 1, 256, i32::MAX]);
+        check_order(&[i128::MIN, -1, 0, i128::MAX]);
+        check_order(&[false, true]);
+    }
+
+    #[test]
+    fn principals() {
+        let principal = Principal::from_slice(&[7; 10]);
+        let mut expected = vec![10];
+        expected.extend_from_slice(&[7; 10]);
+        expected.extend_from_slice(&[0; 19]);
+        assert_eq!(encode(&principal), expected);
+
+        // The shorter principals are ordered first, like the principals themselves.
+        check_order(&[
+            Principal::management_canister(),
+            Principal::anonymous(),
+            Principal::from_slice(&[0, 1]),
+            Principal::from_slice(&[1, 0]),
+            Principal::from_slice(&[0; 29]),
+        ]);
+    }
+
+    #[test]
+    fn options_and_tuples() {
+        assert_eq!(encode(&None::<u16>), vec![0, 0, 0]);
+        assert_eq!(encode(&Some(2u16)), vec![1, 0, 2]);
+        check_order(&[None, Some(-5i16), Some(0), Some(7)]);
+
+        check_order(&[(1u8, -1i32), (1, 0), (2, i32::MIN), (2, 5)]);
+        check_order(&[
+            (0u8, None, [0u8; 2]),
+            (0, Some(true), [0, 1]),
+            (0, Some(true), [1, 0]),
+            (1, None, [0, 0]),
+        ]);
+        assert_eq!(<(u8, Option<u32>, [u8; 3])>::SIZE, 1 + 5 + 3);
+    }
+
+    #[test]
+    fn layouts() {
+        let layouts = [
+            <u32>::LAYOUT,
+            <i32>::LAYOUT,
+            <u64>::LAYOUT,
+            <[u8; 4]>::LAYOUT,
+            <[u8; 8]>::LAYOUT,
+            <Option<u32>>::LAYOUT,
+            <(u32,)>::LAYOUT,
+            <(u32, u64)>::LAYOUT,
+            <(u64, u32)>::LAYOUT,
+            <Principal>::LAYOUT,
+        ];
+
+        for (i, a) in layouts.iter().enumerate() {
+            for b in &layouts[i + 1..] {
+                assert_ne!(a, b);
+            }
+        }
+
+        // The FNV-1a test vectors.
+        assert_eq!(layout_hash(LAYOUT_SEED, ""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(layout_hash(LAYOUT_SEED, "a"), 0xaf63_dc4c_8601_ec8c);
+    }
+
+    #[test]
+    fn versioned_key() {
+        assert_eq!(decode_versioned_key::<u16>(&[3, 1, 2], Some(3)), 0x0102);
+        assert_eq!(decode_versioned_key::<u16>(&[1, 2], None), 0x0102);
+    }
+
+    #[test]
+    #[should_panic(expected = "The key of type u16 has the version 2, not 3.")]
+    fn versioned_key_other_version() {
+        decode_versioned_key::<u16>(&[2, 1, 2], Some(3));
+    }
+
+    #[test]
+    #[should_panic(expected = "The key of type u16 is 2 bytes long, not 3.")]
+    fn versioned_key_without_version() {
+        decode_versioned_key::<u16>(&[1, 2], Some(3));
+    }
+}
@@ -0,0 +1,281 @@
+use super::memory::{ensure_size, read_u64, write_u64, Memory};
+use super::storable::Storable;
+use ic_kit_sys::types::StableMemoryError;
+use std::marker::PhantomData;
+
+/// The magic bytes at the beginning of the index and the data memories of a log.
+const INDEX_MAGIC: &[u8; 4] = b"KLGI";
+const DATA_MAGIC: &[u8; 4] = b"KLGD";
+
+// The index memory contains the magic bytes and the number of entries, followed by the end offset
+// of each entry in the data memory. The data memory contains the magic bytes followed by the data
+// of the entries.
+const LEN_OFFSET: u64 = 8;
+const INDEX_HEADER_SIZE: u64 = 16;
+const DATA_HEADER_SIZE: u64 = 8;
+
+/// An append-only list of entries of any size stored directly in the stable memory, which is
+/// suitable for data such as the transaction history of a canister.
+///
+/// The log uses two memories, the index memory keeps the position of each entry in the data
+/// memory, so the entries can be appended and accessed in constant time.
+///
+/// ```ignore
+/// use ic_kit::stable::StableLog;
+///
+/// let mut transactions = StableLog::<Transaction, _>::init(index_memory, data_memory);
+/// let index = transactions.append(&tx).unwrap();
+/// ```
+pub struct StableLog<T, M: Memory> {
+    index: M,
+    data: M,
+    len: u64,
+    _marker: PhantomData<T>,
+}
+
+impl<T: Storable, M: Memory> StableLog<T, M> {
+    /// Load the log stored in the memories, or create a new one if the memories are empty.
+    ///
+    /// # Panics
+    ///
+    /// If the memories are not empty and do not contain a log.
+    pub fn init(index: M, data: M) -> Self {
+        if index.size() == 0 && data.size() == 0 {
+            return Self::new(index, data);
+        }
+
+        Self::load(index, data)
+    }
+
+    /// Create a new empty log in the memories, overwriting their content.
+    ///
+    /// # Panics
+    ///
+    /// If the memories can not grow to store the headers of the log.
+    pub fn new(index: M, data: M) -> Self {
+        ensure_size(&index, INDEX_HEADER_SIZE)
+            .and_then(|_| ensure_size(&data, DATA_HEADER_SIZE))
+            .expect("Could not grow the stable memory for the log.");
+
+        index.write(0, INDEX_MAGIC);
+        write_u64(&index, LEN_OFFSET, 0);
+        data.write(0, DATA_MAGIC);
+
+        Self {
+            index,
+            data,
+            len: 0,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Load the log stored in the memories.
+    ///
+    /// # Panics
+    ///
+    /// If the memories do not contain a log.
+    pub fn load(index: M, data: M) -> Self {
+        let mut magic = [0; 4];
+        index.read(0, &mut magic);
+        assert_eq!(
+            &magic, INDEX_MAGIC,
+            "The memory does not contain a StableLog index."
+        );
+        data.read(0, &mut magic);
+        assert_eq!(
+            &magic, DATA_MAGIC,
+            "The memory does not contain a StableLog data."
+        );
+
+        Self {
+            len: read_u64(&index, LEN_OFFSET),
+            index,
+            data,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Return the number of entries in the log.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns true if the log does not have any entries.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Append the entry to the end of the log and return its index, returns an error if the
+    /// memories can not grow.
+    pub fn append(&mut self, item: &T) -> Result<u64, StableMemoryError> {
+        let bytes = item.to_bytes();
+        let start = self.end_of(self.len);
+        let end = start + bytes.len() as u64;
+
+        ensure_size(&self.data, DATA_HEADER_SIZE + end)?;
+        ensure_size(&self.index, INDEX_HEADER_SIZE + (self.len + 1) * 8)?;
+
+        self.data.write(DATA_HEADER_SIZE + start, &bytes);
+        write_u64(&self.index, INDEX_HEADER_SIZE + self.len * 8, end);
+
+        // Only count the entry once it is completely written.
+        let index = self.len;
+        self.len += 1;
+        write_u64(&self.index, LEN_OFFSET, self.len);
+
+        Ok(index)
+    }
+
+    /// Return the entry at the given index.
+    pub fn get(&self, index: u64) -> Option<T> {
+        if index >= self.len {
+            return None;
+        }
+
+        let start = self.end_of(index);
+        let end = self.end_of(index + 1);
+        let mut bytes = vec![0; (end - start) as usize];
+        self.data.read(DATA_HEADER_SIZE + start, &mut bytes);
+        Some(T::from_bytes(bytes))
+    }
+
+    /// Iterate over the entries of the log, from the oldest to the newest.
+    pub fn iter(&self) -> LogIter<'_, T, M> {
+        LogIter {
+            log: self,
+            front: 0,
+            back: self.len,
+        }
+    }
+
+    /// Iterate over the entries of the log starting from the given index.
+    pub fn iter_from(&self, index: u64) -> LogIter<'_, T, M> {
+        LogIter {
+            log: self,
+            front: index.min(self.len),
+            back: self.len,
+        }
+    }
+
+    /// Return the memories of the log.
+    pub fn into_memories(self) -> (M, M) {
+        (self.index, self.data)
+    }
+
+    /// Return the offset in the data memory where the entry before the given index ends.
+    fn end_of(&self, index: u64) -> u64 {
+        if index == 0 {
+            return 0;
+        }
+
+        read_u64(&self.index, INDEX_HEADER_SIZE + (index - 1) * 8)
+    }
+}
+
+/// An iterator over the entries of a [`StableLog`].
+pub struct LogIter<'a, T, M: Memory> {
+    log: &'a StableLog<T, M>,
+    front: u64,
+    back: u64,
+}
+
+impl<'a, T: Storable, M: Memory> Iterator for LogIter<'a, T, M> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        if self.front >= self.back {
+            return None;
+        }
+
+        self.front += 1;
+        self.log.get(self.front - 1)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = (self.back - self.front) as usize;
+        (len, Some(len))
+    }
+}
+
+impl<'a, T: Storable, M: Memory> DoubleEndedIterator for LogIter<'a, T, M> {
+    fn next_back(&mut self) -> Option<T> {
+        if self.front >= self.back {
+            return None;
+        }
+
+        self.back -= 1;
+        self.log.get(self.back)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stable::{VectorMemory, WASM_PAGE_SIZE};
+
+    #[test]
+    fn append_get_iter() {
+        let mut log =
+            StableLog::<String, _>::init(VectorMemory::default(), VectorMemory::default());
+        assert!(log.is_empty());
+        assert_eq!(log.get(0), None);
+
+        assert_eq!(log.append(&"a".to_string()), Ok(0));
+        assert_eq!(log.append(&String::new()), Ok(1));
+        assert_eq!(log.append(&"ccc".to_string()), Ok(2));
+
+        assert_eq!(log.len(), 3);
+        assert_eq!(log.get(1), Some(String::new()));
+        assert_eq!(log.get(2), Some("ccc".to_string()));
+        assert_eq!(log.get(3), None);
+        assert_eq!(
+            log.iter().rev().collect::<Vec<_>>(),
+            vec!["ccc".to_string(), String::new(), "a".to_string()]
+        );
+        assert_eq!(log.iter_from(1).size_hint(), (2, Some(2)));
+        assert_eq!(log.iter_from(5).next(), None);
+    }
+
+    #[test]
+    fn reload_after_grow() {
+        let index = VectorMemory::default();
+        let data = VectorMemory::default();
+        let entry = |i: u8| vec![i; 20_000];
+
+        let mut log = StableLog::<Vec<u8>, _>::init(index.clone(), data.clone());
+        for i in 0..10 {
+            log.append(&entry(i)).unwrap();
+        }
+        drop(log);
+
+        // The entries span several pages, some of them across a page boundary.
+        assert!(data.size() > 3);
+
+        let mut log = StableLog::<Vec<u8>, _>::init(index.clone(), data.clone());
+        assert_eq!(log.len(), 10);
+        assert_eq!(log.get(3), Some(entry(3)));
+        assert_eq!(log.append(&entry(10)), Ok(10));
+        drop(log);
+
+        let log = StableLog::<Vec<u8>, _>::init(index, data.clone());
+        assert_eq!(
+            log.iter_from(8).collect::<Vec<_>>(),
+            vec![entry(8), entry(9), entry(10)]
+        );
+        assert_eq!(
+            data.size(),
+            (DATA_HEADER_SIZE + 11 * 20_000 - 1) / WASM_PAGE_SIZE + 1
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "The memory does not contain a StableLog data.")]
+    fn load_other_data() {
+        let index = VectorMemory::default();
+        StableLog::<u64, _>::init(index.clone(), VectorMemory::default());
+
+        let data = VectorMemory::default();
+        data.grow(1).unwrap();
+        StableLog::<u64, _>::init(index, data);
+    }
+}
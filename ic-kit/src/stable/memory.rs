@@ -0,0 +1,129 @@
+use crate::ic::{
+    reserve_stable_region, stable_grow, stable_read, stable_size, write_reserved, StableSize,
+};
+use ic_kit_sys::types::StableMemoryError;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// The size of a WebAssembly page in bytes.
+pub const WASM_PAGE_SIZE: u64 = 1 << 16;
+
+/// A growable memory which the stable structures are stored in, the memory is addressed in bytes
+/// and grows in WebAssembly pages of 64KiB.
+pub trait Memory {
+    /// Returns the current size of the memory in WebAssembly pages.
+    fn size(&self) -> u64;
+
+    /// Attempts to grow the memory by `pages`, and returns the previous size in pages.
+    fn grow(&self, pages: u64) -> Result<u64, StableMemoryError>;
+
+    /// Copy the data at the given offset of the memory to the buffer.
+    fn read(&self, offset: u64, buf: &mut [u8]);
+
+    /// Write the data to the memory at the given offset, the memory must be large enough.
+    fn write(&self, offset: u64, buf: &[u8]);
+
+    /// Mark the first `size` bytes of the memory as used by a stable structure, see
+    /// [`crate::ic::set_stable_write_check`]. This does nothing by default.
+    fn reserve(&self, _size: u64) {}
+}
+
+/// The entire stable memory of the canister.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct DefaultMemory;
+
+impl Memory for DefaultMemory {
+    fn size(&self) -> u64 {
+        stable_size() as u64
+    }
+
+    fn grow(&self, pages: u64) -> Result<u64, StableMemoryError> {
+        stable_grow(pages as StableSize).map(|size| size as u64)
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) {
+        stable_read(offset as StableSize, buf)
+    }
+
+    fn write(&self, offset: u64, buf: &[u8]) {
+        write_reserved(offset as StableSize, buf)
+    }
+
+    fn reserve(&self, size: u64) {
+        reserve_stable_region(0..size)
+    }
+}
+
+impl<M: Memory> Memory for &M {
+    fn size(&self) -> u64 {
+        (**self).size()
+    }
+
+    fn grow(&self, pages: u64) -> Result<u64, StableMemoryError> {
+        (**self).grow(pages)
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) {
+        (**self).read(offset, buf)
+    }
+
+    fn write(&self, offset: u64, buf: &[u8]) {
+        (**self).write(offset, buf)
+    }
+
+    fn reserve(&self, size: u64) {
+        (**self).reserve(size)
+    }
+}
+
+/// A memory on the heap, which can be used to unit test the code using the stable structures
+/// without a replica.
+pub type VectorMemory = Rc<RefCell<Vec<u8>>>;
+
+impl Memory for VectorMemory {
+    fn size(&self) -> u64 {
+        self.borrow().len() as u64 / WASM_PAGE_SIZE
+    }
+
+    fn grow(&self, pages: u64) -> Result<u64, StableMemoryError> {
+        let size = self.size();
+        let mut vec = self.borrow_mut();
+        vec.resize(((size + pages) * WASM_PAGE_SIZE) as usize, 0);
+        Ok(size)
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) {
+        let offset = offset as usize;
+        buf.copy_from_slice(&self.borrow()[offset..offset + buf.len()]);
+    }
+
+    fn write(&self, offset: u64, buf: &[u8]) {
+        let offset = offset as usize;
+        self.borrow_mut()[offset..offset + buf.len()].copy_from_slice(buf);
+    }
+}
+
+/// Make sure the memory is large enough to hold `size` bytes, growing it if needed.
+pub(crate) fn ensure_size<M: Memory>(memory: &M, size: u64) -> Result<(), StableMemoryError> {
+    let current = memory.size() * WASM_PAGE_SIZE;
+
+    if size > current {
+        let pages = (size - current - 1) / WASM_PAGE_SIZE + 1;
+        memory.grow(pages)?;
+    }
+
+    memory.reserve(size);
+    Ok(())
+}
+
+/// Read a little endian u64 from the memory.
+pub(crate) fn read_u64<M: Memory>(memory: &M, offset: u64) -> u64 {
+    let mut bytes = [0; 8];
+    memory.read(offset, &mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+/// Write a u64 to the memory in little endian.
+pub(crate) fn write_u64<M: Memory>(memory: &M, offset: u64, value: u64) {
+    memory.write(offset, &value.to_le_bytes());
+}
@@ -0,0 +1,367 @@
+use super::memory::{ensure_size, read_u64, write_u64, DefaultMemory, Memory, WASM_PAGE_SIZE};
+use ic_kit_sys::types::StableMemoryError;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// The magic bytes at the beginning of the memory of a memory manager.
+const MAGIC: &[u8; 4] = b"KMGR";
+
+// The layout of the header page: the magic bytes, followed by the size of the buckets in pages,
+// the number of the allocated buckets and the size of each virtual memory in pages as u64s, and
+// the table of the owner of each bucket.
+const BUCKET_SIZE_OFFSET: u64 = 8;
+const ALLOCATED_BUCKETS_OFFSET: u64 = 16;
+const SIZES_OFFSET: u64 = 24;
+const BUCKET_TABLE_OFFSET: u64 = 4096;
+const HEADER_PAGES: u64 = 1;
+
+/// The maximum number of buckets, which is limited by the size of the bucket table.
+const MAX_BUCKETS: u64 = 32768;
+
+/// The default size of a bucket in pages, which is 8MiB.
+const DEFAULT_BUCKET_SIZE: u64 = 128;
+
+/// The owner of the buckets that are not allocated.
+const UNALLOCATED: u8 = u8::MAX;
+
+/// The id of a virtual memory, there can be up to 255 virtual memories.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MemoryId(u8);
+
+impl MemoryId {
+    /// Create a new memory id.
+    ///
+    /// # Panics
+    ///
+    /// If the id is 255, which is reserved.
+    pub const fn new(id: u8) -> Self {
+        assert!(id != UNALLOCATED, "The memory id 255 is reserved.");
+        Self(id)
+    }
+}
+
+/// Partitions a memory into several virtual memories, which can be used by different stable
+/// structures at the same time. Each virtual memory grows independently, in buckets of 8MiB
+/// taken from the underlying memory.
+///
+/// The memory manager of the canister's stable memory can be accessed from the canister's
+/// storage:
+///
+/// ```ignore
+/// use ic_kit::stable::{MemoryId, MemoryManager, StableBTreeMap};
+///
+/// let memory = ic::with(|manager: &MemoryManager| manager.get(MemoryId::new(0)));
+/// let balances = StableBTreeMap::<Principal, u64, _>::init(memory);
+/// ```
+pub struct MemoryManager<M: Memory = DefaultMemory> {
+    inner: Rc<RefCell<Inner<M>>>,
+}
+
+impl Default for MemoryManager<DefaultMemory> {
+    fn default() -> Self {
+        Self::init(DefaultMemory)
+    }
+}
+
+impl<M: Memory> MemoryManager<M> {
+    /// Load the memory manager stored in the memory, or create a new one if the memory is empty.
+    ///
+    /// # Panics
+    ///
+    /// If the memory is not empty and does not contain a memory manager.
+    pub fn init(memory: M) -> Self {
+        Self::init_with_bucket_size(memory, DEFAULT_BUCKET_SIZE)
+    }
+
+    /// Same as [`MemoryManager::init`], but uses the given number of pages as the size of the
+    /// buckets when creating a new memory manager.
+    pub fn init_with_bucket_size(memory: M, bucket_size: u64) -> Self {
+        assert!(bucket_size > 0, "The bucket size must not be zero.");
+
+        let inner = if memory.size() == 0 {
+            Inner::new(memory, bucket_size)
+        } else {
+            Inner::load(memory)
+        };
+
+        Self {
+            inner: Rc::new(RefCell::new(inner)),
+        }
+    }
+
+    /// Return the virtual memory with the given id.
+    pub fn get(&self, id: MemoryId) -> VirtualMemory<M> {
+        VirtualMemory {
+            id,
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+/// A memory provided by a [`MemoryManager`].
+pub struct VirtualMemory<M: Memory> {
+    id: MemoryId,
+    inner: Rc<RefCell<Inner<M>>>,
+}
+
+impl<M: Memory> Clone for VirtualMemory<M> {
+    fn clone(&self) -> Self {
+        Self {
+            id: self.id,
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<M: Memory> Memory for VirtualMemory<M> {
+    fn size(&self) -> u64 {
+        self.inner.borrow().sizes[self.id.0 as usize]
+    }
+
+    fn grow(&self, pages: u64) -> Result<u64, StableMemoryError> {
+        self.inner.borrow_mut().grow(self.id, pages)
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) {
+        let inner = self.inner.borrow();
+        inner.for_each_chunk(self.id, offset, buf.len() as u64, |address, start, end| {
+            inner.memory.read(address, &mut buf[start..end])
+        });
+    }
+
+    fn write(&self, offset: u64, buf: &[u8]) {
+        let inner = self.inner.borrow();
+        inner.for_each_chunk(self.id, offset, buf.len() as u64, |address, start, end| {
+            inner.memory.write(address, &buf[start..end])
+        });
+    }
+}
+
+struct Inner<M: Memory> {
+    memory: M,
+    bucket_size: u64,
+    allocated_buckets: u64,
+    /// The size of each virtual memory in pages.
+    sizes: Vec<u64>,
+    /// The buckets of each virtual memory, in order.
+    buckets: Vec<Vec<u16>>,
+}
+
+impl<M: Memory> Inner<M> {
+    fn new(memory: M, bucket_size: u64) -> Self {
+        ensure_size(&memory, HEADER_PAGES * WASM_PAGE_SIZE)
+            .expect("Could not grow the stable memory for the memory manager.");
+
+        let mut header = vec![0; (HEADER_PAGES * WASM_PAGE_SIZE) as usize];
+        header[0..4].copy_from_slice(MAGIC);
+        header[BUCKET_SIZE_OFFSET as usize..ALLOCATED_BUCKETS_OFFSET as usize]
+            .copy_from_slice(&bucket_size.to_le_bytes());
+        for owner in &mut header[BUCKET_TABLE_OFFSET as usize..] {
+            *owner = UNALLOCATED;
+        }
+        memory.write(0, &header);
+
+        Self {
+            memory,
+            bucket_size,
+            allocated_buckets: 0,
+            sizes: vec![0; UNALLOCATED as usize],
+            buckets: vec![Vec::new(); UNALLOCATED as usize],
+        }
+    }
+
+    fn load(memory: M) -> Self {
+        let mut header = vec![0; (HEADER_PAGES * WASM_PAGE_SIZE) as usize];
+        memory.read(0, &mut header);
+        assert_eq!(
+            &header[0..4],
+            MAGIC,
+            "The memory does not contain a MemoryManager."
+        );
+
+        let allocated_buckets = read_u64(&memory, ALLOCATED_BUCKETS_OFFSET);
+        let sizes = (0..UNALLOCATED as u64)
+            .map(|id| read_u64(&memory, SIZES_OFFSET + id * 8))
+            .collect();
+
+        let mut buckets = vec![Vec::new(); UNALLOCATED as usize];
+        for bucket in 0..allocated_buckets {
+            let owner = header[(BUCKET_TABLE_OFFSET + bucket) as usize];
+            buckets[owner as usize].push(bucket as u16);
+        }
+
+        let bucket_size = read_u64(&memory, BUCKET_SIZE_OFFSET);
+        memory.reserve((HEADER_PAGES + allocated_buckets * bucket_size) * WASM_PAGE_SIZE);
+
+        Self {
+            bucket_size,
+            memory,
+            allocated_buckets,
+            sizes,
+            buckets,
+        }
+    }
+
+    fn grow(&mut self, id: MemoryId, pages: u64) -> Result<u64, StableMemoryError> {
+        let id = id.0 as usize;
+        let old_size = self.sizes[id];
+        let new_size = old_size + pages;
+
+        let available = self.buckets[id].len() as u64 * self.bucket_size;
+        let new_buckets = if new_size > available {
+            (new_size - available - 1) / self.bucket_size + 1
+        } else {
+            0
+        };
+
+        if self.allocated_buckets + new_buckets > MAX_BUCKETS {
+            return Err(StableMemoryError::OutOfMemory);
+        }
+
+        // Grow the underlying memory before assigning the buckets, so a failure changes nothing.
+        let total_buckets = self.allocated_buckets + new_buckets;
+        ensure_size(
+            &self.memory,
+            (HEADER_PAGES + total_buckets * self.bucket_size) * WASM_PAGE_SIZE,
+        )?;
+
+        for _ in 0..new_buckets {
+            let bucket = self.allocated_buckets;
+            self.memory.write(BUCKET_TABLE_OFFSET + bucket, &[id as u8]);
+            self.buckets[id].push(bucket as u16);
+            self.allocated_buckets += 1;
+        }
+
+        write_u64(
+            &self.memory,
+            ALLOCATED_BUCKETS_OFFSET,
+            self.allocated_buckets,
+        );
+
+        self.sizes[id] = new_size;
+        write_u64(&self.memory, SIZES_OFFSET + id as u64 * 8, new_size);
+
+        Ok(old_size)
+    }
+
+    /// Call the function with the address in the underlying memory and the range in the buffer of
+    /// each part of the given range of the virtual memory that is in a different bucket.
+    fn for_each_chunk<F: FnMut(u64, usize, usize)>(
+        &self,
+        id: MemoryId,
+        offset: u64,
+        len: u64,
+        mut f: F,
+    ) {
+        let id = id.0 as usize;
+        assert!(
+            offset + len <= self.sizes[id] * WASM_PAGE_SIZE,
+            "Out of bounds access to the virtual memory {}.",
+            id
+        );
+
+        let bucket_bytes = self.bucket_size * WASM_PAGE_SIZE;
+        let mut position = offset;
+
+        while position < offset + len {
+            let bucket = self.buckets[id][(position / bucket_bytes) as usize] as u64;
+            let bucket_offset = position % bucket_bytes;
+            let chunk = (bucket_bytes - bucket_offset).min(offset + len - position);
+            let address =
+                (HEADER_PAGES + bucket * self.bucket_size) * WASM_PAGE_SIZE + bucket_offset;
+
+            f(
+                address,
+                (position - offset) as usize,
+                (position - offset + chunk) as usize,
+            );
+            position += chunk;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stable::VectorMemory;
+
+    /// The content written at the given offset of a virtual memory, which differs per memory.
+    fn pattern(id: u8, offset: u64, len: usize) -> Vec<u8> {
+        (0..len as u64)
+            .map(|i| ((offset + i) % 251) as u8 ^ id)
+            .collect()
+    }
+
+    #[test]
+    fn interleaved_memories() {
+        let memory = VectorMemory::default();
+        let manager = MemoryManager::init_with_bucket_size(memory.clone(), 1);
+        let ids = [MemoryId::new(0), MemoryId::new(1), MemoryId::new(7)];
+
+        // Grow the memories in turns, so their buckets are interleaved in the underlying memory,
+        // and write a range crossing the boundary between the last two buckets of each.
+        for round in 0..4u64 {
+            for (index, id) in ids.iter().enumerate() {
+                let virtual_memory = manager.get(*id);
+                let pages = 1 + index as u64;
+                assert_eq!(virtual_memory.grow(pages).unwrap(), round * pages);
+
+                let end = virtual_memory.size() * WASM_PAGE_SIZE;
+                let offset = match round {
+                    0 => 0,
+                    _ => end - pages * WASM_PAGE_SIZE - 100,
+                };
+                let len = (end - offset) as usize;
+                virtual_memory.write(offset, &pattern(id.0, offset, len));
+            }
+        }
+
+        assert_eq!(memory.size(), HEADER_PAGES + 4 * (1 + 2 + 3));
+
+        let check = |manager: &MemoryManager<VectorMemory>| {
+            for (index, id) in ids.iter().enumerate() {
+                let virtual_memory = manager.get(*id);
+                assert_eq!(virtual_memory.size(), 4 * (1 + index as u64));
+
+                let len = (virtual_memory.size() * WASM_PAGE_SIZE) as usize;
+                let mut buf = vec![0; len];
+                virtual_memory.read(0, &mut buf);
+                assert_eq!(buf, pattern(id.0, 0, len));
+            }
+        };
+
+        check(&manager);
+
+        drop(manager);
+        let manager = MemoryManager::init(memory.clone());
+        check(&manager);
+
+        // The reloaded manager keeps the size of the buckets, and grows after the last bucket.
+        let virtual_memory = manager.get(MemoryId::new(2));
+        assert_eq!(virtual_memory.grow(2).unwrap(), 0);
+        virtual_memory.write(0, &pattern(2, 0, 2 * WASM_PAGE_SIZE as usize));
+        assert_eq!(memory.size(), HEADER_PAGES + 4 * (1 + 2 + 3) + 2);
+        check(&manager);
+
+        let mut buf = vec![0; 2 * WASM_PAGE_SIZE as usize];
+        virtual_memory.read(0, &mut buf);
+        assert_eq!(buf, pattern(2, 0, buf.len()));
+    }
+
+    #[test]
+    #[should_panic(expected = "Out of bounds access to the virtual memory 3.")]
+    fn out_of_bounds() {
+        let manager = MemoryManager::init_with_bucket_size(VectorMemory::default(), 1);
+        let virtual_memory = manager.get(MemoryId::new(3));
+        virtual_memory.grow(1).unwrap();
+        virtual_memory.write(WASM_PAGE_SIZE - 1, &[1, 2]);
+    }
+
+    #[test]
+    #[should_panic(expected = "The memory does not contain a MemoryManager.")]
+    fn load_other_memory() {
+        let memory = VectorMemory::default();
+        memory.grow(1).unwrap();
+        MemoryManager::init(memory);
+    }
+}
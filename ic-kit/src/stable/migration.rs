@@ -0,0 +1,172 @@
+use super::btreemap::StableBTreeMap;
+use super::memory::Memory;
+use super::storable::{BoundedStorable, Storable};
+use super::vec::StableVec;
+use candid::CandidType;
+use ic_kit_sys::types::StableMemoryError;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+
+/// Tracks the progress of moving a collection from the heap to a stable structure in batches, so
+/// the migration can be spread across several messages, for example a batch per heartbeat, when
+/// the collection is too large to be moved within a single message.
+///
+/// The migration should be kept in the canister's state along with the heap collection, so it is
+/// saved with it if the canister is upgraded again before the migration is done. Until then, the
+/// reads should look for the entries in both of the collections.
+///
+/// ```ignore
+/// #[heartbeat]
+/// fn heartbeat(state: &mut State) {
+///     if !state.migration.is_done() {
+///         state.migration.migrate_map(&mut state.balances, &mut state.stable_balances, 1000);
+///     }
+/// }
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, CandidType, Deserialize)]
+pub struct Migration {
+    migrated: u64,
+    done: bool,
+}
+
+impl Migration {
+    /// Returns the number of the items moved so far.
+    pub fn migrated(&self) -> u64 {
+        self.migrated
+    }
+
+    /// Returns true once all of the items are moved.
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// Move up to `batch` entries from the heap map to the stable map, the entries are removed
+    /// from the heap map as they are moved. Returns true once the heap map is empty.
+    pub fn migrate_map<K, V, M, S>(
+        &mut self,
+        heap: &mut HashMap<K, V, S>,
+        stable: &mut StableBTreeMap<K, V, M>,
+        batch: usize,
+    ) -> bool
+    where
+        K: Storable + Ord + Clone + Hash,
+        V: Storable,
+        M: Memory,
+        S: BuildHasher,
+    {
+        let keys = heap.keys().take(batch).cloned().collect::<Vec<_>>();
+
+        for key in keys {
+            if let Some(value) = heap.remove(&key) {
+                stable.insert(key, value);
+                self.migrated += 1;
+            }
+        }
+
+        if heap.is_empty() {
+            heap.shrink_to_fit();
+            self.done = true;
+        }
+
+        self.done
+    }
+
+    /// Push up to `batch` items of the heap vector to the stable vector in order. The heap vector
+    /// is only cleared once all of its items are moved, so the items keep their indices until
+    /// then. Returns true once all of the items are moved.
+    pub fn migrate_vec<T, M>(
+        &mut self,
+        heap: &mut Vec<T>,
+        stable: &mut StableVec<T, M>,
+        batch: usize,
+    ) -> Result<bool, StableMemoryError>
+    where
+        T: BoundedStorable,
+        M: Memory,
+    {
+        let start = self.migrated as usize;
+        let end = (start + batch).min(heap.len());
+
+        for item in &heap[start..end] {
+            stable.push(item)?;
+            self.migrated += 1;
+        }
+
+        if end == heap.len() {
+            *heap = Vec::new();
+            self.done = true;
+        }
+
+        Ok(self.done)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stable::VectorMemory;
+
+    /// Save and restore the migration with candid, as it is during an upgrade.
+    fn upgrade(migration: Migration) -> Migration {
+        candid::decode_one(&candid::encode_one(migration).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn map_in_batches() {
+        let memory = VectorMemory::default();
+        let mut heap = (0..25u64).map(|i| (i, i * 10)).collect::<HashMap<_, _>>();
+        let mut stable = StableBTreeMap::<u64, u64, _>::init(memory.clone());
+        let mut migration = Migration::default();
+
+        assert!(!migration.migrate_map(&mut heap, &mut stable, 10));
+        assert_eq!(migration.migrated(), 10);
+        assert_eq!(heap.len(), 15);
+        assert_eq!(stable.len(), 10);
+
+        // An upgrade in the middle of the migration keeps the progress.
+        let mut migration = upgrade(migration);
+        let mut stable = StableBTreeMap::<u64, u64, _>::init(memory);
+        assert!(!migration.migrate_map(&mut heap, &mut stable, 10));
+        assert!(migration.migrate_map(&mut heap, &mut stable, 10));
+        assert!(migration.is_done());
+        assert_eq!(migration.migrated(), 25);
+
+        assert!(heap.is_empty());
+        assert_eq!(
+            stable.iter().collect::<Vec<_>>(),
+            (0..25).map(|i| (i, i * 10)).collect::<Vec<_>>()
+        );
+
+        // Once done, there is nothing left to move.
+        assert!(migration.migrate_map(&mut heap, &mut stable, 10));
+        assert_eq!(migration.migrated(), 25);
+    }
+
+    #[test]
+    fn vec_in_batches() {
+        let memory = VectorMemory::default();
+        let mut heap = (0..25u32).collect::<Vec<_>>();
+        let mut stable = StableVec::<u32, _>::init(memory.clone());
+        let mut migration = Migration::default();
+
+        assert_eq!(migration.migrate_vec(&mut heap, &mut stable, 10), Ok(false));
+        assert_eq!(migration.migrated(), 10);
+
+        // The heap vector keeps all of its items, so the indices stay valid until the end.
+        assert_eq!(heap.len(), 25);
+        assert_eq!(stable.len(), 10);
+
+        let mut migration = upgrade(migration);
+        let mut stable = StableVec::<u32, _>::init(memory);
+        assert_eq!(migration.migrate_vec(&mut heap, &mut stable, 10), Ok(false));
+        assert_eq!(migration.migrate_vec(&mut heap, &mut stable, 10), Ok(true));
+        assert_eq!(migration.migrated(), 25);
+
+        assert!(heap.is_empty());
+        assert_eq!(
+            stable.iter().collect::<Vec<_>>(),
+            (0..25).collect::<Vec<_>>()
+        );
+    }
+}
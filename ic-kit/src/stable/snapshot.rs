@@ -0,0 +1,297 @@
+use super::io::StableWriter64;
+use super::memory::{ensure_size, read_u64, write_u64, DefaultMemory, Memory};
+use crate::utils::performance_counter;
+use candid::CandidType;
+use serde::Deserialize;
+
+/// The magic bytes at the beginning of a chunked snapshot.
+const MAGIC: &[u8; 4] = b"KSNP";
+
+// The layout of the header: the magic bytes, the version as a u32, the number of chunks and the
+// end of the data as u64s, and a byte which is set once the snapshot is complete. Each chunk is
+// stored as its length as a u64 followed by its candid encoding.
+const VERSION_OFFSET: u64 = 4;
+const CHUNKS_OFFSET: u64 = 8;
+const END_OFFSET: u64 = 16;
+const COMPLETE_OFFSET: u64 = 24;
+const HEADER_SIZE: u64 = 32;
+
+/// Writes a snapshot of the state to the memory in chunks, which can be spread across several
+/// messages before an upgrade to stay under the instruction limit of a single message. The state
+/// should not change once the snapshot is started, for example by rejecting the updates with a
+/// guard, or the changes made to the already written chunks are lost.
+///
+/// The writer is kept in the canister's storage between the messages:
+///
+/// ```ignore
+/// #[update]
+/// fn snapshot_step() -> bool {
+///     let mut writer = with_mut(|writer: &mut Option<SnapshotWriter>| writer.take())
+///         .unwrap_or_else(|| SnapshotWriter::new(DefaultMemory, 1));
+///
+///     let done = writer
+///         .write_while(4_000_000_000, || next_batch_of_accounts())
+///         .unwrap();
+///
+///     if done {
+///         writer.finish();
+///     } else {
+///         with_mut(|w: &mut Option<SnapshotWriter>| *w = Some(writer));
+///     }
+///
+///     done
+/// }
+/// ```
+pub struct SnapshotWriter<M: Memory = DefaultMemory> {
+    memory: M,
+    chunks: u64,
+    /// The offset of the next chunk.
+    offset: u64,
+}
+
+impl<M: Memory> SnapshotWriter<M> {
+    /// Start a new snapshot with the given version, which replaces any data in the memory.
+    ///
+    /// # Panics
+    ///
+    /// If the memory can not be grown to hold the header.
+    pub fn new(memory: M, version: u32) -> Self {
+        ensure_size(&memory, HEADER_SIZE).expect("Could not grow the memory for the snapshot.");
+
+        let mut header = [0u8; HEADER_SIZE as usize];
+        header[0..4].copy_from_slice(MAGIC);
+        header[VERSION_OFFSET as usize..CHUNKS_OFFSET as usize]
+            .copy_from_slice(&version.to_le_bytes());
+        header[END_OFFSET as usize..COMPLETE_OFFSET as usize]
+            .copy_from_slice(&HEADER_SIZE.to_le_bytes());
+        memory.write(0, &header);
+
+        Self {
+            memory,
+            chunks: 0,
+            offset: HEADER_SIZE,
+        }
+    }
+
+    /// Returns the number of chunks written so far.
+    pub fn chunks(&self) -> u64 {
+        self.chunks
+    }
+
+    /// Append a chunk to the snapshot.
+    pub fn write_chunk<T: CandidType>(&mut self, chunk: &T) -> Result<(), String> {
+        let start = self.offset + 8;
+        let mut writer = StableWriter64::new(&self.memory, start);
+        candid::ser::IDLBuilder::new()
+            .arg(chunk)
+            .and_then(|builder| builder.serialize(&mut writer))
+            .map_err(|e| format!("{:?}", e))?;
+        let end = writer.offset();
+        drop(writer);
+
+        write_u64(&self.memory, self.offset, end - start);
+        self.offset = end;
+        self.chunks += 1;
+
+        // Only update the header once the chunk is written, so it never points to partial data.
+        write_u64(&self.memory, END_OFFSET, self.offset);
+        write_u64(&self.memory, CHUNKS_OFFSET, self.chunks);
+
+        Ok(())
+    }
+
+    /// Write the chunks returned by the function until it returns `None`, or until the current
+    /// message has executed `instructions` instructions. Returns `true` once all of the chunks
+    /// are written, otherwise this should be called again in a later message.
+    pub fn write_while<T, F>(&mut self, instructions: u64, mut next: F) -> Result<bool, String>
+    where
+        T: CandidType,
+        F: FnMut() -> Option<T>,
+    {
+        while performance_counter(0) < instructions {
+            match next() {
+                Some(chunk) => self.write_chunk(&chunk)?,
+                None => return Ok(true),
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Mark the snapshot as complete, so it can be restored by a [`SnapshotReader`].
+    pub fn finish(&mut self) {
+        self.memory.write(COMPLETE_OFFSET, &[1]);
+    }
+}
+
+/// Reads a snapshot written by a [`SnapshotWriter`] one chunk at a time, so the state can be
+/// restored incrementally in `post_upgrade` and the messages after it. The reader is kept in the
+/// canister's storage between the messages, like the writer.
+pub struct SnapshotReader<M: Memory = DefaultMemory> {
+    memory: M,
+    /// The number of chunks that are not read yet.
+    remaining: u64,
+    /// The offset of the next chunk.
+    offset: u64,
+}
+
+impl<M: Memory> SnapshotReader<M> {
+    /// Open the snapshot stored in the memory. Returns `None` if the memory is empty, and an
+    /// error if it does not contain a complete snapshot of the given version.
+    pub fn new(memory: M, version: u32) -> Result<Option<Self>, String> {
+        if memory.size() == 0 {
+            return Ok(None);
+        }
+
+        let mut header = [0u8; HEADER_SIZE as usize];
+        memory.read(0, &mut header);
+
+        if &header[0..4] != MAGIC {
+            return Err("The memory does not contain a chunked snapshot.".into());
+        }
+
+        let mut version_bytes = [0u8; 4];
+        version_bytes.copy_from_slice(&header[VERSION_OFFSET as usize..CHUNKS_OFFSET as usize]);
+        let stored_version = u32::from_le_bytes(version_bytes);
+
+        if stored_version != version {
+            return Err(format!(
+                "Expected the snapshot version to be {} but found {}.",
+                version, stored_version
+            ));
+        }
+
+        if header[COMPLETE_OFFSET as usize] != 1 {
+            return Err("The snapshot was not completed before the upgrade.".into());
+        }
+
+        Ok(Some(Self {
+            remaining: read_u64(&memory, CHUNKS_OFFSET),
+            memory,
+            offset: HEADER_SIZE,
+        }))
+    }
+
+    /// Returns the number of chunks that are not read yet.
+    pub fn remaining(&self) -> u64 {
+        self.remaining
+    }
+
+    /// Read the next chunk of the snapshot, returns `None` once all of the chunks are read.
+    pub fn read_chunk<T>(&mut self) -> Result<Option<T>, String>
+    where
+        T: CandidType + for<'de> Deserialize<'de>,
+    {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+
+        let len = read_u64(&self.memory, self.offset);
+        let mut bytes = vec![0; len as usize];
+        self.memory.read(self.offset + 8, &mut bytes);
+        let chunk = candid::decode_one(&bytes).map_err(|e| format!("{:?}", e))?;
+
+        self.offset += 8 + len;
+        self.remaining -= 1;
+
+        Ok(Some(chunk))
+    }
+
+    /// Pass the chunks to the function until all of them are read, or until the current message
+    /// has executed `instructions` instructions. Returns `true` once all of the chunks are read,
+    /// otherwise this should be called again in a later message.
+    pub fn read_while<T, F>(&mut self, instructions: u64, mut apply: F) -> Result<bool, String>
+    where
+        T: CandidType + for<'de> Deserialize<'de>,
+        F: FnMut(T),
+    {
+        while performance_counter(0) < instructions {
+            match self.read_chunk()? {
+                Some(chunk) => apply(chunk),
+                None => return Ok(true),
+            }
+        }
+
+        Ok(self.remaining == 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rt::context::MockContext;
+    use crate::stable::VectorMemory;
+
+    /// The accounts of the state in batches of 5,000, so each chunk is larger than a page.
+    fn batches() -> Vec<Vec<(u64, u64)>> {
+        (0..4u64)
+            .map(|batch| (0..5_000).map(|i| (batch * 5_000 + i, i * 7)).collect())
+            .collect()
+    }
+
+    #[test]
+    fn chunked_round_trip() {
+        // The mock context reports zero instructions, so a budget of zero ends each step at once.
+        MockContext::new().inject();
+        let memory = VectorMemory::default();
+        let mut chunks = batches().into_iter();
+
+        let mut writer = SnapshotWriter::new(&memory, 2);
+        assert_eq!(writer.write_while(0, || chunks.next()), Ok(false));
+        assert_eq!(writer.chunks(), 0);
+        writer.write_chunk(&chunks.next().unwrap()).unwrap();
+        assert_eq!(writer.write_while(1, || chunks.next()), Ok(true));
+        assert_eq!(writer.chunks(), 4);
+        writer.finish();
+        assert!(memory.size() > 4);
+
+        let mut reader = SnapshotReader::new(&memory, 2).unwrap().unwrap();
+        let mut restored = Vec::<Vec<(u64, u64)>>::new();
+        assert_eq!(
+            reader.read_while(0, |chunk| restored.push(chunk)),
+            Ok(false)
+        );
+        restored.push(reader.read_chunk().unwrap().unwrap());
+        assert_eq!(reader.remaining(), 3);
+        assert_eq!(reader.read_while(1, |chunk| restored.push(chunk)), Ok(true));
+        assert_eq!(reader.remaining(), 0);
+        assert_eq!(reader.read_chunk::<Vec<(u64, u64)>>(), Ok(None));
+
+        assert_eq!(restored, batches());
+    }
+
+    #[test]
+    fn incomplete_snapshot() {
+        let memory = VectorMemory::default();
+        assert!(SnapshotReader::new(&memory, 1).unwrap().is_none());
+
+        let mut writer = SnapshotWriter::new(&memory, 1);
+        writer.write_chunk(&vec![1u8; 10]).unwrap();
+        assert_eq!(
+            SnapshotReader::new(&memory, 1).err().unwrap(),
+            "The snapshot was not completed before the upgrade."
+        );
+
+        writer.finish();
+        assert_eq!(
+            SnapshotReader::new(&memory, 2).err().unwrap(),
+            "Expected the snapshot version to be 2 but found 1."
+        );
+
+        // Starting a new snapshot replaces the previous one.
+        SnapshotWriter::new(&memory, 1).finish();
+        let mut reader = SnapshotReader::new(&memory, 1).unwrap().unwrap();
+        assert_eq!(reader.read_chunk::<Vec<u8>>(), Ok(None));
+    }
+
+    #[test]
+    fn other_memory() {
+        let memory = VectorMemory::default();
+        memory.grow(1).unwrap();
+
+        assert_eq!(
+            SnapshotReader::new(&memory, 1).err().unwrap(),
+            "The memory does not contain a chunked snapshot."
+        );
+    }
+}
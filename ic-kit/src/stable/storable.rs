@@ -0,0 +1,208 @@
+use candid::Principal;
+use std::borrow::Cow;
+
+/// A type that can be stored in the stable structures, by converting it to bytes.
+///
+/// The byte representation of a type must not change across upgrades, types that implement
+/// `CandidType` can simply use the candid encoding:
+///
+/// ```ignore
+/// impl Storable for Account {
+///     fn to_bytes(&self) -> Cow<'_, [u8]> {
+///         Cow::Owned(candid::encode_one(self).unwrap())
+///     }
+///
+///     fn from_bytes(bytes: Vec<u8>) -> Self {
+///         candid::decode_one(&bytes).unwrap()
+///     }
+/// }
+/// ```
+pub trait Storable {
+    /// Convert the value to bytes.
+    fn to_bytes(&self) -> Cow<'_, [u8]>;
+
+    /// Create the value from the bytes returned by [`Storable::to_bytes`].
+    fn from_bytes(bytes: Vec<u8>) -> Self;
+}
+
+impl Storable for Vec<u8> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self)
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        bytes
+    }
+}
+
+impl Storable for String {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.as_bytes())
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        String::from_utf8(bytes).expect("Invalid UTF-8 string in the stable memory.")
+    }
+}
+
+impl Storable for Principal {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.as_slice())
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        Principal::from_slice(&bytes)
+    }
+}
+
+impl Storable for () {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&[])
+    }
+
+    fn from_bytes(_: Vec<u8>) -> Self {}
+}
+
+impl Storable for bool {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(vec![*self as u8])
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        bytes[0] != 0
+    }
+}
+
+// The integers are stored in big endian, so that the byte order of the unsigned integers matches
+// their numeric order.
+macro_rules! impl_storable_uint {
+    ($($t:ty),*) => {
+        $(
+            impl Storable for $t {
+                fn to_bytes(&self) -> Cow<'_, [u8]> {
+                    Cow::Owned(self.to_be_bytes().to_vec())
+                }
+
+                fn from_bytes(bytes: Vec<u8>) -> Self {
+                    let mut buf = [0; std::mem::size_of::<$t>()];
+                    buf.copy_from_slice(&bytes);
+                    <$t>::from_be_bytes(buf)
+                }
+            }
+        )*
+    };
+}
+
+impl_storable_uint!(u8, u16, u32, u64, u128);
+
+// The sign bit is flipped, so the negative numbers are ordered before the positive ones.
+macro_rules! impl_storable_int {
+    ($($t:ty => $u:ty),*) => {
+        $(
+            impl Storable for $t {
+                fn to_bytes(&self) -> Cow<'_, [u8]> {
+                    let flipped = (*self as $u) ^ (1 << (<$u>::BITS - 1));
+                    Cow::Owned(flipped.to_be_bytes().to_vec())
+                }
+
+                fn from_bytes(bytes: Vec<u8>) -> Self {
+                    let flipped = <$u as Storable>::from_bytes(bytes);
+                    (flipped ^ (1 << (<$u>::BITS - 1))) as $t
+                }
+            }
+        )*
+    };
+}
+
+impl_storable_int!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128);
+
+/// A [`Storable`] type whose byte representation is never larger than [`MAX_SIZE`] bytes, which
+/// allows it to be stored in fixed-size slots.
+///
+/// [`MAX_SIZE`]: BoundedStorable::MAX_SIZE
+pub trait BoundedStorable: Storable {
+    /// The maximum size of the value in bytes.
+    const MAX_SIZE: u32;
+}
+
+impl BoundedStorable for Principal {
+    const MAX_SIZE: u32 = 29;
+}
+
+impl BoundedStorable for () {
+    const MAX_SIZE: u32 = 0;
+}
+
+impl BoundedStorable for bool {
+    const MAX_SIZE: u32 = 1;
+}
+
+macro_rules! impl_bounded_storable_int {
+    ($($t:ty),*) => {
+        $(
+            impl BoundedStorable for $t {
+                const MAX_SIZE: u32 = std::mem::size_of::<$t>() as u32;
+            }
+        )*
+    };
+}
+
+impl_bounded_storable_int!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<T: Storable + PartialEq + std::fmt::Debug>(value: T) {
+        let bytes = value.to_bytes().into_owned();
+        assert_eq!(T::from_bytes(bytes), value);
+    }
+
+    #[test]
+    fn round_trips() {
+        round_trip(vec![1u8, 2, 3]);
+        round_trip("hello".to_string());
+        round_trip(Principal::management_canister());
+        round_trip(Principal::anonymous());
+        round_trip(());
+        round_trip(true);
+        round_trip(false);
+        round_trip(u8::MAX);
+        round_trip(u64::MAX);
+        round_trip(u128::MAX);
+        round_trip(i8::MIN);
+        round_trip(-1i16);
+        round_trip(i32::MAX);
+        round_trip(i64::MIN);
+        round_trip(-12_345i128);
+    }
+
+    #[test]
+    fn int_order() {
+        let values = [i64::MIN, -1_000, -1, 0, 1, 1_000, i64::MAX];
+        for pair in values.windows(2) {
+            assert!(pair[0].to_bytes() < pair[1].to_bytes());
+        }
+
+        let values = [i8::MIN, -1, 0, 1, i8::MAX];
+        for pair in values.windows(2) {
+            assert!(pair[0].to_bytes() < pair[1].to_bytes());
+        }
+
+        let values = [i128::MIN, -1, 0, i128::MAX];
+        for pair in values.windows(2) {
+            assert!(pair[0].to_bytes() < pair[1].to_bytes());
+        }
+
+        let values = [0u32, 1, 256, u32::MAX];
+        for pair in values.windows(2) {
+            assert!(pair[0].to_bytes() < pair[1].to_bytes());
+        }
+    }
+
+    #[test]
+    fn int_size() {
+        assert_eq!(i16::MIN.to_bytes().len(), i16::MAX_SIZE as usize);
+        assert_eq!(u128::MAX.to_bytes().len(), u128::MAX_SIZE as usize);
+    }
+}
@@ -0,0 +1,302 @@
+use super::memory::{ensure_size, read_u64, write_u64, DefaultMemory, Memory};
+use super::storable::BoundedStorable;
+use ic_kit_sys::types::StableMemoryError;
+use std::marker::PhantomData;
+
+/// The magic bytes at the beginning of the memory of a vector.
+const MAGIC: &[u8; 4] = b"KVEC";
+
+// The layout of the header: the magic bytes, followed by the number of elements and the size of
+// each slot as u64s. Each slot contains the length of the element as a u32 and its bytes.
+const LEN_OFFSET: u64 = 8;
+const SLOT_SIZE_OFFSET: u64 = 16;
+const HEADER_SIZE: u64 = 24;
+
+/// A growable array stored directly in the stable memory, each element is kept in a slot of the
+/// maximum size of the type so they can be accessed and replaced in constant time.
+///
+/// ```ignore
+/// use ic_kit::stable::{DefaultMemory, StableVec};
+///
+/// let mut prices = StableVec::<u64>::init(DefaultMemory);
+/// prices.push(&100).unwrap();
+/// ```
+pub struct StableVec<T, M: Memory = DefaultMemory> {
+    memory: M,
+    len: u64,
+    _marker: PhantomData<T>,
+}
+
+impl<T: BoundedStorable, M: Memory> StableVec<T, M> {
+    const SLOT_SIZE: u64 = T::MAX_SIZE as u64 + 4;
+
+    /// Load the vector stored in the memory, or create a new one if the memory is empty.
+    ///
+    /// # Panics
+    ///
+    /// If the memory is not empty and does not contain a vector of the same type.
+    pub fn init(memory: M) -> Self {
+        if memory.size() == 0 {
+            return Self::new(memory);
+        }
+
+        Self::load(memory)
+    }
+
+    /// Create a new empty vector in the memory, overwriting its content.
+    ///
+    /// # Panics
+    ///
+    /// If the memory can not grow to store the header of the vector.
+    pub fn new(memory: M) -> Self {
+        ensure_size(&memory, HEADER_SIZE)
+            .expect("Could not grow the stable memory for the vector.");
+
+        memory.write(0, MAGIC);
+        write_u64(&memory, LEN_OFFSET, 0);
+        write_u64(&memory, SLOT_SIZE_OFFSET, Self::SLOT_SIZE);
+
+        Self {
+            memory,
+            len: 0,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Load the vector stored in the memory.
+    ///
+    /// # Panics
+    ///
+    /// If the memory does not contain a vector of the same type.
+    pub fn load(memory: M) -> Self {
+        let mut magic = [0; 4];
+        memory.read(0, &mut magic);
+        assert_eq!(&magic, MAGIC, "The memory does not contain a StableVec.");
+        assert_eq!(
+            read_u64(&memory, SLOT_SIZE_OFFSET),
+            Self::SLOT_SIZE,
+            "The StableVec was created with a type of a different size."
+        );
+
+        Self {
+            len: read_u64(&memory, LEN_OFFSET),
+            memory,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Return the number of elements in the vector.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns true if the vector does not have any elements.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Return the element at the given index.
+    pub fn get(&self, index: u64) -> Option<T> {
+        if index >= self.len {
+            return None;
+        }
+
+        let offset = HEADER_SIZE + index * Self::SLOT_SIZE;
+        let mut len = [0; 4];
+        self.memory.read(offset, &mut len);
+
+        let mut bytes = vec![0; u32::from_le_bytes(len) as usize];
+        self.memory.read(offset + 4, &mut bytes);
+        Some(T::from_bytes(bytes))
+    }
+
+    /// Replace the element at the given index.
+    ///
+    /// # Panics
+    ///
+    /// If the index is out of bounds.
+    pub fn set(&mut self, index: u64, item: &T) {
+        assert!(
+            index < self.len,
+            "Index {} is out of bounds for a StableVec of length {}.",
+            index,
+            self.len
+        );
+
+        self.write_slot(index, item);
+    }
+
+    /// Append the element to the end of the vector, returns an error if the memory can not grow.
+    pub fn push(&mut self, item: &T) -> Result<(), StableMemoryError> {
+        ensure_size(&self.memory, HEADER_SIZE + (self.len + 1) * Self::SLOT_SIZE)?;
+
+        self.write_slot(self.len, item);
+        self.set_len(self.len + 1);
+        Ok(())
+    }
+
+    /// Remove the last element of the vector and return it.
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let item = self.get(self.len - 1);
+        self.set_len(self.len - 1);
+        item
+    }
+
+    /// Remove all of the elements, the memory does not shrink.
+    pub fn clear(&mut self) {
+        self.set_len(0);
+    }
+
+    /// Iterate over the elements of the vector.
+    pub fn iter(&self) -> VecIter<'_, T, M> {
+        VecIter {
+            vec: self,
+            front: 0,
+            back: self.len,
+        }
+    }
+
+    /// Return the memory of the vector.
+    pub fn into_memory(self) -> M {
+        self.memory
+    }
+
+    fn set_len(&mut self, len: u64) {
+        self.len = len;
+        write_u64(&self.memory, LEN_OFFSET, len);
+    }
+
+    fn write_slot(&mut self, index: u64, item: &T) {
+        let bytes = item.to_bytes();
+        assert!(
+            bytes.len() <= T::MAX_SIZE as usize,
+            "The element is larger than the MAX_SIZE of its type."
+        );
+
+        let offset = HEADER_SIZE + index * Self::SLOT_SIZE;
+        self.memory
+            .write(offset, &(bytes.len() as u32).to_le_bytes());
+        self.memory.write(offset + 4, &bytes);
+    }
+}
+
+/// An iterator over the elements of a [`StableVec`].
+pub struct VecIter<'a, T, M: Memory> {
+    vec: &'a StableVec<T, M>,
+    front: u64,
+    back: u64,
+}
+
+impl<'a, T: BoundedStorable, M: Memory> Iterator for VecIter<'a, T, M> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        if self.front >= self.back {
+            return None;
+        }
+
+        self.front += 1;
+        self.vec.get(self.front - 1)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = (self.back - self.front) as usize;
+        (len, Some(len))
+    }
+}
+
+impl<'a, T: BoundedStorable, M: Memory> DoubleEndedIterator for VecIter<'a, T, M> {
+    fn next_back(&mut self) -> Option<T> {
+        if self.front >= self.back {
+            return None;
+        }
+
+        self.back -= 1;
+        self.vec.get(self.back)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stable::VectorMemory;
+    use candid::Principal;
+
+    #[test]
+    fn push_get_set_pop() {
+        let mut vec = StableVec::<u64, _>::init(VectorMemory::default());
+        assert!(vec.is_empty());
+        assert_eq!(vec.pop(), None);
+
+        for i in 0..10_000 {
+            vec.push(&i).unwrap();
+        }
+
+        assert_eq!(vec.len(), 10_000);
+        assert_eq!(vec.get(1_234), Some(1_234));
+        assert_eq!(vec.get(10_000), None);
+
+        vec.set(1_234, &7);
+        assert_eq!(vec.get(1_234), Some(7));
+
+        assert_eq!(vec.pop(), Some(9_999));
+        assert_eq!(vec.len(), 9_999);
+        assert_eq!(vec.iter().next_back(), Some(9_998));
+        assert_eq!(vec.iter().take(3).collect::<Vec<_>>(), vec![0, 1, 2]);
+
+        vec.clear();
+        assert!(vec.is_empty());
+        assert_eq!(vec.iter().next(), None);
+    }
+
+    #[test]
+    fn variable_size() {
+        let principals = vec![
+            Principal::management_canister(),
+            Principal::anonymous(),
+            Principal::from_slice(&[7; 29]),
+        ];
+
+        let mut vec = StableVec::<Principal, _>::init(VectorMemory::default());
+        for principal in &principals {
+            vec.push(principal).unwrap();
+        }
+
+        assert_eq!(vec.iter().collect::<Vec<_>>(), principals);
+    }
+
+    #[test]
+    fn reload() {
+        let memory = VectorMemory::default();
+        let mut vec = StableVec::<u32, _>::init(memory.clone());
+        for i in 0..100 {
+            vec.push(&i).unwrap();
+        }
+        vec.pop();
+        drop(vec);
+
+        let vec = StableVec::<u32, _>::init(memory);
+        assert_eq!(vec.len(), 99);
+        assert_eq!(vec.iter().collect::<Vec<_>>(), (0..99).collect::<Vec<_>>());
+    }
+
+    #[test]
+    #[should_panic(expected = "The StableVec was created with a type of a different size.")]
+    fn reload_other_type() {
+        let memory = VectorMemory::default();
+        StableVec::<u32, _>::init(memory.clone()).push(&1).unwrap();
+        StableVec::<u64, _>::init(memory);
+    }
+
+    #[test]
+    #[should_panic(expected = "Index 1 is out of bounds for a StableVec of length 1.")]
+    fn set_out_of_bounds() {
+        let mut vec = StableVec::<u8, _>::init(VectorMemory::default());
+        vec.push(&1).unwrap();
+        vec.set(1, &2);
+    }
+}
@@ -1,12 +1,38 @@
 #![allow(non_snake_case)]
-use std::any::{Any, TypeId};
-use std::borrow::{Borrow, BorrowMut};
-use std::cell::RefCell;
+use std::any::{type_name, Any, TypeId};
+use std::cell::{Ref, RefCell, RefMut};
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
-use std::ops::DerefMut;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::rc::Rc;
 
-type StorageMap = HashMap<TypeId, RefCell<Box<dyn Any>>>;
+/// The values are behind an `Rc` so they don't move when the map grows, and the guards returned
+/// by [`Storage::borrow`] can keep them alive.
+type Cell = Rc<RefCell<Box<dyn Any>>>;
+
+type StorageMap = HashMap<TypeId, Cell>;
+
+/// An immutable reference to the value associated with a type, which is held until it's dropped,
+/// see [`crate::ic::borrow`].
+pub struct StateRef<T: 'static> {
+    // Declared before the cell, so the borrow is released before the cell is dropped.
+    borrow: Ref<'static, Box<dyn Any>>,
+    _cell: Cell,
+    #[cfg(debug_assertions)]
+    resumes: u64,
+    _type: PhantomData<T>,
+}
+
+/// A mutable reference to the value associated with a type, which is held until it's dropped,
+/// see [`crate::ic::borrow_mut`].
+pub struct StateRefMut<T: 'static> {
+    borrow: RefMut<'static, Box<dyn Any>>,
+    _cell: Cell,
+    #[cfg(debug_assertions)]
+    resumes: u64,
+    _type: PhantomData<T>,
+}
 
 /// An storage implementation for singleton design pattern, where we only have one value
 /// associated with each types.
@@ -22,7 +48,13 @@ impl Storage {
         self.storage
             .borrow_mut()
             .entry(tid)
-            .or_insert_with(|| RefCell::new(Box::new(T::default())));
+            .or_insert_with(|| Rc::new(RefCell::new(Box::new(T::default()))));
+    }
+
+    /// Return the cell of the value associated with the type `T`, if any.
+    #[inline(always)]
+    fn cell<T: 'static>(&self) -> Option<Cell> {
+        self.storage.borrow().get(&TypeId::of::<T>()).cloned()
     }
 
     /// Pass an immutable reference to the stored data of the type `T` to the closure,
@@ -32,25 +64,18 @@ impl Storage {
     pub fn with<T: 'static + Default, U, F: FnOnce(&T) -> U>(&self, callback: F) -> U {
         let tid = TypeId::of::<T>();
         self.ensure_default::<T>(tid);
-        let cell = unsafe { self.storage.try_borrow_unguarded() }
-            .unwrap()
-            .get(&tid)
-            .unwrap()
-            .borrow();
-        let borrow = cell.downcast_ref::<T>().unwrap();
-        callback(borrow)
+        let cell = self.storage.borrow().get(&tid).unwrap().clone();
+        let borrow = borrow::<T>(&cell);
+        callback(borrow.downcast_ref::<T>().unwrap())
     }
 
     /// Pass an immutable reference to the stored data of the type `T` to the closure,
     /// if there is no data associated with the type, just return None.
     #[inline]
     pub fn maybe_with<T: 'static, U, F: FnOnce(&T) -> U>(&self, callback: F) -> Option<U> {
-        let tid = TypeId::of::<T>();
-        unsafe { self.storage.try_borrow_unguarded() }
-            .unwrap()
-            .get(&tid)
-            .map(|c| c.borrow())
-            .map(|c| callback(c.borrow().downcast_ref::<T>().unwrap()))
+        let cell = self.cell::<T>()?;
+        let borrow = borrow::<T>(&cell);
+        Some(callback(borrow.downcast_ref::<T>().unwrap()))
     }
 
     /// Like [`Self::with`] but passes a mutable reference.
@@ -58,34 +83,65 @@ impl Storage {
     pub fn with_mut<T: 'static + Default, U, F: FnOnce(&mut T) -> U>(&self, callback: F) -> U {
         let tid = TypeId::of::<T>();
         self.ensure_default::<T>(tid);
-        let mut cell = unsafe { self.storage.try_borrow_unguarded() }
-            .unwrap()
-            .get(&tid)
-            .unwrap()
-            .borrow_mut();
-        let borrow = cell.downcast_mut::<T>().unwrap();
-        callback(borrow)
+        let cell = self.storage.borrow().get(&tid).unwrap().clone();
+        let mut borrow = borrow_mut::<T>(&cell);
+        callback(borrow.downcast_mut::<T>().unwrap())
     }
 
     /// Like [`Self::maybe_with`] but passes a mutable reference.
     #[inline]
     pub fn maybe_with_mut<T: 'static, U, F: FnOnce(&mut T) -> U>(&self, callback: F) -> Option<U> {
-        let tid = TypeId::of::<T>();
-        unsafe { self.storage.try_borrow_unguarded() }
-            .unwrap()
-            .get(&tid)
-            .map(|c| c.borrow_mut())
-            .map(|mut c| callback(c.borrow_mut().downcast_mut::<T>().unwrap()))
+        let cell = self.cell::<T>()?;
+        let mut borrow = borrow_mut::<T>(&cell);
+        Some(callback(borrow.downcast_mut::<T>().unwrap()))
+    }
+
+    /// Return a guard which holds an immutable reference to the stored data of the type `T`,
+    /// the `Default` is stored first if there is no data associated with the type.
+    #[inline]
+    pub fn borrow<T: 'static + Default>(&self) -> StateRef<T> {
+        self.ensure_default::<T>(TypeId::of::<T>());
+        let cell = self.cell::<T>().unwrap();
+        // The borrow does not outlive the cell it's dropped with.
+        let borrow = unsafe { &*Rc::as_ptr(&cell) };
+
+        StateRef {
+            borrow: self::borrow::<T>(borrow),
+            _cell: cell,
+            #[cfg(debug_assertions)]
+            resumes: crate::futures::resumes(),
+            _type: PhantomData,
+        }
+    }
+
+    /// Like [`Self::borrow`] but the guard holds a mutable reference.
+    #[inline]
+    pub fn borrow_mut<T: 'static + Default>(&self) -> StateRefMut<T> {
+        self.ensure_default::<T>(TypeId::of::<T>());
+        let cell = self.cell::<T>().unwrap();
+        let borrow = unsafe { &*Rc::as_ptr(&cell) };
+
+        StateRefMut {
+            borrow: self::borrow_mut::<T>(borrow),
+            _cell: cell,
+            #[cfg(debug_assertions)]
+            resumes: crate::futures::resumes(),
+            _type: PhantomData,
+        }
     }
 
     /// Remove the data associated with the type `T`, and returns it if any.
     #[inline]
     pub fn take<T: 'static>(&self) -> Option<T> {
         let tid = TypeId::of::<T>();
-        self.storage
-            .borrow_mut()
-            .remove(&tid)
-            .map(|cell| *cell.into_inner().downcast::<T>().unwrap())
+
+        if self.cell::<T>()?.try_borrow_mut().is_err() {
+            already_borrowed::<T>();
+        }
+
+        let cell = self.storage.borrow_mut().remove(&tid)?;
+        let cell = Rc::try_unwrap(cell).unwrap_or_else(|_| already_borrowed::<T>());
+        Some(*cell.into_inner().downcast::<T>().unwrap())
     }
 
     /// Store the given value for type `T`, returns the previously stored value if any.
@@ -93,14 +149,13 @@ impl Storage {
     pub fn swap<T: 'static>(&self, value: T) -> Option<T> {
         let tid = TypeId::of::<T>();
         match self.storage.borrow_mut().entry(tid) {
-            Entry::Occupied(mut o) => Some(
-                *o.get_mut()
-                    .replace(Box::new(value))
-                    .downcast::<T>()
-                    .unwrap(),
-            ),
+            Entry::Occupied(o) => {
+                let mut borrow = borrow_mut::<T>(o.get());
+                let old = std::mem::replace(&mut *borrow, Box::new(value));
+                Some(*old.downcast::<T>().unwrap())
+            }
             Entry::Vacant(v) => {
-                v.insert(RefCell::new(Box::new(value)));
+                v.insert(Rc::new(RefCell::new(Box::new(value))));
                 None
             }
         }
@@ -129,6 +184,68 @@ impl Storage {
     }
 }
 
+/// Borrow the value of the type `T`, with a clear message if it's already mutably borrowed.
+fn borrow<T: 'static>(cell: &RefCell<Box<dyn Any>>) -> Ref<'_, Box<dyn Any>> {
+    cell.try_borrow()
+        .unwrap_or_else(|_| already_borrowed::<T>())
+}
+
+/// Mutably borrow the value of the type `T`, with a clear message if it's already borrowed.
+fn borrow_mut<T: 'static>(cell: &RefCell<Box<dyn Any>>) -> RefMut<'_, Box<dyn Any>> {
+    cell.try_borrow_mut()
+        .unwrap_or_else(|_| already_borrowed::<T>())
+}
+
+fn already_borrowed<T: 'static>() -> ! {
+    panic!(
+        "The state '{}' is already borrowed, such as by a `StateRefMut` held across an await, or by \
+         a closure of `with_mut` which accesses it again.",
+        type_name::<T>()
+    )
+}
+
+impl<T: 'static> Deref for StateRef<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        #[cfg(debug_assertions)]
+        check_resumes::<T>(self.resumes, false);
+        self.borrow.downcast_ref::<T>().unwrap()
+    }
+}
+
+impl<T: 'static> Deref for StateRefMut<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        #[cfg(debug_assertions)]
+        check_resumes::<T>(self.resumes, true);
+        self.borrow.downcast_ref::<T>().unwrap()
+    }
+}
+
+impl<T: 'static> DerefMut for StateRefMut<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        #[cfg(debug_assertions)]
+        check_resumes::<T>(self.resumes, true);
+        self.borrow.downcast_mut::<T>().unwrap()
+    }
+}
+
+/// Trap if an await was resumed since the reference was obtained, which means the reference was
+/// held across the await while the other messages could not access the state.
+#[cfg(debug_assertions)]
+fn check_resumes<T: 'static>(resumes: u64, mutable: bool) {
+    if crate::futures::resumes() != resumes {
+        crate::ic::trap(&format!(
+            "The {} reference to the state '{}' was obtained before an await and used after it, \
+             borrow the state again after the await.",
+            if mutable { "mutable" } else { "immutable" },
+            type_name::<T>()
+        ));
+    }
+}
+
 pub trait BorrowMany: Sized {
     fn ensure_default(storage: &mut StorageMap);
 
@@ -153,14 +270,14 @@ macro_rules! implement_borrow_many {
                 $(
                 storage
                     .entry(TypeId::of::<$name>())
-                    .or_insert_with(|| RefCell::new(Box::new($name::default())));
+                    .or_insert_with(|| Rc::new(RefCell::new(Box::new($name::default()))));
                 )+
             }
 
             #[inline(always)]
             fn with<U, F: FnOnce(($(&'a $name,)+)) -> U>(storage: &StorageMap, callback: F) -> U {
                 $(
-                let $name = storage.get(&TypeId::of::<$name>()).unwrap().borrow();
+                let $name = borrow::<$name>(storage.get(&TypeId::of::<$name>()).unwrap());
                 )+
 
                 callback((
@@ -182,14 +299,14 @@ macro_rules! implement_borrow_many {
                 $(
                 storage
                     .entry(TypeId::of::<$name>())
-                    .or_insert_with(|| RefCell::new(Box::new($name::default())));
+                    .or_insert_with(|| Rc::new(RefCell::new(Box::new($name::default()))));
                 )+
             }
 
             #[inline(always)]
             fn with_mut<U, F: FnOnce(($(&'a mut $name,)+)) -> U>(storage: &StorageMap, callback: F) -> U {
                 $(
-                let mut $name = storage.get(&TypeId::of::<$name>()).unwrap().borrow_mut();
+                let mut $name = borrow_mut::<$name>(storage.get(&TypeId::of::<$name>()).unwrap());
                 )+
 
                 callback((
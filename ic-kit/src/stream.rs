@@ -0,0 +1,571 @@
+//! Transfer a blob larger than the message limit to another canister, as a stream of sequenced
+//! chunks which is reassembled and checked against the hash of the blob by the receiver.
+//!
+//! The receiver acknowledges each chunk with the sequence number of the next chunk it expects, so
+//! a chunk which failed is sent again, a duplicate is acknowledged without being appended twice,
+//! and a transfer which was interrupted resumes from the last chunk received when it's sent again
+//! with the same stream id.
+//!
+//! ```ignore
+//! // The sender.
+//! #[update]
+//! async fn publish(snapshot_id: u64) -> Result<(), String> {
+//!     let blob = load_snapshot(snapshot_id);
+//!     StreamSender::new(archive_id(), "receive_chunk", snapshot_id, blob)
+//!         .send()
+//!         .await
+//! }
+//!
+//! // The receiver.
+//! #[update]
+//! fn receive_chunk(chunk: Chunk) -> Result<ChunkAck, String> {
+//!     let ack = with_mut(|streams: &mut Reassembler| streams.receive(caller(), chunk))?;
+//!
+//!     if ack.complete {
+//!         let blob = with_mut(|streams: &mut Reassembler| streams.take(caller(), ack.stream_id));
+//!         store_snapshot(ack.stream_id, blob.unwrap());
+//!     }
+//!
+//!     Ok(ack)
+//! }
+//! ```
+
+use crate::ic::{CallBuilder, MAX_REPLY_SIZE};
+use candid::{CandidType, Principal};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+/// The size of the chunks by default, half of the message limit so the chunk and its envelope
+/// always fit in a message.
+pub const DEFAULT_CHUNK_SIZE: usize = MAX_REPLY_SIZE / 2;
+
+/// The largest chunk size, which leaves room in the message for the candid encoding of the chunk.
+pub const MAX_CHUNK_SIZE: usize = MAX_REPLY_SIZE - 1024;
+
+/// The number of times a chunk is sent by default before the transfer fails.
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+
+/// A chunk of a blob, with the metadata of the whole blob so the receiver can check that the
+/// chunks belong to the same stream.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct Chunk {
+    /// The id of the stream, chosen by the sender and unique among its streams to the receiver.
+    pub stream_id: u64,
+    /// The sequence number of the chunk, from `0` to `chunk_count - 1`.
+    pub seq: u64,
+    /// The number of chunks of the stream.
+    pub chunk_count: u64,
+    /// The length of the whole blob in bytes.
+    pub total_len: u64,
+    /// The sha256 hash of the whole blob.
+    pub hash: Vec<u8>,
+    /// The bytes of this chunk.
+    pub data: Vec<u8>,
+}
+
+/// The reply of the receiver to a chunk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct ChunkAck {
+    /// The id of the stream.
+    pub stream_id: u64,
+    /// The sequence number of the next chunk the receiver expects.
+    pub next_seq: u64,
+    /// Whether all of the chunks were received and the blob matches its hash.
+    pub complete: bool,
+}
+
+/// Split a blob into chunks and send them in order to a method of another canister, which
+/// replies with a `Result<ChunkAck, String>`, such as one calling [`Reassembler::receive`].
+pub struct StreamSender {
+    canister_id: Principal,
+    method: String,
+    stream_id: u64,
+    blob: Vec<u8>,
+    hash: Vec<u8>,
+    chunk_size: usize,
+    max_attempts: u32,
+}
+
+impl StreamSender {
+    /// Create a sender of the blob to the given method of the canister, under the given stream id.
+    pub fn new<S: Into<String>>(
+        canister_id: Principal,
+        method: S,
+        stream_id: u64,
+        blob: Vec<u8>,
+    ) -> Self {
+        let hash = Sha256::digest(&blob).to_vec();
+
+        Self {
+            canister_id,
+            method: method.into(),
+            stream_id,
+            blob,
+            hash,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+        }
+    }
+
+    /// Split the blob into chunks of the given size.
+    ///
+    /// # Panics
+    ///
+    /// If the size is `0` or larger than [`MAX_CHUNK_SIZE`].
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        assert!(
+            chunk_size > 0 && chunk_size <= MAX_CHUNK_SIZE,
+            "The chunk size must be between 1 and {} bytes.",
+            MAX_CHUNK_SIZE
+        );
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Send each chunk at most the given number of times, in a row, before the transfer fails.
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// Return the id of the stream.
+    pub fn stream_id(&self) -> u64 {
+        self.stream_id
+    }
+
+    /// Return the number of chunks of the stream, an empty blob is sent as one empty chunk.
+    pub fn chunk_count(&self) -> u64 {
+        match self.blob.len() {
+            0 => 1,
+            len => ((len - 1) / self.chunk_size + 1) as u64,
+        }
+    }
+
+    /// Return the chunk with the given sequence number.
+    ///
+    /// # Panics
+    ///
+    /// If the sequence number is not lower than the number of chunks.
+    pub fn chunk(&self, seq: u64) -> Chunk {
+        assert!(
+            seq < self.chunk_count(),
+            "The stream {} has no chunk {}.",
+            self.stream_id,
+            seq
+        );
+
+        let start = seq as usize * self.chunk_size;
+        let end = (start + self.chunk_size).min(self.blob.len());
+
+        Chunk {
+            stream_id: self.stream_id,
+            seq,
+            chunk_count: self.chunk_count(),
+            total_len: self.blob.len() as u64,
+            hash: self.hash.clone(),
+            data: self.blob[start..end].to_vec(),
+        }
+    }
+
+    /// Send the chunks in order until the receiver has the whole blob, starting from the first
+    /// one. The receiver acknowledges the chunks it already has, so a stream which was interrupted
+    /// resumes from where it stopped.
+    pub async fn send(&self) -> Result<(), String> {
+        self.send_from(0).await
+    }
+
+    /// Send the chunks in order until the receiver has the whole blob, starting from the given
+    /// sequence number.
+    pub async fn send_from(&self, mut seq: u64) -> Result<(), String> {
+        let chunk_count = self.chunk_count();
+        let mut attempts = 0;
+
+        loop {
+            let result = CallBuilder::new(self.canister_id, &self.method)
+                .with_arg(self.chunk(seq))
+                .perform_one::<Result<ChunkAck, String>>()
+                .await;
+
+            let error = match result {
+                Ok(Ok(ack)) if ack.complete => return Ok(()),
+                Ok(Ok(ack)) if ack.next_seq >= chunk_count => {
+                    return Err(format!(
+                        "The receiver expects the chunk {} of the stream {} which has {} chunks.",
+                        ack.next_seq, self.stream_id, chunk_count
+                    ))
+                }
+                Ok(Ok(ack)) if ack.next_seq != seq => {
+                    attempts = 0;
+                    seq = ack.next_seq;
+                    continue;
+                }
+                Ok(Ok(_)) => "The receiver did not accept the chunk.".to_string(),
+                Ok(Err(e)) => return Err(e),
+                Err(e) => e.to_string(),
+            };
+
+            attempts += 1;
+
+            if attempts >= self.max_attempts {
+                return Err(format!(
+                    "The chunk {} of the stream {} failed after {} attempts: {}",
+                    seq, self.stream_id, attempts, error
+                ));
+            }
+        }
+    }
+}
+
+/// A stream being received.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+struct PartialStream {
+    chunk_count: u64,
+    total_len: u64,
+    hash: Vec<u8>,
+    next_seq: u64,
+    data: Vec<u8>,
+}
+
+/// Reassemble the streams sent by [`StreamSender`], by their sender and id. It can be kept in
+/// the canister's storage, and saved and restored in the upgrade hooks so the streams being
+/// received survive an upgrade.
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct Reassembler {
+    streams: BTreeMap<(Principal, u64), PartialStream>,
+    completed: BTreeMap<(Principal, u64), Vec<u8>>,
+    max_len: Option<u64>,
+}
+
+impl Reassembler {
+    /// Create an empty reassembler.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reject the streams longer than the given number of bytes.
+    pub fn with_max_len(mut self, max_len: u64) -> Self {
+        self.max_len = Some(max_len);
+        self
+    }
+
+    /// Receive a chunk from the given sender, and return the sequence number of the next chunk
+    /// expected. A chunk received again is acknowledged without being appended twice, and a chunk
+    /// ahead of the expected one is ignored, so the sender goes back to the expected one.
+    ///
+    /// Once the last chunk is received the blob is checked against its hash, and it's kept until
+    /// it's taken with [`Reassembler::take`]. If it does not match, the stream is dropped so it
+    /// can be sent again from the start.
+    pub fn receive(&mut self, sender: Principal, chunk: Chunk) -> Result<ChunkAck, String> {
+        let key = (sender, chunk.stream_id);
+
+        if self.completed.contains_key(&key) {
+            return Ok(ChunkAck {
+                stream_id: chunk.stream_id,
+                next_seq: chunk.chunk_count,
+                complete: true,
+            });
+        }
+
+        let max_len = self.max_len;
+        let stream = match self.streams.get_mut(&key) {
+            Some(stream) => {
+                if stream.chunk_count != chunk.chunk_count
+                    || stream.total_len != chunk.total_len
+                    || stream.hash != chunk.hash
+                {
+                    return Err(format!(
+                        "The chunk {} does not match the stream {}.",
+                        chunk.seq, chunk.stream_id
+                    ));
+                }
+
+                stream
+            }
+            None => {
+                if chunk.hash.len() != 32 || chunk.seq >= chunk.chunk_count {
+                    return Err(format!("The chunk {} is not valid.", chunk.seq));
+                }
+
+                if matches!(max_len, Some(max_len) if chunk.total_len > max_len) {
+                    return Err(format!(
+                        "The stream {} of {} bytes is too large.",
+                        chunk.stream_id, chunk.total_len
+                    ));
+                }
+
+                self.streams.entry(key).or_insert(PartialStream {
+                    chunk_count: chunk.chunk_count,
+                    total_len: chunk.total_len,
+                    hash: chunk.hash.clone(),
+                    next_seq: 0,
+                    data: Vec::new(),
+                })
+            }
+        };
+
+        if chunk.seq != stream.next_seq {
+            return Ok(ChunkAck {
+                stream_id: chunk.stream_id,
+                next_seq: stream.next_seq,
+                complete: false,
+            });
+        }
+
+        if stream.data.len() as u64 + chunk.data.len() as u64 > stream.total_len {
+            return Err(format!(
+                "The chunk {} exceeds the length of the stream {}.",
+                chunk.seq, chunk.stream_id
+            ));
+        }
+
+        stream.data.extend_from_slice(&chunk.data);
+        stream.next_seq += 1;
+
+        if stream.next_seq < stream.chunk_count {
+            return Ok(ChunkAck {
+                stream_id: chunk.stream_id,
+                next_seq: stream.next_seq,
+                complete: false,
+            });
+        }
+
+        let stream = self.streams.remove(&key).unwrap();
+
+        if stream.data.len() as u64 != stream.total_len
+            || Sha256::digest(&stream.data)[..] != stream.hash[..]
+        {
+            return Err(format!(
+                "The stream {} does not match its hash.",
+                chunk.stream_id
+            ));
+        }
+
+        self.completed.insert(key, stream.data);
+
+        Ok(ChunkAck {
+            stream_id: chunk.stream_id,
+            next_seq: stream.chunk_count,
+            complete: true,
+        })
+    }
+
+    /// Return the number of chunks received and the number of chunks of a stream being received.
+    pub fn progress(&self, sender: Principal, stream_id: u64) -> Option<(u64, u64)> {
+        self.streams
+            .get(&(sender, stream_id))
+            .map(|stream| (stream.next_seq, stream.chunk_count))
+    }
+
+    /// Return whether the stream was completely received and not taken yet.
+    pub fn is_complete(&self, sender: Principal, stream_id: u64) -> bool {
+        self.completed.contains_key(&(sender, stream_id))
+    }
+
+    /// Remove and return the blob of a completed stream, after which the same stream id starts a
+    /// new stream.
+    pub fn take(&mut self, sender: Principal, stream_id: u64) -> Option<Vec<u8>> {
+        self.completed.remove(&(sender, stream_id))
+    }
+
+    /// Drop a stream being received, or a completed one which was not taken.
+    pub fn abort(&mut self, sender: Principal, stream_id: u64) -> bool {
+        let key = (sender, stream_id);
+        self.streams.remove(&key).is_some() || self.completed.remove(&key).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rt::context::MockContext;
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+
+    fn sender() -> Principal {
+        Principal::from_slice(&[1])
+    }
+
+    fn receiver() -> Principal {
+        Principal::from_slice(&[2])
+    }
+
+    fn stream(len: usize) -> StreamSender {
+        let blob = (0..len).map(|i| i as u8).collect();
+        StreamSender::new(receiver(), "receive_chunk", 7, blob).with_chunk_size(4)
+    }
+
+    fn ack(next_seq: u64, complete: bool) -> Result<ChunkAck, String> {
+        Ok(ChunkAck {
+            stream_id: 7,
+            next_seq,
+            complete,
+        })
+    }
+
+    #[test]
+    fn in_order() {
+        let stream = stream(10);
+        let mut reassembler = Reassembler::new();
+        assert_eq!(stream.chunk_count(), 3);
+        assert_eq!(stream.chunk(2).data, vec![8, 9]);
+
+        assert_eq!(
+            reassembler.receive(sender(), stream.chunk(0)),
+            ack(1, false)
+        );
+        assert_eq!(reassembler.progress(sender(), 7), Some((1, 3)));
+        assert_eq!(
+            reassembler.receive(sender(), stream.chunk(1)),
+            ack(2, false)
+        );
+        assert_eq!(reassembler.receive(sender(), stream.chunk(2)), ack(3, true));
+
+        assert_eq!(reassembler.progress(sender(), 7), None);
+        assert!(reassembler.is_complete(sender(), 7));
+        assert_eq!(reassembler.take(sender(), 7), Some(stream.blob.clone()));
+        assert!(!reassembler.is_complete(sender(), 7));
+
+        // An empty blob is sent as one empty chunk.
+        let empty = StreamSender::new(receiver(), "receive_chunk", 8, Vec::new());
+        assert_eq!(empty.chunk_count(), 1);
+        assert!(
+            reassembler
+                .receive(sender(), empty.chunk(0))
+                .unwrap()
+                .complete
+        );
+        assert_eq!(reassembler.take(sender(), 8), Some(Vec::new()));
+    }
+
+    #[test]
+    fn out_of_order_and_duplicates() {
+        let stream = stream(10);
+        let mut reassembler = Reassembler::new();
+
+        // A chunk ahead of the expected one is ignored, and one received again is not appended.
+        assert_eq!(
+            reassembler.receive(sender(), stream.chunk(1)),
+            ack(0, false)
+        );
+        assert_eq!(
+            reassembler.receive(sender(), stream.chunk(0)),
+            ack(1, false)
+        );
+        assert_eq!(
+            reassembler.receive(sender(), stream.chunk(0)),
+            ack(1, false)
+        );
+        assert_eq!(
+            reassembler.receive(sender(), stream.chunk(2)),
+            ack(1, false)
+        );
+        assert_eq!(
+            reassembler.receive(sender(), stream.chunk(1)),
+            ack(2, false)
+        );
+        assert_eq!(reassembler.receive(sender(), stream.chunk(2)), ack(3, true));
+        assert_eq!(reassembler.receive(sender(), stream.chunk(1)), ack(3, true));
+
+        // The streams of another sender are kept apart.
+        assert_eq!(
+            reassembler.receive(receiver(), stream.chunk(0)),
+            ack(1, false)
+        );
+        assert_eq!(reassembler.progress(receiver(), 7), Some((1, 3)));
+
+        assert_eq!(reassembler.take(sender(), 7), Some(stream.blob.clone()));
+        assert!(reassembler.abort(receiver(), 7));
+        assert!(!reassembler.abort(receiver(), 7));
+    }
+
+    #[test]
+    fn invalid_chunks() {
+        let stream = stream(10);
+        let mut reassembler = Reassembler::new().with_max_len(8);
+
+        assert_eq!(
+            reassembler.receive(sender(), stream.chunk(0)),
+            Err("The stream 7 of 10 bytes is too large.".into())
+        );
+
+        let mut reassembler = Reassembler::new();
+        reassembler.receive(sender(), stream.chunk(0)).unwrap();
+        let mut other = stream.chunk(1);
+        other.chunk_count = 4;
+        assert_eq!(
+            reassembler.receive(sender(), other),
+            Err("The chunk 1 does not match the stream 7.".into())
+        );
+    }
+
+    #[test]
+    fn hash_mismatch() {
+        let stream = stream(10);
+        let mut reassembler = Reassembler::new();
+
+        let mut corrupted = stream.chunk(1);
+        corrupted.data[0] ^= 1;
+        reassembler.receive(sender(), stream.chunk(0)).unwrap();
+        reassembler.receive(sender(), corrupted).unwrap();
+        assert_eq!(
+            reassembler.receive(sender(), stream.chunk(2)),
+            Err("The stream 7 does not match its hash.".into())
+        );
+
+        // The stream is dropped, so it's sent again from the start.
+        assert_eq!(reassembler.progress(sender(), 7), None);
+        assert!(!reassembler.is_complete(sender(), 7));
+        for seq in 0..3 {
+            reassembler.receive(sender(), stream.chunk(seq)).unwrap();
+        }
+        assert_eq!(reassembler.take(sender(), 7), Some(stream.blob.clone()));
+    }
+
+    #[test]
+    fn send_and_resume() {
+        let ctx = MockContext::new().with_id(sender()).inject();
+        let reassembler = Rc::new(RefCell::new(Reassembler::new()));
+        let failures = Rc::new(Cell::new(0));
+
+        // The receiver rejects the chunk 2 as many times as the test asks it to.
+        let ctx = {
+            let reassembler = reassembler.clone();
+            let failures = failures.clone();
+            ctx.with_handler(receiver(), "receive_chunk", move |call| {
+                let chunk: Chunk = call.decode_one().unwrap();
+                if chunk.seq == 2 && failures.get() > 0 {
+                    failures.set(failures.get() - 1);
+                    return Err("Out of cycles.".into());
+                }
+
+                let ack = reassembler.borrow_mut().receive(sender(), chunk);
+                Ok(candid::encode_one(ack).unwrap())
+            })
+        };
+
+        // The transfer fails once the chunk failed too many times in a row.
+        let stream = stream(10).with_max_attempts(2);
+        failures.set(2);
+        let error = ctx.block_on(stream.send()).unwrap_err();
+        assert!(error.starts_with("The chunk 2 of the stream 7 failed after 2 attempts"));
+        assert_eq!(reassembler.borrow().progress(sender(), 7), Some((2, 3)));
+        assert_eq!(ctx.watcher().call_count(), 4);
+
+        // Sent again, the stream resumes from the chunk the receiver expects.
+        failures.set(1);
+        ctx.block_on(stream.send()).unwrap();
+        let seqs: Vec<u64> = ctx
+            .watcher()
+            .calls()
+            .iter()
+            .skip(4)
+            .map(|call| call.decode_one::<Chunk>().unwrap().seq)
+            .collect();
+        assert_eq!(seqs, vec![0, 2, 2]);
+        assert_eq!(
+            reassembler.borrow_mut().take(sender(), 7),
+            Some(stream.blob.clone())
+        );
+    }
+}
@@ -0,0 +1,184 @@
+//! Timers running a function once a time has passed, on top of the global timer of the canister.
+//!
+//! The canisters with `#[derive(KitCanister)]` or `export_candid!()` export a `global_timer` hook
+//! which runs the timers whose time has passed, and sets the global timer to the next one. In the
+//! tests, the timers run once the time of the replica has passed and the global timer of the
+//! canister is run:
+//!
+//! ```ignore
+//! timers::set_timer(Duration::from_secs(60), || ic::print("A minute has passed."));
+//!
+//! replica.advance_time(Duration::from_secs(60));
+//! canister.global_timer().await.assert_ok();
+//! ```
+//!
+//! The timers are kept in the heap, so they are lost on the upgrades and must be set again from
+//! the `post_upgrade` hook.
+
+use crate::ic::time;
+use ic_kit_sys::ic0;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// The id of a timer, to clear it with [`clear_timer`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TimerId(u64);
+
+type Timer = Box<dyn FnOnce()>;
+
+thread_local! {
+    /// The timers which did not run yet, by their time and their id.
+    static TIMERS: RefCell<BTreeMap<(u64, TimerId), Timer>> = RefCell::new(BTreeMap::new());
+    static NEXT_ID: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Run the function once the given duration has passed.
+pub fn set_timer<F: FnOnce() + 'static>(delay: Duration, f: F) -> TimerId {
+    set_timer_at(time().saturating_add(delay.as_nanos() as u64), f)
+}
+
+/// Run the function once the given time, in nanoseconds since the epoch, has passed. A time in the
+/// past runs the function with the next global timer.
+pub fn set_timer_at<F: FnOnce() + 'static>(at: u64, f: F) -> TimerId {
+    let id = TimerId(NEXT_ID.with(|next| next.replace(next.get() + 1)));
+    TIMERS.with(|timers| timers.borrow_mut().insert((at, id), Box::new(f)));
+    arm();
+    id
+}
+
+/// Clear the timer, returns false if it already ran or was cleared.
+pub fn clear_timer(id: TimerId) -> bool {
+    let removed = TIMERS.with(|timers| {
+        let mut timers = timers.borrow_mut();
+        let key = timers.keys().find(|(_, timer_id)| *timer_id == id).copied();
+        key.and_then(|key| timers.remove(&key)).is_some()
+    });
+
+    if removed {
+        arm();
+    }
+
+    removed
+}
+
+/// Return the time of the next timer, if any.
+pub fn next_timer() -> Option<u64> {
+    TIMERS.with(|timers| timers.borrow().keys().next().map(|(at, _)| *at))
+}
+
+/// Run the timers whose time has passed, in the order of their time, and set the global timer to
+/// the next one. This is the `global_timer` hook of the canisters.
+pub fn run_expired() {
+    let now = time();
+
+    loop {
+        // The timer is removed before it runs, so it can set other timers.
+        let timer = TIMERS.with(|timers| {
+            let mut timers = timers.borrow_mut();
+            let key = *timers.keys().next().filter(|(at, _)| *at <= now)?;
+            timers.remove(&key)
+        });
+
+        match timer {
+            Some(timer) => timer(),
+            None => break,
+        }
+    }
+
+    arm();
+}
+
+/// Set the global timer of the canister to the time of the next timer, or deactivate it.
+fn arm() {
+    // Zero deactivates the global timer, so a timer at zero is set to the smallest time instead.
+    let at = next_timer().map(|at| at.max(1)).unwrap_or(0);
+
+    unsafe {
+        ic0::global_timer_set(at as i64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rt::context::MockContext;
+    use std::rc::Rc;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    #[test]
+    fn run_in_order() {
+        let ctx = MockContext::new().with_time(10 * SECOND).inject();
+        let runs = Rc::new(RefCell::new(Vec::new()));
+
+        for (delay, name) in [(3, "c"), (1, "a"), (2, "b"), (5, "e")] {
+            let runs = runs.clone();
+            set_timer(Duration::from_secs(delay), move || {
+                runs.borrow_mut().push(name)
+            });
+        }
+
+        assert_eq!(ctx.global_timer(), 11 * SECOND);
+
+        // Nothing has expired yet.
+        run_expired();
+        assert!(runs.borrow().is_empty());
+
+        let ctx = ctx.with_time(13 * SECOND);
+        run_expired();
+        assert_eq!(*runs.borrow(), vec!["a", "b", "c"]);
+        assert_eq!(ctx.global_timer(), 15 * SECOND);
+
+        let ctx = ctx.with_time(20 * SECOND);
+        run_expired();
+        assert_eq!(*runs.borrow(), vec!["a", "b", "c", "e"]);
+        assert_eq!(ctx.global_timer(), 0);
+        assert_eq!(next_timer(), None);
+    }
+
+    #[test]
+    fn clear() {
+        let ctx = MockContext::new().with_time(10 * SECOND).inject();
+        let runs = Rc::new(Cell::new(0));
+
+        let first = {
+            let runs = runs.clone();
+            set_timer(Duration::from_secs(1), move || runs.set(runs.get() + 1))
+        };
+        set_timer_at(12 * SECOND, || ());
+
+        assert!(clear_timer(first));
+        assert!(!clear_timer(first));
+        assert_eq!(ctx.global_timer(), 12 * SECOND);
+
+        let ctx = ctx.with_time(20 * SECOND);
+        run_expired();
+        assert_eq!(runs.get(), 0);
+        assert_eq!(ctx.global_timer(), 0);
+    }
+
+    #[test]
+    fn set_from_timer() {
+        let ctx = MockContext::new().with_time(10 * SECOND).inject();
+        let runs = Rc::new(Cell::new(0));
+
+        fn repeat(runs: Rc<Cell<u32>>) {
+            runs.set(runs.get() + 1);
+            set_timer(Duration::from_secs(1), move || repeat(runs));
+        }
+
+        let first = runs.clone();
+        set_timer_at(0, move || repeat(first));
+        assert_eq!(ctx.global_timer(), 1);
+
+        run_expired();
+        assert_eq!(runs.get(), 1);
+        assert_eq!(ctx.global_timer(), 11 * SECOND);
+
+        let ctx = ctx.with_time(11 * SECOND);
+        run_expired();
+        assert_eq!(runs.get(), 2);
+        assert_eq!(ctx.global_timer(), 12 * SECOND);
+    }
+}
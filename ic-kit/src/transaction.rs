@@ -0,0 +1,214 @@
+//! Changes to the canister's state which are only kept once all the calls of a flow succeeded.
+//!
+//! The state changes made before an await are committed by the replica when the call is sent,
+//! so a method which debits a balance and then calls a ledger must undo the debit itself if the
+//! call fails. A [`Transaction`] keeps track of these changes:
+//!
+//! ```ignore
+//! #[update]
+//! async fn withdraw(amount: u64) -> Result<(), String> {
+//!     let user = caller();
+//!     let mut tx = Transaction::new();
+//!
+//!     // Debit now so the concurrent calls can not spend the balance again, and credit it back if
+//!     // the transaction is rolled back.
+//!     tx.apply(
+//!         |b: &mut Balances| b.debit(user, amount),
+//!         move |b: &mut Balances| b.credit(user, amount),
+//!     )?;
+//!     // Only record the withdrawal once the transfer succeeded.
+//!     tx.stage(move |h: &mut History| h.push(user, amount));
+//!
+//!     transfer(user, amount).await?; // An error here rolls back the transaction.
+//!
+//!     tx.commit();
+//!     Ok(())
+//! }
+//! ```
+//!
+//! A transaction which is dropped without being committed is rolled back, this also happens in
+//! the cleanup callback if the method traps after an await.
+
+use crate::ic::with_mut;
+
+type Change = Box<dyn FnOnce()>;
+
+/// A set of staged changes, applied once the transaction is committed, and of applied changes,
+/// undone if the transaction is rolled back.
+#[derive(Default)]
+pub struct Transaction {
+    staged: Vec<Change>,
+    undo: Vec<Change>,
+}
+
+impl Transaction {
+    /// Create an empty transaction.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stage a change to the state of type `T`, which is applied when the transaction is
+    /// committed. The staged changes are applied in the order they were staged.
+    pub fn stage<T, F>(&mut self, change: F)
+    where
+        T: 'static + Default,
+        F: 'static + FnOnce(&mut T),
+    {
+        self.staged.push(Box::new(move || with_mut(change)));
+    }
+
+    /// Apply a change to the state of type `T` right away, and record the change which undoes
+    /// it if the transaction is rolled back. If the change returns an error, nothing is recorded
+    /// and the error is returned, so the change should not modify the state when it fails.
+    pub fn apply<T, R, E, F, U>(&mut self, change: F, undo: U) -> Result<R, E>
+    where
+        T: 'static + Default,
+        F: FnOnce(&mut T) -> Result<R, E>,
+        U: 'static + FnOnce(&mut T),
+    {
+        let result = with_mut(change)?;
+        self.undo.push(Box::new(move || with_mut(undo)));
+        Ok(result)
+    }
+
+    /// Run the function when the transaction is committed, after the staged changes.
+    pub fn on_commit<F: 'static + FnOnce()>(&mut self, f: F) {
+        self.staged.push(Box::new(f));
+    }
+
+    /// Run the function if the transaction is rolled back.
+    pub fn on_rollback<F: 'static + FnOnce()>(&mut self, f: F) {
+        self.undo.push(Box::new(f));
+    }
+
+    /// Return true if the transaction has no changes.
+    pub fn is_empty(&self) -> bool {
+        self.staged.is_empty() && self.undo.is_empty()
+    }
+
+    /// Apply the staged changes and keep the applied ones.
+    pub fn commit(mut self) {
+        self.undo.clear();
+
+        for change in std::mem::take(&mut self.staged) {
+            change();
+        }
+    }
+
+    /// Discard the staged changes and undo the applied ones, in the reverse order.
+    pub fn rollback(self) {
+        drop(self);
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        self.staged.clear();
+
+        while let Some(undo) = self.undo.pop() {
+            undo();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ic::{with, CallBuilder};
+    use crate::rt::context::MockContext;
+    use candid::Principal;
+    use std::panic::{self, AssertUnwindSafe};
+
+    #[derive(Default)]
+    struct Log(Vec<&'static str>);
+
+    fn log() -> Vec<&'static str> {
+        with(|log: &Log| log.0.clone())
+    }
+
+    /// Apply a change which logs the name, and is undone by logging it again.
+    fn apply(tx: &mut Transaction, name: &'static str) {
+        tx.apply(
+            |log: &mut Log| {
+                log.0.push(name);
+                Ok::<_, ()>(())
+            },
+            move |log: &mut Log| log.0.push(name),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn rollback() {
+        MockContext::new().inject();
+        let mut tx = Transaction::new();
+        assert!(tx.is_empty());
+
+        apply(&mut tx, "a");
+        tx.on_rollback(|| with_mut(|log: &mut Log| log.0.push("rolled back")));
+        apply(&mut tx, "b");
+        tx.stage(|log: &mut Log| log.0.push("staged"));
+        assert_eq!(log(), vec!["a", "b"]);
+
+        // The applied changes are undone in the reverse order, and the staged ones are dropped.
+        drop(tx);
+        assert_eq!(log(), vec!["a", "b", "b", "rolled back", "a"]);
+    }
+
+    #[test]
+    fn commit() {
+        MockContext::new().inject();
+        let mut tx = Transaction::new();
+
+        apply(&mut tx, "a");
+        tx.on_commit(|| with_mut(|log: &mut Log| log.0.push("committed")));
+        tx.stage(|log: &mut Log| log.0.push("staged"));
+        tx.on_rollback(|| with_mut(|log: &mut Log| log.0.push("rolled back")));
+        assert_eq!(log(), vec!["a"]);
+
+        // The staged changes are applied in order, and nothing is undone.
+        tx.commit();
+        assert_eq!(log(), vec!["a", "committed", "staged"]);
+    }
+
+    #[test]
+    fn failed_change() {
+        MockContext::new().inject();
+        let mut tx = Transaction::new();
+
+        let result = tx.apply(
+            |_: &mut Log| Err::<(), _>("No balance."),
+            |log: &mut Log| log.0.push("a"),
+        );
+        assert_eq!(result, Err("No balance."));
+        assert!(tx.is_empty());
+
+        tx.rollback();
+        assert!(log().is_empty());
+    }
+
+    #[test]
+    fn trap_after_await() {
+        let ctx = MockContext::new()
+            .with_reply(Principal::from_slice(&[1]), "transfer", ())
+            .inject();
+
+        // The future of the call is dropped when it traps, which rolls back the transaction.
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            ctx.block_on(async {
+                let mut tx = Transaction::new();
+                apply(&mut tx, "a");
+                apply(&mut tx, "b");
+
+                CallBuilder::new(Principal::from_slice(&[1]), "transfer")
+                    .perform_one::<()>()
+                    .await
+                    .unwrap();
+                crate::ic::trap("The transfer failed.");
+            })
+        }));
+
+        assert!(result.is_err());
+        assert_eq!(log(), vec!["a", "b", "b", "a"]);
+    }
+}
@@ -35,6 +35,21 @@ pub fn reject(message: &str) {
     unsafe { ic0::msg_reject(message.as_ptr() as isize, message.len() as isize) }
 }
 
+/// Return the reject code of the response to the call, in a callback, `0` if it was a reply.
+pub fn reject_code() -> i32 {
+    unsafe { ic0::msg_reject_code() }
+}
+
+/// Return the reject message of the response to the call, in a callback which was rejected.
+pub fn reject_message() -> String {
+    let len = unsafe { ic0::msg_reject_msg_size() as usize };
+    let mut bytes = vec![0u8; len];
+    unsafe {
+        ic0::msg_reject_msg_copy(bytes.as_mut_ptr() as isize, 0, len as isize);
+    }
+    String::from_utf8_lossy(&bytes).to_string()
+}
+
 /// Accept the incoming message.
 pub fn accept() {
     unsafe {